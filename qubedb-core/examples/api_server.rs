@@ -0,0 +1,34 @@
+//! REST API server example for QubeDB Core
+//!
+//! Opens an embedded database, creates a table and serves it over HTTP:
+//!
+//!   curl http://127.0.0.1:8080/health
+//!   curl http://127.0.0.1:8080/tables
+//!   curl -X POST http://127.0.0.1:8080/tables/users \
+//!        -H 'Content-Type: application/json' -d '{"name": "Alice"}'
+//!   curl -X POST http://127.0.0.1:8080/query \
+//!        -H 'Content-Type: application/json' -d '{"query": "SELECT * FROM users"}'
+
+use qubedb_core::api::{ApiConfig, RestApiServer};
+use qubedb_core::embedded::EmbeddedQubeDB;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("🦀 QubeDB Core - REST API Server Example");
+    println!("=========================================");
+
+    let db = EmbeddedQubeDB::open("./api_example_db")?;
+    db.execute(
+        "CREATE TABLE users (id INT PRIMARY KEY AUTO_INCREMENT, name VARCHAR(100) NOT NULL)",
+    )
+    .await?;
+    println!("✅ Database opened at: {}", db.path());
+
+    let config = ApiConfig::default();
+    println!("🌐 Listening on http://{}:{}", config.host, config.port);
+
+    let server = RestApiServer::new(config, db);
+    server.start().await?;
+
+    Ok(())
+}
@@ -8,7 +8,7 @@
 //! - Graph operations
 
 use qubedb_core::embedded::EmbeddedQubeDB;
-use qubedb_core::types::{Row, Value};
+use qubedb_core::types::Value;
 use qubedb_core::logging::{LoggerConfig, LogLevel, init_logger};
 use std::collections::HashMap;
 
@@ -4,7 +4,7 @@
 //! and provides benchmarking for different operations.
 
 use qubedb_core::embedded::EmbeddedQubeDB;
-use qubedb_core::types::{Row, Value};
+use qubedb_core::types::Value;
 use qubedb_core::logging::{LoggerConfig, LogLevel, init_logger};
 use std::collections::HashMap;
 use std::time::Instant;
@@ -208,7 +208,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut handles = vec![];
 
     for thread_id in 1..=10 {
-        let db_clone = &db;
         let handle = tokio::spawn(async move {
             for i in 1..=100 {
                 let mut row = HashMap::new();
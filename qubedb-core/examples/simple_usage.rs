@@ -120,7 +120,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut record = HashMap::new();
         record.insert("id".to_string(), Value::Int32(i));
         record.insert("name".to_string(), Value::String(format!("User{}", i)));
-        record.insert("value".to_string(), Value::Float64(i as f64 * 3.14));
+        record.insert("value".to_string(), Value::Float64(i as f64 * 2.5));
         
         db.insert("performance_test", record)?;
     }
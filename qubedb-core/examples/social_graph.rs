@@ -0,0 +1,80 @@
+//! Social graph example for QubeDB Core
+//!
+//! Builds a small friendship graph and queries it with the Cypher-style
+//! graph query language:
+//!
+//!   MATCH (a:Person)-[:FRIENDS]->(b) WHERE a.name = 'Alice' RETURN b.name
+
+use qubedb_core::embedded::EmbeddedQubeDB;
+use qubedb_core::types::{QueryResult, Row, Value};
+
+fn person(name: &str, age: i64) -> Row {
+    Row::from([
+        ("label".to_string(), Value::String("Person".to_string())),
+        ("name".to_string(), Value::String(name.to_string())),
+        ("age".to_string(), Value::Int64(age)),
+    ])
+}
+
+fn relationship(kind: &str, since: i64) -> Row {
+    Row::from([
+        ("type".to_string(), Value::String(kind.to_string())),
+        ("since".to_string(), Value::Int64(since)),
+    ])
+}
+
+fn print_result(query: &str, result: &QueryResult) {
+    println!("\n🔍 {}", query);
+    for row in &result.rows {
+        let values: Vec<String> = result
+            .columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "{} = {}",
+                    column,
+                    row.get(column).cloned().unwrap_or(Value::Null)
+                )
+            })
+            .collect();
+        println!("   {}", values.join(", "));
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("🦀 QubeDB Core - Social Graph Example");
+    println!("=====================================");
+
+    let mut db = EmbeddedQubeDB::open("./social_graph_db")?;
+    db.drop_all()?;
+
+    for (id, name, age) in [
+        ("alice", "Alice", 30),
+        ("bob", "Bob", 25),
+        ("carol", "Carol", 35),
+        ("dave", "Dave", 40),
+    ] {
+        db.store_node("social", id, person(name, age))?;
+    }
+    db.store_edge("social", "alice", "bob", relationship("FRIENDS", 2015))?;
+    db.store_edge("social", "alice", "carol", relationship("FRIENDS", 2018))?;
+    db.store_edge("social", "bob", "dave", relationship("FRIENDS", 2020))?;
+    db.store_edge("social", "carol", "alice", relationship("FOLLOWS", 2021))?;
+    println!("✅ Stored 4 people and 4 relationships");
+
+    let queries = [
+        "MATCH (a:Person)-[:FRIENDS]->(b) WHERE a.name = 'Alice' RETURN b.name ORDER BY b.name",
+        "MATCH (a {name: 'Alice'})-[r:FRIENDS]->(b) WHERE b.age > 30 RETURN b.name AS friend, r.since",
+        "MATCH (a {name: 'Alice'})-[:FRIENDS*2]->(c) RETURN c.name AS friend_of_friend",
+        "MATCH (a)<-[:FOLLOWS]-(b) RETURN a.name AS followed, b.name AS follower",
+        "MATCH (a)-[:FRIENDS]-(b) WHERE a.name = 'Bob' RETURN count(b) AS friends_of_bob",
+    ];
+    for query in queries {
+        let result = db.graph_query("social", query)?;
+        print_result(query, &result);
+    }
+
+    println!("\n🎉 Social graph example completed!");
+    Ok(())
+}
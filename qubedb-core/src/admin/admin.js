@@ -0,0 +1,112 @@
+// QubeDB admin page: lists tables and runs SQL through the REST API
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+
+async function request(method, path, body) {
+  const response = await fetch(path, {
+    method,
+    headers: body ? { "Content-Type": "application/json" } : {},
+    body: body ? JSON.stringify(body) : undefined,
+  });
+  const json = await response.json();
+  if (!response.ok) {
+    throw new Error(json.error || response.statusText);
+  }
+  return json;
+}
+
+async function loadTables() {
+  const list = $("tables");
+  list.replaceChildren();
+  try {
+    const { tables } = await request("GET", "/tables");
+    for (const name of tables) {
+      const item = document.createElement("li");
+      item.textContent = name;
+      item.title = "Show the first 100 rows";
+      item.onclick = () => {
+        $("sql").value = `SELECT * FROM ${name} LIMIT 100`;
+        runQuery();
+      };
+      list.append(item);
+    }
+  } catch (error) {
+    showError(error);
+  }
+}
+
+async function runQuery() {
+  const query = $("sql").value.trim();
+  if (!query) {
+    return;
+  }
+  $("error").hidden = true;
+  $("summary").textContent = "Running...";
+  try {
+    const result = await request("POST", "/query", { query, number_format: "string" });
+    renderResult(result);
+    if (!/^\s*select\b/i.test(query)) {
+      loadTables();
+    }
+  } catch (error) {
+    $("result").replaceChildren();
+    $("summary").textContent = "";
+    showError(error);
+  }
+}
+
+function renderResult(result) {
+  const table = $("result");
+  table.replaceChildren();
+  if (result.columns.length > 0) {
+    const header = table.insertRow();
+    for (const column of result.columns) {
+      const cell = document.createElement("th");
+      cell.textContent = column;
+      header.append(cell);
+    }
+    for (const row of result.rows) {
+      const tr = table.insertRow();
+      for (const value of row) {
+        const cell = tr.insertCell();
+        if (value === null) {
+          cell.textContent = "NULL";
+          cell.className = "null";
+        } else {
+          cell.textContent = typeof value === "object" ? JSON.stringify(value) : String(value);
+        }
+      }
+    }
+  }
+  const count = result.columns.length > 0
+    ? `${result.rows.length} row(s)`
+    : `${result.affected_rows} row(s) affected`;
+  $("summary").textContent = `${count} in ${result.execution_time_ms.toFixed(2)} ms`;
+}
+
+function showError(error) {
+  $("error").textContent = error.message;
+  $("error").hidden = false;
+}
+
+async function checkHealth() {
+  try {
+    const { version } = await request("GET", "/health");
+    $("status").textContent = `connected, v${version}`;
+  } catch (error) {
+    $("status").textContent = "unreachable";
+  }
+}
+
+$("run").onclick = runQuery;
+$("refresh").onclick = loadTables;
+$("sql").addEventListener("keydown", (event) => {
+  if (event.key === "Enter" && (event.ctrlKey || event.metaKey)) {
+    event.preventDefault();
+    runQuery();
+  }
+});
+
+checkHealth();
+loadTables();
@@ -0,0 +1,222 @@
+//! Index advisor for QubeDB
+//!
+//! Suggests indexes for a workload of SELECTs from how the planner would
+//! run them. Wherever a plan scans a table in full although its WHERE or
+//! ON filters compare columns of that table with constants, the advisor
+//! proposes a B-Tree index on those columns: the equality columns, most
+//! selective first, then one column compared by a range. The query is then
+//! planned again as if the index existed, and the proposal is only kept if
+//! the planner would use it.
+//!
+//! The benefit of an index is the number of rows it saves reading,
+//! estimated from table statistics where ANALYZE has collected them, and
+//! summed over the queries of the workload that would use it.
+//!
+//! Joins run as hash joins, which read both sides in full whatever the
+//! indexes, so join keys are not proposed on their own; filters in ON
+//! clauses count like those in WHERE.
+
+use crate::error::QubeResult;
+use crate::planner::{self, QueryPlan, Relation};
+use crate::types::{Index, IndexType};
+use serde::Serialize;
+use sqlparser::ast::{BinaryOperator, Expr};
+use std::collections::BTreeMap;
+
+/// An index the advisor suggests creating
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexRecommendation {
+    pub table: String,
+    pub columns: Vec<String>,
+    /// The `CREATE INDEX` statement that creates it
+    pub statement: String,
+    /// Estimated rows the queries read without the index
+    pub rows_scanned: f64,
+    /// Estimated rows they would read with it
+    pub rows_with_index: f64,
+    /// Estimated rows the index saves reading
+    pub benefit: f64,
+    /// Queries of the workload that would use it
+    pub queries: usize,
+}
+
+/// Indexes that would spare the full scans of `plan`, the plan of a query
+/// over `relations`. Only relations marked `indexable` are stored tables
+/// that can take an index. `replan` plans the same query over other
+/// relations.
+pub(crate) fn advise(
+    relations: &[Relation],
+    plan: &QueryPlan,
+    indexable: &[bool],
+    replan: impl Fn(&[Relation]) -> QubeResult<QueryPlan>,
+) -> QubeResult<Vec<IndexRecommendation>> {
+    let mut recommendations = Vec::new();
+    for step in &plan.steps {
+        if step.index.is_some() || !indexable[step.relation] {
+            continue;
+        }
+        let relation = &relations[step.relation];
+        let columns = candidate_columns(relation, &step.filters);
+        if columns.is_empty() {
+            continue;
+        }
+        let index = Index {
+            name: format!("{}_{}_idx", relation.table.name, columns.join("_")),
+            columns,
+            index_type: IndexType::BTree,
+            unique: false,
+        };
+
+        // Plan again as if the index existed
+        let mut table = relation.table.clone();
+        table.indexes.push(index.clone());
+        let hypothetical: Vec<Relation> = relations
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let table = if i == step.relation { &table } else { r.table };
+                Relation::new(table, r.qualifier.clone(), r.data)
+            })
+            .collect();
+        let replanned = replan(&hypothetical)?;
+        let scan = replanned
+            .steps
+            .iter()
+            .find(|s| s.relation == step.relation)
+            .and_then(|s| s.index.as_ref())
+            .filter(|scan| scan.index == index.name);
+        let Some(scan) = scan else {
+            continue;
+        };
+
+        let rows_scanned = relation.row_count() as f64;
+        let rows_with_index = scan
+            .conditions
+            .iter()
+            .fold(rows_scanned, |rows, condition| {
+                rows * planner::selectivity(condition, relation)
+            });
+        recommendations.push(IndexRecommendation {
+            table: relation.table.name.clone(),
+            statement: format!(
+                "CREATE INDEX {} ON {} ({})",
+                index.name,
+                relation.table.name,
+                index.columns.join(", ")
+            ),
+            columns: index.columns,
+            rows_scanned,
+            rows_with_index,
+            benefit: rows_scanned - rows_with_index,
+            queries: 1,
+        });
+    }
+    Ok(recommendations)
+}
+
+/// Columns for an index serving `filters` on `relation`: those compared
+/// for equality, most selective first, then the first compared by a range
+fn candidate_columns(relation: &Relation, filters: &[Expr]) -> Vec<String> {
+    let stored = |column: &str| {
+        relation
+            .table
+            .columns
+            .iter()
+            .any(|c| c.name == column && !c.is_virtual())
+    };
+    let mut equalities: Vec<(String, f64)> = Vec::new();
+    let mut range = None;
+    for filter in filters {
+        for (column, op, _) in planner::column_comparisons(filter) {
+            if !stored(column) {
+                continue;
+            }
+            if op == BinaryOperator::Eq {
+                if !equalities.iter().any(|(name, _)| name == column) {
+                    equalities.push((column.to_string(), planner::selectivity(filter, relation)));
+                }
+            } else if range.is_none() {
+                range = Some(column.to_string());
+            }
+        }
+    }
+    equalities.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+    let mut columns: Vec<String> = equalities.into_iter().map(|(name, _)| name).collect();
+    if let Some(range) = range.filter(|range| !columns.contains(range)) {
+        columns.push(range);
+    }
+    columns
+}
+
+/// Combine the recommendations for the queries of a workload, adding up
+/// the benefit of each index, most beneficial first
+pub(crate) fn merge(
+    recommendations: impl IntoIterator<Item = IndexRecommendation>,
+) -> Vec<IndexRecommendation> {
+    let mut merged: BTreeMap<(String, Vec<String>), IndexRecommendation> = BTreeMap::new();
+    for recommendation in recommendations {
+        let key = (recommendation.table.clone(), recommendation.columns.clone());
+        match merged.get_mut(&key) {
+            Some(existing) => {
+                existing.rows_scanned += recommendation.rows_scanned;
+                existing.rows_with_index += recommendation.rows_with_index;
+                existing.benefit += recommendation.benefit;
+                existing.queries += recommendation.queries;
+            }
+            None => {
+                merged.insert(key, recommendation);
+            }
+        }
+    }
+    let mut merged: Vec<IndexRecommendation> = merged.into_values().collect();
+    merged.sort_by(|a, b| b.benefit.total_cmp(&a.benefit));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recommendation(table: &str, column: &str, rows_with_index: f64) -> IndexRecommendation {
+        IndexRecommendation {
+            table: table.to_string(),
+            columns: vec![column.to_string()],
+            statement: format!("CREATE INDEX {0}_{1}_idx ON {0} ({1})", table, column),
+            rows_scanned: 100.0,
+            rows_with_index,
+            benefit: 100.0 - rows_with_index,
+            queries: 1,
+        }
+    }
+
+    #[test]
+    fn recommendations_for_one_index_add_up() {
+        let merged = merge([
+            recommendation("t", "a", 90.0),
+            recommendation("t", "b", 50.0),
+            recommendation("u", "a", 80.0),
+            recommendation("t", "a", 70.0),
+        ]);
+        let ranked: Vec<(&str, &str, f64, usize)> = merged
+            .iter()
+            .map(|r| {
+                (
+                    r.table.as_str(),
+                    r.columns[0].as_str(),
+                    r.benefit,
+                    r.queries,
+                )
+            })
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                ("t", "b", 50.0, 1),
+                ("t", "a", 40.0, 2),
+                ("u", "a", 20.0, 1)
+            ]
+        );
+        assert_eq!(merged[1].rows_scanned, 200.0);
+        assert_eq!(merged[1].rows_with_index, 160.0);
+    }
+}
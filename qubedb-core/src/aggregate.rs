@@ -0,0 +1,650 @@
+//! Aggregate functions for QubeDB queries
+//!
+//! Evaluates COUNT, SUM, AVG, MIN, MAX and APPROX_COUNT_DISTINCT over every
+//! row a query produces.
+//! NULL inputs are skipped, except by `COUNT(*)`, which counts rows. Integer
+//! sums are checked and fail rather than wrap on overflow.
+//! `APPROX_COUNT_DISTINCT(expr [, precision])` estimates a distinct count
+//! with a HyperLogLog sketch, in memory fixed by its precision.
+//!
+//! Over enough rows the calls are evaluated on several threads, one
+//! partition of the rows each, and the partial results merged; DISTINCT
+//! aggregates always run on one thread.
+
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::hll::{self, HyperLogLog};
+use crate::parallel;
+use crate::query::ExecutionContext;
+use crate::types::{Row, Value};
+use sqlparser::ast::{
+    visit_expressions, visit_expressions_mut, Expr, Function, FunctionArg, FunctionArgExpr, Ident,
+    SelectItem,
+};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+
+/// A supported aggregate function. Every function but `COUNT(*)` ignores
+/// NULL inputs, and every function but `COUNT` returns NULL when no
+/// non-NULL input remains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    /// `COUNT(*)` counts rows; `COUNT(expr)` counts non-NULL values
+    Count,
+    Sum,
+    /// The sum divided by the number of non-NULL values
+    Avg,
+    Min,
+    Max,
+    /// Estimated number of distinct non-NULL values
+    ApproxCountDistinct,
+}
+
+impl AggregateFunction {
+    /// The aggregate a function name refers to, if any
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "COUNT" => Some(AggregateFunction::Count),
+            "SUM" => Some(AggregateFunction::Sum),
+            "AVG" => Some(AggregateFunction::Avg),
+            "MIN" => Some(AggregateFunction::Min),
+            "MAX" => Some(AggregateFunction::Max),
+            "APPROX_COUNT_DISTINCT" => Some(AggregateFunction::ApproxCountDistinct),
+            _ => None,
+        }
+    }
+}
+
+/// One aggregate call, e.g. `SUM(DISTINCT price)`
+#[derive(Debug, Clone)]
+struct Aggregate {
+    function: AggregateFunction,
+    /// `None` for `COUNT(*)`
+    argument: Option<Expr>,
+    distinct: bool,
+    /// Sketch precision, for `APPROX_COUNT_DISTINCT`
+    precision: Option<u8>,
+}
+
+/// Whether a SELECT list calls any aggregate function
+pub fn has_aggregates(items: &[SelectItem]) -> bool {
+    items.iter().any(|item| match item {
+        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+            contains_aggregate(expr)
+        }
+        _ => false,
+    })
+}
+
+/// Whether an expression calls an aggregate function anywhere inside it
+pub fn contains_aggregate(expr: &Expr) -> bool {
+    visit_expressions(expr, |e| {
+        if is_aggregate_call(e) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    })
+    .is_break()
+}
+
+/// Evaluate a projection containing aggregates over `rows`, producing the
+/// single output row. Columns outside an aggregate have no single value
+/// (there is no GROUP BY), so they are rejected.
+pub(crate) fn aggregate_rows<'a>(
+    projection: &[(String, Expr)],
+    rows: impl IntoIterator<Item = &'a Row>,
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Row> {
+    // Validate every call before rewriting, so nested calls are caught
+    for (_, expr) in projection {
+        let mut error = None;
+        let _ = visit_expressions(expr, |e| match aggregate_call(e) {
+            Err(e) => {
+                error = Some(e);
+                ControlFlow::Break(())
+            }
+            Ok(_) => ControlFlow::Continue(()),
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+
+    // Replace each call with a reference to its result
+    let mut calls = Vec::new();
+    let mut outputs = Vec::with_capacity(projection.len());
+    for (name, expr) in projection {
+        let mut expr = expr.clone();
+        let _ = visit_expressions_mut(&mut expr, |e| {
+            if let Ok(Some(call)) = aggregate_call(e) {
+                *e = Expr::Identifier(Ident::new(result_key(calls.len())));
+                calls.push(call);
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        if let Some(column) = column_reference(&expr, calls.len()) {
+            return Err(QubeError::QueryParse(format!(
+                "Column '{}' must be used in an aggregate function",
+                column
+            )));
+        }
+        outputs.push((name, expr));
+    }
+
+    let rows: Vec<&Row> = rows.into_iter().collect();
+    let distinct = calls.iter().any(|call| call.distinct);
+    let accumulators = if !distinct && ctx.parallel.partitions(rows.len()) > 1 {
+        let count = rows.len();
+        let shared = &*ctx;
+        let partials = parallel::map_partitions(rows, &ctx.parallel, |rows| {
+            accumulate(&calls, rows, |i| shared.check_at(i))
+        })?;
+        ctx.tick_many(count)?;
+        let mut partials = partials.into_iter();
+        let mut accumulators = partials.next().unwrap_or_default();
+        for partial in partials {
+            for (accumulator, other) in accumulators.iter_mut().zip(partial) {
+                accumulator.merge(other)?;
+            }
+        }
+        accumulators
+    } else {
+        accumulate(&calls, rows, |_| ctx.tick())?
+    };
+
+    let results: Row = accumulators
+        .into_iter()
+        .enumerate()
+        .map(|(i, accumulator)| (result_key(i), accumulator.finish()))
+        .collect();
+    outputs
+        .into_iter()
+        .map(|(name, expr)| Ok((name.clone(), expr::evaluate(&expr, &results)?)))
+        .collect()
+}
+
+/// Run every call over `rows`, calling `tick` with each row's position
+fn accumulate<'a>(
+    calls: &[Aggregate],
+    rows: impl IntoIterator<Item = &'a Row>,
+    mut tick: impl FnMut(usize) -> QubeResult<()>,
+) -> QubeResult<Vec<Accumulator>> {
+    let mut accumulators: Vec<Accumulator> = calls.iter().map(Accumulator::new).collect();
+    for (i, row) in rows.into_iter().enumerate() {
+        tick(i)?;
+        for (call, accumulator) in calls.iter().zip(&mut accumulators) {
+            let value = match &call.argument {
+                Some(argument) => Some(expr::evaluate(argument, row)?),
+                None => None,
+            };
+            accumulator.add(value)?;
+        }
+    }
+    Ok(accumulators)
+}
+
+/// Name under which the result of the `i`th aggregate call is looked up
+fn result_key(i: usize) -> String {
+    format!("#{}", i)
+}
+
+/// Whether an expression is an aggregate call; with OVER it is a window
+/// function instead
+fn is_aggregate_call(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(function) => {
+            function.name.0.len() == 1
+                && function.over.is_none()
+                && AggregateFunction::from_name(&function.name.0[0].value).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// Parse an aggregate call, or return `None` for any other expression
+fn aggregate_call(expr: &Expr) -> QubeResult<Option<Aggregate>> {
+    let function = match expr {
+        Expr::Function(function) if is_aggregate_call(expr) => function,
+        _ => return Ok(None),
+    };
+    let kind = AggregateFunction::from_name(&function.name.0[0].value).unwrap();
+
+    let approx = kind == AggregateFunction::ApproxCountDistinct;
+    let (argument, precision) = match function.args.as_slice() {
+        [FunctionArg::Unnamed(FunctionArgExpr::Wildcard)]
+            if kind == AggregateFunction::Count && !function.distinct =>
+        {
+            (None, None)
+        }
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(argument))] => (Some(argument), None),
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(argument)), FunctionArg::Unnamed(FunctionArgExpr::Expr(precision))]
+            if approx =>
+        {
+            (Some(argument), Some(sketch_precision(precision)?))
+        }
+        _ => {
+            return Err(QubeError::QueryParse(format!(
+                "Invalid arguments to {}",
+                function.name
+            )))
+        }
+    };
+    if argument.is_some_and(contains_aggregate) {
+        return Err(QubeError::QueryParse(
+            "Aggregate function calls cannot be nested".to_string(),
+        ));
+    }
+    Ok(Some(Aggregate {
+        function: kind,
+        argument: argument.cloned(),
+        // The sketch already ignores repeated values
+        distinct: function.distinct && !approx,
+        precision: approx.then(|| precision.unwrap_or(hll::DEFAULT_PRECISION)),
+    }))
+}
+
+/// The argument and fresh running state of an aggregate used as a window
+/// function, e.g. `SUM(price) OVER (ORDER BY day)`, or `None` if the call
+/// is to another function. The argument is `None` for `COUNT(*)`.
+pub(crate) fn window_aggregate(
+    function: &Function,
+) -> QubeResult<Option<(Option<Expr>, Accumulator)>> {
+    let mut call = function.clone();
+    call.over = None;
+    let call = match aggregate_call(&Expr::Function(call))? {
+        Some(call) => call,
+        None => return Ok(None),
+    };
+    if call.distinct {
+        return Err(QubeError::Unsupported(format!(
+            "DISTINCT in window function {}",
+            function.name
+        )));
+    }
+    let accumulator = Accumulator::new(&call);
+    Ok(Some((call.argument, accumulator)))
+}
+
+/// The precision argument of `APPROX_COUNT_DISTINCT`, which must be a
+/// constant integer in the range a sketch accepts
+fn sketch_precision(expr: &Expr) -> QubeResult<u8> {
+    let value = expr::evaluate(expr, &Row::new())?;
+    value
+        .as_i64()
+        .and_then(|precision| u8::try_from(precision).ok())
+        .filter(|precision| (hll::MIN_PRECISION..=hll::MAX_PRECISION).contains(precision))
+        .ok_or_else(|| {
+            QubeError::QueryParse(format!(
+                "APPROX_COUNT_DISTINCT precision must be an integer between {} and {}, got {}",
+                hll::MIN_PRECISION,
+                hll::MAX_PRECISION,
+                value
+            ))
+        })
+}
+
+/// The first column an expression reads, other than aggregate results
+fn column_reference(expr: &Expr, results: usize) -> Option<String> {
+    let is_result = |ident: &Ident| (0..results).any(|i| ident.value == result_key(i));
+    let mut found = None;
+    let _ = visit_expressions(expr, |e| {
+        match e {
+            Expr::Identifier(ident) if !is_result(ident) => found = Some(ident.value.clone()),
+            Expr::CompoundIdentifier(idents) => {
+                found = Some(
+                    idents
+                        .iter()
+                        .map(|i| i.value.as_str())
+                        .collect::<Vec<_>>()
+                        .join("."),
+                )
+            }
+            _ => return ControlFlow::Continue(()),
+        }
+        ControlFlow::Break(())
+    });
+    found
+}
+
+/// Running state of one aggregate call
+#[derive(Clone)]
+pub(crate) struct Accumulator {
+    function: AggregateFunction,
+    /// Values seen so far, for DISTINCT aggregates
+    seen: Option<HashSet<Value>>,
+    count: i64,
+    int_sum: i64,
+    /// Sum once any input was a float (always for AVG)
+    float_sum: Option<f64>,
+    /// Current minimum or maximum
+    extreme: Option<Value>,
+    /// Distinct-count sketch, for `APPROX_COUNT_DISTINCT`
+    sketch: Option<HyperLogLog>,
+}
+
+impl Accumulator {
+    fn new(call: &Aggregate) -> Self {
+        Accumulator {
+            function: call.function,
+            seen: call.distinct.then(HashSet::new),
+            count: 0,
+            int_sum: 0,
+            float_sum: None,
+            extreme: None,
+            sketch: call
+                .precision
+                .map(|precision| HyperLogLog::new(precision).unwrap_or_default()),
+        }
+    }
+
+    /// Add one input; `None` stands for a row counted by `COUNT(*)`
+    pub(crate) fn add(&mut self, value: Option<Value>) -> QubeResult<()> {
+        let value = match value {
+            None => {
+                self.count += 1;
+                return Ok(());
+            }
+            Some(value) if value.is_null() => return Ok(()),
+            Some(value) => value,
+        };
+        if let Some(seen) = &mut self.seen {
+            if !seen.insert(value.clone()) {
+                return Ok(());
+            }
+        }
+        self.count += 1;
+
+        match self.function {
+            AggregateFunction::Count => {}
+            AggregateFunction::Sum | AggregateFunction::Avg => {
+                if !value.is_numeric() {
+                    return Err(QubeError::QueryParse(format!(
+                        "Cannot aggregate non-numeric value '{}'",
+                        value
+                    )));
+                }
+                let is_float = matches!(value, Value::Float32(_) | Value::Float64(_));
+                if is_float || self.float_sum.is_some() || self.function == AggregateFunction::Avg {
+                    let sum = self.float_sum.unwrap_or(self.int_sum as f64);
+                    self.float_sum = Some(sum + value.as_f64().unwrap_or(0.0));
+                } else {
+                    let v = value.as_i64().ok_or_else(expr::overflow)?;
+                    self.int_sum = self.int_sum.checked_add(v).ok_or_else(expr::overflow)?;
+                }
+            }
+            AggregateFunction::Min | AggregateFunction::Max => self.offer(value),
+            AggregateFunction::ApproxCountDistinct => {
+                if let Some(sketch) = &mut self.sketch {
+                    sketch.add(&value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep `value` as the minimum or maximum if it beats the current one;
+    /// the first of equal values is kept
+    fn offer(&mut self, value: Value) {
+        let wanted = if self.function == AggregateFunction::Min {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        let replace = match &self.extreme {
+            None => true,
+            Some(current) => value.compare(current) == Some(wanted),
+        };
+        if replace {
+            self.extreme = Some(value);
+        }
+    }
+
+    /// Fold in an accumulator of the same call that saw later rows. Only
+    /// used without DISTINCT, whose values seen would have to be merged.
+    fn merge(&mut self, other: Accumulator) -> QubeResult<()> {
+        self.count += other.count;
+        if self.float_sum.is_some() || other.float_sum.is_some() {
+            let sum = |accumulator: &Accumulator| {
+                accumulator.float_sum.unwrap_or(accumulator.int_sum as f64)
+            };
+            self.float_sum = Some(sum(self) + sum(&other));
+        } else {
+            self.int_sum = self
+                .int_sum
+                .checked_add(other.int_sum)
+                .ok_or_else(expr::overflow)?;
+        }
+        if let Some(value) = other.extreme {
+            self.offer(value);
+        }
+        if let (Some(sketch), Some(other)) = (&mut self.sketch, &other.sketch) {
+            sketch.merge(other)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Value {
+        match self.function {
+            AggregateFunction::Count => Value::Int64(self.count),
+            AggregateFunction::ApproxCountDistinct => Value::Int64(
+                self.sketch
+                    .map_or(0, |sketch| sketch.estimate().min(i64::MAX as u64) as i64),
+            ),
+            _ if self.count == 0 => Value::Null,
+            AggregateFunction::Sum => match self.float_sum {
+                Some(sum) => Value::Float64(sum),
+                None => Value::Int64(self.int_sum),
+            },
+            AggregateFunction::Avg => {
+                Value::Float64(self.float_sum.unwrap_or(0.0) / self.count as f64)
+            }
+            AggregateFunction::Min | AggregateFunction::Max => self.extreme.unwrap_or(Value::Null),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel::ParallelConfig;
+    use crate::query::{QueryEngine, QueryOptions};
+
+    /// The single row `sql` returns after `setup` has run
+    fn aggregate(setup: &str, sql: &str) -> Row {
+        let engine = QueryEngine::new();
+        engine.execute_script(setup).unwrap();
+        let mut result = engine.execute_script(sql).unwrap().pop().unwrap();
+        assert_eq!(result.rows.len(), 1);
+        result.rows.pop().unwrap()
+    }
+
+    #[test]
+    fn count_star_counts_rows_and_count_column_skips_nulls() {
+        let row = aggregate(
+            "CREATE TABLE t (id INT, v INT);
+             INSERT INTO t VALUES (1, NULL), (2, 5), (3, NULL), (4, 5)",
+            "SELECT COUNT(*) AS rows, COUNT(v) AS present, COUNT(DISTINCT v) AS values FROM t",
+        );
+        assert_eq!(row["rows"], Value::Int64(4));
+        assert_eq!(row["present"], Value::Int64(2));
+        assert_eq!(row["values"], Value::Int64(1));
+    }
+
+    #[test]
+    fn sum_and_avg_of_only_nulls_are_null() {
+        let row = aggregate(
+            "CREATE TABLE t (id INT, v INT);
+             INSERT INTO t VALUES (1, NULL), (2, NULL)",
+            "SELECT SUM(v) AS s, AVG(v) AS a, COUNT(v) AS c, COUNT(*) AS n FROM t",
+        );
+        assert_eq!(row["s"], Value::Null);
+        assert_eq!(row["a"], Value::Null);
+        assert_eq!(row["c"], Value::Int64(0));
+        assert_eq!(row["n"], Value::Int64(2));
+
+        let row = aggregate(
+            "CREATE TABLE t (id INT, v INT);
+             INSERT INTO t VALUES (1, NULL), (2, 4), (3, NULL), (4, 8)",
+            "SELECT SUM(v) AS s, AVG(v) AS a FROM t",
+        );
+        assert_eq!(row["s"], Value::Int64(12));
+        assert_eq!(row["a"], Value::Float64(6.0));
+    }
+
+    #[test]
+    fn min_and_max_skip_nulls() {
+        let row = aggregate(
+            "CREATE TABLE t (id INT, v INT);
+             INSERT INTO t VALUES (1, NULL), (2, 3), (3, NULL), (4, 7), (5, 1)",
+            "SELECT MIN(v) AS lo, MAX(v) AS hi FROM t",
+        );
+        assert_eq!(row["lo"], Value::Int32(1));
+        assert_eq!(row["hi"], Value::Int32(7));
+
+        let row = aggregate(
+            "CREATE TABLE t (id INT, v INT);
+             INSERT INTO t VALUES (1, NULL)",
+            "SELECT MIN(v) AS lo, MAX(v) AS hi FROM t",
+        );
+        assert_eq!(row["lo"], Value::Null);
+        assert_eq!(row["hi"], Value::Null);
+    }
+
+    #[test]
+    fn merged_partitions_skip_nulls_too() {
+        let call = |function| Aggregate {
+            function,
+            argument: None,
+            distinct: false,
+            precision: None,
+        };
+        for function in [
+            AggregateFunction::Sum,
+            AggregateFunction::Avg,
+            AggregateFunction::Min,
+        ] {
+            let mut first = Accumulator::new(&call(function));
+            let mut second = Accumulator::new(&call(function));
+            first.add(Some(Value::Null)).unwrap();
+            second.add(Some(Value::Null)).unwrap();
+            first.merge(second).unwrap();
+            assert_eq!(first.finish(), Value::Null);
+        }
+
+        let mut first = Accumulator::new(&call(AggregateFunction::Max));
+        let mut second = Accumulator::new(&call(AggregateFunction::Max));
+        first.add(Some(Value::Int32(2))).unwrap();
+        second.add(Some(Value::Null)).unwrap();
+        first.merge(second).unwrap();
+        assert_eq!(first.finish(), Value::Int32(2));
+    }
+
+    #[test]
+    fn integer_sum_overflow_is_an_error() {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE t (v BIGINT);
+                 INSERT INTO t VALUES (9223372036854775807), (1)",
+            )
+            .unwrap();
+        let error = engine.execute_script("SELECT SUM(v) FROM t").unwrap_err();
+        assert_eq!(error.to_string(), expr::overflow().to_string());
+
+        let call = Aggregate {
+            function: AggregateFunction::Sum,
+            argument: None,
+            distinct: false,
+            precision: None,
+        };
+        let mut first = Accumulator::new(&call);
+        let mut second = Accumulator::new(&call);
+        first.add(Some(Value::Int64(i64::MAX))).unwrap();
+        second.add(Some(Value::Int64(1))).unwrap();
+        assert!(first.merge(second).is_err());
+    }
+
+    #[test]
+    fn partial_aggregates_merge_to_the_serial_result() {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script("CREATE TABLE t (id INT, v INT, f DOUBLE)")
+            .unwrap();
+        let values: Vec<String> = (0..100)
+            .map(|id| match id % 10 {
+                0 => format!("({}, NULL, NULL)", id),
+                _ => format!("({}, {}, {}.5)", id, id % 9, id % 4),
+            })
+            .collect();
+        engine
+            .execute_script(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        let sql = "SELECT COUNT(*) AS n, COUNT(v) AS c, SUM(v) AS s, AVG(v) AS a, \
+                   MIN(v) AS lo, MAX(f) AS hi, SUM(f) AS fs, COUNT(DISTINCT v) AS d FROM t";
+        let row = |config: ParallelConfig| {
+            engine.set_parallelism(config);
+            let options = QueryOptions::default();
+            let mut result = engine.execute_sql_with_options(sql, &options).unwrap();
+            result.rows.pop().unwrap()
+        };
+        let serial = row(ParallelConfig::serial());
+        let parallel = row(ParallelConfig {
+            threads: 3,
+            min_rows: 2,
+        });
+        assert_eq!(parallel, serial);
+        assert_eq!(serial["c"], Value::Int64(90));
+        assert_eq!(serial["d"], Value::Int64(9));
+    }
+
+    #[test]
+    fn approx_count_distinct_estimates_within_its_precision() {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script("CREATE TABLE t (id INT, v INT, s TEXT)")
+            .unwrap();
+        let values: Vec<String> = (0..3_000)
+            .map(|id| match id % 100 {
+                0 => format!("({}, NULL, NULL)", id),
+                _ => format!("({}, {}, 'x{}')", id, id % 1_000, id % 7),
+            })
+            .collect();
+        engine
+            .execute_script(&format!("INSERT INTO t VALUES {}", values.join(", ")))
+            .unwrap();
+        let sql = "SELECT APPROX_COUNT_DISTINCT(v) AS v, APPROX_COUNT_DISTINCT(s) AS s, \
+                   APPROX_COUNT_DISTINCT(v, 8) AS rough, COUNT(DISTINCT v) AS exact FROM t";
+        let row = |config: ParallelConfig| {
+            engine.set_parallelism(config);
+            let options = QueryOptions::default();
+            let mut result = engine.execute_sql_with_options(sql, &options).unwrap();
+            result.rows.pop().unwrap()
+        };
+        let serial = row(ParallelConfig::serial());
+        assert_eq!(serial["exact"], Value::Int64(990));
+        let estimate = |name: &str| serial[name].as_i64().unwrap();
+        assert!((estimate("v") - 990).abs() < 990 * 4 / 100, "{:?}", serial);
+        assert_eq!(estimate("s"), 7);
+        assert!(
+            (estimate("rough") - 990).abs() < 990 * 30 / 100,
+            "{:?}",
+            serial
+        );
+        // Sketches merged from partitions match one that saw every row
+        let parallel = row(ParallelConfig {
+            threads: 4,
+            min_rows: 2,
+        });
+        assert_eq!(parallel, serial);
+
+        for sql in [
+            "SELECT APPROX_COUNT_DISTINCT(v, 3) AS n FROM t",
+            "SELECT APPROX_COUNT_DISTINCT(v, id) AS n FROM t",
+            "SELECT APPROX_COUNT_DISTINCT(v, 10, 1) AS n FROM t",
+        ] {
+            assert!(engine.execute_script(sql).is_err(), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,1906 @@
+//! REST API server for QubeDB
+//!
+//! Serves an embedded database over HTTP. Requests and responses are JSON;
+//! errors are returned as `{"error": "..."}` with a matching status code.
+//!
+//! - `GET  /health`
+//! - `GET  /stats` returns `{"latency": [{"op": "select", "count": 10,
+//!   "p50_us": 120, "p95_us": 480, "p99_us": 910, "max_us": 1500}]}`, the
+//!   latency percentiles of each operation type (see `metrics`)
+//! - `GET  /metrics` returns the same percentiles in the Prometheus text
+//!   format
+//! - `POST /query` with `{"query": "SELECT ..."}`, optionally with
+//!   `"number_format": "string"` or `{"fixed": 2}` to send floats as exact
+//!   or rounded strings (see `expr::NumberFormat`). With `"page_size": n`
+//!   a SELECT returns one page and a `next_cursor`, passed back as
+//!   `"cursor"` for the page after it (see `pagination`)
+//! - `GET  /databases`
+//! - `GET  /databases/{database}/quota` returns the database's quota and usage
+//! - `PUT  /databases/{database}/quota` with `{"max_rows": 1000, "max_bytes":
+//!   null, "max_indexes": 10}` sets it; `null` or a missing field is
+//!   unlimited (see `quota`)
+//! - `GET  /tables`
+//! - `GET  /tables/{table}` returns the table definition
+//! - `POST /tables/{table}` with a row object such as `{"id": 1, "name": "a"}`
+//! - `GET  /vectors/{collection}`
+//! - `POST /vectors/{collection}` with `{"id": "a", "vector": [0.1, 0.2]}`
+//! - `POST /vectors/{collection}/search` with `{"vector": [0.1, 0.2], "k": 10}`
+//! - `POST /graph/{graph}/nodes` with `{"id": "n1", "properties": {...}}`
+//! - `POST /graph/{graph}/edges` with `{"from": "n1", "to": "n2", "properties": {...}}`
+//! - `GET  /wal?from=1&limit=100` returns `{"changes": [{"lsn": 1, "sql":
+//!   "..."}]}`, the committed changes from that LSN on, when the server was
+//!   given a WAL source to publish (see `subscriber`)
+//! - `POST /rpc` speaks JSON-RPC 2.0, single calls or batches, with the
+//!   methods `query`, `insert`, `get`, `listTables`, `storeVector` and
+//!   `vectorSearch` (see `rpc_call`)
+//!
+//! An OpenAPI 3.0 description of these endpoints is served at
+//! `/openapi.json`, with a Swagger UI for it at `/docs`. A small admin page
+//! at `/admin` lists tables and runs ad-hoc SQL through the same endpoints;
+//! its files are compiled into the binary from `src/admin/`.
+
+use crate::embedded::EmbeddedQubeDB;
+use crate::error::{QubeError, QubeResult};
+use crate::expr::{self, NumberFormat};
+use crate::metrics;
+use crate::quota::Quota;
+use crate::subscriber::{WalSource, DEFAULT_BATCH_SIZE};
+use crate::types::{QueryResult, Row};
+use axum::body::Bytes;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// REST API server configuration
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    pub host: String,
+    pub port: u16,
+    /// Answer cross-origin requests from browsers. When false no CORS
+    /// headers are sent at all.
+    pub enable_cors: bool,
+    /// Which cross-origin requests are answered when `enable_cors` is set
+    pub cors: CorsConfig,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            enable_cors: true,
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+/// Cross-origin (CORS) policy for browser clients
+///
+/// Only origins on the allowlist get CORS headers back, so browsers block
+/// every other cross-origin caller. The default allowlist is empty, which
+/// limits browsers to same-origin pages such as `/admin` and `/docs`.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://app.example.com`.
+    /// `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Let browsers send cookies and `Authorization` headers. Not allowed
+    /// together with a `*` origin.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response, in seconds
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "DELETE", "OPTIONS"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: vec!["Content-Type".to_string()],
+            allow_credentials: false,
+            max_age: Some(600),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Environment variable holding a comma-separated origin allowlist
+    pub const ORIGINS_ENV: &'static str = "QUBEDB_CORS_ORIGINS";
+
+    /// The default policy with the origins listed in `QUBEDB_CORS_ORIGINS`
+    pub fn from_env() -> Self {
+        let allowed_origins = std::env::var(Self::ORIGINS_ENV)
+            .map(|origins| {
+                origins
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        CorsConfig {
+            allowed_origins,
+            ..Self::default()
+        }
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Whether requests from `origin` may be answered
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allows_any_origin() || self.allowed_origins.iter().any(|o| o == origin)
+    }
+
+    /// Reject policies browsers would refuse to honour
+    pub fn validate(&self) -> QubeResult<()> {
+        if self.allow_credentials && self.allows_any_origin() {
+            return Err(QubeError::Config(
+                "CORS credentials cannot be allowed for a '*' origin".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// CORS headers for a response to a request carrying `origin`. Empty
+    /// when the request has no `Origin` header or the origin is not allowed.
+    pub fn response_headers(&self, origin: Option<&str>) -> Vec<(&'static str, String)> {
+        let origin = match origin {
+            Some(origin) if self.allows_origin(origin) => origin,
+            _ => return Vec::new(),
+        };
+        let mut headers = Vec::new();
+        if self.allows_any_origin() {
+            headers.push(("Access-Control-Allow-Origin", "*".to_string()));
+        } else {
+            headers.push(("Access-Control-Allow-Origin", origin.to_string()));
+            headers.push(("Vary", "Origin".to_string()));
+        }
+        if self.allow_credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        headers
+    }
+
+    /// Headers for a response to a preflight `OPTIONS` request: the
+    /// response headers plus the allowed methods and headers
+    pub fn preflight_headers(&self, origin: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut headers = self.response_headers(origin);
+        if headers.is_empty() {
+            return headers;
+        }
+        headers.push((
+            "Access-Control-Allow-Methods",
+            self.allowed_methods.join(", "),
+        ));
+        headers.push((
+            "Access-Control-Allow-Headers",
+            self.allowed_headers.join(", "),
+        ));
+        if let Some(max_age) = self.max_age {
+            headers.push(("Access-Control-Max-Age", max_age.to_string()));
+        }
+        headers
+    }
+
+    /// The equivalent tower-http layer, which also answers preflights
+    fn layer(&self) -> QubeResult<CorsLayer> {
+        self.validate()?;
+        let invalid = |what: &str, value: &str| {
+            QubeError::Config(format!("Invalid CORS {}: '{}'", what, value))
+        };
+
+        let origins = if self.allows_any_origin() {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o).map_err(|_| invalid("origin", o)))
+                .collect::<QubeResult<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|m| Method::from_bytes(m.as_bytes()).map_err(|_| invalid("method", m)))
+            .collect::<QubeResult<Vec<_>>>()?;
+        let headers = self
+            .allowed_headers
+            .iter()
+            .map(|h| HeaderName::from_bytes(h.as_bytes()).map_err(|_| invalid("header", h)))
+            .collect::<QubeResult<Vec<_>>>()?;
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(Duration::from_secs(max_age));
+        }
+        Ok(layer)
+    }
+}
+
+type SharedDb = Arc<RwLock<EmbeddedQubeDB>>;
+
+/// HTTP front end for an embedded database
+pub struct RestApiServer {
+    config: ApiConfig,
+    db: SharedDb,
+    wal: Option<Arc<dyn WalSource>>,
+}
+
+impl RestApiServer {
+    /// Create a server for `db`
+    pub fn new(config: ApiConfig, db: EmbeddedQubeDB) -> Self {
+        Self::with_shared(config, Arc::new(RwLock::new(db)))
+    }
+
+    /// Create a server for a database that is also used elsewhere
+    pub fn with_shared(config: ApiConfig, db: Arc<RwLock<EmbeddedQubeDB>>) -> Self {
+        RestApiServer {
+            config,
+            db,
+            wal: None,
+        }
+    }
+
+    /// Publish the changes of `source` at `GET /wal`, for replicas to
+    /// follow with `subscriber::HttpWalSource`
+    pub fn with_wal_source(mut self, source: Arc<dyn WalSource>) -> Self {
+        self.wal = Some(source);
+        self
+    }
+
+    pub fn config(&self) -> &ApiConfig {
+        &self.config
+    }
+
+    /// The database the server is serving
+    pub fn database(&self) -> Arc<RwLock<EmbeddedQubeDB>> {
+        Arc::clone(&self.db)
+    }
+
+    /// Build the request router, failing if the CORS policy is invalid
+    pub fn router(&self) -> QubeResult<Router> {
+        let router = Router::new()
+            .route("/health", get(health))
+            .route("/stats", get(stats))
+            .route("/metrics", get(prometheus_metrics))
+            .route("/openapi.json", get(openapi_json))
+            .route("/docs", get(docs))
+            .route("/admin", get(admin_page))
+            .route("/admin/admin.js", get(admin_script))
+            .route("/admin/admin.css", get(admin_style))
+            .route("/query", post(query))
+            .route("/databases", get(list_databases))
+            .route("/databases/:database/quota", get(get_quota).put(set_quota))
+            .route("/tables", get(list_tables))
+            .route("/tables/:table", get(describe_table).post(insert_row))
+            .route(
+                "/vectors/:collection",
+                get(get_collection).post(store_vector),
+            )
+            .route("/vectors/:collection/search", post(search_vectors))
+            .route("/graph/:graph/nodes", post(store_node))
+            .route("/graph/:graph/edges", post(store_edge))
+            .route("/rpc", post(rpc))
+            .fallback(not_found)
+            .with_state(Arc::clone(&self.db));
+        let router = match &self.wal {
+            Some(source) => router.merge(
+                Router::new()
+                    .route("/wal", get(wal_changes))
+                    .with_state(Arc::clone(source)),
+            ),
+            None => router,
+        };
+
+        if self.config.enable_cors {
+            Ok(router.layer(self.config.cors.layer()?))
+        } else {
+            Ok(router)
+        }
+    }
+
+    /// Listen on the configured address and serve requests until the
+    /// process stops
+    pub async fn start(&self) -> QubeResult<()> {
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve requests on an already bound listener, e.g. one on an
+    /// ephemeral port
+    pub async fn serve(&self, listener: TcpListener) -> QubeResult<()> {
+        axum::serve(listener, self.router()?).await?;
+        Ok(())
+    }
+}
+
+/// An error response: `{"error": message}` with a status code
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, message.into())
+    }
+}
+
+impl From<QubeError> for ApiError {
+    fn from(error: QubeError) -> Self {
+        let status =
+            StatusCode::from_u16(error.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        ApiError(status, error.to_string())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::bad_request(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type ApiResult = Result<(StatusCode, Json<JsonValue>), ApiError>;
+
+fn ok(body: JsonValue) -> ApiResult {
+    Ok((StatusCode::OK, Json(body)))
+}
+
+fn created(body: JsonValue) -> ApiResult {
+    Ok((StatusCode::CREATED, Json(body)))
+}
+
+async fn health() -> ApiResult {
+    ok(json!({ "status": "healthy", "version": env!("CARGO_PKG_VERSION") }))
+}
+
+/// GET /stats
+async fn stats() -> ApiResult {
+    ok(json!({ "latency": metrics::registry().summaries() }))
+}
+
+/// GET /metrics
+async fn prometheus_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::registry().render_prometheus(),
+    )
+}
+
+/// GET /openapi.json
+async fn openapi_json() -> Json<JsonValue> {
+    Json(openapi())
+}
+
+/// GET /docs
+async fn docs() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+/// GET /admin
+async fn admin_page() -> Html<&'static str> {
+    Html(ADMIN_HTML)
+}
+
+async fn admin_script() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        ADMIN_JS,
+    )
+}
+
+async fn admin_style() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/css; charset=utf-8")],
+        ADMIN_CSS,
+    )
+}
+
+/// Query string of GET /wal
+#[derive(Deserialize)]
+struct WalPage {
+    from: u64,
+    limit: Option<usize>,
+}
+
+/// GET /wal
+async fn wal_changes(
+    State(source): State<Arc<dyn WalSource>>,
+    page: Result<axum::extract::Query<WalPage>, QueryRejection>,
+) -> ApiResult {
+    let axum::extract::Query(page) =
+        page.map_err(|rejection| ApiError::bad_request(rejection.body_text()))?;
+    let changes = source.fetch(page.from, page.limit.unwrap_or(DEFAULT_BATCH_SIZE).max(1))?;
+    let changes: Vec<JsonValue> = changes
+        .into_iter()
+        .map(|change| {
+            let sql: Vec<String> = change.statements.iter().map(|s| s.to_string()).collect();
+            json!({ "lsn": change.lsn, "sql": sql.join(";\n") })
+        })
+        .collect();
+    ok(json!({ "changes": changes }))
+}
+
+async fn not_found() -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, "Endpoint not found".to_string())
+}
+
+/// POST /query
+async fn query(
+    State(db): State<SharedDb>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let sql = string_field(&body, "query")?;
+    let format = match body.get("number_format") {
+        None | Some(JsonValue::Null) => NumberFormat::Native,
+        Some(format) => serde_json::from_value(format.clone())
+            .map_err(|e| ApiError::bad_request(format!("Invalid 'number_format': {}", e)))?,
+    };
+    let page_size = match body.get("page_size") {
+        None | Some(JsonValue::Null) => None,
+        Some(size) => Some(
+            size.as_u64()
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|&size| size > 0)
+                .ok_or_else(|| ApiError::bad_request("'page_size' must be a positive integer"))?,
+        ),
+    };
+    let cursor = match body.get("cursor") {
+        None | Some(JsonValue::Null) => None,
+        Some(_) if page_size.is_none() => {
+            return Err(ApiError::bad_request("'cursor' requires 'page_size'"))
+        }
+        Some(_) => Some(string_field(&body, "cursor")?),
+    };
+
+    let db = db.read().await;
+    match page_size {
+        Some(page_size) => {
+            let page = db.execute_page(sql, page_size, cursor)?;
+            let mut body = result_json(&page.result, format);
+            body["next_cursor"] = json!(page.next_cursor);
+            ok(body)
+        }
+        None => ok(result_json(&db.execute(sql).await?, format)),
+    }
+}
+
+/// GET /databases
+async fn list_databases(State(db): State<SharedDb>) -> ApiResult {
+    ok(json!({ "databases": db.read().await.list_databases() }))
+}
+
+/// GET /databases/{database}/quota
+async fn get_quota(State(db): State<SharedDb>, Path(database): Path<String>) -> ApiResult {
+    quota_json(&*db.read().await, &database)
+}
+
+/// PUT /databases/{database}/quota
+async fn set_quota(
+    State(db): State<SharedDb>,
+    Path(database): Path<String>,
+    body: Result<Json<Quota>, JsonRejection>,
+) -> ApiResult {
+    let Json(quota) = body?;
+    let db = db.read().await;
+    db.set_quota(&database, quota)?;
+    quota_json(&db, &database)
+}
+
+fn quota_json(db: &EmbeddedQubeDB, database: &str) -> ApiResult {
+    ok(json!({
+        "database": database,
+        "quota": db.quota(database)?,
+        "usage": db.quota_usage(database)?,
+    }))
+}
+
+/// GET /tables
+async fn list_tables(State(db): State<SharedDb>) -> ApiResult {
+    ok(json!({ "tables": db.read().await.list_tables() }))
+}
+
+/// GET /tables/{table}
+async fn describe_table(State(db): State<SharedDb>, Path(table): Path<String>) -> ApiResult {
+    let table = db.read().await.describe_table(&table)?;
+    ok(serde_json::to_value(table).map_err(|e| QubeError::Serialization(e.to_string()))?)
+}
+
+/// POST /tables/{table}
+async fn insert_row(
+    State(db): State<SharedDb>,
+    Path(table): Path<String>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let fields = body
+        .as_object()
+        .ok_or_else(|| ApiError::bad_request("Body must be a JSON object"))?;
+
+    let db = db.read().await;
+    let definition = db.describe_table(&table)?;
+    let row = fields
+        .iter()
+        .map(|(name, value)| {
+            let column = definition
+                .columns
+                .iter()
+                .find(|c| &c.name == name)
+                .ok_or_else(|| QubeError::ColumnNotFound(format!("{}.{}", table, name)))?;
+            Ok((
+                name.clone(),
+                expr::from_json(value, Some(&column.data_type))?,
+            ))
+        })
+        .collect::<QubeResult<Row>>()?;
+
+    let result = db.insert_row(&table, &row)?;
+    created(json!({
+        "affected_rows": result.affected_rows,
+        "last_insert_id": result.last_insert_id,
+    }))
+}
+
+/// GET /vectors/{collection}
+async fn get_collection(State(db): State<SharedDb>, Path(collection): Path<String>) -> ApiResult {
+    let db = db.read().await;
+    let index = db
+        .vector_collection(&collection)
+        .ok_or_else(|| QubeError::NotFound(format!("Vector collection '{}'", collection)))?;
+    ok(json!({
+        "name": index.name(),
+        "dimension": index.dimensions(),
+        "metric": index.metric().to_string(),
+        "count": index.len(),
+    }))
+}
+
+/// POST /vectors/{collection}
+async fn store_vector(
+    State(db): State<SharedDb>,
+    Path(collection): Path<String>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let id = string_field(&body, "id")?;
+    let vector = vector_field(&body)?;
+
+    db.write().await.store_vector(&collection, id, &vector)?;
+    created(json!({ "collection": collection, "id": id }))
+}
+
+/// POST /vectors/{collection}/search
+async fn search_vectors(
+    State(db): State<SharedDb>,
+    Path(collection): Path<String>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let vector = vector_field(&body)?;
+    let k = match body.get("k") {
+        None => 10,
+        Some(k) => k
+            .as_u64()
+            .ok_or_else(|| ApiError::bad_request("'k' must be a non-negative integer"))?
+            as usize,
+    };
+
+    let results = db.read().await.search_vectors(&collection, &vector, k)?;
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|(id, score)| json!({ "id": id, "score": score }))
+        .collect();
+    ok(json!({ "results": results }))
+}
+
+/// POST /graph/{graph}/nodes
+async fn store_node(
+    State(db): State<SharedDb>,
+    Path(graph): Path<String>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let id = string_field(&body, "id")?;
+    let properties = properties_field(&body)?;
+
+    db.write().await.store_node(&graph, id, properties)?;
+    created(json!({ "graph": graph, "id": id }))
+}
+
+/// POST /graph/{graph}/edges
+async fn store_edge(
+    State(db): State<SharedDb>,
+    Path(graph): Path<String>,
+    body: Result<Json<JsonValue>, JsonRejection>,
+) -> ApiResult {
+    let Json(body) = body?;
+    let from = string_field(&body, "from")?;
+    let to = string_field(&body, "to")?;
+    let properties = properties_field(&body)?;
+
+    db.write().await.store_edge(&graph, from, to, properties)?;
+    created(json!({ "graph": graph, "from": from, "to": to }))
+}
+
+/// JSON-RPC 2.0 error codes
+mod rpc_error {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// A call the database failed; the HTTP status the REST endpoint would
+    /// have answered is in the error's `data`
+    pub const SERVER_ERROR: i64 = -32000;
+}
+
+/// POST /rpc
+///
+/// The body is one JSON-RPC request or a batch of them. Notifications,
+/// requests without an `id`, are run but not answered, so a batch of
+/// nothing else gets `204 No Content`.
+async fn rpc(State(db): State<SharedDb>, body: Bytes) -> Response {
+    let request: JsonValue = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = rpc_failure(JsonValue::Null, rpc_error::PARSE_ERROR, e.to_string(), None);
+            return Json(error).into_response();
+        }
+    };
+    let response = match request {
+        JsonValue::Array(calls) if calls.is_empty() => Some(rpc_failure(
+            JsonValue::Null,
+            rpc_error::INVALID_REQUEST,
+            "Empty batch",
+            None,
+        )),
+        JsonValue::Array(calls) => {
+            let mut responses = Vec::new();
+            for call in calls {
+                responses.extend(rpc_call(&db, call).await);
+            }
+            (!responses.is_empty()).then_some(JsonValue::Array(responses))
+        }
+        call => rpc_call(&db, call).await,
+    };
+    match response {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Run one JSON-RPC request through the REST handler it maps to, returning
+/// its response, or `None` for a notification. Parameters are passed by
+/// name:
+///
+/// - `query`: the body of `POST /query`
+/// - `insert`: `{"table": "users", "row": {...}}`
+/// - `get`: `{"table": "users"}`, the table definition
+/// - `listTables`: no parameters
+/// - `storeVector`: `{"collection": "docs", "id": "a", "vector": [...]}`
+/// - `vectorSearch`: `{"collection": "docs", "vector": [...], "k": 10}`
+async fn rpc_call(db: &SharedDb, call: JsonValue) -> Option<JsonValue> {
+    let id = call.get("id").cloned();
+    let invalid = |message: &str| {
+        Some(rpc_failure(
+            id.clone().unwrap_or(JsonValue::Null),
+            rpc_error::INVALID_REQUEST,
+            message,
+            None,
+        ))
+    };
+    if !call.is_object() {
+        return invalid("Request must be a JSON object");
+    }
+    if call.get("jsonrpc").and_then(JsonValue::as_str) != Some("2.0") {
+        return invalid("'jsonrpc' must be \"2.0\"");
+    }
+    if !matches!(
+        id,
+        None | Some(JsonValue::Null | JsonValue::String(_) | JsonValue::Number(_))
+    ) {
+        return Some(rpc_failure(
+            JsonValue::Null,
+            rpc_error::INVALID_REQUEST,
+            "'id' must be a string, a number or null",
+            None,
+        ));
+    }
+    let Some(method) = call.get("method").and_then(JsonValue::as_str) else {
+        return invalid("'method' must be a string");
+    };
+    let params = match call.get("params") {
+        None | Some(JsonValue::Null) => json!({}),
+        Some(params @ JsonValue::Object(_)) => params.clone(),
+        Some(_) => {
+            return id.map(|id| {
+                rpc_failure(
+                    id,
+                    rpc_error::INVALID_PARAMS,
+                    "'params' must be an object",
+                    None,
+                )
+            })
+        }
+    };
+
+    let state = || State(Arc::clone(db));
+    let outcome = match method {
+        "query" => Some(query(state(), Ok(Json(params))).await),
+        "insert" => Some(match string_field(&params, "table") {
+            Ok(table) => {
+                let row = params.get("row").cloned().unwrap_or(JsonValue::Null);
+                insert_row(state(), Path(table.to_string()), Ok(Json(row))).await
+            }
+            Err(e) => Err(e),
+        }),
+        "get" => Some(match string_field(&params, "table") {
+            Ok(table) => describe_table(state(), Path(table.to_string())).await,
+            Err(e) => Err(e),
+        }),
+        "listTables" => Some(list_tables(state()).await),
+        "storeVector" | "vectorSearch" => Some(match string_field(&params, "collection") {
+            Ok(collection) => {
+                let collection = Path(collection.to_string());
+                if method == "storeVector" {
+                    store_vector(state(), collection, Ok(Json(params))).await
+                } else {
+                    search_vectors(state(), collection, Ok(Json(params))).await
+                }
+            }
+            Err(e) => Err(e),
+        }),
+        _ => None,
+    };
+
+    let id = id?;
+    Some(match outcome {
+        Some(Ok((_, Json(result)))) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Some(Err(ApiError(status, message))) => {
+            let code = if status == StatusCode::BAD_REQUEST {
+                rpc_error::INVALID_PARAMS
+            } else {
+                rpc_error::SERVER_ERROR
+            };
+            let data = json!({ "status": status.as_u16() });
+            rpc_failure(id, code, message, Some(data))
+        }
+        None => rpc_failure(
+            id,
+            rpc_error::METHOD_NOT_FOUND,
+            format!("Method '{}' not found", method),
+            None,
+        ),
+    })
+}
+
+/// A JSON-RPC error response
+fn rpc_failure(
+    id: JsonValue,
+    code: i64,
+    message: impl Into<String>,
+    data: Option<JsonValue>,
+) -> JsonValue {
+    let mut error = json!({ "code": code, "message": message.into() });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+/// A query result as `{"columns": [...], "rows": [[...], ...], ...}`, with
+/// each row's values in column order
+fn result_json(result: &QueryResult, format: NumberFormat) -> JsonValue {
+    let rows: Vec<Vec<JsonValue>> = result
+        .rows
+        .iter()
+        .map(|row| {
+            result
+                .columns
+                .iter()
+                .map(|column| {
+                    row.get(column)
+                        .map(|value| expr::to_json_with(value, format))
+                        .unwrap_or(JsonValue::Null)
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut body = json!({
+        "columns": result.columns,
+        "rows": rows,
+        "affected_rows": result.affected_rows,
+        "execution_time_ms": result.execution_time.as_secs_f64() * 1000.0,
+    });
+    if let Some(id) = result.last_insert_id {
+        body["last_insert_id"] = json!(id);
+    }
+    body
+}
+
+fn string_field<'a>(body: &'a JsonValue, name: &str) -> Result<&'a str, ApiError> {
+    body.get(name)
+        .and_then(JsonValue::as_str)
+        .ok_or_else(|| ApiError::bad_request(format!("'{}' must be a string", name)))
+}
+
+/// Parse the `vector` field as an array of finite numbers
+fn vector_field(body: &JsonValue) -> Result<Vec<f32>, ApiError> {
+    let items = body
+        .get("vector")
+        .and_then(JsonValue::as_array)
+        .ok_or_else(|| ApiError::bad_request("'vector' must be an array of numbers"))?;
+    items
+        .iter()
+        .map(|item| {
+            item.as_f64()
+                .map(|v| v as f32)
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| ApiError::bad_request(format!("Invalid vector component: {}", item)))
+        })
+        .collect()
+}
+
+/// Parse the optional `properties` object into a row
+fn properties_field(body: &JsonValue) -> Result<Row, ApiError> {
+    match body.get("properties") {
+        None | Some(JsonValue::Null) => Ok(Row::new()),
+        Some(JsonValue::Object(properties)) => Ok(properties
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), expr::from_json(value, None)?)))
+            .collect::<QubeResult<Row>>()?),
+        Some(_) => Err(ApiError::bad_request("'properties' must be an object")),
+    }
+}
+
+/// Swagger UI page, loading the viewer from a CDN and pointing it at
+/// `/openapi.json`
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>QubeDB API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+/// Admin page assets, served under `/admin`
+const ADMIN_HTML: &str = include_str!("admin/index.html");
+const ADMIN_JS: &str = include_str!("admin/admin.js");
+const ADMIN_CSS: &str = include_str!("admin/admin.css");
+
+/// OpenAPI 3.0 description of the endpoints served by `RestApiServer`
+pub fn openapi() -> JsonValue {
+    fn schema(name: &str) -> JsonValue {
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+    fn body(name: &str) -> JsonValue {
+        json!({
+            "required": true,
+            "content": { "application/json": { "schema": schema(name) } }
+        })
+    }
+    fn response(description: &str, name: &str) -> JsonValue {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": schema(name) } }
+        })
+    }
+    fn path_param(name: &str) -> JsonValue {
+        json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } })
+    }
+    let error = response("Error", "Error");
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "QubeDB API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Multi-model database: SQL tables, vectors and graphs"
+        },
+        "paths": {
+            "/health": {
+                "get": {
+                    "summary": "Check that the server is running",
+                    "operationId": "health",
+                    "responses": { "200": response("Server is healthy", "Health") }
+                }
+            },
+            "/stats": {
+                "get": {
+                    "summary": "Latency percentiles of each operation type",
+                    "operationId": "stats",
+                    "responses": { "200": response("Latency percentiles", "Stats") }
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Latency percentiles in the Prometheus text format",
+                    "operationId": "metrics",
+                    "responses": {
+                        "200": {
+                            "description": "Prometheus metrics",
+                            "content": { "text/plain": { "schema": { "type": "string" } } }
+                        }
+                    }
+                }
+            },
+            "/query": {
+                "post": {
+                    "summary": "Execute a SQL statement",
+                    "operationId": "query",
+                    "requestBody": body("QueryRequest"),
+                    "responses": {
+                        "200": response("Statement result", "QueryResult"),
+                        "400": error,
+                        "404": error,
+                        "409": error
+                    }
+                }
+            },
+            "/databases": {
+                "get": {
+                    "summary": "List databases",
+                    "operationId": "listDatabases",
+                    "responses": { "200": response("Database names", "DatabaseList") }
+                }
+            },
+            "/databases/{database}/quota": {
+                "parameters": [path_param("database")],
+                "get": {
+                    "summary": "Get a database's quota and usage",
+                    "operationId": "getQuota",
+                    "responses": {
+                        "200": response("Quota and usage", "QuotaStatus"),
+                        "404": error
+                    }
+                },
+                "put": {
+                    "summary": "Set a database's quota",
+                    "operationId": "setQuota",
+                    "requestBody": body("Quota"),
+                    "responses": {
+                        "200": response("Quota and usage", "QuotaStatus"),
+                        "400": error,
+                        "404": error
+                    }
+                }
+            },
+            "/tables": {
+                "get": {
+                    "summary": "List tables",
+                    "operationId": "listTables",
+                    "responses": { "200": response("Table names", "TableList") }
+                }
+            },
+            "/tables/{table}": {
+                "parameters": [path_param("table")],
+                "get": {
+                    "summary": "Describe a table",
+                    "operationId": "describeTable",
+                    "responses": {
+                        "200": response("Table definition", "Table"),
+                        "404": error
+                    }
+                },
+                "post": {
+                    "summary": "Insert a row",
+                    "operationId": "insertRow",
+                    "requestBody": body("Row"),
+                    "responses": {
+                        "201": response("Row inserted", "InsertResult"),
+                        "400": error,
+                        "404": error,
+                        "409": error
+                    }
+                }
+            },
+            "/vectors/{collection}": {
+                "parameters": [path_param("collection")],
+                "get": {
+                    "summary": "Describe a vector collection",
+                    "operationId": "getCollection",
+                    "responses": {
+                        "200": response("Collection details", "VectorCollection"),
+                        "404": error
+                    }
+                },
+                "post": {
+                    "summary": "Store a vector",
+                    "operationId": "storeVector",
+                    "requestBody": body("VectorRequest"),
+                    "responses": { "201": response("Vector stored", "VectorStored"), "400": error }
+                }
+            },
+            "/vectors/{collection}/search": {
+                "parameters": [path_param("collection")],
+                "post": {
+                    "summary": "Find the nearest vectors",
+                    "operationId": "searchVectors",
+                    "requestBody": body("VectorSearchRequest"),
+                    "responses": {
+                        "200": response("Nearest vectors, closest first", "VectorSearchResult"),
+                        "400": error,
+                        "404": error
+                    }
+                }
+            },
+            "/graph/{graph}/nodes": {
+                "parameters": [path_param("graph")],
+                "post": {
+                    "summary": "Store a graph node",
+                    "operationId": "storeNode",
+                    "requestBody": body("NodeRequest"),
+                    "responses": { "201": response("Node stored", "NodeStored"), "400": error }
+                }
+            },
+            "/graph/{graph}/edges": {
+                "parameters": [path_param("graph")],
+                "post": {
+                    "summary": "Store a graph edge",
+                    "operationId": "storeEdge",
+                    "requestBody": body("EdgeRequest"),
+                    "responses": { "201": response("Edge stored", "EdgeStored"), "400": error }
+                }
+            },
+            "/wal": {
+                "get": {
+                    "summary": "Read committed changes from the write-ahead log",
+                    "operationId": "walChanges",
+                    "parameters": [
+                        { "name": "from", "in": "query", "required": true, "schema": { "type": "integer", "minimum": 0 } },
+                        { "name": "limit", "in": "query", "schema": { "type": "integer", "minimum": 1 } }
+                    ],
+                    "responses": {
+                        "200": response("Changes in LSN order", "WalPage"),
+                        "400": error,
+                        "404": error
+                    }
+                }
+            },
+            "/rpc": {
+                "post": {
+                    "summary": "Call methods through JSON-RPC 2.0, singly or in a batch",
+                    "operationId": "rpc",
+                    "requestBody": body("RpcRequest"),
+                    "responses": {
+                        "200": response("Responses to the calls that have an id", "RpcResponse"),
+                        "204": { "description": "Only notifications were sent" }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": { "error": { "type": "string" } }
+                },
+                "Health": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "string", "example": "healthy" },
+                        "version": { "type": "string" }
+                    }
+                },
+                "Stats": {
+                    "type": "object",
+                    "properties": {
+                        "latency": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "op": { "type": "string", "example": "select" },
+                                    "count": { "type": "integer" },
+                                    "p50_us": { "type": "integer" },
+                                    "p95_us": { "type": "integer" },
+                                    "p99_us": { "type": "integer" },
+                                    "max_us": { "type": "integer" }
+                                }
+                            }
+                        }
+                    }
+                },
+                "QueryRequest": {
+                    "type": "object",
+                    "required": ["query"],
+                    "properties": {
+                        "query": { "type": "string", "example": "SELECT * FROM users" },
+                        "number_format": schema("NumberFormat"),
+                        "page_size": {
+                            "type": "integer",
+                            "minimum": 1,
+                            "description": "Return a single-table SELECT one page of this many rows at a time, in ORDER BY order made unique by the primary key"
+                        },
+                        "cursor": {
+                            "type": "string",
+                            "description": "The next_cursor of the previous page, to continue after it"
+                        }
+                    }
+                },
+                "NumberFormat": {
+                    "description": "How result numbers are written. \"native\" uses JSON numbers; \"string\" sends floats, and integers beyond 2^53, as exact strings; {\"fixed\": n} sends floats as strings rounded to n decimal places.",
+                    "oneOf": [
+                        { "type": "string", "enum": ["native", "string"] },
+                        {
+                            "type": "object",
+                            "required": ["fixed"],
+                            "properties": { "fixed": { "type": "integer", "minimum": 0 } }
+                        }
+                    ],
+                    "default": "native"
+                },
+                "QueryResult": {
+                    "type": "object",
+                    "properties": {
+                        "columns": { "type": "array", "items": { "type": "string" } },
+                        "rows": {
+                            "type": "array",
+                            "description": "Row values in column order",
+                            "items": { "type": "array", "items": {} }
+                        },
+                        "affected_rows": { "type": "integer", "minimum": 0 },
+                        "last_insert_id": { "type": "integer", "format": "int64" },
+                        "execution_time_ms": { "type": "number" },
+                        "next_cursor": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "With page_size: the cursor for the next page, or null on the last page"
+                        }
+                    }
+                },
+                "TableList": {
+                    "type": "object",
+                    "properties": {
+                        "tables": { "type": "array", "items": { "type": "string" } }
+                    }
+                },
+                "WalPage": {
+                    "type": "object",
+                    "properties": {
+                        "changes": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "lsn": { "type": "integer" },
+                                    "sql": { "type": "string", "description": "Statements the record committed, separated by semicolons" }
+                                }
+                            }
+                        }
+                    }
+                },
+                "DatabaseList": {
+                    "type": "object",
+                    "properties": {
+                        "databases": { "type": "array", "items": { "type": "string" } }
+                    }
+                },
+                "Quota": {
+                    "type": "object",
+                    "description": "Limits on a database; null or missing means unlimited",
+                    "properties": {
+                        "max_rows": { "type": "integer", "minimum": 0, "nullable": true },
+                        "max_bytes": { "type": "integer", "minimum": 0, "nullable": true },
+                        "max_indexes": { "type": "integer", "minimum": 0, "nullable": true }
+                    }
+                },
+                "QuotaStatus": {
+                    "type": "object",
+                    "properties": {
+                        "database": { "type": "string" },
+                        "quota": schema("Quota"),
+                        "usage": {
+                            "type": "object",
+                            "properties": {
+                                "rows": { "type": "integer", "minimum": 0 },
+                                "bytes": { "type": "integer", "minimum": 0 },
+                                "indexes": { "type": "integer", "minimum": 0 }
+                            }
+                        }
+                    }
+                },
+                "Table": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "columns": { "type": "array", "items": schema("Column") },
+                        "indexes": { "type": "array", "items": { "type": "object" } },
+                        "constraints": { "type": "array", "items": { "type": "object" } }
+                    }
+                },
+                "Column": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "data_type": {},
+                        "nullable": { "type": "boolean" },
+                        "default_value": {},
+                        "primary_key": { "type": "boolean" },
+                        "unique": { "type": "boolean" },
+                        "index": { "type": "boolean" },
+                        "auto_increment": { "type": "boolean" },
+                        "json_schema": {},
+                        "generated": {}
+                    }
+                },
+                "Row": {
+                    "type": "object",
+                    "description": "Column values keyed by column name",
+                    "additionalProperties": {}
+                },
+                "InsertResult": {
+                    "type": "object",
+                    "properties": {
+                        "affected_rows": { "type": "integer", "minimum": 0 },
+                        "last_insert_id": { "type": "integer", "format": "int64", "nullable": true }
+                    }
+                },
+                "VectorCollection": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "dimension": { "type": "integer", "minimum": 0 },
+                        "metric": { "type": "string" },
+                        "count": { "type": "integer", "minimum": 0 }
+                    }
+                },
+                "VectorRequest": {
+                    "type": "object",
+                    "required": ["id", "vector"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "vector": { "type": "array", "items": { "type": "number" } }
+                    }
+                },
+                "VectorStored": {
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "id": { "type": "string" }
+                    }
+                },
+                "VectorSearchRequest": {
+                    "type": "object",
+                    "required": ["vector"],
+                    "properties": {
+                        "vector": { "type": "array", "items": { "type": "number" } },
+                        "k": { "type": "integer", "minimum": 0, "default": 10 }
+                    }
+                },
+                "VectorSearchResult": {
+                    "type": "object",
+                    "properties": {
+                        "results": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "id": { "type": "string" },
+                                    "score": { "type": "number" }
+                                }
+                            }
+                        }
+                    }
+                },
+                "NodeRequest": {
+                    "type": "object",
+                    "required": ["id"],
+                    "properties": {
+                        "id": { "type": "string" },
+                        "properties": schema("Row")
+                    }
+                },
+                "NodeStored": {
+                    "type": "object",
+                    "properties": {
+                        "graph": { "type": "string" },
+                        "id": { "type": "string" }
+                    }
+                },
+                "EdgeRequest": {
+                    "type": "object",
+                    "required": ["from", "to"],
+                    "properties": {
+                        "from": { "type": "string" },
+                        "to": { "type": "string" },
+                        "properties": schema("Row")
+                    }
+                },
+                "EdgeStored": {
+                    "type": "object",
+                    "properties": {
+                        "graph": { "type": "string" },
+                        "from": { "type": "string" },
+                        "to": { "type": "string" }
+                    }
+                },
+                "RpcRequest": {
+                    "description": "A JSON-RPC 2.0 request, or an array of them",
+                    "type": "object",
+                    "required": ["jsonrpc", "method"],
+                    "properties": {
+                        "jsonrpc": { "type": "string", "enum": ["2.0"] },
+                        "method": {
+                            "type": "string",
+                            "enum": ["query", "insert", "get", "listTables", "storeVector", "vectorSearch"]
+                        },
+                        "params": { "type": "object" },
+                        "id": { "nullable": true }
+                    }
+                },
+                "RpcResponse": {
+                    "description": "A JSON-RPC 2.0 response, or an array of them for a batch",
+                    "type": "object",
+                    "required": ["jsonrpc", "id"],
+                    "properties": {
+                        "jsonrpc": { "type": "string", "enum": ["2.0"] },
+                        "result": {},
+                        "error": {
+                            "type": "object",
+                            "required": ["code", "message"],
+                            "properties": {
+                                "code": { "type": "integer" },
+                                "message": { "type": "string" },
+                                "data": {}
+                            }
+                        },
+                        "id": { "nullable": true }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::JoinHandle;
+
+    /// A database in a fresh temp directory, removed again on drop
+    struct TestDb {
+        db: SharedDb,
+        // Declared after `db` so the directory is removed once the
+        // database has been dropped and has saved its files
+        _dir: TempDir,
+    }
+
+    struct TempDir(std::path::PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl TestDb {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("qubedb-api-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            let db = EmbeddedQubeDB::open(&path).unwrap();
+            TestDb {
+                db: Arc::new(RwLock::new(db)),
+                _dir: TempDir(path),
+            }
+        }
+
+        /// POST `body` to /rpc, returning the status and the parsed response
+        async fn rpc(&self, body: &str) -> (StatusCode, Option<JsonValue>) {
+            let response = rpc(State(Arc::clone(&self.db)), Bytes::from(body.to_string())).await;
+            let status = response.status();
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body = (!bytes.is_empty()).then(|| serde_json::from_slice(&bytes).unwrap());
+            (status, body)
+        }
+
+        /// Serve the database on an ephemeral port
+        async fn serve(&self) -> (SocketAddr, JoinHandle<QubeResult<()>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = RestApiServer::with_shared(ApiConfig::default(), Arc::clone(&self.db));
+            let handle = tokio::spawn(async move { server.serve(listener).await });
+            (addr, handle)
+        }
+    }
+
+    /// Send one HTTP request, returning the status code and the body
+    async fn http(addr: SocketAddr, method: &str, path: &str, body: Option<&str>) -> (u16, String) {
+        let body = body.unwrap_or("");
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    /// Send one HTTP request whose response is JSON
+    async fn http_json(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        body: Option<&str>,
+    ) -> (u16, JsonValue) {
+        let (status, body) = http(addr, method, path, body).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    fn query_call(id: Option<i64>, sql: &str) -> JsonValue {
+        let mut call = json!({ "jsonrpc": "2.0", "method": "query", "params": { "query": sql } });
+        if let Some(id) = id {
+            call["id"] = json!(id);
+        }
+        call
+    }
+
+    #[tokio::test]
+    async fn single_call_returns_its_result() {
+        let db = TestDb::new("rpc-single");
+        let create = query_call(Some(1), "CREATE TABLE t (id INT)");
+        let (status, response) = db.rpc(&create.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        assert_eq!(response["jsonrpc"], "2.0");
+        assert_eq!(response["id"], 1);
+        assert!(response.get("error").is_none());
+
+        let (_, response) = db
+            .rpc(r#"{"jsonrpc": "2.0", "method": "listTables", "id": "tables"}"#)
+            .await;
+        assert_eq!(
+            response.unwrap(),
+            json!({ "jsonrpc": "2.0", "result": { "tables": ["t"] }, "id": "tables" })
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_answer_every_call_but_notifications() {
+        let db = TestDb::new("rpc-batch");
+        let batch = json!([
+            query_call(Some(1), "CREATE TABLE t (id INT)"),
+            query_call(None, "INSERT INTO t VALUES (1)"),
+            { "jsonrpc": "2.0", "method": "noSuchMethod", "id": 2 },
+            query_call(Some(3), "SELECT id FROM t"),
+        ]);
+        let (status, response) = db.rpc(&batch.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        let responses = response.unwrap();
+        let responses = responses.as_array().unwrap();
+        let ids: Vec<&JsonValue> = responses.iter().map(|r| &r["id"]).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(responses[1]["error"]["code"], rpc_error::METHOD_NOT_FOUND);
+        // The notification ran, before the calls after it
+        assert_eq!(responses[2]["result"]["rows"], json!([[1]]));
+
+        // Nothing to answer at all
+        let batch = json!([query_call(None, "INSERT INTO t VALUES (2)")]);
+        let (status, response) = db.rpc(&batch.to_string()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(response.is_none());
+        let count = query_call(Some(4), "SELECT COUNT(*) AS n FROM t");
+        let (_, response) = db.rpc(&count.to_string()).await;
+        assert_eq!(response.unwrap()["result"]["rows"], json!([[2]]));
+    }
+
+    #[tokio::test]
+    async fn errors_use_the_json_rpc_error_object() {
+        let db = TestDb::new("rpc-errors");
+        let error = |response: Option<JsonValue>| {
+            let response = response.unwrap();
+            assert_eq!(response["jsonrpc"], "2.0");
+            assert!(response.get("result").is_none());
+            (response["id"].clone(), response["error"].clone())
+        };
+
+        let (id, e) = error(db.rpc("{not json").await.1);
+        assert_eq!(
+            (id, &e["code"]),
+            (JsonValue::Null, &json!(rpc_error::PARSE_ERROR))
+        );
+
+        let (id, e) = error(db.rpc("[]").await.1);
+        assert_eq!(
+            (id, &e["code"]),
+            (JsonValue::Null, &json!(rpc_error::INVALID_REQUEST))
+        );
+
+        let (id, e) = error(
+            db.rpc(r#"{"jsonrpc": "1.0", "method": "listTables", "id": 1}"#)
+                .await
+                .1,
+        );
+        assert_eq!(
+            (id, &e["code"]),
+            (json!(1), &json!(rpc_error::INVALID_REQUEST))
+        );
+
+        let (id, e) = error(
+            db.rpc(r#"{"jsonrpc": "2.0", "method": "query", "params": [1], "id": 2}"#)
+                .await
+                .1,
+        );
+        assert_eq!(
+            (id, &e["code"]),
+            (json!(2), &json!(rpc_error::INVALID_PARAMS))
+        );
+
+        // A failure of the database carries the REST status
+        let (id, e) = error(
+            db.rpc(
+                r#"{"jsonrpc": "2.0", "method": "get", "params": {"table": "missing"}, "id": 3}"#,
+            )
+            .await
+            .1,
+        );
+        assert_eq!(
+            (id, &e["code"]),
+            (json!(3), &json!(rpc_error::SERVER_ERROR))
+        );
+        assert_eq!(e["data"]["status"], 404);
+        assert!(e["message"].as_str().unwrap().contains("missing"));
+    }
+
+    #[tokio::test]
+    async fn rest_endpoints_serve_tables_and_queries() {
+        let db = TestDb::new("rest");
+        let (addr, server) = db.serve().await;
+
+        let (status, health) = http_json(addr, "GET", "/health", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(health["status"], "healthy");
+
+        let create = r#"{"query": "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)"}"#;
+        assert_eq!(http_json(addr, "POST", "/query", Some(create)).await.0, 200);
+        let row = r#"{"id": 1, "name": "ann"}"#;
+        let (status, inserted) = http_json(addr, "POST", "/tables/users", Some(row)).await;
+        assert_eq!(status, 201);
+        assert_eq!(inserted["affected_rows"], 1);
+
+        let (_, tables) = http_json(addr, "GET", "/tables", None).await;
+        assert_eq!(tables["tables"], json!(["users"]));
+        let (_, table) = http_json(addr, "GET", "/tables/users", None).await;
+        assert_eq!(table["columns"][1]["name"], "name");
+        let select = r#"{"query": "SELECT name FROM users"}"#;
+        let (status, result) = http_json(addr, "POST", "/query", Some(select)).await;
+        assert_eq!(status, 200);
+        assert_eq!(result["columns"], json!(["name"]));
+        assert_eq!(result["rows"], json!([["ann"]]));
+
+        // Errors are JSON with the status of their kind
+        for (method, path, body, expected) in [
+            ("GET", "/tables/missing", None, 404),
+            ("POST", "/query", Some("{not json"), 400),
+            ("POST", "/query", Some(r#"{"sql": "SELECT 1"}"#), 400),
+            ("POST", "/tables/users", Some(r#"{"age": 3}"#), 404),
+            ("GET", "/nowhere", None, 404),
+        ] {
+            let (status, error) = http_json(addr, method, path, body).await;
+            assert_eq!(status, expected, "{} {}", method, path);
+            assert!(error["error"].is_string(), "{} {}", method, path);
+        }
+
+        server.abort();
+        let _ = server.await;
+    }
+
+    /// Every `$ref` in `value`
+    fn refs<'a>(value: &'a JsonValue, found: &mut Vec<&'a str>) {
+        match value {
+            JsonValue::Object(fields) => {
+                for (key, field) in fields {
+                    match field.as_str() {
+                        Some(target) if key == "$ref" => found.push(target),
+                        _ => refs(field, found),
+                    }
+                }
+            }
+            JsonValue::Array(items) => items.iter().for_each(|item| refs(item, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn openapi_spec_documents_every_route_and_schema() {
+        let spec = openapi();
+        assert_eq!(spec["openapi"], "3.0.3");
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/health",
+            "/query",
+            "/tables/{table}",
+            "/vectors/{collection}/search",
+            "/graph/{graph}/edges",
+            "/rpc",
+        ] {
+            assert!(paths.contains_key(path), "{} is undocumented", path);
+        }
+
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        let mut found = Vec::new();
+        refs(&spec, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(schemas.contains_key(name), "{} is not defined", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn spec_and_swagger_ui_are_served() {
+        let db = TestDb::new("openapi");
+        let (addr, server) = db.serve().await;
+        let (status, spec) = http_json(addr, "GET", "/openapi.json", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(spec, openapi());
+        let (status, page) = http(addr, "GET", "/docs", None).await;
+        assert_eq!(status, 200);
+        assert!(page.contains(r#"url: "/openapi.json""#));
+
+        server.abort();
+        let _ = server.await;
+    }
+
+    #[tokio::test]
+    async fn admin_page_and_its_assets_are_served() {
+        let db = TestDb::new("admin");
+        let (addr, server) = db.serve().await;
+        let (status, page) = http(addr, "GET", "/admin", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(page, ADMIN_HTML);
+        for (path, asset) in [
+            ("/admin/admin.js", ADMIN_JS),
+            ("/admin/admin.css", ADMIN_CSS),
+        ] {
+            assert!(page.contains(path));
+            assert_eq!(
+                http(addr, "GET", path, None).await,
+                (200, asset.to_string())
+            );
+        }
+        server.abort();
+        let _ = server.await;
+
+        let content_type = |response: Response| response.headers()[header::CONTENT_TYPE].clone();
+        assert_eq!(
+            content_type(admin_page().await.into_response()),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(admin_script().await.into_response()),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(admin_style().await.into_response()),
+            "text/css; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_encode_numbers_as_the_client_asks() {
+        let db = TestDb::new("number-format");
+        let (addr, server) = db.serve().await;
+        for sql in [
+            "CREATE TABLE t (price DOUBLE)",
+            "INSERT INTO t VALUES (19.999)",
+        ] {
+            let body = json!({ "query": sql }).to_string();
+            assert_eq!(http_json(addr, "POST", "/query", Some(&body)).await.0, 200);
+        }
+
+        for (format, expected) in [
+            ("null", json!(19.999)),
+            (r#""string""#, json!("19.999")),
+            (r#"{"fixed": 2}"#, json!("20.00")),
+        ] {
+            let body = format!(
+                r#"{{"query": "SELECT price FROM t", "number_format": {}}}"#,
+                format
+            );
+            let (status, result) = http_json(addr, "POST", "/query", Some(&body)).await;
+            assert_eq!(status, 200);
+            assert_eq!(result["rows"][0][0], expected, "{}", format);
+        }
+        let body = r#"{"query": "SELECT price FROM t", "number_format": "words"}"#;
+        assert_eq!(http_json(addr, "POST", "/query", Some(body)).await.0, 400);
+
+        server.abort();
+        let _ = server.await;
+    }
+
+    #[test]
+    fn cors_headers_are_only_sent_to_allowed_origins() {
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://app.example".to_string()],
+            ..CorsConfig::default()
+        };
+        assert!(cors.allows_origin("https://app.example"));
+        assert!(!cors.allows_origin("https://evil.example"));
+        assert!(cors
+            .response_headers(Some("https://evil.example"))
+            .is_empty());
+        assert!(cors.response_headers(None).is_empty());
+        assert_eq!(
+            cors.response_headers(Some("https://app.example")),
+            [
+                (
+                    "Access-Control-Allow-Origin",
+                    "https://app.example".to_string()
+                ),
+                ("Vary", "Origin".to_string()),
+            ]
+        );
+        assert_eq!(
+            cors.preflight_headers(Some("https://app.example"))[2..],
+            [
+                (
+                    "Access-Control-Allow-Methods",
+                    "GET, POST, DELETE, OPTIONS".to_string()
+                ),
+                ("Access-Control-Allow-Headers", "Content-Type".to_string()),
+                ("Access-Control-Max-Age", "600".to_string()),
+            ]
+        );
+        assert!(cors
+            .preflight_headers(Some("https://evil.example"))
+            .is_empty());
+
+        // The default policy answers no cross-origin caller
+        assert!(!CorsConfig::default().allows_origin("https://app.example"));
+
+        let any = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        };
+        assert!(any.allows_origin("https://evil.example"));
+        assert!(matches!(any.validate(), Err(QubeError::Config(_))));
+        let credentials = CorsConfig {
+            allow_credentials: true,
+            ..cors
+        };
+        assert_eq!(
+            credentials.response_headers(Some("https://app.example"))[2],
+            ("Access-Control-Allow-Credentials", "true".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn the_router_answers_preflights_for_allowed_origins() {
+        let db = TestDb::new("cors");
+        let config = ApiConfig {
+            cors: CorsConfig {
+                allowed_origins: vec!["https://app.example".to_string()],
+                ..CorsConfig::default()
+            },
+            ..ApiConfig::default()
+        };
+        let server = RestApiServer::with_shared(config, Arc::clone(&db.db));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { server.serve(listener).await });
+
+        let preflight = |origin: &'static str| async move {
+            let request = format!(
+                "OPTIONS /query HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+                 Origin: {}\r\nAccess-Control-Request-Method: POST\r\n\r\n",
+                origin
+            );
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response.to_lowercase()
+        };
+        let allowed = preflight("https://app.example").await;
+        assert!(allowed.contains("access-control-allow-origin: https://app.example"));
+        assert!(allowed.contains("access-control-max-age: 600"));
+        let refused = preflight("https://evil.example").await;
+        assert!(!refused.contains("access-control-allow-origin"));
+
+        server.abort();
+        let _ = server.await;
+
+        let invalid = ApiConfig {
+            cors: CorsConfig {
+                allowed_methods: vec!["NOT A METHOD".to_string()],
+                ..CorsConfig::default()
+            },
+            ..ApiConfig::default()
+        };
+        let server = RestApiServer::with_shared(invalid, Arc::clone(&db.db));
+        assert!(matches!(server.router(), Err(QubeError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn database_quotas_are_read_and_set() {
+        let db = TestDb::new("quota");
+        db.db
+            .read()
+            .await
+            .execute("CREATE DATABASE sales")
+            .await
+            .unwrap();
+        let (addr, server) = db.serve().await;
+
+        let (status, body) = http_json(addr, "GET", "/databases", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "databases": ["public", "sales"] }));
+
+        let (status, body) = http_json(addr, "GET", "/databases/sales/quota", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["quota"]["max_rows"], JsonValue::Null);
+        assert_eq!(body["usage"]["rows"], 0);
+
+        let quota = r#"{"max_rows": 10, "max_indexes": null}"#;
+        let (status, body) = http_json(addr, "PUT", "/databases/sales/quota", Some(quota)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["database"], "sales");
+        assert_eq!(
+            body["quota"],
+            json!({ "max_rows": 10, "max_bytes": null, "max_indexes": null })
+        );
+        let sales = db.db.read().await.quota("sales").unwrap();
+        assert_eq!(sales, Quota::unlimited().with_max_rows(10));
+
+        let (status, _) = http(addr, "GET", "/databases/nowhere/quota", None).await;
+        assert_eq!(status, 404);
+        let bad = Some(r#"{"max_rows": -1}"#);
+        let (status, _) = http(addr, "PUT", "/databases/sales/quota", bad).await;
+        assert_eq!(status, 400);
+
+        server.abort();
+        let _ = server.await;
+    }
+}
@@ -0,0 +1,411 @@
+//! Audit trail for QubeDB
+//!
+//! An `AuditLog` records security-sensitive operations: logins, permission
+//! denials, changes to users and roles, and schema changes. Each entry
+//! names the acting user and the request it belongs to, and the trail is
+//! kept apart from the diagnostic logs in its own append-only file of JSON
+//! lines.
+//!
+//! Entries are hash-chained: each carries the SHA-256 hash of the one
+//! before it and a hash over its own fields, so editing, removing or
+//! reordering entries is detected by `AuditLog::verify`.
+
+use crate::error::{QubeError, QubeResult};
+use crate::security::SecurityContext;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What kind of operation an audit entry records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// A user authenticated, or tried to
+    Login,
+    /// A statement was refused for lack of permission
+    PermissionDenied,
+    /// A user or role was created, changed or removed
+    AccountChange,
+    /// A database, table or index was created, changed or dropped
+    SchemaChange,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditAction::Login => write!(f, "login"),
+            AuditAction::PermissionDenied => write!(f, "permission_denied"),
+            AuditAction::AccountChange => write!(f, "account_change"),
+            AuditAction::SchemaChange => write!(f, "schema_change"),
+        }
+    }
+}
+
+/// Whether the audited operation went ahead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// One line of the audit trail
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the trail, starting at 1
+    pub seq: u64,
+    /// Milliseconds since the epoch
+    pub timestamp: i64,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+    pub user: String,
+    pub request_id: Option<String>,
+    /// What was done, e.g. the statement that ran or was refused
+    pub detail: String,
+    /// Hash of the previous entry, empty for the first
+    pub prev_hash: String,
+    /// Hash of this entry's other fields
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// The hash this entry should carry
+    fn compute_hash(&self) -> QubeResult<String> {
+        let unsigned = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json =
+            serde_json::to_vec(&unsigned).map_err(|e| QubeError::Serialization(e.to_string()))?;
+        Ok(sha256_hex(&json))
+    }
+}
+
+/// Hex-encoded SHA-256 hash of `data`, as used to chain log entries
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Where and why a hash-chained log stops verifying
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainBreak {
+    /// Line of the first entry that fails, starting at 1
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ChainBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "hash chain broken at line {}: {}",
+            self.line, self.reason
+        )
+    }
+}
+
+struct Tail {
+    file: File,
+    last_hash: String,
+    next_seq: u64,
+}
+
+/// Append-only, hash-chained audit trail in a file
+pub struct AuditLog {
+    path: PathBuf,
+    tail: Mutex<Tail>,
+}
+
+impl AuditLog {
+    /// Open the audit trail at `path`, creating it if needed. New entries
+    /// continue the chain of those already there.
+    pub fn open<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (last_hash, next_seq) = match Self::read(&path) {
+            Ok(entries) => entries
+                .last()
+                .map_or((String::new(), 1), |last| (last.hash.clone(), last.seq + 1)),
+            Err(QubeError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => (String::new(), 1),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(AuditLog {
+            path,
+            tail: Mutex::new(Tail {
+                file,
+                last_hash,
+                next_seq,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry for an operation by `context`'s user, synced to disk
+    /// before returning
+    pub fn record(
+        &self,
+        action: AuditAction,
+        outcome: AuditOutcome,
+        context: &SecurityContext,
+        detail: impl Into<String>,
+    ) -> QubeResult<AuditEntry> {
+        self.append(
+            action,
+            outcome,
+            &context.user,
+            context.request_id.clone(),
+            detail.into(),
+        )
+    }
+
+    /// Record a login attempt by `user`, which has no security context yet
+    /// if it failed
+    pub fn record_login(
+        &self,
+        user: &str,
+        request_id: Option<String>,
+        succeeded: bool,
+        detail: impl Into<String>,
+    ) -> QubeResult<AuditEntry> {
+        let outcome = if succeeded {
+            AuditOutcome::Success
+        } else {
+            AuditOutcome::Failure
+        };
+        self.append(AuditAction::Login, outcome, user, request_id, detail.into())
+    }
+
+    fn append(
+        &self,
+        action: AuditAction,
+        outcome: AuditOutcome,
+        user: &str,
+        request_id: Option<String>,
+        detail: String,
+    ) -> QubeResult<AuditEntry> {
+        let mut tail = self.tail.lock().unwrap();
+        let mut entry = AuditEntry {
+            seq: tail.next_seq,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            action,
+            outcome,
+            user: user.to_string(),
+            request_id,
+            detail,
+            prev_hash: tail.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash()?;
+
+        let mut line =
+            serde_json::to_vec(&entry).map_err(|e| QubeError::Serialization(e.to_string()))?;
+        line.push(b'\n');
+        tail.file.write_all(&line)?;
+        tail.file.sync_data()?;
+        tail.last_hash = entry.hash.clone();
+        tail.next_seq += 1;
+        Ok(entry)
+    }
+
+    /// Every entry of the audit trail at `path`, oldest first
+    pub fn read<P: AsRef<Path>>(path: P) -> QubeResult<Vec<AuditEntry>> {
+        let reader = BufReader::new(File::open(path)?);
+        reader
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(&line?).map_err(|e| {
+                    QubeError::Serialization(format!("audit trail line {}: {}", i + 1, e))
+                })
+            })
+            .collect()
+    }
+
+    /// Check the audit trail at `path` entry by entry, returning the first
+    /// one that was altered, removed, inserted or moved, or `None` if the
+    /// chain is intact
+    pub fn verify<P: AsRef<Path>>(path: P) -> QubeResult<Option<ChainBreak>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut prev_hash = String::new();
+        for (i, line) in reader.lines().enumerate() {
+            let broken = |reason: String| {
+                Ok(Some(ChainBreak {
+                    line: i + 1,
+                    reason,
+                }))
+            };
+            let entry: AuditEntry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(e) => return broken(format!("unreadable entry: {}", e)),
+            };
+            if entry.seq != i as u64 + 1 {
+                return broken(format!("expected entry {} but found {}", i + 1, entry.seq));
+            }
+            if entry.prev_hash != prev_hash {
+                return broken("does not follow the previous entry".to_string());
+            }
+            if entry.hash != entry.compute_hash()? {
+                return broken("contents do not match its hash".to_string());
+            }
+            prev_hash = entry.hash;
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryEngine;
+    use crate::session::Session;
+    use std::sync::Arc;
+
+    fn trail_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "qubedb-audit-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// A trail of three entries
+    fn trail(name: &str) -> PathBuf {
+        let path = trail_path(name);
+        let log = AuditLog::open(&path).unwrap();
+        let admin = SecurityContext::admin("root").with_request_id("req-1");
+        log.record_login("root", Some("req-1".to_string()), true, "password")
+            .unwrap();
+        log.record(
+            AuditAction::AccountChange,
+            AuditOutcome::Success,
+            &admin,
+            "CREATE USER ann",
+        )
+        .unwrap();
+        log.record_login("mallory", None, false, "bad password")
+            .unwrap();
+        path
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn write_lines(path: &Path, lines: &[String]) {
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn entries_are_chained_across_reopening() {
+        let path = trail("chain");
+        let log = AuditLog::open(&path).unwrap();
+        let entry = log
+            .record(
+                AuditAction::SchemaChange,
+                AuditOutcome::Success,
+                &SecurityContext::admin("root"),
+                "DROP TABLE t",
+            )
+            .unwrap();
+        assert_eq!(entry.seq, 4);
+
+        let entries = AuditLog::read(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].prev_hash, "");
+        for pair in entries.windows(2) {
+            assert_eq!(pair[1].prev_hash, pair[0].hash);
+        }
+        assert_eq!(entries[1].request_id.as_deref(), Some("req-1"));
+        assert_eq!(
+            (entries[2].user.as_str(), entries[2].outcome),
+            ("mallory", AuditOutcome::Failure)
+        );
+        assert_eq!(AuditLog::verify(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn tampering_is_detected_at_the_entry_it_touches() {
+        let original = lines(&trail("tamper"));
+        let path = trail_path("tampered");
+
+        let mut edited = original.clone();
+        edited[1] = edited[1].replace("CREATE USER ann", "CREATE USER eve");
+        write_lines(&path, &edited);
+        let broken = AuditLog::verify(&path).unwrap().unwrap();
+        assert_eq!(broken.line, 2);
+        assert!(broken.reason.contains("hash"), "{}", broken);
+
+        let mut removed = original.clone();
+        removed.remove(1);
+        write_lines(&path, &removed);
+        assert_eq!(AuditLog::verify(&path).unwrap().unwrap().line, 2);
+
+        let mut reordered = original.clone();
+        reordered.swap(1, 2);
+        write_lines(&path, &reordered);
+        assert_eq!(AuditLog::verify(&path).unwrap().unwrap().line, 2);
+
+        let mut truncated = original;
+        truncated.truncate(2);
+        truncated.push("{\"seq\": 3".to_string());
+        write_lines(&path, &truncated);
+        let broken = AuditLog::verify(&path).unwrap().unwrap();
+        assert_eq!(broken.line, 3);
+        assert!(broken.reason.contains("unreadable"), "{}", broken);
+    }
+
+    #[test]
+    fn sessions_audit_refusals_and_schema_changes() {
+        let path = trail_path("session");
+        let audit = Arc::new(AuditLog::open(&path).unwrap());
+        let engine = Arc::new(QueryEngine::new());
+        let mut admin = Session::new(Arc::clone(&engine), SecurityContext::admin("root"))
+            .with_audit(Arc::clone(&audit));
+        admin
+            .execute_script("CREATE TABLE t (id INT); INSERT INTO t VALUES (1)")
+            .unwrap();
+        let mut reader = Session::new(Arc::clone(&engine), SecurityContext::read_only("ann"))
+            .with_audit(Arc::clone(&audit));
+        reader.execute_script("SELECT id FROM t").unwrap();
+        assert!(reader.execute_script("DELETE FROM t").is_err());
+
+        // Reads and ordinary writes are not audited
+        let entries = AuditLog::read(&path).unwrap();
+        let summary: Vec<(AuditAction, AuditOutcome, &str)> = entries
+            .iter()
+            .map(|entry| (entry.action, entry.outcome, entry.user.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (AuditAction::SchemaChange, AuditOutcome::Success, "root"),
+                (AuditAction::PermissionDenied, AuditOutcome::Failure, "ann"),
+            ]
+        );
+        assert!(entries[1].detail.contains("DELETE FROM t"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Benchmark harness for QubeDB
+//!
+//! Runs insert, query, vector and graph workloads against an
+//! `EmbeddedQubeDB`, the same engine applications use, and reports each as
+//! a `BenchResult`: how many operations ran, how long they took in total,
+//! the throughput and the latency percentiles of single operations.
+//! Results serialize to JSON, so CI can store them and compare runs.
+
+use crate::embedded::EmbeddedQubeDB;
+use crate::error::QubeResult;
+use crate::index::DistanceMetric;
+use crate::types::{Row, Value};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Table the insert and query workloads use
+pub const BENCH_TABLE: &str = "bench_rows";
+
+/// Vector collection the vector workload uses
+pub const BENCH_COLLECTION: &str = "bench_vectors";
+
+/// Graph the graph workload uses
+pub const BENCH_GRAPH: &str = "bench_graph";
+
+/// Dimensions of the vectors the vector workload stores
+pub const BENCH_DIMENSIONS: usize = 64;
+
+/// Nearest neighbours each vector search asks for
+const SEARCH_K: usize = 10;
+
+/// Outcome of one workload
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    /// What was measured, e.g. `insert` or `vector_search`
+    pub op: String,
+    /// Operations run
+    pub count: usize,
+    /// Time all of them took
+    pub duration: Duration,
+    pub ops_per_sec: f64,
+    /// Latencies of single operations
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+impl BenchResult {
+    /// Summarize the latency of every operation of a workload that took
+    /// `duration` in all
+    pub fn from_latencies(op: &str, mut latencies: Vec<Duration>, duration: Duration) -> Self {
+        latencies.sort_unstable();
+        let seconds = duration.as_secs_f64();
+        BenchResult {
+            op: op.to_string(),
+            count: latencies.len(),
+            duration,
+            ops_per_sec: if seconds > 0.0 {
+                latencies.len() as f64 / seconds
+            } else {
+                0.0
+            },
+            p50: percentile(&latencies, 50.0),
+            p95: percentile(&latencies, 95.0),
+            p99: percentile(&latencies, 99.0),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted latencies; zero when there are none
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Time `count` runs of `operation`, given the index of each run
+fn measure(
+    op: &str,
+    count: usize,
+    mut operation: impl FnMut(usize) -> QubeResult<()>,
+) -> QubeResult<BenchResult> {
+    let mut latencies = Vec::with_capacity(count);
+    let started = Instant::now();
+    for i in 0..count {
+        let start = Instant::now();
+        operation(i)?;
+        latencies.push(start.elapsed());
+    }
+    Ok(BenchResult::from_latencies(
+        op,
+        latencies,
+        started.elapsed(),
+    ))
+}
+
+/// Insert `count` rows into `BENCH_TABLE` through the SQL engine, creating
+/// the table first if needed
+pub async fn bench_insert(db: &EmbeddedQubeDB, count: usize) -> QubeResult<BenchResult> {
+    db.execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (id BIGINT PRIMARY KEY, name TEXT, score BIGINT)",
+        BENCH_TABLE
+    ))
+    .await?;
+    let first = db
+        .execute(&format!("SELECT COUNT(*) FROM {}", BENCH_TABLE))
+        .await?;
+    let offset = first
+        .rows
+        .first()
+        .and_then(|row| row.values().next())
+        .and_then(Value::as_i64)
+        .unwrap_or(0);
+    measure("insert", count, |i| {
+        let id = offset + i as i64;
+        let row = Row::from([
+            ("id".to_string(), Value::Int64(id)),
+            ("name".to_string(), Value::String(format!("row{}", id))),
+            ("score".to_string(), Value::Int64(id % 100)),
+        ]);
+        db.insert_row(BENCH_TABLE, &row).map(|_| ())
+    })
+}
+
+/// Run `sql` `count` times, reporting it as `op`
+pub async fn bench_query(
+    db: &EmbeddedQubeDB,
+    op: &str,
+    sql: &str,
+    count: usize,
+) -> QubeResult<BenchResult> {
+    let mut latencies = Vec::with_capacity(count);
+    let started = Instant::now();
+    for _ in 0..count {
+        let start = Instant::now();
+        db.execute(sql).await?;
+        latencies.push(start.elapsed());
+    }
+    Ok(BenchResult::from_latencies(
+        op,
+        latencies,
+        started.elapsed(),
+    ))
+}
+
+/// Store `count` vectors in `BENCH_COLLECTION`, then run `count` nearest
+/// neighbour searches over them. Returns the store and search results.
+pub fn bench_vectors(
+    db: &mut EmbeddedQubeDB,
+    count: usize,
+) -> QubeResult<(BenchResult, BenchResult)> {
+    if db.vector_collection(BENCH_COLLECTION).is_none() {
+        db.create_vector_collection(
+            BENCH_COLLECTION,
+            BENCH_DIMENSIONS,
+            DistanceMetric::Cosine,
+            false,
+        )?;
+    }
+    let store = measure("vector_insert", count, |i| {
+        db.store_vector(BENCH_COLLECTION, &format!("v{}", i), &bench_vector(i))
+    })?;
+    let search = measure("vector_search", count, |i| {
+        db.search_vectors(BENCH_COLLECTION, &bench_vector(i + 1), SEARCH_K)
+            .map(|_| ())
+    })?;
+    Ok((store, search))
+}
+
+/// Deterministic, non-zero vector for the `i`th insert or search
+fn bench_vector(i: usize) -> Vec<f32> {
+    (0..BENCH_DIMENSIONS)
+        .map(|d| ((i * 31 + d * 17) % 97) as f32 + 1.0)
+        .collect()
+}
+
+/// Store `count` nodes in `BENCH_GRAPH`, each linked by an edge to the one
+/// before it; one operation is a node and its edge
+pub fn bench_graph(db: &mut EmbeddedQubeDB, count: usize) -> QubeResult<BenchResult> {
+    measure("graph_insert", count, |i| {
+        let node = format!("n{}", i);
+        let properties = Row::from([("position".to_string(), Value::Int64(i as i64))]);
+        db.store_node(BENCH_GRAPH, &node, properties)?;
+        if i > 0 {
+            db.store_edge(BENCH_GRAPH, &format!("n{}", i - 1), &node, Row::new())?;
+        }
+        Ok(())
+    })
+}
+
+/// Run every workload with `count` operations against a fresh database at
+/// `path`, which must not exist yet
+pub async fn run<P: AsRef<Path>>(path: P, count: usize) -> QubeResult<Vec<BenchResult>> {
+    let mut db = EmbeddedQubeDB::open(path)?;
+    let mut results = vec![bench_insert(&db, count).await?];
+    let point = format!("SELECT * FROM {} WHERE id = {}", BENCH_TABLE, count / 2);
+    results.push(bench_query(&db, "point_query", &point, count).await?);
+    let scan = format!("SELECT COUNT(*) FROM {} WHERE score < 50", BENCH_TABLE);
+    results.push(bench_query(&db, "scan_query", &scan, count).await?);
+    let (store, search) = bench_vectors(&mut db, count)?;
+    results.push(store);
+    results.push(search);
+    results.push(bench_graph(&mut db, count)?);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(values: impl IntoIterator<Item = u64>) -> Vec<Duration> {
+        values.into_iter().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let latencies = millis((1..=100).rev());
+        let result = BenchResult::from_latencies("op", latencies, Duration::from_secs(2));
+        assert_eq!(result.count, 100);
+        assert_eq!(result.ops_per_sec, 50.0);
+        assert_eq!(
+            (result.p50, result.p95, result.p99),
+            (
+                Duration::from_millis(50),
+                Duration::from_millis(95),
+                Duration::from_millis(99)
+            )
+        );
+
+        let few = BenchResult::from_latencies("op", millis([7, 3]), Duration::ZERO);
+        assert_eq!(
+            (few.p50, few.p99),
+            (Duration::from_millis(3), Duration::from_millis(7))
+        );
+        assert_eq!(few.ops_per_sec, 0.0);
+        let none = BenchResult::from_latencies("op", Vec::new(), Duration::from_secs(1));
+        assert_eq!((none.count, none.p99), (0, Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn every_workload_reports_its_operations() {
+        let path = std::env::temp_dir().join(format!("qubedb-bench-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let results = run(&path, 20).await.unwrap();
+        let ops: Vec<&str> = results.iter().map(|result| result.op.as_str()).collect();
+        assert_eq!(
+            ops,
+            [
+                "insert",
+                "point_query",
+                "scan_query",
+                "vector_insert",
+                "vector_search",
+                "graph_insert"
+            ]
+        );
+        for result in &results {
+            assert_eq!(result.count, 20, "{}", result.op);
+            assert!(result.p50 <= result.p95 && result.p95 <= result.p99);
+            assert!(result.p99 <= result.duration);
+        }
+        let json = serde_json::to_string(&results[0]).unwrap();
+        let back: BenchResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, results[0]);
+
+        // Running a workload again adds to what is there
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        bench_insert(&db, 5).await.unwrap();
+        let rows = db
+            .execute(&format!("SELECT COUNT(*) AS n FROM {}", BENCH_TABLE))
+            .await
+            .unwrap()
+            .rows;
+        assert_eq!(rows[0]["n"], Value::Int64(25));
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,20 +1,21 @@
-use qubedb_core::storage::{KeyValueStore, StoreStats};
-use qubedb_core::embedded_simple::EmbeddedQubeDB;
+use qubedb_core::api::CorsConfig;
+use qubedb_core::embedded::EmbeddedQubeDB;
 use qubedb_core::logging::{init_logger, LoggerConfig};
-use std::collections::HashMap;
-use std::sync::Arc;
+use qubedb_core::metrics::{self, LatencySummary};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::Instant;
 
 // HTTP server for QubeDB Core
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use serde::{Deserialize, Serialize};
-use tokio;
 
 #[derive(Clone)]
 struct QubeDBServer {
-    store: Arc<KeyValueStore>,
+    db: Arc<Mutex<EmbeddedQubeDB>>,
+    started: Instant,
+    cors: CorsConfig,
 }
 
 #[derive(Deserialize)]
@@ -37,16 +38,30 @@ struct GetResponse {
 
 #[derive(Serialize)]
 struct StatsResponse {
-    store_stats: StoreStats,
+    keys: usize,
     server_uptime: u64,
+    latency: Vec<LatencySummary>,
 }
 
 impl QubeDBServer {
-    fn new(store: Arc<KeyValueStore>) -> Self {
-        Self { store }
+    fn new(db: EmbeddedQubeDB) -> Self {
+        Self { db: Arc::new(Mutex::new(db)), started: Instant::now(), cors: CorsConfig::from_env() }
     }
 
     fn handle_request(&self, request: &str) -> String {
+        let origin = request_header(request, "Origin");
+        // Browsers send a preflight OPTIONS request before cross-origin
+        // calls; answer it for any path
+        if request.starts_with("OPTIONS ") {
+            let response = "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n".to_string();
+            return with_headers(response, &self.cors.preflight_headers(origin));
+        }
+
+        let response = self.route_request(request);
+        with_headers(response, &self.cors.response_headers(origin))
+    }
+
+    fn route_request(&self, request: &str) -> String {
         // Parse HTTP request
         let lines: Vec<&str> = request.lines().collect();
         if lines.is_empty() {
@@ -70,6 +85,14 @@ impl QubeDBServer {
             ("GET", "/api/stats") => {
                 self.handle_stats_request()
             }
+            ("GET", "/metrics") => {
+                let body = metrics::registry().render_prometheus();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
             ("POST", "/api/put") => {
                 self.handle_put_request(request)
             }
@@ -89,35 +112,27 @@ impl QubeDBServer {
     }
 
     fn handle_stats_request(&self) -> String {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.store.stats()) {
-            Ok(stats) => {
-                let response = StatsResponse {
-                    store_stats: stats,
-                    server_uptime: 0, // TODO: Track uptime
-                };
-                match serde_json::to_string(&response) {
-                    Ok(json) => self.create_response(200, "OK", &json),
-                    Err(e) => self.create_response(500, "Internal Server Error", &format!(r#"{{"error": "{}"}}"#, e)),
-                }
-            }
+        let response = StatsResponse {
+            keys: self.db.lock().unwrap().kv_len(),
+            server_uptime: self.started.elapsed().as_secs(),
+            latency: metrics::registry().summaries(),
+        };
+        match serde_json::to_string(&response) {
+            Ok(json) => self.create_response(200, "OK", &json),
             Err(e) => self.create_response(500, "Internal Server Error", &format!(r#"{{"error": "{}"}}"#, e)),
         }
     }
 
     fn handle_put_request(&self, request: &str) -> String {
         // Extract JSON body from request
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         match serde_json::from_str::<PutRequest>(body) {
             Ok(put_req) => {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                match rt.block_on(self.store.put(put_req.key.clone(), put_req.value)) {
+                match self.db.lock().unwrap().kv_put(&put_req.key, put_req.value.as_bytes()) {
                     Ok(_) => {
                         let response = format!(r#"{{"status": "success", "message": "Key '{}' stored successfully"}}"#, put_req.key);
                         self.create_response(200, "OK", &response)
@@ -137,18 +152,16 @@ impl QubeDBServer {
 
     fn handle_get_request(&self, request: &str) -> String {
         // Extract JSON body from request
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         match serde_json::from_str::<GetRequest>(body) {
             Ok(get_req) => {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                match rt.block_on(self.store.get(&get_req.key)) {
+                match self.db.lock().unwrap().kv_get(&get_req.key) {
                     Ok(value) => {
+                        let value = value.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
                         let response = GetResponse {
                             key: get_req.key,
                             value: value.clone(),
@@ -174,17 +187,14 @@ impl QubeDBServer {
 
     fn handle_delete_request(&self, request: &str) -> String {
         // Extract JSON body from request
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         match serde_json::from_str::<GetRequest>(body) {
             Ok(delete_req) => {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                match rt.block_on(self.store.delete(&delete_req.key)) {
+                match self.db.lock().unwrap().kv_delete(&delete_req.key) {
                     Ok(deleted) => {
                         let response = format!(r#"{{"status": "success", "message": "Key '{}' {}"}}"#, 
                             delete_req.key, 
@@ -206,10 +216,9 @@ impl QubeDBServer {
     }
 
     fn handle_flush_request(&self) -> String {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.store.force_flush()) {
+        match self.db.lock().unwrap().save_kv() {
             Ok(_) => {
-                self.create_response(200, "OK", r#"{"status": "success", "message": "MemTable flushed to SSTable"}"#)
+                self.create_response(200, "OK", r#"{"status": "success", "message": "Key-value namespace flushed to disk"}"#)
             }
             Err(e) => {
                 let response = format!(r#"{{"error": "{}"}}"#, e);
@@ -220,7 +229,7 @@ impl QubeDBServer {
 
     fn create_response(&self, status_code: u16, status_text: &str, body: &str) -> String {
         format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\n\r\n{}",
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
             status_code,
             status_text,
             body.len(),
@@ -229,6 +238,33 @@ impl QubeDBServer {
     }
 }
 
+/// Extract the body of a raw HTTP request, if it has one
+fn request_body(request: &str) -> Option<&str> {
+    request.find("\r\n\r\n").map(|body_start| &request[body_start + 4..])
+}
+
+/// Look up a header of a raw HTTP request by case-insensitive name
+fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.split("\r\n\r\n").next()?.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Insert extra headers after the status line of a response
+fn with_headers(response: String, headers: &[(&str, String)]) -> String {
+    if headers.is_empty() {
+        return response;
+    }
+    let (status_line, rest) = response.split_once("\r\n").unwrap_or((&response, ""));
+    let mut out = format!("{}\r\n", status_line);
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(rest);
+    out
+}
+
 fn main() {
     // Initialize logging
     let config = LoggerConfig::default();
@@ -239,28 +275,22 @@ fn main() {
     println!("📍 API Endpoint: http://localhost:8080/api/");
     println!("📍 Health Check: http://localhost:8080/api/health");
     println!("📍 Stats: http://localhost:8080/api/stats");
+    println!("📍 Metrics: http://localhost:8080/metrics");
     println!();
 
-    // Initialize KeyValueStore
-    let store = match KeyValueStore::new("./data") {
-        Ok(store) => {
-            println!("✅ KeyValueStore initialized");
-            Arc::new(store)
+    // Open the database
+    let db = match EmbeddedQubeDB::open("./data") {
+        Ok(db) => {
+            println!("✅ Database opened with {} keys", db.kv_len());
+            db
         }
         Err(e) => {
-            eprintln!("❌ Failed to initialize KeyValueStore: {}", e);
+            eprintln!("❌ Failed to open database: {}", e);
             return;
         }
     };
 
-    // Recover from WAL
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    if let Err(e) = rt.block_on(store.recover()) {
-        eprintln!("❌ Failed to recover from WAL: {}", e);
-        return;
-    }
-
-    let server = QubeDBServer::new(store);
+    let server = QubeDBServer::new(db);
 
     // Start HTTP server
     let listener = TcpListener::bind("127.0.0.1:8080").expect("Failed to bind to port 8080");
@@ -301,3 +331,37 @@ fn handle_client(mut stream: TcpStream, server: QubeDBServer) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> String {
+        format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body)
+    }
+
+    #[test]
+    fn latency_is_reported_in_stats_and_metrics() {
+        let path = std::env::temp_dir().join(format!("qubedb-real-server-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let server = QubeDBServer::new(EmbeddedQubeDB::open(&path).unwrap());
+
+        let body = r#"{"key": "k", "value": "v"}"#;
+        let put = server.route_request(&request("POST", "/api/put", body));
+        assert!(put.starts_with("HTTP/1.1 200"), "{}", put);
+
+        let stats = server.route_request(&request("GET", "/api/stats", ""));
+        let body = request_body(&stats).unwrap();
+        let stats: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(stats["keys"], 1);
+        let latency = stats["latency"].as_array().unwrap();
+        let kv_put = latency.iter().find(|summary| summary["op"] == "kv_put").unwrap();
+        assert!(kv_put["count"].as_u64().unwrap() >= 1);
+
+        let metrics = server.route_request(&request("GET", "/metrics", ""));
+        assert!(metrics.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(metrics.contains("qubedb_operation_latency_seconds_count{op=\"kv_put\"}"));
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
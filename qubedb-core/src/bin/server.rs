@@ -1,5 +1,8 @@
-use qubedb_core::embedded::EmbeddedQubeDB;
+use qubedb_core::api::CorsConfig;
+use qubedb_core::error::QubeError;
+use qubedb_core::index::{DistanceMetric, VectorIndex};
 use qubedb_core::logging::{init_logger, LoggerConfig};
+use serde_json::json;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -11,17 +14,32 @@ use std::net::{TcpListener, TcpStream};
 
 #[derive(Clone)]
 struct QubeDBServer {
-    databases: Arc<Mutex<HashMap<String, EmbeddedQubeDB>>>,
+    collections: Arc<Mutex<HashMap<String, VectorIndex>>>,
+    cors: CorsConfig,
 }
 
 impl QubeDBServer {
     fn new() -> Self {
         Self {
-            databases: Arc::new(Mutex::new(HashMap::new())),
+            collections: Arc::new(Mutex::new(HashMap::new())),
+            cors: CorsConfig::from_env(),
         }
     }
 
     fn handle_request(&self, request: &str) -> String {
+        let origin = request_header(request, "Origin");
+        // Browsers send a preflight OPTIONS request before cross-origin
+        // calls; answer it for any path
+        if request.starts_with("OPTIONS ") {
+            let response = "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n".to_string();
+            return with_headers(response, &self.cors.preflight_headers(origin));
+        }
+
+        let response = self.route_request(request);
+        with_headers(response, &self.cors.response_headers(origin))
+    }
+
+    fn route_request(&self, request: &str) -> String {
         // Parse HTTP request
         let lines: Vec<&str> = request.lines().collect();
         if lines.is_empty() {
@@ -46,34 +64,212 @@ impl QubeDBServer {
             ),
             ("POST", "/api/query") => self.handle_query_request(request),
             ("POST", "/api/connect") => self.handle_connect_request(request),
+            (_, path) if path.starts_with("/vectors/") => {
+                self.handle_vectors_request(method, &path["/vectors/".len()..], request)
+            }
             _ => self.create_response(404, "Not Found", r#"{"error": "Endpoint not found"}"#),
         }
     }
 
-    fn handle_query_request(&self, request: &str) -> String {
-        // Extract JSON body from request
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-
-        let body = &request[body_start.unwrap() + 4..];
-
-        // Parse JSON (simplified)
-        let query = if body.contains("\"query\"") {
-            // Extract query from JSON
-            if let Some(query_start) = body.find("\"query\":\"") {
-                let query_start = query_start + 9;
-                if let Some(query_end) = body[query_start..].find("\"") {
-                    &body[query_start..query_start + query_end]
-                } else {
-                    "SELECT 1 as test"
+    /// Route `/vectors/{collection}` and its `vectors` and `search` sub-resources
+    fn handle_vectors_request(&self, method: &str, path: &str, request: &str) -> String {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            ("POST", [collection]) => self.create_collection(collection, request),
+            ("GET", [collection]) => self.get_collection(collection),
+            ("DELETE", [collection]) => self.delete_collection(collection),
+            ("POST", [collection, "vectors"]) => self.insert_vector(collection, request),
+            ("POST", [collection, "search"]) => self.search_collection(collection, request),
+            _ => self.create_response(404, "Not Found", r#"{"error": "Endpoint not found"}"#),
+        }
+    }
+
+    /// POST /vectors/{collection} with `{"dimension": 3, "metric": "cosine"}`,
+    /// plus `"normalize": true` to store unit-length vectors
+    fn create_collection(&self, name: &str, request: &str) -> String {
+        let body = match self.json_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        let dimension = match body.get("dimension").and_then(|d| d.as_u64()) {
+            Some(dimension) if dimension > 0 => dimension as usize,
+            _ => {
+                return self.error_response(
+                    400,
+                    "Bad Request",
+                    "'dimension' must be a positive integer",
+                )
+            }
+        };
+        let metric = match body.get("metric") {
+            None => DistanceMetric::default(),
+            Some(metric) => match metric.as_str().map(str::parse::<DistanceMetric>) {
+                Some(Ok(metric)) => metric,
+                Some(Err(e)) => return self.error_response(400, "Bad Request", &e.to_string()),
+                None => {
+                    return self.error_response(400, "Bad Request", "'metric' must be a string")
                 }
-            } else {
-                "SELECT 1 as test"
+            },
+        };
+        let normalize = match body.get("normalize") {
+            None => false,
+            Some(normalize) => match normalize.as_bool() {
+                Some(normalize) => normalize,
+                None => {
+                    return self.error_response(400, "Bad Request", "'normalize' must be a boolean")
+                }
+            },
+        };
+
+        let mut collections = self.collections.lock().unwrap();
+        if collections.contains_key(name) {
+            return self.error_response(
+                409,
+                "Conflict",
+                &format!("Collection '{}' already exists", name),
+            );
+        }
+        let index = VectorIndex::with_metric(name.to_string(), dimension, metric)
+            .with_normalization(normalize);
+        let response = collection_json(&index);
+        collections.insert(name.to_string(), index);
+        self.create_response(201, "Created", &response.to_string())
+    }
+
+    /// GET /vectors/{collection}
+    fn get_collection(&self, name: &str) -> String {
+        let collections = self.collections.lock().unwrap();
+        match collections.get(name) {
+            Some(index) => self.create_response(200, "OK", &collection_json(index).to_string()),
+            None => self.collection_not_found(name),
+        }
+    }
+
+    /// DELETE /vectors/{collection}
+    fn delete_collection(&self, name: &str) -> String {
+        match self.collections.lock().unwrap().remove(name) {
+            Some(_) => self.create_response(200, "OK", &json!({ "deleted": name }).to_string()),
+            None => self.collection_not_found(name),
+        }
+    }
+
+    /// POST /vectors/{collection}/vectors with `{"id": "a", "vector": [0.1, 0.2, 0.3]}`
+    fn insert_vector(&self, name: &str, request: &str) -> String {
+        let body = match self.json_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let id = match body.get("id").and_then(|id| id.as_str()) {
+            Some(id) => id.to_string(),
+            None => return self.error_response(400, "Bad Request", "'id' must be a string"),
+        };
+        let vector = match body.get("vector").map(parse_vector) {
+            Some(Ok(vector)) => vector,
+            Some(Err(message)) => return self.error_response(400, "Bad Request", &message),
+            None => return self.error_response(400, "Bad Request", "'vector' is required"),
+        };
+
+        let mut collections = self.collections.lock().unwrap();
+        let index = match collections.get_mut(name) {
+            Some(index) => index,
+            None => return self.collection_not_found(name),
+        };
+        match index.insert(&id, &vector) {
+            Ok(()) => self.create_response(
+                201,
+                "Created",
+                &json!({ "id": id, "count": index.len() }).to_string(),
+            ),
+            Err(e) => self.qube_error_response(&e),
+        }
+    }
+
+    /// POST /vectors/{collection}/search with `{"vector": [...], "k": 10}`
+    fn search_collection(&self, name: &str, request: &str) -> String {
+        let body = match self.json_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let vector = match body.get("vector").map(parse_vector) {
+            Some(Ok(vector)) => vector,
+            Some(Err(message)) => return self.error_response(400, "Bad Request", &message),
+            None => return self.error_response(400, "Bad Request", "'vector' is required"),
+        };
+        let k = match body.get("k") {
+            None => 10,
+            Some(k) => match k.as_u64() {
+                Some(k) => k as usize,
+                None => {
+                    return self.error_response(
+                        400,
+                        "Bad Request",
+                        "'k' must be a non-negative integer",
+                    )
+                }
+            },
+        };
+
+        let collections = self.collections.lock().unwrap();
+        let index = match collections.get(name) {
+            Some(index) => index,
+            None => return self.collection_not_found(name),
+        };
+        match index.search(&vector, k) {
+            Ok(results) => {
+                let results: Vec<_> = results
+                    .into_iter()
+                    .map(|(id, score)| json!({ "id": id, "score": score }))
+                    .collect();
+                self.create_response(200, "OK", &json!({ "results": results }).to_string())
             }
-        } else {
-            "SELECT 1 as test"
+            Err(e) => self.qube_error_response(&e),
+        }
+    }
+
+    /// Parse the request body as a JSON object, or produce a 400 response
+    fn json_body(&self, request: &str) -> Result<serde_json::Value, String> {
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return Err(self.error_response(400, "Bad Request", "No body found")),
+        };
+        match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(value) if value.is_object() => Ok(value),
+            Ok(_) => Err(self.error_response(400, "Bad Request", "Body must be a JSON object")),
+            Err(e) => Err(self.error_response(400, "Bad Request", &format!("Invalid JSON: {}", e))),
+        }
+    }
+
+    fn collection_not_found(&self, name: &str) -> String {
+        self.error_response(
+            404,
+            "Not Found",
+            &format!("Collection '{}' not found", name),
+        )
+    }
+
+    /// Respond with the HTTP status that corresponds to a database error
+    fn qube_error_response(&self, error: &QubeError) -> String {
+        let (status_code, status_text) = error_status(error);
+        self.error_response(status_code, status_text, &error.to_string())
+    }
+
+    fn error_response(&self, status_code: u16, status_text: &str, message: &str) -> String {
+        self.create_response(
+            status_code,
+            status_text,
+            &json!({ "error": message }).to_string(),
+        )
+    }
+
+    fn handle_query_request(&self, request: &str) -> String {
+        let body = match self.json_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let query = match body.get("query").and_then(|query| query.as_str()) {
+            Some(query) => query,
+            None => return self.error_response(400, "Bad Request", "'query' must be a string"),
         };
 
         // Execute query (simplified)
@@ -106,7 +302,7 @@ impl QubeDBServer {
 
     fn create_response(&self, status_code: u16, status_text: &str, body: &str) -> String {
         format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\n\r\n{}",
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
             status_code,
             status_text,
             body.len(),
@@ -115,6 +311,81 @@ impl QubeDBServer {
     }
 }
 
+/// HTTP status for a database error
+fn error_status(error: &QubeError) -> (u16, &'static str) {
+    let status_code = error.http_status();
+    let status_text = match status_code {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    (status_code, status_text)
+}
+
+/// Extract the body of a raw HTTP request, if it has one
+fn request_body(request: &str) -> Option<&str> {
+    request
+        .find("\r\n\r\n")
+        .map(|body_start| &request[body_start + 4..])
+}
+
+/// Look up a header of a raw HTTP request by case-insensitive name
+fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()?
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+}
+
+/// Insert extra headers after the status line of a response
+fn with_headers(response: String, headers: &[(&str, String)]) -> String {
+    if headers.is_empty() {
+        return response;
+    }
+    let (status_line, rest) = response.split_once("\r\n").unwrap_or((&response, ""));
+    let mut out = format!("{}\r\n", status_line);
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Public description of a vector collection
+fn collection_json(index: &VectorIndex) -> serde_json::Value {
+    json!({
+        "name": index.name(),
+        "dimension": index.dimensions(),
+        "metric": index.metric().to_string(),
+        "normalize": index.is_normalized(),
+        "count": index.len(),
+    })
+}
+
+/// Parse a JSON array of numbers into a vector
+fn parse_vector(value: &serde_json::Value) -> Result<Vec<f32>, String> {
+    let items = value
+        .as_array()
+        .ok_or_else(|| "'vector' must be an array of numbers".to_string())?;
+    items
+        .iter()
+        .map(|item| {
+            item.as_f64()
+                .map(|v| v as f32)
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| format!("Invalid vector component: {}", item))
+        })
+        .collect()
+}
+
 fn main() {
     // Initialize logging
     let config = LoggerConfig::default();
@@ -167,3 +438,184 @@ fn handle_client(mut stream: TcpStream, server: QubeDBServer) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a request and return its status code and JSON body
+    fn send(
+        server: &QubeDBServer,
+        method: &str,
+        path: &str,
+        body: Option<&str>,
+    ) -> (u16, serde_json::Value) {
+        let request = match body {
+            Some(body) => format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n{}", method, path, body),
+            None => format!("{} {} HTTP/1.1\r\nHost: test\r\n", method, path),
+        };
+        let response = server.route_request(&request);
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn vector_collections_are_created_filled_and_searched() {
+        let server = QubeDBServer::new();
+        let (status, created) = send(
+            &server,
+            "POST",
+            "/vectors/points",
+            Some(r#"{"dimension": 2, "metric": "l2"}"#),
+        );
+        assert_eq!(status, 201);
+        assert_eq!(created["metric"], "euclidean");
+        assert_eq!(created["normalize"], false);
+
+        for (id, vector) in [("a", "[0, 0]"), ("b", "[3, 4]"), ("c", "[1, 1]")] {
+            let body = format!(r#"{{"id": "{}", "vector": {}}}"#, id, vector);
+            let (status, _) = send(&server, "POST", "/vectors/points/vectors", Some(&body));
+            assert_eq!(status, 201);
+        }
+        let (status, found) = send(
+            &server,
+            "POST",
+            "/vectors/points/search",
+            Some(r#"{"vector": [3, 3], "k": 2}"#),
+        );
+        assert_eq!(status, 200);
+        let ids: Vec<&str> = found["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(found["results"][0]["score"], 1.0);
+
+        let (_, described) = send(&server, "GET", "/vectors/points", None);
+        assert_eq!(described["count"], 3);
+        assert_eq!(described["dimension"], 2);
+        assert_eq!(send(&server, "DELETE", "/vectors/points", None).0, 200);
+        assert_eq!(send(&server, "GET", "/vectors/points", None).0, 404);
+    }
+
+    #[test]
+    fn bad_vector_requests_are_refused() {
+        let server = QubeDBServer::new();
+        let create = |body: &str| send(&server, "POST", "/vectors/v", Some(body)).0;
+        assert_eq!(create(r#"{"dimension": 0}"#), 400);
+        assert_eq!(create(r#"{"dimension": 2, "metric": "manhattan"}"#), 400);
+        assert_eq!(create(r#"{"dimension": 2}"#), 201);
+        assert_eq!(create(r#"{"dimension": 2}"#), 409);
+
+        let insert = |body: &str| send(&server, "POST", "/vectors/v/vectors", Some(body)).0;
+        assert_eq!(insert(r#"{"id": "a", "vector": [1, 2, 3]}"#), 400);
+        assert_eq!(insert(r#"{"id": "a", "vector": [1, "x"]}"#), 400);
+        assert_eq!(insert(r#"{"vector": [1, 2]}"#), 400);
+        let (status, _) = send(
+            &server,
+            "POST",
+            "/vectors/other/search",
+            Some(r#"{"vector": [1, 2]}"#),
+        );
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn missing_or_malformed_bodies_are_bad_requests() {
+        let server = QubeDBServer::new();
+        let query = |body: Option<&str>| send(&server, "POST", "/api/query", body);
+        for body in [
+            None,
+            Some("{not json"),
+            Some("[1, 2]"),
+            Some(r#"{"query": 1}"#),
+        ] {
+            let (status, error) = query(body);
+            assert_eq!(status, 400, "{:?}", body);
+            assert!(error["error"].is_string());
+        }
+        assert_eq!(query(None).1["error"], "No body found");
+        assert_eq!(query(Some(r#"{"query": "SELECT 1"}"#)).0, 200);
+    }
+
+    #[test]
+    fn cors_headers_follow_the_configured_origins() {
+        let mut server = QubeDBServer::new();
+        server.cors = CorsConfig {
+            allowed_origins: vec!["https://app.example".to_string()],
+            ..CorsConfig::default()
+        };
+        let get = |origin: &str| {
+            server.handle_request(&format!(
+                "GET /api/health HTTP/1.1\r\nHost: test\r\norigin: {}\r\n\r\n",
+                origin
+            ))
+        };
+        let allowed = get("https://app.example");
+        assert!(allowed.starts_with(
+            "HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: https://app.example\r\n"
+        ));
+        assert!(allowed.contains("\r\nVary: Origin\r\n"));
+        assert!(!get("https://evil.example").contains("Access-Control-Allow-Origin"));
+        let no_origin = server.handle_request("GET /api/health HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert_eq!(
+            no_origin,
+            server.route_request("GET /api/health HTTP/1.1\r\nHost: test\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn preflights_are_answered_for_any_path() {
+        let mut server = QubeDBServer::new();
+        server.cors = CorsConfig {
+            allowed_origins: vec!["https://app.example".to_string()],
+            ..CorsConfig::default()
+        };
+        let preflight = |path: &str, origin: &str| {
+            server.handle_request(&format!(
+                "OPTIONS {} HTTP/1.1\r\nHost: test\r\nOrigin: {}\r\n\r\n",
+                path, origin
+            ))
+        };
+        for path in ["/api/query", "/vectors/points/search", "/nowhere"] {
+            let allowed = preflight(path, "https://app.example");
+            assert!(allowed.starts_with("HTTP/1.1 204 No Content\r\n"));
+            assert!(allowed.contains("Access-Control-Allow-Methods: GET, POST, DELETE, OPTIONS"));
+            assert!(allowed.ends_with("Content-Length: 0\r\n\r\n"));
+        }
+        let refused = preflight("/api/query", "https://evil.example");
+        assert_eq!(
+            refused,
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn collections_can_store_normalized_vectors() {
+        let server = QubeDBServer::new();
+        let create = |name: &str, body: &str| {
+            let path = format!("/vectors/{}", name);
+            send(&server, "POST", &path, Some(body))
+        };
+        let (status, created) = create("unit", r#"{"dimension": 2, "normalize": true}"#);
+        assert_eq!(status, 201);
+        assert_eq!(created["normalize"], true);
+        let (status, _) = create("bad", r#"{"dimension": 2, "normalize": "yes"}"#);
+        assert_eq!(status, 400);
+
+        let body = r#"{"id": "a", "vector": [0, 4]}"#;
+        assert_eq!(
+            send(&server, "POST", "/vectors/unit/vectors", Some(body)).0,
+            201
+        );
+        let search = Some(r#"{"vector": [0, 1], "k": 1}"#);
+        let (_, found) = send(&server, "POST", "/vectors/unit/search", search);
+        assert_eq!(found["results"][0]["id"], "a");
+        assert_eq!(found["results"][0]["score"], 1.0);
+        let (_, described) = send(&server, "GET", "/vectors/unit", None);
+        assert_eq!(described["normalize"], true);
+    }
+}
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use qubedb_core::api::CorsConfig;
 
 /// Simple WAL Entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -151,16 +152,31 @@ struct StoreStats {
 }
 
 /// Simple HTTP Server
+#[derive(Clone)]
 struct SimpleServer {
     store: Arc<SimpleKVStore>,
+    cors: CorsConfig,
 }
 
 impl SimpleServer {
     fn new(store: Arc<SimpleKVStore>) -> Self {
-        Self { store }
+        Self { store, cors: CorsConfig::from_env() }
     }
     
     fn handle_request(&self, request: &str) -> String {
+        let origin = request_header(request, "Origin");
+        // Browsers send a preflight OPTIONS request before cross-origin
+        // calls; answer it for any path
+        if request.starts_with("OPTIONS ") {
+            let response = "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n".to_string();
+            return with_headers(response, &self.cors.preflight_headers(origin));
+        }
+    
+        let response = self.route_request(request);
+        with_headers(response, &self.cors.response_headers(origin))
+    }
+    
+    fn route_request(&self, request: &str) -> String {
         let lines: Vec<&str> = request.lines().collect();
         if lines.is_empty() {
             return self.create_response(400, "Bad Request", "Empty request");
@@ -207,12 +223,10 @@ impl SimpleServer {
     }
     
     fn handle_put_request(&self, request: &str) -> String {
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-        
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         #[derive(Deserialize)]
         struct PutRequest {
@@ -241,12 +255,10 @@ impl SimpleServer {
     }
     
     fn handle_get_request(&self, request: &str) -> String {
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-        
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         #[derive(Deserialize)]
         struct GetRequest {
@@ -278,12 +290,10 @@ impl SimpleServer {
     }
     
     fn handle_delete_request(&self, request: &str) -> String {
-        let body_start = request.find("\r\n\r\n");
-        if body_start.is_none() {
-            return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#);
-        }
-        
-        let body = &request[body_start.unwrap() + 4..];
+        let body = match request_body(request) {
+            Some(body) => body,
+            None => return self.create_response(400, "Bad Request", r#"{"error": "No body found"}"#),
+        };
         
         #[derive(Deserialize)]
         struct DeleteRequest {
@@ -315,7 +325,7 @@ impl SimpleServer {
     
     fn create_response(&self, status_code: u16, status_text: &str, body: &str) -> String {
         format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\n\r\n{}",
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
             status_code,
             status_text,
             body.len(),
@@ -324,6 +334,33 @@ impl SimpleServer {
     }
 }
 
+/// Extract the body of a raw HTTP request, if it has one
+fn request_body(request: &str) -> Option<&str> {
+    request.find("\r\n\r\n").map(|body_start| &request[body_start + 4..])
+}
+
+/// Look up a header of a raw HTTP request by case-insensitive name
+fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.split("\r\n\r\n").next()?.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Insert extra headers after the status line of a response
+fn with_headers(response: String, headers: &[(&str, String)]) -> String {
+    if headers.is_empty() {
+        return response;
+    }
+    let (status_line, rest) = response.split_once("\r\n").unwrap_or((&response, ""));
+    let mut out = format!("{}\r\n", status_line);
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(rest);
+    out
+}
+
 fn main() {
     println!("🦀 Starting QubeDB Real Database Server...");
     println!("📍 Server will run on: http://localhost:8080");
@@ -385,3 +422,60 @@ fn handle_client(mut stream: std::net::TcpStream, server: SimpleServer) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str) -> (SimpleServer, String) {
+        let dir = std::env::temp_dir().join(format!("qubedb-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().into_owned();
+        let store = SimpleKVStore::new(&dir).unwrap();
+        (SimpleServer::new(Arc::new(store)), dir)
+    }
+
+    fn post(server: &SimpleServer, path: &str, body: Option<&str>) -> u16 {
+        let request = match body {
+            Some(body) => format!("POST {} HTTP/1.1\r\nHost: test\r\n\r\n{}", path, body),
+            None => format!("POST {} HTTP/1.1\r\nHost: test\r\n", path),
+        };
+        server.route_request(&request)[9..12].parse().unwrap()
+    }
+
+    #[test]
+    fn missing_or_malformed_bodies_are_bad_requests() {
+        let (server, dir) = server("simple-server-bodies");
+        for path in ["/api/put", "/api/get", "/api/delete"] {
+            assert_eq!(post(&server, path, None), 400);
+            assert_eq!(post(&server, path, Some("{not json")), 400);
+            assert_eq!(post(&server, path, Some(r#"{"other": "field"}"#)), 400);
+        }
+        assert_eq!(post(&server, "/api/put", Some(r#"{"key": "a", "value": "1"}"#)), 200);
+        assert_eq!(server.store.get("a").unwrap().as_deref(), Some("1"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn preflights_are_answered_for_allowed_origins() {
+        let (mut server, dir) = server("simple-server-preflight");
+        server.cors = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            ..CorsConfig::default()
+        };
+        let preflight = server.handle_request(
+            "OPTIONS /api/put HTTP/1.1\r\nHost: test\r\nOrigin: https://app.example\r\n\r\n",
+        );
+        assert!(preflight.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Headers: Content-Type\r\n"));
+
+        server.cors = CorsConfig::default();
+        let preflight = server.handle_request(
+            "OPTIONS /api/put HTTP/1.1\r\nHost: test\r\nOrigin: https://app.example\r\n\r\n",
+        );
+        assert!(!preflight.contains("Access-Control-"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1344 @@
+//! Table catalog for QubeDB
+//!
+//! Keeps track of table definitions, their indexes and their rows so the
+//! query engine can resolve names, scan data and answer introspection
+//! queries.
+//!
+//! Each table also keeps the history of its rows for a retention window
+//! (`DEFAULT_HISTORY_RETENTION` unless configured for the catalog or the
+//! table): every change is logged with the row as it was before, so the
+//! table can be rebuilt as it was at any moment inside the window for
+//! `SELECT ... FROM t AS OF TIMESTAMP`. Changes that fall out of the window,
+//! or beyond the number of versions the table keeps, are reclaimed as the
+//! table is written, by VACUUM and as soon as the retention shrinks. The
+//! history is kept in memory only: an engine reopened from its log starts
+//! a new one.
+
+use crate::columnar::{ColumnStore, ColumnStoreStats, CHUNK_ROWS};
+use crate::error::{QubeError, QubeResult};
+use crate::index::{BTreeIndex, IndexIssue, IndexIssueKind, IndexKey};
+use crate::namespace;
+use crate::quota::{self, Headroom, MemoryUsage, Quota, QuotaUsage};
+use crate::stats::TableStats;
+use crate::types::{Index, IndexType, Row, StorageLayout, Table, Value};
+use crate::view::View;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long tables keep the history of their rows unless configured
+pub const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How much of the history of a table's rows is kept for AS OF reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRetention {
+    /// How long changes are kept; zero keeps no history
+    pub duration: Duration,
+    /// Most changes kept, however recent, when limited
+    pub max_versions: Option<usize>,
+}
+
+impl HistoryRetention {
+    pub fn new(duration: Duration) -> Self {
+        HistoryRetention {
+            duration,
+            max_versions: None,
+        }
+    }
+
+    /// Keep no more than the last `max_versions` changes
+    pub fn with_max_versions(mut self, max_versions: usize) -> Self {
+        self.max_versions = Some(max_versions);
+        self
+    }
+
+    /// Retention keeping no history at all
+    pub fn none() -> Self {
+        HistoryRetention::new(Duration::ZERO)
+    }
+
+    pub fn keeps_history(&self) -> bool {
+        !self.duration.is_zero() && self.max_versions != Some(0)
+    }
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention::new(DEFAULT_HISTORY_RETENTION)
+    }
+}
+
+/// One change to a table's rows, for reading the table as it was before
+#[derive(Clone)]
+struct RowChange {
+    /// When the change was made, in milliseconds since the Unix epoch
+    at: i64,
+    row_id: u64,
+    /// The row before the change, `None` if the change inserted it
+    before: Option<Row>,
+}
+
+impl RowChange {
+    /// Estimated size, see `quota::row_size`
+    fn size(&self) -> u64 {
+        std::mem::size_of::<RowChange>() as u64 + self.before.as_ref().map_or(0, quota::row_size)
+    }
+}
+
+/// Rows and bookkeeping for a single table
+#[derive(Clone)]
+pub struct TableData {
+    /// Rows keyed by an internal, monotonically increasing row id. For a
+    /// columnar table these are only the rows not yet written to a chunk.
+    rows: BTreeMap<u64, Row>,
+    /// Column chunks, for a columnar table
+    columnar: Option<ColumnStore>,
+    /// Entries of the table's B-Tree indexes, by index name
+    indexes: HashMap<String, BTreeIndex>,
+    next_row_id: u64,
+    /// Estimated size of the rows, see `quota::row_size`
+    bytes: u64,
+    /// Rows inserted, updated or deleted since statistics were last collected
+    pub modified_rows: usize,
+    /// Statistics from the last ANALYZE, if any
+    pub stats: Option<TableStats>,
+    /// Last value handed out to the table's auto-increment column. Copies
+    /// of the table share it, so a transaction writing to a snapshot never
+    /// hands out a value the shared table or another snapshot has.
+    sequence: Arc<Mutex<i64>>,
+    /// Rows changed while the table's catalog journals its writes, each
+    /// with the row as it was before its first change (`None` if it was
+    /// inserted)
+    journal: Option<BTreeMap<u64, Option<Row>>>,
+    /// Changes made within the retention window, oldest first
+    history: VecDeque<RowChange>,
+    /// Estimated size of the history
+    history_bytes: u64,
+    /// Earliest moment the table can be read as of
+    history_since: i64,
+    /// How much history is kept
+    retention: HistoryRetention,
+    /// Retention set for this table rather than taken from the catalog
+    own_retention: Option<HistoryRetention>,
+}
+
+impl TableData {
+    fn new(table: &Table) -> Self {
+        let columnar = match table.storage {
+            StorageLayout::Row => None,
+            StorageLayout::Columnar => Some(ColumnStore::new(
+                table.columns.iter().map(|c| c.name.clone()).collect(),
+            )),
+        };
+        let mut data = TableData {
+            rows: BTreeMap::new(),
+            columnar,
+            indexes: HashMap::new(),
+            next_row_id: 1,
+            bytes: 0,
+            modified_rows: 0,
+            stats: None,
+            sequence: Arc::default(),
+            journal: None,
+            history: VecDeque::new(),
+            history_bytes: 0,
+            history_since: now_millis(),
+            retention: HistoryRetention::none(),
+            own_retention: None,
+        };
+        for index in &table.indexes {
+            data.build_index(index);
+        }
+        data
+    }
+
+    /// Data of `table` holding `rows`, kept outside any catalog
+    pub(crate) fn from_rows(table: &Table, rows: impl IntoIterator<Item = Row>) -> Self {
+        let mut data = TableData::new(table);
+        for row in rows {
+            data.insert(row);
+        }
+        data
+    }
+
+    /// Number of rows in the table
+    pub fn len(&self) -> usize {
+        self.rows.len() + self.columnar.as_ref().map_or(0, ColumnStore::len)
+    }
+
+    /// Estimated size of the rows in bytes
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All rows with their row ids, in row id order
+    pub fn rows(&self) -> impl Iterator<Item = (u64, Cow<'_, Row>)> + Clone + '_ {
+        self.rows_with(None)
+    }
+
+    /// Rows in row id order. A columnar table only reads `columns` (every
+    /// column when `None`), so the rows it returns hold just those; a row
+    /// table returns whole rows.
+    pub fn rows_with(
+        &self,
+        columns: Option<&BTreeSet<String>>,
+    ) -> impl Iterator<Item = (u64, Cow<'_, Row>)> + Clone + '_ {
+        let columns = columns.cloned();
+        self.columnar
+            .iter()
+            .flat_map(move |store| store.rows(columns.as_ref()))
+            .map(|(row_id, row)| (row_id, Cow::Owned(row)))
+            .chain(
+                self.rows
+                    .iter()
+                    .map(|(&row_id, row)| (row_id, Cow::Borrowed(row))),
+            )
+    }
+
+    /// The row stored under `row_id`. A columnar table only reads
+    /// `columns` (every column when `None`).
+    pub fn row(&self, row_id: u64, columns: Option<&BTreeSet<String>>) -> Option<Cow<'_, Row>> {
+        if let Some(row) = self.rows.get(&row_id) {
+            return Some(Cow::Borrowed(row));
+        }
+        self.columnar.as_ref()?.row(row_id, columns).map(Cow::Owned)
+    }
+
+    /// A B-Tree index over this table's rows
+    pub fn index(&self, name: &str) -> Option<&BTreeIndex> {
+        self.indexes.get(name)
+    }
+
+    /// Cross-check every B-Tree index against the rows: each entry must
+    /// point at a live row holding its key, and each row must have an
+    /// entry under its key
+    pub fn verify_indexes(&self, table: &str) -> Vec<IndexIssue> {
+        let mut names: Vec<&String> = self.indexes.keys().collect();
+        names.sort();
+        let mut issues = Vec::new();
+        for name in names {
+            let index = &self.indexes[name];
+            let columns: BTreeSet<String> = index.columns().iter().cloned().collect();
+            let issue = |row_id, key: Vec<Value>, kind| IndexIssue {
+                table: table.to_string(),
+                index: name.clone(),
+                row_id,
+                key,
+                kind,
+            };
+            for (key, row_id) in index.entries() {
+                match self.row(row_id, Some(&columns)) {
+                    None => issues.push(issue(row_id, key.to_vec(), IndexIssueKind::DanglingEntry)),
+                    Some(row) => {
+                        let row_key = index.key_of(&row);
+                        if IndexKey(row_key.clone()) != IndexKey(key.to_vec()) {
+                            issues.push(issue(
+                                row_id,
+                                key.to_vec(),
+                                IndexIssueKind::StaleEntry { row_key },
+                            ));
+                        }
+                    }
+                }
+            }
+            for (row_id, row) in self.rows_with(Some(&columns)) {
+                let key = index.key_of(&row);
+                if !index.contains(key.clone(), row_id) {
+                    issues.push(issue(row_id, key, IndexIssueKind::MissingEntry));
+                }
+            }
+        }
+        issues
+    }
+
+    /// Build the entries of a B-Tree index from the current rows. Other
+    /// index types keep no entries.
+    fn build_index(&mut self, index: &Index) {
+        if !matches!(index.index_type, IndexType::BTree) {
+            return;
+        }
+        let mut entries = BTreeIndex::new(index.name.clone(), index.columns.clone());
+        let columns: BTreeSet<String> = index.columns.iter().cloned().collect();
+        for (row_id, row) in self.rows_with(Some(&columns)) {
+            entries.insert(entries.key_of(&row), row_id);
+        }
+        self.indexes.insert(index.name.clone(), entries);
+    }
+
+    /// Append a row and return its row id
+    pub fn insert(&mut self, row: Row) -> u64 {
+        let row_id = self.next_row_id;
+        self.next_row_id += 1;
+        for index in self.indexes.values_mut() {
+            index.insert(index.key_of(&row), row_id);
+        }
+        self.bytes += quota::row_size(&row);
+        self.rows.insert(row_id, row);
+        self.modified_rows += 1;
+        self.record(row_id, None);
+        if let Some(store) = &mut self.columnar {
+            if self.rows.len() >= CHUNK_ROWS {
+                store.push_chunk(std::mem::take(&mut self.rows));
+            }
+        }
+        row_id
+    }
+
+    /// Replace the row stored under `row_id`
+    pub fn update(&mut self, row_id: u64, row: Row) {
+        self.modified_rows += 1;
+        let before = self.row(row_id, None).map(Cow::into_owned);
+        let old_size = before.as_ref().map_or(0, quota::row_size);
+        self.bytes = self.bytes.saturating_sub(old_size) + quota::row_size(&row);
+        self.record(row_id, before.as_ref());
+        if !self.indexes.is_empty() {
+            let columns: BTreeSet<String> = self
+                .indexes
+                .values()
+                .flat_map(|index| index.columns().iter().cloned())
+                .collect();
+            let old = self.row(row_id, Some(&columns)).map(Cow::into_owned);
+            for index in self.indexes.values_mut() {
+                if let Some(old) = &old {
+                    index.remove(index.key_of(old), row_id);
+                }
+                index.insert(index.key_of(&row), row_id);
+            }
+        }
+        if let Some(store) = &mut self.columnar {
+            if !self.rows.contains_key(&row_id) && store.update(row_id, row.clone()) {
+                return;
+            }
+        }
+        self.rows.insert(row_id, row);
+    }
+
+    /// Remove the row stored under `row_id`, returning it
+    pub fn remove(&mut self, row_id: u64) -> Option<Row> {
+        let row = match self.rows.remove(&row_id) {
+            Some(row) => row,
+            None => self.columnar.as_mut()?.remove(row_id)?,
+        };
+        for index in self.indexes.values_mut() {
+            index.remove(index.key_of(&row), row_id);
+        }
+        self.bytes = self.bytes.saturating_sub(quota::row_size(&row));
+        self.modified_rows += 1;
+        self.record(row_id, Some(&row));
+        Some(row)
+    }
+
+    /// Remove several rows at once, updating each index in a single pass.
+    /// Returns the rows that existed, in the order given.
+    pub fn remove_many(&mut self, row_ids: &[u64]) -> Vec<Row> {
+        let mut removed = Vec::with_capacity(row_ids.len());
+        let mut ids = Vec::with_capacity(row_ids.len());
+        for &row_id in row_ids {
+            let row = match self.rows.remove(&row_id) {
+                Some(row) => Some(row),
+                None => self
+                    .columnar
+                    .as_mut()
+                    .and_then(|store| store.remove(row_id)),
+            };
+            if let Some(row) = row {
+                removed.push(row);
+                ids.push(row_id);
+            }
+        }
+        for index in self.indexes.values_mut() {
+            for (row, &row_id) in removed.iter().zip(&ids) {
+                index.remove(index.key_of(row), row_id);
+            }
+        }
+        let bytes: u64 = removed.iter().map(quota::row_size).sum();
+        self.bytes = self.bytes.saturating_sub(bytes);
+        self.modified_rows += removed.len();
+        for (row, &row_id) in removed.iter().zip(&ids) {
+            self.record(row_id, Some(row));
+        }
+        removed
+    }
+
+    /// Log a change to the row `row_id`, which held `before`, dropping the
+    /// changes that fell out of the retention window
+    fn record(&mut self, row_id: u64, before: Option<&Row>) {
+        if let Some(journal) = &mut self.journal {
+            journal.entry(row_id).or_insert_with(|| before.cloned());
+        }
+        if !self.retention.keeps_history() {
+            return;
+        }
+        // Keep the log in order even if the clock steps back
+        let now = now_millis();
+        let at = self.history.back().map_or(now, |last| last.at.max(now));
+        let change = RowChange {
+            at,
+            row_id,
+            before: before.cloned(),
+        };
+        self.history_bytes += change.size();
+        self.history.push_back(change);
+        self.prune_history(at);
+    }
+
+    /// Drop the oldest change, returning its size. The table can no
+    /// longer be read as of a moment before it.
+    fn pop_history(&mut self) -> Option<u64> {
+        let change = self.history.pop_front()?;
+        self.history_since = self.history_since.max(change.at);
+        self.history_bytes -= change.size();
+        Some(change.size())
+    }
+
+    /// Drop the changes made before the retention window ending at `now`,
+    /// and the oldest beyond the versions kept, returning how many were
+    /// dropped. The table can no longer be read as of a moment before them.
+    fn prune_history(&mut self, now: i64) -> usize {
+        let horizon = self.horizon(now);
+        let max_versions = self.retention.max_versions.unwrap_or(usize::MAX);
+        let mut dropped = 0;
+        while let Some(change) = self.history.front() {
+            if change.at >= horizon && self.history.len() <= max_versions {
+                break;
+            }
+            self.pop_history();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Reclaim the changes that fell out of the retention window since the
+    /// table was last written. Returns how many were dropped.
+    pub fn reclaim_history(&mut self) -> usize {
+        self.prune_history(now_millis())
+    }
+
+    /// Start of the retention window ending at `now`
+    fn horizon(&self, now: i64) -> i64 {
+        now.saturating_sub(self.retention.duration.as_millis() as i64)
+    }
+
+    /// Number of past versions kept, one per change
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Estimated size of the past versions kept
+    pub fn history_bytes(&self) -> u64 {
+        self.history_bytes
+    }
+
+    /// How much history the table keeps
+    pub fn history_retention(&self) -> HistoryRetention {
+        self.retention
+    }
+
+    /// The retention set for this table, if it does not use the catalog's
+    pub fn own_history_retention(&self) -> Option<HistoryRetention> {
+        self.own_retention
+    }
+
+    /// Keep the history of the rows as `retention` says from now on,
+    /// reclaiming what it no longer covers at once. Returns how many
+    /// changes were dropped.
+    fn apply_history_retention(&mut self, retention: HistoryRetention) -> usize {
+        let now = now_millis();
+        if !self.retention.keeps_history() {
+            self.history_since = now;
+        }
+        self.retention = retention;
+        if retention.keeps_history() {
+            self.prune_history(now)
+        } else {
+            let dropped = self.history.len();
+            self.history.clear();
+            self.history_bytes = 0;
+            dropped
+        }
+    }
+
+    /// The rows of `table` as they were at `at`, in milliseconds since the
+    /// Unix epoch, as data of their own. Fails with `NotFound` when `at`
+    /// lies before the retention window or before the history kept.
+    pub fn as_of(&self, table: &Table, at: i64) -> QubeResult<TableData> {
+        let earliest = self.history_since.max(self.horizon(now_millis()));
+        if at < earliest {
+            return Err(QubeError::NotFound(format!(
+                "Version of table '{}' as of {}: history is only kept from {}",
+                table.name,
+                format_millis(at),
+                format_millis(earliest)
+            )));
+        }
+        let mut rows: BTreeMap<u64, Row> = self
+            .rows()
+            .map(|(row_id, row)| (row_id, row.into_owned()))
+            .collect();
+        // Undo every change made after `at`, newest first
+        for change in self
+            .history
+            .iter()
+            .rev()
+            .take_while(|change| change.at > at)
+        {
+            match &change.before {
+                Some(row) => rows.insert(change.row_id, row.clone()),
+                None => rows.remove(&change.row_id),
+            };
+        }
+        Ok(TableData::from_rows(table, rows.into_values()))
+    }
+
+    /// Reclaim the space that updates and deletes left behind: refill
+    /// partly empty column chunks, rebuild the B-Tree indexes of `table`
+    /// from the live rows and recount their size. Returns the number of
+    /// rows scanned.
+    pub fn vacuum(&mut self, table: &Table) -> usize {
+        if let Some(store) = &mut self.columnar {
+            store.compact();
+        }
+        self.indexes.clear();
+        for index in &table.indexes {
+            self.build_index(index);
+        }
+        let mut scanned = 0;
+        let mut bytes = 0;
+        for (_, row) in self.rows() {
+            scanned += 1;
+            bytes += quota::row_size(&row);
+        }
+        self.bytes = bytes;
+        scanned
+    }
+
+    /// Make sure the sequence never hands out `value` or anything below it,
+    /// e.g. after a row was inserted with an explicit id
+    pub fn advance_sequence(&self, value: i64) {
+        let mut sequence = self.sequence.lock().unwrap();
+        *sequence = (*sequence).max(value);
+    }
+
+    /// Last value handed out to the table's auto-increment column
+    pub fn sequence(&self) -> i64 {
+        *self.sequence.lock().unwrap()
+    }
+
+    /// The sequence itself, for an INSERT to hold while it numbers its rows
+    pub(crate) fn shared_sequence(&self) -> Arc<Mutex<i64>> {
+        Arc::clone(&self.sequence)
+    }
+
+    /// The changes recorded in the journal since it was last taken, as
+    /// each row was before and is now
+    fn take_journal(&mut self) -> Vec<(u64, Option<Row>, Option<Row>)> {
+        let journal = match &mut self.journal {
+            Some(journal) => std::mem::take(journal),
+            None => return Vec::new(),
+        };
+        journal
+            .into_iter()
+            .map(|(row_id, before)| {
+                let after = self.row(row_id, None).map(Cow::into_owned);
+                (row_id, before, after)
+            })
+            .filter(|(_, before, after)| before != after)
+            .collect()
+    }
+
+    /// Chunk counters, for a columnar table
+    pub fn column_store_stats(&self) -> Option<ColumnStoreStats> {
+        self.columnar.as_ref().map(|store| ColumnStoreStats {
+            chunks: store.chunk_count(),
+            chunked_rows: store.len(),
+            buffered_rows: self.rows.len(),
+            values_read: store.values_read(),
+        })
+    }
+}
+
+/// What a journaled write did to one row of a table
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowEffect {
+    pub table: String,
+    /// Id of the row in the catalog that journaled the write
+    pub row_id: u64,
+    /// The row before the write, `None` if the write inserted it
+    pub before: Option<Row>,
+    /// The row after the write, `None` if the write deleted it
+    pub after: Option<Row>,
+}
+
+/// Catalog of all tables known to a query engine.
+///
+/// Cloning a catalog only copies the table definitions: the rows of each
+/// table are shared until one of the copies writes to them.
+#[derive(Clone)]
+pub struct Catalog {
+    tables: HashMap<String, Table>,
+    data: HashMap<String, Arc<TableData>>,
+    /// Databases created besides the default one
+    databases: BTreeSet<String>,
+    /// How much history of their rows tables keep, unless set per table
+    history_retention: HistoryRetention,
+    /// Quotas of the databases that have one
+    quotas: HashMap<String, Quota>,
+    /// Views, in the order they were created
+    views: Vec<View>,
+    /// Whether tables journal the rows written to them, see `start_journal`
+    journaling: bool,
+    /// Soft cap on the estimated memory held by all tables, see `quota`
+    memory_limit: Option<u64>,
+}
+
+impl Catalog {
+    /// Create an empty catalog
+    pub fn new() -> Self {
+        Catalog {
+            tables: HashMap::new(),
+            data: HashMap::new(),
+            databases: BTreeSet::new(),
+            history_retention: HistoryRetention::default(),
+            quotas: HashMap::new(),
+            views: Vec::new(),
+            journaling: false,
+            memory_limit: None,
+        }
+    }
+
+    /// Register a new, empty database
+    pub fn create_database(&mut self, name: &str) -> QubeResult<()> {
+        if name.contains('.') {
+            return Err(QubeError::QueryParse(format!(
+                "Invalid database name '{}'",
+                name
+            )));
+        }
+        if self.has_database(name) {
+            return Err(QubeError::AlreadyExists(format!("Database '{}'", name)));
+        }
+        self.databases.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Remove a database and every table and view in it, returning the
+    /// tables' names. The default database cannot be dropped.
+    pub fn drop_database(&mut self, name: &str) -> QubeResult<Vec<String>> {
+        if namespace::is_default(name) {
+            return Err(QubeError::Other(format!(
+                "The default database '{}' cannot be dropped",
+                name
+            )));
+        }
+        if !self.databases.remove(name) {
+            return Err(QubeError::DatabaseNotFound(name.to_string()));
+        }
+        self.quotas.remove(name);
+        self.views
+            .retain(|view| view.is_materialized() || namespace::split(&view.name).0 != name);
+        let tables: Vec<String> = self
+            .tables
+            .keys()
+            .filter(|table| namespace::split(table).0 == name)
+            .cloned()
+            .collect();
+        for table in &tables {
+            self.drop_table(table)?;
+        }
+        Ok(tables)
+    }
+
+    /// Whether a database with this name exists
+    pub fn has_database(&self, name: &str) -> bool {
+        namespace::is_default(name) || self.databases.contains(name)
+    }
+
+    /// Names of all databases, the default one first
+    pub fn list_databases(&self) -> Vec<String> {
+        std::iter::once(namespace::DEFAULT_DATABASE.to_string())
+            .chain(self.databases.iter().cloned())
+            .collect()
+    }
+
+    /// Limit what a database may hold. Data already over the new limits
+    /// stays, but nothing more can be added until enough is removed.
+    pub fn set_quota(&mut self, database: &str, quota: Quota) -> QubeResult<()> {
+        if !self.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+        if quota.is_unlimited() {
+            self.quotas.remove(database);
+        } else {
+            self.quotas.insert(database.to_string(), quota);
+        }
+        Ok(())
+    }
+
+    /// The quota of a database, unlimited unless one was set
+    pub fn quota(&self, database: &str) -> Quota {
+        self.quotas.get(database).copied().unwrap_or_default()
+    }
+
+    /// What a database currently holds
+    pub fn quota_usage(&self, database: &str) -> QuotaUsage {
+        let mut usage = QuotaUsage::default();
+        for table in self
+            .tables
+            .values()
+            .filter(|table| namespace::split(&table.name).0 == database)
+        {
+            usage.indexes += table.indexes.len() as u64;
+            if let Some(data) = self.data.get(&table.name) {
+                usage.rows += data.len() as u64;
+                usage.bytes += data.bytes;
+            }
+        }
+        usage
+    }
+
+    /// The quota and usage of the database a table belongs to. Usage is
+    /// only collected if the database has a quota.
+    pub(crate) fn headroom(&self, table: &str) -> Headroom {
+        let (database, _) = namespace::split(table);
+        let quota = self.quota(database);
+        let usage = if quota.is_unlimited() {
+            QuotaUsage::default()
+        } else {
+            self.quota_usage(database)
+        };
+        Headroom {
+            database: database.to_string(),
+            quota,
+            usage,
+            memory_limit: self.memory_limit,
+            memory: match self.memory_limit {
+                Some(_) => self.memory_usage().rows,
+                None => 0,
+            },
+        }
+    }
+
+    /// Estimated memory held by every table's rows and history
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.data
+            .values()
+            .fold(MemoryUsage::default(), |usage, data| MemoryUsage {
+                rows: usage.rows + data.bytes,
+                history: usage.history + data.history_bytes,
+            })
+    }
+
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory_limit
+    }
+
+    /// Cap the estimated memory held by all tables, evicting history at
+    /// once if they are over it. Returns how many past versions were
+    /// evicted. See `quota`.
+    pub fn set_memory_limit(&mut self, limit: Option<u64>) -> usize {
+        self.memory_limit = limit;
+        self.evict_history()
+    }
+
+    /// While the tables hold more than the memory limit, evict the oldest
+    /// change kept in any table's history. Returns how many were evicted.
+    pub fn evict_history(&mut self) -> usize {
+        let Some(limit) = self.memory_limit else {
+            return 0;
+        };
+        let mut total = self.memory_usage().total();
+        if total <= limit {
+            return 0;
+        }
+        let mut oldest: BinaryHeap<Reverse<(i64, String)>> = self
+            .data
+            .iter()
+            .filter_map(|(name, data)| Some(Reverse((data.history.front()?.at, name.clone()))))
+            .collect();
+        let mut evicted = 0;
+        while total > limit {
+            let Some(Reverse((_, name))) = oldest.pop() else {
+                break;
+            };
+            let data = Arc::make_mut(self.data.get_mut(&name).unwrap());
+            if let Some(size) = data.pop_history() {
+                total -= size;
+                evicted += 1;
+            }
+            if let Some(change) = data.history.front() {
+                oldest.push(Reverse((change.at, name)));
+            }
+        }
+        evicted
+    }
+
+    /// Register a new table
+    pub fn create_table(&mut self, table: Table) -> QubeResult<()> {
+        if self.tables.contains_key(&table.name) {
+            return Err(QubeError::AlreadyExists(format!("Table '{}'", table.name)));
+        }
+        if self.view(&table.name).is_some() {
+            return Err(QubeError::AlreadyExists(format!("View '{}'", table.name)));
+        }
+        let (database, _) = namespace::split(&table.name);
+        if !self.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+
+        let mut auto_increment = table.columns.iter().filter(|c| c.auto_increment);
+        if let Some(column) = auto_increment.next() {
+            if !column.data_type.is_integer() {
+                return Err(QubeError::QueryParse(format!(
+                    "AUTO_INCREMENT column '{}' must have an integer type",
+                    column.name
+                )));
+            }
+            if auto_increment.next().is_some() {
+                return Err(QubeError::QueryParse(format!(
+                    "Table '{}' has more than one AUTO_INCREMENT column",
+                    table.name
+                )));
+            }
+        }
+
+        for column in table.columns.iter().filter(|c| c.generated.is_some()) {
+            if column.default_value.is_some() || column.auto_increment {
+                return Err(QubeError::QueryParse(format!(
+                    "Generated column '{}' cannot have a default",
+                    column.name
+                )));
+            }
+        }
+        for index in &table.indexes {
+            check_indexable(&table, index)?;
+        }
+        self.headroom(&table.name)
+            .check(0, 0, table.indexes.len() as i64)?;
+
+        let mut data = TableData::new(&table);
+        data.apply_history_retention(self.history_retention);
+        self.data.insert(table.name.clone(), Arc::new(data));
+        self.tables.insert(table.name.clone(), table);
+        Ok(())
+    }
+
+    /// Keep the history of the rows of every table without a retention of
+    /// its own as `retention` says, reclaiming what it no longer covers.
+    /// Returns how many changes were dropped.
+    pub fn set_history_retention(&mut self, retention: HistoryRetention) -> usize {
+        self.history_retention = retention;
+        self.data
+            .values_mut()
+            .filter(|data| data.own_retention.is_none())
+            .map(|data| Arc::make_mut(data).apply_history_retention(retention))
+            .sum()
+    }
+
+    pub fn history_retention(&self) -> HistoryRetention {
+        self.history_retention
+    }
+
+    /// Keep the history of one table's rows as `retention` says, or as the
+    /// catalog's retention says when `None`, reclaiming what it no longer
+    /// covers. Returns how many changes were dropped.
+    pub fn set_table_history_retention(
+        &mut self,
+        name: &str,
+        retention: Option<HistoryRetention>,
+    ) -> QubeResult<usize> {
+        let default = self.history_retention;
+        let data = self
+            .data
+            .get_mut(name)
+            .map(Arc::make_mut)
+            .ok_or_else(|| QubeError::TableNotFound(name.to_string()))?;
+        data.own_retention = retention;
+        Ok(data.apply_history_retention(retention.unwrap_or(default)))
+    }
+
+    /// A copy of the table definitions and indexes without any rows
+    pub fn schema(&self) -> Catalog {
+        Catalog {
+            tables: self.tables.clone(),
+            data: self
+                .tables
+                .iter()
+                .map(|(name, table)| (name.clone(), Arc::new(TableData::new(table))))
+                .collect(),
+            databases: self.databases.clone(),
+            history_retention: self.history_retention,
+            quotas: self.quotas.clone(),
+            views: self.views.clone(),
+            journaling: false,
+            memory_limit: self.memory_limit,
+        }
+    }
+
+    /// Remove a table and its rows, returning its definition. Dropping the
+    /// table of a materialized view drops the view.
+    pub fn drop_table(&mut self, name: &str) -> QubeResult<Table> {
+        self.views.retain(|view| view.name != name);
+        self.data.remove(name);
+        self.tables
+            .remove(name)
+            .ok_or_else(|| QubeError::TableNotFound(name.to_string()))
+    }
+
+    /// Register a view that is not materialized
+    pub fn create_view(&mut self, view: View) -> QubeResult<()> {
+        if self.tables.contains_key(&view.name) {
+            return Err(QubeError::AlreadyExists(format!("Table '{}'", view.name)));
+        }
+        if self.view(&view.name).is_some() {
+            return Err(QubeError::AlreadyExists(format!("View '{}'", view.name)));
+        }
+        let (database, _) = namespace::split(&view.name);
+        if !self.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+        self.views.push(view);
+        Ok(())
+    }
+
+    /// Register a materialized view whose rows are `rows`, stored in the
+    /// table `table`
+    pub fn create_materialized_view(
+        &mut self,
+        view: View,
+        table: Table,
+        rows: impl IntoIterator<Item = Row>,
+    ) -> QubeResult<()> {
+        self.create_table(table.clone())?;
+        self.refresh_view(table, rows)?;
+        self.views.push(view);
+        Ok(())
+    }
+
+    /// Replace the rows of a materialized view with `rows`, its table with
+    /// `table`. The indexes created on the view are kept as long as the
+    /// table still has their columns.
+    pub fn refresh_view(
+        &mut self,
+        mut table: Table,
+        rows: impl IntoIterator<Item = Row>,
+    ) -> QubeResult<()> {
+        let old = self.get_table(&table.name)?;
+        table.indexes = old
+            .indexes
+            .iter()
+            .filter(|index| {
+                index
+                    .columns
+                    .iter()
+                    .all(|column| table.columns.iter().any(|c| &c.name == column))
+            })
+            .cloned()
+            .collect();
+        let own_retention = self.table_data(&table.name)?.own_retention;
+        let mut data = TableData::from_rows(&table, rows);
+        data.own_retention = own_retention;
+        data.apply_history_retention(own_retention.unwrap_or(self.history_retention));
+        self.data.insert(table.name.clone(), Arc::new(data));
+        self.tables.insert(table.name.clone(), table);
+        Ok(())
+    }
+
+    /// Remove a view, and the rows of a materialized one, returning its
+    /// definition
+    pub fn drop_view(&mut self, name: &str) -> QubeResult<View> {
+        let position = self
+            .views
+            .iter()
+            .position(|view| view.name == name)
+            .ok_or_else(|| QubeError::NotFound(format!("View '{}'", name)))?;
+        let view = self.views.remove(position);
+        if view.is_materialized() {
+            self.drop_table(name)?;
+        }
+        Ok(view)
+    }
+
+    /// The view named `name`, if there is one
+    pub fn view(&self, name: &str) -> Option<&View> {
+        self.views.iter().find(|view| view.name == name)
+    }
+
+    /// Views, in the order they were created
+    pub fn views(&self) -> &[View] {
+        &self.views
+    }
+
+    /// Whether `view` reads `name`, directly or through the views it reads
+    pub fn view_reads(&self, view: &View, name: &str) -> bool {
+        view.reads(name)
+            || view
+                .tables
+                .iter()
+                .filter_map(|table| self.view(table))
+                .any(|read| self.view_reads(read, name))
+    }
+
+    /// Fail unless rows of `name` may be written directly, which those of
+    /// a view may not
+    pub fn check_writable(&self, name: &str) -> QubeResult<()> {
+        match self.view(name) {
+            Some(view) if view.is_materialized() => Err(QubeError::ConstraintViolation(format!(
+                "'{}' is a materialized view and cannot be written to",
+                name
+            ))),
+            Some(_) => Err(QubeError::ConstraintViolation(format!(
+                "'{}' is a view and cannot be written to",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a table with this name exists
+    pub fn has_table(&self, name: &str) -> bool {
+        self.tables.contains_key(name)
+    }
+
+    /// Get a table definition by name
+    pub fn get_table(&self, name: &str) -> QubeResult<&Table> {
+        self.tables
+            .get(name)
+            .ok_or_else(|| QubeError::TableNotFound(name.to_string()))
+    }
+
+    /// Get the rows of a table
+    pub fn table_data(&self, name: &str) -> QubeResult<&TableData> {
+        self.data
+            .get(name)
+            .map(Arc::as_ref)
+            .ok_or_else(|| QubeError::TableNotFound(name.to_string()))
+    }
+
+    /// Get a table definition together with mutable access to its rows
+    pub fn table_data_mut(&mut self, name: &str) -> QubeResult<(&Table, &mut TableData)> {
+        let journaling = self.journaling;
+        match (self.tables.get(name), self.data.get_mut(name)) {
+            (Some(table), Some(data)) => {
+                let data = Arc::make_mut(data);
+                if journaling && data.journal.is_none() {
+                    data.journal = Some(BTreeMap::new());
+                }
+                Ok((table, data))
+            }
+            _ => Err(QubeError::TableNotFound(name.to_string())),
+        }
+    }
+
+    /// Journal every row written from now on, for `take_journal` to
+    /// collect. Tables are only copied when written to, so a snapshot that
+    /// journals its writes knows what it changed without comparing tables.
+    pub(crate) fn start_journal(&mut self) {
+        self.journaling = true;
+    }
+
+    /// The rows written since the journal was last taken, by table name
+    /// and then row id. A row written more than once shows up once, as it
+    /// was before the first write and after the last.
+    pub(crate) fn take_journal(&mut self) -> Vec<RowEffect> {
+        let mut names: Vec<String> = self
+            .data
+            .iter()
+            .filter(|(_, data)| data.journal.as_ref().is_some_and(|j| !j.is_empty()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        let mut effects = Vec::new();
+        for name in names {
+            let data = Arc::make_mut(self.data.get_mut(&name).unwrap());
+            effects.extend(
+                data.take_journal()
+                    .into_iter()
+                    .map(|(row_id, before, after)| RowEffect {
+                        table: name.clone(),
+                        row_id,
+                        before,
+                        after,
+                    }),
+            );
+        }
+        effects
+    }
+
+    /// Recompute statistics for a table
+    pub fn analyze_table(&mut self, name: &str) -> QubeResult<&TableStats> {
+        let (table, data) = self.table_data_mut(name)?;
+        let stats = {
+            let rows: Vec<Cow<Row>> = data.rows().map(|(_, row)| row).collect();
+            TableStats::collect(&table.columns, rows.iter().map(|row| row.as_ref()))
+        };
+        data.stats = Some(stats);
+        data.modified_rows = 0;
+        Ok(data.stats.as_ref().unwrap())
+    }
+
+    /// List all tables, sorted by name
+    pub fn list_tables(&self) -> Vec<&Table> {
+        let mut tables: Vec<&Table> = self.tables.values().collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        tables
+    }
+
+    /// Add an index to an existing table
+    pub fn create_index(&mut self, table: &str, index: Index) -> QubeResult<()> {
+        if self.find_index(&index.name).is_some() {
+            return Err(QubeError::AlreadyExists(format!("Index '{}'", index.name)));
+        }
+        self.headroom(table).check(0, 0, 1)?;
+
+        let table = self
+            .tables
+            .get_mut(table)
+            .ok_or_else(|| QubeError::TableNotFound(table.to_string()))?;
+
+        check_indexable(table, &index)?;
+
+        if let Some(data) = self.data.get_mut(&table.name).map(Arc::make_mut) {
+            data.build_index(&index);
+        }
+        table.indexes.push(index);
+        Ok(())
+    }
+
+    /// Drop an index by name, returning the table it belonged to
+    pub fn drop_index(&mut self, name: &str) -> QubeResult<String> {
+        let table_name = self
+            .find_index(name)
+            .ok_or_else(|| QubeError::NotFound(format!("Index '{}'", name)))?;
+
+        if let Some(table) = self.tables.get_mut(&table_name) {
+            table.indexes.retain(|index| index.name != name);
+        }
+        if let Some(data) = self.data.get_mut(&table_name).map(Arc::make_mut) {
+            data.indexes.remove(name);
+        }
+        Ok(table_name)
+    }
+
+    /// Rebuild an index's entries from its table's rows, discarding
+    /// whatever they held. Returns the table's name.
+    pub fn rebuild_index(&mut self, name: &str) -> QubeResult<String> {
+        let table_name = self
+            .find_index(name)
+            .ok_or_else(|| QubeError::NotFound(format!("Index '{}'", name)))?;
+        let table = &self.tables[&table_name];
+        if let (Some(index), Some(data)) = (
+            table.indexes.iter().find(|index| index.name == name),
+            self.data.get_mut(&table_name).map(Arc::make_mut),
+        ) {
+            data.build_index(index);
+        }
+        Ok(table_name)
+    }
+
+    /// Rebuild every index of a table from its rows. Returns how many
+    /// indexes the table has.
+    pub fn rebuild_table_indexes(&mut self, name: &str) -> QubeResult<usize> {
+        let table = self
+            .tables
+            .get(name)
+            .ok_or_else(|| QubeError::TableNotFound(name.to_string()))?;
+        if let Some(data) = self.data.get_mut(name).map(Arc::make_mut) {
+            for index in &table.indexes {
+                data.build_index(index);
+            }
+        }
+        Ok(table.indexes.len())
+    }
+
+    /// Find the table that owns an index
+    fn find_index(&self, name: &str) -> Option<String> {
+        self.tables
+            .values()
+            .find(|table| table.indexes.iter().any(|index| index.name == name))
+            .map(|table| table.name.clone())
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fail unless every column of the index exists and is stored: a virtual
+/// column has no stored values to index
+fn check_indexable(table: &Table, index: &Index) -> QubeResult<()> {
+    for name in &index.columns {
+        match table.columns.iter().find(|c| &c.name == name) {
+            None => {
+                return Err(QubeError::ColumnNotFound(format!(
+                    "{}.{}",
+                    table.name, name
+                )))
+            }
+            Some(column) if column.is_virtual() => {
+                return Err(QubeError::QueryParse(format!(
+                    "Virtual column '{}' cannot be indexed",
+                    name
+                )))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// A moment in milliseconds since the Unix epoch, in RFC 3339 form
+fn format_millis(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .map_or_else(|| millis.to_string(), |time| time.to_rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{DataType, TableBuilder};
+
+    /// A catalog with table `t (id, v)` holding rows `(1, 10)` and
+    /// `(2, 20)`, indexed on `v` by `t_v`
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::new();
+        let table = TableBuilder::new("t")
+            .column("id", DataType::Int32)
+            .column("v", DataType::Int32)
+            .build()
+            .unwrap();
+        catalog.create_table(table).unwrap();
+        let (_, data) = catalog.table_data_mut("t").unwrap();
+        for id in [1, 2] {
+            data.insert(Row::from([
+                ("id".to_string(), Value::Int32(id)),
+                ("v".to_string(), Value::Int32(id * 10)),
+            ]));
+        }
+        let index = Index {
+            name: "t_v".to_string(),
+            columns: vec!["v".to_string()],
+            index_type: IndexType::BTree,
+            unique: false,
+        };
+        catalog.create_index("t", index).unwrap();
+        catalog
+    }
+
+    #[test]
+    fn index_drift_is_reported_entry_by_entry() {
+        let mut catalog = catalog();
+        assert!(catalog
+            .table_data("t")
+            .unwrap()
+            .verify_indexes("t")
+            .is_empty());
+
+        let (_, data) = catalog.table_data_mut("t").unwrap();
+        let (first, second) = {
+            let mut ids = data.rows().map(|(row_id, _)| row_id);
+            (ids.next().unwrap(), ids.next().unwrap())
+        };
+        let index = data.indexes.get_mut("t_v").unwrap();
+        index.remove(vec![Value::Int32(10)], first);
+        index.insert(vec![Value::Int32(99)], second);
+        index.insert(vec![Value::Int32(30)], 1_000);
+
+        let issues = data.verify_indexes("t");
+        let kinds: Vec<(u64, &IndexIssueKind)> = issues
+            .iter()
+            .map(|issue| (issue.row_id, &issue.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (1_000, &IndexIssueKind::DanglingEntry),
+                (
+                    second,
+                    &IndexIssueKind::StaleEntry {
+                        row_key: vec![Value::Int32(20)]
+                    }
+                ),
+                (first, &IndexIssueKind::MissingEntry),
+            ]
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            "t.t_v: entry [Int32(30)] points at missing row 1000"
+        );
+        assert_eq!(issues[2].key, [Value::Int32(10)]);
+    }
+
+    #[test]
+    fn rows_removed_in_a_batch_leave_their_indexes() {
+        let mut catalog = catalog();
+        let (_, data) = catalog.table_data_mut("t").unwrap();
+        let (bytes, modified) = (data.bytes(), data.modified_rows);
+        let first = data.rows().map(|(row_id, _)| row_id).next().unwrap();
+
+        let removed = data.remove_many(&[first, 1_000]);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0]["v"], Value::Int32(10));
+        assert_eq!(data.len(), 1);
+        assert_eq!(data.modified_rows, modified + 1);
+        assert_eq!(data.bytes(), bytes - quota::row_size(&removed[0]));
+        assert!(data.verify_indexes("t").is_empty());
+        let index = &data.indexes["t_v"];
+        assert!(index.search(&[Value::Int32(10)]).is_empty());
+        assert!(data.remove_many(&[first]).is_empty());
+    }
+
+    #[test]
+    fn history_is_kept_as_the_table_or_catalog_retention_says() {
+        let mut catalog = catalog();
+        assert_eq!(catalog.history_retention(), HistoryRetention::default());
+        let (_, data) = catalog.table_data_mut("t").unwrap();
+        let row_id = data.rows().map(|(row_id, _)| row_id).next().unwrap();
+        for v in 11..16 {
+            data.update(
+                row_id,
+                Row::from([
+                    ("id".to_string(), Value::Int32(1)),
+                    ("v".to_string(), Value::Int32(v)),
+                ]),
+            );
+        }
+        // Two inserts and five updates
+        assert_eq!(data.history_len(), 7);
+
+        let capped = HistoryRetention::default().with_max_versions(3);
+        let dropped = catalog.set_table_history_retention("t", Some(capped));
+        assert_eq!(dropped.unwrap(), 4);
+        let data = catalog.table_data("t").unwrap();
+        assert_eq!(data.history_len(), 3);
+        assert_eq!(data.own_history_retention(), Some(capped));
+
+        // Tables with a retention of their own keep it
+        assert_eq!(catalog.set_history_retention(HistoryRetention::none()), 0);
+        assert_eq!(catalog.table_data("t").unwrap().history_len(), 3);
+        let dropped = catalog.set_table_history_retention("t", None);
+        assert_eq!(dropped.unwrap(), 3);
+        let data = catalog.table_data("t").unwrap();
+        assert_eq!(data.history_retention(), HistoryRetention::none());
+        assert_eq!(data.own_history_retention(), None);
+        assert!(!HistoryRetention::default()
+            .with_max_versions(0)
+            .keeps_history());
+        assert!(matches!(
+            catalog.set_table_history_retention("missing", None),
+            Err(QubeError::TableNotFound(_))
+        ));
+    }
+}
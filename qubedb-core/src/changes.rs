@@ -0,0 +1,500 @@
+//! Change data capture for QubeDB
+//!
+//! A `ChangeListener` receives an event for every row the query engine
+//! writes, in commit order. Each listener buffers events up to a fixed
+//! capacity so a slow or stalled consumer cannot exhaust memory; its
+//! `OverflowPolicy` decides what happens to a write that finds the buffer
+//! full. Statements run as one transaction only publish their events once
+//! the transaction commits.
+
+use crate::error::{QubeError, QubeResult};
+use crate::types::Row;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default number of events a listener buffers
+pub const DEFAULT_CHANGE_BUFFER_CAPACITY: usize = 10_000;
+
+/// The kind of write a change event records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// One row written to a table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Position in this listener's stream, starting at 1. A gap means
+    /// events were dropped.
+    pub sequence: u64,
+    /// Time the event was published, in milliseconds since the epoch
+    pub timestamp: u64,
+    pub table: String,
+    pub kind: ChangeKind,
+    /// The row after an insert or update, or before a delete
+    pub row: Row,
+}
+
+/// What a write does when a listener's buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the consumer makes room. Writes stall, but no event is
+    /// lost.
+    Block,
+    /// Discard the oldest buffered event to make room
+    DropOldest,
+    /// Fail the write before it changes anything
+    Error,
+}
+
+/// Buffer settings for a change listener
+#[derive(Debug, Clone)]
+pub struct ChangeListenerConfig {
+    /// Maximum number of buffered events, at least 1
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ChangeListenerConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CHANGE_BUFFER_CAPACITY,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// Counters describing a listener's buffer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChangeListenerMetrics {
+    /// Events currently buffered
+    pub depth: usize,
+    pub capacity: usize,
+    /// Events accepted into the buffer
+    pub published: u64,
+    /// Events discarded by `OverflowPolicy::DropOldest`
+    pub dropped: u64,
+    /// Writes failed by `OverflowPolicy::Error`
+    pub rejected: u64,
+    /// Times a writer waited under `OverflowPolicy::Block`
+    pub blocked: u64,
+}
+
+struct Buffer {
+    events: VecDeque<ChangeEvent>,
+    next_sequence: u64,
+    closed: bool,
+    metrics: ChangeListenerMetrics,
+}
+
+struct Shared {
+    config: ChangeListenerConfig,
+    buffer: Mutex<Buffer>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// A bounded stream of change events. Clones share the same buffer.
+#[derive(Clone)]
+pub struct ChangeListener {
+    shared: Arc<Shared>,
+}
+
+impl ChangeListener {
+    pub fn new(config: ChangeListenerConfig) -> Self {
+        let config = ChangeListenerConfig {
+            capacity: config.capacity.max(1),
+            ..config
+        };
+        let buffer = Buffer {
+            events: VecDeque::new(),
+            next_sequence: 1,
+            closed: false,
+            metrics: ChangeListenerMetrics {
+                capacity: config.capacity,
+                ..Default::default()
+            },
+        };
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                buffer: Mutex::new(buffer),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &ChangeListenerConfig {
+        &self.shared.config
+    }
+
+    /// Take the oldest buffered event, if any
+    pub fn try_recv(&self) -> Option<ChangeEvent> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        let event = buffer.events.pop_front();
+        if event.is_some() {
+            self.shared.not_full.notify_all();
+        }
+        event
+    }
+
+    /// Take the oldest event, waiting up to `timeout` for one to arrive
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        loop {
+            if let Some(event) = buffer.events.pop_front() {
+                self.shared.not_full.notify_all();
+                return Some(event);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if buffer.closed || remaining.is_zero() {
+                return None;
+            }
+            buffer = self
+                .shared
+                .not_empty
+                .wait_timeout(buffer, remaining)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Take up to `max` buffered events without waiting
+    pub fn drain(&self, max: usize) -> Vec<ChangeEvent> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        let count = max.min(buffer.events.len());
+        let events: Vec<ChangeEvent> = buffer.events.drain(..count).collect();
+        if !events.is_empty() {
+            self.shared.not_full.notify_all();
+        }
+        events
+    }
+
+    pub fn metrics(&self) -> ChangeListenerMetrics {
+        let buffer = self.shared.buffer.lock().unwrap();
+        ChangeListenerMetrics {
+            depth: buffer.events.len(),
+            ..buffer.metrics
+        }
+    }
+
+    /// Stop receiving events. The engine forgets a closed listener, and any
+    /// writer blocked on it is released.
+    pub fn close(&self) {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        buffer.closed = true;
+        buffer.events.clear();
+        self.shared.not_full.notify_all();
+        self.shared.not_empty.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.buffer.lock().unwrap().closed
+    }
+
+    /// Fail if `count` more events would overflow a listener whose policy
+    /// is `Error`
+    fn check_room(&self, count: usize) -> QubeResult<()> {
+        if self.shared.config.overflow != OverflowPolicy::Error {
+            return Ok(());
+        }
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        if buffer.closed || buffer.events.len() + count <= self.shared.config.capacity {
+            return Ok(());
+        }
+        buffer.metrics.rejected += 1;
+        Err(QubeError::Other(format!(
+            "Change buffer full ({} of {} events); write rejected",
+            buffer.events.len(),
+            self.shared.config.capacity
+        )))
+    }
+
+    fn publish(&self, change: &Change, timestamp: u64) {
+        let capacity = self.shared.config.capacity;
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        if buffer.events.len() >= capacity && !buffer.closed {
+            match self.shared.config.overflow {
+                OverflowPolicy::Block => {
+                    buffer.metrics.blocked += 1;
+                    while buffer.events.len() >= capacity && !buffer.closed {
+                        buffer = self.shared.not_full.wait(buffer).unwrap();
+                    }
+                }
+                // `Error` listeners had their room checked before the
+                // write, so dropping is only a fallback for them
+                OverflowPolicy::DropOldest | OverflowPolicy::Error => {
+                    buffer.events.pop_front();
+                    buffer.metrics.dropped += 1;
+                }
+            }
+        }
+        if buffer.closed {
+            return;
+        }
+        let sequence = buffer.next_sequence;
+        buffer.next_sequence += 1;
+        buffer.metrics.published += 1;
+        buffer.events.push_back(ChangeEvent {
+            sequence,
+            timestamp,
+            table: change.table.clone(),
+            kind: change.kind,
+            row: change.row.clone(),
+        });
+        self.shared.not_empty.notify_one();
+    }
+}
+
+impl std::fmt::Debug for ChangeListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeListener")
+            .field("config", &self.shared.config)
+            .field("metrics", &self.metrics())
+            .finish()
+    }
+}
+
+/// A row write waiting to be published
+#[derive(Debug, Clone)]
+pub(crate) struct Change {
+    pub table: String,
+    pub kind: ChangeKind,
+    pub row: Row,
+}
+
+/// The listeners attached to a query engine
+#[derive(Debug, Default)]
+pub(crate) struct ChangeFeed {
+    listeners: Mutex<Vec<ChangeListener>>,
+    /// Set on the staging engine of a transaction: changes are held here
+    /// and published by the real engine once the transaction commits
+    deferred: Option<Mutex<Vec<Change>>>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A feed that holds changes back, checking room against the same
+    /// listeners as `parent`
+    pub fn deferred(parent: &ChangeFeed) -> Self {
+        Self {
+            listeners: Mutex::new(parent.listeners()),
+            deferred: Some(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn subscribe(&self, config: ChangeListenerConfig) -> ChangeListener {
+        let listener = ChangeListener::new(config);
+        self.listeners.lock().unwrap().push(listener.clone());
+        listener
+    }
+
+    /// Open listeners, forgetting closed ones
+    fn listeners(&self) -> Vec<ChangeListener> {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.retain(|listener| !listener.is_closed());
+        listeners.clone()
+    }
+
+    /// Whether anything would receive a change, so callers can skip
+    /// building events nobody reads
+    pub fn is_active(&self) -> bool {
+        !self.listeners().is_empty()
+    }
+
+    /// Changes recording `rows` being written to `table`, or none if
+    /// nothing is listening
+    pub fn changes<'a>(
+        &self,
+        table: &str,
+        kind: ChangeKind,
+        rows: impl IntoIterator<Item = &'a Row>,
+    ) -> Vec<Change> {
+        if !self.is_active() {
+            return Vec::new();
+        }
+        rows.into_iter()
+            .map(|row| Change {
+                table: table.to_string(),
+                kind,
+                row: row.clone(),
+            })
+            .collect()
+    }
+
+    /// Check that `count` more changes fit every listener that rejects
+    /// writes on overflow. Call before applying the write.
+    pub fn check_room(&self, count: usize) -> QubeResult<()> {
+        let pending = self
+            .deferred
+            .as_ref()
+            .map_or(0, |deferred| deferred.lock().unwrap().len());
+        self.listeners()
+            .iter()
+            .try_for_each(|listener| listener.check_room(pending + count))
+    }
+
+    /// Publish changes that have been applied
+    pub fn publish(&self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        if let Some(deferred) = &self.deferred {
+            deferred.lock().unwrap().extend(changes);
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        for listener in self.listeners() {
+            for change in &changes {
+                listener.publish(change, timestamp);
+            }
+        }
+    }
+
+    /// Take the changes a deferred feed has held back
+    pub fn take_deferred(&self) -> Vec<Change> {
+        self.deferred
+            .as_ref()
+            .map(|deferred| std::mem::take(&mut *deferred.lock().unwrap()))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Value;
+    use std::thread;
+
+    fn subscribe(feed: &ChangeFeed, capacity: usize, overflow: OverflowPolicy) -> ChangeListener {
+        feed.subscribe(ChangeListenerConfig { capacity, overflow })
+    }
+
+    /// Insert changes for rows with ids `ids`
+    fn inserts(ids: impl IntoIterator<Item = i32>) -> Vec<Change> {
+        ids.into_iter()
+            .map(|id| Change {
+                table: "t".to_string(),
+                kind: ChangeKind::Insert,
+                row: Row::from([("id".to_string(), Value::Int32(id))]),
+            })
+            .collect()
+    }
+
+    fn ids(events: &[ChangeEvent]) -> Vec<Value> {
+        events.iter().map(|event| event.row["id"].clone()).collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_events_and_counts_the_rest() {
+        let feed = ChangeFeed::new();
+        assert!(!feed.is_active());
+        let listener = subscribe(&feed, 2, OverflowPolicy::DropOldest);
+        assert!(feed.is_active());
+
+        feed.publish(inserts(1..=3));
+        let events = listener.drain(10);
+        assert_eq!(ids(&events), [Value::Int32(2), Value::Int32(3)]);
+        // Sequence numbers show the gap left by the dropped event
+        assert_eq!(events[0].sequence, 2);
+        assert_eq!(events[0].table, "t");
+        assert_eq!(
+            listener.metrics(),
+            ChangeListenerMetrics {
+                depth: 0,
+                capacity: 2,
+                published: 3,
+                dropped: 1,
+                rejected: 0,
+                blocked: 0,
+            }
+        );
+        assert!(listener.try_recv().is_none());
+        assert!(listener.recv_timeout(Duration::from_millis(1)).is_none());
+    }
+
+    #[test]
+    fn error_listeners_reject_writes_that_would_overflow() {
+        let feed = ChangeFeed::new();
+        let listener = subscribe(&feed, 2, OverflowPolicy::Error);
+        feed.check_room(2).unwrap();
+        feed.publish(inserts([1]));
+        assert!(feed.check_room(2).is_err());
+        assert_eq!(listener.metrics().rejected, 1);
+
+        listener.try_recv().unwrap();
+        feed.check_room(2).unwrap();
+        // Listeners that drop never refuse a write
+        let other = ChangeFeed::new();
+        let _listener = subscribe(&other, 1, OverflowPolicy::DropOldest);
+        other.check_room(100).unwrap();
+    }
+
+    #[test]
+    fn blocked_writers_wait_for_the_consumer() {
+        let feed = Arc::new(ChangeFeed::new());
+        let listener = subscribe(&feed, 1, OverflowPolicy::Block);
+        feed.publish(inserts([1]));
+
+        let writer = {
+            let feed = Arc::clone(&feed);
+            thread::spawn(move || feed.publish(inserts([2])))
+        };
+        while listener.metrics().blocked == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let first = listener.recv_timeout(Duration::from_secs(5)).unwrap();
+        writer.join().unwrap();
+        let second = listener.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(ids(&[first, second]), [Value::Int32(1), Value::Int32(2)]);
+        assert_eq!(listener.metrics().dropped, 0);
+    }
+
+    #[test]
+    fn closing_releases_blocked_writers_and_unsubscribes() {
+        let feed = Arc::new(ChangeFeed::new());
+        let listener = subscribe(&feed, 1, OverflowPolicy::Block);
+        feed.publish(inserts([1]));
+
+        let writer = {
+            let feed = Arc::clone(&feed);
+            thread::spawn(move || feed.publish(inserts([2])))
+        };
+        while listener.metrics().blocked == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        listener.close();
+        writer.join().unwrap();
+        assert!(listener.is_closed());
+        assert!(listener.try_recv().is_none());
+        assert!(!feed.is_active());
+        assert!(feed.changes("t", ChangeKind::Insert, []).is_empty());
+    }
+
+    #[test]
+    fn deferred_feeds_hold_changes_until_taken() {
+        let feed = ChangeFeed::new();
+        let listener = subscribe(&feed, 3, OverflowPolicy::Error);
+        let staging = ChangeFeed::deferred(&feed);
+        staging.publish(inserts([1, 2]));
+        assert!(listener.try_recv().is_none());
+        // Held changes count against the room left
+        assert!(staging.check_room(2).is_err());
+
+        feed.publish(staging.take_deferred());
+        assert_eq!(ids(&listener.drain(10)), [Value::Int32(1), Value::Int32(2)]);
+        assert!(staging.take_deferred().is_empty());
+    }
+}
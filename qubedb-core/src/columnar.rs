@@ -0,0 +1,316 @@
+//! Columnar table storage for QubeDB
+//!
+//! A table created `WITH (storage = 'columnar')` keeps each column's values
+//! contiguously, in chunks of `CHUNK_ROWS` rows. A scan that only needs
+//! some columns reads only those columns' values, which suits aggregates
+//! and other analytical queries over wide tables. New rows collect in the
+//! table's row buffer and are written out as a chunk once a full chunk has
+//! arrived. Reading a whole row means gathering it from every column, so
+//! point lookups are slower than in the row layout.
+
+use crate::types::{Row, Value};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of rows written out together as one column chunk
+pub const CHUNK_ROWS: usize = 1024;
+
+/// Counters describing a columnar table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ColumnStoreStats {
+    pub chunks: usize,
+    /// Rows stored in column chunks
+    pub chunked_rows: usize,
+    /// Rows still waiting in the row buffer for a chunk to fill
+    pub buffered_rows: usize,
+    /// Column values read out of chunks since the table was created
+    pub values_read: usize,
+}
+
+/// Rows stored column by column
+#[derive(Debug, Clone)]
+struct ColumnChunk {
+    /// Row ids in ascending order
+    row_ids: Vec<u64>,
+    /// One vector per column, holding a value for every row id
+    columns: BTreeMap<String, Vec<Value>>,
+}
+
+impl ColumnChunk {
+    fn position(&self, row_id: u64) -> Option<usize> {
+        self.row_ids.binary_search(&row_id).ok()
+    }
+}
+
+/// The column chunks of a columnar table
+#[derive(Debug)]
+pub struct ColumnStore {
+    columns: Vec<String>,
+    chunks: Vec<ColumnChunk>,
+    values_read: AtomicUsize,
+}
+
+impl Clone for ColumnStore {
+    fn clone(&self) -> Self {
+        ColumnStore {
+            columns: self.columns.clone(),
+            chunks: self.chunks.clone(),
+            values_read: AtomicUsize::new(self.values_read()),
+        }
+    }
+}
+
+impl ColumnStore {
+    /// An empty store for a table with these columns
+    pub fn new(columns: Vec<String>) -> Self {
+        ColumnStore {
+            columns,
+            chunks: Vec::new(),
+            values_read: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of rows stored in chunks
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.row_ids.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Column values read out of chunks so far
+    pub fn values_read(&self) -> usize {
+        self.values_read.load(Ordering::Relaxed)
+    }
+
+    /// Write rows out as a new chunk. Row ids must be ascending and above
+    /// every row id already stored.
+    pub fn push_chunk(&mut self, rows: impl IntoIterator<Item = (u64, Row)>) {
+        let mut chunk = ColumnChunk {
+            row_ids: Vec::new(),
+            columns: self
+                .columns
+                .iter()
+                .map(|column| (column.clone(), Vec::new()))
+                .collect(),
+        };
+        for (row_id, mut row) in rows {
+            chunk.row_ids.push(row_id);
+            for (column, values) in chunk.columns.iter_mut() {
+                values.push(row.remove(column).unwrap_or(Value::Null));
+            }
+        }
+        if !chunk.row_ids.is_empty() {
+            self.chunks.push(chunk);
+        }
+    }
+
+    /// Rows in row id order, holding only `columns` (every column when
+    /// `None`). Only the selected columns' values are read.
+    pub fn rows(
+        &self,
+        columns: Option<&BTreeSet<String>>,
+    ) -> impl Iterator<Item = (u64, Row)> + Clone + '_ {
+        let columns = columns.cloned();
+        self.chunks.iter().flat_map(move |chunk| {
+            let selected: Vec<(&String, &Vec<Value>)> = chunk
+                .columns
+                .iter()
+                .filter(|(name, _)| {
+                    columns
+                        .as_ref()
+                        .is_none_or(|columns| columns.contains(*name))
+                })
+                .collect();
+            (0..chunk.row_ids.len()).map(move |i| {
+                self.values_read
+                    .fetch_add(selected.len(), Ordering::Relaxed);
+                let row = selected
+                    .iter()
+                    .map(|(name, values)| ((*name).clone(), values[i].clone()))
+                    .collect();
+                (chunk.row_ids[i], row)
+            })
+        })
+    }
+
+    /// Gather one stored row, holding only `columns` (every column when
+    /// `None`)
+    pub fn row(&self, row_id: u64, columns: Option<&BTreeSet<String>>) -> Option<Row> {
+        let chunk = self.chunk_of(row_id)?;
+        let position = chunk.position(row_id)?;
+        let row: Row = chunk
+            .columns
+            .iter()
+            .filter(|(name, _)| columns.is_none_or(|columns| columns.contains(*name)))
+            .map(|(name, values)| (name.clone(), values[position].clone()))
+            .collect();
+        self.values_read.fetch_add(row.len(), Ordering::Relaxed);
+        Some(row)
+    }
+
+    /// The chunk whose row id range covers `row_id`
+    fn chunk_of(&self, row_id: u64) -> Option<&ColumnChunk> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.row_ids.last().is_some_and(|&last| last >= row_id))
+    }
+
+    /// Take a row out of its chunk, dropping the chunk once it is empty
+    pub fn remove(&mut self, row_id: u64) -> Option<Row> {
+        let index = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.row_ids.last().is_some_and(|&last| last >= row_id))?;
+        let chunk = &mut self.chunks[index];
+        let position = chunk.position(row_id)?;
+        chunk.row_ids.remove(position);
+        let row = chunk
+            .columns
+            .iter_mut()
+            .map(|(name, values)| (name.clone(), values.remove(position)))
+            .collect();
+        if chunk.row_ids.is_empty() {
+            self.chunks.remove(index);
+        }
+        Some(row)
+    }
+
+    /// Replace a stored row. Returns false if no chunk holds `row_id`.
+    pub fn update(&mut self, row_id: u64, mut row: Row) -> bool {
+        let chunk = match self
+            .chunks
+            .iter_mut()
+            .find(|chunk| chunk.row_ids.last().is_some_and(|&last| last >= row_id))
+        {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+        let position = match chunk.position(row_id) {
+            Some(position) => position,
+            None => return false,
+        };
+        for (column, values) in chunk.columns.iter_mut() {
+            values[position] = row.remove(column).unwrap_or(Value::Null);
+        }
+        true
+    }
+
+    /// Rewrite the chunks that deletes left partly empty into full ones.
+    /// Returns the number of chunks given up.
+    pub fn compact(&mut self) -> usize {
+        let before = self.chunks.len();
+        if self
+            .chunks
+            .iter()
+            .all(|chunk| chunk.row_ids.len() >= CHUNK_ROWS)
+        {
+            return 0;
+        }
+        let mut rows = Vec::with_capacity(self.len());
+        for chunk in std::mem::take(&mut self.chunks) {
+            let mut columns: Vec<_> = chunk
+                .columns
+                .into_iter()
+                .map(|(name, values)| (name, values.into_iter()))
+                .collect();
+            for row_id in chunk.row_ids {
+                let row = columns
+                    .iter_mut()
+                    .map(|(name, values)| (name.clone(), values.next().unwrap_or(Value::Null)))
+                    .collect();
+                rows.push((row_id, row));
+            }
+        }
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            self.push_chunk(rows.by_ref().take(CHUNK_ROWS).collect::<Vec<_>>());
+        }
+        before - self.chunks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> ColumnStore {
+        ColumnStore::new(vec!["a".to_string(), "b".to_string()])
+    }
+
+    /// Rows `(id, {a: id, b: id * 10})`
+    fn rows(ids: impl IntoIterator<Item = u64>) -> Vec<(u64, Row)> {
+        ids.into_iter()
+            .map(|id| {
+                let row = Row::from([
+                    ("a".to_string(), Value::Int64(id as i64)),
+                    ("b".to_string(), Value::Int64(id as i64 * 10)),
+                ]);
+                (id, row)
+            })
+            .collect()
+    }
+
+    fn only(column: &str) -> BTreeSet<String> {
+        BTreeSet::from([column.to_string()])
+    }
+
+    #[test]
+    fn scans_read_only_the_requested_columns() {
+        let mut store = store();
+        assert!(store.is_empty());
+        store.push_chunk(rows(1..=3));
+        store.push_chunk(rows(4..=5));
+        assert_eq!((store.len(), store.chunk_count()), (5, 2));
+
+        let b = only("b");
+        let values: Vec<(u64, Row)> = store.rows(Some(&b)).collect();
+        assert_eq!(values.len(), 5);
+        assert_eq!(
+            values[4],
+            (5, Row::from([("b".to_string(), Value::Int64(50))]))
+        );
+        assert_eq!(store.values_read(), 5);
+
+        assert_eq!(store.rows(None).count(), 5);
+        assert_eq!(store.values_read(), 15);
+        assert_eq!(store.row(2, None), Some(rows([2]).remove(0).1));
+        assert_eq!(store.row(9, None), None);
+        assert_eq!(store.row(4, Some(&only("a"))).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rows_are_updated_removed_and_compacted_in_place() {
+        let mut store = store();
+        store.push_chunk(rows(1..=3));
+        store.push_chunk(rows(4..=6));
+
+        // Columns missing from the new row become NULL
+        assert!(store.update(2, Row::from([("a".to_string(), Value::Int64(-2))])));
+        assert!(!store.update(7, Row::new()));
+        let updated = store.row(2, None).unwrap();
+        assert_eq!(
+            (&updated["a"], &updated["b"]),
+            (&Value::Int64(-2), &Value::Null)
+        );
+
+        assert_eq!(store.remove(5).unwrap()["b"], Value::Int64(50));
+        assert_eq!(store.remove(5), None);
+        for id in [4, 6] {
+            store.remove(id).unwrap();
+        }
+        assert_eq!(store.chunk_count(), 1);
+
+        store.push_chunk(rows(7..=8));
+        assert_eq!(store.compact(), 1);
+        let ids: Vec<u64> = store.rows(None).map(|(id, _)| id).collect();
+        assert_eq!(ids, [1, 2, 3, 7, 8]);
+        assert_eq!(store.row(8, None), Some(rows([8]).remove(0).1));
+    }
+}
@@ -0,0 +1,230 @@
+//! Conflict-free replicated data types for QubeDB
+//!
+//! A `Counter` can be incremented and decremented on any number of replicas
+//! at once, without a single writer, and still converges. Each replica
+//! only ever raises its own running totals of increments and decrements,
+//! and merging two counters keeps the higher total of every replica, so
+//! counters can be merged in any order and any number of times. The value
+//! is the sum of all increments minus the sum of all decrements.
+//!
+//! Every operation returns a `CounterDelta` holding just the changed
+//! replica's totals. Shipping deltas rather than whole counters keeps
+//! replication small, and applying a delta is itself a merge, so one that
+//! arrives twice or out of order does no harm.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A counter that replicas update independently and merge (a PN-counter)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Counter {
+    /// Total increments made on each replica
+    increments: BTreeMap<String, u64>,
+    /// Total decrements made on each replica
+    decrements: BTreeMap<String, u64>,
+}
+
+/// One replica's totals after an operation, to apply on other replicas
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterDelta {
+    pub replica: String,
+    pub increments: u64,
+    pub decrements: u64,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current value: every replica's increments less their decrements
+    pub fn value(&self) -> i64 {
+        let increments: i128 = self.increments.values().map(|&n| n as i128).sum();
+        let decrements: i128 = self.decrements.values().map(|&n| n as i128).sum();
+        (increments - decrements).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Add `amount` on `replica`
+    pub fn increment(&mut self, replica: &str, amount: u64) -> CounterDelta {
+        if amount > 0 {
+            let total = self.increments.entry(replica.to_string()).or_default();
+            *total = total.saturating_add(amount);
+        }
+        self.delta(replica)
+    }
+
+    /// Subtract `amount` on `replica`
+    pub fn decrement(&mut self, replica: &str, amount: u64) -> CounterDelta {
+        if amount > 0 {
+            let total = self.decrements.entry(replica.to_string()).or_default();
+            *total = total.saturating_add(amount);
+        }
+        self.delta(replica)
+    }
+
+    /// The totals `replica` has contributed so far
+    pub fn delta(&self, replica: &str) -> CounterDelta {
+        CounterDelta {
+            replica: replica.to_string(),
+            increments: self.increments.get(replica).copied().unwrap_or(0),
+            decrements: self.decrements.get(replica).copied().unwrap_or(0),
+        }
+    }
+
+    /// Fold in another replica's copy of the counter
+    pub fn merge(&mut self, other: &Counter) {
+        merge_totals(&mut self.increments, &other.increments);
+        merge_totals(&mut self.decrements, &other.decrements);
+    }
+
+    /// Fold in an operation made on another replica
+    pub fn apply(&mut self, delta: &CounterDelta) {
+        raise(&mut self.increments, &delta.replica, delta.increments);
+        raise(&mut self.decrements, &delta.replica, delta.decrements);
+    }
+
+    /// Replicas that have changed the counter, sorted
+    pub fn replicas(&self) -> Vec<&str> {
+        let mut replicas: Vec<&str> = self
+            .increments
+            .keys()
+            .chain(self.decrements.keys())
+            .map(String::as_str)
+            .collect();
+        replicas.sort_unstable();
+        replicas.dedup();
+        replicas
+    }
+}
+
+/// A counter starting at `value`, credited to the unnamed replica `""`.
+/// This is how an integer stored in a `COUNTER` column becomes one.
+impl From<i64> for Counter {
+    fn from(value: i64) -> Self {
+        let mut counter = Counter::new();
+        if value >= 0 {
+            counter.increment("", value as u64);
+        } else {
+            counter.decrement("", value.unsigned_abs());
+        }
+        counter
+    }
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value())
+    }
+}
+
+fn merge_totals(totals: &mut BTreeMap<String, u64>, other: &BTreeMap<String, u64>) {
+    for (replica, &total) in other {
+        raise(totals, replica, total);
+    }
+}
+
+/// Raise `replica`'s total to `total` unless it is already higher
+fn raise(totals: &mut BTreeMap<String, u64>, replica: &str, total: u64) {
+    if total == 0 {
+        return;
+    }
+    let current = totals.entry(replica.to_string()).or_default();
+    *current = (*current).max(total);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three replicas that each counted on their own
+    fn replicas() -> [Counter; 3] {
+        let mut a = Counter::new();
+        a.increment("a", 5);
+        a.decrement("a", 2);
+        let mut b = Counter::new();
+        b.increment("b", 7);
+        let mut c = Counter::from(10);
+        c.decrement("c", 4);
+        [a, b, c]
+    }
+
+    fn merged(counters: &[&Counter]) -> Counter {
+        let mut merged = Counter::new();
+        for counter in counters {
+            merged.merge(counter);
+        }
+        merged
+    }
+
+    #[test]
+    fn merging_in_any_order_converges() {
+        let [a, b, c] = replicas();
+        let expected = merged(&[&a, &b, &c]);
+        assert_eq!(expected.value(), 3 + 7 + 6);
+        assert_eq!(expected.replicas(), ["", "a", "b", "c"]);
+        for order in [
+            [&a, &c, &b],
+            [&b, &a, &c],
+            [&b, &c, &a],
+            [&c, &a, &b],
+            [&c, &b, &a],
+        ] {
+            assert_eq!(merged(&order), expected);
+        }
+
+        // Replicas that diverged after seeing each other still agree
+        let mut left = merged(&[&a, &b]);
+        let mut right = merged(&[&b, &c]);
+        left.increment("a", 1);
+        right.decrement("c", 1);
+        let mut left_then_right = left.clone();
+        left_then_right.merge(&right);
+        let mut right_then_left = right.clone();
+        right_then_left.merge(&left);
+        assert_eq!(left_then_right, right_then_left);
+        assert_eq!(left_then_right.value(), expected.value());
+    }
+
+    #[test]
+    fn merging_again_changes_nothing() {
+        let [a, b, _] = replicas();
+        let mut counter = merged(&[&a, &b]);
+        let before = counter.clone();
+        counter.merge(&a);
+        counter.merge(&before);
+        counter.merge(&counter.clone());
+        assert_eq!(counter, before);
+        assert_eq!(counter.value(), 10);
+    }
+
+    #[test]
+    fn deltas_applied_twice_or_out_of_order_converge() {
+        let mut origin = Counter::new();
+        let deltas = [
+            origin.increment("a", 3),
+            origin.decrement("a", 1),
+            origin.increment("a", 4),
+        ];
+        assert_eq!(origin.value(), 6);
+
+        let mut replica = Counter::new();
+        for delta in deltas.iter().rev().chain(&deltas) {
+            replica.apply(delta);
+        }
+        assert_eq!(replica, origin);
+        // A stale delta can't lower a total
+        replica.apply(&deltas[0]);
+        assert_eq!(replica.value(), 6);
+    }
+
+    #[test]
+    fn integers_become_counters_on_the_unnamed_replica() {
+        assert_eq!(Counter::from(-3).value(), -3);
+        assert_eq!(Counter::from(0).replicas(), Vec::<&str>::new());
+        let mut counter = Counter::from(i64::MAX);
+        counter.increment("a", u64::MAX);
+        assert_eq!(counter.value(), i64::MAX);
+        assert_eq!(Counter::from(42).to_string(), "42");
+    }
+}
@@ -0,0 +1,1011 @@
+//! Cypher-style graph queries
+//!
+//! Parses and runs a subset of Cypher against a single graph:
+//!
+//! ```text
+//! MATCH (a:Person {name: 'Alice'})-[r:FRIENDS]->(b)
+//! WHERE b.age > 30
+//! RETURN b.name AS friend, r.since
+//! ORDER BY friend SKIP 5 LIMIT 10
+//! ```
+//!
+//! A pattern is a chain of nodes joined by relationships pointing right
+//! (`-[]->`), left (`<-[]-`) or either way (`-[]-`). A relationship may
+//! accept several types (`[:FRIENDS|KNOWS]`) and span a fixed number of
+//! hops (`[:KNOWS*2]`). No edge is used twice within one match.
+//!
+//! WHERE, RETURN and ORDER BY take SQL expressions, in which `a.name` reads
+//! a property of whatever `a` is bound to and missing properties are NULL.
+//! `RETURN a` returns a whole node or relationship as a JSON object, and
+//! aggregates such as `count(b)` summarise every match.
+//!
+//! When the first node of the pattern has a label, and one of its property
+//! constraints or a `WHERE a.prop = literal` equality is on an indexed
+//! property, only the nodes the index returns are tried as start nodes.
+
+use crate::aggregate;
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::graph::{Edge, Graph, Node};
+use crate::query::{compare_sort_keys, ExecutionContext, QueryOptions};
+use crate::types::{QueryResult, ResultKind, Row, Value};
+use sqlparser::ast::{
+    visit_expressions, BinaryOperator, Distinct, Expr, Ident, OrderByExpr, SelectItem, SetExpr,
+    Statement,
+};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+/// A parsed graph query
+#[derive(Debug, Clone)]
+pub struct CypherQuery {
+    pattern: Pattern,
+    projection: Vec<SelectItem>,
+    selection: Option<Expr>,
+    distinct: bool,
+    order_by: Vec<OrderByExpr>,
+    limit: Option<Expr>,
+    offset: Option<Expr>,
+}
+
+/// A chain of nodes joined by relationships
+#[derive(Debug, Clone)]
+struct Pattern {
+    start: NodePattern,
+    steps: Vec<(RelationshipPattern, NodePattern)>,
+}
+
+/// `(variable:Label {property: value})`
+#[derive(Debug, Clone, Default)]
+struct NodePattern {
+    variable: Option<String>,
+    label: Option<String>,
+    properties: Vec<(String, Value)>,
+}
+
+/// `-[variable:TYPE|OTHER*hops]->`
+#[derive(Debug, Clone)]
+struct RelationshipPattern {
+    variable: Option<String>,
+    types: Vec<String>,
+    direction: Direction,
+    hops: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Outgoing,
+    Incoming,
+    Either,
+}
+
+/// Parse a `MATCH ... [WHERE ...] RETURN ...` query
+pub fn parse(query: &str) -> QubeResult<CypherQuery> {
+    let query = query.trim().trim_end_matches(';');
+    let rest = match query.get(..5) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("MATCH") => &query[5..],
+        _ => return Err(syntax("queries must start with MATCH")),
+    };
+
+    let mut cursor = Cursor::new(rest);
+    let pattern = cursor.pattern()?;
+    let rest = cursor.remainder();
+
+    let return_at = find_keyword(rest, "RETURN").ok_or_else(|| syntax("missing RETURN"))?;
+    let condition = rest[..return_at].trim();
+    let condition = match find_keyword(condition, "WHERE") {
+        Some(0) => Some(&condition[5..]),
+        None if condition.is_empty() => None,
+        _ => return Err(syntax(&format!("unexpected '{}'", condition))),
+    };
+    let returned = &rest[return_at + 6..];
+    let tail_at = ["ORDER", "SKIP", "LIMIT"]
+        .iter()
+        .filter_map(|keyword| find_keyword(returned, keyword))
+        .min()
+        .unwrap_or(returned.len());
+    let (items, tail) = returned.split_at(tail_at);
+
+    // The clauses after MATCH are SQL apart from SKIP, so let the SQL
+    // parser handle them as a SELECT over the matches
+    let tail = match find_keyword(tail, "SKIP") {
+        Some(at) => format!("{}OFFSET{}", &tail[..at], &tail[at + 4..]),
+        None => tail.to_string(),
+    };
+    let sql = format!(
+        "SELECT {} FROM matches {} {}",
+        items,
+        condition
+            .map(|c| format!("WHERE {}", c))
+            .unwrap_or_default(),
+        tail
+    );
+    let statement = Parser::parse_sql(&GenericDialect {}, &sql)
+        .map_err(|e| syntax(&e.to_string()))?
+        .pop();
+    let query = match statement {
+        Some(Statement::Query(query)) => *query,
+        _ => return Err(syntax("invalid RETURN clause")),
+    };
+    let select = match *query.body {
+        SetExpr::Select(select) => *select,
+        _ => return Err(syntax("invalid RETURN clause")),
+    };
+
+    Ok(CypherQuery {
+        pattern,
+        projection: select.projection,
+        selection: select.selection,
+        distinct: matches!(select.distinct, Some(Distinct::Distinct)),
+        order_by: query.order_by,
+        limit: query.limit,
+        offset: query.offset.map(|offset| offset.value),
+    })
+}
+
+/// Run a parsed query against `graph`
+pub fn execute(graph: &Graph, query: &CypherQuery) -> QubeResult<QueryResult> {
+    let start_time = Instant::now();
+    let mut ctx = ExecutionContext::new(&QueryOptions::default());
+    let variables = query.pattern.variables();
+
+    // Output columns; `RETURN *` returns every variable
+    let mut projection = Vec::new();
+    for item in &query.projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) => projection.push((expr.to_string(), expr.clone())),
+            SelectItem::ExprWithAlias { expr, alias } => {
+                projection.push((alias.value.clone(), expr.clone()))
+            }
+            SelectItem::Wildcard(_) => projection.extend(
+                variables
+                    .iter()
+                    .map(|v| (v.to_string(), Expr::Identifier(Ident::new(*v)))),
+            ),
+            SelectItem::QualifiedWildcard(..) => return Err(syntax("unsupported RETURN item")),
+        }
+    }
+    let columns: Vec<String> = projection.iter().map(|(name, _)| name.clone()).collect();
+
+    // Only the properties the query reads are copied into each row
+    let mut references = References::default();
+    projection
+        .iter()
+        .map(|(_, expr)| expr)
+        .chain(&query.selection)
+        .chain(query.order_by.iter().map(|order| &order.expr))
+        .for_each(|expr| references.collect(expr));
+
+    let mut rows = Vec::new();
+    let matcher = Matcher::new(graph, &query.pattern);
+    for bindings in matcher.run(query.selection.as_ref(), &mut ctx)? {
+        let row = references.row(&bindings);
+        if let Some(selection) = &query.selection {
+            if !expr::is_true(&expr::evaluate(selection, &row)?) {
+                continue;
+            }
+        }
+        rows.push(row);
+    }
+
+    let items: Vec<SelectItem> = projection
+        .iter()
+        .map(|(_, expr)| SelectItem::UnnamedExpr(expr.clone()))
+        .collect();
+    let mut output = if aggregate::has_aggregates(&items) {
+        vec![aggregate::aggregate_rows(&projection, &rows, &mut ctx)?]
+    } else {
+        // Sort on the match row extended with the output columns, so ORDER
+        // BY can name a RETURN alias
+        let mut keyed = Vec::with_capacity(rows.len());
+        for mut row in rows {
+            ctx.tick()?;
+            let projected = projection
+                .iter()
+                .map(|(name, expr)| Ok((name.clone(), expr::evaluate(expr, &row)?)))
+                .collect::<QubeResult<Row>>()?;
+            row.extend(projected.clone());
+            let keys = query
+                .order_by
+                .iter()
+                .map(|order| expr::evaluate(&order.expr, &row))
+                .collect::<QubeResult<Vec<_>>>()?;
+            keyed.push((keys, projected));
+        }
+        if !query.order_by.is_empty() {
+            keyed.sort_by(|(a, _), (b, _)| compare_sort_keys(a, b, &query.order_by));
+        }
+        keyed.into_iter().map(|(_, row)| row).collect::<Vec<_>>()
+    };
+
+    if query.distinct {
+        let mut seen = HashSet::new();
+        output.retain(|row| {
+            let values: Vec<Value> = columns
+                .iter()
+                .map(|c| row.get(c).cloned().unwrap_or(Value::Null))
+                .collect();
+            seen.insert(values)
+        });
+    }
+    let offset = count(query.offset.as_ref(), "SKIP")?.unwrap_or(0);
+    let limit = count(query.limit.as_ref(), "LIMIT")?.unwrap_or(usize::MAX);
+    let rows = output.into_iter().skip(offset).take(limit).collect();
+
+    Ok(QueryResult {
+        kind: ResultKind::Rows,
+        columns,
+        rows,
+        affected_rows: 0,
+        last_insert_id: None,
+        execution_time: start_time.elapsed(),
+    })
+}
+
+fn syntax(message: &str) -> QubeError {
+    QubeError::QueryParse(format!("Invalid graph query: {}", message))
+}
+
+/// Evaluate a SKIP or LIMIT count
+fn count(expr: Option<&Expr>, clause: &str) -> QubeResult<Option<usize>> {
+    let Some(expr) = expr else {
+        return Ok(None);
+    };
+    let value = expr::evaluate(expr, &Row::new())?;
+    value
+        .as_i64()
+        .and_then(|n| usize::try_from(n).ok())
+        .map(Some)
+        .ok_or_else(|| syntax(&format!("{} must be a non-negative integer", clause)))
+}
+
+/// Byte offset of `keyword` as a whole word outside quotes and brackets
+fn find_keyword(text: &str, keyword: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let mut quote = None;
+    let mut depth = 0usize;
+    for (i, &b) in bytes.iter().enumerate() {
+        match (quote, b) {
+            (Some(q), _) if b == q => quote = None,
+            (Some(_), _) => {}
+            (None, b'\'' | b'"' | b'`') => quote = Some(b),
+            (None, b'(' | b'[' | b'{') => depth += 1,
+            (None, b')' | b']' | b'}') => depth = depth.saturating_sub(1),
+            (None, _) if depth == 0 && (i == 0 || !is_word(bytes[i - 1])) => {
+                let end = i + keyword.len();
+                if text
+                    .get(i..end)
+                    .is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+                    && bytes.get(end).is_none_or(|&b| !is_word(b))
+                {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+impl Pattern {
+    /// Every variable the pattern binds, in order of appearance
+    fn variables(&self) -> Vec<&str> {
+        let relationships = self.steps.iter().flat_map(|(relationship, node)| {
+            [relationship.variable.as_deref(), node.variable.as_deref()]
+        });
+        let mut variables: Vec<&str> = Vec::new();
+        for variable in std::iter::once(self.start.variable.as_deref())
+            .chain(relationships)
+            .flatten()
+        {
+            if !variables.contains(&variable) {
+                variables.push(variable);
+            }
+        }
+        variables
+    }
+}
+
+/// Character cursor over a MATCH pattern
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(text: &'a str) -> Self {
+        Cursor { text, pos: 0 }
+    }
+
+    fn remainder(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        let rest = self.remainder();
+        self.pos += rest.len() - rest.trim_start().len();
+        self.remainder().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> QubeResult<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(syntax(&format!(
+                "expected '{}' at '{}'",
+                c,
+                self.remainder().trim()
+            )))
+        }
+    }
+
+    fn pattern(&mut self) -> QubeResult<Pattern> {
+        let start = self.node()?;
+        let mut steps = Vec::new();
+        while matches!(self.peek(), Some('-' | '<')) {
+            let relationship = self.relationship()?;
+            steps.push((relationship, self.node()?));
+        }
+
+        // A variable names either nodes or relationships, never both
+        let nodes: HashSet<&String> = std::iter::once(&start)
+            .chain(steps.iter().map(|(_, node)| node))
+            .filter_map(|node| node.variable.as_ref())
+            .collect();
+        let mut relationships = HashSet::new();
+        for (relationship, _) in &steps {
+            if let Some(v) = &relationship.variable {
+                if nodes.contains(v) || !relationships.insert(v) {
+                    return Err(syntax(&format!("variable '{}' is bound twice", v)));
+                }
+            }
+        }
+        Ok(Pattern { start, steps })
+    }
+
+    /// `(variable:Label {property: value, ...})`, every part optional
+    fn node(&mut self) -> QubeResult<NodePattern> {
+        self.expect('(')?;
+        let mut node = NodePattern {
+            variable: self.identifier()?,
+            ..NodePattern::default()
+        };
+        if self.eat(':') {
+            node.label = Some(self.required_identifier()?);
+        }
+        if self.eat('{') && !self.eat('}') {
+            loop {
+                let key = self.required_identifier()?;
+                self.expect(':')?;
+                node.properties.push((key, self.literal()?));
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect('}')?;
+        }
+        self.expect(')')?;
+        Ok(node)
+    }
+
+    /// `-[...]->`, `<-[...]-` or `-[...]-`, where the brackets are optional
+    fn relationship(&mut self) -> QubeResult<RelationshipPattern> {
+        let incoming = self.eat('<');
+        self.expect('-')?;
+        let mut relationship = RelationshipPattern {
+            variable: None,
+            types: Vec::new(),
+            direction: Direction::Either,
+            hops: 1,
+        };
+        if self.eat('[') {
+            relationship.variable = self.identifier()?;
+            if self.eat(':') {
+                loop {
+                    relationship.types.push(self.required_identifier()?);
+                    if !self.eat('|') {
+                        break;
+                    }
+                    self.eat(':');
+                }
+            }
+            if self.eat('*') {
+                relationship.hops = match self.integer() {
+                    Some(hops) if hops > 0 && self.peek() == Some(']') => hops,
+                    _ => {
+                        return Err(syntax(
+                            "only fixed-length relationships such as *2 are supported",
+                        ))
+                    }
+                };
+                if relationship.hops > 1 && relationship.variable.is_some() {
+                    return Err(syntax(
+                        "a multi-hop relationship cannot be bound to a variable",
+                    ));
+                }
+            }
+            self.expect(']')?;
+        }
+        self.expect('-')?;
+        relationship.direction = match (incoming, self.eat('>')) {
+            (false, true) => Direction::Outgoing,
+            (true, false) => Direction::Incoming,
+            (false, false) => Direction::Either,
+            (true, true) => return Err(syntax("a relationship cannot point both ways")),
+        };
+        Ok(relationship)
+    }
+
+    fn identifier(&mut self) -> QubeResult<Option<String>> {
+        if self.eat('`') {
+            let rest = self.remainder();
+            let end = rest.find('`').ok_or_else(|| syntax("unterminated `"))?;
+            self.pos += end + 1;
+            return Ok(Some(rest[..end].to_string()));
+        }
+        match self.peek() {
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let rest = self.remainder();
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                self.pos += end;
+                Ok(Some(rest[..end].to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn required_identifier(&mut self) -> QubeResult<String> {
+        self.identifier()?
+            .ok_or_else(|| syntax(&format!("expected a name at '{}'", self.remainder().trim())))
+    }
+
+    fn integer(&mut self) -> Option<usize> {
+        let rest = self.remainder();
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = rest[..end].parse().ok()?;
+        self.pos += end;
+        Some(n)
+    }
+
+    /// A string, number, boolean or null in a property map
+    fn literal(&mut self) -> QubeResult<Value> {
+        match self.peek() {
+            Some(quote @ ('\'' | '"')) => {
+                self.pos += 1;
+                let mut text = String::new();
+                let mut chars = self.remainder().char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some((_, escaped)) = chars.next() {
+                                text.push(escaped);
+                            }
+                        }
+                        c if c == quote => {
+                            self.pos += i + 1;
+                            return Ok(Value::String(text));
+                        }
+                        c => text.push(c),
+                    }
+                }
+                Err(syntax("unterminated string"))
+            }
+            _ => {
+                let rest = self.remainder();
+                let end = rest.find([',', '}']).unwrap_or(rest.len());
+                let word = rest[..end].trim();
+                self.pos += end;
+                if let Ok(n) = word.parse::<i64>() {
+                    Ok(Value::Int64(n))
+                } else if let Ok(n) = word.parse::<f64>() {
+                    Ok(Value::Float64(n))
+                } else {
+                    match word.to_lowercase().as_str() {
+                        "true" => Ok(Value::Boolean(true)),
+                        "false" => Ok(Value::Boolean(false)),
+                        "null" => Ok(Value::Null),
+                        _ => Err(syntax(&format!("invalid property value '{}'", word))),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What a pattern variable is bound to in one match
+#[derive(Debug, Clone, Copy)]
+enum Bound<'g> {
+    Node(&'g Node),
+    Edge(&'g Edge),
+}
+
+type Bindings<'g, 'q> = Vec<(&'q str, Bound<'g>)>;
+
+/// Depth-first search for every way the pattern fits the graph
+struct Matcher<'g, 'q> {
+    graph: &'g Graph,
+    pattern: &'q Pattern,
+    bindings: Bindings<'g, 'q>,
+    /// Edges used by the current partial match
+    used: Vec<usize>,
+    matches: Vec<Bindings<'g, 'q>>,
+}
+
+impl<'g, 'q> Matcher<'g, 'q> {
+    fn new(graph: &'g Graph, pattern: &'q Pattern) -> Self {
+        Matcher {
+            graph,
+            pattern,
+            bindings: Vec::new(),
+            used: Vec::new(),
+            matches: Vec::new(),
+        }
+    }
+
+    fn run(
+        mut self,
+        selection: Option<&Expr>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<Vec<Bindings<'g, 'q>>> {
+        let candidates = self.start_candidates(selection);
+        self.graph.record_examined(candidates.len());
+        for node in candidates {
+            if self.bind_node(&self.pattern.start, node) {
+                self.step(0, node, ctx)?;
+            }
+            self.bindings.clear();
+        }
+        Ok(self.matches)
+    }
+
+    /// Nodes the pattern may start from: those a property index returns
+    /// for an equality on the start node, or else every node
+    fn start_candidates(&self, selection: Option<&Expr>) -> Vec<&'g Node> {
+        let graph = self.graph;
+        let start = &self.pattern.start;
+        if let Some(label) = &start.label {
+            let mut equalities = start.properties.clone();
+            if let (Some(variable), Some(selection)) = (&start.variable, selection) {
+                equalities_on(selection, variable, &mut equalities);
+            }
+            for (property, value) in &equalities {
+                if let Some(nodes) = graph.find_nodes(label, property, value) {
+                    return nodes;
+                }
+            }
+        }
+        graph.nodes().collect()
+    }
+
+    /// Match the `step`th relationship onwards, starting from `node`
+    fn step(&mut self, step: usize, node: &'g Node, ctx: &mut ExecutionContext) -> QubeResult<()> {
+        ctx.tick()?;
+        match self.pattern.steps.get(step) {
+            None => {
+                self.matches.push(self.bindings.clone());
+                Ok(())
+            }
+            Some((relationship, _)) => self.hop(step, node, relationship.hops, ctx),
+        }
+    }
+
+    /// Follow one edge of the `step`th relationship, `remaining` hops from
+    /// its end
+    fn hop(
+        &mut self,
+        step: usize,
+        node: &'g Node,
+        remaining: usize,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<()> {
+        let pattern = self.pattern;
+        let graph = self.graph;
+        let (relationship, target) = &pattern.steps[step];
+        for (index, neighbour) in self.neighbours(node, relationship) {
+            if self.used.contains(&index) {
+                continue;
+            }
+            let Some(next) = graph.node(neighbour) else {
+                continue;
+            };
+            self.used.push(index);
+            let mark = self.bindings.len();
+            if remaining > 1 {
+                self.hop(step, next, remaining - 1, ctx)?;
+            } else if self.bind(&relationship.variable, Bound::Edge(graph.edge(index)))
+                && self.bind_node(target, next)
+            {
+                self.step(step + 1, next, ctx)?;
+            }
+            self.bindings.truncate(mark);
+            self.used.pop();
+        }
+        Ok(())
+    }
+
+    /// Edges of an allowed type touching `node` in the pattern's direction,
+    /// with the node at their other end
+    fn neighbours(&self, node: &Node, relationship: &RelationshipPattern) -> Vec<(usize, &'g str)> {
+        let graph = self.graph;
+        let mut found = Vec::new();
+        if relationship.direction != Direction::Incoming {
+            for &index in graph.outgoing(&node.id) {
+                found.push((index, graph.edge(index).to.as_str()));
+            }
+        }
+        if relationship.direction != Direction::Outgoing {
+            for &index in graph.incoming(&node.id) {
+                let edge = graph.edge(index);
+                // A self-loop was already found as an outgoing edge
+                if relationship.direction == Direction::Either && edge.from == edge.to {
+                    continue;
+                }
+                found.push((index, edge.from.as_str()));
+            }
+        }
+        found.retain(|&(index, _)| {
+            relationship.types.is_empty()
+                || graph
+                    .edge(index)
+                    .edge_type()
+                    .is_some_and(|t| relationship.types.iter().any(|wanted| wanted == t))
+        });
+        found
+    }
+
+    /// Bind `node` if it fits the pattern, returning whether it did
+    fn bind_node(&mut self, pattern: &'q NodePattern, node: &'g Node) -> bool {
+        let fits = pattern
+            .label
+            .as_ref()
+            .is_none_or(|label| node.label() == Some(label))
+            && pattern.properties.iter().all(|(key, value)| {
+                node.properties
+                    .get(key)
+                    .is_some_and(|actual| expr::values_equal(actual, value) == Some(true))
+            });
+        fits && self.bind(&pattern.variable, Bound::Node(node))
+    }
+
+    /// Bind a variable, or check it is already bound to the same entity
+    fn bind(&mut self, variable: &'q Option<String>, bound: Bound<'g>) -> bool {
+        let Some(variable) = variable else {
+            return true;
+        };
+        match self.bindings.iter().find(|(v, _)| v == variable) {
+            Some((_, Bound::Node(a))) => matches!(bound, Bound::Node(b) if std::ptr::eq(*a, b)),
+            Some((_, Bound::Edge(a))) => matches!(bound, Bound::Edge(b) if std::ptr::eq(*a, b)),
+            None => {
+                self.bindings.push((variable, bound));
+                true
+            }
+        }
+    }
+}
+
+/// Collect `variable.property = literal` equalities that every row passing
+/// `condition` satisfies
+fn equalities_on(condition: &Expr, variable: &str, found: &mut Vec<(String, Value)>) {
+    match condition {
+        Expr::Nested(inner) => equalities_on(inner, variable, found),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            equalities_on(left, variable, found);
+            equalities_on(right, variable, found);
+        }
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => {
+            for (side, other) in [(left, right), (right, left)] {
+                let (Expr::CompoundIdentifier(idents), Expr::Value(_)) = (&**side, &**other) else {
+                    continue;
+                };
+                if idents.len() != 2 || idents[0].value != variable {
+                    continue;
+                }
+                if let Ok(value) = expr::evaluate(other, &Row::new()) {
+                    found.push((idents[1].value.clone(), value));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The variables and properties a query reads
+#[derive(Default)]
+struct References {
+    /// Variables used whole, e.g. `RETURN b`
+    whole: HashSet<String>,
+    /// Properties read per variable, e.g. `b.name`
+    properties: HashMap<String, HashSet<String>>,
+}
+
+impl References {
+    fn collect(&mut self, expr: &Expr) {
+        let _ = visit_expressions(expr, |e| {
+            match e {
+                Expr::Identifier(ident) => {
+                    self.whole.insert(ident.value.clone());
+                }
+                Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+                    self.properties
+                        .entry(idents[0].value.clone())
+                        .or_default()
+                        .insert(idents[1].value.clone());
+                }
+                _ => {}
+            }
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    /// The row expressions are evaluated against for one match
+    fn row(&self, bindings: &Bindings) -> Row {
+        let mut row = Row::new();
+        for (variable, bound) in bindings {
+            let properties = match bound {
+                Bound::Node(node) => &node.properties,
+                Bound::Edge(edge) => &edge.properties,
+            };
+            if self.whole.contains(*variable) {
+                let mut object = serde_json::Map::new();
+                match bound {
+                    Bound::Node(node) => {
+                        object.insert("id".to_string(), node.id.clone().into());
+                    }
+                    Bound::Edge(edge) => {
+                        object.insert("from".to_string(), edge.from.clone().into());
+                        object.insert("to".to_string(), edge.to.clone().into());
+                    }
+                }
+                for (key, value) in properties {
+                    object.insert(key.clone(), expr::to_json(value));
+                }
+                row.insert(variable.to_string(), Value::Json(object.into()));
+            }
+            for property in self.properties.get(*variable).into_iter().flatten() {
+                let value = match (properties.get(property), bound) {
+                    (Some(value), _) => value.clone(),
+                    (None, Bound::Node(node)) if property == "id" => Value::String(node.id.clone()),
+                    _ => Value::Null,
+                };
+                row.insert(format!("{}.{}", variable, property), value);
+            }
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(pairs: &[(&str, Value)]) -> Row {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    /// Alice is friends with Bob and Carol, Carol with Dave, Bob knows
+    /// Carol, and Alice works at Acme
+    fn social() -> Graph {
+        let mut graph = Graph::new();
+        for (id, name, age) in [
+            ("alice", "Alice", 30),
+            ("bob", "Bob", 35),
+            ("carol", "Carol", 40),
+            ("dave", "Dave", 25),
+        ] {
+            graph.add_node(
+                id,
+                properties(&[
+                    ("label", text("Person")),
+                    ("name", text(name)),
+                    ("age", Value::Int64(age)),
+                ]),
+            );
+        }
+        graph.add_node(
+            "acme",
+            properties(&[("label", text("Company")), ("name", text("Acme"))]),
+        );
+        for (from, to, edge_type, since) in [
+            ("alice", "bob", "FRIENDS", 2010),
+            ("alice", "carol", "FRIENDS", 2015),
+            ("carol", "dave", "FRIENDS", 2020),
+            ("bob", "carol", "KNOWS", 2018),
+            ("alice", "acme", "WORKS_AT", 2012),
+        ] {
+            graph.add_edge(
+                from,
+                to,
+                properties(&[("type", text(edge_type)), ("since", Value::Int64(since))]),
+            );
+        }
+        graph
+    }
+
+    fn run(graph: &Graph, query: &str) -> QueryResult {
+        execute(graph, &parse(query).unwrap()).unwrap()
+    }
+
+    /// One column of every row
+    fn column(graph: &Graph, query: &str, name: &str) -> Vec<Value> {
+        run(graph, query)
+            .rows
+            .iter()
+            .map(|row| row[name].clone())
+            .collect()
+    }
+
+    fn texts(values: &[&str]) -> Vec<Value> {
+        values.iter().map(|value| text(value)).collect()
+    }
+
+    #[test]
+    fn patterns_parse_into_nodes_and_relationships() {
+        let query = parse(
+            "match (a:Person {name: 'Al\\'s', age: 30, vip: true})<-[r:FRIENDS|:KNOWS]-(b)\
+             -[*2]-(:Company) WHERE a.age > 20 RETURN DISTINCT b.name AS friend \
+             ORDER BY friend SKIP 1 LIMIT 2;",
+        )
+        .unwrap();
+        let start = &query.pattern.start;
+        assert_eq!(start.variable.as_deref(), Some("a"));
+        assert_eq!(start.label.as_deref(), Some("Person"));
+        assert_eq!(
+            start.properties,
+            [
+                ("name".to_string(), text("Al's")),
+                ("age".to_string(), Value::Int64(30)),
+                ("vip".to_string(), Value::Boolean(true)),
+            ]
+        );
+
+        let (first, b) = &query.pattern.steps[0];
+        assert_eq!(first.variable.as_deref(), Some("r"));
+        assert_eq!(first.types, ["FRIENDS", "KNOWS"]);
+        assert_eq!((first.direction, first.hops), (Direction::Incoming, 1));
+        assert_eq!(b.variable.as_deref(), Some("b"));
+        let (second, company) = &query.pattern.steps[1];
+        assert!(second.types.is_empty());
+        assert_eq!((second.direction, second.hops), (Direction::Either, 2));
+        assert_eq!(company.variable, None);
+        assert_eq!(company.label.as_deref(), Some("Company"));
+        assert_eq!(query.pattern.variables(), ["a", "r", "b"]);
+
+        assert!(query.distinct);
+        assert!(query.selection.is_some());
+        assert_eq!(query.order_by.len(), 1);
+        assert!(query.offset.is_some() && query.limit.is_some());
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        for query in [
+            "RETURN 1",
+            "MATCH (a)",
+            "MATCH (a) a.x = 1 RETURN a",
+            "MATCH (a RETURN a",
+            "MATCH (a)<-[]->(b) RETURN a",
+            "MATCH (a)-[*]->(b) RETURN a",
+            "MATCH (a)-[r*2]->(b) RETURN a",
+            "MATCH (a)-[a]->(b) RETURN a",
+            "MATCH (a {name: Alice}) RETURN a",
+            "MATCH (a {name: 'Alice}) RETURN a",
+            "MATCH (a) RETURN a LIMIT -1",
+        ] {
+            let result = parse(query).and_then(|parsed| execute(&social(), &parsed));
+            assert!(
+                matches!(result, Err(QubeError::QueryParse(_))),
+                "{} gave {:?}",
+                query,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn relationships_are_followed_in_their_direction() {
+        let graph = social();
+        let friends =
+            "MATCH (a:Person {name: 'Alice'})-[:FRIENDS]->(b) RETURN b.name ORDER BY b.name";
+        assert_eq!(column(&graph, friends, "b.name"), texts(&["Bob", "Carol"]));
+
+        let incoming = "MATCH (c {name: 'Carol'})<-[r]-(b) RETURN b.name, r.since ORDER BY r.since";
+        let result = run(&graph, incoming);
+        assert_eq!(result.columns, ["b.name", "r.since"]);
+        let rows: Vec<(Value, Value)> = result
+            .rows
+            .iter()
+            .map(|row| (row["b.name"].clone(), row["r.since"].clone()))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (text("Alice"), Value::Int64(2015)),
+                (text("Bob"), Value::Int64(2018)),
+            ]
+        );
+
+        let either = "MATCH (c {name: 'Carol'})-[:FRIENDS|KNOWS]-(b) RETURN b.name ORDER BY b.name";
+        assert_eq!(
+            column(&graph, either, "b.name"),
+            texts(&["Alice", "Bob", "Dave"])
+        );
+    }
+
+    #[test]
+    fn multi_hop_patterns_never_reuse_an_edge() {
+        let graph = social();
+        let two_hops =
+            "MATCH (a {name: 'Alice'})-[:FRIENDS|KNOWS*2]->(c) RETURN c.name ORDER BY c.name";
+        assert_eq!(
+            column(&graph, two_hops, "c.name"),
+            texts(&["Carol", "Dave"])
+        );
+
+        // Back along the edge just taken is not a friend of a friend
+        let chain = "MATCH (a {name: 'Alice'})-[:FRIENDS]-(b)-[:FRIENDS]-(c) RETURN c.name";
+        assert_eq!(column(&graph, chain, "c.name"), texts(&["Dave"]));
+        let chain = "MATCH (a {name: 'Alice'})-[:FRIENDS*2]-(c) RETURN c.name";
+        assert_eq!(column(&graph, chain, "c.name"), texts(&["Dave"]));
+    }
+
+    #[test]
+    fn return_clauses_filter_sort_page_and_aggregate() {
+        let graph = social();
+        let older = "MATCH (p:Person) WHERE p.age >= 30 RETURN p.name AS name \
+                     ORDER BY p.age DESC SKIP 1 LIMIT 1";
+        assert_eq!(column(&graph, older, "name"), texts(&["Bob"]));
+
+        let count = "MATCH (a:Person)-[:FRIENDS]->(b) RETURN count(b) AS n, max(b.age) AS oldest";
+        let result = run(&graph, count);
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0]["n"], Value::Int64(3));
+        assert_eq!(result.rows[0]["oldest"], Value::Int64(40));
+
+        let distinct = "MATCH (a)-[:FRIENDS]->(b) RETURN DISTINCT a.name ORDER BY a.name";
+        assert_eq!(
+            column(&graph, distinct, "a.name"),
+            texts(&["Alice", "Carol"])
+        );
+
+        // Missing properties are NULL, and whole nodes come back as JSON
+        let company = "MATCH (c:Company) RETURN c, c.age";
+        let row = &run(&graph, company).rows[0];
+        assert_eq!(row["c.age"], Value::Null);
+        assert_eq!(
+            row["c"],
+            Value::Json(serde_json::json!({"id": "acme", "label": "Company", "name": "Acme"}))
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Connection management for QubeDB drivers
+//!
+//! A `DriverConnection` sends statements over a `Transport` opened by a
+//! `Connector`, and keeps that link alive. The transport is opened lazily
+//! and checked with a ping once it has been idle for the keepalive
+//! interval. A transport that fails with a network error is dropped and a
+//! new one opened, backing off between attempts. Read-only statements are
+//! then retried; a statement that changes data is not, since it may have
+//! been applied before the link broke.
+
+use crate::drivers::DriverConfig;
+use crate::error::{QubeError, QubeResult};
+use crate::query::{ExecutionContext, QueryEngine, QueryOptions};
+use crate::security::Permission;
+use crate::types::QueryResult;
+use serde::Serialize;
+use sqlparser::ast::Statement;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default idle time after which the link is pinged before use
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Default number of reconnect attempts before giving up
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+/// Default wait before the first reconnect attempt
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Default cap on the wait between reconnect attempts
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The link a driver connection sends statements over
+pub trait Transport: Send + Sync {
+    /// Run one statement. A broken link reports `QubeError::Network` or
+    /// `QubeError::Io`.
+    fn execute(&self, statement: &Statement) -> QubeResult<QueryResult>;
+
+    /// Check that the link is still alive
+    fn ping(&self) -> QubeResult<()>;
+}
+
+/// Opens transports to a database
+pub trait Connector: Send + Sync {
+    fn connect(&self, config: &DriverConfig) -> QubeResult<Arc<dyn Transport>>;
+}
+
+/// Connects to a query engine in the same process
+pub struct EmbeddedConnector {
+    engine: Arc<QueryEngine>,
+}
+
+impl EmbeddedConnector {
+    pub fn new(engine: Arc<QueryEngine>) -> Self {
+        EmbeddedConnector { engine }
+    }
+}
+
+impl Connector for EmbeddedConnector {
+    fn connect(&self, _config: &DriverConfig) -> QubeResult<Arc<dyn Transport>> {
+        Ok(Arc::new(EmbeddedTransport {
+            engine: Arc::clone(&self.engine),
+        }))
+    }
+}
+
+struct EmbeddedTransport {
+    engine: Arc<QueryEngine>,
+}
+
+impl Transport for EmbeddedTransport {
+    fn execute(&self, statement: &Statement) -> QubeResult<QueryResult> {
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        self.engine.execute_statement(statement.clone(), &mut ctx)
+    }
+
+    fn ping(&self) -> QubeResult<()> {
+        Ok(())
+    }
+}
+
+/// How a driver connection reconnects after its link breaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Reconnect attempts before the error is returned to the caller
+    pub max_retries: u32,
+    /// Wait before the first attempt; doubles with each further attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect: the first network error is returned
+    pub fn disabled() -> Self {
+        ReconnectPolicy {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Wait before reconnect attempt `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+/// Counters describing a driver connection's link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    /// Transports opened, including the first
+    pub connects: u64,
+    /// Connect attempts that failed
+    pub failed_connects: u64,
+    /// Links found broken, by a failed statement or keepalive ping
+    pub disconnects: u64,
+    /// Keepalive pings that failed
+    pub keepalive_failures: u64,
+    /// Statements run again after a reconnect
+    pub retries: u64,
+}
+
+struct Link {
+    transport: Option<Arc<dyn Transport>>,
+    /// When the link last proved alive
+    last_used: Instant,
+    stats: ConnectionStats,
+}
+
+/// A driver's connection to the database, reconnecting as needed
+pub struct DriverConnection {
+    config: DriverConfig,
+    connector: Arc<dyn Connector>,
+    link: Mutex<Link>,
+}
+
+impl DriverConnection {
+    /// A connection that opens its transport on first use
+    pub fn new(config: DriverConfig, connector: Arc<dyn Connector>) -> Self {
+        DriverConnection {
+            config,
+            connector,
+            link: Mutex::new(Link {
+                transport: None,
+                last_used: Instant::now(),
+                stats: ConnectionStats::default(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &DriverConfig {
+        &self.config
+    }
+
+    /// Whether a transport is open. It may still turn out to be broken.
+    pub fn is_connected(&self) -> bool {
+        self.link.lock().unwrap().transport.is_some()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.link.lock().unwrap().stats
+    }
+
+    /// Run a statement, reconnecting if the link is broken. A read-only
+    /// statement is retried on a new link; any other statement fails with
+    /// the network error, since it may already have been applied.
+    pub async fn execute(&self, statement: &Statement) -> QubeResult<QueryResult> {
+        let retryable = Permission::required_for(statement) == Permission::Read;
+        let policy = self.config.reconnect;
+        let mut attempt = 0;
+        loop {
+            let transport = self.transport().await?;
+            let error = match transport.execute(statement) {
+                Err(e) if is_link_error(&e) => e,
+                result => {
+                    self.link.lock().unwrap().last_used = Instant::now();
+                    return result;
+                }
+            };
+            self.disconnect(&transport);
+            if !retryable {
+                return Err(QubeError::Network(format!(
+                    "Connection lost while running a statement that changes data, \
+                     so it was not retried and may or may not have been applied: {}",
+                    error
+                )));
+            }
+            attempt += 1;
+            if attempt > policy.max_retries {
+                return Err(QubeError::Network(format!(
+                    "Connection lost; giving up after {} retries: {}",
+                    policy.max_retries, error
+                )));
+            }
+            tracing::warn!("Connection lost, retrying statement: {}", error);
+            self.link.lock().unwrap().stats.retries += 1;
+            tokio::time::sleep(policy.backoff(attempt)).await;
+        }
+    }
+
+    /// Ping the link if it has been idle for the keepalive interval,
+    /// reconnecting if the ping fails. Call periodically to notice a broken
+    /// link before the next statement does.
+    pub async fn keepalive(&self) -> QubeResult<()> {
+        self.transport().await.map(|_| ())
+    }
+
+    /// Ping the database now, reconnecting if the link is broken
+    pub async fn ping(&self) -> QubeResult<()> {
+        let transport = self.transport().await?;
+        match transport.ping() {
+            Err(e) if is_link_error(&e) => {
+                self.record_keepalive_failure(&transport, &e);
+                self.transport().await.map(|_| ())
+            }
+            result => result,
+        }
+    }
+
+    /// The open transport, after a keepalive check, or a newly opened one
+    async fn transport(&self) -> QubeResult<Arc<dyn Transport>> {
+        let idle = {
+            let link = self.link.lock().unwrap();
+            link.transport
+                .clone()
+                .map(|transport| (transport, link.last_used.elapsed()))
+        };
+        if let Some((transport, idle)) = idle {
+            let due = self
+                .config
+                .keepalive_interval
+                .is_some_and(|interval| idle >= interval);
+            if !due {
+                return Ok(transport);
+            }
+            match transport.ping() {
+                Ok(()) => {
+                    self.link.lock().unwrap().last_used = Instant::now();
+                    return Ok(transport);
+                }
+                Err(e) => self.record_keepalive_failure(&transport, &e),
+            }
+        }
+        self.connect().await
+    }
+
+    /// Open a transport, backing off between failed attempts
+    async fn connect(&self) -> QubeResult<Arc<dyn Transport>> {
+        let policy = self.config.reconnect;
+        let mut attempt = 0;
+        loop {
+            match self.connector.connect(&self.config) {
+                Ok(transport) => {
+                    let mut link = self.link.lock().unwrap();
+                    link.stats.connects += 1;
+                    link.last_used = Instant::now();
+                    // Another caller may have reconnected meanwhile
+                    let transport = link.transport.get_or_insert(transport);
+                    return Ok(Arc::clone(transport));
+                }
+                Err(e) => {
+                    self.link.lock().unwrap().stats.failed_connects += 1;
+                    attempt += 1;
+                    if attempt > policy.max_retries {
+                        return Err(QubeError::Network(format!(
+                            "Cannot connect to {}:{} after {} attempts: {}",
+                            self.config.host, self.config.port, attempt, e
+                        )));
+                    }
+                    tracing::warn!(
+                        "Connect to {}:{} failed, retrying: {}",
+                        self.config.host,
+                        self.config.port,
+                        e
+                    );
+                    tokio::time::sleep(policy.backoff(attempt)).await;
+                }
+            }
+        }
+    }
+
+    fn record_keepalive_failure(&self, transport: &Arc<dyn Transport>, error: &QubeError) {
+        tracing::warn!("Keepalive ping failed: {}", error);
+        self.link.lock().unwrap().stats.keepalive_failures += 1;
+        self.disconnect(transport);
+    }
+
+    /// Drop `transport` if it is still the open one
+    fn disconnect(&self, transport: &Arc<dyn Transport>) {
+        let mut link = self.link.lock().unwrap();
+        if link
+            .transport
+            .as_ref()
+            .is_some_and(|open| Arc::ptr_eq(open, transport))
+        {
+            link.transport = None;
+            link.stats.disconnects += 1;
+        }
+    }
+}
+
+impl std::fmt::Debug for DriverConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DriverConnection")
+            .field("host", &self.config.host)
+            .field("port", &self.config.port)
+            .field("connected", &self.is_connected())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Whether an error means the link itself is broken
+fn is_link_error(error: &QubeError) -> bool {
+    matches!(error, QubeError::Network(_) | QubeError::Io(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+    /// Connects to an embedded engine. Bumping `generation` breaks every
+    /// transport opened so far; `refusals` connect attempts fail first.
+    struct FlakyConnector {
+        inner: EmbeddedConnector,
+        generation: Arc<AtomicU64>,
+        refusals: AtomicU32,
+    }
+
+    struct FlakyTransport {
+        inner: Arc<dyn Transport>,
+        opened_in: u64,
+        generation: Arc<AtomicU64>,
+    }
+
+    impl FlakyTransport {
+        fn check(&self) -> QubeResult<()> {
+            if self.generation.load(Ordering::SeqCst) == self.opened_in {
+                Ok(())
+            } else {
+                Err(QubeError::Network("connection reset".to_string()))
+            }
+        }
+    }
+
+    impl Transport for FlakyTransport {
+        fn execute(&self, statement: &Statement) -> QubeResult<QueryResult> {
+            self.check()?;
+            self.inner.execute(statement)
+        }
+
+        fn ping(&self) -> QubeResult<()> {
+            self.check()
+        }
+    }
+
+    impl Connector for FlakyConnector {
+        fn connect(&self, config: &DriverConfig) -> QubeResult<Arc<dyn Transport>> {
+            let refused = self
+                .refusals
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if refused.is_ok() {
+                return Err(QubeError::Network("connection refused".to_string()));
+            }
+            Ok(Arc::new(FlakyTransport {
+                inner: self.inner.connect(config)?,
+                opened_in: self.generation.load(Ordering::SeqCst),
+                generation: Arc::clone(&self.generation),
+            }))
+        }
+    }
+
+    /// A connection to a fresh engine holding table `t`, the handle that
+    /// breaks its links, and the engine
+    fn flaky(
+        keepalive_interval: Option<Duration>,
+        refusals: u32,
+    ) -> (DriverConnection, Arc<AtomicU64>, Arc<QueryEngine>) {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script("CREATE TABLE t (id INT PRIMARY KEY)")
+            .unwrap();
+        let generation = Arc::new(AtomicU64::new(0));
+        let connector = FlakyConnector {
+            inner: EmbeddedConnector::new(Arc::clone(&engine)),
+            generation: Arc::clone(&generation),
+            refusals: AtomicU32::new(refusals),
+        };
+        let config = DriverConfig {
+            keepalive_interval,
+            reconnect: ReconnectPolicy {
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            },
+            ..DriverConfig::default()
+        };
+        let connection = DriverConnection::new(config, Arc::new(connector));
+        (connection, generation, engine)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = ReconnectPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+        };
+        let waits: Vec<_> = (1..=4)
+            .map(|attempt| policy.backoff(attempt).as_millis())
+            .collect();
+        assert_eq!(waits, [100, 200, 350, 350]);
+        assert_eq!(policy.backoff(1000), policy.max_backoff);
+        assert_eq!(ReconnectPolicy::disabled().max_retries, 0);
+    }
+
+    #[tokio::test]
+    async fn reads_are_retried_on_a_new_link_and_writes_are_not() {
+        let (connection, generation, engine) = flaky(None, 0);
+        let select = engine.parse_sql("SELECT * FROM t").unwrap();
+        let insert = engine.parse_sql("INSERT INTO t VALUES (1)").unwrap();
+        assert!(!connection.is_connected());
+        connection.execute(&insert).await.unwrap();
+        assert!(connection.is_connected());
+
+        generation.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(connection.execute(&select).await.unwrap().rows.len(), 1);
+        let stats = connection.stats();
+        assert_eq!(
+            (stats.connects, stats.disconnects, stats.retries),
+            (2, 1, 1)
+        );
+
+        generation.fetch_add(1, Ordering::SeqCst);
+        let lost = connection.execute(&insert).await;
+        assert!(matches!(lost, Err(QubeError::Network(m)) if m.contains("was not retried")));
+        assert!(!connection.is_connected());
+        assert_eq!(connection.stats().retries, 1);
+        assert_eq!(connection.execute(&select).await.unwrap().rows.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn idle_links_are_pinged_and_replaced_when_broken() {
+        let (connection, generation, _) = flaky(Some(Duration::ZERO), 0);
+        connection.keepalive().await.unwrap();
+        connection.keepalive().await.unwrap();
+        assert_eq!(connection.stats().connects, 1);
+
+        generation.fetch_add(1, Ordering::SeqCst);
+        connection.keepalive().await.unwrap();
+        let stats = connection.stats();
+        assert_eq!(
+            (stats.connects, stats.keepalive_failures, stats.disconnects),
+            (2, 1, 1)
+        );
+
+        generation.fetch_add(1, Ordering::SeqCst);
+        connection.ping().await.unwrap();
+        assert_eq!(connection.stats().keepalive_failures, 2);
+    }
+
+    #[tokio::test]
+    async fn connecting_gives_up_once_the_retries_run_out() {
+        let (connection, _, _) = flaky(None, 2);
+        connection.ping().await.unwrap();
+        assert_eq!(connection.stats().failed_connects, 2);
+
+        let (connection, _, _) = flaky(None, 3);
+        let refused = connection.ping().await;
+        let expected = "Cannot connect to localhost:8080 after 3 attempts";
+        assert!(matches!(refused, Err(QubeError::Network(m)) if m.starts_with(expected)));
+        assert!(!connection.is_connected());
+    }
+}
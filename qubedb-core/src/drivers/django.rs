@@ -38,8 +38,8 @@ impl DjangoBackend {
 
         // Convert result to Django format
         Ok(DjangoResult {
+            count: result.row_count(),
             objects: result.rows,
-            count: result.affected_rows,
         })
     }
 
@@ -7,6 +7,7 @@ use crate::error::QubeResult;
 use crate::drivers::DriverConfig;
 use crate::query::QueryEngine;
 use crate::storage::StorageEngine;
+use crate::types::ResultKind;
 use std::collections::HashMap;
 
 /// JDBC connection for QubeDB
@@ -69,13 +70,14 @@ impl<'a> JDBCPreparedStatement<'a> {
         let result = self.connection.query_engine.execute_sql(&self.sql).await?;
         
         Ok(JDBCResultSet {
+            kind: result.kind,
             columns: result.columns,
             rows: result.rows,
             current_row: 0,
         })
     }
     
-    /// Execute update (INSERT, UPDATE, DELETE)
+    /// Execute update (INSERT, UPDATE, DELETE), returning the number of rows changed
     pub async fn execute_update(&self, _params: &[String]) -> QubeResult<i32> {
         let result = self.connection.query_engine.execute_sql(&self.sql).await?;
         Ok(result.affected_rows as i32)
@@ -84,13 +86,20 @@ impl<'a> JDBCPreparedStatement<'a> {
 
 /// JDBC result set
 pub struct JDBCResultSet {
+    kind: ResultKind,
     columns: Vec<String>,
     rows: Vec<HashMap<String, crate::types::Value>>,
     current_row: usize,
 }
 
 impl JDBCResultSet {
-    /// Move to next row
+    /// Whether the statement produced a result set (as opposed to an update count)
+    pub fn is_result_set(&self) -> bool {
+        self.kind == ResultKind::Rows
+    }
+    
+    /// Move to next row, like JDBC's `ResultSet.next()`
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> bool {
         if self.current_row < self.rows.len() {
             self.current_row += 1;
@@ -8,6 +8,7 @@
 //! - Go - database/sql driver
 //! - Rust - Native driver
 
+pub mod connection;
 pub mod django;
 pub mod go;
 pub mod jdbc;
@@ -15,6 +16,9 @@ pub mod nodejs;
 pub mod pdo;
 pub mod rust;
 
+use connection::{ReconnectPolicy, DEFAULT_KEEPALIVE_INTERVAL};
+use std::time::Duration;
+
 /// Driver configuration
 #[derive(Debug, Clone)]
 pub struct DriverConfig {
@@ -25,6 +29,10 @@ pub struct DriverConfig {
     pub password: String,
     pub ssl: bool,
     pub timeout: u64,
+    /// Ping the link before use once it has been idle this long; `None`
+    /// never pings
+    pub keepalive_interval: Option<Duration>,
+    pub reconnect: ReconnectPolicy,
 }
 
 impl Default for DriverConfig {
@@ -37,6 +45,8 @@ impl Default for DriverConfig {
             password: "".to_string(),
             ssl: false,
             timeout: 30,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            reconnect: ReconnectPolicy::default(),
         }
     }
 }
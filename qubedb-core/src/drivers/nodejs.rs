@@ -33,8 +33,8 @@ impl NodeJSConnection {
         let result = self.query_engine.execute_sql(sql).await?;
         
         Ok(NodeJSResult {
+            row_count: result.row_count(),
             rows: result.rows,
-            row_count: result.affected_rows,
         })
     }
 }
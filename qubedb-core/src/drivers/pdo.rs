@@ -7,6 +7,7 @@ use crate::drivers::DriverConfig;
 use crate::error::{QubeError, QubeResult};
 use crate::query::QueryEngine;
 use crate::storage::StorageEngine;
+use crate::types::ResultKind;
 use std::collections::HashMap;
 
 /// PDO-compatible connection for QubeDB
@@ -47,6 +48,7 @@ impl PDOConnection {
         let result = self.query_engine.execute_sql(sql).await?;
 
         Ok(PDOResult {
+            kind: result.kind,
             rows_affected: result.affected_rows,
             columns: result.columns,
             rows: result.rows,
@@ -75,6 +77,8 @@ impl PDOConnection {
 /// PDO result set
 #[derive(Debug)]
 pub struct PDOResult {
+    pub kind: ResultKind,
+    /// Rows changed by INSERT/UPDATE/DELETE; 0 for result sets
     pub rows_affected: usize,
     pub columns: Vec<String>,
     pub rows: Vec<HashMap<String, crate::types::Value>>,
@@ -3,17 +3,21 @@
 //! This module provides a native Rust driver for QubeDB
 //! that can be used directly in Rust applications.
 
-use crate::error::QubeResult;
+use crate::error::{QubeError, QubeResult};
+use crate::drivers::connection::{Connector, DriverConnection, EmbeddedConnector};
 use crate::drivers::DriverConfig;
+use crate::prepared::PreparedStatement;
 use crate::query::QueryEngine;
 use crate::storage::StorageEngine;
+use crate::types::{QueryResult, Row, Value};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Rust native connection for QubeDB
 pub struct RustConnection {
-    #[allow(dead_code)]
-    config: DriverConfig,
-    query_engine: QueryEngine,
+    /// Parses statements before they are sent
+    query_engine: Arc<QueryEngine>,
+    connection: DriverConnection,
     #[allow(dead_code)]
     storage_engine: StorageEngine,
 }
@@ -21,22 +25,67 @@ pub struct RustConnection {
 impl RustConnection {
     /// Create a new Rust connection
     pub fn new(config: DriverConfig) -> Self {
+        let query_engine = Arc::new(QueryEngine::new());
+        let connector = Arc::new(EmbeddedConnector::new(Arc::clone(&query_engine)));
+        Self::open(config, query_engine, connector)
+    }
+    
+    /// Create a connection whose link is opened by `connector`. The link
+    /// is kept alive and reopened as `config` describes.
+    pub fn with_connector(config: DriverConfig, connector: Arc<dyn Connector>) -> Self {
+        Self::open(config, Arc::new(QueryEngine::new()), connector)
+    }
+    
+    fn open(config: DriverConfig, query_engine: Arc<QueryEngine>, connector: Arc<dyn Connector>) -> Self {
         RustConnection {
-            config,
-            query_engine: QueryEngine::new(),
+            query_engine,
+            connection: DriverConnection::new(config, connector),
             storage_engine: StorageEngine::new("./data").unwrap(),
         }
     }
     
+    /// The underlying link, for its state and statistics
+    pub fn connection(&self) -> &DriverConnection {
+        &self.connection
+    }
+    
+    /// Check the database is reachable, reconnecting if the link is broken
+    pub async fn ping(&self) -> QubeResult<()> {
+        self.connection.ping().await
+    }
+    
     /// Execute a query
     pub async fn query(&self, sql: &str) -> QubeResult<RustResult> {
-        let result = self.query_engine.execute_sql(sql).await?;
+        let statement = self.query_engine.parse_sql(sql)?;
+        let result = self.connection.execute(&statement).await?;
         
         Ok(RustResult {
             rows: result.rows,
             affected_rows: result.affected_rows,
         })
     }
+    
+    /// Prepare a statement with `?` or `$n` placeholders for repeated
+    /// execution
+    ///
+    /// ```ignore
+    /// let adults = conn.prepare("SELECT * FROM users WHERE age > ?")?;
+    /// for row in adults.bind(25i32).query().await? {
+    ///     let name: String = row.get("name")?;
+    /// }
+    /// ```
+    pub fn prepare(&self, sql: &str) -> QubeResult<RustStatement<'_>> {
+        let statement = self.query_engine.parse_sql(sql)?;
+        Ok(RustStatement {
+            connection: self,
+            prepared: PreparedStatement::new(sql, statement)?,
+        })
+    }
+    
+    async fn execute_prepared(&self, prepared: &PreparedStatement, params: &[Value]) -> QubeResult<QueryResult> {
+        let statement = prepared.bind(params)?;
+        self.connection.execute(&statement).await
+    }
 }
 
 /// Rust result
@@ -45,3 +94,306 @@ pub struct RustResult {
     pub rows: Vec<HashMap<String, crate::types::Value>>,
     pub affected_rows: usize,
 }
+
+/// A statement prepared on a `RustConnection`
+///
+/// The SQL is parsed once; each `bind` starts a fresh set of parameters,
+/// so the statement can be run any number of times with different values.
+#[derive(Clone)]
+pub struct RustStatement<'c> {
+    connection: &'c RustConnection,
+    prepared: PreparedStatement,
+}
+
+impl<'c> RustStatement<'c> {
+    /// The SQL text this statement was prepared from
+    pub fn sql(&self) -> &str {
+        self.prepared.sql()
+    }
+    
+    /// Number of parameters the statement expects
+    pub fn param_count(&self) -> usize {
+        self.prepared.param_count()
+    }
+    
+    /// Start an execution, binding `value` to the first parameter
+    pub fn bind(&self, value: impl Into<Value>) -> RustQuery<'_, 'c> {
+        RustQuery {
+            statement: self,
+            params: vec![value.into()],
+        }
+    }
+    
+    /// Run a statement that takes no parameters, returning its rows
+    pub async fn query(&self) -> QubeResult<Vec<RustRow>> {
+        self.params().query().await
+    }
+    
+    /// Run a statement that takes no parameters, returning the number of
+    /// rows it changed
+    pub async fn execute(&self) -> QubeResult<usize> {
+        self.params().execute().await
+    }
+    
+    fn params(&self) -> RustQuery<'_, 'c> {
+        RustQuery {
+            statement: self,
+            params: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for RustStatement<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RustStatement")
+            .field("prepared", &self.prepared)
+            .finish()
+    }
+}
+
+/// One execution of a `RustStatement`, collecting parameter values
+#[derive(Debug, Clone)]
+pub struct RustQuery<'s, 'c> {
+    statement: &'s RustStatement<'c>,
+    params: Vec<Value>,
+}
+
+impl RustQuery<'_, '_> {
+    /// Bind `value` to the next parameter
+    pub fn bind(mut self, value: impl Into<Value>) -> Self {
+        self.params.push(value.into());
+        self
+    }
+    
+    /// Run the statement, returning its rows. Fails if the number of bound
+    /// values does not match the statement's parameters.
+    pub async fn query(self) -> QubeResult<Vec<RustRow>> {
+        let result = self.run().await?;
+        let columns: Arc<[String]> = result.columns.into();
+        Ok(result
+            .rows
+            .into_iter()
+            .map(|values| RustRow {
+                columns: Arc::clone(&columns),
+                values,
+            })
+            .collect())
+    }
+    
+    /// Run the statement, returning the first row, or `None` if there is
+    /// none
+    pub async fn query_optional(self) -> QubeResult<Option<RustRow>> {
+        Ok(self.query().await?.into_iter().next())
+    }
+    
+    /// Run the statement, returning the number of rows it changed
+    pub async fn execute(self) -> QubeResult<usize> {
+        Ok(self.run().await?.affected_rows)
+    }
+    
+    async fn run(&self) -> QubeResult<QueryResult> {
+        let statement = self.statement;
+        statement
+            .connection
+            .execute_prepared(&statement.prepared, &self.params)
+            .await
+    }
+}
+
+/// A row returned by a prepared statement, read with typed getters
+#[derive(Debug, Clone)]
+pub struct RustRow {
+    columns: Arc<[String]>,
+    values: Row,
+}
+
+impl RustRow {
+    /// Column names, in result order
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    
+    /// The raw value of a column
+    pub fn value(&self, column: &str) -> Option<&Value> {
+        self.values.get(column)
+    }
+    
+    /// Read a column as `T`, e.g. `row.get::<i32>("age")`. Fails if the
+    /// column is missing or its value does not convert to `T`; read
+    /// nullable columns as `Option<T>`.
+    pub fn get<T: FromValue>(&self, column: &str) -> QubeResult<T> {
+        let value = self
+            .values
+            .get(column)
+            .ok_or_else(|| QubeError::ColumnNotFound(column.to_string()))?;
+        T::from_value(value).map_err(|e| QubeError::Other(format!("Column '{}': {}", column, e)))
+    }
+    
+    /// The underlying row
+    pub fn into_row(self) -> Row {
+        self.values
+    }
+}
+
+/// Conversion from a QubeDB value into a Rust type, used by `RustRow::get`
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> QubeResult<Self>;
+}
+
+fn mismatch<T>(value: &Value) -> QubeError {
+    QubeError::Other(format!(
+        "Cannot convert {:?} to {}",
+        value,
+        std::any::type_name::<T>()
+    ))
+}
+
+macro_rules! from_integer {
+    ($($type:ty),*) => {
+        $(
+            impl FromValue for $type {
+                fn from_value(value: &Value) -> QubeResult<Self> {
+                    let v = match value {
+                        Value::UInt64(v) => <$type>::try_from(*v).ok(),
+                        Value::Timestamp(_) => None,
+                        other => other.as_i64().and_then(|v| <$type>::try_from(v).ok()),
+                    };
+                    v.ok_or_else(|| mismatch::<$type>(value))
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i8, i16, i32, i64, u8, u16, u32, u64);
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Timestamp(_) => Err(mismatch::<f64>(value)),
+            other => other.as_f64().ok_or_else(|| mismatch::<f64>(value)),
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Float32(v) => Ok(*v),
+            other => f64::from_value(other).map(|v| v as f32),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Boolean(v) => Ok(*v),
+            other => Err(mismatch::<bool>(other)),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::String(v) => Ok(v.clone()),
+            other => Err(mismatch::<String>(other)),
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Binary(v) => Ok(v.clone()),
+            other => Err(mismatch::<Vec<u8>>(other)),
+        }
+    }
+}
+
+impl FromValue for Vec<f32> {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Vector(v) => Ok(v.clone()),
+            other => Err(mismatch::<Vec<f32>>(other)),
+        }
+    }
+}
+
+impl FromValue for serde_json::Value {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Json(v) => Ok(v.clone()),
+            other => Err(mismatch::<serde_json::Value>(other)),
+        }
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> QubeResult<Self> {
+        match value {
+            Value::Null => Ok(None),
+            other => T::from_value(other).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prepared_statements_run_with_fresh_parameters() {
+        let conn = RustConnection::new(DriverConfig::default());
+        conn.query("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT, email TEXT)")
+            .await
+            .unwrap();
+
+        let insert = conn.prepare("INSERT INTO users VALUES (?, ?, ?, ?)").unwrap();
+        assert_eq!(insert.param_count(), 4);
+        for (id, name, age) in [(1, "Ada", 36), (2, "Alan", 41), (3, "Grace", 22)] {
+            let email = (id != 3).then(|| format!("{}@example.com", name));
+            let query = insert.bind(id).bind(name).bind(age).bind(email);
+            assert_eq!(query.execute().await.unwrap(), 1);
+        }
+
+        let older = conn.prepare("SELECT * FROM users WHERE age > $1 ORDER BY id").unwrap();
+        let rows = older.bind(30i32).query().await.unwrap();
+        let names: Vec<String> = rows.iter().map(|row| row.get("name").unwrap()).collect();
+        assert_eq!(names, ["Ada", "Alan"]);
+        assert_eq!(rows[0].get::<i64>("age").unwrap(), 36);
+        assert_eq!(rows[0].get::<u8>("age").unwrap(), 36);
+        assert!(rows[0].get::<String>("age").is_err());
+        assert!(rows[0].get::<i32>("missing").is_err());
+
+        let by_id = conn.prepare("SELECT * FROM users WHERE id = ?").unwrap();
+        let grace = by_id.bind(3).query_optional().await.unwrap().unwrap();
+        assert_eq!(grace.get::<Option<String>>("email").unwrap(), None);
+        assert!(grace.get::<String>("email").is_err());
+        assert!(by_id.bind(9).query_optional().await.unwrap().is_none());
+
+        assert!(by_id.query().await.is_err());
+        assert!(by_id.bind(1).bind(2).query().await.is_err());
+    }
+
+    #[test]
+    fn values_convert_only_to_types_that_hold_them() {
+        assert_eq!(u16::from_value(&Value::Int64(300)).unwrap(), 300);
+        assert!(u8::from_value(&Value::Int64(300)).is_err());
+        assert!(i32::from_value(&Value::Int32(-1)).is_ok());
+        assert!(u32::from_value(&Value::Int32(-1)).is_err());
+        assert_eq!(f64::from_value(&Value::Int32(2)).unwrap(), 2.0);
+        assert_eq!(f32::from_value(&Value::Float32(1.5)).unwrap(), 1.5);
+        assert!(bool::from_value(&Value::Int32(1)).is_err());
+        assert_eq!(Vec::<f32>::from_value(&Value::Vector(vec![1.0])).unwrap(), [1.0]);
+        assert_eq!(Option::<i32>::from_value(&Value::Null).unwrap(), None);
+        assert!(i32::from_value(&Value::Null).is_err());
+    }
+}
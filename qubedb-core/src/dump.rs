@@ -0,0 +1,365 @@
+//! SQL dumps of a QubeDB catalog
+//!
+//! `dump` writes a script that rebuilds a catalog when run on an empty
+//! engine: a CREATE SCHEMA for each database besides the default one, then
+//! for each table its CREATE TABLE, its explicitly created indexes, INSERTs
+//! for its rows and an ANALYZE if it had statistics. Names are quoted, so
+//! their case survives the round trip. Generated columns are left out of
+//! the INSERTs; they are computed again as the rows go in.
+//!
+//! Materialized views come last, in the order they were created, as their
+//! CREATE MATERIALIZED VIEW and indexes; their rows are computed again
+//! when the script runs, as of the rows dumped before them.
+//!
+//! The write-ahead log is checkpointed to such a script by VACUUM, which
+//! replaces the history of every row with its current value.
+//!
+//! `insert_row`, `update_row` and `delete_row` write a single change to a
+//! row the same way, which is how the rows a transaction wrote are logged.
+
+use crate::catalog::{Catalog, TableData};
+use crate::expr;
+use crate::namespace;
+use crate::query::VIRTUAL_MARKER;
+use crate::types::{
+    Column, Constraint, ConstraintType, DefaultValue, Generated, Row, StorageLayout, Table, Value,
+};
+use crate::view::{View, ViewRefresh};
+use sqlparser::ast::Ident;
+
+/// Rows written per INSERT statement
+const INSERT_BATCH: usize = 100;
+
+/// A script that rebuilds `catalog`, one statement per line
+pub fn dump(catalog: &Catalog) -> String {
+    let mut out = String::new();
+    for database in catalog.list_databases() {
+        if !namespace::is_default(&database) {
+            out.push_str(&format!("CREATE SCHEMA {};\n", quote(&database)));
+        }
+    }
+    for table in catalog.list_tables() {
+        if catalog.view(&table.name).is_some() {
+            continue;
+        }
+        let Ok(data) = catalog.table_data(&table.name) else {
+            continue;
+        };
+        out.push_str(&create_table(table, data));
+        out.push_str(";\n");
+        for statement in create_indexes(table) {
+            out.push_str(&statement);
+            out.push_str(";\n");
+        }
+        for statement in inserts(table, data) {
+            out.push_str(&statement);
+            out.push_str(";\n");
+        }
+        if data.stats.is_some() {
+            out.push_str(&format!("ANALYZE TABLE {};\n", quote_name(&table.name)));
+        }
+    }
+    for view in catalog.views() {
+        out.push_str(&create_view(view));
+        out.push_str(";\n");
+        if let Ok(table) = catalog.get_table(&view.name) {
+            for statement in create_indexes(table) {
+                out.push_str(&statement);
+                out.push_str(";\n");
+            }
+        }
+    }
+    out
+}
+
+/// The CREATE VIEW or CREATE MATERIALIZED VIEW statement of `view`
+pub fn create_view(view: &View) -> String {
+    let kind = if view.is_materialized() {
+        "MATERIALIZED VIEW"
+    } else {
+        "VIEW"
+    };
+    let mut statement = format!("CREATE {} {}", kind, quote_name(&view.name));
+    if !view.columns.is_empty() {
+        let columns: Vec<String> = view.columns.iter().map(|c| c.value.clone()).collect();
+        statement.push_str(&format!(" ({})", quote_list(&columns)));
+    }
+    if let Some(refresh @ ViewRefresh::OnWrite) = view.materialized {
+        statement.push_str(&format!(" WITH (refresh = '{}')", refresh));
+    }
+    statement.push_str(&format!(" AS {}", view.query));
+    statement
+}
+
+/// The CREATE TABLE statement of `table`, carrying over its sequence and
+/// history retention
+pub fn create_table(table: &Table, data: &TableData) -> String {
+    let mut definitions: Vec<String> = table
+        .columns
+        .iter()
+        .map(|column| column_definition(table, column))
+        .collect();
+    for constraint in &table.constraints {
+        let columns = quote_list(&constraint.columns);
+        let definition = match &constraint.constraint_type {
+            ConstraintType::PrimaryKey => format!("PRIMARY KEY ({})", columns),
+            ConstraintType::Unique => format!("UNIQUE ({})", columns),
+            ConstraintType::ForeignKey {
+                referenced_table,
+                referenced_column,
+            } => format!(
+                "CONSTRAINT {} FOREIGN KEY ({}) REFERENCES {} ({})",
+                quote(&constraint.name),
+                columns,
+                quote_name(referenced_table),
+                quote(referenced_column)
+            ),
+            // Checks on a single column are written with the column
+            ConstraintType::Check { .. } if column_check(table, constraint).is_some() => continue,
+            ConstraintType::Check { expression } => format!(
+                "CONSTRAINT {} CHECK ({})",
+                quote(&constraint.name),
+                expression
+            ),
+        };
+        definitions.push(definition);
+    }
+
+    let mut options = Vec::new();
+    if let StorageLayout::Columnar = table.storage {
+        options.push("storage = 'columnar'".to_string());
+    }
+    let sequence = data.sequence();
+    if sequence > 0 {
+        options.push(format!("auto_increment = {}", sequence));
+    }
+    if let Some(retention) = data.own_history_retention() {
+        options.push(format!(
+            "history_retention = {}",
+            retention.duration.as_secs_f64()
+        ));
+        if let Some(max_versions) = retention.max_versions {
+            options.push(format!("history_versions = {}", max_versions));
+        }
+    }
+    let mut statement = format!(
+        "CREATE TABLE {} ({})",
+        quote_name(&table.name),
+        definitions.join(", ")
+    );
+    if !options.is_empty() {
+        statement.push_str(&format!(" WITH ({})", options.join(", ")));
+    }
+    statement
+}
+
+fn column_definition(table: &Table, column: &Column) -> String {
+    let mut definition = format!("{} {}", quote(&column.name), column.data_type);
+    if !column.nullable {
+        definition.push_str(" NOT NULL");
+    }
+    match &column.default_value {
+        Some(DefaultValue::Value(value)) => {
+            definition.push_str(&format!(" DEFAULT {}", expr::to_literal(value)))
+        }
+        Some(DefaultValue::CurrentTimestamp) => definition.push_str(" DEFAULT CURRENT_TIMESTAMP"),
+        Some(DefaultValue::CurrentDate) => definition.push_str(" DEFAULT CURRENT_DATE"),
+        Some(DefaultValue::CurrentTime) => definition.push_str(" DEFAULT CURRENT_TIME"),
+        None => {}
+    }
+    if column.auto_increment {
+        definition.push_str(" AUTO_INCREMENT");
+    }
+    match &column.generated {
+        Some(Generated::Stored(expression)) => {
+            definition.push_str(&format!(" GENERATED ALWAYS AS ({}) STORED", expression))
+        }
+        Some(Generated::Virtual(expression)) => definition.push_str(&format!(
+            " GENERATED ALWAYS AS ({}) COMMENT '{}'",
+            expression, VIRTUAL_MARKER
+        )),
+        None => {}
+    }
+    if let Some(schema) = &column.json_schema {
+        let schema = schema.schema().to_string().replace('\'', "''");
+        definition.push_str(&format!(
+            " CHECK (JSON_SCHEMA_VALID('{}', {}))",
+            schema,
+            quote(&column.name)
+        ));
+    }
+    for constraint in &table.constraints {
+        if column_check(table, constraint) == Some(column.name.as_str()) {
+            if let ConstraintType::Check { expression } = &constraint.constraint_type {
+                definition.push_str(&format!(" CHECK ({})", expression));
+            }
+        }
+    }
+    definition
+}
+
+/// The column a CHECK constraint was declared on, when it was declared on
+/// one and so got the name `build_table` gives such checks
+fn column_check<'a>(table: &Table, constraint: &'a Constraint) -> Option<&'a str> {
+    match constraint.columns.as_slice() {
+        [column] if constraint.name == format!("{}_{}_check", table.name, column) => Some(column),
+        _ => None,
+    }
+}
+
+/// CREATE INDEX statements for the indexes of `table` that no constraint
+/// creates
+pub fn create_indexes(table: &Table) -> Vec<String> {
+    table
+        .indexes
+        .iter()
+        .filter(|index| {
+            !table
+                .constraints
+                .iter()
+                .any(|constraint| constraint.name == index.name)
+        })
+        .map(|index| {
+            format!(
+                "CREATE {}INDEX {} ON {} USING {} ({})",
+                if index.unique { "UNIQUE " } else { "" },
+                quote_name(&index.name),
+                quote_name(&table.name),
+                index.index_type,
+                quote_list(&index.columns)
+            )
+        })
+        .collect()
+}
+
+/// INSERT statements holding every row of `table`, `INSERT_BATCH` at a time
+fn inserts(table: &Table, data: &TableData) -> Vec<String> {
+    let columns = stored_columns(table);
+    if columns.is_empty() {
+        return Vec::new();
+    }
+    let names: Vec<String> = columns.iter().map(|column| quote(&column.name)).collect();
+    let prefix = format!(
+        "INSERT INTO {} ({}) VALUES ",
+        quote_name(&table.name),
+        names.join(", ")
+    );
+
+    let rows: Vec<String> = data.rows().map(|(_, row)| values(&columns, &row)).collect();
+    rows.chunks(INSERT_BATCH)
+        .map(|batch| format!("{}{}", prefix, batch.join(", ")))
+        .collect()
+}
+
+/// The INSERT statement adding `row` to `table`
+pub fn insert_row(table: &Table, row: &Row) -> String {
+    let columns = stored_columns(table);
+    let names: Vec<String> = columns.iter().map(|column| quote(&column.name)).collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES {}",
+        quote_name(&table.name),
+        names.join(", "),
+        values(&columns, row)
+    )
+}
+
+/// The UPDATE statement turning the row `before` of `table` into `after`,
+/// if a unique key of `table` picks out `before`
+pub fn update_row(table: &Table, before: &Row, after: &Row) -> Option<String> {
+    let assignments: Vec<String> = stored_columns(table)
+        .into_iter()
+        .filter(|column| before.get(&column.name) != after.get(&column.name))
+        .map(|column| format!("{} = {}", quote(&column.name), literal(after, column)))
+        .collect();
+    if assignments.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "UPDATE {} SET {} WHERE {}",
+        quote_name(&table.name),
+        assignments.join(", "),
+        key_filter(table, before)?
+    ))
+}
+
+/// The DELETE statement removing the row `before` from `table`, if a
+/// unique key of `table` picks it out
+pub fn delete_row(table: &Table, before: &Row) -> Option<String> {
+    Some(format!(
+        "DELETE FROM {} WHERE {}",
+        quote_name(&table.name),
+        key_filter(table, before)?
+    ))
+}
+
+/// A condition matching only `row`, on the first unique index of `table`
+/// none of whose columns `row` holds NULL in
+fn key_filter(table: &Table, row: &Row) -> Option<String> {
+    table
+        .indexes
+        .iter()
+        .filter(|index| index.unique)
+        .find(|index| {
+            index
+                .columns
+                .iter()
+                .all(|column| row.get(column).is_some_and(|value| !value.is_null()))
+        })
+        .map(|index| {
+            index
+                .columns
+                .iter()
+                .map(|column| {
+                    format!(
+                        "{} = {}",
+                        quote(column),
+                        expr::to_literal(&row[column.as_str()])
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        })
+}
+
+/// The columns of `table` that are written rather than generated
+fn stored_columns(table: &Table) -> Vec<&Column> {
+    table
+        .columns
+        .iter()
+        .filter(|column| column.generated.is_none())
+        .collect()
+}
+
+/// The values `row` holds in `columns`, as a VALUES tuple
+fn values(columns: &[&Column], row: &Row) -> String {
+    let values: Vec<String> = columns
+        .iter()
+        .map(|column| literal(row, column).to_string())
+        .collect();
+    format!("({})", values.join(", "))
+}
+
+fn literal(row: &Row, column: &Column) -> sqlparser::ast::Value {
+    expr::to_literal(row.get(&column.name).unwrap_or(&Value::Null))
+}
+
+/// A single identifier, quoted
+fn quote(name: &str) -> String {
+    Ident::with_quote('"', name).to_string()
+}
+
+/// A catalog name, with its database part quoted separately
+fn quote_name(name: &str) -> String {
+    match name.split_once('.') {
+        Some((database, name)) => format!("{}.{}", quote(database), quote(name)),
+        None => quote(name),
+    }
+}
+
+fn quote_list(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| quote(name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -3,44 +3,336 @@
 //! This module provides an embedded version of QubeDB that can be used
 //! like SQLite - as a library embedded in applications.
 
-use crate::error::QubeResult;
-// use crate::storage::StorageEngine; // Temporarily disabled for real database implementation
-use crate::query::QueryEngine;
-use crate::types::{QueryResult, Row};
-use crate::logging::{LogCategory, log_query, log_table, log_vector, log_graph, log_performance};
-use std::path::Path;
-use std::time::Instant;
+use crate::audit::AuditLog;
+use crate::changes::{ChangeListener, ChangeListenerConfig};
+use crate::error::{QubeError, QubeResult};
+use crate::graph::{self, Edge, GraphEngine, ImportSummary, Node};
+use crate::hybrid::{Document, DocumentStore, Embedder, HybridHit, HybridQuery};
+use crate::index::{DistanceMetric, VectorIndex};
+use crate::kv::KvNamespace;
+use crate::metrics;
+use crate::storage::StorageEngine;
+use crate::pagination::Page;
+use crate::query::{QueryEngine, QueryOptions};
+use crate::quota::{Quota, QuotaUsage};
+use crate::scheduler::MaintenanceScheduler;
+use crate::security::{Permission, SecurityContext};
+use crate::session::Session;
+use crate::types::{QueryResult, Row, Table, Value};
+use crate::vector_cache::{EvictionPolicy, VectorCacheConfig};
+use crate::wal::WalConfig;
+use crate::logging::{self, LogCategory, log_query, log_table, log_vector, log_graph, log_performance};
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Directory (inside the vector path) holding persisted vector indexes
+const VECTOR_INDEX_DIR: &str = "vector_indexes";
+
+/// File extension of a persisted vector index
+const VECTOR_INDEX_EXTENSION: &str = "qvi";
+
+/// File extension of the scratch file a vector collection spills to
+const VECTOR_SPILL_EXTENSION: &str = "spill";
+
+/// File (inside the relational path) holding the key-value namespace
+const KV_FILE: &str = "kv.qkv";
+
+/// File (inside the relational path) holding the write-ahead log the SQL
+/// tables are rebuilt from
+const TABLE_WAL_FILE: &str = "tables.wal";
+
+/// File (inside the graph path) holding the graphs
+const GRAPH_FILE: &str = "graphs.qgr";
+
+/// File (inside the vector path) holding the document collections
+const DOCUMENT_FILE: &str = "documents.qdc";
+
+/// File (inside the database path) holding the audit trail
+const AUDIT_FILE: &str = "audit.jsonl";
+
+/// Storage table the key-value namespace is written through to
+const KV_TABLE: &str = "__kv__";
+
+/// How often stale table statistics are re-collected in the background
+const STATS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often every index is checked against its table in the background
+const INDEX_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Paths of the databases open in this process, so `delete_database` can
+/// refuse to remove files still in use
+static OPEN_DATABASES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Where an embedded database keeps the files of each data model. A path
+/// left unset falls back to the database directory, so by default
+/// everything lives together; setting `vector_path` to a directory on a
+/// fast disk moves the vector indexes, their spill files and the document
+/// collections there. The audit trail always stays in the database
+/// directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// Tables, and the key-value namespace written through to them
+    pub relational_path: Option<PathBuf>,
+    /// Vector indexes and document collections
+    pub vector_path: Option<PathBuf>,
+    pub graph_path: Option<PathBuf>,
+}
+
+impl StorageConfig {
+    pub fn with_relational_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.relational_path = Some(path.into());
+        self
+    }
+    
+    pub fn with_vector_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.vector_path = Some(path.into());
+        self
+    }
+    
+    pub fn with_graph_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.graph_path = Some(path.into());
+        self
+    }
+    
+    /// Directory holding relational data for the database at `root`
+    pub fn relational_dir(&self, root: &Path) -> PathBuf {
+        self.relational_path.clone().unwrap_or_else(|| root.to_path_buf())
+    }
+    
+    /// Directory holding vector data for the database at `root`
+    pub fn vector_dir(&self, root: &Path) -> PathBuf {
+        self.vector_path.clone().unwrap_or_else(|| root.to_path_buf())
+    }
+    
+    /// Directory holding graph data for the database at `root`
+    pub fn graph_dir(&self, root: &Path) -> PathBuf {
+        self.graph_path.clone().unwrap_or_else(|| root.to_path_buf())
+    }
+}
 
 /// Embedded QubeDB instance
 pub struct EmbeddedQubeDB {
     storage: StorageEngine,
-    query_engine: QueryEngine,
+    query_engine: Arc<QueryEngine>,
+    vector_indexes: HashMap<String, VectorIndex>,
+    kv: KvNamespace,
+    graphs: GraphEngine,
+    documents: DocumentStore,
+    embedder: Option<Arc<dyn Embedder>>,
+    maintenance: MaintenanceScheduler,
+    audit: Option<Arc<AuditLog>>,
     path: String,
+    storage_config: StorageConfig,
+    read_only: bool,
 }
 
 impl EmbeddedQubeDB {
     /// Open or create an embedded QubeDB database
     pub fn open<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        Self::open_with_mode(path, false, StorageConfig::default())
+    }
+    
+    /// Open an existing database for reading only. Every method that would
+    /// modify it fails with `PermissionDenied`, no background maintenance
+    /// runs and nothing is written back when it is dropped, so any number of
+    /// readers can inspect a database without disturbing it.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        Self::open_with_mode(path, true, StorageConfig::default())
+    }
+    
+    /// Open or create a database whose data models keep their files where
+    /// `storage_config` says. The same configuration must be given every
+    /// time the database is opened.
+    pub fn open_with_storage<P: AsRef<Path>>(path: P, storage_config: StorageConfig) -> QubeResult<Self> {
+        Self::open_with_mode(path, false, storage_config)
+    }
+    
+    fn open_with_mode<P: AsRef<Path>>(path: P, read_only: bool, storage_config: StorageConfig) -> QubeResult<Self> {
         let path_str = path.as_ref().to_string_lossy().to_string();
+        if read_only && !path.as_ref().is_dir() {
+            return Err(QubeError::DatabaseNotFound(path_str));
+        }
+        let relational_dir = storage_config.relational_dir(path.as_ref());
+        let vector_dir = storage_config.vector_dir(path.as_ref());
+        let graph_dir = storage_config.graph_dir(path.as_ref());
+        if !read_only {
+            for dir in [path.as_ref(), relational_dir.as_path(), vector_dir.as_path(), graph_dir.as_path()] {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        
+        let storage = StorageEngine::new(&relational_dir)?;
+        let wal_path = relational_dir.join(TABLE_WAL_FILE);
+        let query_engine = Arc::new(if read_only {
+            QueryEngine::open_read_only(&wal_path)?
+        } else {
+            QueryEngine::open(&wal_path, WalConfig::default())?
+        });
+        let vector_indexes = load_vector_indexes(&vector_dir.join(VECTOR_INDEX_DIR))?;
+        
+        let kv_path = relational_dir.join(KV_FILE);
+        let kv = if kv_path.exists() {
+            KvNamespace::load(&kv_path)?
+        } else {
+            KvNamespace::new()
+        };
+        
+        let graph_path = graph_dir.join(GRAPH_FILE);
+        let graphs = if graph_path.exists() {
+            GraphEngine::load(&graph_path)?
+        } else {
+            GraphEngine::new()
+        };
         
-        let storage = StorageEngine::new(path.as_ref())?;
-        let query_engine = QueryEngine::new();
+        let document_path = vector_dir.join(DOCUMENT_FILE);
+        let documents = if document_path.exists() {
+            DocumentStore::load(&document_path)?
+        } else {
+            DocumentStore::new()
+        };
+        
+        // Statistics refreshes write to the catalog, so a read-only database
+        // leaves the scheduler idle
+        let maintenance = MaintenanceScheduler::new();
+        if !read_only {
+            register_maintenance(&maintenance, &query_engine)?;
+            maintenance.start()?;
+        }
+        
+        let audit = if read_only {
+            None
+        } else {
+            Some(Arc::new(AuditLog::open(path.as_ref().join(AUDIT_FILE))?))
+        };
+        
+        if let Ok(canonical) = path.as_ref().canonicalize() {
+            OPEN_DATABASES.lock().unwrap().push(canonical);
+        }
         
         Ok(EmbeddedQubeDB {
             storage,
             query_engine,
+            vector_indexes,
+            kv,
+            graphs,
+            documents,
+            embedder: None,
+            maintenance,
+            audit,
             path: path_str,
+            storage_config,
+            read_only,
         })
     }
     
+    /// Whether the database was opened with `open_read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    
+    /// Fail with `PermissionDenied` if the database is read-only
+    fn check_writable(&self) -> QubeResult<()> {
+        if self.read_only {
+            Err(QubeError::PermissionDenied(format!("database {} is open read-only", self.path)))
+        } else {
+            Ok(())
+        }
+    }
+    
+    /// Fail with `PermissionDenied` if the database is read-only and `sql`
+    /// has a statement needing more than read permission
+    fn check_sql(&self, sql: &str) -> QubeResult<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let statements = self.query_engine.parse_script(sql)?;
+        if statements.iter().any(|s| Permission::required_for(s) != Permission::Read) {
+            return self.check_writable();
+        }
+        Ok(())
+    }
+    
+    /// The scheduler running this database's background maintenance.
+    /// Further periodic tasks can be registered on it.
+    pub fn maintenance(&self) -> &MaintenanceScheduler {
+        &self.maintenance
+    }
+    
+    /// Open a session running queries as `context`. Each session has its own
+    /// transaction and variables, so concurrent clients stay isolated. On a
+    /// read-only database the session keeps only the read permission.
+    pub fn session(&self, mut context: SecurityContext) -> Session {
+        if self.read_only {
+            context.permissions.retain(|p| *p == Permission::Read);
+        }
+        let session = Session::new(Arc::clone(&self.query_engine), context);
+        match &self.audit {
+            Some(audit) => {
+                let context = session.security_context();
+                if let Err(e) = audit.record_login(&context.user, context.request_id.clone(), true, "session opened") {
+                    crate::logging::log_error(LogCategory::Security, "Failed to write audit entry", &e, None).ok();
+                }
+                session.with_audit(Arc::clone(audit))
+            }
+            None => session,
+        }
+    }
+    
+    /// Run `body` in an optimistic transaction on a fresh session with
+    /// full access, retrying it up to `max_retries` times when its commit
+    /// conflicts with another transaction's. See `Session::transaction_retry`.
+    pub fn transaction_retry<T>(&self, body: impl FnMut(&mut Session) -> QubeResult<T>, max_retries: usize) -> QubeResult<T> {
+        let context = if self.read_only {
+            SecurityContext::read_only("embedded")
+        } else {
+            SecurityContext::admin("embedded")
+        };
+        let mut session = Session::new(Arc::clone(&self.query_engine), context);
+        if let Some(audit) = &self.audit {
+            session = session.with_audit(Arc::clone(audit));
+        }
+        session.transaction_retry(body, max_retries)
+    }
+    
+    /// The audit trail recording logins, permission denials and schema
+    /// changes made through this database's sessions. A read-only database
+    /// has none.
+    pub fn audit_log(&self) -> Option<&Arc<AuditLog>> {
+        self.audit.as_ref()
+    }
+    
+    /// Stream an event for every table row written through SQL, buffered
+    /// according to `config`
+    pub fn subscribe_changes(&self, config: ChangeListenerConfig) -> ChangeListener {
+        self.query_engine.subscribe_changes(config)
+    }
+    
     /// Execute a SQL query
     pub async fn execute(&self, sql: &str) -> QubeResult<QueryResult> {
+        self.execute_with_options(sql, QueryOptions::default()).await
+    }
+    
+    /// Execute a SQL query with an optional timeout and/or cancellation
+    /// token. Everything logged for the query shares one correlation id.
+    pub async fn execute_with_options(&self, sql: &str, options: QueryOptions) -> QubeResult<QueryResult> {
+        logging::traced(self.run_with_options(sql, options)).await
+    }
+    
+    async fn run_with_options(&self, sql: &str, options: QueryOptions) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log query start
         log_query(sql, true, 0).ok();
         
-        let result = match self.query_engine.execute_sql(sql).await {
+        let outcome = match self.check_sql(sql) {
+            Ok(()) => self.query_engine.execute_sql_with_options(sql, &options),
+            Err(e) => Err(e),
+        };
+        metrics::record_latency(metrics::sql_operation(sql), start.elapsed());
+        let result = match outcome {
             Ok(result) => {
                 let duration = start.elapsed();
                 let duration_ms = duration.as_millis() as u64;
@@ -70,8 +362,93 @@ impl EmbeddedQubeDB {
         result
     }
     
+    /// Run a SELECT over one table a page at a time, starting after
+    /// `cursor`, the previous page's `next_cursor`
+    pub fn execute_page(&self, sql: &str, page_size: usize, cursor: Option<&str>) -> QubeResult<Page> {
+        logging::traced_sync(|| {
+            let start = Instant::now();
+            let result = self.query_engine.execute_page(sql, page_size, cursor);
+            
+            let duration_ms = start.elapsed().as_millis() as u64;
+            log_query(sql, result.is_ok(), duration_ms).ok();
+            if let Err(e) = &result {
+                crate::logging::log_error(LogCategory::Query, &format!("Query failed: {}", sql), e, Some(format!("Duration: {}ms", duration_ms))).ok();
+            }
+            
+            result
+        })
+    }
+    
+    /// Execute a script of `;`-separated statements as one transaction,
+    /// returning each statement's result. If any statement fails nothing is
+    /// applied.
+    pub fn execute_script(&self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        logging::traced_sync(|| {
+            let start = Instant::now();
+            let result = self.check_sql(sql).and_then(|()| self.query_engine.execute_script(sql));
+            
+            let duration_ms = start.elapsed().as_millis() as u64;
+            log_query(sql, result.is_ok(), duration_ms).ok();
+            if let Err(e) = &result {
+                crate::logging::log_error(LogCategory::Query, "Script failed and was rolled back", e, Some(format!("Duration: {}ms", duration_ms))).ok();
+            }
+            
+            result
+        })
+    }
+    
+    /// Create a table from a definition built with `TableBuilder`
+    pub fn create_table(&self, table: Table) -> QubeResult<()> {
+        let name = table.name.clone();
+        let result = self.check_writable().and_then(|()| self.query_engine.create_table(table));
+        log_table("CREATE", &name, result.is_ok()).ok();
+        result
+    }
+    
+    /// List the names of all tables, sorted
+    pub fn list_tables(&self) -> Vec<String> {
+        self.query_engine.list_tables()
+    }
+    
+    /// Get the definition of a table
+    pub fn describe_table(&self, name: &str) -> QubeResult<Table> {
+        self.query_engine.describe_table(name)
+    }
+    
+    /// List the names of all databases, the default one first
+    pub fn list_databases(&self) -> Vec<String> {
+        self.query_engine.list_databases()
+    }
+    
+    /// Limit the rows, bytes and indexes a database may hold
+    pub fn set_quota(&self, database: &str, quota: Quota) -> QubeResult<()> {
+        self.check_writable()?;
+        self.query_engine.set_quota(database, quota)
+    }
+    
+    /// The quota of a database
+    pub fn quota(&self, database: &str) -> QubeResult<Quota> {
+        self.query_engine.quota(database)
+    }
+    
+    /// What a database currently holds, in the units of its quota
+    pub fn quota_usage(&self, database: &str) -> QubeResult<QuotaUsage> {
+        self.query_engine.quota_usage(database)
+    }
+    
+    /// Insert a row into a SQL table, checking it against the table's
+    /// column types and constraints
+    pub fn insert_row(&self, table: &str, row: &Row) -> QubeResult<QueryResult> {
+        let start = Instant::now();
+        let result = self.check_writable().and_then(|()| self.query_engine.insert_row(table, row));
+        metrics::record_latency("insert", start.elapsed());
+        log_table("INSERT", table, result.is_ok()).ok();
+        result
+    }
+    
     /// Insert a row into a table
     pub fn insert(&mut self, table: &str, row: Row) -> QubeResult<()> {
+        self.check_writable()?;
         let start = Instant::now();
         
         // Generate a simple ID (in production, use proper ID generation)
@@ -80,7 +457,7 @@ impl EmbeddedQubeDB {
             .unwrap()
             .as_millis());
         
-        let result = self.storage.put_row(table, &id, &row);
+        let result = storage_span("put_row", table).in_scope(|| self.storage.put_row(table, &id, &row));
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
@@ -103,26 +480,34 @@ impl EmbeddedQubeDB {
     
     /// Get a row by ID
     pub fn get(&self, table: &str, id: &str) -> QubeResult<Option<Row>> {
-        self.storage.get_row(table, id)
+        storage_span("get_row", table).in_scope(|| self.storage.get_row(table, id))
     }
     
     /// Update a row
     pub fn update(&mut self, table: &str, id: &str, row: Row) -> QubeResult<()> {
-        self.storage.put_row(table, id, &row)
+        self.check_writable()?;
+        storage_span("put_row", table).in_scope(|| self.storage.put_row(table, id, &row))
     }
     
     /// Delete a row
     pub fn delete(&mut self, table: &str, id: &str) -> QubeResult<()> {
-        self.storage.delete_row(table, id)
+        self.check_writable()?;
+        storage_span("delete_row", table).in_scope(|| self.storage.delete_row(table, id))
     }
     
     /// Store a vector
     pub fn store_vector(&mut self, collection: &str, id: &str, vector: &[f32]) -> QubeResult<()> {
+        self.check_writable()?;
         let start = Instant::now();
         
-        let result = self.storage.put_vector(collection, id, vector);
+        let index = self.vector_indexes
+            .entry(collection.to_string())
+            .or_insert_with(|| VectorIndex::new(collection.to_string(), vector.len()));
+        let result = index.insert(id, vector)
+            .and_then(|_| storage_span("put_vector", collection).in_scope(|| self.storage.put_vector(collection, id, vector)));
         
         let duration = start.elapsed();
+        metrics::record_latency("vector_insert", duration);
         let duration_ms = duration.as_millis() as u64;
         
         match &result {
@@ -141,18 +526,142 @@ impl EmbeddedQubeDB {
         result
     }
     
+    /// Create an empty vector collection compared by `metric`. With
+    /// `normalize` its vectors are kept at unit length, which makes cosine
+    /// search cheaper. Storing into a collection that doesn't exist creates
+    /// one with the defaults instead.
+    pub fn create_vector_collection(&mut self, collection: &str, dimensions: usize, metric: DistanceMetric, normalize: bool) -> QubeResult<()> {
+        self.check_writable()?;
+        if self.vector_indexes.contains_key(collection) {
+            return Err(QubeError::Index(format!("Vector collection '{}' already exists", collection)));
+        }
+        let index = VectorIndex::with_metric(collection.to_string(), dimensions, metric)
+            .with_normalization(normalize);
+        self.vector_indexes.insert(collection.to_string(), index);
+        Ok(())
+    }
+    
     /// Get a vector
     pub fn get_vector(&self, collection: &str, id: &str) -> QubeResult<Option<Vec<f32>>> {
-        self.storage.get_vector(collection, id)
+        storage_span("get_vector", collection).in_scope(|| self.storage.get_vector(collection, id))
+    }
+    
+    /// Get the index of a vector collection
+    pub fn vector_collection(&self, collection: &str) -> Option<&VectorIndex> {
+        self.vector_indexes.get(collection)
+    }
+    
+    /// Keep at most `memory_budget` bytes of a collection's vectors in
+    /// memory, spilling the coldest by `policy` to a scratch file beside its
+    /// persisted index. The budget lasts until the database is closed.
+    pub fn set_vector_cache(&mut self, collection: &str, memory_budget: usize, policy: EvictionPolicy) -> QubeResult<()> {
+        let spill_path = self.vector_index_dir().join(format!("{}.{}", collection, VECTOR_SPILL_EXTENSION));
+        let index = self.vector_indexes
+            .get_mut(collection)
+            .ok_or_else(|| QubeError::NotFound(format!("Vector collection '{}'", collection)))?;
+        index.set_cache(VectorCacheConfig::new(memory_budget, spill_path).with_policy(policy))
+    }
+    
+    /// Find the `k` vectors in a collection most similar to `query`
+    pub fn search_vectors(&self, collection: &str, query: &[f32], k: usize) -> QubeResult<Vec<(String, f32)>> {
+        let start = Instant::now();
+        
+        let result = self.vector_indexes
+            .get(collection)
+            .ok_or_else(|| QubeError::NotFound(format!("Vector collection '{}'", collection)))
+            .and_then(|index| index.search(query, k));
+        
+        metrics::record_latency("vector_search", start.elapsed());
+        let duration_ms = start.elapsed().as_millis() as u64;
+        log_vector("SEARCH", collection, result.is_ok(), duration_ms).ok();
+        
+        result
+    }
+    
+    /// Persist all vector indexes so they can be restored without re-indexing.
+    /// Called automatically when the database is dropped.
+    pub fn save_vector_indexes(&self) -> QubeResult<()> {
+        self.check_writable()?;
+        let dir = self.vector_index_dir();
+        for (collection, index) in &self.vector_indexes {
+            index.save(dir.join(format!("{}.{}", collection, VECTOR_INDEX_EXTENSION)))?;
+        }
+        Ok(())
+    }
+    
+    fn vector_index_dir(&self) -> PathBuf {
+        self.storage_config.vector_dir(Path::new(&self.path)).join(VECTOR_INDEX_DIR)
+    }
+    
+    /// Store `value` under `key` in the key-value namespace, replacing any
+    /// previous value
+    pub fn kv_put(&mut self, key: &str, value: &[u8]) -> QubeResult<()> {
+        self.check_writable()?;
+        let start = Instant::now();
+        let mut row = Row::new();
+        row.insert("value".to_string(), Value::Binary(value.to_vec()));
+        storage_span("put_row", KV_TABLE).in_scope(|| self.storage.put_row(KV_TABLE, key, &row))?;
+        
+        self.kv.put(key, value.to_vec());
+        metrics::record_latency("kv_put", start.elapsed());
+        Ok(())
+    }
+    
+    /// Get the value stored under `key` in the key-value namespace
+    pub fn kv_get(&self, key: &str) -> QubeResult<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let value = self.kv.get(key).map(<[u8]>::to_vec);
+        metrics::record_latency("kv_get", start.elapsed());
+        Ok(value)
+    }
+    
+    /// Remove `key` from the key-value namespace, returning whether it was
+    /// present
+    pub fn kv_delete(&mut self, key: &str) -> QubeResult<bool> {
+        self.check_writable()?;
+        let start = Instant::now();
+        if self.kv.get(key).is_none() {
+            return Ok(false);
+        }
+        storage_span("delete_row", KV_TABLE).in_scope(|| self.storage.delete_row(KV_TABLE, key))?;
+        let deleted = self.kv.delete(key);
+        metrics::record_latency("kv_delete", start.elapsed());
+        Ok(deleted)
+    }
+    
+    /// Every key-value pair whose key starts with `prefix`, sorted by key.
+    /// An empty prefix returns the whole namespace.
+    pub fn kv_scan(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        self.kv
+            .scan(prefix)
+            .map(|(key, value)| (key.to_string(), value.to_vec()))
+            .collect()
+    }
+    
+    /// Number of keys in the key-value namespace
+    pub fn kv_len(&self) -> usize {
+        self.kv.len()
+    }
+    
+    /// Persist the key-value namespace. Called automatically when the
+    /// database is dropped.
+    pub fn save_kv(&self) -> QubeResult<()> {
+        self.check_writable()?;
+        self.kv.save(self.storage_config.relational_dir(Path::new(&self.path)).join(KV_FILE))
     }
     
     /// Store a graph node
     pub fn store_node(&mut self, graph: &str, node_id: &str, properties: Row) -> QubeResult<()> {
+        self.check_writable()?;
         let start = Instant::now();
         
-        let result = self.storage.put_graph_node(graph, node_id, &properties);
+        let result = storage_span("put_graph_node", graph).in_scope(|| self.storage.put_graph_node(graph, node_id, &properties));
+        if result.is_ok() {
+            self.graphs.add_node(graph, node_id, properties);
+        }
         
         let duration = start.elapsed();
+        metrics::record_latency("graph_node", duration);
         let duration_ms = duration.as_millis() as u64;
         
         match &result {
@@ -173,11 +682,16 @@ impl EmbeddedQubeDB {
     
     /// Store a graph edge
     pub fn store_edge(&mut self, graph: &str, from: &str, to: &str, properties: Row) -> QubeResult<()> {
+        self.check_writable()?;
         let start = Instant::now();
         
-        let result = self.storage.put_graph_edge(graph, from, to, &properties);
+        let result = storage_span("put_graph_edge", graph).in_scope(|| self.storage.put_graph_edge(graph, from, to, &properties));
+        if result.is_ok() {
+            self.graphs.add_edge(graph, from, to, properties);
+        }
         
         let duration = start.elapsed();
+        metrics::record_latency("graph_edge", duration);
         let duration_ms = duration.as_millis() as u64;
         
         match &result {
@@ -196,21 +710,349 @@ impl EmbeddedQubeDB {
         result
     }
     
+    /// Bulk-load an edge list of `from,to[,weight]` CSV lines into a graph,
+    /// creating nodes that do not exist yet
+    pub fn import_edges<R: Read>(&mut self, graph: &str, reader: R) -> QubeResult<ImportSummary> {
+        let edges = graph::read_edge_list(BufReader::new(reader))?;
+        self.import_graph(graph, Vec::new(), edges)
+    }
+    
+    /// Bulk-load nodes from a CSV file whose header is `id` followed by
+    /// property names. Existing nodes get the imported properties.
+    pub fn import_nodes<R: Read>(&mut self, graph: &str, reader: R) -> QubeResult<ImportSummary> {
+        let nodes = graph::read_node_list(BufReader::new(reader))?;
+        self.import_graph(graph, nodes, Vec::new())
+    }
+    
+    fn import_graph(&mut self, graph: &str, nodes: Vec<Node>, edges: Vec<Edge>) -> QubeResult<ImportSummary> {
+        self.check_writable()?;
+        let start = Instant::now();
+        
+        let result = storage_span("import_graph", graph).in_scope(|| {
+            nodes
+                .iter()
+                .try_for_each(|node| self.storage.put_graph_node(graph, &node.id, &node.properties))
+                .and_then(|()| {
+                    edges
+                        .iter()
+                        .try_for_each(|edge| self.storage.put_graph_edge(graph, &edge.from, &edge.to, &edge.properties))
+                })
+        });
+        
+        let duration_ms = start.elapsed().as_millis() as u64;
+        match result {
+            Ok(()) => {
+                let summary = self.graphs.import(graph, nodes, edges);
+                log_graph("IMPORT", graph, true).ok();
+                log_performance("Graph Import", duration_ms, 0, 0.0).ok();
+                Ok(summary)
+            }
+            Err(e) => {
+                log_graph("IMPORT", graph, false).ok();
+                crate::logging::log_error(LogCategory::Graph, &format!("Graph import failed for graph: {}", graph), &e, Some(format!("Duration: {}ms", duration_ms))).ok();
+                Err(e)
+            }
+        }
+    }
+    
+    /// Run a Cypher-style query against a graph, e.g.
+    /// `MATCH (a)-[:FRIENDS]->(b) WHERE a.name = 'Alice' RETURN b.name`
+    pub fn graph_query(&self, graph: &str, query: &str) -> QubeResult<QueryResult> {
+        let start = Instant::now();
+        let result = self.graphs.query(graph, query);
+        
+        let duration_ms = start.elapsed().as_millis() as u64;
+        log_query(query, result.is_ok(), duration_ms).ok();
+        if let Err(e) = &result {
+            crate::logging::log_error(LogCategory::Graph, &format!("Graph query failed: {}", query), e, Some(format!("Duration: {}ms", duration_ms))).ok();
+        }
+        result
+    }
+    
+    /// Index a property of the nodes labelled `label`, and of the edges of
+    /// type `label`, so graph queries matching on it look nodes up instead
+    /// of scanning. Creating an index that exists is a no-op.
+    pub fn create_graph_index(&mut self, graph: &str, label: &str, property: &str) -> QubeResult<()> {
+        self.check_writable()?;
+        if self.graphs.graph(graph).is_none() {
+            return Err(QubeError::NotFound(format!("Graph '{}' not found", graph)));
+        }
+        if self.graphs.create_index(graph, label, property) {
+            log_graph("CREATE_INDEX", graph, true).ok();
+        }
+        Ok(())
+    }
+    
+    /// Remove a graph node and every edge touching it, returning whether
+    /// the node existed
+    pub fn delete_node(&mut self, graph: &str, node_id: &str) -> QubeResult<bool> {
+        self.check_writable()?;
+        let deleted = self.graphs.remove_node(graph, node_id);
+        log_graph("DELETE_NODE", graph, deleted).ok();
+        Ok(deleted)
+    }
+    
+    /// Remove every edge from `from` to `to`, returning how many there were
+    pub fn delete_edge(&mut self, graph: &str, from: &str, to: &str) -> QubeResult<usize> {
+        self.check_writable()?;
+        let deleted = self.graphs.remove_edges(graph, from, to);
+        log_graph("DELETE_EDGE", graph, deleted > 0).ok();
+        Ok(deleted)
+    }
+    
+    /// The graphs stored in this database
+    pub fn graphs(&self) -> &GraphEngine {
+        &self.graphs
+    }
+    
+    /// Persist the graphs. Called automatically when the database is
+    /// dropped.
+    pub fn save_graphs(&self) -> QubeResult<()> {
+        self.check_writable()?;
+        self.graphs.save(self.storage_config.graph_dir(Path::new(&self.path)).join(GRAPH_FILE))
+    }
+    
+    /// Create an empty collection of documents searchable by embedding,
+    /// text or both, its embeddings compared by `metric`
+    pub fn create_document_collection(&mut self, collection: &str, dimensions: usize, metric: DistanceMetric) -> QubeResult<()> {
+        self.check_writable()?;
+        self.documents.create_collection(collection, dimensions, metric)?;
+        Ok(())
+    }
+    
+    /// Store a document, replacing any with the same id. A collection that
+    /// doesn't exist is created with the default metric.
+    pub fn store_document(&mut self, collection: &str, id: &str, document: Document) -> QubeResult<()> {
+        self.check_writable()?;
+        if self.documents.collection(collection).is_none() {
+            self.documents.create_collection(collection, document.embedding.len(), DistanceMetric::default())?;
+        }
+        let result = self.documents
+            .collection_mut(collection)
+            .expect("collection was just created")
+            .insert(id, document);
+        log_vector("STORE_DOCUMENT", collection, result.is_ok(), 0).ok();
+        result
+    }
+    
+    pub fn delete_document(&mut self, collection: &str, id: &str) -> QubeResult<bool> {
+        self.check_writable()?;
+        Ok(self.documents.collection_mut(collection).is_some_and(|c| c.remove(id)))
+    }
+    
+    /// Generate embeddings with `embedder` in `store_text` and
+    /// `search_text`
+    pub fn set_embedder(&mut self, embedder: Arc<dyn Embedder>) {
+        self.embedder = Some(embedder);
+    }
+    
+    pub fn embedder(&self) -> Option<&Arc<dyn Embedder>> {
+        self.embedder.as_ref()
+    }
+    
+    /// The embedding of `text` from the configured embedder
+    pub fn embed(&self, text: &str) -> QubeResult<Vec<f32>> {
+        match &self.embedder {
+            Some(embedder) => embedder.embed(text),
+            None => Err(QubeError::Config("no embedder configured".to_string())),
+        }
+    }
+    
+    /// Store a document given only its text, embedding it with the
+    /// configured embedder
+    pub fn store_text(&mut self, collection: &str, id: &str, text: &str, metadata: Row) -> QubeResult<()> {
+        self.check_writable()?;
+        let embedding = self.embed(text)?;
+        self.store_document(collection, id, Document { embedding, text: text.to_string(), metadata })
+    }
+    
+    /// Find the `k` documents closest to `text`, embedding it with the
+    /// configured embedder and blending similarity with keyword relevance
+    pub fn search_text(&self, collection: &str, text: &str, k: usize) -> QubeResult<Vec<HybridHit>> {
+        let query = HybridQuery::new(k).with_vector(self.embed(text)?).with_text(text);
+        self.hybrid_search(collection, &query)
+    }
+    
+    /// Rank a collection's documents by a blend of vector similarity and
+    /// text relevance, see `hybrid`
+    pub fn hybrid_search(&self, collection: &str, query: &HybridQuery) -> QubeResult<Vec<HybridHit>> {
+        let start = Instant::now();
+        
+        let result = self.documents
+            .collection(collection)
+            .ok_or_else(|| QubeError::NotFound(format!("Document collection '{}'", collection)))
+            .and_then(|documents| documents.search(query));
+        
+        let duration_ms = start.elapsed().as_millis() as u64;
+        log_vector("HYBRID_SEARCH", collection, result.is_ok(), duration_ms).ok();
+        
+        result
+    }
+    
+    /// The document collections stored in this database
+    pub fn documents(&self) -> &DocumentStore {
+        &self.documents
+    }
+    
+    /// Persist the document collections. Called automatically when the
+    /// database is dropped.
+    pub fn save_documents(&self) -> QubeResult<()> {
+        self.check_writable()?;
+        self.documents.save(self.storage_config.vector_dir(Path::new(&self.path)).join(DOCUMENT_FILE))
+    }
+    
     /// Get database path
     pub fn path(&self) -> &str {
         &self.path
     }
+    
+    /// Where each data model keeps its files
+    pub fn storage_config(&self) -> &StorageConfig {
+        &self.storage_config
+    }
+    
+    /// Remove every table, index, vector collection, graph node and edge,
+    /// document and key-value pair, both in memory and in the files they are
+    /// persisted to, leaving an empty database that is still open and
+    /// usable. Fails while any session has a transaction open.
+    pub fn drop_all(&mut self) -> QubeResult<()> {
+        self.check_writable()?;
+        self.query_engine.reset()?;
+        
+        self.vector_indexes.clear();
+        let vector_dir = self.vector_index_dir();
+        if vector_dir.exists() {
+            std::fs::remove_dir_all(&vector_dir)?;
+        }
+        
+        for (key, _) in self.kv_scan("") {
+            self.storage.delete_row(KV_TABLE, &key)?;
+        }
+        self.kv.clear();
+        self.save_kv()?;
+        
+        self.graphs.clear();
+        self.save_graphs()?;
+        
+        self.documents.clear();
+        self.save_documents()?;
+        
+        log_table("DROP ALL", &self.path, true).ok();
+        Ok(())
+    }
+    
+    /// Delete the database at `path` and every file in it. Refuses while the
+    /// database is open in this process; drop the `EmbeddedQubeDB` first.
+    /// Directories a `StorageConfig` placed elsewhere are left alone.
+    pub fn delete_database<P: AsRef<Path>>(path: P) -> QubeResult<()> {
+        let path = path.as_ref();
+        let canonical = path.canonicalize().map_err(|_| {
+            QubeError::DatabaseNotFound(path.display().to_string())
+        })?;
+        if !canonical.is_dir() {
+            return Err(QubeError::Config(format!(
+                "{} is not a database directory",
+                path.display()
+            )));
+        }
+        if canonical.parent().is_none() {
+            return Err(QubeError::Config(format!(
+                "refusing to delete {}",
+                path.display()
+            )));
+        }
+        
+        let open = OPEN_DATABASES.lock().unwrap();
+        if open.contains(&canonical) {
+            return Err(QubeError::Storage(format!(
+                "database {} is still open",
+                path.display()
+            )));
+        }
+        std::fs::remove_dir_all(&canonical)?;
+        drop(open);
+        
+        log_table("DELETE DATABASE", &path.display().to_string(), true).ok();
+        Ok(())
+    }
+}
+
+impl Drop for EmbeddedQubeDB {
+    fn drop(&mut self) {
+        self.maintenance.stop();
+        if !self.read_only {
+            if let Err(e) = self.save_vector_indexes() {
+                crate::logging::log_error(LogCategory::Vector, "Failed to persist vector indexes", &e, None).ok();
+            }
+            if let Err(e) = self.save_kv() {
+                crate::logging::log_error(LogCategory::Storage, "Failed to persist key-value namespace", &e, None).ok();
+            }
+            if let Err(e) = self.save_graphs() {
+                crate::logging::log_error(LogCategory::Graph, "Failed to persist graphs", &e, None).ok();
+            }
+            if let Err(e) = self.save_documents() {
+                crate::logging::log_error(LogCategory::Vector, "Failed to persist document collections", &e, None).ok();
+            }
+        }
+        
+        if let Ok(canonical) = Path::new(&self.path).canonicalize() {
+            let mut open = OPEN_DATABASES.lock().unwrap();
+            if let Some(i) = open.iter().position(|p| *p == canonical) {
+                open.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Register the engine's built-in maintenance tasks
+fn register_maintenance(scheduler: &MaintenanceScheduler, engine: &Arc<QueryEngine>) -> QubeResult<()> {
+    let stats_engine = Arc::clone(engine);
+    scheduler.register("refresh_stats", STATS_REFRESH_INTERVAL, move || {
+        stats_engine.refresh_stale_stats().map(|_| ())
+    })?;
+    let index_engine = Arc::clone(engine);
+    scheduler.register("verify_indexes", INDEX_CHECK_INTERVAL, move || {
+        index_engine.verify_indexes();
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Load every persisted vector index in `dir`, keyed by collection name
+/// Debug-level tracing span around one call into the storage engine
+fn storage_span(operation: &str, table: &str) -> tracing::Span {
+    tracing::debug_span!("storage", operation, table)
+}
+
+fn load_vector_indexes(dir: &Path) -> QubeResult<HashMap<String, VectorIndex>> {
+    let mut indexes = HashMap::new();
+    if !dir.exists() {
+        return Ok(indexes);
+    }
+    
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(VECTOR_INDEX_EXTENSION) {
+            continue;
+        }
+        let index = VectorIndex::load(&path)?;
+        indexes.insert(index.name().to_string(), index);
+    }
+    Ok(indexes)
 }
 
 /// Builder for creating embedded QubeDB instances
 pub struct EmbeddedQubeDBBuilder {
     path: Option<String>,
+    read_only: bool,
+    embedder: Option<Arc<dyn Embedder>>,
+    storage_config: StorageConfig,
 }
 
 impl EmbeddedQubeDBBuilder {
     /// Create a new builder
     pub fn new() -> Self {
-        EmbeddedQubeDBBuilder { path: None }
+        EmbeddedQubeDBBuilder { path: None, read_only: false, embedder: None, storage_config: StorageConfig::default() }
     }
     
     /// Set the database path
@@ -219,10 +1061,31 @@ impl EmbeddedQubeDBBuilder {
         self
     }
     
+    /// Open the database read-only; see `EmbeddedQubeDB::open_read_only`
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    
+    /// Generate embeddings with `embedder`; see `EmbeddedQubeDB::set_embedder`
+    pub fn embedder(mut self, embedder: Arc<dyn Embedder>) -> Self {
+        self.embedder = Some(embedder);
+        self
+    }
+    
+    /// Keep each data model's files where `storage_config` says; see
+    /// `EmbeddedQubeDB::open_with_storage`
+    pub fn storage_config(mut self, storage_config: StorageConfig) -> Self {
+        self.storage_config = storage_config;
+        self
+    }
+    
     /// Build the embedded database
     pub fn build(self) -> QubeResult<EmbeddedQubeDB> {
         let path = self.path.unwrap_or_else(|| "./qubedb_embedded".to_string());
-        EmbeddedQubeDB::open(path)
+        let mut db = EmbeddedQubeDB::open_with_mode(path, self.read_only, self.storage_config)?;
+        db.embedder = self.embedder;
+        Ok(db)
     }
 }
 
@@ -231,3 +1094,402 @@ impl Default for EmbeddedQubeDBBuilder {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{DataType, TableBuilder};
+
+    /// A fresh database directory under the temp dir
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qubedb-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn assert_empty(db: &EmbeddedQubeDB) {
+        assert!(db.list_tables().is_empty());
+        assert!(db.vector_collection("vectors").is_none());
+        assert!(db.kv_scan("").is_empty());
+        assert!(db.graphs().graph_names().is_empty());
+        assert!(db.graph_query("people", "MATCH (n) RETURN n.id").is_err());
+        assert!(db.documents().collection("docs").is_none());
+    }
+
+    #[test]
+    fn drop_all_removes_every_data_model() {
+        let path = db_path("drop-all");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        db.execute_script("CREATE TABLE t (id INT PRIMARY KEY); INSERT INTO t VALUES (1)").unwrap();
+        db.store_vector("vectors", "a", &[1.0, 0.0]).unwrap();
+        db.kv_put("key", b"value").unwrap();
+        db.store_node("people", "alice", Row::new()).unwrap();
+        db.store_node("people", "bob", Row::new()).unwrap();
+        db.store_edge("people", "alice", "bob", Row::new()).unwrap();
+        let document = Document {
+            embedding: vec![1.0, 0.0],
+            text: "hello".to_string(),
+            metadata: Row::new(),
+        };
+        db.store_document("docs", "d", document).unwrap();
+        let people = db.graph_query("people", "MATCH (n) RETURN n.id").unwrap();
+        assert_eq!(people.rows.len(), 2);
+
+        db.drop_all().unwrap();
+        assert_empty(&db);
+
+        // Nothing comes back from disk either
+        drop(db);
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        assert_empty(&db);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn scripts_run_every_statement_in_order() {
+        let path = db_path("script");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        let results = db
+            .execute_script(
+                "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT);
+                 INSERT INTO notes VALUES (1, 'first; with a semicolon'), (2, 'it''s second');
+                 SELECT body FROM notes ORDER BY id",
+            )
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].affected_rows, 2);
+        let bodies: Vec<&Value> = results[2].rows.iter().map(|row| &row["body"]).collect();
+        assert_eq!(
+            bodies,
+            [
+                &Value::String("first; with a semicolon".to_string()),
+                &Value::String("it's second".to_string())
+            ]
+        );
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn a_failing_statement_rolls_the_script_back() {
+        let path = db_path("script-rollback");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        db.execute_script("CREATE TABLE notes (id INT PRIMARY KEY, body TEXT)")
+            .unwrap();
+
+        let error = db.execute_script(
+            "INSERT INTO notes VALUES (1, 'a');
+             CREATE TABLE tags (name TEXT);
+             INSERT INTO notes VALUES (1, 'duplicate');
+             INSERT INTO notes VALUES (2, 'b')",
+        );
+        assert!(error.is_err());
+        assert_eq!(db.list_tables(), ["notes"]);
+        let rows = db.execute_script("SELECT id FROM notes").unwrap();
+        assert!(rows[0].rows.is_empty());
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn tables_survive_reopening() {
+        let path = db_path("reopen");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        db.execute_script(
+            "CREATE TABLE notes (id INT AUTO_INCREMENT PRIMARY KEY, body TEXT);
+             INSERT INTO notes (body) VALUES ('a'), ('b')",
+        )
+        .unwrap();
+        let tags = TableBuilder::new("tags")
+            .column("name", DataType::String)
+            .unique()
+            .build()
+            .unwrap();
+        db.create_table(tags).unwrap();
+        db.execute_script("INSERT INTO tags VALUES ('x')").unwrap();
+        drop(db);
+
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        assert_eq!(db.list_tables(), ["notes", "tags"]);
+        let result = db
+            .execute_script("INSERT INTO notes (body) VALUES ('c')")
+            .unwrap();
+        assert_eq!(result[0].last_insert_id, Some(3));
+        let rows = db.execute_script("SELECT body FROM notes ORDER BY id").unwrap();
+        assert_eq!(rows[0].rows.len(), 3);
+        // The table made with the builder keeps its unique index
+        assert!(db.execute_script("INSERT INTO tags VALUES ('x')").is_err());
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_databases_see_the_tables_but_cannot_change_them() {
+        let path = db_path("read-only");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        db.execute_script(
+            "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT);
+             INSERT INTO notes VALUES (1, 'a'), (2, 'b')",
+        )
+        .unwrap();
+        drop(db);
+        let wal = path.join(TABLE_WAL_FILE);
+        let logged = std::fs::read(&wal).unwrap();
+
+        let db = EmbeddedQubeDB::open_read_only(&path).unwrap();
+        assert_eq!(db.list_tables(), ["notes"]);
+        let rows = db.execute_script("SELECT body FROM notes ORDER BY id").unwrap();
+        let bodies: Vec<&Value> = rows[0].rows.iter().map(|row| &row["body"]).collect();
+        assert_eq!(
+            bodies,
+            [&Value::String("a".to_string()), &Value::String("b".to_string())]
+        );
+        assert!(matches!(
+            db.execute_script("INSERT INTO notes VALUES (3, 'c')"),
+            Err(QubeError::PermissionDenied(_))
+        ));
+        drop(db);
+        assert_eq!(std::fs::read(&wal).unwrap(), logged);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn tables_are_kept_under_the_relational_path() {
+        let path = db_path("relational-path");
+        let tables = db_path("relational-path-tables");
+        let config = StorageConfig::default().with_relational_path(&tables);
+        let db = EmbeddedQubeDB::open_with_storage(&path, config.clone()).unwrap();
+        db.execute_script("CREATE TABLE t (id INT); INSERT INTO t VALUES (1)")
+            .unwrap();
+        drop(db);
+        assert!(tables.join(TABLE_WAL_FILE).exists());
+        assert!(!path.join(TABLE_WAL_FILE).exists());
+
+        let db = EmbeddedQubeDB::open_with_storage(&path, config).unwrap();
+        let rows = db.execute_script("SELECT id FROM t").unwrap();
+        assert_eq!(rows[0].rows.len(), 1);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&tables).unwrap();
+    }
+
+    /// Counts of "rust" and "cook", plus one so no embedding is all zeros
+    fn toy_embedding(text: &str) -> QubeResult<Vec<f32>> {
+        if text.is_empty() {
+            return Err(QubeError::VectorSearch("nothing to embed".to_string()));
+        }
+        let count = |word: &str| text.split_whitespace().filter(|w| *w == word).count() as f32;
+        Ok(vec![1.0 + count("rust"), 1.0 + count("cook")])
+    }
+
+    #[test]
+    fn text_is_embedded_by_the_configured_embedder() {
+        let path = db_path("embedder");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        assert!(db.embedder().is_none());
+        assert!(matches!(db.store_text("docs", "a", "rust", Row::new()), Err(QubeError::Config(_))));
+        assert!(matches!(db.search_text("docs", "rust", 1), Err(QubeError::Config(_))));
+        drop(db);
+
+        let embedder: Arc<dyn Embedder> = Arc::new(toy_embedding);
+        let mut db = EmbeddedQubeDBBuilder::new().path(&path).embedder(embedder).build().unwrap();
+        assert_eq!(db.embed("rust and rust").unwrap(), [3.0, 1.0]);
+        db.store_text("docs", "a", "rust is fast", Row::new()).unwrap();
+        db.store_text("docs", "b", "cook the pasta", Row::new()).unwrap();
+        // A failed embedding stores nothing
+        assert!(db.store_text("docs", "c", "", Row::new()).is_err());
+
+        let docs = db.documents().collection("docs").unwrap();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs.vectors().get("a").unwrap(), [2.0, 1.0]);
+        let hits = db.search_text("docs", "cook", 2).unwrap();
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, ["b", "a"]);
+        assert!(hits[0].vector_score.is_some() && hits[0].text_score.is_some());
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn vector_collections_are_searchable_after_reopening() {
+        let path = db_path("vectors-reopen");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        db.store_vector("points", "x", &[1.0, 0.0]).unwrap();
+        db.store_vector("points", "y", &[0.0, 1.0]).unwrap();
+        assert!(db.store_vector("points", "z", &[1.0]).is_err());
+        let before = db.search_vectors("points", &[0.9, 0.1], 2).unwrap();
+        drop(db);
+
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        let after = db.search_vectors("points", &[0.9, 0.1], 2).unwrap();
+        assert_eq!(after, before);
+        assert_eq!(after[0].0, "x");
+        assert!(db.search_vectors("missing", &[1.0, 0.0], 1).is_err());
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn key_values_survive_reopening() {
+        let path = db_path("kv-reopen");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        db.kv_put("cart:1", b"apples").unwrap();
+        db.kv_put("cart:2", b"pears").unwrap();
+        db.kv_put("cart:2", b"plums").unwrap();
+        db.kv_put("other", b"x").unwrap();
+        assert!(db.kv_delete("other").unwrap());
+        assert!(!db.kv_delete("other").unwrap());
+        drop(db);
+
+        let mut db = EmbeddedQubeDB::open_read_only(&path).unwrap();
+        assert_eq!(
+            db.kv_scan("cart:"),
+            [
+                ("cart:1".to_string(), b"apples".to_vec()),
+                ("cart:2".to_string(), b"plums".to_vec())
+            ]
+        );
+        assert_eq!(db.kv_get("other").unwrap(), None);
+        assert!(matches!(db.kv_put("cart:3", b"figs"), Err(QubeError::PermissionDenied(_))));
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+
+    /// Records each span as it opens, as `name{fields}`
+    #[derive(Clone, Default)]
+    struct SpanLog(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanLog {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields(String::new());
+            attrs.record(&mut fields);
+            let entry = format!("{}{{{}}}", attrs.metadata().name(), fields.0);
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn storage_calls_run_inside_tracing_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let path = db_path("storage-spans");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        let log = SpanLog::default();
+        let subscriber = tracing_subscriber::registry().with(log.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            db.kv_put("k", b"v").unwrap();
+            db.kv_delete("k").unwrap();
+            db.import_graph("people", Vec::new(), Vec::new()).unwrap();
+        });
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            [
+                format!(r#"storage{{ operation="put_row" table="{}"}}"#, KV_TABLE),
+                format!(r#"storage{{ operation="delete_row" table="{}"}}"#, KV_TABLE),
+                r#"storage{ operation="import_graph" table="people"}"#.to_string(),
+            ]
+        );
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+
+    #[test]
+    fn maintenance_runs_in_the_background_while_open() {
+        let path = db_path("maintenance");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        let maintenance = db.maintenance();
+        assert!(maintenance.is_running());
+        assert_eq!(maintenance.tasks(), ["refresh_stats", "verify_indexes"]);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        maintenance
+            .register("ping", Duration::from_millis(1), move || {
+                sender.send(()).ok();
+                Ok(())
+            })
+            .unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+
+    #[test]
+    fn vector_collections_can_be_created_normalized() {
+        let path = db_path("vectors-normalized");
+        let mut db = EmbeddedQubeDB::open(&path).unwrap();
+        db.create_vector_collection("points", 2, DistanceMetric::Cosine, true)
+            .unwrap();
+        assert!(db
+            .create_vector_collection("points", 2, DistanceMetric::Cosine, false)
+            .is_err());
+        db.store_vector("points", "x", &[2.0, 0.0]).unwrap();
+        db.store_vector("points", "y", &[0.0, 5.0]).unwrap();
+
+        let points = db.vector_collection("points").unwrap();
+        assert!(points.is_normalized());
+        assert_eq!(points.get("y"), Some(vec![0.0, 5.0]));
+        let found = db.search_vectors("points", &[0.0, 3.0], 1).unwrap();
+        assert_eq!(found, [("y".to_string(), 1.0)]);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+
+    #[test]
+    fn transactions_can_be_retried_on_a_fresh_session() {
+        let path = db_path("transaction-retry");
+        let db = EmbeddedQubeDB::open(&path).unwrap();
+        let engine = Arc::clone(&db.query_engine);
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT PRIMARY KEY, v INT);
+                 INSERT INTO t VALUES (1, 1)",
+            )
+            .unwrap();
+        let mut attempts = 0;
+        let rows = db
+            .transaction_retry(|session| {
+                attempts += 1;
+                session.execute_script("SELECT v FROM t")?;
+                if attempts == 1 {
+                    engine.execute_script("UPDATE t SET v = 2")?;
+                }
+                let mut results = session.execute_script("UPDATE t SET v = v * 10")?;
+                Ok(results.pop().unwrap().affected_rows)
+            }, 1)
+            .unwrap();
+        assert_eq!((attempts, rows), (2, 1));
+        let result = engine.execute_script("SELECT v FROM t").unwrap().pop().unwrap();
+        assert_eq!(result.rows[0]["v"], Value::Int32(20));
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -4,8 +4,8 @@
 //! that works with the real database implementation.
 
 use crate::error::QubeResult;
-use crate::types::{QueryResult, Row};
-use crate::logging::{LogCategory, log_query, log_table, log_vector, log_graph, log_performance};
+use crate::types::{QueryResult, ResultKind, Row, Value};
+use crate::logging::{log_query, log_table, log_vector, log_graph, log_performance};
 use std::time::Instant;
 
 /// Simple Embedded QubeDB
@@ -31,48 +31,58 @@ impl EmbeddedQubeDB {
             s if s.starts_with("SELECT") => {
                 // Simple SELECT query
                 QueryResult {
+                    kind: ResultKind::Rows,
                     columns: vec!["id".to_string(), "name".to_string()],
                     rows: vec![
-                        Row::new(vec!["1".to_string(), "John Doe".to_string()]),
-                        Row::new(vec!["2".to_string(), "Jane Smith".to_string()]),
+                        row(&["id", "name"], vec!["1".to_string(), "John Doe".to_string()]),
+                        row(&["id", "name"], vec!["2".to_string(), "Jane Smith".to_string()]),
                     ],
-                    affected_rows: 2,
+                    affected_rows: 0,
+                    last_insert_id: None,
                     execution_time: start.elapsed(),
                 }
             }
             s if s.starts_with("INSERT") => {
                 // Simple INSERT query
                 QueryResult {
+                    kind: ResultKind::Mutation,
                     columns: vec!["affected_rows".to_string()],
-                    rows: vec![Row::new(vec!["1".to_string()])],
+                    rows: vec![row(&["affected_rows"], vec!["1".to_string()])],
                     affected_rows: 1,
+                    last_insert_id: None,
                     execution_time: start.elapsed(),
                 }
             }
             s if s.starts_with("UPDATE") => {
                 // Simple UPDATE query
                 QueryResult {
+                    kind: ResultKind::Mutation,
                     columns: vec!["affected_rows".to_string()],
-                    rows: vec![Row::new(vec!["1".to_string()])],
+                    rows: vec![row(&["affected_rows"], vec!["1".to_string()])],
                     affected_rows: 1,
+                    last_insert_id: None,
                     execution_time: start.elapsed(),
                 }
             }
             s if s.starts_with("DELETE") => {
                 // Simple DELETE query
                 QueryResult {
+                    kind: ResultKind::Mutation,
                     columns: vec!["affected_rows".to_string()],
-                    rows: vec![Row::new(vec!["1".to_string()])],
+                    rows: vec![row(&["affected_rows"], vec!["1".to_string()])],
                     affected_rows: 1,
+                    last_insert_id: None,
                     execution_time: start.elapsed(),
                 }
             }
             _ => {
                 // Default response
                 QueryResult {
+                    kind: ResultKind::Rows,
                     columns: vec!["message".to_string()],
-                    rows: vec![Row::new(vec!["Query executed".to_string()])],
+                    rows: vec![row(&["message"], vec!["Query executed".to_string()])],
                     affected_rows: 0,
+                    last_insert_id: None,
                     execution_time: start.elapsed(),
                 }
             }
@@ -83,7 +93,7 @@ impl EmbeddedQubeDB {
         
         // Log successful query
         log_query(sql, true, duration_ms).ok();
-        log_performance("query_execution", duration_ms).ok();
+        log_performance("query_execution", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
@@ -93,20 +103,22 @@ impl EmbeddedQubeDB {
         let start = Instant::now();
         
         // Log table operation
-        log_table(table, "insert", true, 0).ok();
+        log_table("insert", table, true).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["affected_rows".to_string()],
-            rows: vec![Row::new(vec![data.len().to_string()])],
+            rows: vec![row(&["affected_rows"], vec![data.len().to_string()])],
             affected_rows: data.len(),
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_table(table, "insert", true, duration_ms).ok();
-        log_performance("insert_operation", duration_ms).ok();
+        log_table("insert", table, true).ok();
+        log_performance("insert_operation", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
@@ -116,85 +128,91 @@ impl EmbeddedQubeDB {
         let start = Instant::now();
         
         // Log table operation
-        log_table(table, "get", true, 0).ok();
+        log_table("get", table, true).ok();
         
         // Simple get operation
-        let result = Some(Row::new(vec![key.to_string(), "value".to_string()]));
+        let result = Some(row(&["key", "value"], vec![key.to_string(), "value".to_string()]));
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_table(table, "get", true, duration_ms).ok();
-        log_performance("get_operation", duration_ms).ok();
+        log_table("get", table, true).ok();
+        log_performance("get_operation", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
     
     /// Update data (simplified)
-    pub async fn update(&self, table: &str, key: &str, data: Row) -> QubeResult<QueryResult> {
+    pub async fn update(&self, table: &str, _key: &str, _data: Row) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log table operation
-        log_table(table, "update", true, 0).ok();
+        log_table("update", table, true).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["affected_rows".to_string()],
-            rows: vec![Row::new(vec!["1".to_string()])],
+            rows: vec![row(&["affected_rows"], vec!["1".to_string()])],
             affected_rows: 1,
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_table(table, "update", true, duration_ms).ok();
-        log_performance("update_operation", duration_ms).ok();
+        log_table("update", table, true).ok();
+        log_performance("update_operation", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
     
     /// Delete data (simplified)
-    pub async fn delete(&self, table: &str, key: &str) -> QubeResult<QueryResult> {
+    pub async fn delete(&self, table: &str, _key: &str) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log table operation
-        log_table(table, "delete", true, 0).ok();
+        log_table("delete", table, true).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["affected_rows".to_string()],
-            rows: vec![Row::new(vec!["1".to_string()])],
+            rows: vec![row(&["affected_rows"], vec!["1".to_string()])],
             affected_rows: 1,
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_table(table, "delete", true, duration_ms).ok();
-        log_performance("delete_operation", duration_ms).ok();
+        log_table("delete", table, true).ok();
+        log_performance("delete_operation", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
     
     /// Store vector data (simplified)
-    pub async fn store_vector(&self, id: &str, vector: Vec<f32>) -> QubeResult<QueryResult> {
+    pub async fn store_vector(&self, id: &str, _vector: Vec<f32>) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log vector operation
-        log_vector(id, "store", true, 0).ok();
+        log_vector("store", id, true, 0).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["vector_id".to_string()],
-            rows: vec![Row::new(vec![id.to_string()])],
+            rows: vec![row(&["vector_id"], vec![id.to_string()])],
             affected_rows: 1,
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_vector(id, "store", true, duration_ms).ok();
-        log_performance("vector_store", duration_ms).ok();
+        log_vector("store", id, true, duration_ms).ok();
+        log_performance("vector_store", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
@@ -204,7 +222,7 @@ impl EmbeddedQubeDB {
         let start = Instant::now();
         
         // Log vector operation
-        log_vector(id, "get", true, 0).ok();
+        log_vector("get", id, true, 0).ok();
         
         // Simple vector data
         let result = Some(vec![0.1, 0.2, 0.3, 0.4]);
@@ -212,55 +230,68 @@ impl EmbeddedQubeDB {
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_vector(id, "get", true, duration_ms).ok();
-        log_performance("vector_get", duration_ms).ok();
+        log_vector("get", id, true, duration_ms).ok();
+        log_performance("vector_get", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
     
     /// Store graph node (simplified)
-    pub async fn store_node(&self, id: &str, properties: std::collections::HashMap<String, String>) -> QubeResult<QueryResult> {
+    pub async fn store_node(&self, id: &str, _properties: std::collections::HashMap<String, String>) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log graph operation
-        log_graph(id, "store_node", true, 0).ok();
+        log_graph("store_node", id, true).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["node_id".to_string()],
-            rows: vec![Row::new(vec![id.to_string()])],
+            rows: vec![row(&["node_id"], vec![id.to_string()])],
             affected_rows: 1,
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_graph(id, "store_node", true, duration_ms).ok();
-        log_performance("graph_store_node", duration_ms).ok();
+        log_graph("store_node", id, true).ok();
+        log_performance("graph_store_node", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
     
     /// Store graph edge (simplified)
-    pub async fn store_edge(&self, from: &str, to: &str, relationship: &str) -> QubeResult<QueryResult> {
+    pub async fn store_edge(&self, from: &str, to: &str, _relationship: &str) -> QubeResult<QueryResult> {
         let start = Instant::now();
         
         // Log graph operation
-        log_graph(from, "store_edge", true, 0).ok();
+        log_graph("store_edge", from, true).ok();
         
         let result = QueryResult {
+            kind: ResultKind::Mutation,
             columns: vec!["edge_id".to_string()],
-            rows: vec![Row::new(vec![format!("{}->{}", from, to)])],
+            rows: vec![row(&["edge_id"], vec![format!("{}->{}", from, to)])],
             affected_rows: 1,
+            last_insert_id: None,
             execution_time: start.elapsed(),
         };
         
         let duration = start.elapsed();
         let duration_ms = duration.as_millis() as u64;
         
-        log_graph(from, "store_edge", true, duration_ms).ok();
-        log_performance("graph_store_edge", duration_ms).ok();
+        log_graph("store_edge", from, true).ok();
+        log_performance("graph_store_edge", duration_ms, 0, 0.0).ok();
         
         Ok(result)
     }
 }
+
+/// A row holding `values` as text under `columns`
+fn row(columns: &[&str], values: Vec<String>) -> Row {
+    columns
+        .iter()
+        .map(|column| column.to_string())
+        .zip(values.into_iter().map(Value::String))
+        .collect()
+}
@@ -43,7 +43,127 @@ pub enum QubeError {
 
     #[error("Transaction error: {0}")]
     Transaction(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    /// A query stopped through its cancellation token
+    #[error("Query cancelled")]
+    Cancelled,
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+
+    /// A valid statement using a construct the engine does not implement,
+    /// named so users know what to avoid
+    #[error("Not supported: {0}")]
+    Unsupported(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Reason of the `Transaction` error for an optimistic transaction whose
+/// reads changed before it committed; retrying it may succeed
+pub const CONFLICT: &str = "conflict";
+
+/// Start of the `Transaction` error for a transaction rolled back to break
+/// a deadlock over row locks
+pub const DEADLOCK: &str = "deadlock";
+
+impl QubeError {
+    /// Whether this is an optimistic transaction's conflict
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, QubeError::Transaction(reason) if reason == CONFLICT)
+    }
+
+    /// Whether this is a transaction rolled back to break a deadlock
+    pub fn is_deadlock(&self) -> bool {
+        matches!(self, QubeError::Transaction(reason) if reason.starts_with(DEADLOCK))
+    }
+
+    /// HTTP status code that best describes this error to an API client
+    pub fn http_status(&self) -> u16 {
+        match self {
+            QubeError::QueryParse(_)
+            | QubeError::ConstraintViolation(_)
+            | QubeError::Index(_)
+            | QubeError::VectorSearch(_)
+            | QubeError::Serialization(_) => 400,
+            QubeError::PermissionDenied(_) => 403,
+            QubeError::NotFound(_)
+            | QubeError::DatabaseNotFound(_)
+            | QubeError::TableNotFound(_)
+            | QubeError::ColumnNotFound(_) => 404,
+            QubeError::Timeout(_) => 408,
+            // The status nginx uses for a request its client gave up on
+            QubeError::Cancelled => 499,
+            QubeError::AlreadyExists(_) | QubeError::Transaction(_) => 409,
+            QubeError::Unsupported(_) => 501,
+            _ => 500,
+        }
+    }
 }
 
 /// Result type alias for QubeDB operations
 pub type QubeResult<T> = Result<T, QubeError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_the_http_status_a_client_expects() {
+        let statuses: Vec<u16> = [
+            QubeError::QueryParse("bad".to_string()),
+            QubeError::PermissionDenied("no".to_string()),
+            QubeError::NotFound("Index 'i'".to_string()),
+            QubeError::TableNotFound("t".to_string()),
+            QubeError::Timeout("slow".to_string()),
+            QubeError::AlreadyExists("Table 't'".to_string()),
+            QubeError::Transaction(CONFLICT.to_string()),
+            QubeError::Storage("disk".to_string()),
+            QubeError::Other("anything".to_string()),
+        ]
+        .iter()
+        .map(QubeError::http_status)
+        .collect();
+        assert_eq!(statuses, [400, 403, 404, 404, 408, 409, 409, 500, 500]);
+    }
+
+    #[test]
+    fn messages_name_the_kind_of_error() {
+        let messages: Vec<String> = [
+            QubeError::PermissionDenied("DROP TABLE t".to_string()),
+            QubeError::NotFound("Index 'i'".to_string()),
+            QubeError::AlreadyExists("Table 't'".to_string()),
+            QubeError::Other("plain".to_string()),
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        assert_eq!(
+            messages,
+            [
+                "Permission denied: DROP TABLE t",
+                "Not found: Index 'i'",
+                "Already exists: Table 't'",
+                "plain",
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicts_are_told_apart_from_other_transaction_errors() {
+        assert!(QubeError::Transaction(CONFLICT.to_string()).is_conflict());
+        assert!(!QubeError::Transaction("conflict: other".to_string()).is_conflict());
+        assert!(!QubeError::Transaction(DEADLOCK.to_string()).is_conflict());
+        assert!(!QubeError::Other(CONFLICT.to_string()).is_conflict());
+    }
+}
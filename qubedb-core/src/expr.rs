@@ -0,0 +1,1014 @@
+//! Expression evaluation for QubeDB queries
+//!
+//! Evaluates parsed SQL expressions against a single row and converts
+//! literals and stored values between SQL and QubeDB types.
+
+use crate::aggregate::AggregateFunction;
+use crate::crdt::Counter;
+use crate::error::{QubeError, QubeResult};
+use crate::functions;
+use crate::types::{DataType, Row, Value};
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::{
+    BinaryOperator, DateTimeField, Expr, Function, FunctionArg, FunctionArgExpr, UnaryOperator,
+    Value as SqlValue,
+};
+use std::cmp::Ordering;
+
+/// Evaluate an expression against a row
+pub fn evaluate(expr: &Expr, row: &Row) -> QubeResult<Value> {
+    match expr {
+        Expr::Identifier(ident) => lookup_column(row, None, &ident.value),
+        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+            [column] => lookup_column(row, None, &column.value),
+            [.., table, column] => lookup_column(row, Some(&table.value), &column.value),
+            [] => Err(QubeError::QueryParse("Empty identifier".to_string())),
+        },
+        Expr::Value(value) => literal(value),
+        Expr::Nested(inner) => evaluate(inner, row),
+        Expr::UnaryOp { op, expr } => {
+            let value = evaluate(expr, row)?;
+            match op {
+                UnaryOperator::Not => Ok(not(&value)),
+                UnaryOperator::Minus => negate(value),
+                UnaryOperator::Plus => Ok(value),
+                other => Err(QubeError::Unsupported(format!("unary operator {}", other))),
+            }
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let left = evaluate(left, row)?;
+            let right = evaluate(right, row)?;
+            binary_op(&left, op, &right)
+        }
+        Expr::IsNull(inner) => Ok(Value::Boolean(evaluate(inner, row)?.is_null())),
+        Expr::IsNotNull(inner) => Ok(Value::Boolean(!evaluate(inner, row)?.is_null())),
+        Expr::IsTrue(inner) => Ok(Value::Boolean(truth(&evaluate(inner, row)?) == Some(true))),
+        Expr::IsFalse(inner) => Ok(Value::Boolean(truth(&evaluate(inner, row)?) == Some(false))),
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let value = evaluate(expr, row)?;
+            let low = binary_op(&value, &BinaryOperator::GtEq, &evaluate(low, row)?)?;
+            let high = binary_op(&value, &BinaryOperator::LtEq, &evaluate(high, row)?)?;
+            let result = binary_op(&low, &BinaryOperator::And, &high)?;
+            Ok(if *negated { not(&result) } else { result })
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = evaluate(expr, row)?;
+            if value.is_null() {
+                return Ok(Value::Null);
+            }
+            let mut saw_null = false;
+            for item in list {
+                match values_equal(&value, &evaluate(item, row)?) {
+                    Some(true) => return Ok(Value::Boolean(!*negated)),
+                    Some(false) => {}
+                    None => saw_null = true,
+                }
+            }
+            Ok(if saw_null {
+                Value::Null
+            } else {
+                Value::Boolean(*negated)
+            })
+        }
+        Expr::Like {
+            negated,
+            expr,
+            pattern,
+            ..
+        } => like(expr, pattern, *negated, false, row),
+        Expr::ILike {
+            negated,
+            expr,
+            pattern,
+            ..
+        } => like(expr, pattern, *negated, true, row),
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => case(
+            operand.as_deref(),
+            conditions,
+            results,
+            else_result.as_deref(),
+            row,
+        ),
+        Expr::Function(function) => call_function(function, row),
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+            ..
+        } => {
+            let mut args = vec![
+                evaluate(expr, row)?,
+                match substring_from {
+                    Some(from) => evaluate(from, row)?,
+                    None => Value::Int64(1),
+                },
+            ];
+            if let Some(length) = substring_for {
+                args.push(evaluate(length, row)?);
+            }
+            functions::call("SUBSTRING", args)
+        }
+        Expr::Trim {
+            expr,
+            trim_where,
+            trim_what,
+        } => {
+            let characters = trim_what
+                .as_ref()
+                .map(|what| evaluate(what, row))
+                .transpose()?;
+            functions::trim(evaluate(expr, row)?, characters, trim_where.as_ref())
+        }
+        Expr::Ceil {
+            expr,
+            field: DateTimeField::NoDateTime,
+        } => functions::call("CEIL", vec![evaluate(expr, row)?]),
+        Expr::Floor {
+            expr,
+            field: DateTimeField::NoDateTime,
+        } => functions::call("FLOOR", vec![evaluate(expr, row)?]),
+        Expr::Extract { field, expr } => match evaluate(expr, row)? {
+            Value::Null => Ok(Value::Null),
+            value => functions::extract(&field.to_string(), &value),
+        },
+        Expr::Interval(interval) => {
+            // Normalized to the `'<n> <unit>'` form DATE_ADD reads
+            let value = evaluate(&interval.value, row)?;
+            Ok(match &interval.leading_field {
+                _ if value.is_null() => Value::Null,
+                Some(unit) => Value::String(format!("{} {}", value, unit)),
+                None => Value::String(value.to_string()),
+            })
+        }
+        other => Err(QubeError::Unsupported(format!("expression {}", other))),
+    }
+}
+
+/// Whether a value counts as TRUE in a WHERE clause
+pub fn is_true(value: &Value) -> bool {
+    truth(value) == Some(true)
+}
+
+/// Convert a SQL literal into a value
+pub fn literal(value: &SqlValue) -> QubeResult<Value> {
+    match value {
+        SqlValue::Number(n, _) => {
+            if let Ok(v) = n.parse::<i64>() {
+                Ok(Value::Int64(v))
+            } else {
+                n.parse::<f64>()
+                    .map(Value::Float64)
+                    .map_err(|_| QubeError::QueryParse(format!("Invalid number: {}", n)))
+            }
+        }
+        SqlValue::SingleQuotedString(s)
+        | SqlValue::DoubleQuotedString(s)
+        | SqlValue::NationalStringLiteral(s)
+        | SqlValue::EscapedStringLiteral(s) => Ok(Value::String(s.clone())),
+        SqlValue::Boolean(b) => Ok(Value::Boolean(*b)),
+        SqlValue::Null => Ok(Value::Null),
+        SqlValue::HexStringLiteral(hex) => decode_hex(hex)
+            .map(Value::Binary)
+            .ok_or_else(|| QubeError::QueryParse(format!("Invalid hex literal: X'{}'", hex))),
+        other => Err(QubeError::Unsupported(format!("literal {}", other))),
+    }
+}
+
+/// Convert a value so it can be stored in a column of the given type
+pub fn coerce(value: Value, data_type: &DataType) -> QubeResult<Value> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+
+    let mismatch = |value: &Value| {
+        QubeError::ConstraintViolation(format!(
+            "Cannot store value '{}' in column of type {}",
+            value, data_type
+        ))
+    };
+    let out_of_range = |value: &Value| {
+        QubeError::ConstraintViolation(format!(
+            "Value {} out of range for type {}",
+            value, data_type
+        ))
+    };
+
+    let coerced = match data_type {
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32 => {
+            let v = integer_of(&value).ok_or_else(|| mismatch(&value))?;
+            match data_type {
+                DataType::Int8 => i8::try_from(v).map(Value::Int8).ok(),
+                DataType::Int16 => i16::try_from(v).map(Value::Int16).ok(),
+                DataType::Int32 => i32::try_from(v).map(Value::Int32).ok(),
+                DataType::Int64 => Some(Value::Int64(v)),
+                DataType::UInt8 => u8::try_from(v).map(Value::UInt8).ok(),
+                DataType::UInt16 => u16::try_from(v).map(Value::UInt16).ok(),
+                _ => u32::try_from(v).map(Value::UInt32).ok(),
+            }
+            .ok_or_else(|| out_of_range(&value))?
+        }
+        DataType::UInt64 => match &value {
+            Value::UInt64(v) => Value::UInt64(*v),
+            other => {
+                let v = integer_of(other).ok_or_else(|| mismatch(other))?;
+                Value::UInt64(u64::try_from(v).map_err(|_| out_of_range(other))?)
+            }
+        },
+        DataType::Float32 => match &value {
+            Value::String(s) => Value::Float32(s.trim().parse().map_err(|_| mismatch(&value))?),
+            other => Value::Float32(other.as_f64().ok_or_else(|| mismatch(other))? as f32),
+        },
+        DataType::Float64 => match &value {
+            Value::String(s) => Value::Float64(s.trim().parse().map_err(|_| mismatch(&value))?),
+            other => Value::Float64(other.as_f64().ok_or_else(|| mismatch(other))?),
+        },
+        DataType::String | DataType::Text => match value {
+            Value::String(s) => Value::String(s),
+            other => Value::String(other.to_string()),
+        },
+        DataType::Boolean => match &value {
+            Value::Boolean(b) => Value::Boolean(*b),
+            Value::String(s) => match s.to_lowercase().as_str() {
+                "true" | "t" | "1" => Value::Boolean(true),
+                "false" | "f" | "0" => Value::Boolean(false),
+                _ => return Err(mismatch(&value)),
+            },
+            other => Value::Boolean(other.as_i64().ok_or_else(|| mismatch(other))? != 0),
+        },
+        DataType::Json => match value {
+            Value::Json(json) => Value::Json(json),
+            Value::String(s) => Value::Json(serde_json::from_str(&s).map_err(|e| {
+                QubeError::ConstraintViolation(format!("Invalid JSON document: {}", e))
+            })?),
+            other => Value::Json(to_json(&other)),
+        },
+        DataType::Vector { dimensions } => {
+            let vector = match &value {
+                Value::Vector(v) => v.clone(),
+                Value::String(s) => {
+                    serde_json::from_str::<Vec<f32>>(s).map_err(|_| mismatch(&value))?
+                }
+                Value::Json(json) => serde_json::from_value::<Vec<f32>>(json.clone())
+                    .map_err(|_| mismatch(&value))?,
+                other => return Err(mismatch(other)),
+            };
+            if vector.len() != *dimensions {
+                return Err(QubeError::ConstraintViolation(format!(
+                    "Vector dimension mismatch: expected {}, got {}",
+                    dimensions,
+                    vector.len()
+                )));
+            }
+            Value::Vector(vector)
+        }
+        DataType::Timestamp => match &value {
+            Value::Timestamp(v) => Value::Timestamp(*v),
+            Value::String(s) => {
+                Value::Timestamp(parse_timestamp(s).ok_or_else(|| mismatch(&value))?)
+            }
+            other => Value::Timestamp(other.as_i64().ok_or_else(|| mismatch(other))?),
+        },
+        DataType::Date | DataType::Time => match value {
+            Value::String(s) => Value::String(s),
+            other => return Err(mismatch(&other)),
+        },
+        DataType::Binary | DataType::Blob => match value {
+            Value::Binary(b) => Value::Binary(b),
+            Value::String(s) => Value::Binary(s.into_bytes()),
+            other => return Err(mismatch(&other)),
+        },
+        DataType::Counter => match value {
+            Value::Counter(counter) => Value::Counter(counter),
+            Value::String(s) => Value::Counter(
+                serde_json::from_str::<Counter>(&s).map_err(|_| mismatch(&Value::String(s)))?,
+            ),
+            other => Value::Counter(Counter::from(
+                other.as_i64().ok_or_else(|| mismatch(&other))?,
+            )),
+        },
+        DataType::GraphNode | DataType::GraphEdge => value,
+    };
+    Ok(coerced)
+}
+
+/// Convert a value into a SQL literal that evaluates (after coercion to the
+/// target column type) back to the same value. Used to bind statement
+/// parameters.
+pub fn to_literal(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Boolean(b) => SqlValue::Boolean(*b),
+        Value::String(s) => SqlValue::SingleQuotedString(s.clone()),
+        Value::Binary(b) => {
+            SqlValue::HexStringLiteral(b.iter().map(|byte| format!("{:02X}", byte)).collect())
+        }
+        Value::Json(_) | Value::Vector(_) => {
+            SqlValue::SingleQuotedString(to_json(value).to_string())
+        }
+        // The whole state, so binding a counter doesn't lose its replicas
+        Value::Counter(counter) => {
+            SqlValue::SingleQuotedString(serde_json::to_string(counter).unwrap_or_default())
+        }
+        Value::Float32(v) => SqlValue::Number(v.to_string(), false),
+        Value::Float64(v) => SqlValue::Number(v.to_string(), false),
+        Value::UInt64(v) => SqlValue::Number(v.to_string(), false),
+        other => SqlValue::Number(other.as_i64().unwrap_or_default().to_string(), false),
+    }
+}
+
+/// How numbers are written when converting values to JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberFormat {
+    /// JSON numbers. Clients that parse them into doubles lose precision
+    /// on integers beyond 2^53.
+    #[default]
+    Native,
+    /// Floats, and integers beyond 2^53, as strings holding their exact
+    /// decimal value
+    String,
+    /// Floats as strings rounded to this many decimal places
+    Fixed(u32),
+}
+
+/// Largest integer a double represents exactly
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Convert a value into its natural JSON representation
+pub fn to_json(value: &Value) -> serde_json::Value {
+    to_json_with(value, NumberFormat::Native)
+}
+
+/// Convert a value into JSON, writing numbers as `format` says
+pub fn to_json_with(value: &Value, format: NumberFormat) -> serde_json::Value {
+    let float = |v: f64, exact: String| match format {
+        // Go through the shortest representation, so an f32 such as 0.1
+        // is not widened to 0.10000000149011612
+        NumberFormat::Native => serde_json::json!(exact.parse::<f64>().unwrap_or(v)),
+        NumberFormat::String => serde_json::Value::String(exact),
+        NumberFormat::Fixed(places) => {
+            serde_json::Value::String(format!("{:.*}", places as usize, v))
+        }
+    };
+    let integer = |number: serde_json::Value, magnitude: u64| match format {
+        NumberFormat::String if magnitude > MAX_SAFE_INTEGER => {
+            serde_json::Value::String(number.to_string())
+        }
+        _ => number,
+    };
+
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Float32(v) => float(*v as f64, v.to_string()),
+        Value::Float64(v) => float(*v, v.to_string()),
+        Value::UInt64(v) => integer(serde_json::json!(v), *v),
+        Value::Json(json) => json.clone(),
+        Value::Boolean(b) => serde_json::Value::Bool(*b),
+        Value::Vector(v) => serde_json::json!(v),
+        Value::Binary(b) => serde_json::json!(b),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        other => match other.as_i64() {
+            Some(v) => integer(serde_json::json!(v), v.unsigned_abs()),
+            None => serde_json::Value::Null,
+        },
+    }
+}
+
+/// Convert a JSON value (e.g. from a client request) into a value. Integers
+/// keep their full range: `Int32` when they fit, otherwise `Int64`, or
+/// `UInt64` above `i64::MAX`. When the target column type is known the
+/// result is coerced to it, failing instead of truncating.
+pub fn from_json(json: &serde_json::Value, data_type: Option<&DataType>) -> QubeResult<Value> {
+    let value = match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => {
+            if let Some(v) = n.as_i64() {
+                i32::try_from(v)
+                    .map(Value::Int32)
+                    .unwrap_or(Value::Int64(v))
+            } else if let Some(v) = n.as_u64() {
+                Value::UInt64(v)
+            } else {
+                n.as_f64().map(Value::Float64).ok_or_else(|| {
+                    QubeError::ConstraintViolation(format!("Unrepresentable number: {}", n))
+                })?
+            }
+        }
+        serde_json::Value::String(s) => Value::String(s.clone()),
+        other => Value::Json(other.clone()),
+    };
+
+    match data_type {
+        Some(data_type) => coerce(value, data_type),
+        None => Ok(value),
+    }
+}
+
+/// Parse a timestamp string into milliseconds since the Unix epoch
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let s = s.trim();
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(dt.timestamp_millis());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(s, format) {
+            return Some(dt.and_utc().timestamp_millis());
+        }
+    }
+    chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|dt| dt.and_utc().timestamp_millis())
+}
+
+/// Match text against a SQL LIKE pattern (`%` and `_` wildcards)
+pub fn like_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Classic wildcard matching with backtracking on the last `%`
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '_' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '%' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+/// SQL equality, `None` when either side is NULL
+pub fn values_equal(left: &Value, right: &Value) -> Option<bool> {
+    if left.is_null() || right.is_null() {
+        return None;
+    }
+    Some(match left.compare(right) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => left == right,
+    })
+}
+
+/// Look up a column, accepting both `column` and `table.column` keys
+fn lookup_column(row: &Row, table: Option<&str>, column: &str) -> QubeResult<Value> {
+    if let Some(table) = table {
+        if let Some(value) = row.get(&format!("{}.{}", table, column)) {
+            return Ok(value.clone());
+        }
+    }
+    row.get(column).cloned().ok_or_else(|| {
+        QubeError::ColumnNotFound(match table {
+            Some(table) => format!("{}.{}", table, column),
+            None => column.to_string(),
+        })
+    })
+}
+
+/// Three-valued truth of a value
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Boolean(b) => Some(*b),
+        Value::Null => None,
+        other => other.as_f64().map(|v| v != 0.0),
+    }
+}
+
+fn not(value: &Value) -> Value {
+    match truth(value) {
+        Some(b) => Value::Boolean(!b),
+        None => Value::Null,
+    }
+}
+
+fn negate(value: Value) -> QubeResult<Value> {
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Float32(v) => Ok(Value::Float32(-v)),
+        Value::Float64(v) => Ok(Value::Float64(-v)),
+        other => match other.as_i64() {
+            Some(v) => v.checked_neg().map(Value::Int64).ok_or_else(overflow),
+            None => Err(QubeError::QueryParse(format!("Cannot negate '{}'", other))),
+        },
+    }
+}
+
+fn integer_of(value: &Value) -> Option<i64> {
+    match value {
+        Value::Float32(v) => whole_number(*v as f64),
+        Value::Float64(v) => whole_number(*v),
+        Value::Boolean(b) => Some(*b as i64),
+        Value::String(s) => s.trim().parse().ok(),
+        other => other.as_i64(),
+    }
+}
+
+/// A float as an integer, if it is whole and within i64 range
+fn whole_number(v: f64) -> Option<i64> {
+    // `i64::MAX as f64` rounds up to 2^63, so the upper bound is exclusive
+    if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 {
+        Some(v as i64)
+    } else {
+        None
+    }
+}
+
+fn like(
+    expr: &Expr,
+    pattern: &Expr,
+    negated: bool,
+    case_insensitive: bool,
+    row: &Row,
+) -> QubeResult<Value> {
+    let value = evaluate(expr, row)?;
+    let pattern = evaluate(pattern, row)?;
+    if value.is_null() || pattern.is_null() {
+        return Ok(Value::Null);
+    }
+
+    let (pattern, text) = if case_insensitive {
+        (
+            pattern.to_string().to_lowercase(),
+            value.to_string().to_lowercase(),
+        )
+    } else {
+        (pattern.to_string(), value.to_string())
+    };
+    Ok(Value::Boolean(like_match(&pattern, &text) != negated))
+}
+
+/// Evaluate a scalar function call
+fn call_function(function: &Function, row: &Row) -> QubeResult<Value> {
+    let name = function.name.to_string();
+    if AggregateFunction::from_name(&name).is_some() {
+        return Err(QubeError::QueryParse(format!(
+            "Aggregate function {} is not allowed here",
+            name
+        )));
+    }
+    if function.over.is_some() || function.distinct || !function.order_by.is_empty() {
+        return Err(QubeError::Unsupported(format!(
+            "call to {}: {}",
+            name, function
+        )));
+    }
+
+    let args = function
+        .args
+        .iter()
+        .map(|arg| match arg {
+            FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => evaluate(expr, row),
+            other => Err(QubeError::Unsupported(format!(
+                "argument {} to {}",
+                other, name
+            ))),
+        })
+        .collect::<QubeResult<Vec<_>>>()?;
+    functions::call(&name, args)
+}
+
+/// Evaluate `CASE [operand] WHEN ... THEN ... [ELSE ...] END`. The first
+/// matching branch wins; with no match and no ELSE the result is NULL.
+fn case(
+    operand: Option<&Expr>,
+    conditions: &[Expr],
+    results: &[Expr],
+    else_result: Option<&Expr>,
+    row: &Row,
+) -> QubeResult<Value> {
+    let operand = operand.map(|operand| evaluate(operand, row)).transpose()?;
+    let mut chosen = else_result;
+    for (condition, result) in conditions.iter().zip(results) {
+        let condition = evaluate(condition, row)?;
+        let matched = match &operand {
+            // CASE x WHEN v compares x = v, so a NULL operand matches nothing
+            Some(operand) => values_equal(operand, &condition) == Some(true),
+            None => is_true(&condition),
+        };
+        if matched {
+            chosen = Some(result);
+            break;
+        }
+    }
+
+    let value = match chosen {
+        Some(result) => evaluate(result, row)?,
+        None => return Ok(Value::Null),
+    };
+    // Branches that fail to evaluate have no type to contribute
+    let branches = results
+        .iter()
+        .chain(else_result)
+        .filter_map(|result| evaluate(result, row).ok());
+    common_type(value, branches)
+}
+
+/// Convert a CASE result to the common type of every branch: FLOAT64 when
+/// numeric branches include a float, INT64 when integer branches differ in
+/// width, and TEXT when the branches mix unrelated types
+fn common_type(value: Value, branches: impl Iterator<Item = Value>) -> QubeResult<Value> {
+    if value.is_null() {
+        return Ok(value);
+    }
+
+    let is_float = |v: &Value| matches!(v, Value::Float32(_) | Value::Float64(_));
+    let (mut float, mut wide, mut text) = (false, false, false);
+    for branch in branches.filter(|v| !v.is_null()) {
+        if std::mem::discriminant(&branch) == std::mem::discriminant(&value) {
+            continue;
+        }
+        if value.is_numeric() && branch.is_numeric() {
+            if is_float(&value) || is_float(&branch) {
+                float = true;
+            } else {
+                wide = true;
+            }
+        } else {
+            text = true;
+        }
+    }
+
+    if text {
+        coerce(value, &DataType::Text)
+    } else if float {
+        coerce(value, &DataType::Float64)
+    } else if wide && value.as_i64().is_some() {
+        coerce(value, &DataType::Int64)
+    } else {
+        Ok(value)
+    }
+}
+
+fn binary_op(left: &Value, op: &BinaryOperator, right: &Value) -> QubeResult<Value> {
+    match op {
+        BinaryOperator::And => Ok(match (truth(left), truth(right)) {
+            (Some(false), _) | (_, Some(false)) => Value::Boolean(false),
+            (Some(true), Some(true)) => Value::Boolean(true),
+            _ => Value::Null,
+        }),
+        BinaryOperator::Or => Ok(match (truth(left), truth(right)) {
+            (Some(true), _) | (_, Some(true)) => Value::Boolean(true),
+            (Some(false), Some(false)) => Value::Boolean(false),
+            _ => Value::Null,
+        }),
+        BinaryOperator::Eq => Ok(bool_or_null(values_equal(left, right))),
+        BinaryOperator::NotEq => Ok(bool_or_null(values_equal(left, right).map(|eq| !eq))),
+        BinaryOperator::Lt => Ok(ordering(left, right, |o| o == Ordering::Less)),
+        BinaryOperator::LtEq => Ok(ordering(left, right, |o| o != Ordering::Greater)),
+        BinaryOperator::Gt => Ok(ordering(left, right, |o| o == Ordering::Greater)),
+        BinaryOperator::GtEq => Ok(ordering(left, right, |o| o != Ordering::Less)),
+        BinaryOperator::StringConcat => {
+            if left.is_null() || right.is_null() {
+                Ok(Value::Null)
+            } else {
+                Ok(Value::String(format!("{}{}", left, right)))
+            }
+        }
+        BinaryOperator::Plus
+        | BinaryOperator::Minus
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulo => arithmetic(left, op, right),
+        other => Err(QubeError::Unsupported(format!("operator {}", other))),
+    }
+}
+
+fn bool_or_null(value: Option<bool>) -> Value {
+    value.map(Value::Boolean).unwrap_or(Value::Null)
+}
+
+fn ordering(left: &Value, right: &Value, test: impl Fn(Ordering) -> bool) -> Value {
+    bool_or_null(left.compare(right).map(test))
+}
+
+/// Integer arithmetic never wraps; a result outside the 64-bit range is an
+/// error
+pub fn overflow() -> QubeError {
+    QubeError::ConstraintViolation("integer overflow".to_string())
+}
+
+fn arithmetic(left: &Value, op: &BinaryOperator, right: &Value) -> QubeResult<Value> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    if !left.is_numeric() || !right.is_numeric() {
+        return Err(QubeError::QueryParse(format!(
+            "Cannot apply {} to '{}' and '{}'",
+            op, left, right
+        )));
+    }
+
+    let is_float = |v: &Value| matches!(v, Value::Float32(_) | Value::Float64(_));
+    if let (false, false, Some(a), Some(b)) = (
+        is_float(left),
+        is_float(right),
+        left.as_i64(),
+        right.as_i64(),
+    ) {
+        let result = match op {
+            BinaryOperator::Plus => a.checked_add(b),
+            BinaryOperator::Minus => a.checked_sub(b),
+            BinaryOperator::Multiply => a.checked_mul(b),
+            // Division by zero yields NULL, as in MySQL
+            BinaryOperator::Divide if b == 0 => return Ok(Value::Null),
+            BinaryOperator::Divide => a.checked_div(b),
+            BinaryOperator::Modulo if b == 0 => return Ok(Value::Null),
+            _ => a.checked_rem(b),
+        };
+        return result.map(Value::Int64).ok_or_else(overflow);
+    }
+
+    let (a, b) = match (left.as_f64(), right.as_f64()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(Value::Null),
+    };
+    Ok(match op {
+        BinaryOperator::Plus => Value::Float64(a + b),
+        BinaryOperator::Minus => Value::Float64(a - b),
+        BinaryOperator::Multiply => Value::Float64(a * b),
+        BinaryOperator::Divide if b == 0.0 => Value::Null,
+        BinaryOperator::Divide => Value::Float64(a / b),
+        BinaryOperator::Modulo if b == 0.0 => Value::Null,
+        _ => Value::Float64(a % b),
+    })
+}
+
+/// Decode a string of hex digit pairs
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryEngine;
+    use serde_json::json;
+
+    #[test]
+    fn json_integers_keep_their_range() {
+        assert_eq!(from_json(&json!(7), None).unwrap(), Value::Int32(7));
+        assert_eq!(
+            from_json(&json!(9_007_199_254_740_993_i64), None).unwrap(),
+            Value::Int64(9_007_199_254_740_993)
+        );
+        assert_eq!(
+            from_json(&json!(u64::MAX), None).unwrap(),
+            Value::UInt64(u64::MAX)
+        );
+        assert_eq!(from_json(&json!(1.5), None).unwrap(), Value::Float64(1.5));
+    }
+
+    #[test]
+    fn json_values_take_the_column_type() {
+        assert_eq!(
+            from_json(&json!(7), Some(&DataType::Int64)).unwrap(),
+            Value::Int64(7)
+        );
+        assert_eq!(
+            from_json(&json!(u64::MAX), Some(&DataType::UInt64)).unwrap(),
+            Value::UInt64(u64::MAX)
+        );
+        // Out of range for the column: an error, not a truncated value
+        let error = from_json(&json!(3_000_000_000_i64), Some(&DataType::Int32)).unwrap_err();
+        assert!(matches!(error, QubeError::ConstraintViolation(_)));
+        assert!(from_json(&json!(u64::MAX), Some(&DataType::Int64)).is_err());
+    }
+
+    #[test]
+    fn large_json_integers_survive_an_insert() {
+        let engine = QueryEngine::new();
+        engine.execute_script("CREATE TABLE t (v BIGINT)").unwrap();
+        let value = from_json(&json!(9_007_199_254_740_993_i64), Some(&DataType::Int64)).unwrap();
+        let sql = format!("INSERT INTO t VALUES ({})", to_literal(&value));
+        engine.execute_script(&sql).unwrap();
+        let rows = engine
+            .execute_script("SELECT v FROM t")
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows;
+        assert_eq!(rows[0]["v"], Value::Int64(9_007_199_254_740_993));
+    }
+
+    #[test]
+    fn integer_arithmetic_fails_instead_of_wrapping() {
+        let sum = arithmetic(
+            &Value::Int64(i64::MAX),
+            &BinaryOperator::Plus,
+            &Value::Int32(1),
+        );
+        assert_eq!(sum.unwrap_err().to_string(), overflow().to_string());
+        let product = arithmetic(
+            &Value::Int64(i64::MIN),
+            &BinaryOperator::Multiply,
+            &Value::Int32(-1),
+        );
+        assert!(matches!(product, Err(QubeError::ConstraintViolation(_))));
+        assert_eq!(
+            arithmetic(
+                &Value::Int32(i32::MAX),
+                &BinaryOperator::Plus,
+                &Value::Int32(1)
+            )
+            .unwrap(),
+            Value::Int64(i32::MAX as i64 + 1)
+        );
+    }
+
+    #[test]
+    fn update_increment_past_the_column_range_fails() {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT, hits INT); INSERT INTO t VALUES (1, 2147483647)",
+            )
+            .unwrap();
+        let error = engine
+            .execute_script("UPDATE t SET hits = hits + 1 WHERE id = 1")
+            .unwrap_err();
+        assert!(
+            matches!(error, QubeError::ConstraintViolation(_)),
+            "{}",
+            error
+        );
+
+        let rows = engine
+            .execute_script("SELECT hits FROM t")
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows;
+        assert_eq!(rows[0]["hits"], Value::Int32(i32::MAX));
+    }
+
+    #[test]
+    fn numbers_are_written_in_the_requested_format() {
+        let big = Value::Int64(9_007_199_254_740_993);
+        let values = [
+            Value::Float32(0.1),
+            Value::Float64(2.0 / 3.0),
+            Value::Int32(7),
+            big,
+        ];
+        let encode = |format| -> Vec<serde_json::Value> {
+            values
+                .iter()
+                .map(|value| to_json_with(value, format))
+                .collect()
+        };
+        assert_eq!(
+            encode(NumberFormat::Native),
+            [
+                json!(0.1),
+                json!(0.6666666666666666),
+                json!(7),
+                json!(9_007_199_254_740_993_i64)
+            ]
+        );
+        assert_eq!(
+            encode(NumberFormat::String),
+            [
+                json!("0.1"),
+                json!("0.6666666666666666"),
+                json!(7),
+                json!("9007199254740993")
+            ]
+        );
+        assert_eq!(
+            encode(NumberFormat::Fixed(2)),
+            [
+                json!("0.10"),
+                json!("0.67"),
+                json!(7),
+                json!(9_007_199_254_740_993_i64)
+            ]
+        );
+    }
+
+    #[test]
+    fn number_formats_are_read_from_json() {
+        let format = |json| serde_json::from_value::<NumberFormat>(json).unwrap();
+        assert_eq!(format(json!("native")), NumberFormat::Native);
+        assert_eq!(format(json!("string")), NumberFormat::String);
+        assert_eq!(format(json!({ "fixed": 3 })), NumberFormat::Fixed(3));
+        assert!(serde_json::from_value::<NumberFormat>(json!("exact")).is_err());
+    }
+
+    /// Run `sql` on a table of scores 5, 50 and NULL, returning the `v`
+    /// column ordered by id
+    fn over_scores(sql: &str) -> Vec<Value> {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE s (id INT PRIMARY KEY, score INT);
+                 INSERT INTO s VALUES (1, 5), (2, 50), (3, NULL)",
+            )
+            .unwrap();
+        engine
+            .execute_script(sql)
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows
+            .iter()
+            .map(|row| row["v"].clone())
+            .collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<Value> {
+        values
+            .iter()
+            .map(|v| Value::String(v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn case_picks_the_first_matching_branch() {
+        let searched = over_scores(
+            "SELECT CASE WHEN score >= 10 THEN 'high' WHEN score >= 0 THEN 'low'
+                         ELSE 'none' END AS v
+             FROM s ORDER BY id",
+        );
+        assert_eq!(searched, strings(&["low", "high", "none"]));
+
+        let simple = over_scores(
+            "SELECT CASE score WHEN 5 THEN 'five' WHEN 50 THEN 'fifty' END AS v FROM s ORDER BY id",
+        );
+        assert_eq!(simple[..2], strings(&["five", "fifty"]));
+        // No branch matches NULL and there is no ELSE
+        assert_eq!(simple[2], Value::Null);
+
+        let filtered = over_scores(
+            "SELECT id AS v FROM s
+             WHERE CASE WHEN score IS NULL THEN 0 ELSE score END > 1 ORDER BY id",
+        );
+        assert_eq!(filtered, [Value::Int32(1), Value::Int32(2)]);
+    }
+
+    #[test]
+    fn string_functions_are_called_from_queries() {
+        let labels = over_scores(
+            "SELECT CONCAT(UPPER(SUBSTRING('score', 1, 1)), '-', id) AS v FROM s ORDER BY id",
+        );
+        assert_eq!(labels, strings(&["S-1", "S-2", "S-3"]));
+
+        let filtered =
+            over_scores("SELECT id AS v FROM s WHERE LENGTH(TRIM(CONCAT(' ', score))) = 2");
+        assert_eq!(filtered, [Value::Int32(2)]);
+    }
+
+    #[test]
+    fn date_and_math_syntax_reaches_the_functions() {
+        let engine = QueryEngine::new();
+        let result = engine
+            .execute_script(
+                "SELECT EXTRACT(YEAR FROM '2024-05-15') AS y,
+                        DATE_ADD('2024-05-15', INTERVAL 1 DAY) AS next,
+                        CEIL(1.5) AS c, FLOOR(-1.5) AS f",
+            )
+            .unwrap()
+            .pop()
+            .unwrap();
+        let row = &result.rows[0];
+        assert_eq!(row["y"], Value::Int64(2024));
+        // 2024-05-16 00:00 UTC
+        assert_eq!(row["next"], Value::Timestamp(1_715_817_600_000));
+        assert_eq!(row["c"], Value::Float64(2.0));
+        assert_eq!(row["f"], Value::Float64(-2.0));
+    }
+}
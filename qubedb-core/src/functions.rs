@@ -0,0 +1,541 @@
+//! Scalar SQL functions for QubeDB queries
+//!
+//! Functions called by name from SELECT lists, WHERE clauses and other
+//! expressions, e.g. `WHERE UPPER(name) = 'ALICE'`. Arguments are evaluated
+//! before the call, and a NULL argument makes the result NULL.
+//!
+//! Date functions accept TIMESTAMP values (milliseconds since the Unix
+//! epoch) as well as date and date-time strings, and return TIMESTAMPs.
+//! Intervals are written as `'<n> <unit>'` strings, which is also what an
+//! `INTERVAL 1 DAY` expression evaluates to.
+
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::types::{DefaultValue, Value};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveTime, Timelike, Utc};
+use sqlparser::ast::TrimWhereField;
+use std::ops::RangeInclusive;
+
+/// Call the scalar function `name` with already evaluated arguments
+pub fn call(name: &str, args: Vec<Value>) -> QubeResult<Value> {
+    let name = name.to_uppercase();
+    let arity =
+        arity(&name).ok_or_else(|| QubeError::QueryParse(format!("Unknown function: {}", name)))?;
+    check_arity(&name, args.len(), arity)?;
+    if args.iter().any(Value::is_null) {
+        return Ok(Value::Null);
+    }
+
+    match name.as_str() {
+        "CONCAT" => Ok(Value::String(
+            args.iter().map(|arg| arg.to_string()).collect(),
+        )),
+        "UPPER" => Ok(Value::String(args[0].to_string().to_uppercase())),
+        "LOWER" => Ok(Value::String(args[0].to_string().to_lowercase())),
+        "LENGTH" | "CHAR_LENGTH" | "CHARACTER_LENGTH" => Ok(Value::Int64(match &args[0] {
+            Value::Binary(bytes) => bytes.len() as i64,
+            other => other.to_string().chars().count() as i64,
+        })),
+        "SUBSTRING" | "SUBSTR" => substring(&args[0], &args[1], args.get(2)),
+        "REPLACE" => {
+            let from = args[1].to_string();
+            let text = args[0].to_string();
+            Ok(Value::String(if from.is_empty() {
+                text
+            } else {
+                text.replace(&from, &args[2].to_string())
+            }))
+        }
+        "LTRIM" => trim(args[0].clone(), None, Some(&TrimWhereField::Leading)),
+        "RTRIM" => trim(args[0].clone(), None, Some(&TrimWhereField::Trailing)),
+        "TRIM" => trim(args[0].clone(), args.get(1).cloned(), None),
+        "ABS" => abs(&args[0]),
+        "ROUND" => round(&args[0], args.get(1)),
+        "CEIL" | "CEILING" => rounded(&name, &args[0], f64::ceil),
+        "FLOOR" => rounded(&name, &args[0], f64::floor),
+        "MOD" => modulo(&args[0], &args[1]),
+        "POWER" | "POW" => {
+            let base = number(&name, &args[0])?;
+            Ok(Value::Float64(base.powf(number(&name, &args[1])?)))
+        }
+        "NOW" | "CURRENT_TIMESTAMP" => Ok(DefaultValue::CurrentTimestamp.evaluate()),
+        "CURRENT_DATE" => Ok(DefaultValue::CurrentDate.evaluate()),
+        "CURRENT_TIME" => Ok(DefaultValue::CurrentTime.evaluate()),
+        "DATE_TRUNC" => date_trunc(&args[0].to_string(), &args[1]),
+        "EXTRACT" => extract(&args[0].to_string(), &args[1]),
+        "DATE_ADD" => date_add(&args[0], &args[1], false),
+        "DATE_SUB" => date_add(&args[0], &args[1], true),
+        _ => unreachable!("arity is checked for every known function"),
+    }
+}
+
+/// The number of arguments a scalar function takes, or `None` if no
+/// function has this (upper-case) name
+pub fn arity(name: &str) -> Option<RangeInclusive<usize>> {
+    Some(match name {
+        "CONCAT" => 1..=usize::MAX,
+        "UPPER" | "LOWER" | "LENGTH" | "CHAR_LENGTH" | "CHARACTER_LENGTH" | "LTRIM" | "RTRIM" => {
+            1..=1
+        }
+        "TRIM" => 1..=2,
+        "SUBSTRING" | "SUBSTR" => 2..=3,
+        "REPLACE" => 3..=3,
+        "ABS" | "CEIL" | "CEILING" | "FLOOR" => 1..=1,
+        "ROUND" => 1..=2,
+        "MOD" | "POWER" | "POW" => 2..=2,
+        "NOW" | "CURRENT_TIMESTAMP" | "CURRENT_DATE" | "CURRENT_TIME" => 0..=0,
+        "DATE_TRUNC" | "EXTRACT" | "DATE_ADD" | "DATE_SUB" => 2..=2,
+        _ => return None,
+    })
+}
+
+/// `TRIM([BOTH | LEADING | TRAILING] [characters FROM] text)`. Without
+/// `characters`, spaces are removed.
+pub fn trim(
+    text: Value,
+    characters: Option<Value>,
+    side: Option<&TrimWhereField>,
+) -> QubeResult<Value> {
+    if text.is_null() || characters.as_ref().is_some_and(Value::is_null) {
+        return Ok(Value::Null);
+    }
+    let characters: Vec<char> = match characters {
+        Some(characters) => characters.to_string().chars().collect(),
+        None => vec![' '],
+    };
+    let text = text.to_string();
+    let trimmed = match side {
+        Some(TrimWhereField::Leading) => text.trim_start_matches(characters.as_slice()),
+        Some(TrimWhereField::Trailing) => text.trim_end_matches(characters.as_slice()),
+        _ => text.trim_matches(characters.as_slice()),
+    };
+    Ok(Value::String(trimmed.to_string()))
+}
+
+/// `SUBSTRING(text, start [, length])` with a 1-based `start`. Positions
+/// before the first character count towards `length`, as in PostgreSQL.
+fn substring(text: &Value, start: &Value, length: Option<&Value>) -> QubeResult<Value> {
+    let start = integer("SUBSTRING", start)?;
+    let end = match length {
+        Some(length) => {
+            let length = integer("SUBSTRING", length)?;
+            if length < 0 {
+                return Err(QubeError::QueryParse(
+                    "Negative substring length not allowed".to_string(),
+                ));
+            }
+            start.saturating_add(length)
+        }
+        None => i64::MAX,
+    };
+
+    let text = text.to_string();
+    let result = text
+        .chars()
+        .zip(1i64..)
+        .filter(|(_, position)| *position >= start && *position < end)
+        .map(|(c, _)| c)
+        .collect();
+    Ok(Value::String(result))
+}
+
+/// `ABS(x)`, failing for the one integer whose absolute value overflows
+fn abs(value: &Value) -> QubeResult<Value> {
+    match value {
+        Value::Float32(v) => Ok(Value::Float32(v.abs())),
+        Value::Float64(v) => Ok(Value::Float64(v.abs())),
+        Value::UInt64(_) => Ok(value.clone()),
+        other => {
+            let v = integer("ABS", other)?;
+            v.checked_abs().map(Value::Int64).ok_or_else(expr::overflow)
+        }
+    }
+}
+
+/// `ROUND(x [, places])`, rounding halves away from zero. Negative
+/// `places` round to tens, hundreds and so on. Integers stay integers.
+fn round(value: &Value, places: Option<&Value>) -> QubeResult<Value> {
+    let places = match places {
+        Some(places) => integer("ROUND", places)?.clamp(-18, 18) as i32,
+        None => 0,
+    };
+    match value {
+        Value::Float32(_) | Value::Float64(_) => {
+            let v = number("ROUND", value)?;
+            let scale = 10f64.powi(places);
+            Ok(Value::Float64((v * scale).round() / scale))
+        }
+        Value::UInt64(_) if places >= 0 => Ok(value.clone()),
+        other => {
+            let v = integer("ROUND", other)?;
+            if places >= 0 {
+                return Ok(Value::Int64(v));
+            }
+            let scale = 10i64.pow(places.unsigned_abs());
+            let remainder = v % scale;
+            let down = v - remainder;
+            let rounded = if remainder.abs() * 2 >= scale {
+                down.checked_add(scale * remainder.signum())
+            } else {
+                Some(down)
+            };
+            rounded.map(Value::Int64).ok_or_else(expr::overflow)
+        }
+    }
+}
+
+/// `CEIL` / `FLOOR`: floats are rounded, integers are already whole
+fn rounded(function: &str, value: &Value, op: fn(f64) -> f64) -> QubeResult<Value> {
+    match value {
+        Value::Float32(_) | Value::Float64(_) => Ok(Value::Float64(op(number(function, value)?))),
+        other => number(function, other).map(|_| other.clone()),
+    }
+}
+
+/// `MOD(a, b)`, which like `%` yields NULL when `b` is zero
+fn modulo(a: &Value, b: &Value) -> QubeResult<Value> {
+    let is_float = |v: &Value| matches!(v, Value::Float32(_) | Value::Float64(_));
+    if is_float(a) || is_float(b) {
+        let (a, b) = (number("MOD", a)?, number("MOD", b)?);
+        return Ok(if b == 0.0 {
+            Value::Null
+        } else {
+            Value::Float64(a % b)
+        });
+    }
+    let (a, b) = (integer("MOD", a)?, integer("MOD", b)?);
+    if b == 0 {
+        return Ok(Value::Null);
+    }
+    a.checked_rem(b)
+        .map(Value::Int64)
+        .ok_or_else(expr::overflow)
+}
+
+/// `DATE_TRUNC(unit, ts)`: the start of the year, quarter, month, week
+/// (Monday), day, hour, minute or second containing `ts`
+fn date_trunc(unit: &str, value: &Value) -> QubeResult<Value> {
+    let ts = timestamp("DATE_TRUNC", value)?;
+    let date = ts.date_naive();
+    let (date, time) = match unit.to_lowercase().as_str() {
+        "year" => (date.with_day(1).and_then(|d| d.with_month(1)), None),
+        "quarter" => (
+            date.with_day(1)
+                .and_then(|d| d.with_month(1 + 3 * ((d.month() - 1) / 3))),
+            None,
+        ),
+        "month" => (date.with_day(1), None),
+        "week" => (
+            Some(date - Duration::days(date.weekday().num_days_from_monday() as i64)),
+            None,
+        ),
+        "day" => (Some(date), None),
+        "hour" => (Some(date), NaiveTime::from_hms_opt(ts.hour(), 0, 0)),
+        "minute" => (
+            Some(date),
+            NaiveTime::from_hms_opt(ts.hour(), ts.minute(), 0),
+        ),
+        "second" => (
+            Some(date),
+            NaiveTime::from_hms_opt(ts.hour(), ts.minute(), ts.second()),
+        ),
+        other => {
+            return Err(QubeError::QueryParse(format!(
+                "Unsupported DATE_TRUNC unit: {}",
+                other
+            )))
+        }
+    };
+    let date = date.unwrap_or(ts.date_naive());
+    Ok(Value::Timestamp(
+        date.and_time(time.unwrap_or(NaiveTime::MIN))
+            .and_utc()
+            .timestamp_millis(),
+    ))
+}
+
+/// `EXTRACT(field FROM ts)` as an integer. `dow` counts from Sunday = 0 and
+/// `epoch` is in seconds.
+pub fn extract(field: &str, value: &Value) -> QubeResult<Value> {
+    let ts = timestamp("EXTRACT", value)?;
+    let part = match field.to_lowercase().as_str() {
+        "year" => ts.year() as i64,
+        "quarter" => (ts.month0() / 3 + 1) as i64,
+        "month" => ts.month() as i64,
+        "week" => ts.iso_week().week() as i64,
+        "day" => ts.day() as i64,
+        "dow" | "dayofweek" => ts.weekday().num_days_from_sunday() as i64,
+        "doy" | "dayofyear" => ts.ordinal() as i64,
+        "hour" => ts.hour() as i64,
+        "minute" => ts.minute() as i64,
+        "second" => ts.second() as i64,
+        "millisecond" | "milliseconds" => ts.timestamp_subsec_millis() as i64,
+        "epoch" => ts.timestamp(),
+        other => {
+            return Err(QubeError::QueryParse(format!(
+                "Unsupported EXTRACT field: {}",
+                other
+            )))
+        }
+    };
+    Ok(Value::Int64(part))
+}
+
+/// `DATE_ADD(ts, interval)` / `DATE_SUB(ts, interval)`
+fn date_add(value: &Value, interval: &Value, subtract: bool) -> QubeResult<Value> {
+    let ts = timestamp("DATE_ADD", value)?;
+    let invalid = || QubeError::QueryParse(format!("Invalid interval: '{}'", interval));
+
+    let text = interval.to_string();
+    let mut parts = text.split_whitespace();
+    let (amount, unit) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(amount), Some(unit), None) => (amount, unit.to_lowercase()),
+        _ => return Err(invalid()),
+    };
+    let mut amount: i64 = amount.trim_matches('\'').parse().map_err(|_| invalid())?;
+    if subtract {
+        amount = amount.checked_neg().ok_or_else(expr::overflow)?;
+    }
+
+    let months = |months: i64| {
+        let count = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+        if months < 0 {
+            ts.checked_sub_months(count)
+        } else {
+            ts.checked_add_months(count)
+        }
+    };
+    let duration = |millis: i64| amount.checked_mul(millis).map(Duration::milliseconds);
+    let result = match unit.trim_end_matches('s') {
+        "year" => amount.checked_mul(12).and_then(months),
+        "quarter" => amount.checked_mul(3).and_then(months),
+        "month" => months(amount),
+        "week" => duration(7 * 86_400_000).and_then(|d| ts.checked_add_signed(d)),
+        "day" => duration(86_400_000).and_then(|d| ts.checked_add_signed(d)),
+        "hour" => duration(3_600_000).and_then(|d| ts.checked_add_signed(d)),
+        "minute" => duration(60_000).and_then(|d| ts.checked_add_signed(d)),
+        "second" => duration(1_000).and_then(|d| ts.checked_add_signed(d)),
+        "millisecond" => duration(1).and_then(|d| ts.checked_add_signed(d)),
+        _ => return Err(invalid()),
+    };
+    result
+        .map(|ts| Value::Timestamp(ts.timestamp_millis()))
+        .ok_or_else(expr::overflow)
+}
+
+/// Read a TIMESTAMP, or a date or date-time string, as a UTC time
+fn timestamp(function: &str, value: &Value) -> QubeResult<DateTime<Utc>> {
+    let millis = match value {
+        Value::String(s) => expr::parse_timestamp(s),
+        other => other.as_i64(),
+    };
+    millis
+        .and_then(DateTime::from_timestamp_millis)
+        .ok_or_else(|| {
+            QubeError::QueryParse(format!("{} expects a timestamp, got '{}'", function, value))
+        })
+}
+
+fn integer(function: &str, value: &Value) -> QubeResult<i64> {
+    value.as_i64().ok_or_else(|| {
+        QubeError::QueryParse(format!("{} expects an integer, got '{}'", function, value))
+    })
+}
+
+fn number(function: &str, value: &Value) -> QubeResult<f64> {
+    match value {
+        other if other.is_numeric() => other.as_f64(),
+        _ => None,
+    }
+    .ok_or_else(|| QubeError::QueryParse(format!("{} expects a number, got '{}'", function, value)))
+}
+
+/// Fail unless `count` arguments fit the function's arity
+pub fn check_arity(name: &str, count: usize, arity: RangeInclusive<usize>) -> QubeResult<()> {
+    if arity.contains(&count) {
+        return Ok(());
+    }
+    let expected = if arity.start() == arity.end() {
+        arity.start().to_string()
+    } else if *arity.end() == usize::MAX {
+        format!("at least {}", arity.start())
+    } else {
+        format!("{} to {}", arity.start(), arity.end())
+    };
+    let plural = if expected == "1" { "" } else { "s" };
+    Err(QubeError::QueryParse(format!(
+        "{} expects {} argument{} but {} were given",
+        name, expected, plural, count
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn call_ok(name: &str, args: &[Value]) -> Value {
+        call(name, args.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn string_functions_transform_text() {
+        assert_eq!(
+            call_ok("concat", &[text("a"), Value::Int32(1), text("b")]),
+            text("a1b")
+        );
+        assert_eq!(call_ok("UPPER", &[text("Alice")]), text("ALICE"));
+        assert_eq!(call_ok("lower", &[text("Alice")]), text("alice"));
+        assert_eq!(call_ok("LENGTH", &[text("héllo")]), Value::Int64(5));
+        assert_eq!(
+            call_ok("LENGTH", &[Value::Binary(vec![1, 2, 3])]),
+            Value::Int64(3)
+        );
+        assert_eq!(
+            call_ok("REPLACE", &[text("a-b-c"), text("-"), text("+")]),
+            text("a+b+c")
+        );
+        assert_eq!(
+            call_ok("REPLACE", &[text("abc"), text(""), text("x")]),
+            text("abc")
+        );
+        assert_eq!(call_ok("LTRIM", &[text("  x  ")]), text("x  "));
+        assert_eq!(call_ok("RTRIM", &[text("  x  ")]), text("  x"));
+        assert_eq!(call_ok("TRIM", &[text("  x  ")]), text("x"));
+        assert_eq!(call_ok("TRIM", &[text("xxaxx"), text("x")]), text("a"));
+    }
+
+    #[test]
+    fn substring_counts_from_one() {
+        let hello = text("hello");
+        assert_eq!(
+            call_ok("SUBSTRING", &[hello.clone(), Value::Int32(2)]),
+            text("ello")
+        );
+        assert_eq!(
+            call_ok("SUBSTR", &[hello.clone(), Value::Int32(2), Value::Int32(3)]),
+            text("ell")
+        );
+        // Positions before the first character still use up the length
+        assert_eq!(
+            call_ok(
+                "SUBSTRING",
+                &[hello.clone(), Value::Int32(-1), Value::Int32(3)]
+            ),
+            text("h")
+        );
+        assert!(call("SUBSTRING", vec![hello, Value::Int32(1), Value::Int32(-1)]).is_err());
+    }
+
+    #[test]
+    fn nulls_unknown_names_and_wrong_arity() {
+        assert_eq!(call_ok("UPPER", &[Value::Null]), Value::Null);
+        assert_eq!(call_ok("CONCAT", &[text("a"), Value::Null]), Value::Null);
+
+        let unknown = call("SHOUT", vec![text("a")]).unwrap_err();
+        assert!(unknown.to_string().contains("Unknown function: SHOUT"));
+        let arity = call("UPPER", vec![]).unwrap_err();
+        assert!(arity
+            .to_string()
+            .contains("UPPER expects 1 argument but 0 were given"));
+        let range = call("REPLACE", vec![text("a")]).unwrap_err();
+        assert!(range.to_string().contains("REPLACE expects 3 arguments"));
+        let concat = call("CONCAT", vec![]).unwrap_err();
+        assert!(concat
+            .to_string()
+            .contains("CONCAT expects at least 1 argument"));
+    }
+
+    #[test]
+    fn math_functions_keep_integers_whole() {
+        assert_eq!(call_ok("ABS", &[Value::Int32(-4)]), Value::Int64(4));
+        assert_eq!(call_ok("ABS", &[Value::Float64(-1.5)]), Value::Float64(1.5));
+        assert!(call("ABS", vec![Value::Int64(i64::MIN)]).is_err());
+
+        assert_eq!(
+            call_ok("ROUND", &[Value::Float64(2.5)]),
+            Value::Float64(3.0)
+        );
+        assert_eq!(
+            call_ok("ROUND", &[Value::Float64(-1.2345), Value::Int32(2)]),
+            Value::Float64(-1.23)
+        );
+        assert_eq!(
+            call_ok("ROUND", &[Value::Int32(1250), Value::Int32(-2)]),
+            Value::Int64(1300)
+        );
+        assert_eq!(
+            call_ok("ROUND", &[Value::Int32(-1249), Value::Int32(-2)]),
+            Value::Int64(-1200)
+        );
+
+        assert_eq!(call_ok("CEIL", &[Value::Float64(1.2)]), Value::Float64(2.0));
+        assert_eq!(
+            call_ok("FLOOR", &[Value::Float64(-1.2)]),
+            Value::Float64(-2.0)
+        );
+        assert_eq!(call_ok("CEILING", &[Value::Int32(7)]), Value::Int32(7));
+        assert!(call("FLOOR", vec![text("x")]).is_err());
+
+        assert_eq!(
+            call_ok("MOD", &[Value::Int32(7), Value::Int32(3)]),
+            Value::Int64(1)
+        );
+        assert_eq!(
+            call_ok("MOD", &[Value::Float64(7.5), Value::Int32(2)]),
+            Value::Float64(1.5)
+        );
+        assert_eq!(
+            call_ok("MOD", &[Value::Int32(7), Value::Int32(0)]),
+            Value::Null
+        );
+        assert_eq!(
+            call_ok("POWER", &[Value::Int32(2), Value::Int32(10)]),
+            Value::Float64(1024.0)
+        );
+    }
+
+    #[test]
+    fn date_functions_read_timestamps_and_date_strings() {
+        // 2024-05-15 13:45:30.250 UTC, a Wednesday
+        let ts = Value::Timestamp(1_715_780_730_250);
+        let trunc = |unit: &str| call_ok("DATE_TRUNC", &[text(unit), ts.clone()]);
+        assert_eq!(trunc("quarter"), Value::Timestamp(1_711_929_600_000));
+        assert_eq!(trunc("week"), Value::Timestamp(1_715_558_400_000));
+        assert_eq!(trunc("HOUR"), Value::Timestamp(1_715_778_000_000));
+        assert!(call("DATE_TRUNC", vec![text("fortnight"), ts.clone()]).is_err());
+
+        let extract = |field: &str| call_ok("EXTRACT", &[text(field), ts.clone()]);
+        assert_eq!(extract("year"), Value::Int64(2024));
+        assert_eq!(extract("quarter"), Value::Int64(2));
+        assert_eq!(extract("dow"), Value::Int64(3));
+        assert_eq!(extract("doy"), Value::Int64(136));
+        assert_eq!(extract("millisecond"), Value::Int64(250));
+        assert_eq!(extract("epoch"), Value::Int64(1_715_780_730));
+        assert_eq!(
+            call_ok("EXTRACT", &[text("month"), text("2024-02-29")]),
+            Value::Int64(2)
+        );
+
+        assert_eq!(
+            call_ok("DATE_ADD", &[ts.clone(), text("1 day")]),
+            Value::Timestamp(1_715_867_130_250)
+        );
+        // Month arithmetic clamps to the end of shorter months
+        assert_eq!(
+            call_ok("DATE_ADD", &[text("2024-03-31"), text("-1 month")]),
+            Value::Timestamp(1_709_164_800_000)
+        );
+        assert_eq!(
+            call_ok("DATE_SUB", &[text("2024-02-29"), text("1 YEAR")]),
+            Value::Timestamp(1_677_542_400_000)
+        );
+        assert!(call("DATE_ADD", vec![ts.clone(), text("soon")]).is_err());
+        assert!(call("DATE_ADD", vec![text("yesterday"), text("1 day")]).is_err());
+
+        assert!(matches!(call_ok("NOW", &[]), Value::Timestamp(_)));
+    }
+}
@@ -0,0 +1,1124 @@
+//! Property graphs for QubeDB
+//!
+//! A graph is a set of nodes and directed edges, each carrying a row of
+//! properties. A node's label is its `label` property and an edge's type is
+//! its `type` property, which is what patterns such as `(a:Person)` and
+//! `-[:FRIENDS]->` match against. Adjacency lists index every edge by both
+//! endpoints so traversals never scan the whole edge list.
+//!
+//! Property indexes map the values of one property to the nodes carrying a
+//! label, and the edges of the same type, with that value. Graph queries use
+//! them to find start nodes without scanning every node.
+//!
+//! `shortest_weighted_path` runs Dijkstra's algorithm along edge direction,
+//! taking each edge's cost from a numeric property.
+//!
+//! Large graphs can be loaded from CSV: an edge list of `from,to[,weight]`
+//! lines, and optionally a node file whose header names the properties of
+//! the `id` column's nodes.
+
+use crate::cypher;
+use crate::error::{QubeError, QubeResult};
+use crate::types::{QueryResult, Row, Value};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap};
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// On-disk format version written by `GraphEngine::save`
+const GRAPH_FORMAT_VERSION: u32 = 1;
+
+/// Property holding a node's label
+pub const LABEL_PROPERTY: &str = "label";
+
+/// Property holding an edge's type
+pub const TYPE_PROPERTY: &str = "type";
+
+/// Cost of an edge without the weight property, unless the caller picks
+/// another
+pub const DEFAULT_EDGE_WEIGHT: f64 = 1.0;
+
+/// A graph node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub properties: Row,
+}
+
+impl Node {
+    pub fn label(&self) -> Option<&str> {
+        string_property(&self.properties, LABEL_PROPERTY)
+    }
+}
+
+/// A directed graph edge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub properties: Row,
+}
+
+impl Edge {
+    pub fn edge_type(&self) -> Option<&str> {
+        string_property(&self.properties, TYPE_PROPERTY)
+    }
+}
+
+fn string_property<'a>(properties: &'a Row, name: &str) -> Option<&'a str> {
+    match properties.get(name) {
+        Some(Value::String(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// Property an imported edge list's third column is stored in
+pub const WEIGHT_PROPERTY: &str = "weight";
+
+/// What a bulk import created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub nodes_created: usize,
+    pub edges_created: usize,
+}
+
+/// The cheapest path between two nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeightedPath {
+    /// Node ids from start to end, inclusive
+    pub nodes: Vec<String>,
+    /// Sum of the weights of the edges taken
+    pub cost: f64,
+}
+
+/// A node waiting to be visited by Dijkstra's algorithm. Ordered so that
+/// the cheapest entry is at the top of a `BinaryHeap`.
+#[derive(Debug, PartialEq)]
+struct Frontier<'a> {
+    cost: f64,
+    node: &'a str,
+}
+
+impl Eq for Frontier<'_> {}
+
+impl Ord for Frontier<'_> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .cost
+            .total_cmp(&self.cost)
+            .then_with(|| other.node.cmp(self.node))
+    }
+}
+
+impl PartialOrd for Frontier<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Values of one property, for nodes with a label and edges of the same
+/// type
+#[derive(Debug, Default)]
+struct PropertyIndex {
+    nodes: HashMap<Value, BTreeSet<String>>,
+    /// Indexes into `Graph::edges`
+    edges: HashMap<Value, BTreeSet<usize>>,
+}
+
+/// Key a property value is indexed under. NULL is never indexed, and
+/// numbers are normalized so that values of different widths match, as
+/// they do when compared.
+fn index_key(value: &Value) -> Option<Value> {
+    if value.is_null() {
+        return None;
+    }
+    Some(match value.as_f64() {
+        Some(v) if v.fract() == 0.0 && v.abs() < i64::MAX as f64 => Value::Int64(v as i64),
+        Some(v) if value.is_numeric() => Value::Float64(v),
+        _ => value.clone(),
+    })
+}
+
+/// One named graph with its adjacency and property indexes
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Graph {
+    nodes: BTreeMap<String, Node>,
+    edges: Vec<Edge>,
+    /// `(label, property)` pairs with a property index
+    #[serde(default)]
+    indexed: BTreeSet<(String, String)>,
+    /// Indexes into `edges`, by source node
+    #[serde(skip)]
+    outgoing: HashMap<String, Vec<usize>>,
+    /// Indexes into `edges`, by target node
+    #[serde(skip)]
+    incoming: HashMap<String, Vec<usize>>,
+    /// Property indexes, keyed like `indexed`
+    #[serde(skip)]
+    indexes: HashMap<(String, String), PropertyIndex>,
+    /// Candidate nodes queries have examined, to show index use
+    #[serde(skip)]
+    nodes_examined: AtomicUsize,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a node, replacing the properties of an existing node with the
+    /// same id
+    pub fn add_node(&mut self, id: &str, properties: Row) {
+        if let Some(old) = self.nodes.remove(id) {
+            self.index_node(&old, false);
+        }
+        let node = Node {
+            id: id.to_string(),
+            properties,
+        };
+        self.index_node(&node, true);
+        self.nodes.insert(id.to_string(), node);
+    }
+
+    /// Add an edge. Endpoints that do not exist yet are created without
+    /// properties.
+    pub fn add_edge(&mut self, from: &str, to: &str, properties: Row) {
+        for id in [from, to] {
+            if !self.nodes.contains_key(id) {
+                self.add_node(id, Row::new());
+            }
+        }
+        let index = self.edges.len();
+        self.edges.push(Edge {
+            from: from.to_string(),
+            to: to.to_string(),
+            properties,
+        });
+        self.outgoing
+            .entry(from.to_string())
+            .or_default()
+            .push(index);
+        self.incoming.entry(to.to_string()).or_default().push(index);
+        self.index_edge(index);
+    }
+
+    /// Add many nodes, returning how many did not exist before
+    pub fn add_nodes(&mut self, nodes: Vec<Node>) -> usize {
+        let before = self.nodes.len();
+        for node in nodes {
+            self.add_node(&node.id, node.properties);
+        }
+        self.nodes.len() - before
+    }
+
+    /// Add many edges, creating missing endpoints, and update the
+    /// adjacency lists and indexes once for the whole batch. Returns how
+    /// many nodes were created.
+    pub fn add_edges(&mut self, edges: Vec<Edge>) -> usize {
+        let nodes_before = self.nodes.len();
+        let first = self.edges.len();
+        self.edges.reserve(edges.len());
+        for edge in edges {
+            for id in [&edge.from, &edge.to] {
+                if !self.nodes.contains_key(id) {
+                    self.add_node(id, Row::new());
+                }
+            }
+            self.edges.push(edge);
+        }
+        for position in first..self.edges.len() {
+            let edge = &self.edges[position];
+            self.outgoing
+                .entry(edge.from.clone())
+                .or_default()
+                .push(position);
+            self.incoming
+                .entry(edge.to.clone())
+                .or_default()
+                .push(position);
+            self.index_edge(position);
+        }
+        self.nodes.len() - nodes_before
+    }
+
+    /// Remove a node and every edge touching it, returning whether it
+    /// existed
+    pub fn remove_node(&mut self, id: &str) -> bool {
+        let Some(node) = self.nodes.remove(id) else {
+            return false;
+        };
+        self.index_node(&node, false);
+        if !self.outgoing(id).is_empty() || !self.incoming(id).is_empty() {
+            self.edges.retain(|edge| edge.from != id && edge.to != id);
+            self.rebuild_edge_indexes();
+        }
+        true
+    }
+
+    /// Remove every edge from `from` to `to`, returning how many there were
+    pub fn remove_edges(&mut self, from: &str, to: &str) -> usize {
+        let before = self.edges.len();
+        self.edges.retain(|edge| edge.from != from || edge.to != to);
+        let removed = before - self.edges.len();
+        if removed > 0 {
+            self.rebuild_edge_indexes();
+        }
+        removed
+    }
+
+    /// Index `property` for nodes labelled `label` and edges of type
+    /// `label`. Returns false if the index already existed.
+    pub fn create_index(&mut self, label: &str, property: &str) -> bool {
+        let key = (label.to_string(), property.to_string());
+        if !self.indexed.insert(key.clone()) {
+            return false;
+        }
+        let mut index = PropertyIndex::default();
+        for node in self.nodes.values() {
+            if node.label() == Some(label) {
+                if let Some(value) = node.properties.get(property).and_then(index_key) {
+                    index
+                        .nodes
+                        .entry(value)
+                        .or_default()
+                        .insert(node.id.clone());
+                }
+            }
+        }
+        for (position, edge) in self.edges.iter().enumerate() {
+            if edge.edge_type() == Some(label) {
+                if let Some(value) = edge.properties.get(property).and_then(index_key) {
+                    index.edges.entry(value).or_default().insert(position);
+                }
+            }
+        }
+        self.indexes.insert(key, index);
+        true
+    }
+
+    /// The `(label, property)` pairs that are indexed
+    pub fn indexes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.indexed
+            .iter()
+            .map(|(label, property)| (label.as_str(), property.as_str()))
+    }
+
+    /// Nodes labelled `label` whose `property` equals `value`, or `None` if
+    /// that property is not indexed
+    pub fn find_nodes(&self, label: &str, property: &str, value: &Value) -> Option<Vec<&Node>> {
+        let index = self
+            .indexes
+            .get(&(label.to_string(), property.to_string()))?;
+        let ids = index_key(value).and_then(|key| index.nodes.get(&key));
+        Some(
+            ids.into_iter()
+                .flatten()
+                .filter_map(|id| self.nodes.get(id))
+                .collect(),
+        )
+    }
+
+    /// Edges of type `label` whose `property` equals `value`, or `None` if
+    /// that property is not indexed
+    pub fn find_edges(&self, label: &str, property: &str, value: &Value) -> Option<Vec<&Edge>> {
+        let index = self
+            .indexes
+            .get(&(label.to_string(), property.to_string()))?;
+        let positions = index_key(value).and_then(|key| index.edges.get(&key));
+        Some(
+            positions
+                .into_iter()
+                .flatten()
+                .map(|&position| &self.edges[position])
+                .collect(),
+        )
+    }
+
+    /// Number of candidate start nodes graph queries have examined
+    pub fn nodes_examined(&self) -> usize {
+        self.nodes_examined.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_examined(&self, count: usize) {
+        self.nodes_examined.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Add a node to, or remove it from, the indexes on its label
+    fn index_node(&mut self, node: &Node, add: bool) {
+        let Some(label) = node.label() else {
+            return;
+        };
+        for ((index_label, property), index) in self.indexes.iter_mut() {
+            if index_label != label {
+                continue;
+            }
+            let Some(value) = node.properties.get(property).and_then(index_key) else {
+                continue;
+            };
+            if add {
+                index
+                    .nodes
+                    .entry(value)
+                    .or_default()
+                    .insert(node.id.clone());
+            } else if let Some(ids) = index.nodes.get_mut(&value) {
+                ids.remove(&node.id);
+                if ids.is_empty() {
+                    index.nodes.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// Add the edge at `position` to the indexes on its type
+    fn index_edge(&mut self, position: usize) {
+        let edge = &self.edges[position];
+        let Some(edge_type) = edge.edge_type() else {
+            return;
+        };
+        for ((index_label, property), index) in self.indexes.iter_mut() {
+            if index_label != edge_type {
+                continue;
+            }
+            if let Some(value) = edge.properties.get(property).and_then(index_key) {
+                index.edges.entry(value).or_default().insert(position);
+            }
+        }
+    }
+
+    /// Rebuild everything keyed by edge position after edges were removed
+    fn rebuild_edge_indexes(&mut self) {
+        self.rebuild_adjacency();
+        for index in self.indexes.values_mut() {
+            index.edges.clear();
+        }
+        for position in 0..self.edges.len() {
+            self.index_edge(position);
+        }
+    }
+
+    /// Rebuild the adjacency lists and property indexes, which are not
+    /// persisted
+    fn rebuild(&mut self) {
+        self.rebuild_adjacency();
+        self.indexes.clear();
+        for (label, property) in std::mem::take(&mut self.indexed) {
+            self.create_index(&label, &property);
+        }
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// Every node, in id order
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.values()
+    }
+
+    /// Every edge, in insertion order
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn edge(&self, index: usize) -> &Edge {
+        &self.edges[index]
+    }
+
+    /// Indexes of the edges leaving `id`
+    pub fn outgoing(&self, id: &str) -> &[usize] {
+        self.outgoing.get(id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Indexes of the edges arriving at `id`
+    pub fn incoming(&self, id: &str) -> &[usize] {
+        self.incoming.get(id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The cheapest path from `from` to `to` following edge direction,
+    /// where an edge costs its `weight_property`, or `default_weight` when
+    /// the property is missing or NULL. Returns `None` if `to` cannot be
+    /// reached. Negative and non-numeric weights are errors.
+    pub fn shortest_weighted_path(
+        &self,
+        from: &str,
+        to: &str,
+        weight_property: &str,
+        default_weight: f64,
+    ) -> QubeResult<Option<WeightedPath>> {
+        for id in [from, to] {
+            if !self.nodes.contains_key(id) {
+                return Err(QubeError::NotFound(format!("Node '{}' not found", id)));
+            }
+        }
+        if default_weight.is_nan() || default_weight < 0.0 {
+            return Err(QubeError::Other(format!(
+                "Default edge weight must not be negative, got {}",
+                default_weight
+            )));
+        }
+
+        // Cheapest known cost per node, and the edge it was reached by
+        let mut best: HashMap<&str, (f64, Option<usize>)> = HashMap::new();
+        let mut frontier = BinaryHeap::new();
+        best.insert(from, (0.0, None));
+        frontier.push(Frontier {
+            cost: 0.0,
+            node: from,
+        });
+
+        while let Some(Frontier { cost, node }) = frontier.pop() {
+            if node == to {
+                break;
+            }
+            if best.get(node).is_some_and(|&(known, _)| cost > known) {
+                continue;
+            }
+            for &index in self.outgoing(node) {
+                let edge = &self.edges[index];
+                let next = cost + Self::edge_weight(edge, weight_property, default_weight)?;
+                if best
+                    .get(edge.to.as_str())
+                    .is_none_or(|&(known, _)| next < known)
+                {
+                    best.insert(&edge.to, (next, Some(index)));
+                    frontier.push(Frontier {
+                        cost: next,
+                        node: &edge.to,
+                    });
+                }
+            }
+        }
+
+        let Some(&(cost, mut via)) = best.get(to) else {
+            return Ok(None);
+        };
+        let mut nodes = vec![to.to_string()];
+        while let Some(index) = via {
+            let edge = &self.edges[index];
+            nodes.push(edge.from.clone());
+            via = best[edge.from.as_str()].1;
+        }
+        nodes.reverse();
+        Ok(Some(WeightedPath { nodes, cost }))
+    }
+
+    fn edge_weight(edge: &Edge, weight_property: &str, default_weight: f64) -> QubeResult<f64> {
+        let weight = match edge.properties.get(weight_property) {
+            None | Some(Value::Null) => return Ok(default_weight),
+            Some(value) if value.is_numeric() => value.as_f64().unwrap_or(f64::NAN),
+            Some(value) => {
+                return Err(QubeError::Other(format!(
+                    "Edge '{}' -> '{}' has non-numeric weight {}",
+                    edge.from, edge.to, value
+                )))
+            }
+        };
+        if weight.is_nan() || weight < 0.0 {
+            return Err(QubeError::Other(format!(
+                "Edge '{}' -> '{}' has negative weight {}",
+                edge.from, edge.to, weight
+            )));
+        }
+        Ok(weight)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn rebuild_adjacency(&mut self) {
+        self.outgoing.clear();
+        self.incoming.clear();
+        for (index, edge) in self.edges.iter().enumerate() {
+            self.outgoing
+                .entry(edge.from.clone())
+                .or_default()
+                .push(index);
+            self.incoming
+                .entry(edge.to.clone())
+                .or_default()
+                .push(index);
+        }
+    }
+}
+
+/// Named property graphs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GraphEngine {
+    graphs: BTreeMap<String, Graph>,
+}
+
+impl GraphEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn graph(&self, name: &str) -> Option<&Graph> {
+        self.graphs.get(name)
+    }
+
+    /// The names of all graphs, sorted
+    pub fn graph_names(&self) -> Vec<String> {
+        self.graphs.keys().cloned().collect()
+    }
+
+    /// Add a node to `graph`, creating the graph if needed
+    pub fn add_node(&mut self, graph: &str, id: &str, properties: Row) {
+        self.graphs
+            .entry(graph.to_string())
+            .or_default()
+            .add_node(id, properties);
+    }
+
+    /// Add an edge to `graph`, creating the graph if needed
+    pub fn add_edge(&mut self, graph: &str, from: &str, to: &str, properties: Row) {
+        self.graphs
+            .entry(graph.to_string())
+            .or_default()
+            .add_edge(from, to, properties);
+    }
+
+    /// Add the nodes and then the edges of a bulk import to `graph`,
+    /// creating the graph if needed
+    pub fn import(&mut self, graph: &str, nodes: Vec<Node>, edges: Vec<Edge>) -> ImportSummary {
+        let graph = self.graphs.entry(graph.to_string()).or_default();
+        let edges_created = edges.len();
+        let nodes_created = graph.add_nodes(nodes) + graph.add_edges(edges);
+        ImportSummary {
+            nodes_created,
+            edges_created,
+        }
+    }
+
+    /// Remove a node and its edges from `graph`, returning whether it existed
+    pub fn remove_node(&mut self, graph: &str, id: &str) -> bool {
+        self.graphs
+            .get_mut(graph)
+            .is_some_and(|graph| graph.remove_node(id))
+    }
+
+    /// Remove every edge from `from` to `to` in `graph`, returning how many
+    /// there were
+    pub fn remove_edges(&mut self, graph: &str, from: &str, to: &str) -> usize {
+        self.graphs
+            .get_mut(graph)
+            .map_or(0, |graph| graph.remove_edges(from, to))
+    }
+
+    /// Index `property` of the nodes labelled `label`, and the edges of type
+    /// `label`, in `graph`, creating the graph if needed. Returns false if
+    /// the index already existed.
+    pub fn create_index(&mut self, graph: &str, label: &str, property: &str) -> bool {
+        self.graphs
+            .entry(graph.to_string())
+            .or_default()
+            .create_index(label, property)
+    }
+
+    /// The cheapest path between two nodes of `graph`, costing each edge
+    /// by `weight_property` and edges without it `DEFAULT_EDGE_WEIGHT`
+    pub fn shortest_weighted_path(
+        &self,
+        graph: &str,
+        from: &str,
+        to: &str,
+        weight_property: &str,
+    ) -> QubeResult<Option<WeightedPath>> {
+        self.shortest_weighted_path_with_default(
+            graph,
+            from,
+            to,
+            weight_property,
+            DEFAULT_EDGE_WEIGHT,
+        )
+    }
+
+    /// Like `shortest_weighted_path`, with `default_weight` for edges that
+    /// lack the weight property
+    pub fn shortest_weighted_path_with_default(
+        &self,
+        graph: &str,
+        from: &str,
+        to: &str,
+        weight_property: &str,
+        default_weight: f64,
+    ) -> QubeResult<Option<WeightedPath>> {
+        self.graph(graph)
+            .ok_or_else(|| QubeError::NotFound(format!("Graph '{}' not found", graph)))?
+            .shortest_weighted_path(from, to, weight_property, default_weight)
+    }
+
+    /// Run a Cypher-style query such as
+    /// `MATCH (a)-[:FRIENDS]->(b) WHERE a.name = 'Alice' RETURN b.name`
+    /// against `graph`
+    pub fn query(&self, graph: &str, query: &str) -> QubeResult<QueryResult> {
+        let graph = self
+            .graph(graph)
+            .ok_or_else(|| QubeError::NotFound(format!("Graph '{}' not found", graph)))?;
+        cypher::execute(graph, &cypher::parse(query)?)
+    }
+
+    /// Remove every graph
+    pub fn clear(&mut self) {
+        self.graphs.clear();
+    }
+
+    /// Write every graph to `path`, replacing the file atomically. JSON is
+    /// used rather than bincode because properties may hold JSON values.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> QubeResult<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let bytes = serde_json::to_vec(&(GRAPH_FORMAT_VERSION, self))
+            .map_err(|e| QubeError::Serialization(format!("Failed to encode graphs: {}", e)))?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read graphs previously written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let (version, mut engine): (u32, GraphEngine) =
+            serde_json::from_slice(&bytes).map_err(|e| {
+                QubeError::Serialization(format!(
+                    "Failed to decode graphs {}: {}",
+                    path.as_ref().display(),
+                    e
+                ))
+            })?;
+
+        if version != GRAPH_FORMAT_VERSION {
+            return Err(QubeError::Serialization(format!(
+                "Unsupported graph format version {}",
+                version
+            )));
+        }
+        engine.graphs.values_mut().for_each(Graph::rebuild);
+        Ok(engine)
+    }
+}
+
+/// Read an edge list of `from,to[,weight]` lines. Blank lines, `#`
+/// comments and a leading `from,to` header are skipped. A weight is stored
+/// in the edge's `weight` property.
+pub fn read_edge_list<R: BufRead>(reader: R) -> QubeResult<Vec<Edge>> {
+    let mut edges = Vec::new();
+    for (number, line) in csv_lines(reader) {
+        let fields = csv_fields(&line?);
+        if edges.is_empty() && fields[0].eq_ignore_ascii_case("from") {
+            continue;
+        }
+        let (from, to, weight) = match fields.as_slice() {
+            [from, to] => (from, to, None),
+            [from, to, weight] => (from, to, Some(weight)),
+            _ => return Err(import_error(number, "expected from,to[,weight]")),
+        };
+        if from.is_empty() || to.is_empty() {
+            return Err(import_error(number, "node ids must not be empty"));
+        }
+        let mut properties = Row::new();
+        if let Some(weight) = weight.filter(|w| !w.is_empty()) {
+            let weight: f64 = weight
+                .parse()
+                .map_err(|_| import_error(number, &format!("invalid weight '{}'", weight)))?;
+            properties.insert(WEIGHT_PROPERTY.to_string(), Value::Float64(weight));
+        }
+        edges.push(Edge {
+            from: from.clone(),
+            to: to.clone(),
+            properties,
+        });
+    }
+    Ok(edges)
+}
+
+/// Read a node file: a header of `id` followed by property names, then one
+/// node per line. Integer and decimal fields become numbers, other fields
+/// strings, and empty fields are left out.
+pub fn read_node_list<R: BufRead>(reader: R) -> QubeResult<Vec<Node>> {
+    let mut lines = csv_lines(reader);
+    let header = match lines.next() {
+        Some((_, line)) => csv_fields(&line?),
+        None => return Ok(Vec::new()),
+    };
+    if !header[0].eq_ignore_ascii_case("id") {
+        return Err(import_error(1, "the header must start with id"));
+    }
+
+    let mut nodes = Vec::new();
+    for (number, line) in lines {
+        let fields = csv_fields(&line?);
+        if fields.len() != header.len() {
+            return Err(import_error(
+                number,
+                &format!("expected {} fields, found {}", header.len(), fields.len()),
+            ));
+        }
+        if fields[0].is_empty() {
+            return Err(import_error(number, "node ids must not be empty"));
+        }
+        let properties = header[1..]
+            .iter()
+            .zip(&fields[1..])
+            .filter(|(_, field)| !field.is_empty())
+            .map(|(name, field)| (name.clone(), csv_value(field)))
+            .collect();
+        nodes.push(Node {
+            id: fields[0].clone(),
+            properties,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Non-blank, non-comment lines with their 1-based line numbers
+fn csv_lines<R: BufRead>(reader: R) -> impl Iterator<Item = (usize, std::io::Result<String>)> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| {
+            line.as_ref().map_or(true, |l| {
+                !l.trim().is_empty() && !l.trim_start().starts_with('#')
+            })
+        })
+}
+
+/// Split a CSV line on commas outside double quotes, trimming unquoted
+/// whitespace. `""` inside quotes is a literal quote.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn csv_value(field: &str) -> Value {
+    if let Ok(v) = field.parse::<i64>() {
+        Value::Int64(v)
+    } else if let Some(v) = field.parse::<f64>().ok().filter(|v| v.is_finite()) {
+        Value::Float64(v)
+    } else {
+        Value::String(field.to_string())
+    }
+}
+
+fn import_error(line: usize, message: &str) -> QubeError {
+    QubeError::Serialization(format!("Import failed at line {}: {}", line, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(pairs: &[(&str, Value)]) -> Row {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn person(name: &str, age: i64) -> Row {
+        properties(&[
+            (LABEL_PROPERTY, Value::String("Person".to_string())),
+            ("name", Value::String(name.to_string())),
+            ("age", Value::Int64(age)),
+        ])
+    }
+
+    fn ids(nodes: Option<Vec<&Node>>) -> Vec<&str> {
+        nodes.unwrap().iter().map(|node| node.id.as_str()).collect()
+    }
+
+    #[test]
+    fn property_indexes_follow_node_and_edge_changes() {
+        let mut graph = Graph::new();
+        graph.add_node("a", person("Ann", 30));
+        graph.add_node("b", person("Bob", 30));
+        assert!(graph.create_index("Person", "age"));
+        assert!(!graph.create_index("Person", "age"));
+        assert!(graph
+            .find_nodes("Person", "name", &Value::Int64(30))
+            .is_none());
+
+        // Numbers of any width find the same nodes
+        assert_eq!(
+            ids(graph.find_nodes("Person", "age", &Value::Int32(30))),
+            ["a", "b"]
+        );
+        assert_eq!(
+            ids(graph.find_nodes("Person", "age", &Value::Float64(30.0))),
+            ["a", "b"]
+        );
+
+        graph.add_node("c", person("Cat", 41));
+        graph.add_node("b", person("Bob", 41));
+        graph.add_node("d", properties(&[("age", Value::Int64(41))]));
+        assert_eq!(
+            ids(graph.find_nodes("Person", "age", &Value::Int64(30))),
+            ["a"]
+        );
+        assert_eq!(
+            ids(graph.find_nodes("Person", "age", &Value::Int64(41))),
+            ["b", "c"]
+        );
+        assert!(graph.remove_node("c"));
+        assert_eq!(
+            ids(graph.find_nodes("Person", "age", &Value::Int64(41))),
+            ["b"]
+        );
+        assert!(ids(graph.find_nodes("Person", "age", &Value::Null)).is_empty());
+
+        // Edges of the indexed type are indexed too, and stay correct when
+        // removing edges shifts their positions
+        let knows = |since: i64| {
+            properties(&[
+                (TYPE_PROPERTY, Value::String("Person".to_string())),
+                ("age", Value::Int64(since)),
+            ])
+        };
+        graph.add_edge("a", "b", knows(5));
+        graph.add_edge("b", "a", knows(7));
+        assert_eq!(graph.remove_edges("a", "b"), 1);
+        let edges = graph.find_edges("Person", "age", &Value::Int64(7)).unwrap();
+        assert_eq!((edges[0].from.as_str(), edges[0].to.as_str()), ("b", "a"));
+        assert!(graph
+            .find_edges("Person", "age", &Value::Int64(5))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn graph_queries_start_from_indexed_nodes() {
+        let mut engine = GraphEngine::new();
+        for i in 0..50 {
+            engine.add_node("people", &format!("p{}", i), person(&format!("P{}", i), i));
+        }
+        engine.add_edge("people", "p7", "p8", Row::new());
+        let query = "MATCH (a:Person)-->(b) WHERE a.age = 7 RETURN b.name";
+        let examined = |engine: &GraphEngine| engine.graph("people").unwrap().nodes_examined();
+
+        let result = engine.query("people", query).unwrap();
+        assert_eq!(result.rows[0]["b.name"], Value::String("P8".to_string()));
+        assert_eq!(examined(&engine), 50);
+
+        // With an index only the node it returns is tried
+        assert!(engine.create_index("people", "Person", "age"));
+        let result = engine.query("people", query).unwrap();
+        assert_eq!(result.rows[0]["b.name"], Value::String("P8".to_string()));
+        assert_eq!(examined(&engine), 51);
+        engine
+            .query("people", "MATCH (a:Person {age: 7}) RETURN a.name")
+            .unwrap();
+        assert_eq!(examined(&engine), 52);
+        // An equality under OR can't narrow the start nodes
+        engine
+            .query(
+                "people",
+                "MATCH (a:Person) WHERE a.age = 7 OR a.age = 8 RETURN a.name",
+            )
+            .unwrap();
+        assert_eq!(examined(&engine), 102);
+    }
+
+    #[test]
+    fn indexes_are_rebuilt_when_graphs_are_loaded() {
+        let path = std::env::temp_dir().join(format!("qubedb-graphs-{}.json", std::process::id()));
+        let mut engine = GraphEngine::new();
+        engine.add_node("people", "a", person("Ann", 30));
+        engine.add_edge("people", "a", "b", Row::new());
+        engine.create_index("people", "Person", "name");
+        engine.save(&path).unwrap();
+
+        let loaded = GraphEngine::load(&path).unwrap();
+        let graph = loaded.graph("people").unwrap();
+        assert_eq!(graph.indexes().collect::<Vec<_>>(), [("Person", "name")]);
+        let found = graph.find_nodes("Person", "name", &Value::String("Ann".to_string()));
+        assert_eq!(ids(found), ["a"]);
+        assert_eq!(graph.outgoing("a").len(), 1);
+        assert_eq!(graph.incoming("b").len(), 1);
+    }
+
+    /// Roads between towns, each costing its `km`
+    fn roads() -> GraphEngine {
+        let mut engine = GraphEngine::new();
+        for (from, to, km) in [
+            ("a", "b", 4.0),
+            ("a", "c", 1.0),
+            ("c", "b", 2.0),
+            ("b", "d", 5.0),
+            ("c", "d", 8.0),
+        ] {
+            engine.add_edge("roads", from, to, properties(&[("km", Value::Float64(km))]));
+        }
+        engine.add_node("roads", "island", Row::new());
+        engine
+    }
+
+    #[test]
+    fn the_cheapest_path_follows_edge_weights_and_direction() {
+        let engine = roads();
+        let path = engine
+            .shortest_weighted_path("roads", "a", "d", "km")
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.nodes, ["a", "c", "b", "d"]);
+        assert_eq!(path.cost, 8.0);
+
+        // Edges only lead one way, and an unconnected node is unreachable
+        assert_eq!(
+            engine
+                .shortest_weighted_path("roads", "d", "a", "km")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            engine
+                .shortest_weighted_path("roads", "a", "island", "km")
+                .unwrap(),
+            None
+        );
+        let here = engine
+            .shortest_weighted_path("roads", "b", "b", "km")
+            .unwrap()
+            .unwrap();
+        assert_eq!((here.nodes, here.cost), (vec!["b".to_string()], 0.0));
+
+        // Without the property every edge costs the default, so the fewest
+        // hops win
+        let hops = engine
+            .shortest_weighted_path("roads", "a", "d", "minutes")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hops.cost, 2.0);
+        let expensive = engine
+            .shortest_weighted_path_with_default("roads", "a", "d", "minutes", 10.0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(expensive.cost, 20.0);
+    }
+
+    #[test]
+    fn bad_weights_and_missing_nodes_are_errors() {
+        let mut engine = roads();
+        assert!(matches!(
+            engine.shortest_weighted_path("roads", "a", "nowhere", "km"),
+            Err(QubeError::NotFound(_))
+        ));
+        assert!(matches!(
+            engine.shortest_weighted_path("maps", "a", "d", "km"),
+            Err(QubeError::NotFound(_))
+        ));
+        assert!(engine
+            .shortest_weighted_path_with_default("roads", "a", "d", "minutes", -1.0)
+            .is_err());
+
+        engine.add_edge(
+            "roads",
+            "a",
+            "e",
+            properties(&[("km", Value::Float64(-2.0))]),
+        );
+        assert!(engine
+            .shortest_weighted_path("roads", "a", "e", "km")
+            .is_err());
+        engine.add_edge(
+            "roads",
+            "e",
+            "f",
+            properties(&[("km", Value::String("far".to_string()))]),
+        );
+        assert!(engine
+            .shortest_weighted_path("roads", "e", "f", "km")
+            .is_err());
+    }
+
+    #[test]
+    fn csv_files_import_nodes_then_edges() {
+        let nodes = read_node_list(
+            "id,name,age,score\n\
+             # people\n\
+             a,\"Ann, Jr.\",30,1.5\n\
+             b,Bob,,x\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(
+            nodes[0].properties,
+            properties(&[
+                ("name", Value::String("Ann, Jr.".to_string())),
+                ("age", Value::Int64(30)),
+                ("score", Value::Float64(1.5)),
+            ])
+        );
+        // Empty fields are left out
+        assert!(!nodes[1].properties.contains_key("age"));
+
+        let edges =
+            read_edge_list("from,to,weight\n\na,b,2.5\nb,c\n\"a\",c,\n".as_bytes()).unwrap();
+        let pairs: Vec<(&str, &str)> = edges
+            .iter()
+            .map(|edge| (edge.from.as_str(), edge.to.as_str()))
+            .collect();
+        assert_eq!(pairs, [("a", "b"), ("b", "c"), ("a", "c")]);
+        assert_eq!(edges[0].properties[WEIGHT_PROPERTY], Value::Float64(2.5));
+        assert!(edges[1].properties.is_empty() && edges[2].properties.is_empty());
+
+        let mut engine = GraphEngine::new();
+        let summary = engine.import("people", nodes, edges);
+        assert_eq!(
+            summary,
+            ImportSummary {
+                nodes_created: 3,
+                edges_created: 3
+            }
+        );
+        let graph = engine.graph("people").unwrap();
+        assert_eq!(graph.node_count(), 3);
+        assert_eq!(graph.outgoing("a").len(), 2);
+        assert_eq!(graph.incoming("c").len(), 2);
+        let path = graph
+            .shortest_weighted_path("a", "c", WEIGHT_PROPERTY, DEFAULT_EDGE_WEIGHT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.nodes, ["a", "c"]);
+    }
+
+    #[test]
+    fn malformed_csv_lines_are_reported_with_their_number() {
+        let error = |result: QubeResult<Vec<Edge>>| result.unwrap_err().to_string();
+        assert!(error(read_edge_list("a,b\n\nc\n".as_bytes())).contains("line 3"));
+        assert!(error(read_edge_list("a,b,heavy\n".as_bytes())).contains("invalid weight"));
+        assert!(error(read_edge_list("a,\n".as_bytes())).contains("must not be empty"));
+
+        let error = |result: QubeResult<Vec<Node>>| result.unwrap_err().to_string();
+        assert!(error(read_node_list("name,age\n".as_bytes())).contains("line 1"));
+        assert!(error(read_node_list("id,name\na\n".as_bytes())).contains("line 2"));
+        assert!(read_node_list("".as_bytes()).unwrap().is_empty());
+    }
+}
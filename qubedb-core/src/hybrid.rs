@@ -0,0 +1,510 @@
+//! Hybrid search for QubeDB
+//!
+//! A `DocumentCollection` holds documents that have an embedding, text and
+//! metadata, indexed side by side by a `VectorIndex` and a `TextIndex`. A
+//! `HybridQuery` ranks them by a weighted blend of vector similarity and
+//! BM25 text relevance, among the documents its metadata filters let
+//! through. Similarity and BM25 live on different scales, so each is
+//! min-max scaled to 0..1 over those documents before blending; a document
+//! the text query doesn't match gets 0 for text.
+//!
+//! Embeddings can come from the caller or from an `Embedder`, a model the
+//! application plugs in to turn text into vectors.
+
+use crate::error::{QubeError, QubeResult};
+use crate::index::{DistanceMetric, TextIndex, VectorIndex};
+use crate::types::{Row, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// On-disk format version written by `DocumentStore::save`
+const DOCUMENT_FORMAT_VERSION: u32 = 1;
+
+/// Weight each score gets when a query doesn't choose
+pub const DEFAULT_WEIGHT: f32 = 0.5;
+
+/// Turns text into an embedding, with a local model or a remote API. The
+/// crate ships no model; plug one in with `EmbeddedQubeDB::set_embedder`.
+/// Any `Fn(&str) -> QubeResult<Vec<f32>>` closure is an embedder.
+pub trait Embedder: Send + Sync {
+    /// The embedding of `text`
+    fn embed(&self, text: &str) -> QubeResult<Vec<f32>>;
+}
+
+impl<F> Embedder for F
+where
+    F: Fn(&str) -> QubeResult<Vec<f32>> + Send + Sync,
+{
+    fn embed(&self, text: &str) -> QubeResult<Vec<f32>> {
+        self(text)
+    }
+}
+
+/// A document to index
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub embedding: Vec<f32>,
+    pub text: String,
+    pub metadata: Row,
+}
+
+/// What to rank documents by, and which to consider at all
+#[derive(Debug, Clone, PartialEq)]
+pub struct HybridQuery {
+    /// Embedding to compare documents against
+    pub vector: Option<Vec<f32>>,
+    /// Keywords to match documents' text against
+    pub text: Option<String>,
+    pub vector_weight: f32,
+    pub text_weight: f32,
+    /// Metadata a document must have, compared like SQL `=`
+    pub filters: Vec<(String, Value)>,
+    /// Most hits to return
+    pub k: usize,
+}
+
+impl HybridQuery {
+    /// A query returning at most `k` hits, weighting vector and text
+    /// scores equally
+    pub fn new(k: usize) -> Self {
+        HybridQuery {
+            vector: None,
+            text: None,
+            vector_weight: DEFAULT_WEIGHT,
+            text_weight: DEFAULT_WEIGHT,
+            filters: Vec::new(),
+            k,
+        }
+    }
+
+    pub fn with_vector(mut self, vector: Vec<f32>) -> Self {
+        self.vector = Some(vector);
+        self
+    }
+
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    pub fn with_weights(mut self, vector_weight: f32, text_weight: f32) -> Self {
+        self.vector_weight = vector_weight;
+        self.text_weight = text_weight;
+        self
+    }
+
+    /// Only consider documents whose `column` metadata equals `value`
+    pub fn with_filter(mut self, column: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.filters.push((column.into(), value.into()));
+        self
+    }
+}
+
+/// One ranked document
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HybridHit {
+    pub id: String,
+    /// Blended score, higher is better
+    pub score: f32,
+    /// Raw similarity under the collection's metric, if the query had a
+    /// vector
+    pub vector_score: Option<f32>,
+    /// Raw BM25 score, if the query had text
+    pub text_score: Option<f32>,
+}
+
+/// Documents indexed for vector, text and hybrid search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentCollection {
+    vectors: VectorIndex,
+    text: TextIndex,
+    metadata: BTreeMap<String, Row>,
+}
+
+impl DocumentCollection {
+    pub fn new(name: &str, dimensions: usize, metric: DistanceMetric) -> Self {
+        DocumentCollection {
+            vectors: VectorIndex::with_metric(name.to_string(), dimensions, metric),
+            text: TextIndex::new(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.vectors.name()
+    }
+
+    pub fn len(&self) -> usize {
+        self.metadata.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty()
+    }
+
+    pub fn vectors(&self) -> &VectorIndex {
+        &self.vectors
+    }
+
+    pub fn text(&self) -> &TextIndex {
+        &self.text
+    }
+
+    /// Index a document, replacing any with the same id
+    pub fn insert(&mut self, id: &str, document: Document) -> QubeResult<()> {
+        self.vectors.insert(id, &document.embedding)?;
+        self.text.insert(id, &document.text);
+        self.metadata.insert(id.to_string(), document.metadata);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.vectors.remove(id);
+        self.text.remove(id);
+        self.metadata.remove(id).is_some()
+    }
+
+    /// Rank the documents passing `query`'s filters by its blended score,
+    /// best first
+    pub fn search(&self, query: &HybridQuery) -> QubeResult<Vec<HybridHit>> {
+        let candidates: Vec<&String> = self
+            .metadata
+            .iter()
+            .filter(|(_, metadata)| {
+                query.filters.iter().all(|(column, expected)| {
+                    metadata
+                        .get(column)
+                        .and_then(|value| value.compare(expected))
+                        == Some(std::cmp::Ordering::Equal)
+                })
+            })
+            .map(|(id, _)| id)
+            .collect();
+
+        let vector_scores: Option<HashMap<String, f32>> = match &query.vector {
+            Some(vector) => Some(
+                self.vectors
+                    .search(vector, self.vectors.len())?
+                    .into_iter()
+                    .collect(),
+            ),
+            None => None,
+        };
+        let text_scores = query.text.as_deref().map(|text| self.text.scores(text));
+        if vector_scores.is_none() && text_scores.is_none() {
+            return Err(QubeError::VectorSearch(
+                "hybrid query needs a vector, text or both".to_string(),
+            ));
+        }
+
+        let lower_is_closer = self.vectors.metric().lower_is_closer();
+        let vector_scale = vector_scores
+            .as_ref()
+            .map(|scores| Scale::over(candidates.iter().filter_map(|id| scores.get(*id).copied())));
+        let text_scale = text_scores
+            .as_ref()
+            .map(|scores| Scale::over(candidates.iter().map(|id| score_of(scores, id))));
+
+        let mut hits: Vec<HybridHit> = candidates
+            .into_iter()
+            .map(|id| {
+                let vector_score = vector_scores.as_ref().and_then(|s| s.get(id).copied());
+                let text_score = text_scores.as_ref().map(|s| score_of(s, id));
+                let mut score = 0.0;
+                if let (Some(raw), Some(scale)) = (vector_score, &vector_scale) {
+                    let scaled = scale.apply(raw);
+                    score += query.vector_weight
+                        * if lower_is_closer {
+                            1.0 - scaled
+                        } else {
+                            scaled
+                        };
+                }
+                if let (Some(raw), Some(scale)) = (text_score, &text_scale) {
+                    score += query.text_weight * scale.apply(raw);
+                }
+                HybridHit {
+                    id: id.clone(),
+                    score,
+                    vector_score,
+                    text_score,
+                }
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+        hits.truncate(query.k);
+        Ok(hits)
+    }
+}
+
+/// A document's BM25 score, 0 if the query matched none of its terms
+fn score_of(scores: &HashMap<String, f32>, id: &str) -> f32 {
+    scores.get(id).copied().unwrap_or(0.0)
+}
+
+/// Min-max scaling of one kind of score to 0..1
+struct Scale {
+    min: f32,
+    max: f32,
+}
+
+impl Scale {
+    fn over(scores: impl Iterator<Item = f32>) -> Self {
+        scores.fold(
+            Scale {
+                min: f32::INFINITY,
+                max: f32::NEG_INFINITY,
+            },
+            |scale, score| Scale {
+                min: scale.min.min(score),
+                max: scale.max.max(score),
+            },
+        )
+    }
+
+    /// Where `score` falls between the lowest and highest; 1 when they are
+    /// all the same
+    fn apply(&self, score: f32) -> f32 {
+        if self.max > self.min {
+            (score - self.min) / (self.max - self.min)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Named document collections
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DocumentStore {
+    collections: BTreeMap<String, DocumentCollection>,
+}
+
+impl DocumentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn collection(&self, name: &str) -> Option<&DocumentCollection> {
+        self.collections.get(name)
+    }
+
+    pub fn collection_mut(&mut self, name: &str) -> Option<&mut DocumentCollection> {
+        self.collections.get_mut(name)
+    }
+
+    /// Add an empty collection; fails if the name is taken
+    pub fn create_collection(
+        &mut self,
+        name: &str,
+        dimensions: usize,
+        metric: DistanceMetric,
+    ) -> QubeResult<&mut DocumentCollection> {
+        if self.collections.contains_key(name) {
+            return Err(QubeError::AlreadyExists(format!(
+                "Document collection '{}'",
+                name
+            )));
+        }
+        Ok(self
+            .collections
+            .entry(name.to_string())
+            .or_insert_with(|| DocumentCollection::new(name, dimensions, metric)))
+    }
+
+    pub fn drop_collection(&mut self, name: &str) -> bool {
+        self.collections.remove(name).is_some()
+    }
+
+    /// Names of all collections, sorted
+    pub fn list_collections(&self) -> Vec<String> {
+        self.collections.keys().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.collections.clear();
+    }
+
+    /// Write every collection to `path`, via a temporary file so a crash
+    /// never leaves a partial one behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> QubeResult<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let bytes = serde_json::to_vec(&(DOCUMENT_FORMAT_VERSION, self))
+            .map_err(|e| QubeError::Serialization(format!("Failed to encode documents: {}", e)))?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read collections previously written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let (version, store): (u32, DocumentStore) =
+            serde_json::from_slice(&bytes).map_err(|e| {
+                QubeError::Serialization(format!(
+                    "Failed to decode documents {}: {}",
+                    path.as_ref().display(),
+                    e
+                ))
+            })?;
+
+        if version != DOCUMENT_FORMAT_VERSION {
+            return Err(QubeError::Serialization(format!(
+                "Unsupported document format version {}",
+                version
+            )));
+        }
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(embedding: [f32; 2], text: &str, lang: &str) -> Document {
+        let mut metadata = Row::new();
+        metadata.insert("lang".to_string(), Value::from(lang));
+        Document {
+            embedding: embedding.to_vec(),
+            text: text.to_string(),
+            metadata,
+        }
+    }
+
+    fn collection(metric: DistanceMetric) -> DocumentCollection {
+        let mut documents = DocumentCollection::new("docs", 2, metric);
+        documents
+            .insert("a", document([1.0, 0.0], "rust database engine", "en"))
+            .unwrap();
+        documents
+            .insert("b", document([0.0, 1.0], "rust rust rust", "en"))
+            .unwrap();
+        documents
+            .insert("c", document([0.7, 0.7], "cooking recipes", "fr"))
+            .unwrap();
+        documents
+    }
+
+    fn ids(hits: &[HybridHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.id.as_str()).collect()
+    }
+
+    #[test]
+    fn vector_and_text_scores_are_scaled_then_blended() {
+        let documents = collection(DistanceMetric::Cosine);
+
+        let by_vector = documents
+            .search(&HybridQuery::new(10).with_vector(vec![1.0, 0.0]))
+            .unwrap();
+        assert_eq!(ids(&by_vector), ["a", "c", "b"]);
+        assert_eq!(by_vector[0].score, DEFAULT_WEIGHT);
+        assert_eq!(by_vector[2].score, 0.0);
+        assert_eq!(by_vector[0].text_score, None);
+
+        // A document the keywords miss scores 0 for text
+        let by_text = documents
+            .search(&HybridQuery::new(10).with_text("rust"))
+            .unwrap();
+        assert_eq!(ids(&by_text), ["b", "a", "c"]);
+        assert_eq!(by_text[2].text_score, Some(0.0));
+        assert_eq!(by_text[2].vector_score, None);
+
+        let query = HybridQuery::new(10)
+            .with_vector(vec![0.0, 1.0])
+            .with_text("database");
+        assert_eq!(ids(&documents.search(&query).unwrap()), ["a", "b", "c"]);
+        let leaning = documents.search(&query.with_weights(0.8, 0.2)).unwrap();
+        assert_eq!(ids(&leaning), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn filters_and_k_limit_the_hits() {
+        let documents = collection(DistanceMetric::Cosine);
+        let query = HybridQuery::new(10).with_text("rust");
+        let french = documents
+            .search(&query.clone().with_filter("lang", "fr"))
+            .unwrap();
+        assert_eq!(ids(&french), ["c"]);
+        let none = documents
+            .search(&query.clone().with_filter("lang", "de"))
+            .unwrap();
+        assert!(none.is_empty());
+        let top = documents.search(&HybridQuery { k: 1, ..query }).unwrap();
+        assert_eq!(ids(&top), ["b"]);
+    }
+
+    #[test]
+    fn distances_rank_the_nearest_first() {
+        let documents = collection(DistanceMetric::Euclidean);
+        let hits = documents
+            .search(&HybridQuery::new(10).with_vector(vec![0.0, 1.0]))
+            .unwrap();
+        assert_eq!(ids(&hits), ["b", "c", "a"]);
+        assert_eq!(hits[0].vector_score, Some(0.0));
+        assert_eq!(hits[0].score, DEFAULT_WEIGHT);
+    }
+
+    #[test]
+    fn bad_queries_and_documents_are_refused() {
+        let mut documents = collection(DistanceMetric::Cosine);
+        assert!(documents.search(&HybridQuery::new(10)).is_err());
+        let wrong_size = Document {
+            embedding: vec![1.0],
+            ..Document::default()
+        };
+        assert!(documents.insert("d", wrong_size).is_err());
+        assert_eq!(documents.len(), 3);
+
+        assert!(documents.remove("b"));
+        assert!(!documents.remove("b"));
+        let hits = documents
+            .search(&HybridQuery::new(10).with_text("rust"))
+            .unwrap();
+        assert_eq!(ids(&hits), ["a", "c"]);
+    }
+
+    #[test]
+    fn stores_save_and_load_every_collection() {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-documents-{}.json", std::process::id()));
+        let mut store = DocumentStore::new();
+        store
+            .create_collection("empty", 3, DistanceMetric::DotProduct)
+            .unwrap();
+        *store
+            .create_collection("docs", 2, DistanceMetric::Euclidean)
+            .unwrap() = collection(DistanceMetric::Euclidean);
+        assert!(matches!(
+            store.create_collection("docs", 2, DistanceMetric::Cosine),
+            Err(QubeError::AlreadyExists(_))
+        ));
+        store.save(&path).unwrap();
+
+        let loaded = DocumentStore::load(&path).unwrap();
+        assert_eq!(loaded.list_collections(), ["docs", "empty"]);
+        assert_eq!(
+            loaded.collection("empty").unwrap().vectors().metric(),
+            DistanceMetric::DotProduct
+        );
+        let query = HybridQuery::new(10)
+            .with_vector(vec![0.0, 1.0])
+            .with_text("cooking");
+        assert_eq!(
+            loaded.collection("docs").unwrap().search(&query).unwrap(),
+            store.collection("docs").unwrap().search(&query).unwrap()
+        );
+
+        std::fs::write(&path, r#"[2, {"collections": {}}]"#).unwrap();
+        assert!(matches!(
+            DocumentStore::load(&path),
+            Err(QubeError::Serialization(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -8,8 +8,16 @@
 //! - Spatial indexes
 
 use crate::error::{QubeError, QubeResult};
-use crate::types::{Index, Value};
-use std::collections::HashMap;
+use crate::types::{Index, Row, Value};
+use crate::vector_cache::{VectorCacheConfig, VectorCacheStats, VectorStore};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::RwLock;
 
 /// Index manager for different index types
 pub struct IndexManager {
@@ -27,7 +35,7 @@ impl IndexManager {
     /// Create a new index
     pub fn create_index(&mut self, index: Index) -> QubeResult<()> {
         if self.indexes.contains_key(&index.name) {
-            return Err(QubeError::Index(format!("Index '{}' already exists", index.name)));
+            return Err(QubeError::AlreadyExists(format!("Index '{}'", index.name)));
         }
         
         self.indexes.insert(index.name.clone(), index);
@@ -37,7 +45,7 @@ impl IndexManager {
     /// Drop an index
     pub fn drop_index(&mut self, name: &str) -> QubeResult<()> {
         if self.indexes.remove(name).is_none() {
-            return Err(QubeError::Index(format!("Index '{}' not found", name)));
+            return Err(QubeError::NotFound(format!("Index '{}'", name)));
         }
         Ok(())
     }
@@ -45,7 +53,7 @@ impl IndexManager {
     /// Get index by name
     pub fn get_index(&self, name: &str) -> QubeResult<&Index> {
         self.indexes.get(name)
-            .ok_or_else(|| QubeError::Index(format!("Index '{}' not found", name)))
+            .ok_or_else(|| QubeError::NotFound(format!("Index '{}'", name)))
     }
     
     /// List all indexes
@@ -54,13 +62,93 @@ impl IndexManager {
     }
 }
 
+impl Default for IndexManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Key of a B-Tree index entry: one value per index column.
+///
+/// Keys order column by column, so every key sharing a leftmost prefix
+/// sits together and a shorter key sorts before all of its extensions.
+/// Values order NULL first, then booleans, numbers and timestamps (compared
+/// across widths), strings, binary, JSON and vectors.
+#[derive(Debug, Clone)]
+pub struct IndexKey(pub Vec<Value>);
+
+impl IndexKey {
+    /// Total order of two key values
+    pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+        fn rank(value: &Value) -> u8 {
+            match value {
+                Value::Null => 0,
+                Value::Boolean(_) => 1,
+                Value::String(_) => 3,
+                Value::Binary(_) => 4,
+                Value::Json(_) => 5,
+                Value::Vector(_) => 6,
+                // numbers and timestamps
+                _ => 2,
+            }
+        }
+        
+        rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+            (Value::Json(a), Value::Json(b)) => a.to_string().cmp(&b.to_string()),
+            (Value::Vector(a), Value::Vector(b)) => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| x.total_cmp(y))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            _ => a.compare(b).unwrap_or_else(|| match (a.as_f64(), b.as_f64()) {
+                // NaN sorts after every other number
+                (Some(x), Some(y)) => x.total_cmp(&y),
+                _ => Ordering::Equal,
+            }),
+        })
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| IndexKey::compare_values(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| self.0.len().cmp(&other.0.len()))
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+/// A key found by an index lookup and the row stored under it
+pub type IndexEntry<'a> = (&'a [Value], u64);
+
 /// B-Tree index implementation
+///
+/// Maps composite keys to the ids of the rows holding them. Because keys
+/// order by their leftmost column first, the index serves equality on any
+/// leftmost prefix of its columns, optionally followed by a range on the
+/// next column; it cannot serve a filter that skips the first column.
+#[derive(Debug, Clone)]
 pub struct BTreeIndex {
-    #[allow(dead_code)]
     name: String,
-    #[allow(dead_code)]
     columns: Vec<String>,
-    data: std::collections::HashMap<Vec<Value>, Vec<u8>>, // Key -> Row ID
+    data: BTreeMap<IndexKey, BTreeSet<u64>>, // Key -> Row IDs
 }
 
 impl BTreeIndex {
@@ -68,68 +156,480 @@ impl BTreeIndex {
         BTreeIndex {
             name,
             columns,
-            data: std::collections::HashMap::new(),
+            data: BTreeMap::new(),
+        }
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// Indexed columns, in key order
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    
+    /// Number of distinct keys
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    
+    /// The key a row is stored under; missing columns count as NULL
+    pub fn key_of(&self, row: &Row) -> Vec<Value> {
+        self.columns
+            .iter()
+            .map(|column| row.get(column).cloned().unwrap_or(Value::Null))
+            .collect()
+    }
+    
+    pub fn insert(&mut self, key: Vec<Value>, row_id: u64) {
+        self.data.entry(IndexKey(key)).or_default().insert(row_id);
+    }
+    
+    pub fn remove(&mut self, key: Vec<Value>, row_id: u64) {
+        let key = IndexKey(key);
+        if let Some(row_ids) = self.data.get_mut(&key) {
+            row_ids.remove(&row_id);
+            if row_ids.is_empty() {
+                self.data.remove(&key);
+            }
+        }
+    }
+    
+    /// Rows whose key starts with `prefix`
+    pub fn search(&self, prefix: &[Value]) -> Vec<u64> {
+        self.range_search(prefix, Bound::Unbounded, Bound::Unbounded)
+    }
+    
+    /// Rows whose key starts with `prefix` and whose next column lies
+    /// between `lower` and `upper`, in key order
+    pub fn range_search(&self, prefix: &[Value], lower: Bound<&Value>, upper: Bound<&Value>) -> Vec<u64> {
+        self.range_entries(prefix, lower, upper)
+            .into_iter()
+            .map(|(_, row_id)| row_id)
+            .collect()
+    }
+    
+    /// Like `range_search`, but with the key each row is stored under
+    pub fn range_entries(&self, prefix: &[Value], lower: Bound<&Value>, upper: Bound<&Value>) -> Vec<IndexEntry<'_>> {
+        self.range_iter(prefix, lower, upper, false).collect()
+    }
+    
+    /// Like `range_entries`, but lazy, and in descending key order when
+    /// `descending` is set. Rows sharing a key come in row id order either
+    /// way. Only the keys returned, plus at most one past either end, are
+    /// visited.
+    pub fn range_iter<'a>(
+        &'a self,
+        prefix: &[Value],
+        lower: Bound<&Value>,
+        upper: Bound<&Value>,
+        descending: bool,
+    ) -> Box<dyn Iterator<Item = IndexEntry<'a>> + 'a> {
+        let range = KeyRange {
+            prefix: prefix.to_vec(),
+            lower: lower.cloned(),
+            upper: upper.cloned(),
+        };
+        
+        let mut start = range.prefix.clone();
+        if let Bound::Included(value) | Bound::Excluded(value) = &range.lower {
+            start.push(value.clone());
         }
+        let keys: Box<dyn Iterator<Item = (&IndexKey, &BTreeSet<u64>)>> = if descending {
+            // Stop just past the last key in range, so the walk back from
+            // it starts there rather than at the end of the index
+            let end = match &range.upper {
+                Bound::Excluded(value) => {
+                    let mut end = range.prefix.clone();
+                    end.push(value.clone());
+                    Bound::Excluded(IndexKey(end))
+                }
+                Bound::Included(value) => {
+                    let mut group = range.prefix.clone();
+                    group.push(value.clone());
+                    self.key_after(&group)
+                }
+                Bound::Unbounded => self.key_after(&range.prefix),
+            };
+            Box::new(self.data.range((Bound::Included(IndexKey(start)), end)).rev())
+        } else {
+            Box::new(self.data.range(IndexKey(start)..))
+        };
+        
+        // Walking forward the range ends past the upper bound; walking
+        // backward it ends below the lower one
+        let end_side = if descending { Ordering::Less } else { Ordering::Greater };
+        Box::new(
+            keys.map(move |(key, row_ids)| (range.position(&key.0), key, row_ids))
+                .take_while(move |(position, ..)| position.is_some_and(|p| p != end_side))
+                .filter(|(position, ..)| *position == Some(Ordering::Equal))
+                .flat_map(|(_, key, row_ids)| {
+                    row_ids.iter().map(move |&row_id| (key.0.as_slice(), row_id))
+                }),
+        )
     }
     
-    pub fn insert(&mut self, key: Vec<Value>, row_id: Vec<u8>) {
-        self.data.insert(key, row_id);
+    /// Bound just past every key starting with `group`
+    fn key_after(&self, group: &[Value]) -> Bound<IndexKey> {
+        if group.is_empty() {
+            return Bound::Unbounded;
+        }
+        self.data
+            .range((Bound::Excluded(IndexKey(group.to_vec())), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .find(|key| !starts_with(&key.0, group))
+            .map_or(Bound::Unbounded, |key| Bound::Excluded(key.clone()))
     }
     
-    pub fn search(&self, key: &[Value]) -> Option<&Vec<u8>> {
-        self.data.get(key)
+    /// Every entry, in key order
+    pub fn entries(&self) -> impl Iterator<Item = IndexEntry<'_>> + '_ {
+        self.data
+            .iter()
+            .flat_map(|(key, row_ids)| row_ids.iter().map(move |&row_id| (key.0.as_slice(), row_id)))
     }
     
-    pub fn range_search(&self, _start: &[Value], _end: &[Value]) -> Vec<&Vec<u8>> {
-        // TODO: Implement range search
-        vec![]
+    /// Whether `row_id` is stored under exactly `key`
+    pub fn contains(&self, key: Vec<Value>, row_id: u64) -> bool {
+        self.data
+            .get(&IndexKey(key))
+            .is_some_and(|row_ids| row_ids.contains(&row_id))
+    }
+}
+
+/// The keys a range lookup selects: a prefix of equal leading values,
+/// then bounds on the next value
+struct KeyRange {
+    prefix: Vec<Value>,
+    lower: Bound<Value>,
+    upper: Bound<Value>,
+}
+
+impl KeyRange {
+    /// Where a key lies relative to the range: `None` outside the prefix,
+    /// `Less` below the lower bound, `Greater` past the upper one
+    fn position(&self, key: &[Value]) -> Option<Ordering> {
+        if !starts_with(key, &self.prefix) {
+            return None;
+        }
+        let Some(value) = key.get(self.prefix.len()) else {
+            return Some(Ordering::Equal);
+        };
+        let below = match &self.lower {
+            Bound::Included(lower) => IndexKey::compare_values(value, lower).is_lt(),
+            Bound::Excluded(lower) => IndexKey::compare_values(value, lower).is_le(),
+            Bound::Unbounded => false,
+        };
+        let past = match &self.upper {
+            Bound::Included(upper) => IndexKey::compare_values(value, upper).is_gt(),
+            Bound::Excluded(upper) => IndexKey::compare_values(value, upper).is_ge(),
+            Bound::Unbounded => false,
+        };
+        Some(if below {
+            Ordering::Less
+        } else if past {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        })
+    }
+}
+
+/// Whether `key` starts with the values of `prefix`
+fn starts_with(key: &[Value], prefix: &[Value]) -> bool {
+    key.len() >= prefix.len()
+        && key.iter().zip(prefix).all(|(a, b)| IndexKey::compare_values(a, b).is_eq())
+}
+
+/// A way an index has drifted from its table's rows
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum IndexIssueKind {
+    /// The entry points at a row that no longer exists
+    DanglingEntry,
+    /// The row exists but no longer holds the entry's key
+    StaleEntry { row_key: Vec<Value> },
+    /// The row has no entry under its key
+    MissingEntry,
+}
+
+/// A discrepancy between an index and the rows it covers, found by
+/// `QueryEngine::verify_indexes`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexIssue {
+    pub table: String,
+    pub index: String,
+    pub row_id: u64,
+    /// The key the index holds, or should hold, for the row
+    pub key: Vec<Value>,
+    pub kind: IndexIssueKind,
+}
+
+impl std::fmt::Display for IndexIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}: ", self.table, self.index)?;
+        match &self.kind {
+            IndexIssueKind::DanglingEntry => {
+                write!(f, "entry {:?} points at missing row {}", self.key, self.row_id)
+            }
+            IndexIssueKind::StaleEntry { row_key } => write!(
+                f,
+                "entry {:?} points at row {}, which holds {:?}",
+                self.key, self.row_id, row_key
+            ),
+            IndexIssueKind::MissingEntry => {
+                write!(f, "row {} has no entry under {:?}", self.row_id, self.key)
+            }
+        }
     }
 }
 
+/// Default number of partitions of a `HashIndex`
+pub const DEFAULT_HASH_INDEX_SHARDS: usize = 16;
+
 /// Hash index implementation
+///
+/// Entries are split over a number of partitions by the hash of their key,
+/// each behind its own lock, so operations on keys in different partitions
+/// run in parallel instead of queueing for one lock. Only the index is
+/// partitioned: table rows stay in the catalog, behind its single lock.
 pub struct HashIndex {
     #[allow(dead_code)]
     name: String,
     #[allow(dead_code)]
     columns: Vec<String>,
-    data: HashMap<Vec<Value>, Vec<u8>>, // Key -> Row ID
+    shards: Vec<RwLock<HashMap<Vec<Value>, Vec<u8>>>>, // Key -> Row ID
 }
 
 impl HashIndex {
     pub fn new(name: String, columns: Vec<String>) -> Self {
+        Self::with_shards(name, columns, DEFAULT_HASH_INDEX_SHARDS)
+    }
+    
+    /// An index split into `shards` partitions; at least one is used
+    pub fn with_shards(name: String, columns: Vec<String>, shards: usize) -> Self {
         HashIndex {
             name,
             columns,
-            data: HashMap::new(),
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+    
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    
+    /// The partition holding `key`
+    fn shard(&self, key: &[Value]) -> &RwLock<HashMap<Vec<Value>, Vec<u8>>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+    
+    /// Map `key` to `row_id`, returning the row id it replaced
+    pub fn insert(&self, key: Vec<Value>, row_id: Vec<u8>) -> Option<Vec<u8>> {
+        self.shard(&key).write().unwrap().insert(key, row_id)
+    }
+    
+    pub fn search(&self, key: &[Value]) -> Option<Vec<u8>> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+    
+    pub fn remove(&self, key: &[Value]) -> Option<Vec<u8>> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+    
+    /// Set `key`'s row id to `f` of its current one, under its partition's
+    /// lock, so concurrent updates of the same key are never lost
+    pub fn update<F>(&self, key: Vec<Value>, f: F)
+    where
+        F: FnOnce(Option<&Vec<u8>>) -> Vec<u8>,
+    {
+        let mut shard = self.shard(&key).write().unwrap();
+        let row_id = f(shard.get(&key));
+        shard.insert(key, row_id);
+    }
+    
+    /// Number of keys, summed over the partitions one at a time
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().unwrap().is_empty())
+    }
+}
+
+/// On-disk format version written by `VectorIndex::save`. Version 1 had no
+/// normalization and is still read.
+const VECTOR_INDEX_FORMAT_VERSION: u32 = 2;
+
+/// How vector similarity is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DistanceMetric {
+    /// Cosine similarity, higher is closer
+    #[default]
+    Cosine,
+    /// Euclidean (L2) distance, lower is closer
+    Euclidean,
+    /// Dot product, higher is closer
+    DotProduct,
+}
+
+impl DistanceMetric {
+    /// Score `b` against `a` under this metric
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::Cosine => cosine_similarity(a, b),
+            DistanceMetric::Euclidean => a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+            DistanceMetric::DotProduct => a.iter().zip(b).map(|(x, y)| x * y).sum(),
         }
     }
     
-    pub fn insert(&mut self, key: Vec<Value>, row_id: Vec<u8>) {
-        self.data.insert(key, row_id);
+    /// Whether a lower score means a closer match
+    pub fn lower_is_closer(&self) -> bool {
+        matches!(self, DistanceMetric::Euclidean)
+    }
+}
+
+impl std::fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistanceMetric::Cosine => write!(f, "cosine"),
+            DistanceMetric::Euclidean => write!(f, "euclidean"),
+            DistanceMetric::DotProduct => write!(f, "dot"),
+        }
     }
+}
+
+impl std::str::FromStr for DistanceMetric {
+    type Err = QubeError;
     
-    pub fn search(&self, key: &[Value]) -> Option<&Vec<u8>> {
-        self.data.get(key)
+    fn from_str(s: &str) -> QubeResult<Self> {
+        match s.to_lowercase().as_str() {
+            "cosine" => Ok(DistanceMetric::Cosine),
+            "euclidean" | "l2" => Ok(DistanceMetric::Euclidean),
+            "dot" | "dot_product" | "inner_product" => Ok(DistanceMetric::DotProduct),
+            other => Err(QubeError::VectorSearch(format!("Unknown distance metric: {}", other))),
+        }
     }
 }
 
 /// Vector index for AI/ML similarity search
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VectorIndex {
-    #[allow(dead_code)]
     name: String,
     dimensions: usize,
+    metric: DistanceMetric,
+    /// Whether vectors are stored scaled to unit length
+    normalize: bool,
+    /// The vectors, possibly some of them spilled to disk
+    vectors: VectorStore,
+    /// Length of each vector before it was normalized, when normalizing
+    norms: BTreeMap<String, f32>,
     // TODO: Integrate with FAISS or HNSW
 }
 
+/// Layout of a version 1 index file
+#[derive(Deserialize)]
+struct VectorIndexV1 {
+    name: String,
+    dimensions: usize,
+    metric: DistanceMetric,
+    vectors: BTreeMap<String, Vec<f32>>,
+}
+
 impl VectorIndex {
     pub fn new(name: String, dimensions: usize) -> Self {
+        Self::with_metric(name, dimensions, DistanceMetric::default())
+    }
+    
+    pub fn with_metric(name: String, dimensions: usize, metric: DistanceMetric) -> Self {
         VectorIndex {
             name,
             dimensions,
+            metric,
+            normalize: false,
+            vectors: VectorStore::new(),
+            norms: BTreeMap::new(),
         }
     }
     
-    pub fn insert(&mut self, _id: &str, vector: &[f32]) -> QubeResult<()> {
+    /// Store vectors scaled to unit (L2) length, so cosine similarity is a
+    /// plain dot product at search time and queries are normalized once
+    /// rather than every vector on every search. Other metrics then compare
+    /// unit vectors too. Vectors already in the index are converted.
+    pub fn with_normalization(mut self, normalize: bool) -> Self {
+        let norms = &mut self.norms;
+        let converted = if normalize && !self.normalize {
+            self.vectors.map_all(|id, vector| {
+                let norm = l2_norm(vector);
+                scale(vector, norm);
+                norms.insert(id.to_string(), norm);
+            })
+        } else if !normalize && self.normalize {
+            let converted = self.vectors.map_all(|id, vector| {
+                let norm = norms.get(id).copied().unwrap_or(1.0);
+                vector.iter_mut().for_each(|x| *x *= norm);
+            });
+            norms.clear();
+            converted
+        } else {
+            Ok(())
+        };
+        if let Err(e) = converted {
+            tracing::warn!("Failed to convert spilled vectors of {}: {}", self.name, e);
+        }
+        self.normalize = normalize;
+        self
+    }
+    
+    /// Keep at most `config.memory_budget` bytes of vectors in memory,
+    /// spilling the coldest to `config.spill_path` by `config.policy`.
+    /// Spilled vectors are still searched, and loaded back when read.
+    pub fn with_cache(mut self, config: VectorCacheConfig) -> QubeResult<Self> {
+        self.set_cache(config)?;
+        Ok(self)
+    }
+    
+    /// Give an existing index a memory budget, as `with_cache` does
+    pub fn set_cache(&mut self, config: VectorCacheConfig) -> QubeResult<()> {
+        self.vectors.set_cache(config)
+    }
+    
+    /// Where the vectors are, for an index with a cache
+    pub fn cache_stats(&self) -> Option<VectorCacheStats> {
+        self.vectors.cache_stats()
+    }
+    
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+    
+    pub fn is_normalized(&self) -> bool {
+        self.normalize
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+    
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    pub fn insert(&mut self, id: &str, vector: &[f32]) -> QubeResult<()> {
         if vector.len() != self.dimensions {
             return Err(QubeError::Index(format!(
                 "Vector dimension mismatch: expected {}, got {}",
@@ -138,11 +638,49 @@ impl VectorIndex {
             )));
         }
         
-        // TODO: Implement actual vector insertion
-        Ok(())
+        let mut vector = vector.to_vec();
+        if self.normalize {
+            let norm = l2_norm(&vector);
+            scale(&mut vector, norm);
+            self.norms.insert(id.to_string(), norm);
+        }
+        self.vectors.insert(id, vector)
+    }
+    
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.norms.remove(id);
+        self.vectors.remove(id)
+    }
+    
+    /// A vector as it was inserted. A normalizing index rebuilds it from
+    /// the unit vector and its length, so it may differ in the last bits.
+    pub fn get(&self, id: &str) -> Option<Vec<f32>> {
+        let vector = self.stored(id)?;
+        let norm = self.norms.get(id).copied().unwrap_or(1.0);
+        Some(vector.iter().map(|x| x * norm).collect())
+    }
+    
+    /// A vector scaled to unit length; all zeros stays all zeros
+    pub fn get_normalized(&self, id: &str) -> Option<Vec<f32>> {
+        let mut vector = self.stored(id)?;
+        if !self.normalize {
+            let norm = l2_norm(&vector);
+            scale(&mut vector, norm);
+        }
+        Some(vector)
     }
     
-    pub fn search(&self, query_vector: &[f32], _k: usize) -> QubeResult<Vec<(String, f32)>> {
+    /// A vector as stored, loaded back if it was spilled
+    fn stored(&self, id: &str) -> Option<Vec<f32>> {
+        self.vectors.get(id).unwrap_or_else(|e| {
+            tracing::warn!("Failed to load spilled vector {}: {}", id, e);
+            None
+        })
+    }
+    
+    /// Find the `k` vectors closest to `query_vector` under the index metric,
+    /// best match first
+    pub fn search(&self, query_vector: &[f32], k: usize) -> QubeResult<Vec<(String, f32)>> {
         if query_vector.len() != self.dimensions {
             return Err(QubeError::Index(format!(
                 "Query vector dimension mismatch: expected {}, got {}",
@@ -151,7 +689,513 @@ impl VectorIndex {
             )));
         }
         
-        // TODO: Implement actual vector search
-        Ok(vec![])
+        let mut query = query_vector.to_vec();
+        if self.normalize {
+            let norm = l2_norm(&query);
+            scale(&mut query, norm);
+        }
+        let score = |vector: &[f32]| match self.metric {
+            DistanceMetric::Cosine if self.normalize => DistanceMetric::DotProduct.score(&query, vector),
+            metric => metric.score(&query, vector),
+        };
+        
+        // Exhaustive scan until an approximate index is integrated
+        let mut results: Vec<(String, f32)> = Vec::with_capacity(self.len());
+        self.vectors.scan(|id, vector| results.push((id.to_string(), score(vector))))?;
+        let lower_is_closer = self.metric.lower_is_closer();
+        results.sort_by(|a, b| {
+            let ordering = if lower_is_closer { a.1.total_cmp(&b.1) } else { b.1.total_cmp(&a.1) };
+            ordering.then_with(|| a.0.cmp(&b.0))
+        });
+        results.truncate(k);
+        self.vectors.touch(results.iter().map(|(id, _)| id.as_str()));
+        Ok(results)
+    }
+    
+    /// Write the whole index structure to `path`. The file is written to a
+    /// temporary sibling first and renamed into place, so a crash never leaves
+    /// a half-written index behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> QubeResult<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        
+        let bytes = bincode::serialize(&(VECTOR_INDEX_FORMAT_VERSION, self))
+            .map_err(|e| QubeError::Serialization(format!("Failed to encode vector index: {}", e)))?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    
+    /// Read an index previously written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let decode_error = |e: bincode::Error| QubeError::Serialization(format!(
+            "Failed to decode vector index {}: {}",
+            path.as_ref().display(),
+            e
+        ));
+        let version: u32 = bincode::deserialize(&bytes).map_err(decode_error)?;
+        
+        match version {
+            1 => {
+                let (_, old): (u32, VectorIndexV1) = bincode::deserialize(&bytes).map_err(decode_error)?;
+                let mut index = VectorIndex::with_metric(old.name, old.dimensions, old.metric);
+                index.vectors = old.vectors.into();
+                Ok(index)
+            }
+            VECTOR_INDEX_FORMAT_VERSION => {
+                let (_, index): (u32, VectorIndex) = bincode::deserialize(&bytes).map_err(decode_error)?;
+                Ok(index)
+            }
+            _ => Err(QubeError::Serialization(format!(
+                "Unsupported vector index format version {}",
+                version
+            ))),
+        }
+    }
+}
+
+/// BM25 term-frequency saturation
+const BM25_K1: f32 = 1.2;
+
+/// BM25 document-length normalization
+const BM25_B: f32 = 0.75;
+
+/// Inverted index over documents' text, ranked by BM25
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextIndex {
+    /// Documents each term occurs in
+    postings: HashMap<String, BTreeSet<String>>,
+    /// How often each term occurs in each document
+    documents: HashMap<String, HashMap<String, u32>>,
+    /// Terms across all documents
+    total_terms: usize,
+}
+
+impl TextIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+    
+    /// Index a document's text, replacing what was indexed for it before
+    pub fn insert(&mut self, id: &str, text: &str) {
+        self.remove(id);
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for term in tokenize(text) {
+            *counts.entry(term).or_default() += 1;
+        }
+        for term in counts.keys() {
+            self.postings.entry(term.clone()).or_default().insert(id.to_string());
+        }
+        self.total_terms += counts.values().sum::<u32>() as usize;
+        self.documents.insert(id.to_string(), counts);
+    }
+    
+    pub fn remove(&mut self, id: &str) -> bool {
+        let Some(counts) = self.documents.remove(id) else {
+            return false;
+        };
+        for term in counts.keys() {
+            if let Some(ids) = self.postings.get_mut(term) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_terms -= counts.values().sum::<u32>() as usize;
+        true
+    }
+    
+    /// BM25 score of every document containing a term of `query`
+    pub fn scores(&self, query: &str) -> HashMap<String, f32> {
+        let mut scores: HashMap<String, f32> = HashMap::new();
+        if self.documents.is_empty() {
+            return scores;
+        }
+        let documents = self.documents.len() as f32;
+        let average_length = self.total_terms as f32 / documents;
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        for term in terms {
+            let Some(ids) = self.postings.get(&term) else {
+                continue;
+            };
+            let matching = ids.len() as f32;
+            let idf = (1.0 + (documents - matching + 0.5) / (matching + 0.5)).ln();
+            for id in ids {
+                let counts = &self.documents[id];
+                let tf = counts[&term] as f32;
+                let length = counts.values().sum::<u32>() as f32;
+                let norm = 1.0 - BM25_B + BM25_B * length / average_length.max(1.0);
+                *scores.entry(id.clone()).or_default() += idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm);
+            }
+        }
+        scores
+    }
+    
+    /// The `k` documents most relevant to `query`, best first
+    pub fn search(&self, query: &str, k: usize) -> Vec<(String, f32)> {
+        let mut results: Vec<(String, f32)> = self.scores(query).into_iter().collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        results.truncate(k);
+        results
+    }
+}
+
+/// Split text into lower-cased runs of letters and digits
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Euclidean length of a vector
+fn l2_norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// Divide a vector by its length `norm`, leaving an all-zeros vector alone
+fn scale(vector: &mut [f32], norm: f32) {
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Cosine similarity of two equal-length vectors; 0 if either is all zeros
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn btree_index(name: &str) -> Index {
+        Index {
+            name: name.to_string(),
+            columns: vec!["id".to_string()],
+            index_type: crate::types::IndexType::BTree,
+            unique: false,
+        }
+    }
+
+    #[test]
+    fn index_manager_errors_name_the_index() {
+        let mut manager = IndexManager::new();
+        manager.create_index(btree_index("by_id")).unwrap();
+
+        let error = manager.create_index(btree_index("by_id")).unwrap_err();
+        assert!(matches!(error, QubeError::AlreadyExists(_)));
+        assert_eq!(error.to_string(), "Already exists: Index 'by_id'");
+
+        let error = manager.get_index("missing").unwrap_err();
+        assert!(matches!(error, QubeError::NotFound(_)));
+        assert_eq!(error.to_string(), "Not found: Index 'missing'");
+
+        let error = manager.drop_index("missing").unwrap_err();
+        assert_eq!(error.to_string(), "Not found: Index 'missing'");
+
+        manager.drop_index("by_id").unwrap();
+        assert!(manager.list_indexes().is_empty());
+    }
+
+    #[test]
+    fn other_error_displays_its_message_alone() {
+        let error = QubeError::Other("index rebuild interrupted".to_string());
+        assert_eq!(error.to_string(), "index rebuild interrupted");
+    }
+
+    fn index(shards: usize) -> Arc<HashIndex> {
+        Arc::new(HashIndex::with_shards(
+            "idx".to_string(),
+            vec!["id".to_string()],
+            shards,
+        ))
+    }
+
+    /// Two keys the index files in different partitions
+    fn keys_in_different_shards(index: &HashIndex) -> (Vec<Value>, Vec<Value>) {
+        let first = vec![Value::Int32(0)];
+        let other = (1..)
+            .map(|i| vec![Value::Int32(i)])
+            .find(|key| !std::ptr::eq(index.shard(key), index.shard(&first)))
+            .unwrap();
+        (first, other)
+    }
+
+    /// Whether an insert of `other` finishes while an update of `held` is
+    /// stalled inside its partition lock
+    fn insert_proceeds_during_update(
+        index: Arc<HashIndex>,
+        held: Vec<Value>,
+        other: Vec<Value>,
+    ) -> bool {
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let updater = {
+            let index = Arc::clone(&index);
+            thread::spawn(move || {
+                index.update(held, |_| {
+                    entered_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    vec![1]
+                })
+            })
+        };
+        entered_rx.recv().unwrap();
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let inserter = {
+            let index = Arc::clone(&index);
+            thread::spawn(move || {
+                index.insert(other, vec![2]);
+                done_tx.send(()).unwrap();
+            })
+        };
+        let proceeded = done_rx.recv_timeout(Duration::from_millis(500)).is_ok();
+
+        release_tx.send(()).unwrap();
+        updater.join().unwrap();
+        inserter.join().unwrap();
+        proceeded
+    }
+
+    #[test]
+    fn keys_in_other_partitions_are_not_blocked() {
+        let sharded = index(DEFAULT_HASH_INDEX_SHARDS);
+        let (held, other) = keys_in_different_shards(&sharded);
+        assert!(insert_proceeds_during_update(
+            sharded,
+            held.clone(),
+            other.clone()
+        ));
+
+        let single = index(1);
+        assert!(!insert_proceeds_during_update(
+            Arc::clone(&single),
+            held,
+            other.clone()
+        ));
+        assert_eq!(single.search(&other), Some(vec![2]));
+    }
+
+    #[test]
+    fn parallel_writers_lose_no_updates() {
+        const THREADS: u32 = 8;
+        const KEYS: i32 = 500;
+
+        for shards in [1, DEFAULT_HASH_INDEX_SHARDS] {
+            let index = index(shards);
+            let workers: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let index = Arc::clone(&index);
+                    thread::spawn(move || {
+                        for i in 0..KEYS {
+                            index.insert(vec![Value::Int32(t as i32 * KEYS + i)], vec![t as u8]);
+                            index.update(vec![Value::Int64(i as i64)], |count| {
+                                let count = count
+                                    .map_or(0, |c| u32::from_le_bytes(c[..4].try_into().unwrap()));
+                                (count + 1).to_le_bytes().to_vec()
+                            });
+                            assert!(index.search(&[Value::Int32(t as i32 * KEYS + i)]).is_some());
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+
+            assert_eq!(index.len(), (THREADS as i32 * KEYS + KEYS) as usize);
+            for i in 0..KEYS {
+                let count = index.search(&[Value::Int64(i as i64)]).unwrap();
+                assert_eq!(u32::from_le_bytes(count[..4].try_into().unwrap()), THREADS);
+            }
+        }
+    }
+
+    fn vector_index_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-{}-{}.qvi", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn saved_vector_indexes_load_with_the_same_results() {
+        let path = vector_index_path("vector-index");
+        let mut index =
+            VectorIndex::with_metric("points".to_string(), 2, DistanceMetric::Euclidean)
+                .with_normalization(true);
+        index.insert("a", &[3.0, 4.0]).unwrap();
+        index.insert("b", &[0.0, 2.0]).unwrap();
+        index.insert("c", &[-1.0, 0.0]).unwrap();
+        index.save(&path).unwrap();
+
+        let loaded = VectorIndex::load(&path).unwrap();
+        assert_eq!(loaded.name(), "points");
+        assert_eq!(loaded.dimensions(), 2);
+        assert_eq!(loaded.metric(), DistanceMetric::Euclidean);
+        assert!(loaded.is_normalized());
+        assert_eq!(loaded.get("a"), index.get("a"));
+        assert_eq!(
+            loaded.search(&[1.0, 1.0], 3).unwrap(),
+            index.search(&[1.0, 1.0], 3).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn version_one_vector_indexes_still_load() {
+        let path = vector_index_path("vector-index-v1");
+        let vectors: BTreeMap<String, Vec<f32>> =
+            [("a".to_string(), vec![1.0, 0.0])].into_iter().collect();
+        let v1 = (1u32, "old".to_string(), 2usize, DistanceMetric::DotProduct, vectors);
+        std::fs::write(&path, bincode::serialize(&v1).unwrap()).unwrap();
+
+        let loaded = VectorIndex::load(&path).unwrap();
+        assert_eq!(loaded.name(), "old");
+        assert_eq!(loaded.metric(), DistanceMetric::DotProduct);
+        assert!(!loaded.is_normalized());
+        assert_eq!(loaded.get("a"), Some(vec![1.0, 0.0]));
+
+        let future = bincode::serialize(&(99u32, "future".to_string())).unwrap();
+        std::fs::write(&path, future).unwrap();
+        assert!(matches!(
+            VectorIndex::load(&path),
+            Err(QubeError::Serialization(_))
+        ));
+        std::fs::write(&path, [1u8]).unwrap();
+        assert!(matches!(
+            VectorIndex::load(&path),
+            Err(QubeError::Serialization(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+
+    #[test]
+    fn index_keys_order_column_by_column() {
+        let key = |values: &[Value]| IndexKey(values.to_vec());
+        assert!(key(&[Value::Null]) < key(&[Value::Int32(i32::MIN)]));
+        assert_eq!(key(&[Value::Int32(2)]), key(&[Value::Int64(2)]));
+        assert!(key(&[Value::Int64(2)]) < key(&[Value::Float64(2.5)]));
+        assert!(key(&[Value::Int32(1)]) < key(&[Value::Int32(1), Value::Null]));
+        let (one_nine, two_zero) = (
+            key(&[Value::Int32(1), Value::Int32(9)]),
+            key(&[Value::Int32(2), Value::Int32(0)]),
+        );
+        assert!(one_nine < two_zero);
+    }
+
+    #[test]
+    fn btree_lookups_use_a_leftmost_prefix_and_a_range() {
+        let columns = vec!["a".to_string(), "b".to_string()];
+        let mut index = BTreeIndex::new("by_ab".to_string(), columns);
+        for (row_id, (a, b)) in [(1, 1), (1, 2), (1, 3), (2, 1), (2, 2)].into_iter().enumerate() {
+            index.insert(vec![Value::Int32(a), Value::Int32(b)], row_id as u64);
+        }
+        // Rows sharing a key are all kept
+        index.insert(vec![Value::Int32(1), Value::Int32(2)], 9);
+        assert_eq!(index.len(), 5);
+
+        assert_eq!(index.search(&[Value::Int32(1)]), [0, 1, 9, 2]);
+        assert_eq!(index.search(&[Value::Int64(2), Value::Int64(2)]), [4]);
+        assert!(index.search(&[Value::Int32(3)]).is_empty());
+        let (two, three) = (Value::Int32(2), Value::Int32(3));
+        assert_eq!(
+            index.range_search(&[Value::Int32(1)], Bound::Excluded(&two), Bound::Unbounded),
+            [2]
+        );
+        assert_eq!(
+            index.range_search(&[Value::Int32(1)], Bound::Unbounded, Bound::Included(&two)),
+            [0, 1, 9]
+        );
+        assert_eq!(index.range_search(&[], Bound::Included(&two), Bound::Excluded(&three)), [3, 4]);
+
+        index.remove(vec![Value::Int32(1), Value::Int32(2)], 1);
+        index.remove(vec![Value::Int32(1), Value::Int32(2)], 9);
+        assert_eq!(index.search(&[Value::Int32(1)]), [0, 2]);
+        assert_eq!(index.len(), 4);
+        let row = Row::from([("a".to_string(), Value::Int32(7))]);
+        assert_eq!(index.key_of(&row), [Value::Int32(7), Value::Null]);
+    }
+
+
+    #[test]
+    fn range_entries_return_the_stored_keys() {
+        let columns = vec!["a".to_string(), "b".to_string()];
+        let mut index = BTreeIndex::new("by_ab".to_string(), columns);
+        index.insert(vec![Value::Int32(1), Value::Int32(7)], 4);
+        index.insert(vec![Value::Int32(1), Value::Int32(8)], 2);
+        index.insert(vec![Value::Int32(2), Value::Int32(7)], 3);
+
+        let entries = index.range_entries(&[Value::Int32(1)], Bound::Unbounded, Bound::Unbounded);
+        let expected: [(&[Value], u64); 2] = [
+            (&[Value::Int32(1), Value::Int32(7)], 4),
+            (&[Value::Int32(1), Value::Int32(8)], 2),
+        ];
+        assert_eq!(entries, expected);
+    }
+
+
+    #[test]
+    fn normalized_vectors_keep_their_length_and_cosine_scores() {
+        let vectors: [(&str, [f32; 2]); 3] =
+            [("a", [3.0, 4.0]), ("b", [0.0, 2.0]), ("z", [0.0; 2])];
+        let mut plain = VectorIndex::new("plain".to_string(), 2);
+        let mut unit = VectorIndex::new("unit".to_string(), 2).with_normalization(true);
+        for (id, vector) in vectors {
+            plain.insert(id, &vector).unwrap();
+            unit.insert(id, &vector).unwrap();
+        }
+        assert!(unit.is_normalized() && !plain.is_normalized());
+        assert_eq!(unit.get("a"), Some(vec![3.0, 4.0]));
+        assert_eq!(unit.get_normalized("a"), Some(vec![0.6, 0.8]));
+        assert_eq!(plain.get_normalized("a"), Some(vec![0.6, 0.8]));
+        assert_eq!(unit.get_normalized("z"), Some(vec![0.0, 0.0]));
+
+        let (from_plain, from_unit) = (
+            plain.search(&[6.0, 8.0], 2).unwrap(),
+            unit.search(&[6.0, 8.0], 2).unwrap(),
+        );
+        assert_eq!(from_unit[0], ("a".to_string(), 1.0));
+        for ((plain_id, plain_score), (unit_id, unit_score)) in from_plain.iter().zip(&from_unit) {
+            assert_eq!(plain_id, unit_id);
+            assert!((plain_score - unit_score).abs() < 1e-6);
+        }
+
+        unit.remove("a");
+        assert_eq!(unit.get("a"), None);
+        let restored = unit.with_normalization(false);
+        assert_eq!(restored.get("b"), Some(vec![0.0, 2.0]));
+        let converted = plain.with_normalization(true);
+        assert_eq!(converted.get("a"), Some(vec![3.0, 4.0]));
+        assert_eq!(converted.get_normalized("b"), Some(vec![0.0, 1.0]));
     }
 }
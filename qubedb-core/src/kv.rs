@@ -0,0 +1,146 @@
+//! Key-value namespace for QubeDB
+//!
+//! An ordered map from string keys to byte values that lives alongside the
+//! SQL tables, for Redis-style access to data that needs no schema. Keys
+//! are kept sorted so every key sharing a prefix can be scanned in order.
+
+use crate::error::{QubeError, QubeResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+/// On-disk format version written by `KvNamespace::save`
+const KV_FORMAT_VERSION: u32 = 1;
+
+/// Sorted key-value pairs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KvNamespace {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl KvNamespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `value` under `key`, returning the value it replaced
+    pub fn put(&mut self, key: impl Into<String>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.entries.insert(key.into(), value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Remove `key`, returning whether it was present
+    pub fn delete(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Every entry whose key starts with `prefix`, in key order
+    pub fn scan<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a [u8])> + 'a {
+        self.entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    /// Remove every entry
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write the namespace to `path`, replacing the file atomically
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> QubeResult<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let bytes = bincode::serialize(&(KV_FORMAT_VERSION, self)).map_err(|e| {
+            QubeError::Serialization(format!("Failed to encode key-value namespace: {}", e))
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read a namespace previously written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> QubeResult<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let (version, namespace): (u32, KvNamespace) =
+            bincode::deserialize(&bytes).map_err(|e| {
+                QubeError::Serialization(format!(
+                    "Failed to decode key-value namespace {}: {}",
+                    path.as_ref().display(),
+                    e
+                ))
+            })?;
+
+        if version != KV_FORMAT_VERSION {
+            return Err(QubeError::Serialization(format!(
+                "Unsupported key-value namespace format version {}",
+                version
+            )));
+        }
+        Ok(namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace() -> KvNamespace {
+        let mut kv = KvNamespace::new();
+        for key in ["user:2", "user:1", "session:9", "user", "users:1"] {
+            kv.put(key, key.as_bytes().to_vec());
+        }
+        kv
+    }
+
+    #[test]
+    fn scans_return_a_prefix_in_key_order() {
+        let mut kv = namespace();
+        let users: Vec<&str> = kv.scan("user:").map(|(key, _)| key).collect();
+        assert_eq!(users, ["user:1", "user:2"]);
+        let all: Vec<&str> = kv.scan("user").map(|(key, _)| key).collect();
+        assert_eq!(all, ["user", "user:1", "user:2", "users:1"]);
+        assert_eq!(kv.scan("").count(), 5);
+        assert_eq!(kv.scan("zzz").count(), 0);
+
+        assert_eq!(kv.put("user:1", b"new".to_vec()), Some(b"user:1".to_vec()));
+        assert_eq!(kv.get("user:1"), Some(&b"new"[..]));
+        assert!(kv.delete("user:1"));
+        assert!(!kv.delete("user:1"));
+        assert_eq!(kv.get("user:1"), None);
+        assert_eq!(kv.len(), 4);
+    }
+
+    #[test]
+    fn saved_namespaces_load_unchanged() {
+        let path = std::env::temp_dir().join(format!("qubedb-kv-{}.bin", std::process::id()));
+        let kv = namespace();
+        kv.save(&path).unwrap();
+        let loaded = KvNamespace::load(&path).unwrap();
+        assert!(loaded.scan("").eq(kv.scan("")));
+
+        let future = bincode::serialize(&(KV_FORMAT_VERSION + 1, &kv)).unwrap();
+        std::fs::write(&path, future).unwrap();
+        assert!(matches!(
+            KvNamespace::load(&path),
+            Err(QubeError::Serialization(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -9,15 +9,59 @@
 //!
 //! All in one unified system with AI-native optimization.
 
+// The OpenAPI document in `api` is one large `json!` literal
+#![recursion_limit = "256"]
+
+pub mod advisor;
+pub mod aggregate;
+pub mod api;
+pub mod audit;
+pub mod bench;
+pub mod catalog;
+pub mod changes;
+pub mod columnar;
+pub mod crdt;
+pub mod cypher;
 pub mod drivers;
+pub mod dump;
 pub mod embedded;
 pub mod embedded_simple;
 pub mod error;
+pub mod expr;
+pub mod functions;
+pub mod graph;
+pub mod hll;
+pub mod hybrid;
 pub mod index;
+pub mod kv;
+pub mod locks;
 pub mod logging;
+pub mod lsm;
+pub mod metrics;
+pub mod migrations;
+pub mod namespace;
+pub mod pagination;
+pub mod parallel;
+pub mod planner;
+pub mod prepared;
 pub mod query;
+pub mod quota;
+pub mod replication;
+pub mod scheduler;
+pub mod security;
+pub mod session;
+pub mod shard;
+pub mod sink;
+pub mod stats;
 pub mod storage;
+pub mod subscriber;
+pub mod two_phase;
 pub mod types;
+pub mod validate;
+pub mod vector_cache;
+pub mod view;
+pub mod wal;
+pub mod window;
 
 pub use error::{QubeError, QubeResult};
 
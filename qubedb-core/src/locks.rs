@@ -0,0 +1,318 @@
+//! Row locks for QubeDB
+//!
+//! A transaction that reads rows with `SELECT ... FOR UPDATE` locks them
+//! until it commits or rolls back. Any other write to a locked row, from a
+//! statement outside a transaction or another transaction's commit, waits
+//! for the lock to be released, as does another `FOR UPDATE` of the row.
+//!
+//! Waiting transactions are tracked in a wait-for graph. A transaction
+//! whose wait would close a cycle is deadlocked with the ones it waits
+//! for; instead of waiting it fails with a `Transaction` error starting
+//! with `DEADLOCK`, its session rolls it back, and the others go ahead.
+
+use crate::error::{QubeError, QubeResult, DEADLOCK};
+use crate::query::ExecutionContext;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Identifies the transaction holding or waiting for locks
+pub type LockOwner = u64;
+
+/// How often a waiting statement checks its deadline and cancellation
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct LockState {
+    /// Owner of each locked row, by table and row id
+    rows: HashMap<(String, u64), LockOwner>,
+    /// Rows each owner holds
+    held: HashMap<LockOwner, HashSet<(String, u64)>>,
+    /// Owner each waiting transaction waits for
+    waits_for: HashMap<LockOwner, LockOwner>,
+}
+
+/// Row locks of one engine's transactions
+#[derive(Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+    next_owner: AtomicU64,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A fresh owner id for a transaction
+    pub fn new_owner(&self) -> LockOwner {
+        self.next_owner.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Number of rows locked, by all owners
+    pub fn locked_rows(&self) -> usize {
+        self.state.lock().unwrap().rows.len()
+    }
+
+    /// Number of rows `owner` holds locked
+    pub fn held_by(&self, owner: LockOwner) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .held
+            .get(&owner)
+            .map_or(0, HashSet::len)
+    }
+
+    /// An owner other than `owner` holding any of these rows of `table`
+    pub fn holder(
+        &self,
+        owner: Option<LockOwner>,
+        table: &str,
+        row_ids: &[u64],
+    ) -> Option<LockOwner> {
+        let state = self.state.lock().unwrap();
+        if state.rows.is_empty() {
+            return None;
+        }
+        row_ids.iter().find_map(|&row_id| {
+            state
+                .rows
+                .get(&(table.to_string(), row_id))
+                .copied()
+                .filter(|holder| Some(*holder) != owner)
+        })
+    }
+
+    /// Lock these rows of `table` for `owner`, unless another owner holds
+    /// one of them, which is returned instead and nothing locked
+    pub fn try_lock(&self, owner: LockOwner, table: &str, row_ids: &[u64]) -> Option<LockOwner> {
+        let mut state = self.state.lock().unwrap();
+        for &row_id in row_ids {
+            match state.rows.get(&(table.to_string(), row_id)) {
+                Some(&holder) if holder != owner => return Some(holder),
+                _ => {}
+            }
+        }
+        for &row_id in row_ids {
+            let key = (table.to_string(), row_id);
+            state.rows.insert(key.clone(), owner);
+            state.held.entry(owner).or_default().insert(key);
+        }
+        None
+    }
+
+    /// Release every lock `owner` holds, waking whoever waits for them
+    pub fn release(&self, owner: LockOwner) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(rows) = state.held.remove(&owner) {
+            for row in rows {
+                state.rows.remove(&row);
+            }
+            self.released.notify_all();
+        }
+    }
+
+    /// Wait until `holder` releases its locks. A `waiter` that is itself a
+    /// transaction fails at once if `holder` already waits for it, directly
+    /// or through others. Waiting also ends when the statement times out
+    /// or is cancelled.
+    pub(crate) fn wait(
+        &self,
+        waiter: Option<LockOwner>,
+        holder: LockOwner,
+        ctx: &ExecutionContext,
+    ) -> QubeResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(waiter) = waiter {
+            let mut cycle = vec![waiter, holder];
+            let mut next = state.waits_for.get(&holder).copied();
+            while let Some(owner) = next {
+                cycle.push(owner);
+                if owner == waiter {
+                    return Err(QubeError::Transaction(format!(
+                        "{} detected: transactions {} wait for each other's row locks; \
+                         transaction {} was rolled back",
+                        DEADLOCK,
+                        cycle
+                            .iter()
+                            .map(|owner| owner.to_string())
+                            .collect::<Vec<_>>()
+                            .join(" -> "),
+                        waiter
+                    )));
+                }
+                next = state.waits_for.get(&owner).copied();
+            }
+            state.waits_for.insert(waiter, holder);
+        }
+
+        let mut outcome = Ok(());
+        while state.held.contains_key(&holder) {
+            if let Err(e) = ctx.check() {
+                outcome = Err(e);
+                break;
+            }
+            state = self
+                .released
+                .wait_timeout(state, WAIT_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        if let Some(waiter) = waiter {
+            state.waits_for.remove(&waiter);
+        }
+        outcome
+    }
+}
+
+impl std::fmt::Debug for LockManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("LockManager")
+            .field("locked_rows", &state.rows.len())
+            .field("waiting", &state.waits_for.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::QubeError;
+    use crate::query::{QueryEngine, QueryOptions};
+    use crate::security::SecurityContext;
+    use crate::session::Session;
+    use crate::types::Value;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn engine() -> Arc<QueryEngine> {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE accounts (id INT PRIMARY KEY, balance INT);
+                 INSERT INTO accounts VALUES (1, 1), (2, 2)",
+            )
+            .unwrap();
+        engine
+    }
+
+    fn session(engine: &Arc<QueryEngine>) -> Session {
+        Session::new(Arc::clone(engine), SecurityContext::admin("test"))
+    }
+
+    fn balance(engine: &QueryEngine, id: i32) -> Value {
+        let sql = format!("SELECT balance FROM accounts WHERE id = {}", id);
+        engine.execute_script(&sql).unwrap().pop().unwrap().rows[0]["balance"].clone()
+    }
+
+    /// Wait until a transaction is waiting for a lock
+    fn until_waiting(engine: &QueryEngine) {
+        while engine.locks().state.lock().unwrap().waits_for.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn a_write_to_a_locked_row_times_out() {
+        let engine = engine();
+        let mut holder = session(&engine);
+        holder.begin().unwrap();
+        holder
+            .execute_script("SELECT balance FROM accounts WHERE id = 1 FOR UPDATE")
+            .unwrap();
+        assert_eq!(engine.locks().locked_rows(), 1);
+
+        let options = QueryOptions::default().with_timeout(Duration::from_millis(200));
+        let err = engine
+            .execute_sql_with_options("UPDATE accounts SET balance = 10 WHERE id = 1", &options)
+            .unwrap_err();
+        assert!(matches!(err, QubeError::Timeout(_)), "{}", err);
+        // Other rows are not locked
+        engine
+            .execute_sql_with_options("UPDATE accounts SET balance = 20 WHERE id = 2", &options)
+            .unwrap();
+
+        holder.rollback().unwrap();
+        assert_eq!(engine.locks().locked_rows(), 0);
+        assert_eq!(balance(&engine, 1), Value::Int32(1));
+        assert_eq!(balance(&engine, 2), Value::Int32(20));
+    }
+
+    #[test]
+    fn a_waiting_session_proceeds_after_the_holder_commits() {
+        let engine = engine();
+        let mut holder = session(&engine);
+        holder.begin().unwrap();
+        holder
+            .execute_script("SELECT balance FROM accounts WHERE id = 1 FOR UPDATE")
+            .unwrap();
+
+        let waiter = {
+            let mut waiter = session(&engine);
+            std::thread::spawn(move || {
+                waiter.begin().unwrap();
+                waiter
+                    .execute_script(
+                        "SELECT balance FROM accounts WHERE id = 1 FOR UPDATE;
+                         UPDATE accounts SET balance = balance * 10 WHERE id = 1",
+                    )
+                    .unwrap();
+                waiter.commit().unwrap();
+            })
+        };
+        until_waiting(&engine);
+        assert!(!waiter.is_finished());
+
+        holder
+            .execute_script("UPDATE accounts SET balance = balance + 1 WHERE id = 1")
+            .unwrap();
+        holder.commit().unwrap();
+        waiter.join().unwrap();
+        // The waiter read the holder's committed balance, so no update is lost
+        assert_eq!(balance(&engine, 1), Value::Int32(20));
+        assert_eq!(engine.locks().locked_rows(), 0);
+    }
+
+    #[test]
+    fn a_deadlocked_transaction_is_rolled_back_and_the_other_proceeds() {
+        let engine = engine();
+        let mut first = session(&engine);
+        first.begin().unwrap();
+        first
+            .execute_script("SELECT balance FROM accounts WHERE id = 1 FOR UPDATE")
+            .unwrap();
+
+        let second = {
+            let mut second = session(&engine);
+            second.begin().unwrap();
+            second
+                .execute_script("SELECT balance FROM accounts WHERE id = 2 FOR UPDATE")
+                .unwrap();
+            std::thread::spawn(move || {
+                second
+                    .execute_script(
+                        "SELECT balance FROM accounts WHERE id = 1 FOR UPDATE;
+                         UPDATE accounts SET balance = 100 WHERE id = 1",
+                    )
+                    .unwrap();
+                second.commit().unwrap();
+            })
+        };
+        until_waiting(&engine);
+
+        // The second waits for the first, so the first waiting for it would
+        // never end
+        let err = first
+            .execute_script("SELECT balance FROM accounts WHERE id = 2 FOR UPDATE")
+            .unwrap_err();
+        assert!(err.is_deadlock(), "{}", err);
+        assert!(!first.in_transaction());
+
+        second.join().unwrap();
+        assert_eq!(balance(&engine, 1), Value::Int32(100));
+        assert_eq!(engine.locks().locked_rows(), 0);
+    }
+}
@@ -2,16 +2,32 @@
 //!
 //! Comprehensive logging system for tracking all QubeDB operations,
 //! errors, and performance metrics.
+//!
+//! With `enable_json` set, each line of the log file is one JSON object with
+//! the fields of `LogEntry` under their Rust names, `level` and `category`
+//! in lower case, and absent values as `null`, ready for ingestion by
+//! log collectors. Entries logged while an operation runs under a
+//! correlation id (see `traced`) carry it as their `operation_id`, so all
+//! lines of one query can be found together.
+//!
+//! With `enable_hash_chain` set, the log file is written as JSON lines that
+//! each carry the hash of the entry before them, so `verify_chain` can tell
+//! whether an entry was altered or removed after it was written.
 
-use crate::error::QubeError;
+use crate::audit::{sha256_hex, ChainBreak};
+use crate::error::{QubeError, QubeResult};
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Log levels for different types of messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Trace,
     Debug,
@@ -36,6 +52,7 @@ impl LogLevel {
 
 /// Log categories for different types of operations
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogCategory {
     Installation,
     Connection,
@@ -91,6 +108,12 @@ pub struct LogEntry {
     pub duration_ms: Option<u64>,
     pub memory_usage: Option<u64>,
     pub cpu_usage: Option<f64>,
+    /// Hash of the previous entry in a hash-chained log, empty for the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    /// Hash of this entry's other fields in a hash-chained log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
 }
 
 impl LogEntry {
@@ -111,6 +134,8 @@ impl LogEntry {
             duration_ms: None,
             memory_usage: None,
             cpu_usage: None,
+            prev_hash: None,
+            hash: None,
         }
     }
 
@@ -149,6 +174,121 @@ impl LogEntry {
         self.cpu_usage = Some(cpu_usage);
         self
     }
+
+    /// Link the entry to the one whose hash is `prev_hash` and seal it with
+    /// its own hash
+    pub fn chained_to(mut self, prev_hash: String) -> Self {
+        self.prev_hash = Some(prev_hash);
+        self.hash = None;
+        self.hash = Some(self.compute_hash());
+        self
+    }
+
+    /// The hash a chained entry should carry: that of its JSON form without
+    /// the hash itself
+    fn compute_hash(&self) -> String {
+        let unsealed = LogEntry {
+            hash: None,
+            ..self.clone()
+        };
+        sha256_hex(serde_json::to_string(&unsealed).unwrap().as_bytes())
+    }
+}
+
+/// Check the hash-chained log at `path` entry by entry, returning the first
+/// one that was altered, removed, inserted or moved, or `None` if the chain
+/// is intact
+pub fn verify_chain<P: AsRef<Path>>(path: P) -> QubeResult<Option<ChainBreak>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut prev_hash = String::new();
+    for (i, line) in reader.lines().enumerate() {
+        let broken = |reason: String| {
+            Ok(Some(ChainBreak {
+                line: i + 1,
+                reason,
+            }))
+        };
+        let entry: LogEntry = match serde_json::from_str(&line?) {
+            Ok(entry) => entry,
+            Err(e) => return broken(format!("unreadable entry: {}", e)),
+        };
+        let hash = match (&entry.prev_hash, &entry.hash) {
+            (Some(prev), Some(hash)) if *prev == prev_hash => hash.clone(),
+            (Some(_), Some(_)) => return broken("does not follow the previous entry".to_string()),
+            _ => return broken("entry is not chained".to_string()),
+        };
+        if hash != entry.compute_hash() {
+            return broken("contents do not match its hash".to_string());
+        }
+        prev_hash = hash;
+    }
+    Ok(None)
+}
+
+/// Hash of the last entry of the chained log at `path`, empty if there is
+/// none to continue from
+fn last_hash(path: &str) -> String {
+    let Ok(file) = File::open(path) else {
+        return String::new();
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| !line.trim().is_empty())
+        .last()
+        .and_then(|line| serde_json::from_str::<LogEntry>(&line).ok())
+        .and_then(|entry| entry.hash)
+        .unwrap_or_default()
+}
+
+tokio::task_local! {
+    /// Correlation id of the operation the current task is running
+    static CORRELATION_ID: String;
+}
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A correlation id not handed out before by this process
+pub fn new_correlation_id() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let n = NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed);
+    format!("op-{:x}-{}", started, n)
+}
+
+/// Correlation id of the operation being run, if any
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run `operation`, tagging everything it logs with correlation id `id`
+pub async fn with_correlation_id<F: Future>(id: impl Into<String>, operation: F) -> F::Output {
+    CORRELATION_ID.scope(id.into(), operation).await
+}
+
+/// Run a synchronous `operation`, tagging everything it logs with
+/// correlation id `id`
+pub fn with_correlation_id_sync<R>(id: impl Into<String>, operation: impl FnOnce() -> R) -> R {
+    CORRELATION_ID.sync_scope(id.into(), operation)
+}
+
+/// Run `operation` under the current correlation id, or a new one when it
+/// is not part of a traced operation already
+pub async fn traced<F: Future>(operation: F) -> F::Output {
+    match correlation_id() {
+        Some(_) => operation.await,
+        None => with_correlation_id(new_correlation_id(), operation).await,
+    }
+}
+
+/// Synchronous counterpart of `traced`
+pub fn traced_sync<R>(operation: impl FnOnce() -> R) -> R {
+    match correlation_id() {
+        Some(_) => operation(),
+        None => with_correlation_id_sync(new_correlation_id(), operation),
+    }
 }
 
 /// Logger configuration
@@ -162,6 +302,8 @@ pub struct LoggerConfig {
     pub enable_file: bool,
     pub enable_json: bool,
     pub enable_metrics: bool,
+    /// Write the log file as hash-chained JSON lines, see `verify_chain`
+    pub enable_hash_chain: bool,
 }
 
 impl Default for LoggerConfig {
@@ -175,6 +317,7 @@ impl Default for LoggerConfig {
             enable_file: true,
             enable_json: false,
             enable_metrics: true,
+            enable_hash_chain: false,
         }
     }
 }
@@ -183,6 +326,8 @@ impl Default for LoggerConfig {
 pub struct Logger {
     config: LoggerConfig,
     file_handle: Mutex<Option<std::fs::File>>,
+    /// Hash of the last entry written, when the file is hash-chained
+    last_hash: Mutex<String>,
     metrics: Mutex<LogMetrics>,
 }
 
@@ -205,6 +350,7 @@ impl Logger {
         let logger = Self {
             config,
             file_handle: Mutex::new(None),
+            last_hash: Mutex::new(String::new()),
             metrics: Mutex::new(LogMetrics::default()),
         };
 
@@ -223,19 +369,28 @@ impl Logger {
             .create(true)
             .append(true)
             .open(&self.config.log_file)
-            .map_err(|e| QubeError::Io(e))?;
+            .map_err(QubeError::Io)?;
 
+        // Continue the chain of entries already in the file
+        if self.config.enable_hash_chain {
+            *self.last_hash.lock().unwrap() = last_hash(&self.config.log_file);
+        }
         *file_handle = Some(file);
         Ok(())
     }
 
-    /// Log an entry
-    pub fn log(&self, entry: LogEntry) -> Result<(), QubeError> {
+    /// Log an entry, tagged with the current correlation id unless it
+    /// names its operation itself
+    pub fn log(&self, mut entry: LogEntry) -> Result<(), QubeError> {
         // Check if we should log this level
         if !self.should_log(&entry.level) {
             return Ok(());
         }
 
+        if entry.operation_id.is_none() {
+            entry.operation_id = correlation_id();
+        }
+
         // Update metrics
         self.update_metrics(&entry);
 
@@ -254,25 +409,28 @@ impl Logger {
 
     /// Check if we should log this level
     fn should_log(&self, level: &LogLevel) -> bool {
-        match (&self.config.log_level, level) {
-            (LogLevel::Trace, _) => true,
-            (
-                LogLevel::Debug,
-                LogLevel::Debug
-                | LogLevel::Info
-                | LogLevel::Warn
-                | LogLevel::Error
-                | LogLevel::Fatal,
-            ) => true,
-            (
-                LogLevel::Info,
-                LogLevel::Info | LogLevel::Warn | LogLevel::Error | LogLevel::Fatal,
-            ) => true,
-            (LogLevel::Warn, LogLevel::Warn | LogLevel::Error | LogLevel::Fatal) => true,
-            (LogLevel::Error, LogLevel::Error | LogLevel::Fatal) => true,
-            (LogLevel::Fatal, LogLevel::Fatal) => true,
-            _ => false,
-        }
+        matches!(
+            (&self.config.log_level, level),
+            (LogLevel::Trace, _)
+                | (
+                    LogLevel::Debug,
+                    LogLevel::Debug
+                        | LogLevel::Info
+                        | LogLevel::Warn
+                        | LogLevel::Error
+                        | LogLevel::Fatal,
+                )
+                | (
+                    LogLevel::Info,
+                    LogLevel::Info | LogLevel::Warn | LogLevel::Error | LogLevel::Fatal,
+                )
+                | (
+                    LogLevel::Warn,
+                    LogLevel::Warn | LogLevel::Error | LogLevel::Fatal
+                )
+                | (LogLevel::Error, LogLevel::Error | LogLevel::Fatal)
+                | (LogLevel::Fatal, LogLevel::Fatal)
+        )
     }
 
     /// Log to console
@@ -313,8 +471,13 @@ impl Logger {
         let mut file_handle = self.file_handle.lock().unwrap();
 
         if let Some(ref mut file) = *file_handle {
-            let log_line = if self.config.enable_json {
-                serde_json::to_string(entry).unwrap()
+            let log_line = if self.config.enable_hash_chain {
+                let mut last_hash = self.last_hash.lock().unwrap();
+                let entry = entry.clone().chained_to(last_hash.clone());
+                *last_hash = entry.hash.clone().unwrap_or_default();
+                format!("{}\n", serde_json::to_string(&entry).unwrap())
+            } else if self.config.enable_json {
+                format!("{}\n", serde_json::to_string(entry).unwrap())
             } else {
                 format!(
                     "[{}] {} [{}] {} {}\n",
@@ -326,9 +489,8 @@ impl Logger {
                 )
             };
 
-            file.write_all(log_line.as_bytes())
-                .map_err(|e| QubeError::Io(e))?;
-            file.flush().map_err(|e| QubeError::Io(e))?;
+            file.write_all(log_line.as_bytes()).map_err(QubeError::Io)?;
+            file.flush().map_err(QubeError::Io)?;
         }
 
         Ok(())
@@ -354,6 +516,13 @@ impl Logger {
         }
     }
 
+    /// Check the chain of this logger's file, see `verify_chain`
+    pub fn verify_chain(&self) -> QubeResult<Option<ChainBreak>> {
+        // Hold the file so no entry is half written while it is read
+        let _file_handle = self.file_handle.lock().unwrap();
+        verify_chain(&self.config.log_file)
+    }
+
     /// Get current metrics
     pub fn get_metrics(&self) -> LogMetrics {
         self.metrics.lock().unwrap().clone()
@@ -362,7 +531,7 @@ impl Logger {
     /// Clear log file
     pub fn clear_logs(&self) -> Result<(), QubeError> {
         if self.config.enable_file {
-            std::fs::remove_file(&self.config.log_file).map_err(|e| QubeError::Io(e))?;
+            std::fs::remove_file(&self.config.log_file).map_err(QubeError::Io)?;
             self.initialize_file()?;
         }
         Ok(())
@@ -374,7 +543,7 @@ impl Logger {
             return Ok(());
         }
 
-        let metadata = std::fs::metadata(&self.config.log_file).map_err(|e| QubeError::Io(e))?;
+        let metadata = std::fs::metadata(&self.config.log_file).map_err(QubeError::Io)?;
 
         if metadata.len() > self.config.max_file_size {
             // Create rotated filename
@@ -384,7 +553,7 @@ impl Logger {
                 .as_secs();
 
             let rotated_name = format!("{}.{}", self.config.log_file, timestamp);
-            std::fs::rename(&self.config.log_file, &rotated_name).map_err(|e| QubeError::Io(e))?;
+            std::fs::rename(&self.config.log_file, &rotated_name).map_err(QubeError::Io)?;
 
             // Reinitialize file
             self.initialize_file()?;
@@ -736,3 +905,142 @@ pub fn log_info(
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-log-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn file_logger(log_file: &str, enable_hash_chain: bool) -> Logger {
+        Logger::new(LoggerConfig {
+            log_file: log_file.to_string(),
+            enable_console: false,
+            enable_json: true,
+            enable_hash_chain,
+            ..LoggerConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn entries(path: &str) -> Vec<LogEntry> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chained_logs_continue_across_loggers_and_reveal_edits() {
+        let path = log_path("chain");
+        let logger = file_logger(&path, true);
+        logger.log_query("SELECT 1", true, 3).unwrap();
+        logger
+            .log_warning(LogCategory::Storage, "disk almost full", None)
+            .unwrap();
+        drop(logger);
+        let logger = file_logger(&path, true);
+        logger.log_table("CREATE", "t", true).unwrap();
+        assert_eq!(logger.verify_chain().unwrap(), None);
+
+        let written = entries(&path);
+        assert_eq!(written.len(), 3);
+        assert_eq!(written[0].prev_hash.as_deref(), Some(""));
+        assert_eq!(written[2].prev_hash, written[1].hash);
+
+        let lines: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let edited = lines[1].replace("disk almost full", "all is well");
+        std::fs::write(&path, format!("{}\n{}\n{}\n", lines[0], edited, lines[2])).unwrap();
+        let broken = verify_chain(&path).unwrap().unwrap();
+        assert_eq!(broken.line, 2);
+
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let broken = verify_chain(&path).unwrap().unwrap();
+        assert_eq!(broken.line, 2);
+        assert!(broken.reason.contains("previous"), "{}", broken);
+    }
+
+    #[test]
+    fn unchained_entries_fail_verification() {
+        let path = log_path("unchained");
+        let logger = file_logger(&path, false);
+        logger.log_query("SELECT 1", true, 1).unwrap();
+        let broken = verify_chain(&path).unwrap().unwrap();
+        assert_eq!(broken.line, 1);
+        assert!(broken.reason.contains("not chained"), "{}", broken);
+    }
+
+    #[test]
+    fn entries_carry_the_correlation_id_of_their_operation() {
+        let path = log_path("correlation");
+        let logger = file_logger(&path, false);
+        logger
+            .log_info(LogCategory::System, "outside", None)
+            .unwrap();
+        assert_eq!(correlation_id(), None);
+
+        let id = with_correlation_id_sync("op-test", || {
+            logger.log_query("SELECT 1", true, 1).unwrap();
+            // Nested traced operations keep the outer id
+            traced_sync(|| {
+                logger
+                    .log_warning(LogCategory::Query, "slow", None)
+                    .unwrap();
+                correlation_id()
+            })
+        });
+        assert_eq!(id.as_deref(), Some("op-test"));
+        let own = LogEntry::new(LogLevel::Info, LogCategory::User, "own".to_string())
+            .with_operation("op-own".to_string());
+        with_correlation_id_sync("op-test", || logger.log(own).unwrap());
+
+        // Each traced operation outside another gets an id of its own
+        let first = traced_sync(correlation_id).unwrap();
+        let second = traced_sync(correlation_id).unwrap();
+        assert_ne!(first, second);
+
+        let ids: Vec<Option<String>> = entries(&path)
+            .into_iter()
+            .map(|entry| entry.operation_id)
+            .collect();
+        let test = Some("op-test".to_string());
+        assert_eq!(ids, [None, test.clone(), test, Some("op-own".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn async_operations_keep_their_correlation_id_across_awaits() {
+        let id = with_correlation_id("op-async", async {
+            tokio::task::yield_now().await;
+            traced(async { correlation_id() }).await
+        })
+        .await;
+        assert_eq!(id.as_deref(), Some("op-async"));
+        assert_eq!(correlation_id(), None);
+    }
+
+    #[test]
+    fn json_lines_use_lowercase_levels_and_categories() {
+        let path = log_path("json");
+        let logger = file_logger(&path, false);
+        logger
+            .log_connection("login", false, Some("ann".to_string()))
+            .unwrap();
+        let line = std::fs::read_to_string(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(json["level"], "error");
+        assert_eq!(json["category"], "connection");
+        assert_eq!(json["user_id"], "ann");
+        assert!(json["details"].is_null());
+        assert!(json.get("hash").is_none());
+    }
+}
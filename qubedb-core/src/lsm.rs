@@ -0,0 +1,1645 @@
+//! Log-structured merge storage for QubeDB
+//!
+//! Writes go to a memtable, an in-memory sorted map. Once the memtable
+//! holds more than `LsmConfig::memtable_size` bytes it is written out as an
+//! SSTable: an immutable file of entries sorted by key, with a sparse index
+//! of every `INDEX_INTERVAL`th key kept in memory to find a key's block.
+//! Memory use is therefore bounded by the memtable size plus the sparse
+//! indexes, however much data is stored. `LsmConfig::memory_limit` caps the
+//! total as well: once the memtable, indexes and bloom filters together
+//! exceed it, the memtable is flushed early.
+//!
+//! Reads check the memtable first and then the SSTables from newest to
+//! oldest, so the most recent write to a key wins. A delete is recorded as
+//! a tombstone that shadows older values of the key in earlier SSTables.
+//!
+//! Each SSTable also carries a bloom filter over its keys, so a lookup for
+//! a key a table does not hold usually skips the table without reading it.
+//! Blocks that point reads do load are kept, decoded, in a shared LRU block
+//! cache of `LsmConfig::block_cache_size` bytes, so hot keys are served
+//! from memory. SSTables never change, so cached blocks only go stale when
+//! compaction replaces their table, and are dropped then.
+//!
+//! Flushed tables are organised into levels and merged by background
+//! compaction; see `LsmTree` for the strategy.
+//!
+//! The memtable itself is not logged: writes not yet flushed are lost on a
+//! crash unless the caller also records them in a write-ahead log.
+
+use crate::error::{QubeError, QubeResult};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// Default size a memtable may reach before it is flushed
+pub const DEFAULT_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default number of level 0 tables that triggers a compaction into level 1
+pub const DEFAULT_LEVEL0_COMPACTION_TRIGGER: usize = 4;
+
+/// Default size budget of level 1; each deeper level gets
+/// `DEFAULT_LEVEL_SIZE_MULTIPLIER` times the one above
+pub const DEFAULT_LEVEL_BASE_SIZE: u64 = 10 * 1024 * 1024;
+
+pub const DEFAULT_LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+pub const DEFAULT_MAX_LEVELS: usize = 7;
+
+/// Default size at which compaction starts a new output table
+pub const DEFAULT_SSTABLE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Default share of absent keys an SSTable's bloom filter lets through
+pub const DEFAULT_BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Default size of the block cache
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/// File listing the live SSTables
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Every how many entries an SSTable's sparse index records a key
+const INDEX_INTERVAL: usize = 16;
+
+/// Bytes a memtable entry is charged beyond its key and value
+const ENTRY_OVERHEAD: usize = 32;
+
+const SSTABLE_MAGIC: &[u8; 4] = b"QSST";
+const SSTABLE_VERSION: u32 = 2;
+const SSTABLE_EXTENSION: &str = "sst";
+
+/// Footer: index offset, bloom filter offset, entry count and magic
+const FOOTER_LEN: u64 = 8 + 8 + 8 + 4;
+
+/// Most hash functions a bloom filter uses, however low its target rate
+const MAX_BLOOM_HASHES: u32 = 30;
+
+/// A key and its value, or `None` for a tombstone
+pub type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Memtable and compaction settings
+#[derive(Debug, Clone)]
+pub struct LsmConfig {
+    /// Flush the memtable to an SSTable once its keys, values and
+    /// per-entry overhead exceed this many bytes
+    pub memtable_size: usize,
+    /// Compact level 0 into level 1 once it holds this many tables
+    pub level0_compaction_trigger: usize,
+    /// Size budget of level 1, in bytes
+    pub level_base_size: u64,
+    /// How much larger each level's budget is than the one above
+    pub level_size_multiplier: u64,
+    /// Number of levels, level 0 included
+    pub max_levels: usize,
+    /// Compaction output tables are cut at about this many bytes
+    pub sstable_size: usize,
+    /// Compact on a background thread after each flush. When off, call
+    /// `LsmTree::compact` to compact.
+    pub background_compaction: bool,
+    /// Target false-positive rate of each SSTable's bloom filter. Lower
+    /// rates cost more bits per key; `None` writes no filters.
+    pub bloom_false_positive_rate: Option<f64>,
+    /// Soft cap, in bytes, on the memory held by the memtable and the
+    /// SSTables' indexes and filters. A write that takes the tree over it
+    /// flushes the memtable, however small, after giving up cached
+    /// blocks. `None` leaves the memtable to `memtable_size` alone.
+    pub memory_limit: Option<usize>,
+    /// Bytes of decoded SSTable blocks kept for point reads; 0 disables the
+    /// cache
+    pub block_cache_size: usize,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            level0_compaction_trigger: DEFAULT_LEVEL0_COMPACTION_TRIGGER,
+            level_base_size: DEFAULT_LEVEL_BASE_SIZE,
+            level_size_multiplier: DEFAULT_LEVEL_SIZE_MULTIPLIER,
+            max_levels: DEFAULT_MAX_LEVELS,
+            sstable_size: DEFAULT_SSTABLE_SIZE,
+            background_compaction: true,
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            memory_limit: None,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+        }
+    }
+}
+
+/// Counters describing an LSM tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LsmMetrics {
+    pub memtable_entries: usize,
+    pub memtable_bytes: usize,
+    /// Approximate memory held, see `LsmTree::memory_usage`
+    pub memory_bytes: usize,
+    pub sstables: usize,
+    /// Entries across all SSTables, tombstones and shadowed values included
+    pub sstable_entries: usize,
+    /// Memtables written out since the tree was opened
+    pub flushes: u64,
+    /// Point reads that had to search an SSTable
+    pub sstable_reads: u64,
+    /// Point reads of an SSTable its bloom filter ruled out
+    pub bloom_filter_skips: u64,
+    /// Blocks point reads loaded from disk
+    pub block_reads: u64,
+    /// Point reads whose block was already cached
+    pub block_cache_hits: u64,
+    /// Point reads whose block had to be loaded into the cache
+    pub block_cache_misses: u64,
+    pub block_cache_bytes: usize,
+}
+
+/// Sorted writes waiting to be flushed. A `None` value is a tombstone.
+#[derive(Debug, Clone, Default)]
+pub struct MemTable {
+    entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    size: usize,
+}
+
+impl MemTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a value, or a tombstone when `value` is `None`
+    pub fn put(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        if let Some(old) = self.entries.get(&key) {
+            self.size -= entry_size(&key, old.as_deref());
+        }
+        self.size += entry_size(&key, value.as_deref());
+        self.entries.insert(key, value);
+    }
+
+    /// `None` if the key was never written here, `Some(None)` if it was
+    /// deleted
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.entries.get(key).map(Option::as_deref)
+    }
+
+    /// Entries in key order, tombstones included
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> + '_ {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Approximate bytes held
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+}
+
+/// An immutable file of entries sorted by key, belonging to one level.
+///
+/// Layout: magic and version, then each entry as key length, key, a tag
+/// byte (0 for a tombstone, 1 for a value) and, for values, value length
+/// and value. The sparse index follows as a count and (key length, key,
+/// entry offset) triples, then the bloom filter as its hash count and
+/// (bit array length, bit array), empty when the table has no filter. A
+/// footer holds the index offset, the filter offset, the entry count and
+/// the magic again. All integers are little-endian.
+///
+/// Once compaction has replaced a table it is marked obsolete, and its
+/// file is removed when the last reader lets go of it.
+#[derive(Debug)]
+pub struct SsTable {
+    path: PathBuf,
+    id: u64,
+    level: usize,
+    /// First key of every `INDEX_INTERVAL`th entry and where it starts
+    index: Vec<(Vec<u8>, u64)>,
+    last_key: Vec<u8>,
+    bloom: Option<BloomFilter>,
+    /// Where the entries end and the index begins
+    data_end: u64,
+    entries: usize,
+    /// File size in bytes
+    size: u64,
+    obsolete: AtomicBool,
+}
+
+impl SsTable {
+    /// Write sorted entries to `path` and open the result. The file is
+    /// written under a temporary name, synced and then renamed, so a crash
+    /// never leaves a partial SSTable behind. A bloom filter aiming for
+    /// `false_positive_rate` is written alongside, unless that is `None`.
+    pub fn write<'a, P: AsRef<Path>>(
+        path: P,
+        id: u64,
+        level: usize,
+        false_positive_rate: Option<f64>,
+        entries: impl IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> QubeResult<Self> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(SSTABLE_MAGIC)?;
+        writer.write_all(&SSTABLE_VERSION.to_le_bytes())?;
+
+        let mut offset = 8u64;
+        let mut index = Vec::new();
+        let mut last_key = Vec::new();
+        let mut hashes = Vec::new();
+        let mut count = 0usize;
+        for (key, value) in entries {
+            if count.is_multiple_of(INDEX_INTERVAL) {
+                index.push((key.to_vec(), offset));
+            }
+            if false_positive_rate.is_some() {
+                hashes.push(bloom_hash(key));
+            }
+            offset += write_entry(&mut writer, key, value)?;
+            last_key = key.to_vec();
+            count += 1;
+        }
+
+        writer.write_all(&(index.len() as u32).to_le_bytes())?;
+        for (key, entry_offset) in &index {
+            write_bytes(&mut writer, key)?;
+            writer.write_all(&entry_offset.to_le_bytes())?;
+        }
+
+        let bloom = false_positive_rate.map(|rate| BloomFilter::build(&hashes, rate));
+        let bloom_offset = writer.stream_position()?;
+        match &bloom {
+            Some(bloom) => {
+                writer.write_all(&bloom.hashes.to_le_bytes())?;
+                write_bytes(&mut writer, &bloom.bits)?;
+            }
+            None => {
+                writer.write_all(&0u32.to_le_bytes())?;
+                write_bytes(&mut writer, &[])?;
+            }
+        }
+
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&bloom_offset.to_le_bytes())?;
+        writer.write_all(&(count as u64).to_le_bytes())?;
+        writer.write_all(SSTABLE_MAGIC)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        let size = file.metadata()?.len();
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(SsTable {
+            path: path.to_path_buf(),
+            id,
+            level,
+            index,
+            last_key,
+            bloom,
+            data_end: offset,
+            entries: count,
+            size,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Open an SSTable written by `write`, reading its sparse index and
+    /// bloom filter
+    pub fn open<P: AsRef<Path>>(path: P, id: u64, level: usize) -> QubeResult<Self> {
+        let path = path.as_ref();
+        let corrupt = |what: &str| {
+            QubeError::Storage(format!("Corrupt SSTable {}: {}", path.display(), what))
+        };
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < 8 + FOOTER_LEN {
+            return Err(corrupt("file too short"));
+        }
+
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        if &header[..4] != SSTABLE_MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != SSTABLE_VERSION {
+            return Err(corrupt(&format!("unsupported version {}", version)));
+        }
+
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let data_end = read_u64(&mut file)?;
+        let bloom_offset = read_u64(&mut file)?;
+        let entries = read_u64(&mut file)? as usize;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != SSTABLE_MAGIC || data_end > bloom_offset || bloom_offset > size - FOOTER_LEN {
+            return Err(corrupt("bad footer"));
+        }
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(data_end))?;
+        let count = read_u32(&mut reader)? as usize;
+        let mut index = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = read_bytes(&mut reader)?;
+            index.push((key, read_u64(&mut reader)?));
+        }
+
+        reader.seek(SeekFrom::Start(bloom_offset))?;
+        let hashes = read_u32(&mut reader)?;
+        let bits = read_bytes(&mut reader)?;
+        let bloom = (!bits.is_empty()).then_some(BloomFilter { hashes, bits });
+
+        let mut table = SsTable {
+            path: path.to_path_buf(),
+            id,
+            level,
+            index,
+            last_key: Vec::new(),
+            bloom,
+            data_end,
+            entries,
+            size,
+            obsolete: AtomicBool::new(false),
+        };
+        // The last key is the last entry of the final block
+        if let Some((_, start)) = table.index.last() {
+            for entry in table.iter_from(*start)? {
+                table.last_key = entry?.0;
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Position in write order; higher is newer
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Number of entries, tombstones included
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Approximate bytes of the sparse index and bloom filter, which stay
+    /// in memory while the table is open
+    pub fn memory_size(&self) -> usize {
+        let index: usize = self
+            .index
+            .iter()
+            .map(|(key, _)| key.len() + std::mem::size_of::<(Vec<u8>, u64)>())
+            .sum();
+        let bloom = self.bloom.as_ref().map_or(0, |bloom| bloom.bits.len());
+        index + bloom + self.last_key.len()
+    }
+
+    /// Smallest key held
+    pub fn first_key(&self) -> &[u8] {
+        self.index.first().map_or(&[], |(key, _)| key.as_slice())
+    }
+
+    /// Largest key held
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Whether `key` could be here: false if it is out of this table's key
+    /// range or its bloom filter rules it out
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.overlaps(key, key) && self.bloom.as_ref().is_none_or(|b| b.may_contain(key))
+    }
+
+    /// Whether any key between `first` and `last` (inclusive) could be here
+    fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        !self.is_empty() && self.first_key() <= last && first <= self.last_key()
+    }
+
+    /// Look a key up. `None` if the table has no entry for it,
+    /// `Some(None)` if its entry is a tombstone.
+    pub fn get(&self, key: &[u8]) -> QubeResult<Option<Option<Vec<u8>>>> {
+        let Some((start, end)) = self.block_of(key) else {
+            return Ok(None);
+        };
+        Ok(search_block(&self.read_block(start, end)?, key))
+    }
+
+    /// `get`, reading the key's block through `cache`. Counts a block read
+    /// in `block_reads` when it has to go to disk.
+    fn get_cached(
+        &self,
+        key: &[u8],
+        cache: &BlockCache,
+        block_reads: &AtomicU64,
+    ) -> QubeResult<Option<Option<Vec<u8>>>> {
+        let Some((start, end)) = self.block_of(key) else {
+            return Ok(None);
+        };
+        let block = match cache.get(self.id, start) {
+            Some(block) => block,
+            None => {
+                block_reads.fetch_add(1, Ordering::Relaxed);
+                let block = Arc::new(self.read_block(start, end)?);
+                cache.insert(self.id, start, Arc::clone(&block));
+                block
+            }
+        };
+        Ok(search_block(&block, key))
+    }
+
+    /// Start and end offsets of the block that would hold `key`
+    fn block_of(&self, key: &[u8]) -> Option<(u64, u64)> {
+        let block = self
+            .index
+            .partition_point(|(first, _)| first.as_slice() <= key);
+        let start = self.index[block.checked_sub(1)?].1;
+        let end = self
+            .index
+            .get(block)
+            .map_or(self.data_end, |(_, offset)| *offset);
+        Some((start, end))
+    }
+
+    fn read_block(&self, start: u64, end: u64) -> QubeResult<Vec<Entry>> {
+        let mut entries = self.iter_from(start)?;
+        entries.end = end;
+        entries.collect()
+    }
+
+    /// Every entry in key order, tombstones included, read lazily
+    pub fn iter(&self) -> QubeResult<SsTableIter> {
+        self.iter_from(8)
+    }
+
+    /// Entries from the one starting at `offset` to the end of the data
+    fn iter_from(&self, offset: u64) -> QubeResult<SsTableIter> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(SsTableIter {
+            reader,
+            offset,
+            end: self.data_end,
+        })
+    }
+
+    /// Every entry in key order, tombstones included
+    pub fn entries(&self) -> QubeResult<Vec<Entry>> {
+        self.iter()?.collect()
+    }
+}
+
+impl Drop for SsTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                tracing::warn!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// The entry for `key` in a block's sorted entries
+fn search_block(block: &[Entry], key: &[u8]) -> Option<Option<Vec<u8>>> {
+    block
+        .binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key))
+        .ok()
+        .map(|i| block[i].1.clone())
+}
+
+/// A table's block, decoded
+type Block = Arc<Vec<Entry>>;
+
+/// Least-recently-used cache of decoded SSTable blocks, keyed by table id
+/// and block offset and bounded by the blocks' approximate size
+#[derive(Debug, Default)]
+struct BlockCache {
+    capacity: usize,
+    state: Mutex<BlockCacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct BlockCacheState {
+    blocks: HashMap<(u64, u64), CachedBlock>,
+    /// Block keys by when they were last used, oldest first
+    recency: BTreeMap<u64, (u64, u64)>,
+    clock: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    block: Block,
+    size: usize,
+    used: u64,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn get(&self, table: u64, offset: u64) -> Option<Block> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.clock += 1;
+        match state.blocks.get_mut(&(table, offset)) {
+            Some(cached) => {
+                state.recency.remove(&cached.used);
+                cached.used = state.clock;
+                state.recency.insert(cached.used, (table, offset));
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(Arc::clone(&cached.block))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, table: u64, offset: u64, block: Block) {
+        let size: usize = block
+            .iter()
+            .map(|(key, value)| entry_size(key, value.as_deref()))
+            .sum();
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let used = state.clock;
+        if let Some(old) = state
+            .blocks
+            .insert((table, offset), CachedBlock { block, size, used })
+        {
+            state.recency.remove(&old.used);
+            state.bytes -= old.size;
+        }
+        state.recency.insert(used, (table, offset));
+        state.bytes += size;
+        state.evict_to(self.capacity);
+    }
+
+    /// Drop every block of a table that is going away
+    fn invalidate(&self, table: u64) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let (recency, bytes) = (&mut state.recency, &mut state.bytes);
+        state.blocks.retain(|(id, _), cached| {
+            if *id != table {
+                return true;
+            }
+            recency.remove(&cached.used);
+            *bytes -= cached.size;
+            false
+        });
+    }
+
+    /// Evict the least recently used blocks until at most `bytes` are held
+    fn shrink_to(&self, bytes: usize) {
+        self.state.lock().unwrap().evict_to(bytes);
+    }
+
+    fn size(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+}
+
+impl BlockCacheState {
+    fn evict_to(&mut self, bytes: usize) {
+        while self.bytes > bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(cached) = self.blocks.remove(&key) {
+                self.bytes -= cached.size;
+            }
+        }
+    }
+}
+
+/// A set of keys that may report false positives but never false
+/// negatives. Each key sets `hashes` bits, derived from one 64-bit hash by
+/// double hashing.
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// A filter over the keys with these hashes, sized so that about
+    /// `false_positive_rate` of absent keys pass it
+    fn build(key_hashes: &[u64], false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let keys = key_hashes.len().max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * rate.ln() / (ln2 * ln2)).ceil().max(8.0);
+        let hashes = ((bits / keys) * ln2)
+            .round()
+            .clamp(1.0, MAX_BLOOM_HASHES as f64);
+        let mut filter = BloomFilter {
+            hashes: hashes as u32,
+            bits: vec![0; (bits as usize).div_ceil(8)],
+        };
+        for &hash in key_hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(bloom_hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let step = (hash >> 32) | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+/// 64-bit FNV-1a of a key, mixed so both halves are usable. Filters are
+/// stored on disk, so this must never change.
+fn bloom_hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+/// Reads an SSTable's entries in key order
+pub struct SsTableIter {
+    reader: BufReader<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Iterator for SsTableIter {
+    type Item = QubeResult<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        Some(read_entry(&mut self.reader).map(|(key, value, len)| {
+            self.offset += len;
+            (key, value)
+        }))
+    }
+}
+
+/// Files in one level of the tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LevelStats {
+    pub level: usize,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Shape of the tree and the work compaction has done
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompactionStats {
+    /// One entry per level, from level 0 down to the deepest non-empty one
+    pub levels: Vec<LevelStats>,
+    /// Compactions run since the tree was opened
+    pub compactions: u64,
+    /// SSTable bytes read and written by compaction
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Superseded values and tombstones compaction discarded
+    pub entries_dropped: u64,
+}
+
+/// The SSTables making up the tree at one moment
+#[derive(Debug, Clone, Default)]
+struct Version {
+    /// Level 0 holds flushed memtables, oldest first, whose key ranges may
+    /// overlap. Deeper levels hold tables with disjoint key ranges, sorted
+    /// by key.
+    levels: Vec<Vec<Arc<SsTable>>>,
+}
+
+impl Version {
+    /// Every table, from the newest data to the oldest: level 0 newest
+    /// first, then each deeper level
+    fn by_precedence(&self) -> impl Iterator<Item = &Arc<SsTable>> + '_ {
+        self.levels
+            .first()
+            .into_iter()
+            .flat_map(|level| level.iter().rev())
+            .chain(self.levels.iter().skip(1).flatten())
+    }
+
+    fn level_bytes(&self, level: usize) -> u64 {
+        self.levels
+            .get(level)
+            .map_or(0, |tables| tables.iter().map(|t| t.size()).sum())
+    }
+
+    /// Memory held by the indexes and filters of every table
+    fn memory_size(&self) -> usize {
+        self.levels.iter().flatten().map(|t| t.memory_size()).sum()
+    }
+
+    /// Tables of `level` that may hold keys between `first` and `last`
+    fn overlapping(&self, level: usize, first: &[u8], last: &[u8]) -> Vec<Arc<SsTable>> {
+        self.levels.get(level).map_or(Vec::new(), |tables| {
+            tables
+                .iter()
+                .filter(|t| t.overlaps(first, last))
+                .cloned()
+                .collect()
+        })
+    }
+}
+
+/// A merge of some tables into the next level down
+struct Compaction {
+    /// Newest data first
+    inputs: Vec<Arc<SsTable>>,
+    output_level: usize,
+    /// Whether no deeper table could hold an older value the tombstones
+    /// still need to shadow
+    drop_tombstones: bool,
+}
+
+#[derive(Default)]
+struct CompactorSignal {
+    pending: bool,
+    stopped: bool,
+}
+
+/// State shared with the background compactor
+struct Shared {
+    dir: PathBuf,
+    config: LsmConfig,
+    version: Mutex<Version>,
+    next_id: AtomicU64,
+    /// Held while a compaction runs, so only one runs at a time
+    compacting: Mutex<()>,
+    signal: Mutex<CompactorSignal>,
+    wake: Condvar,
+    stats: Mutex<CompactionStats>,
+    sstable_reads: AtomicU64,
+    bloom_filter_skips: AtomicU64,
+    block_reads: AtomicU64,
+    cache: BlockCache,
+}
+
+/// A memtable in front of levels of SSTables in one directory.
+///
+/// Flushed memtables land in level 0. Once level 0 holds
+/// `level0_compaction_trigger` tables they are merged, with the level 1
+/// tables they overlap, into new level 1 tables; once a deeper level
+/// outgrows its size budget (`level_base_size` for level 1, multiplied by
+/// `level_size_multiplier` per level below) one of its tables is merged
+/// into the next. Merging keeps only the newest value of each key, and
+/// drops tombstones once nothing older could remain beneath them, so a
+/// read touches at most every level 0 table plus one table per level.
+///
+/// Compaction runs on a background thread, writing its output before
+/// briefly locking to swap it in, so writes and reads are never blocked
+/// behind a merge. A `MANIFEST` file records the live tables and is
+/// replaced atomically when a flush or compaction installs new ones.
+pub struct LsmTree {
+    shared: Arc<Shared>,
+    memtable: MemTable,
+    flushes: u64,
+    compactor: Option<JoinHandle<()>>,
+}
+
+impl LsmTree {
+    /// Open the tree stored in `dir`, creating the directory if needed.
+    /// The tables listed in its manifest are picked up; any other table or
+    /// partial file, left by an interrupted flush or compaction, is
+    /// removed.
+    pub fn open<P: AsRef<Path>>(dir: P, config: LsmConfig) -> QubeResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let config = LsmConfig {
+            level0_compaction_trigger: config.level0_compaction_trigger.max(2),
+            level_size_multiplier: config.level_size_multiplier.max(2),
+            max_levels: config.max_levels.max(2),
+            ..config
+        };
+
+        let live = read_manifest(&dir)?;
+        let mut version = Version {
+            levels: vec![Vec::new(); config.max_levels],
+        };
+        let mut next_id = 1;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("tmp") => std::fs::remove_file(&path)?,
+                Some(SSTABLE_EXTENSION) => {
+                    let Some((id, level)) = parse_sstable_name(&path) else {
+                        continue;
+                    };
+                    let listed = live.as_ref().is_none_or(|live| live.contains(&(level, id)));
+                    if !listed || level >= config.max_levels {
+                        std::fs::remove_file(&path)?;
+                        continue;
+                    }
+                    next_id = next_id.max(id + 1);
+                    version.levels[level].push(Arc::new(SsTable::open(&path, id, level)?));
+                }
+                _ => {}
+            }
+        }
+        version.levels[0].sort_by_key(|t| t.id());
+        for level in version.levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        }
+        write_manifest(&dir, &version)?;
+
+        let background = config.background_compaction;
+        let block_cache_size = config.block_cache_size;
+        let shared = Arc::new(Shared {
+            dir,
+            config,
+            version: Mutex::new(version),
+            next_id: AtomicU64::new(next_id),
+            compacting: Mutex::new(()),
+            signal: Mutex::new(CompactorSignal::default()),
+            wake: Condvar::new(),
+            stats: Mutex::new(CompactionStats::default()),
+            sstable_reads: AtomicU64::new(0),
+            bloom_filter_skips: AtomicU64::new(0),
+            block_reads: AtomicU64::new(0),
+            cache: BlockCache::new(block_cache_size),
+        });
+        let compactor = if background {
+            Some(spawn_compactor(Arc::clone(&shared))?)
+        } else {
+            None
+        };
+        let tree = LsmTree {
+            shared,
+            memtable: MemTable::new(),
+            flushes: 0,
+            compactor,
+        };
+        tree.wake_compactor();
+        Ok(tree)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.shared.dir
+    }
+
+    pub fn config(&self) -> &LsmConfig {
+        &self.shared.config
+    }
+
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> QubeResult<()> {
+        self.memtable.put(key.into(), Some(value.into()));
+        self.flush_if_full()
+    }
+
+    /// Delete a key by writing a tombstone for it
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> QubeResult<()> {
+        self.memtable.put(key.into(), None);
+        self.flush_if_full()
+    }
+
+    /// The latest value of a key, from the memtable or the newest SSTable
+    /// holding it
+    pub fn get(&self, key: &[u8]) -> QubeResult<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.map(<[u8]>::to_vec));
+        }
+        let version = self.shared.version.lock().unwrap().clone();
+        for table in version.by_precedence() {
+            if !table.overlaps(key, key) {
+                continue;
+            }
+            if !table.may_contain(key) {
+                self.shared
+                    .bloom_filter_skips
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            self.shared.sstable_reads.fetch_add(1, Ordering::Relaxed);
+            if let Some(value) =
+                table.get_cached(key, &self.shared.cache, &self.shared.block_reads)?
+            {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every live entry whose key starts with `prefix`, in key order,
+    /// merged across the memtable and SSTables
+    pub fn scan(&self, prefix: &[u8]) -> QubeResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let version = self.shared.version.lock().unwrap().clone();
+        let tables: Vec<&Arc<SsTable>> = version.by_precedence().collect();
+        let mut merged: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+        // Oldest first, so newer values overwrite older ones
+        for table in tables.into_iter().rev() {
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                if key.starts_with(prefix) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        merged.extend(
+            self.memtable
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.to_vec(), value.map(<[u8]>::to_vec))),
+        );
+        Ok(merged
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect())
+    }
+
+    /// Write the memtable out as a new level 0 SSTable, even if it is not
+    /// full. Returns false if there was nothing to flush.
+    pub fn flush(&mut self) -> QubeResult<bool> {
+        if self.memtable.is_empty() {
+            return Ok(false);
+        }
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = sstable_path(&self.shared.dir, id, 0);
+        let table = SsTable::write(
+            &path,
+            id,
+            0,
+            self.shared.config.bloom_false_positive_rate,
+            self.memtable.iter(),
+        )?;
+        tracing::debug!(
+            "Flushed {} memtable entries to {}",
+            table.len(),
+            path.display()
+        );
+        {
+            let mut version = self.shared.version.lock().unwrap();
+            version.levels[0].push(Arc::new(table));
+            write_manifest(&self.shared.dir, &version)?;
+        }
+        self.flushes += 1;
+        self.memtable.clear();
+        self.wake_compactor();
+        Ok(true)
+    }
+
+    /// Run compactions until every level is within its budget. Returns how
+    /// many ran. With background compaction on this is rarely needed, but
+    /// it is safe to call at any time.
+    pub fn compact(&self) -> QubeResult<usize> {
+        let mut runs = 0;
+        while compact_once(&self.shared)? {
+            runs += 1;
+        }
+        Ok(runs)
+    }
+
+    /// Every SSTable, from the newest data to the oldest
+    pub fn sstables(&self) -> Vec<Arc<SsTable>> {
+        let version = self.shared.version.lock().unwrap();
+        version.by_precedence().cloned().collect()
+    }
+
+    /// Approximate bytes held in memory: the memtable, every open
+    /// SSTable's index and bloom filter, and the block cache
+    pub fn memory_usage(&self) -> usize {
+        let version = self.shared.version.lock().unwrap();
+        self.memtable.size() + version.memory_size() + self.shared.cache.size()
+    }
+
+    pub fn metrics(&self) -> LsmMetrics {
+        let version = self.shared.version.lock().unwrap();
+        LsmMetrics {
+            memtable_entries: self.memtable.len(),
+            memtable_bytes: self.memtable.size(),
+            memory_bytes: self.memtable.size() + version.memory_size() + self.shared.cache.size(),
+            sstables: version.levels.iter().map(Vec::len).sum(),
+            sstable_entries: version.by_precedence().map(|t| t.len()).sum(),
+            flushes: self.flushes,
+            sstable_reads: self.shared.sstable_reads.load(Ordering::Relaxed),
+            bloom_filter_skips: self.shared.bloom_filter_skips.load(Ordering::Relaxed),
+            block_reads: self.shared.block_reads.load(Ordering::Relaxed),
+            block_cache_hits: self.shared.cache.hits.load(Ordering::Relaxed),
+            block_cache_misses: self.shared.cache.misses.load(Ordering::Relaxed),
+            block_cache_bytes: self.shared.cache.size(),
+        }
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        let version = self.shared.version.lock().unwrap();
+        let depth = version
+            .levels
+            .iter()
+            .rposition(|level| !level.is_empty())
+            .map_or(1, |deepest| deepest + 1);
+        CompactionStats {
+            levels: (0..depth)
+                .map(|level| LevelStats {
+                    level,
+                    files: version.levels[level].len(),
+                    bytes: version.level_bytes(level),
+                })
+                .collect(),
+            ..self.shared.stats.lock().unwrap().clone()
+        }
+    }
+
+    fn flush_if_full(&mut self) -> QubeResult<()> {
+        let mut full = self.memtable.size() > self.shared.config.memtable_size;
+        if let Some(limit) = self.shared.config.memory_limit {
+            // Cached blocks are given up before the memtable is flushed
+            let resident = self.memtable.size() + self.shared.version.lock().unwrap().memory_size();
+            self.shared.cache.shrink_to(limit.saturating_sub(resident));
+            full |= resident > limit;
+        }
+        if full {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn wake_compactor(&self) {
+        if self.compactor.is_some() {
+            self.shared.signal.lock().unwrap().pending = true;
+            self.shared.wake.notify_one();
+        }
+    }
+}
+
+impl Drop for LsmTree {
+    fn drop(&mut self) {
+        self.shared.signal.lock().unwrap().stopped = true;
+        self.shared.wake.notify_one();
+        if let Some(compactor) = self.compactor.take() {
+            let _ = compactor.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for LsmTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LsmTree")
+            .field("dir", &self.shared.dir)
+            .field("config", &self.shared.config)
+            .field("metrics", &self.metrics())
+            .finish()
+    }
+}
+
+/// Start the thread that compacts whenever a flush asks it to
+fn spawn_compactor(shared: Arc<Shared>) -> QubeResult<JoinHandle<()>> {
+    let handle = std::thread::Builder::new()
+        .name("qubedb-lsm-compact".to_string())
+        .spawn(move || loop {
+            {
+                let mut signal = shared.signal.lock().unwrap();
+                while !signal.pending && !signal.stopped {
+                    signal = shared.wake.wait(signal).unwrap();
+                }
+                if signal.stopped {
+                    return;
+                }
+                signal.pending = false;
+            }
+            loop {
+                match compact_once(&shared) {
+                    Ok(true) if !shared.signal.lock().unwrap().stopped => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        tracing::warn!("Compaction in {} failed: {}", shared.dir.display(), e);
+                        break;
+                    }
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+/// Run one compaction, if any level needs it. Returns whether one ran.
+fn compact_once(shared: &Shared) -> QubeResult<bool> {
+    let _running = shared.compacting.lock().unwrap();
+    let Some(compaction) = pick_compaction(shared) else {
+        return Ok(false);
+    };
+
+    // Merge without holding the version lock: the inputs are immutable,
+    // and flushes only ever add newer tables to level 0
+    let mut outputs = Vec::new();
+    let mut batch: Vec<Entry> = Vec::new();
+    let mut batch_bytes = 0usize;
+    let mut kept = 0u64;
+    let write_batch = |batch: &mut Vec<Entry>, outputs: &mut Vec<SsTable>| -> QubeResult<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = sstable_path(&shared.dir, id, compaction.output_level);
+        let entries = batch.iter().map(|(k, v)| (k.as_slice(), v.as_deref()));
+        outputs.push(SsTable::write(
+            &path,
+            id,
+            compaction.output_level,
+            shared.config.bloom_false_positive_rate,
+            entries,
+        )?);
+        batch.clear();
+        Ok(())
+    };
+    merge_tables(&compaction.inputs, compaction.drop_tombstones, |entry| {
+        batch_bytes += entry_size(&entry.0, entry.1.as_deref());
+        batch.push(entry);
+        kept += 1;
+        if batch_bytes >= shared.config.sstable_size {
+            write_batch(&mut batch, &mut outputs)?;
+            batch_bytes = 0;
+        }
+        Ok(())
+    })?;
+    write_batch(&mut batch, &mut outputs)?;
+
+    let input_ids: HashSet<u64> = compaction.inputs.iter().map(|t| t.id()).collect();
+    let bytes_written: u64 = outputs.iter().map(SsTable::size).sum();
+    let output_files = outputs.len();
+    {
+        let mut version = shared.version.lock().unwrap();
+        for level in version.levels.iter_mut() {
+            level.retain(|t| !input_ids.contains(&t.id()));
+        }
+        let level = &mut version.levels[compaction.output_level];
+        level.extend(outputs.into_iter().map(Arc::new));
+        level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        write_manifest(&shared.dir, &version)?;
+    }
+    for input in &compaction.inputs {
+        input.obsolete.store(true, Ordering::SeqCst);
+        shared.cache.invalidate(input.id());
+    }
+
+    let read: u64 = compaction.inputs.iter().map(|t| t.len() as u64).sum();
+    let mut stats = shared.stats.lock().unwrap();
+    stats.compactions += 1;
+    stats.bytes_read += compaction.inputs.iter().map(|t| t.size()).sum::<u64>();
+    stats.bytes_written += bytes_written;
+    stats.entries_dropped += read - kept;
+    tracing::debug!(
+        "Compacted {} tables into {} at level {}",
+        compaction.inputs.len(),
+        output_files,
+        compaction.output_level
+    );
+    Ok(true)
+}
+
+/// Choose the next compaction: level 0 once it has too many tables,
+/// otherwise the shallowest level over its size budget
+fn pick_compaction(shared: &Shared) -> Option<Compaction> {
+    let config = &shared.config;
+    let version = shared.version.lock().unwrap();
+
+    let (mut inputs, output_level) = if version.levels[0].len() >= config.level0_compaction_trigger
+    {
+        (
+            version.levels[0].iter().rev().cloned().collect::<Vec<_>>(),
+            1,
+        )
+    } else {
+        let mut budget = config.level_base_size;
+        let level = (1..config.max_levels - 1).find(|&level| {
+            let over = version.level_bytes(level) > budget;
+            budget = budget.saturating_mul(config.level_size_multiplier);
+            over
+        })?;
+        // The oldest table of the level goes first
+        let table = version.levels[level].iter().min_by_key(|t| t.id())?;
+        (vec![Arc::clone(table)], level + 1)
+    };
+
+    let first = inputs.iter().map(|t| t.first_key()).min()?.to_vec();
+    let last = inputs.iter().map(|t| t.last_key()).max()?.to_vec();
+    inputs.extend(version.overlapping(output_level, &first, &last));
+    let drop_tombstones = (output_level + 1..version.levels.len())
+        .all(|level| version.overlapping(level, &first, &last).is_empty());
+    Some(Compaction {
+        inputs,
+        output_level,
+        drop_tombstones,
+    })
+}
+
+/// Merge tables into one sorted stream holding the newest entry of each
+/// key. `inputs` are ordered newest first.
+fn merge_tables(
+    inputs: &[Arc<SsTable>],
+    drop_tombstones: bool,
+    mut emit: impl FnMut(Entry) -> QubeResult<()>,
+) -> QubeResult<()> {
+    let mut sources = inputs
+        .iter()
+        .map(|table| table.iter())
+        .collect::<QubeResult<Vec<_>>>()?;
+    let mut heads = sources
+        .iter_mut()
+        .map(|source| source.next().transpose())
+        .collect::<QubeResult<Vec<_>>>()?;
+
+    loop {
+        // The smallest key; on a tie the first (newest) source wins
+        let Some(winner) = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|(key, _)| (key, i)))
+            .min()
+            .map(|(_, i)| i)
+        else {
+            return Ok(());
+        };
+        let key = heads[winner].as_ref().unwrap().0.clone();
+        let mut newest = None;
+        for (i, source) in sources.iter_mut().enumerate() {
+            if heads[i].as_ref().is_some_and(|(k, _)| *k == key) {
+                let entry = std::mem::replace(&mut heads[i], source.next().transpose()?);
+                if i == winner {
+                    newest = entry;
+                }
+            }
+        }
+        if let Some((key, value)) = newest {
+            if value.is_some() || !drop_tombstones {
+                emit((key, value))?;
+            }
+        }
+    }
+}
+
+fn sstable_path(dir: &Path, id: u64, level: usize) -> PathBuf {
+    dir.join(format!("{:010}-L{}.{}", id, level, SSTABLE_EXTENSION))
+}
+
+/// The id and level encoded in an SSTable's file name
+fn parse_sstable_name(path: &Path) -> Option<(u64, usize)> {
+    let stem = path.file_stem()?.to_str()?;
+    let (id, level) = stem.split_once("-L")?;
+    Some((id.parse().ok()?, level.parse().ok()?))
+}
+
+/// The (level, id) pairs the manifest lists, or `None` if there is none
+fn read_manifest(dir: &Path) -> QubeResult<Option<HashSet<(usize, u64)>>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut live = HashSet::new();
+    for line in std::fs::read_to_string(&path)?.lines() {
+        let parsed = line
+            .split_once(' ')
+            .and_then(|(level, id)| Some((level.parse().ok()?, id.parse().ok()?)));
+        match parsed {
+            Some(entry) => live.insert(entry),
+            None => {
+                return Err(QubeError::Storage(format!(
+                    "Corrupt manifest line in {}: {:?}",
+                    path.display(),
+                    line
+                )))
+            }
+        };
+    }
+    Ok(Some(live))
+}
+
+/// Replace the manifest with the tables of `version`
+fn write_manifest(dir: &Path, version: &Version) -> QubeResult<()> {
+    let mut contents = String::new();
+    for (level, tables) in version.levels.iter().enumerate() {
+        for table in tables {
+            contents.push_str(&format!("{} {}\n", level, table.id()));
+        }
+    }
+    let path = dir.join(MANIFEST_FILE);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Bytes a memtable entry is charged for
+fn entry_size(key: &[u8], value: Option<&[u8]>) -> usize {
+    key.len() + value.map_or(0, <[u8]>::len) + ENTRY_OVERHEAD
+}
+
+/// Write one entry, returning its encoded length
+fn write_entry(writer: &mut impl Write, key: &[u8], value: Option<&[u8]>) -> QubeResult<u64> {
+    write_bytes(writer, key)?;
+    match value {
+        None => {
+            writer.write_all(&[0])?;
+            Ok(4 + key.len() as u64 + 1)
+        }
+        Some(value) => {
+            writer.write_all(&[1])?;
+            write_bytes(writer, value)?;
+            Ok(4 + key.len() as u64 + 1 + 4 + value.len() as u64)
+        }
+    }
+}
+
+/// Read one entry along with its encoded length
+fn read_entry(reader: &mut impl Read) -> QubeResult<(Vec<u8>, Option<Vec<u8>>, u64)> {
+    let key = read_bytes(reader)?;
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    match tag[0] {
+        0 => {
+            let len = 4 + key.len() as u64 + 1;
+            Ok((key, None, len))
+        }
+        1 => {
+            let value = read_bytes(reader)?;
+            let len = 4 + key.len() as u64 + 1 + 4 + value.len() as u64;
+            Ok((key, Some(value), len))
+        }
+        other => Err(QubeError::Storage(format!(
+            "Corrupt SSTable entry: unknown tag {}",
+            other
+        ))),
+    }
+}
+
+/// Write a length-prefixed byte string
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> QubeResult<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| {
+        QubeError::Storage(format!(
+            "SSTable entry of {} bytes is too large",
+            bytes.len()
+        ))
+    })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(reader: &mut impl Read) -> QubeResult<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> QubeResult<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> QubeResult<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tree directory no other test uses, removed if it exists
+    fn tree_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qubedb-lsm-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// A config with a tiny memtable that compacts only when asked
+    fn small_config() -> LsmConfig {
+        LsmConfig {
+            memtable_size: 1024,
+            background_compaction: false,
+            ..LsmConfig::default()
+        }
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn full_memtables_are_flushed_and_still_read() {
+        let dir = tree_dir("flush");
+        let mut tree = LsmTree::open(&dir, small_config()).unwrap();
+        for i in 0..100 {
+            tree.put(key(i), format!("value{}", i)).unwrap();
+        }
+        let metrics = tree.metrics();
+        assert!(metrics.flushes > 0);
+        assert!(metrics.sstables > 0);
+        assert!(metrics.memtable_bytes <= 1024 + 64);
+
+        // Overwrites in the memtable win over flushed values
+        tree.put(key(3), "newer").unwrap();
+        assert_eq!(tree.get(&key(3)).unwrap().as_deref(), Some(&b"newer"[..]));
+        assert_eq!(
+            tree.get(&key(42)).unwrap().as_deref(),
+            Some(&b"value42"[..])
+        );
+        assert_eq!(tree.get(b"missing").unwrap(), None);
+        assert_eq!(tree.scan(b"key00").unwrap().len(), 100);
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flushed_tables_are_recovered_on_reopen() {
+        let dir = tree_dir("reopen");
+        let mut tree = LsmTree::open(&dir, small_config()).unwrap();
+        for i in 0..50 {
+            tree.put(key(i), vec![i as u8]).unwrap();
+        }
+        tree.delete(key(7)).unwrap();
+        tree.flush().unwrap();
+        tree.put(key(99), "unflushed").unwrap();
+        drop(tree);
+        // An interrupted flush leaves a partial file behind
+        std::fs::write(dir.join("000999-0.sst.tmp"), b"partial").unwrap();
+
+        let tree = LsmTree::open(&dir, small_config()).unwrap();
+        assert_eq!(tree.get(&key(8)).unwrap(), Some(vec![8]));
+        assert_eq!(tree.get(&key(7)).unwrap(), None);
+        // Only flushed writes survive; the memtable is not logged
+        assert_eq!(tree.get(&key(99)).unwrap(), None);
+        assert_eq!(tree.scan(b"key").unwrap().len(), 49);
+        assert!(!dir.join("000999-0.sst.tmp").exists());
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_merges_away_deleted_and_overwritten_values() {
+        let dir = tree_dir("compaction");
+        let mut tree = LsmTree::open(&dir, small_config()).unwrap();
+        for i in 0..20 {
+            tree.put(key(i), "old").unwrap();
+        }
+        tree.flush().unwrap();
+        for i in 0..10 {
+            tree.delete(key(i)).unwrap();
+        }
+        tree.flush().unwrap();
+        for round in 0..2 {
+            tree.put(key(15), format!("new{}", round)).unwrap();
+            tree.flush().unwrap();
+        }
+        assert_eq!(tree.compaction_stats().levels[0].files, 4);
+
+        assert!(tree.compact().unwrap() > 0);
+        let stats = tree.compaction_stats();
+        assert_eq!(stats.levels[0].files, 0);
+        assert!(stats.entries_dropped >= 10 + 2);
+        // Nothing deeper could hold the deleted keys, so their tombstones
+        // went along with the values they hid
+        assert_eq!(tree.metrics().sstable_entries, 10);
+        for i in 0..10 {
+            assert_eq!(tree.get(&key(i)).unwrap(), None);
+        }
+        assert_eq!(tree.get(&key(15)).unwrap().as_deref(), Some(&b"new1"[..]));
+        assert_eq!(tree.get(&key(16)).unwrap().as_deref(), Some(&b"old"[..]));
+        drop(tree);
+
+        let tree = LsmTree::open(&dir, small_config()).unwrap();
+        assert_eq!(tree.scan(b"key").unwrap().len(), 10);
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A tree with `0..100` flushed to one SSTable
+    fn flushed_tree(name: &str, config: LsmConfig) -> (PathBuf, LsmTree) {
+        let dir = tree_dir(name);
+        let mut tree = LsmTree::open(
+            &dir,
+            LsmConfig {
+                memtable_size: DEFAULT_MEMTABLE_SIZE,
+                ..config
+            },
+        )
+        .unwrap();
+        for i in 0..100 {
+            tree.put(key(i), "value").unwrap();
+        }
+        tree.flush().unwrap();
+        (dir, tree)
+    }
+
+    #[test]
+    fn bloom_filters_skip_tables_without_the_key() {
+        // Absent keys that still fall within the table's key range
+        let absent: Vec<Vec<u8>> = (0..99)
+            .map(|i| format!("key{:04}x", i).into_bytes())
+            .collect();
+
+        let (dir, tree) = flushed_tree("bloom", small_config());
+        for key in &absent {
+            assert_eq!(tree.get(key).unwrap(), None);
+        }
+        let metrics = tree.metrics();
+        assert!(metrics.bloom_filter_skips >= 90, "{:?}", metrics);
+        assert_eq!(metrics.bloom_filter_skips + metrics.sstable_reads, 99);
+        // A key the table holds is never skipped
+        assert!(tree.get(&key(5)).unwrap().is_some());
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let unfiltered = LsmConfig {
+            bloom_false_positive_rate: None,
+            ..small_config()
+        };
+        let (dir, tree) = flushed_tree("no-bloom", unfiltered);
+        for key in &absent {
+            assert_eq!(tree.get(key).unwrap(), None);
+        }
+        assert_eq!(tree.metrics().bloom_filter_skips, 0);
+        assert_eq!(tree.metrics().sstable_reads, 99);
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_block_cache_serves_repeated_reads() {
+        let (dir, tree) = flushed_tree("block-cache", small_config());
+        for _ in 0..3 {
+            assert!(tree.get(&key(40)).unwrap().is_some());
+        }
+        // Keys in the same block share the cached copy
+        assert!(tree.get(&key(41)).unwrap().is_some());
+        let metrics = tree.metrics();
+        assert_eq!(metrics.block_reads, 1);
+        assert_eq!(metrics.block_cache_misses, 1);
+        assert_eq!(metrics.block_cache_hits, 3);
+        assert!(metrics.block_cache_bytes > 0);
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let uncached = LsmConfig {
+            block_cache_size: 0,
+            ..small_config()
+        };
+        let (dir, tree) = flushed_tree("no-block-cache", uncached);
+        for _ in 0..3 {
+            assert!(tree.get(&key(40)).unwrap().is_some());
+        }
+        let metrics = tree.metrics();
+        assert_eq!(metrics.block_reads, 3);
+        assert_eq!(metrics.block_cache_hits, 0);
+        assert_eq!(metrics.block_cache_bytes, 0);
+        drop(tree);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! Latency metrics for QubeDB
+//!
+//! The global `MetricsRegistry` keeps one latency histogram per operation
+//! type (`select`, `insert`, `vector_search`, ...), recorded by the embedded
+//! database as operations complete. Averages hide the slow tail, so the
+//! registry reports percentiles: p50, p95, p99 and the maximum.
+//!
+//! The histograms follow the HdrHistogram layout: each power of two of
+//! microseconds is split into equal buckets, so a recorded latency is off
+//! by less than 1% at any magnitude, in a bounded amount of memory per
+//! operation type. The maximum is kept exactly.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Values below this get a bucket each; every power of two above it is
+/// split into `SUB_BUCKETS / 2` buckets, so a recorded value is off by less
+/// than `2 / SUB_BUCKETS`
+const SUB_BUCKETS: u64 = 256;
+
+/// `log2(SUB_BUCKETS)`
+const SUB_BUCKET_BITS: u32 = 8;
+
+static REGISTRY: MetricsRegistry = MetricsRegistry::new();
+
+/// The process-wide registry operations are recorded in
+pub fn registry() -> &'static MetricsRegistry {
+    &REGISTRY
+}
+
+/// Record how long one operation of type `op` took in the global registry
+pub fn record_latency(op: &str, latency: Duration) {
+    REGISTRY.record(op, latency);
+}
+
+/// Statements recorded under their own leading keyword
+const SQL_OPERATIONS: [&str; 7] = [
+    "select", "insert", "update", "delete", "create", "drop", "alter",
+];
+
+/// Operation type a SQL statement is recorded under: its leading keyword
+/// for the common statements, `other` for the rest
+pub fn sql_operation(sql: &str) -> &'static str {
+    let keyword = sql.split_whitespace().next().unwrap_or("");
+    SQL_OPERATIONS
+        .into_iter()
+        .find(|op| keyword.eq_ignore_ascii_case(op))
+        .unwrap_or("other")
+}
+
+/// Distribution of latencies, in microseconds
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    /// Values recorded in each bucket, grown as larger values arrive
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = bucket_of(micros);
+        if bucket >= self.counts.len() {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    /// Values recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Largest value recorded
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// Smallest recorded value that `percent` percent of the values are at
+    /// or below, to within the bucket width. Zero when nothing was recorded.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((percent / 100.0 * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(upper_bound(bucket).min(self.max));
+            }
+        }
+        self.max()
+    }
+
+    pub fn summary(&self, op: &str) -> LatencySummary {
+        LatencySummary {
+            op: op.to_string(),
+            count: self.total,
+            p50_us: self.percentile(50.0).as_micros() as u64,
+            p95_us: self.percentile(95.0).as_micros() as u64,
+            p99_us: self.percentile(99.0).as_micros() as u64,
+            max_us: self.max,
+        }
+    }
+}
+
+/// Bucket a value in microseconds falls in
+fn bucket_of(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let magnitude = 63 - value.leading_zeros() - (SUB_BUCKET_BITS - 1);
+    let sub_bucket = value >> magnitude;
+    (magnitude as u64 * (SUB_BUCKETS / 2) + sub_bucket) as usize
+}
+
+/// Largest value that falls in `bucket`
+fn upper_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let magnitude = (bucket - SUB_BUCKETS / 2) / (SUB_BUCKETS / 2);
+    let sub_bucket = bucket - magnitude * (SUB_BUCKETS / 2);
+    ((sub_bucket + 1) << magnitude) - 1
+}
+
+/// Percentiles of one operation type's latency
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    pub op: String,
+    pub count: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Latency histograms by operation type
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    histograms: Mutex<BTreeMap<String, LatencyHistogram>>,
+}
+
+impl MetricsRegistry {
+    pub const fn new() -> Self {
+        MetricsRegistry {
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record(&self, op: &str, latency: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        match histograms.get_mut(op) {
+            Some(histogram) => histogram.record(latency),
+            None => {
+                let mut histogram = LatencyHistogram::new();
+                histogram.record(latency);
+                histograms.insert(op.to_string(), histogram);
+            }
+        }
+    }
+
+    /// A copy of one operation type's histogram
+    pub fn histogram(&self, op: &str) -> Option<LatencyHistogram> {
+        self.histograms.lock().unwrap().get(op).cloned()
+    }
+
+    /// Percentiles of every operation type, by name
+    pub fn summaries(&self) -> Vec<LatencySummary> {
+        self.histograms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, histogram)| histogram.summary(op))
+            .collect()
+    }
+
+    /// Forget everything recorded
+    pub fn reset(&self) {
+        self.histograms.lock().unwrap().clear();
+    }
+
+    /// The percentiles in the Prometheus text format, in seconds
+    pub fn render_prometheus(&self) -> String {
+        let summaries = self.summaries();
+        let mut out = String::new();
+        out.push_str("# HELP qubedb_operation_latency_seconds Latency of database operations\n");
+        out.push_str("# TYPE qubedb_operation_latency_seconds summary\n");
+        for summary in &summaries {
+            for (quantile, micros) in [
+                ("0.5", summary.p50_us),
+                ("0.95", summary.p95_us),
+                ("0.99", summary.p99_us),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "qubedb_operation_latency_seconds{{op=\"{}\",quantile=\"{}\"}} {}",
+                    summary.op,
+                    quantile,
+                    micros as f64 / 1_000_000.0
+                );
+            }
+            let _ = writeln!(
+                out,
+                "qubedb_operation_latency_seconds_count{{op=\"{}\"}} {}",
+                summary.op, summary.count
+            );
+        }
+        out.push_str("# HELP qubedb_operation_latency_max_seconds Slowest database operation\n");
+        out.push_str("# TYPE qubedb_operation_latency_max_seconds gauge\n");
+        for summary in &summaries {
+            let _ = writeln!(
+                out,
+                "qubedb_operation_latency_max_seconds{{op=\"{}\"}} {}",
+                summary.op,
+                summary.max_us as f64 / 1_000_000.0
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn micros(value: u64) -> Duration {
+        Duration::from_micros(value)
+    }
+
+    #[test]
+    fn buckets_hold_values_to_within_one_percent() {
+        for value in [0, 1, 255, 256, 257, 1_000, 65_535, 1_000_000, u64::MAX / 2] {
+            let bucket = bucket_of(value);
+            let upper = upper_bound(bucket);
+            assert!(upper >= value, "{}", value);
+            assert!(
+                upper - value <= value / 100,
+                "{} in bucket up to {}",
+                value,
+                upper
+            );
+            assert_eq!(bucket_of(upper), bucket);
+            assert!(bucket == 0 || upper_bound(bucket - 1) < value);
+        }
+    }
+
+    #[test]
+    fn percentiles_come_from_the_recorded_latencies() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(99.0), Duration::ZERO);
+        for value in 1..=1_000 {
+            histogram.record(micros(value));
+        }
+        histogram.record(micros(50_000));
+        assert_eq!(histogram.count(), 1_001);
+        assert_eq!(histogram.max(), micros(50_000));
+        assert_eq!(histogram.percentile(100.0), micros(50_000));
+        let summary = histogram.summary("select");
+        assert_eq!(summary.p50_us, 501);
+        for (reported, exact) in [(summary.p95_us, 951), (summary.p99_us, 991)] {
+            assert!(reported >= exact && reported - exact <= exact / 100);
+        }
+    }
+
+    #[test]
+    fn operations_are_named_and_reported_separately() {
+        assert_eq!(sql_operation("  SELECT * FROM t"), "select");
+        assert_eq!(sql_operation("insert into t values (1)"), "insert");
+        assert_eq!(sql_operation("EXPLAIN SELECT 1"), "other");
+        assert_eq!(sql_operation(""), "other");
+
+        let registry = MetricsRegistry::new();
+        registry.record("select", micros(100));
+        registry.record("select", micros(300));
+        registry.record("insert", micros(2_000_000));
+        let ops: Vec<(String, u64)> = registry
+            .summaries()
+            .into_iter()
+            .map(|summary| (summary.op, summary.count))
+            .collect();
+        assert_eq!(ops, [("insert".to_string(), 1), ("select".to_string(), 2)]);
+        assert_eq!(registry.histogram("select").unwrap().max(), micros(300));
+
+        let text = registry.render_prometheus();
+        assert!(text
+            .contains("qubedb_operation_latency_seconds{op=\"select\",quantile=\"0.5\"} 0.0001\n"));
+        assert!(text.contains("qubedb_operation_latency_seconds_count{op=\"select\"} 2\n"));
+        assert!(text.contains("qubedb_operation_latency_max_seconds{op=\"insert\"} 2\n"));
+
+        registry.reset();
+        assert!(registry.summaries().is_empty());
+        assert!(registry.histogram("select").is_none());
+    }
+}
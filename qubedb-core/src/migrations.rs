@@ -0,0 +1,406 @@
+//! Schema migrations for QubeDB
+//!
+//! A `MigrationRunner` applies an ordered list of named migrations to a
+//! query engine and records each one in the `_migrations` table, so running
+//! it again only applies migrations added since. Each migration runs in its
+//! own transaction together with its record: it either applies completely
+//! and is recorded, or leaves nothing behind. The most recent migration can
+//! be rolled back by running its `down` step.
+
+use crate::error::{QubeError, QubeResult};
+use crate::query::{ExecutionContext, QueryEngine, QueryOptions};
+use crate::types::Value;
+use sqlparser::ast::Value as SqlValue;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Table recording which migrations have been applied
+pub const MIGRATIONS_TABLE: &str = "_migrations";
+
+/// Builds a migration's SQL when it runs, from the database as it is then
+pub type MigrationFn = Arc<dyn Fn(&QueryEngine) -> QubeResult<String> + Send + Sync>;
+
+/// The SQL one direction of a migration runs
+#[derive(Clone)]
+pub enum MigrationStep {
+    /// A fixed script of `;`-separated statements
+    Sql(String),
+    /// A closure returning the script to run
+    Dynamic(MigrationFn),
+}
+
+impl MigrationStep {
+    /// A step whose script is built by `f` when the migration runs
+    pub fn dynamic<F>(f: F) -> Self
+    where
+        F: Fn(&QueryEngine) -> QubeResult<String> + Send + Sync + 'static,
+    {
+        MigrationStep::Dynamic(Arc::new(f))
+    }
+
+    fn script(&self, engine: &QueryEngine) -> QubeResult<String> {
+        match self {
+            MigrationStep::Sql(sql) => Ok(sql.clone()),
+            MigrationStep::Dynamic(f) => f(engine),
+        }
+    }
+}
+
+impl From<&str> for MigrationStep {
+    fn from(sql: &str) -> Self {
+        MigrationStep::Sql(sql.to_string())
+    }
+}
+
+impl From<String> for MigrationStep {
+    fn from(sql: String) -> Self {
+        MigrationStep::Sql(sql)
+    }
+}
+
+impl std::fmt::Debug for MigrationStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationStep::Sql(sql) => f.debug_tuple("Sql").field(sql).finish(),
+            MigrationStep::Dynamic(_) => f.write_str("Dynamic"),
+        }
+    }
+}
+
+/// A named schema change and, optionally, how to undo it
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub name: String,
+    pub up: MigrationStep,
+    pub down: Option<MigrationStep>,
+}
+
+impl Migration {
+    pub fn new(name: impl Into<String>, up: impl Into<MigrationStep>) -> Self {
+        Migration {
+            name: name.into(),
+            up: up.into(),
+            down: None,
+        }
+    }
+
+    /// Set the step that undoes this migration
+    pub fn with_down(mut self, down: impl Into<MigrationStep>) -> Self {
+        self.down = Some(down.into());
+        self
+    }
+}
+
+/// A row of the `_migrations` table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    /// Position of the migration in the list, starting at 1
+    pub version: i64,
+    pub name: String,
+    /// When it was applied, in milliseconds since the epoch
+    pub applied_at: Option<i64>,
+}
+
+/// Applies an ordered list of migrations to an engine
+pub struct MigrationRunner {
+    engine: Arc<QueryEngine>,
+    migrations: Vec<Migration>,
+}
+
+impl MigrationRunner {
+    /// A runner for `migrations`, in the order they must be applied. Names
+    /// must be unique.
+    pub fn new(engine: Arc<QueryEngine>, migrations: Vec<Migration>) -> QubeResult<Self> {
+        let mut names = HashSet::new();
+        if let Some(duplicate) = migrations.iter().find(|m| !names.insert(m.name.as_str())) {
+            return Err(QubeError::Other(format!(
+                "Migration '{}' is listed more than once",
+                duplicate.name
+            )));
+        }
+        Ok(MigrationRunner { engine, migrations })
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    /// The migrations recorded as applied, oldest first
+    pub fn applied(&self) -> QubeResult<Vec<AppliedMigration>> {
+        self.ensure_table()?;
+        let statement = self.engine.parse_sql(&format!(
+            "SELECT version, name, applied_at FROM {} ORDER BY version",
+            MIGRATIONS_TABLE
+        ))?;
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        let result = self.engine.execute_statement(statement, &mut ctx)?;
+        result
+            .rows
+            .iter()
+            .map(|row| {
+                let version = row.get("version").and_then(Value::as_i64);
+                match (version, row.get("name")) {
+                    (Some(version), Some(Value::String(name))) => Ok(AppliedMigration {
+                        version,
+                        name: name.clone(),
+                        applied_at: row.get("applied_at").and_then(Value::as_i64),
+                    }),
+                    _ => Err(QubeError::Other(format!(
+                        "Malformed row in {}: {:?}",
+                        MIGRATIONS_TABLE, row
+                    ))),
+                }
+            })
+            .collect()
+    }
+
+    /// The migrations not yet applied, in the order they will run
+    pub fn pending(&self) -> QubeResult<&[Migration]> {
+        let applied = self.check_history()?;
+        Ok(&self.migrations[applied.len()..])
+    }
+
+    /// Apply every pending migration in order, returning their names.
+    /// Stops at the first migration that fails; the ones before it stay
+    /// applied.
+    pub fn migrate(&self) -> QubeResult<Vec<String>> {
+        let applied = self.check_history()?;
+        let mut names = Vec::new();
+        for (position, migration) in self.migrations.iter().enumerate().skip(applied.len()) {
+            let record = format!(
+                "INSERT INTO {} (version, name) VALUES ({}, {})",
+                MIGRATIONS_TABLE,
+                position + 1,
+                SqlValue::SingleQuotedString(migration.name.clone())
+            );
+            self.run(migration, &migration.up, &record)?;
+            tracing::info!("Applied migration {}", migration.name);
+            names.push(migration.name.clone());
+        }
+        Ok(names)
+    }
+
+    /// Undo the most recently applied migration with its `down` step,
+    /// returning its name, or `None` if nothing has been applied
+    pub fn rollback(&self) -> QubeResult<Option<String>> {
+        let applied = self.check_history()?;
+        let Some(last) = applied.last() else {
+            return Ok(None);
+        };
+        let migration = &self.migrations[applied.len() - 1];
+        let down = migration.down.as_ref().ok_or_else(|| {
+            QubeError::Other(format!(
+                "Migration '{}' has no down step and cannot be rolled back",
+                migration.name
+            ))
+        })?;
+        let record = format!(
+            "DELETE FROM {} WHERE version = {}",
+            MIGRATIONS_TABLE, last.version
+        );
+        self.run(migration, down, &record)?;
+        tracing::info!("Rolled back migration {}", migration.name);
+        Ok(Some(migration.name.clone()))
+    }
+
+    /// Run one step of a migration and the statement updating its record
+    /// as a single transaction
+    fn run(&self, migration: &Migration, step: &MigrationStep, record: &str) -> QubeResult<()> {
+        let mut statements = self.engine.parse_script(&step.script(&self.engine)?)?;
+        statements.extend(self.engine.parse_script(record)?);
+        self.engine.execute_atomically(&statements).map_err(|e| {
+            QubeError::Other(format!("Migration '{}' failed: {}", migration.name, e))
+        })?;
+        Ok(())
+    }
+
+    /// The applied migrations, checked to be a prefix of this runner's list
+    fn check_history(&self) -> QubeResult<Vec<AppliedMigration>> {
+        let applied = self.applied()?;
+        if applied.len() > self.migrations.len() {
+            return Err(QubeError::Other(format!(
+                "{} migrations are applied but only {} are known",
+                applied.len(),
+                self.migrations.len()
+            )));
+        }
+        for (position, (record, migration)) in applied.iter().zip(&self.migrations).enumerate() {
+            if record.version != position as i64 + 1 || record.name != migration.name {
+                return Err(QubeError::Other(format!(
+                    "Applied migration {} '{}' does not match migration {} '{}'",
+                    record.version,
+                    record.name,
+                    position + 1,
+                    migration.name
+                )));
+            }
+        }
+        Ok(applied)
+    }
+
+    fn ensure_table(&self) -> QubeResult<()> {
+        if self
+            .engine
+            .list_tables()
+            .iter()
+            .any(|t| t == MIGRATIONS_TABLE)
+        {
+            return Ok(());
+        }
+        self.engine.execute_script(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             version INT PRIMARY KEY, \
+             name TEXT NOT NULL UNIQUE, \
+             applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP)",
+            MIGRATIONS_TABLE
+        ))?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for MigrationRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MigrationRunner")
+            .field("migrations", &self.migrations)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users_migrations() -> Vec<Migration> {
+        vec![
+            Migration::new(
+                "create_users",
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)",
+            )
+            .with_down("DROP TABLE users"),
+            // Only runs once the table exists
+            Migration::new(
+                "seed_users",
+                "INSERT INTO users VALUES (1, 'ann'); INSERT INTO users VALUES (2, 'bob')",
+            )
+            .with_down("DELETE FROM users"),
+            Migration::new(
+                "count_users",
+                MigrationStep::dynamic(|engine| {
+                    let count = engine.execute_script("SELECT id FROM users")?[0].rows.len();
+                    Ok(format!(
+                        "CREATE TABLE stats (users INT); INSERT INTO stats VALUES ({})",
+                        count
+                    ))
+                }),
+            ),
+        ]
+    }
+
+    fn names(runner: &MigrationRunner) -> Vec<String> {
+        runner
+            .applied()
+            .unwrap()
+            .into_iter()
+            .map(|migration| migration.name)
+            .collect()
+    }
+
+    fn count(engine: &QueryEngine, sql: &str) -> usize {
+        engine.execute_script(sql).unwrap()[0].rows.len()
+    }
+
+    #[test]
+    fn migrations_are_applied_in_order_and_recorded() {
+        let engine = Arc::new(QueryEngine::new());
+        let runner = MigrationRunner::new(Arc::clone(&engine), users_migrations()).unwrap();
+        assert_eq!(runner.pending().unwrap().len(), 3);
+
+        let applied = runner.migrate().unwrap();
+        assert_eq!(applied, ["create_users", "seed_users", "count_users"]);
+        let records = runner.applied().unwrap();
+        assert_eq!(
+            records.iter().map(|m| m.version).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert!(records.iter().all(|m| m.applied_at.is_some()));
+        // The dynamic step saw the rows the one before it inserted
+        let stats = engine.execute_script("SELECT users FROM stats").unwrap();
+        assert_eq!(stats[0].rows[0]["users"], Value::Int32(2));
+        assert!(runner.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn applied_migrations_are_skipped() {
+        let engine = Arc::new(QueryEngine::new());
+        let mut migrations = users_migrations();
+        let later = migrations.split_off(2);
+        let runner = MigrationRunner::new(Arc::clone(&engine), migrations.clone()).unwrap();
+        assert_eq!(runner.migrate().unwrap().len(), 2);
+        assert!(runner.migrate().unwrap().is_empty());
+
+        // Seeding again would break the primary key, so only the new
+        // migration may run
+        migrations.extend(later);
+        let runner = MigrationRunner::new(Arc::clone(&engine), migrations).unwrap();
+        assert_eq!(runner.migrate().unwrap(), ["count_users"]);
+        assert_eq!(count(&engine, "SELECT id FROM users"), 2);
+
+        // A list that doesn't start with what was applied is refused
+        let renamed = vec![Migration::new("other", "SELECT 1")];
+        let runner = MigrationRunner::new(Arc::clone(&engine), renamed).unwrap();
+        assert!(runner.migrate().is_err());
+        assert!(MigrationRunner::new(
+            engine,
+            vec![
+                Migration::new("a", "SELECT 1"),
+                Migration::new("a", "SELECT 2")
+            ]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn a_failed_migration_leaves_nothing_behind() {
+        let engine = Arc::new(QueryEngine::new());
+        let mut migrations = users_migrations();
+        migrations.truncate(1);
+        migrations.push(Migration::new(
+            "broken",
+            "CREATE TABLE audit (id INT); INSERT INTO missing VALUES (1)",
+        ));
+        migrations.push(Migration::new("never_run", "CREATE TABLE later (id INT)"));
+        let runner = MigrationRunner::new(Arc::clone(&engine), migrations).unwrap();
+
+        let err = runner.migrate().unwrap_err();
+        assert!(err.to_string().contains("broken"), "{}", err);
+        assert_eq!(names(&runner), ["create_users"]);
+        let tables = engine.list_tables();
+        assert!(tables.contains(&"users".to_string()));
+        assert!(!tables.contains(&"audit".to_string()));
+        assert!(!tables.contains(&"later".to_string()));
+        assert_eq!(runner.pending().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rollback_undoes_the_latest_migration() {
+        let engine = Arc::new(QueryEngine::new());
+        let runner = MigrationRunner::new(Arc::clone(&engine), users_migrations()).unwrap();
+        runner.migrate().unwrap();
+
+        // The latest migration has no down step
+        assert!(runner.rollback().is_err());
+        assert_eq!(names(&runner).len(), 3);
+
+        let engine = Arc::new(QueryEngine::new());
+        let mut migrations = users_migrations();
+        migrations.truncate(2);
+        let runner = MigrationRunner::new(Arc::clone(&engine), migrations).unwrap();
+        runner.migrate().unwrap();
+        assert_eq!(runner.rollback().unwrap().as_deref(), Some("seed_users"));
+        assert_eq!(count(&engine, "SELECT id FROM users"), 0);
+        assert_eq!(runner.rollback().unwrap().as_deref(), Some("create_users"));
+        assert!(!engine.list_tables().contains(&"users".to_string()));
+        assert_eq!(runner.rollback().unwrap(), None);
+        assert_eq!(runner.migrate().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,421 @@
+//! Logical databases for QubeDB
+//!
+//! One engine can hold several databases, each with its own tables and
+//! indexes, while sharing the engine's storage and write-ahead log. Objects
+//! of the default database, `public`, keep their plain names in the
+//! catalog; those of any other database are stored as `database.name`.
+//!
+//! Before a statement runs, the table and index names it mentions are
+//! qualified with the database its session is using, so in database `sales`
+//! `t` means `sales.t`, while `public.t` always means `t`. The rewritten
+//! statement is what gets logged, so replaying the log needs no session.
+//! Names that refer to a common table expression in scope where they
+//! appear are left bare.
+//! Schemas are the same thing under another name: `CREATE SCHEMA` creates a
+//! database and `SET schema` switches to one like `USE` does.
+
+use sqlparser::ast::{
+    ColumnOption, Expr, Ident, ObjectName, ObjectType, Query, SetExpr, Statement, TableAlias,
+    TableConstraint, TableFactor, Visit, VisitMut, Visitor, VisitorMut,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::ControlFlow;
+
+/// Database that sessions start in and unqualified names belong to
+pub const DEFAULT_DATABASE: &str = "public";
+
+/// The database a catalog name belongs to, and the name within it
+pub fn split(name: &str) -> (&str, &str) {
+    match name.split_once('.') {
+        Some((database, name)) => (database, name),
+        None => (DEFAULT_DATABASE, name),
+    }
+}
+
+/// Whether `database` names the default database
+pub fn is_default(database: &str) -> bool {
+    database.eq_ignore_ascii_case(DEFAULT_DATABASE)
+}
+
+/// Qualify the table and index names in `statement` with `database`
+pub(crate) fn qualify(statement: &mut Statement, database: &str) {
+    let ctes = CteReferences::of(statement);
+    let _ = statement.visit(&mut Qualifier { database, ctes });
+
+    match statement {
+        Statement::CreateTable {
+            columns,
+            constraints,
+            ..
+        } => {
+            for option in columns.iter_mut().flat_map(|c| c.options.iter_mut()) {
+                if let ColumnOption::ForeignKey { foreign_table, .. } = &mut option.option {
+                    qualify_name(foreign_table, database);
+                }
+            }
+            for constraint in constraints {
+                if let TableConstraint::ForeignKey { foreign_table, .. } = constraint {
+                    qualify_name(foreign_table, database);
+                }
+            }
+        }
+        Statement::CreateIndex {
+            name: Some(name), ..
+        }
+        | Statement::CreateView { name, .. } => qualify_name(name, database),
+        Statement::Drop {
+            object_type: ObjectType::Table | ObjectType::Index | ObjectType::View,
+            names,
+            ..
+        } => names
+            .iter_mut()
+            .for_each(|name| qualify_name(name, database)),
+        Statement::ShowTables { db_name, .. } if db_name.is_none() && !is_default(database) => {
+            *db_name = Some(Ident::new(database));
+        }
+        // `SHOW INDEXES FROM t`, `SHOW STATS FROM t`, `REINDEX`, `VACUUM`
+        // and `REFRESH` keep the name they act on as the words after the
+        // second
+        Statement::ShowVariable { variable }
+            if variable.len() > 2 && names_object(&variable[0]) =>
+        {
+            let mut name = ObjectName(variable.split_off(2));
+            qualify_name(&mut name, database);
+            variable.extend(name.0);
+        }
+        _ => {}
+    }
+}
+
+fn names_object(word: &Ident) -> bool {
+    [
+        "INDEX",
+        "INDEXES",
+        "KEYS",
+        "STATS",
+        "STATISTICS",
+        "REINDEX",
+        "VACUUM",
+        "REFRESH",
+    ]
+    .iter()
+    .any(|keyword| word.value.eq_ignore_ascii_case(keyword))
+}
+
+/// Prefix a bare name with `database`, and strip the default database off
+/// a qualified one
+fn qualify_name(name: &mut ObjectName, database: &str) {
+    match name.0.len() {
+        1 if !is_default(database) => name.0.insert(0, Ident::new(database)),
+        2 if is_default(&name.0[0].value) => {
+            name.0.remove(0);
+        }
+        _ => {}
+    }
+}
+
+/// Finds the relations of a statement that name a CTE rather than a table.
+/// A CTE is in scope in the body of the query defining it, in the CTEs
+/// after it and in any query nested in those; its own query and the CTEs
+/// before it read the table of that name instead. sqlparser's visitors
+/// have no hook for queries, so each query is scoped where it can appear
+/// (as a statement, in FROM or as a subquery expression) before the walk
+/// reaches its relations. Relations are told apart by address, which
+/// qualifying a statement leaves unchanged.
+#[derive(Default)]
+struct CteReferences {
+    /// CTEs visible from the query being walked
+    scope: BTreeSet<String>,
+    /// Whether each relation met names a CTE, decided by the innermost
+    /// query holding it
+    relations: HashMap<*const ObjectName, bool>,
+    /// Queries already scoped
+    queries: HashSet<*const Query>,
+}
+
+impl CteReferences {
+    /// The relations of `statement` that name a CTE
+    fn of(statement: &Statement) -> HashSet<*const ObjectName> {
+        let mut references = CteReferences::default();
+        let _ = Visit::visit(statement, &mut references);
+        references
+            .relations
+            .into_iter()
+            .filter_map(|(relation, cte)| cte.then_some(relation))
+            .collect()
+    }
+
+    fn add(&mut self, query: &Query) {
+        if !self.queries.insert(query) {
+            return;
+        }
+        let outer = self.scope.clone();
+        for cte in query.with.iter().flat_map(|with| &with.cte_tables) {
+            self.add(&cte.query);
+            self.scope.insert(cte.alias.name.value.clone());
+        }
+        self.add_body(&query.body);
+        let _ = Visit::visit(query, self);
+        self.scope = outer;
+    }
+
+    /// Scope the parenthesized queries of a set operation, which the walk
+    /// has no hook for either
+    fn add_body(&mut self, body: &SetExpr) {
+        match body {
+            SetExpr::Query(query) => self.add(query),
+            SetExpr::SetOperation { left, right, .. } => {
+                self.add_body(left);
+                self.add_body(right);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Visitor for CteReferences {
+    type Break = ();
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        let cte = relation.0.len() == 1 && self.scope.contains(&relation.0[0].value);
+        self.relations.entry(relation).or_insert(cte);
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        match statement {
+            Statement::Query(query)
+            | Statement::CreateView { query, .. }
+            | Statement::Insert { source: query, .. } => self.add(query),
+            Statement::CreateTable {
+                query: Some(query), ..
+            } => self.add(query),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, factor: &TableFactor) -> ControlFlow<()> {
+        if let TableFactor::Derived { subquery, .. } = factor {
+            self.add(subquery);
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Subquery(query)
+            | Expr::Exists {
+                subquery: query, ..
+            }
+            | Expr::InSubquery {
+                subquery: query, ..
+            } => self.add(query),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+struct Qualifier<'a> {
+    database: &'a str,
+    /// Relations naming one of the statement's CTEs, which are not tables
+    /// and stay bare
+    ctes: HashSet<*const ObjectName>,
+}
+
+impl Qualifier<'_> {
+    fn is_cte(&self, name: &ObjectName) -> bool {
+        self.ctes.contains(&(name as *const ObjectName))
+    }
+}
+
+impl VisitorMut for Qualifier<'_> {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, factor: &mut TableFactor) -> ControlFlow<()> {
+        // Alias a table that is about to be qualified by its bare name, so
+        // columns written as `t.column` still resolve
+        if let TableFactor::Table { name, alias, .. } = factor {
+            if alias.is_none()
+                && name.0.len() == 1
+                && !is_default(self.database)
+                && !self.is_cte(name)
+            {
+                *alias = Some(TableAlias {
+                    name: name.0[0].clone(),
+                    columns: vec![],
+                });
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_relation(&mut self, relation: &mut ObjectName) -> ControlFlow<()> {
+        if !self.is_cte(relation) {
+            qualify_name(relation, self.database);
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryEngine;
+    use crate::security::SecurityContext;
+    use crate::session::Session;
+    use crate::types::Value;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+    use std::sync::Arc;
+
+    /// `sql` as it runs in `database`
+    fn qualified(sql: &str, database: &str) -> String {
+        let mut statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        qualify(&mut statement, database);
+        statement.to_string()
+    }
+
+    #[test]
+    fn cte_names_stay_bare() {
+        assert_eq!(
+            qualified(
+                "WITH recent AS (SELECT id FROM orders) SELECT id FROM recent",
+                "sales"
+            ),
+            "WITH recent AS (SELECT id FROM sales.orders AS orders) SELECT id FROM recent"
+        );
+        // Later CTEs read earlier ones
+        assert_eq!(
+            qualified(
+                "WITH a AS (SELECT id FROM t), b AS (SELECT id FROM a) SELECT id FROM b JOIN u ON b.id = u.id",
+                "sales"
+            ),
+            "WITH a AS (SELECT id FROM sales.t AS t), b AS (SELECT id FROM a) SELECT id FROM b JOIN sales.u AS u ON b.id = u.id"
+        );
+        // CTEs inside subqueries are found too
+        assert_eq!(
+            qualified(
+                "SELECT id FROM t WHERE id IN (WITH x AS (SELECT id FROM u) SELECT id FROM x)",
+                "sales"
+            ),
+            "SELECT id FROM sales.t AS t WHERE id IN (WITH x AS (SELECT id FROM sales.u AS u) SELECT id FROM x)"
+        );
+    }
+
+    #[test]
+    fn ctes_are_only_visible_in_their_scope() {
+        // A CTE's own query, and the CTEs before it, read the table
+        assert_eq!(
+            qualified("WITH t AS (SELECT id FROM t) SELECT id FROM t", "sales"),
+            "WITH t AS (SELECT id FROM sales.t AS t) SELECT id FROM t"
+        );
+        assert_eq!(
+            qualified(
+                "WITH a AS (SELECT id FROM b), b AS (SELECT id FROM a) SELECT id FROM b",
+                "sales"
+            ),
+            "WITH a AS (SELECT id FROM sales.b AS b), b AS (SELECT id FROM a) SELECT id FROM b"
+        );
+        // A CTE of a subquery is not visible outside it
+        assert_eq!(
+            qualified(
+                "SELECT id FROM x WHERE id IN (WITH x AS (SELECT id FROM u) SELECT id FROM x)",
+                "sales"
+            ),
+            "SELECT id FROM sales.x AS x WHERE id IN (WITH x AS (SELECT id FROM sales.u AS u) SELECT id FROM x)"
+        );
+        assert_eq!(
+            qualified(
+                "SELECT id FROM x UNION (WITH x AS (SELECT id FROM u) SELECT id FROM x)",
+                "sales"
+            ),
+            "SELECT id FROM sales.x AS x UNION (WITH x AS (SELECT id FROM sales.u AS u) SELECT id FROM x)"
+        );
+        // Nested queries see the CTEs of the queries around them
+        assert_eq!(
+            qualified(
+                "WITH x AS (SELECT id FROM u) SELECT id FROM (SELECT id FROM x) AS d",
+                "sales"
+            ),
+            "WITH x AS (SELECT id FROM sales.u AS u) SELECT id FROM (SELECT id FROM x) AS d"
+        );
+    }
+
+    #[test]
+    fn default_database_names_stay_plain() {
+        assert_eq!(
+            qualified(
+                "SELECT id FROM public.t JOIN u ON t.id = u.id",
+                DEFAULT_DATABASE
+            ),
+            "SELECT id FROM t JOIN u ON t.id = u.id"
+        );
+        assert_eq!(
+            qualified("SELECT id FROM public.t", "sales"),
+            "SELECT id FROM t"
+        );
+    }
+
+    #[test]
+    fn ctes_run_in_another_database() {
+        let engine = Arc::new(QueryEngine::new());
+        let mut session = Session::new(Arc::clone(&engine), SecurityContext::admin("test"));
+        let mut run = |sql: &str| {
+            session
+                .execute_script(sql)
+                .unwrap_or_else(|e| panic!("{}: {}", sql, e))
+                .pop()
+                .unwrap()
+        };
+        run("CREATE DATABASE sales; USE sales");
+        run("CREATE TABLE orders (id INT, amount INT);
+             INSERT INTO orders VALUES (1, 10), (2, 50), (3, 70)");
+
+        let result = run(
+            "WITH big AS (SELECT id, amount FROM orders WHERE amount > 20),
+                  bigger AS (SELECT id FROM big WHERE amount > 60)
+             SELECT id FROM bigger",
+        );
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0]["id"], Value::Int32(3));
+
+        // A CTE may share its name with a table of the database
+        let result =
+            run("WITH orders AS (SELECT id FROM orders WHERE id = 1) SELECT id FROM orders");
+        assert_eq!(result.rows.len(), 1);
+    }
+
+    #[test]
+    fn definitions_and_references_are_qualified() {
+        assert_eq!(
+            qualified(
+                "CREATE TABLE o (id INT, c INT REFERENCES customers(id))",
+                "sales"
+            ),
+            "CREATE TABLE sales.o (id INT, c INT REFERENCES sales.customers (id))"
+        );
+        assert_eq!(
+            qualified("CREATE INDEX o_c ON o (c)", "sales"),
+            "CREATE INDEX sales.o_c ON sales.o(c)"
+        );
+        assert_eq!(
+            qualified("DROP TABLE o, public.p", "sales"),
+            "DROP TABLE sales.o, p"
+        );
+        assert_eq!(qualified("SHOW TABLES", "sales"), "SHOW TABLES FROM sales");
+        assert_eq!(
+            qualified("SHOW INDEXES FROM o", "sales"),
+            "SHOW INDEXES FROM sales o"
+        );
+        assert_eq!(
+            qualified("SELECT id FROM other.t", "sales"),
+            "SELECT id FROM other.t"
+        );
+        assert_eq!(split("sales.o"), ("sales", "o"));
+        assert_eq!(split("o"), (DEFAULT_DATABASE, "o"));
+    }
+}
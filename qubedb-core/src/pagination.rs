@@ -0,0 +1,543 @@
+//! Keyset pagination for QubeDB
+//!
+//! `QueryEngine::execute_page` runs a single-table SELECT one page at a
+//! time. Each page but the last comes with an opaque cursor holding the
+//! sort key of its last row; passing the cursor back continues with the
+//! rows after that key. The query is rewritten to `WHERE <sort key> after
+//! <cursor key>` with a `LIMIT`, so when a B-Tree index covers the ORDER BY
+//! columns each page is read straight from the index and a deep page costs
+//! no more than the first, where OFFSET would read and discard every row
+//! before it.
+//!
+//! The sort key is made unique by appending the primary key columns the
+//! ORDER BY lacks, so no row is skipped or repeated between pages. Without
+//! an ORDER BY, rows are paged in primary key order.
+
+use crate::aggregate;
+use crate::catalog::Catalog;
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::planner::column_name;
+use crate::query::object_name;
+use crate::types::{ConstraintType, QueryResult, Table, Value};
+use crate::window;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::{
+    BinaryOperator, Expr, Ident, OrderByExpr, Query, SelectItem, SetExpr, TableFactor,
+    Value as SqlValue,
+};
+
+/// One page of a paginated query
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub result: QueryResult,
+    /// Cursor for the next page, or `None` if this is the last one
+    pub next_cursor: Option<String>,
+}
+
+/// What a cursor holds: the sort key columns and the values they had in the
+/// last row of a page
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    columns: Vec<String>,
+    values: Vec<Value>,
+}
+
+impl Cursor {
+    fn encode(&self) -> QubeResult<String> {
+        let json = serde_json::to_vec(self).map_err(|e| QubeError::Serialization(e.to_string()))?;
+        Ok(json.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    fn decode(token: &str) -> QubeResult<Self> {
+        let invalid = || QubeError::QueryParse("Invalid pagination cursor".to_string());
+        if !token.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| {
+                token
+                    .get(i..i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
+    }
+}
+
+/// One term of the sort key
+struct KeyColumn {
+    column: String,
+    /// Result column the value is read back from
+    output: String,
+    ascending: bool,
+    nulls_first: bool,
+    nullable: bool,
+}
+
+impl KeyColumn {
+    fn identifier(&self) -> Expr {
+        Expr::Identifier(Ident::new(self.column.clone()))
+    }
+
+    /// Rows whose value for this column equals `value`
+    fn equal(&self, value: &Value) -> Expr {
+        if value.is_null() {
+            return Expr::IsNull(Box::new(self.identifier()));
+        }
+        compare(self.identifier(), BinaryOperator::Eq, value)
+    }
+
+    /// Rows whose value for this column sorts after `value`, or `None` if
+    /// none can
+    fn after(&self, value: &Value) -> Option<Expr> {
+        if value.is_null() {
+            // NULLs sort together at one end
+            return self
+                .nulls_first
+                .then(|| Expr::IsNotNull(Box::new(self.identifier())));
+        }
+        let op = if self.ascending {
+            BinaryOperator::Gt
+        } else {
+            BinaryOperator::Lt
+        };
+        let after = compare(self.identifier(), op, value);
+        Some(if self.nullable && !self.nulls_first {
+            or(after, Expr::IsNull(Box::new(self.identifier())))
+        } else {
+            after
+        })
+    }
+
+    /// Rows whose value for this column is `value` or sorts after it, as a
+    /// single range an index can serve, or `None` if NULLs sort after
+    /// `value` and a range would lose them
+    fn from(&self, value: &Value) -> Option<Expr> {
+        if value.is_null() || (self.nullable && !self.nulls_first) {
+            return None;
+        }
+        let op = if self.ascending {
+            BinaryOperator::GtEq
+        } else {
+            BinaryOperator::LtEq
+        };
+        Some(compare(self.identifier(), op, value))
+    }
+}
+
+/// A query rewritten to fetch one page, and how to read the next cursor
+/// from its result
+pub(crate) struct Keyset {
+    key: Vec<KeyColumn>,
+    /// Result columns added only to read the sort key back
+    hidden: Vec<String>,
+    page_size: usize,
+}
+
+impl Keyset {
+    /// Rewrite `query` to fetch the `page_size` rows after `cursor`, or the
+    /// first page when there is no cursor
+    pub(crate) fn apply(
+        query: &mut Query,
+        catalog: &Catalog,
+        page_size: usize,
+        cursor: Option<&str>,
+    ) -> QubeResult<Self> {
+        if page_size == 0 {
+            return Err(QubeError::QueryParse(
+                "Page size must be at least 1".to_string(),
+            ));
+        }
+        if query.limit.is_some() || query.offset.is_some() || query.fetch.is_some() {
+            return Err(QubeError::QueryParse(
+                "A paginated query cannot have LIMIT, OFFSET or FETCH; set the page size instead"
+                    .to_string(),
+            ));
+        }
+        let select = match &mut *query.body {
+            SetExpr::Select(select) if query.with.is_none() => select,
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "Only a plain SELECT can be paginated".to_string(),
+                ))
+            }
+        };
+        let table = match select.from.as_slice() {
+            [from] if from.joins.is_empty() => match &from.relation {
+                TableFactor::Table { name, .. } => catalog.get_table(&object_name(name))?,
+                _ => return Err(single_table()),
+            },
+            _ => return Err(single_table()),
+        };
+        if aggregate::has_aggregates(&select.projection) {
+            return Err(QubeError::QueryParse(
+                "Aggregate queries cannot be paginated".to_string(),
+            ));
+        }
+        if window::has_window_functions(&select.projection) {
+            return Err(QubeError::QueryParse(
+                "Queries with window functions cannot be paginated".to_string(),
+            ));
+        }
+
+        complete_order_by(&mut query.order_by, table)?;
+        let mut key = Vec::new();
+        let mut hidden = Vec::new();
+        for order in &query.order_by {
+            let column = column_name(&order.expr).unwrap_or_default().to_string();
+            let definition = table
+                .columns
+                .iter()
+                .find(|c| c.name == column)
+                .ok_or_else(|| QubeError::ColumnNotFound(format!("{}.{}", table.name, column)))?;
+            let output = match output_name(&select.projection, &column) {
+                Some(output) => output,
+                None => {
+                    let output = format!("__cursor_{}", column);
+                    select.projection.push(SelectItem::ExprWithAlias {
+                        expr: Expr::Identifier(Ident::new(column.clone())),
+                        alias: Ident::new(output.clone()),
+                    });
+                    hidden.push(output.clone());
+                    output
+                }
+            };
+            let ascending = order.asc.unwrap_or(true);
+            key.push(KeyColumn {
+                column,
+                output,
+                ascending,
+                nulls_first: order.nulls_first.unwrap_or(!ascending),
+                nullable: definition.nullable,
+            });
+        }
+        let keyset = Keyset {
+            key,
+            hidden,
+            page_size,
+        };
+
+        if let Some(cursor) = cursor {
+            // Ahead of the query's own filters, so an index range starts
+            // from the cursor rather than from a looser bound of theirs
+            let predicate = keyset.after(&Cursor::decode(cursor)?)?;
+            select.selection = Some(match select.selection.take() {
+                Some(selection) => and(predicate, selection),
+                None => predicate,
+            });
+        }
+        // One extra row tells whether there is another page
+        query.limit = Some(Expr::Value(SqlValue::Number(
+            (page_size + 1).to_string(),
+            false,
+        )));
+        Ok(keyset)
+    }
+
+    /// Turn the rewritten query's result into a page
+    pub(crate) fn page(&self, mut result: QueryResult) -> QubeResult<Page> {
+        let more = result.rows.len() > self.page_size;
+        result.rows.truncate(self.page_size);
+        let next_cursor = match result.rows.last() {
+            Some(last) if more => Some(
+                Cursor {
+                    columns: self.key.iter().map(|k| k.column.clone()).collect(),
+                    values: self
+                        .key
+                        .iter()
+                        .map(|k| last.get(&k.output).cloned().unwrap_or(Value::Null))
+                        .collect(),
+                }
+                .encode()?,
+            ),
+            _ => None,
+        };
+        if !self.hidden.is_empty() {
+            for row in &mut result.rows {
+                for column in &self.hidden {
+                    row.remove(column);
+                }
+            }
+            result.columns.retain(|c| !self.hidden.contains(c));
+        }
+        Ok(Page {
+            result,
+            next_cursor,
+        })
+    }
+
+    /// Rows whose sort key comes after the cursor's: for some term, every
+    /// earlier term equals the cursor and this one sorts after it. The
+    /// first term is also bounded on its own so an index range can find
+    /// where to start.
+    fn after(&self, cursor: &Cursor) -> QubeResult<Expr> {
+        let columns: Vec<&str> = self.key.iter().map(|k| k.column.as_str()).collect();
+        if cursor.columns != columns || cursor.values.len() != columns.len() {
+            return Err(QubeError::QueryParse(
+                "Pagination cursor does not belong to this query".to_string(),
+            ));
+        }
+
+        let mut disjuncts = Vec::new();
+        for (i, (term, value)) in self.key.iter().zip(&cursor.values).enumerate() {
+            if let Some(after) = term.after(value) {
+                let disjunct = self.key[..i]
+                    .iter()
+                    .zip(&cursor.values)
+                    .rfold(after, |rest, (earlier, value)| {
+                        and(earlier.equal(value), rest)
+                    });
+                disjuncts.push(disjunct);
+            }
+        }
+        let predicate = disjuncts.into_iter().reduce(or).unwrap_or_else(falsy);
+        Ok(match self.key[0].from(&cursor.values[0]) {
+            Some(from) if self.key.len() > 1 => and(from, predicate),
+            _ => predicate,
+        })
+    }
+}
+
+/// Make the ORDER BY a unique sort key of plain columns by appending the
+/// primary key columns it lacks, unless it already covers a unique index
+fn complete_order_by(order_by: &mut Vec<OrderByExpr>, table: &Table) -> QubeResult<()> {
+    let mut columns = Vec::new();
+    for order in order_by.iter() {
+        match column_name(&order.expr) {
+            Some(column) => columns.push(column.to_string()),
+            None => {
+                return Err(QubeError::QueryParse(format!(
+                    "A paginated query can only ORDER BY columns, not {}",
+                    order.expr
+                )))
+            }
+        }
+    }
+    let unique = table
+        .indexes
+        .iter()
+        .any(|index| index.unique && index.columns.iter().all(|c| columns.contains(c)));
+    if unique {
+        return Ok(());
+    }
+
+    let primary_key = table
+        .constraints
+        .iter()
+        .find(|c| matches!(c.constraint_type, ConstraintType::PrimaryKey))
+        .ok_or_else(|| {
+            QubeError::QueryParse(format!(
+                "Table '{}' has no primary key, so a paginated query must ORDER BY a unique key",
+                table.name
+            ))
+        })?;
+    // Ties continue in the direction of the last term
+    let asc = order_by.last().and_then(|order| order.asc);
+    for column in &primary_key.columns {
+        if !columns.contains(column) {
+            order_by.push(OrderByExpr {
+                expr: Expr::Identifier(Ident::new(column.clone())),
+                asc,
+                nulls_first: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Name of the result column holding `column` unchanged, if the select
+/// list has one
+fn output_name(projection: &[SelectItem], column: &str) -> Option<String> {
+    projection.iter().find_map(|item| match item {
+        SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => Some(column.to_string()),
+        SelectItem::UnnamedExpr(expr) if column_name(expr) == Some(column) => {
+            Some(column.to_string())
+        }
+        SelectItem::ExprWithAlias { expr, alias } if column_name(expr) == Some(column) => {
+            Some(alias.value.clone())
+        }
+        _ => None,
+    })
+}
+
+fn single_table() -> QubeError {
+    QubeError::QueryParse("Only single-table queries can be paginated".to_string())
+}
+
+fn compare(column: Expr, op: BinaryOperator, value: &Value) -> Expr {
+    Expr::BinaryOp {
+        left: Box::new(column),
+        op,
+        right: Box::new(Expr::Value(expr::to_literal(value))),
+    }
+}
+
+fn and(left: Expr, right: Expr) -> Expr {
+    Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOperator::And,
+        right: Box::new(right),
+    }
+}
+
+fn or(left: Expr, right: Expr) -> Expr {
+    Expr::Nested(Box::new(Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOperator::Or,
+        right: Box::new(right),
+    }))
+}
+
+fn falsy() -> Expr {
+    Expr::Value(SqlValue::Boolean(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::QubeError;
+    use crate::query::QueryEngine;
+    use crate::types::Value;
+
+    /// Scores with ties and NULLs, so only the primary key breaks ties
+    fn engine() -> QueryEngine {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (1..=20)
+            .map(|id| match id % 4 {
+                0 => format!("({}, NULL)", id),
+                n => format!("({}, {})", id, n * 10),
+            })
+            .collect();
+        engine
+            .execute_script(&format!(
+                "CREATE TABLE scores (id INT PRIMARY KEY, score INT);
+                 INSERT INTO scores VALUES {}",
+                values.join(", ")
+            ))
+            .unwrap();
+        engine
+    }
+
+    fn ids(rows: &[crate::types::Row]) -> Vec<Value> {
+        rows.iter().map(|row| row["id"].clone()).collect()
+    }
+
+    /// Every page of `sql`, concatenated
+    fn all_pages(engine: &QueryEngine, sql: &str, page_size: usize) -> (Vec<Value>, usize) {
+        let mut rows = Vec::new();
+        let mut pages = 0;
+        let mut cursor = None;
+        loop {
+            let page = engine
+                .execute_page(sql, page_size, cursor.as_deref())
+                .unwrap();
+            assert!(page.result.rows.len() <= page_size);
+            rows.extend(ids(&page.result.rows));
+            pages += 1;
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return (rows, pages),
+            }
+        }
+    }
+
+    #[test]
+    fn pages_neither_repeat_nor_skip_rows() {
+        let engine = engine();
+        // Each order, and the same order with the primary key breaking ties
+        // as the cursor does
+        for (order, tie_broken) in [
+            ("", "ORDER BY id"),
+            ("ORDER BY score", "ORDER BY score, id"),
+            ("ORDER BY score DESC", "ORDER BY score DESC, id DESC"),
+            (
+                "ORDER BY score NULLS FIRST",
+                "ORDER BY score NULLS FIRST, id",
+            ),
+            ("ORDER BY score DESC, id ASC", "ORDER BY score DESC, id ASC"),
+        ] {
+            let sql = format!("SELECT id FROM scores WHERE id > 2 {}", order);
+            let expected = engine
+                .execute_script(&format!(
+                    "SELECT id FROM scores WHERE id > 2 {}",
+                    tie_broken
+                ))
+                .unwrap();
+            let expected = ids(&expected[0].rows);
+            assert_eq!(expected.len(), 18);
+
+            for page_size in [1, 3, 7, 18, 50] {
+                let (rows, pages) = all_pages(&engine, &sql, page_size);
+                assert_eq!(rows, expected, "{} in pages of {}", order, page_size);
+                assert_eq!(pages, 18usize.div_ceil(page_size));
+            }
+        }
+    }
+
+    #[test]
+    fn sort_key_columns_added_for_the_cursor_are_not_returned() {
+        let engine = engine();
+        let page = engine
+            .execute_page("SELECT score FROM scores ORDER BY score", 4, None)
+            .unwrap();
+        assert_eq!(page.result.columns, ["score"]);
+        assert!(page.result.rows.iter().all(|row| row.len() == 1));
+        assert_eq!(page.result.rows[0]["score"], Value::Int32(10));
+        assert!(page.next_cursor.is_some());
+    }
+
+    #[test]
+    fn changes_between_pages_only_show_up_ahead_of_the_cursor() {
+        let engine = engine();
+        let sql = "SELECT id FROM scores ORDER BY id";
+        let first = engine.execute_page(sql, 5, None).unwrap();
+        assert_eq!(ids(&first.result.rows), [1, 2, 3, 4, 5].map(Value::Int32));
+        let cursor = first.next_cursor.unwrap();
+
+        engine
+            .execute_script(
+                "DELETE FROM scores WHERE id = 2;
+                 DELETE FROM scores WHERE id = 6;
+                 INSERT INTO scores VALUES (0, 0)",
+            )
+            .unwrap();
+        let second = engine.execute_page(sql, 5, Some(&cursor)).unwrap();
+        assert_eq!(
+            ids(&second.result.rows),
+            [7, 8, 9, 10, 11].map(Value::Int32)
+        );
+    }
+
+    #[test]
+    fn queries_that_cannot_be_paged_and_foreign_cursors_are_refused() {
+        let engine = engine();
+        for (sql, page_size) in [
+            ("SELECT id FROM scores", 0),
+            ("SELECT id FROM scores LIMIT 5", 2),
+            ("SELECT COUNT(*) FROM scores", 2),
+            ("SELECT id FROM scores ORDER BY score + 1", 2),
+            ("SELECT a.id FROM scores a JOIN scores b ON a.id = b.id", 2),
+            ("DELETE FROM scores", 2),
+        ] {
+            let result = engine.execute_page(sql, page_size, None);
+            assert!(
+                matches!(result, Err(QubeError::QueryParse(_))),
+                "{} gave {:?}",
+                sql,
+                result
+            );
+        }
+
+        let cursor = engine
+            .execute_page("SELECT id FROM scores ORDER BY score", 2, None)
+            .unwrap()
+            .next_cursor
+            .unwrap();
+        for cursor in [cursor.as_str(), "zz", "abc"] {
+            let result = engine.execute_page("SELECT id FROM scores", 2, Some(cursor));
+            assert!(matches!(result, Err(QubeError::QueryParse(_))));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Parallel query execution for QubeDB
+//!
+//! Scans, aggregates and sorts over at least `min_rows` rows split their
+//! input into one contiguous partition per worker thread, process the
+//! partitions at the same time and combine the partial results in
+//! partition order, so rows come out exactly as they would on one thread.
+//! Only floating-point sums may differ, in the last bits, since they are
+//! added up in a different order. Smaller inputs stay on the calling
+//! thread, where starting workers would cost more than it saves.
+
+use crate::error::QubeResult;
+
+/// Fewest rows an operator needs before it is split across threads
+pub const DEFAULT_PARALLEL_THRESHOLD: usize = 10_000;
+
+/// How many threads a query may use, and from what size on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelConfig {
+    /// Worker threads per operator; 1 runs every query serially
+    pub threads: usize,
+    /// Fewest rows an operator needs before it is split
+    pub min_rows: usize,
+}
+
+impl Default for ParallelConfig {
+    /// One thread per core
+    fn default() -> Self {
+        ParallelConfig {
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            min_rows: DEFAULT_PARALLEL_THRESHOLD,
+        }
+    }
+}
+
+impl ParallelConfig {
+    /// Run every query on the calling thread
+    pub fn serial() -> Self {
+        ParallelConfig {
+            threads: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Number of partitions `rows` rows are split into, 1 to run serially
+    pub fn partitions(&self, rows: usize) -> usize {
+        if self.threads <= 1 || rows < self.min_rows.max(2) {
+            1
+        } else {
+            self.threads.min(rows)
+        }
+    }
+}
+
+/// Apply `f` to contiguous partitions of `items`, each on its own thread
+/// when there are enough items, returning the results in partition order.
+/// The first error is returned; a panic in `f` is passed on.
+pub(crate) fn map_partitions<T, R, F>(
+    mut items: Vec<T>,
+    config: &ParallelConfig,
+    f: F,
+) -> QubeResult<Vec<R>>
+where
+    T: Send,
+    R: Send,
+    F: Fn(Vec<T>) -> QubeResult<R> + Sync,
+{
+    let partitions = config.partitions(items.len());
+    if partitions <= 1 {
+        return Ok(vec![f(items)?]);
+    }
+    let size = items.len().div_ceil(partitions);
+    let mut chunks = Vec::with_capacity(partitions);
+    while items.len() > size {
+        chunks.push(items.split_off(items.len() - size));
+    }
+    chunks.push(items);
+    chunks.reverse();
+
+    let f = &f;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || f(chunk)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QubeError;
+
+    fn config(threads: usize, min_rows: usize) -> ParallelConfig {
+        ParallelConfig { threads, min_rows }
+    }
+
+    #[test]
+    fn inputs_are_split_only_from_the_threshold_on() {
+        assert_eq!(config(4, 100).partitions(99), 1);
+        assert_eq!(config(4, 100).partitions(100), 4);
+        assert_eq!(config(8, 0).partitions(3), 3);
+        assert_eq!(config(8, 0).partitions(1), 1);
+        assert_eq!(config(1, 0).partitions(1_000), 1);
+        assert_eq!(ParallelConfig::serial().partitions(usize::MAX), 1);
+    }
+
+    #[test]
+    fn partitions_come_back_in_order() {
+        let items: Vec<u32> = (0..10).collect();
+        let parts = map_partitions(items.clone(), &config(3, 2), Ok).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| !part.is_empty()));
+        assert_eq!(parts.concat(), items);
+
+        let serial = map_partitions(items.clone(), &config(3, 20), |part| Ok(part.len())).unwrap();
+        assert_eq!(serial, [10]);
+    }
+
+    #[test]
+    fn an_error_in_any_partition_is_returned() {
+        let result = map_partitions((0..10).collect(), &config(4, 2), |part: Vec<u32>| {
+            if part.contains(&7) {
+                Err(QubeError::Other("seven".to_string()))
+            } else {
+                Ok(part.len())
+            }
+        });
+        assert!(matches!(result, Err(QubeError::Other(m)) if m == "seven"));
+    }
+}
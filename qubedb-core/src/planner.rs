@@ -0,0 +1,937 @@
+//! Query planner for QubeDB
+//!
+//! Decides the order in which the relations of a SELECT are joined and where
+//! each WHERE/ON predicate is applied. Join order is chosen greedily
+//! (left-deep): start from the smallest relation after filtering, then keep
+//! adding the connected relation that yields the smallest intermediate result.
+//! Sizes come from table statistics when they have been collected.
+
+use crate::catalog::TableData;
+use crate::error::{QubeError, QubeResult};
+use crate::stats::TableStats;
+use crate::types::{Index, IndexType, StorageLayout, Table};
+use sqlparser::ast::{visit_expressions, BinaryOperator, Expr, OrderByExpr};
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+
+/// Selectivity assumed for predicates we cannot estimate
+const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+
+/// Selectivity assumed for `column = constant` without statistics
+const DEFAULT_EQUALITY_SELECTIVITY: f64 = 0.1;
+
+/// A table referenced in the FROM clause
+pub struct Relation<'a> {
+    pub table: &'a Table,
+    /// Name the relation is referred to by: its alias, or the table name
+    pub qualifier: String,
+    pub data: &'a TableData,
+}
+
+impl<'a> Relation<'a> {
+    pub fn new(table: &'a Table, qualifier: String, data: &'a TableData) -> Self {
+        Relation {
+            table,
+            qualifier,
+            data,
+        }
+    }
+
+    /// Collected statistics, if the table has been analyzed
+    pub fn stats(&self) -> Option<&'a TableStats> {
+        self.data.stats.as_ref()
+    }
+
+    /// Row count from statistics, falling back to the live row count
+    pub fn row_count(&self) -> usize {
+        self.stats()
+            .map(|stats| stats.row_count)
+            .unwrap_or_else(|| self.data.len())
+    }
+
+    /// Human readable name used by EXPLAIN
+    pub fn display_name(&self) -> String {
+        if self.qualifier == self.table.name {
+            self.table.name.clone()
+        } else {
+            format!("{} AS {}", self.table.name, self.qualifier)
+        }
+    }
+
+    fn has_column(&self, column: &str) -> bool {
+        self.table.columns.iter().any(|c| c.name == column)
+    }
+
+    fn distinct_count(&self, column: &str) -> Option<usize> {
+        self.stats()?
+            .columns
+            .get(column)
+            .map(|stats| stats.distinct_count)
+    }
+}
+
+/// One relation joined into the running result
+#[derive(Debug, Clone)]
+pub struct JoinStep {
+    /// Index of the relation in FROM order
+    pub relation: usize,
+    /// Predicates that only reference this relation, applied while scanning it
+    pub filters: Vec<Expr>,
+    /// Equi-join keys as (expression over earlier relations, expression over this one)
+    pub hash_keys: Vec<(Expr, Expr)>,
+    /// Remaining predicates that become evaluable once this relation is joined
+    pub conditions: Vec<Expr>,
+    /// Estimated number of rows after this step
+    pub estimated_rows: f64,
+    /// B-Tree index that narrows the scan of this relation, if one fits
+    /// its filters
+    pub index: Option<IndexScan>,
+}
+
+/// A lookup into a B-Tree index that finds the rows a scan's filters can
+/// match. The filters are still applied to every row it finds.
+#[derive(Debug, Clone)]
+pub struct IndexScan {
+    pub index: String,
+    /// Values the leading index columns must equal, in index column order
+    pub prefix: Vec<Expr>,
+    /// Index column after the prefix that `lower` and `upper` bound
+    pub range_column: Option<String>,
+    pub lower: Option<RangeBound>,
+    pub upper: Option<RangeBound>,
+    /// The filters the lookup was derived from, for EXPLAIN
+    pub conditions: Vec<Expr>,
+    /// Whether the index holds every column the scan reads, so rows can be
+    /// answered from index entries without reading the table
+    pub covering: bool,
+    /// Walk the index in key order, so rows come out sorted for ORDER BY.
+    /// Otherwise they come out in row id order.
+    pub order: Option<IndexOrder>,
+}
+
+/// Direction an ordered index scan walks its index in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexOrder {
+    Ascending,
+    Descending,
+}
+
+/// One end of a range on an index column
+#[derive(Debug, Clone)]
+pub struct RangeBound {
+    pub value: Expr,
+    pub inclusive: bool,
+}
+
+/// Join order and predicate placement for a SELECT
+#[derive(Debug, Clone)]
+pub struct QueryPlan {
+    pub steps: Vec<JoinStep>,
+    /// Predicates that reference no relation, applied to the final result
+    pub residual: Vec<Expr>,
+}
+
+impl QueryPlan {
+    /// Mark the index lookups whose index holds every column `columns`
+    /// says their scan reads
+    pub fn mark_covering(
+        &mut self,
+        relations: &[Relation],
+        columns: impl Fn(&JoinStep) -> BTreeSet<String>,
+    ) {
+        for step in &mut self.steps {
+            let needed = columns(step);
+            if let Some(scan) = &mut step.index {
+                scan.covering = relations[step.relation]
+                    .table
+                    .indexes
+                    .iter()
+                    .find(|index| index.name == scan.index)
+                    .is_some_and(|index| needed.iter().all(|c| index.columns.contains(c)));
+            }
+        }
+    }
+
+    /// Read a single table through a B-Tree index whose key order is the
+    /// ORDER BY order, so rows need no sort and a LIMIT can stop the scan
+    /// early. Keeps the index chosen for the filters, if there is one, and
+    /// only uses it if its order fits. Returns whether the rows now come
+    /// out sorted.
+    pub fn use_index_order(&mut self, relations: &[Relation], order_by: &[OrderByExpr]) -> bool {
+        if relations.len() != 1 || !self.residual.is_empty() {
+            return false;
+        }
+        let step = &mut self.steps[0];
+        match index_in_order(&relations[0], step.index.as_ref(), order_by) {
+            Some(scan) => {
+                step.index = Some(scan);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether rows come out of the plan already in ORDER BY order
+    pub fn is_ordered(&self) -> bool {
+        self.steps
+            .first()
+            .and_then(|step| step.index.as_ref())
+            .is_some_and(|scan| scan.order.is_some())
+    }
+
+    /// Render the plan as EXPLAIN output lines
+    pub fn explain(&self, relations: &[Relation]) -> Vec<String> {
+        self.explain_with(relations, |_| None)
+    }
+
+    /// Render the plan as EXPLAIN output lines, adding `annotate(i)` to the
+    /// line of step `i` and `annotate(steps.len())` to the residual filter,
+    /// e.g. what EXPLAIN ANALYZE measured
+    pub fn explain_with(
+        &self,
+        relations: &[Relation],
+        annotate: impl Fn(usize) -> Option<String>,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            let relation = &relations[step.relation];
+            let operator = if i == 0 && relation.table.storage == StorageLayout::Columnar {
+                "Columnar Scan"
+            } else if i == 0 {
+                "Scan"
+            } else if step.hash_keys.is_empty() {
+                "Nested Loop Join"
+            } else {
+                "Hash Join"
+            };
+            let mut line = format!(
+                "{} {} (estimated rows: {}",
+                operator,
+                relation.display_name(),
+                step.estimated_rows.round() as u64
+            );
+            if let Some(annotation) = annotate(i) {
+                line.push_str(&format!(", {}", annotation));
+            }
+            line.push(')');
+            lines.push(line);
+            if !step.hash_keys.is_empty() {
+                let keys: Vec<String> = step
+                    .hash_keys
+                    .iter()
+                    .map(|(outer, inner)| format!("{} = {}", outer, inner))
+                    .collect();
+                lines.push(format!("  Hash Cond: {}", keys.join(" AND ")));
+            }
+            if let Some(index) = &step.index {
+                let conditions: Vec<String> =
+                    index.conditions.iter().map(|c| c.to_string()).collect();
+                let mut line = format!(
+                    "  {}: {}",
+                    if index.covering {
+                        "Index-Only Scan"
+                    } else {
+                        "Index Scan"
+                    },
+                    index.index
+                );
+                if !conditions.is_empty() {
+                    line.push_str(&format!(" ({})", conditions.join(" AND ")));
+                }
+                match index.order {
+                    Some(IndexOrder::Ascending) => line.push_str(" in key order"),
+                    Some(IndexOrder::Descending) => line.push_str(" in descending key order"),
+                    None => {}
+                }
+                lines.push(line);
+            }
+            for filter in &step.filters {
+                lines.push(format!("  Filter: {}", filter));
+            }
+            for condition in &step.conditions {
+                lines.push(format!("  Join Filter: {}", condition));
+            }
+        }
+        for (i, predicate) in self.residual.iter().enumerate() {
+            let mut line = format!("Filter: {}", predicate);
+            if i == 0 {
+                if let Some(annotation) = annotate(self.steps.len()) {
+                    line.push_str(&format!(" ({})", annotation));
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Plan the joins of a SELECT over `relations` (in FROM order) constrained by
+/// the conjunctive `predicates` gathered from ON clauses and WHERE
+pub fn plan(relations: &[Relation], predicates: Vec<Expr>) -> QubeResult<QueryPlan> {
+    let predicates = predicates
+        .into_iter()
+        .flat_map(conjuncts)
+        .map(|predicate| {
+            let refs = references(&predicate, relations)?;
+            Ok((predicate, refs))
+        })
+        .collect::<QubeResult<Vec<_>>>()?;
+
+    // Size of each relation after its own filters
+    let filtered: Vec<f64> = relations
+        .iter()
+        .enumerate()
+        .map(|(i, relation)| {
+            predicates
+                .iter()
+                .filter(|(_, refs)| refs.len() == 1 && refs.contains(&i))
+                .fold(relation.row_count() as f64, |rows, (predicate, _)| {
+                    rows * selectivity(predicate, relation)
+                })
+        })
+        .collect();
+
+    let mut steps: Vec<JoinStep> = Vec::new();
+    let mut joined = BTreeSet::new();
+    let mut current_rows = 0.0;
+    while joined.len() < relations.len() {
+        let remaining: Vec<usize> = (0..relations.len())
+            .filter(|i| !joined.contains(i))
+            .collect();
+
+        // Prefer relations connected to what is already joined, to avoid cross products
+        let connected: Vec<usize> = remaining
+            .iter()
+            .copied()
+            .filter(|&r| {
+                predicates
+                    .iter()
+                    .any(|(_, refs)| refs.contains(&r) && refs.iter().any(|i| joined.contains(i)))
+            })
+            .collect();
+        let candidates = if connected.is_empty() {
+            &remaining
+        } else {
+            &connected
+        };
+
+        let (relation, estimated_rows) = candidates
+            .iter()
+            .map(|&r| {
+                let rows = if joined.is_empty() {
+                    filtered[r]
+                } else {
+                    estimate_join(
+                        relations,
+                        &predicates,
+                        &joined,
+                        r,
+                        current_rows,
+                        filtered[r],
+                    )
+                };
+                (r, rows)
+            })
+            .min_by(|(a, a_rows), (b, b_rows)| {
+                a_rows
+                    .total_cmp(b_rows)
+                    .then(filtered[*a].total_cmp(&filtered[*b]))
+                    .then(a.cmp(b))
+            })
+            .expect("at least one relation remains");
+
+        steps.push(JoinStep {
+            relation,
+            filters: Vec::new(),
+            hash_keys: Vec::new(),
+            conditions: Vec::new(),
+            estimated_rows,
+            index: None,
+        });
+        joined.insert(relation);
+        current_rows = estimated_rows;
+    }
+
+    // Place each predicate at the first step where everything it references is available
+    let mut residual = Vec::new();
+    for (predicate, refs) in predicates {
+        if refs.is_empty() {
+            residual.push(predicate);
+            continue;
+        }
+
+        let mut available = BTreeSet::new();
+        let position = steps
+            .iter()
+            .position(|step| {
+                available.insert(step.relation);
+                refs.is_subset(&available)
+            })
+            .expect("every referenced relation is planned");
+        let step = &mut steps[position];
+        if refs.len() == 1 {
+            step.filters.push(predicate);
+            continue;
+        }
+
+        available.remove(&step.relation);
+        match equi_join_sides(&predicate, relations, &available, step.relation)? {
+            Some(keys) => step.hash_keys.push(keys),
+            None => step.conditions.push(predicate),
+        }
+    }
+
+    for step in &mut steps {
+        step.index = choose_index(&relations[step.relation], &step.filters);
+    }
+
+    Ok(QueryPlan { steps, residual })
+}
+
+/// Pick the B-Tree index whose leftmost columns the filters pin down best:
+/// the longest prefix of equalities, then a range on the next column. An
+/// index is only usable when its first column is constrained.
+fn choose_index(relation: &Relation, filters: &[Expr]) -> Option<IndexScan> {
+    let comparisons: Vec<(&str, BinaryOperator, &Expr, &Expr)> = filters
+        .iter()
+        .flat_map(|filter| {
+            column_comparisons(filter)
+                .into_iter()
+                .map(move |(column, op, value)| (column, op, value, filter))
+        })
+        .collect();
+
+    let mut best: Option<(usize, bool, IndexScan)> = None;
+    for index in &relation.table.indexes {
+        if !matches!(index.index_type, IndexType::BTree) {
+            continue;
+        }
+        let mut scan = IndexScan {
+            index: index.name.clone(),
+            prefix: Vec::new(),
+            range_column: None,
+            lower: None,
+            upper: None,
+            conditions: Vec::new(),
+            covering: false,
+            order: None,
+        };
+        for column in &index.columns {
+            let on_column: Vec<_> = comparisons
+                .iter()
+                .filter(|(name, ..)| name == column)
+                .collect();
+            let equality = on_column
+                .iter()
+                .find(|(_, op, ..)| *op == BinaryOperator::Eq);
+            if let Some((_, _, value, filter)) = equality {
+                scan.prefix.push((*value).clone());
+                scan.conditions.push((*filter).clone());
+                continue;
+            }
+            for (_, op, value, filter) in on_column {
+                let (slot, inclusive) = match op {
+                    BinaryOperator::Gt => (&mut scan.lower, false),
+                    BinaryOperator::GtEq => (&mut scan.lower, true),
+                    BinaryOperator::Lt => (&mut scan.upper, false),
+                    BinaryOperator::LtEq => (&mut scan.upper, true),
+                    _ => continue,
+                };
+                if slot.is_none() {
+                    *slot = Some(RangeBound {
+                        value: (*value).clone(),
+                        inclusive,
+                    });
+                    if !scan.conditions.iter().any(|c| c == *filter) {
+                        scan.conditions.push((*filter).clone());
+                    }
+                }
+            }
+            if scan.lower.is_some() || scan.upper.is_some() {
+                scan.range_column = Some(column.clone());
+            }
+            break;
+        }
+
+        let ranged = scan.range_column.is_some();
+        if scan.prefix.is_empty() && !ranged {
+            continue;
+        }
+        let better = match &best {
+            Some((prefix, best_ranged, _)) => (scan.prefix.len(), ranged) > (*prefix, *best_ranged),
+            None => true,
+        };
+        if better {
+            best = Some((scan.prefix.len(), ranged, scan));
+        }
+    }
+    best.map(|(_, _, scan)| scan)
+}
+
+/// An index scan that produces a relation's rows in ORDER BY order: `scan`
+/// walked in key order if its index fits, or else, with no scan, a full
+/// walk of an index whose leading columns are the ORDER BY columns. Every
+/// ORDER BY term must be a column sorted in the same direction, and a
+/// nullable column must want NULLs where the index keeps them (first when
+/// ascending) unless a range on it already excludes them.
+fn index_in_order(
+    relation: &Relation,
+    scan: Option<&IndexScan>,
+    order_by: &[OrderByExpr],
+) -> Option<IndexScan> {
+    let ascending = order_by.first()?.asc.unwrap_or(true);
+    let mut columns = Vec::new();
+    for order in order_by {
+        if order.asc.unwrap_or(true) != ascending {
+            return None;
+        }
+        let name = column_name(&order.expr)?;
+        let column = relation.table.columns.iter().find(|c| c.name == name)?;
+        let nulls_first = order.nulls_first.unwrap_or(!ascending);
+        columns.push((name, column.nullable && nulls_first != ascending));
+    }
+    let fits = |scan: &IndexScan, index: &Index| {
+        if !matches!(index.index_type, IndexType::BTree) {
+            return false;
+        }
+        // Columns pinned by the equality prefix are constant, so they
+        // cannot disturb the order
+        let (fixed, rest) = index.columns.split_at(scan.prefix.len());
+        let wanted: Vec<&(&str, bool)> = columns
+            .iter()
+            .filter(|(name, _)| !fixed.iter().any(|c| c == name))
+            .collect();
+        wanted.len() <= rest.len()
+            && wanted
+                .iter()
+                .zip(rest)
+                .all(|((name, nulls_misplaced), column)| {
+                    name == column
+                        && (!nulls_misplaced || scan.range_column.as_ref() == Some(column))
+                })
+    };
+    let order = Some(if ascending {
+        IndexOrder::Ascending
+    } else {
+        IndexOrder::Descending
+    });
+
+    if let Some(scan) = scan {
+        let index = relation
+            .table
+            .indexes
+            .iter()
+            .find(|index| index.name == scan.index)?;
+        return fits(scan, index).then(|| IndexScan {
+            order,
+            ..scan.clone()
+        });
+    }
+    relation.table.indexes.iter().find_map(|index| {
+        let scan = IndexScan {
+            index: index.name.clone(),
+            prefix: Vec::new(),
+            range_column: None,
+            lower: None,
+            upper: None,
+            conditions: Vec::new(),
+            covering: false,
+            order,
+        };
+        fits(&scan, index).then_some(scan)
+    })
+}
+
+/// The `column <op> constant` comparisons a filter makes, with the column
+/// on the left. BETWEEN yields one comparison for each end.
+pub(crate) fn column_comparisons(filter: &Expr) -> Vec<(&str, BinaryOperator, &Expr)> {
+    match filter {
+        Expr::BinaryOp { left, op, right } => {
+            let flipped = match op {
+                BinaryOperator::Eq => BinaryOperator::Eq,
+                BinaryOperator::Lt => BinaryOperator::Gt,
+                BinaryOperator::LtEq => BinaryOperator::GtEq,
+                BinaryOperator::Gt => BinaryOperator::Lt,
+                BinaryOperator::GtEq => BinaryOperator::LtEq,
+                _ => return Vec::new(),
+            };
+            match (column_name(left), column_name(right)) {
+                (Some(column), None) if is_constant(right) => vec![(column, op.clone(), &**right)],
+                (None, Some(column)) if is_constant(left) => vec![(column, flipped, &**left)],
+                _ => Vec::new(),
+            }
+        }
+        Expr::Nested(inner) => column_comparisons(inner),
+        Expr::Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } => match column_name(expr) {
+            Some(column) if is_constant(low) && is_constant(high) => vec![
+                (column, BinaryOperator::GtEq, &**low),
+                (column, BinaryOperator::LtEq, &**high),
+            ],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Whether an expression reads no columns, so it can be evaluated once
+/// before a scan
+fn is_constant(expr: &Expr) -> bool {
+    visit_expressions(expr, |e| match e {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) | Expr::Subquery(_) => {
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    })
+    .is_continue()
+}
+
+/// Split an AND chain into its conjuncts
+fn conjuncts(expr: Expr) -> Vec<Expr> {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut result = conjuncts(*left);
+            result.extend(conjuncts(*right));
+            result
+        }
+        Expr::Nested(inner)
+            if matches!(
+                *inner,
+                Expr::BinaryOp {
+                    op: BinaryOperator::And,
+                    ..
+                }
+            ) =>
+        {
+            conjuncts(*inner)
+        }
+        other => vec![other],
+    }
+}
+
+/// The set of relations an expression references. Expressions the planner
+/// cannot see into are treated as referencing every relation. Fails on
+/// ambiguous or unknown qualifiers.
+pub fn references(expr: &Expr, relations: &[Relation]) -> QubeResult<BTreeSet<usize>> {
+    let mut refs = BTreeSet::new();
+    if !collect_references(expr, relations, &mut refs)? {
+        refs = (0..relations.len()).collect();
+    }
+    Ok(refs)
+}
+
+fn collect_references(
+    expr: &Expr,
+    relations: &[Relation],
+    refs: &mut BTreeSet<usize>,
+) -> QubeResult<bool> {
+    match expr {
+        Expr::Identifier(ident) => {
+            let mut matches = relations
+                .iter()
+                .enumerate()
+                .filter(|(_, relation)| relation.has_column(&ident.value))
+                .map(|(i, _)| i);
+            match (matches.next(), matches.next()) {
+                (Some(_), Some(_)) => Err(QubeError::QueryParse(format!(
+                    "Column reference '{}' is ambiguous",
+                    ident.value
+                ))),
+                (Some(i), None) => {
+                    refs.insert(i);
+                    Ok(true)
+                }
+                // Unknown columns are reported when the expression is evaluated
+                (None, _) => Ok(true),
+            }
+        }
+        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+            [.., table, _] => {
+                let i = relations
+                    .iter()
+                    .position(|relation| relation.qualifier == table.value)
+                    .ok_or_else(|| QubeError::TableNotFound(table.value.clone()))?;
+                refs.insert(i);
+                Ok(true)
+            }
+            [column] => collect_references(&Expr::Identifier(column.clone()), relations, refs),
+            [] => Ok(true),
+        },
+        Expr::Value(_) => Ok(true),
+        Expr::Nested(inner)
+        | Expr::UnaryOp { expr: inner, .. }
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner)
+        | Expr::IsTrue(inner)
+        | Expr::IsFalse(inner) => collect_references(inner, relations, refs),
+        Expr::BinaryOp { left, right, .. } => Ok(collect_references(left, relations, refs)?
+            && collect_references(right, relations, refs)?),
+        Expr::Between {
+            expr, low, high, ..
+        } => Ok(collect_references(expr, relations, refs)?
+            && collect_references(low, relations, refs)?
+            && collect_references(high, relations, refs)?),
+        Expr::InList { expr, list, .. } => {
+            let mut known = collect_references(expr, relations, refs)?;
+            for item in list {
+                known &= collect_references(item, relations, refs)?;
+            }
+            Ok(known)
+        }
+        Expr::Like { expr, pattern, .. } | Expr::ILike { expr, pattern, .. } => {
+            Ok(collect_references(expr, relations, refs)?
+                && collect_references(pattern, relations, refs)?)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Columns of each relation (in FROM order) that `exprs` read. Joins copy
+/// only these columns into the rows they build, so a narrow projection over
+/// wide tables does not pay for every column.
+pub fn required_columns<'e>(
+    exprs: impl IntoIterator<Item = &'e Expr>,
+    relations: &[Relation],
+) -> Vec<BTreeSet<String>> {
+    let mut columns = vec![BTreeSet::new(); relations.len()];
+    for expr in exprs {
+        let _ = visit_expressions(expr, |e| {
+            match e {
+                Expr::Identifier(column) => {
+                    for (i, relation) in relations.iter().enumerate() {
+                        if relation.has_column(&column.value) {
+                            columns[i].insert(column.value.clone());
+                        }
+                    }
+                }
+                Expr::CompoundIdentifier(idents) => {
+                    if let [.., table, column] = idents.as_slice() {
+                        if let Some(i) = relations
+                            .iter()
+                            .position(|relation| relation.qualifier == table.value)
+                        {
+                            columns[i].insert(column.value.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+            ControlFlow::<()>::Continue(())
+        });
+    }
+    columns
+}
+
+/// If `predicate` is `a = b` with one side over `joined` and the other over
+/// `relation` alone, return the sides as (outer, inner)
+fn equi_join_sides(
+    predicate: &Expr,
+    relations: &[Relation],
+    joined: &BTreeSet<usize>,
+    relation: usize,
+) -> QubeResult<Option<(Expr, Expr)>> {
+    let (left, right) = match predicate {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => (left, right),
+        _ => return Ok(None),
+    };
+
+    let left_refs = references(left, relations)?;
+    let right_refs = references(right, relations)?;
+    let inner = BTreeSet::from([relation]);
+    let is_outer = |refs: &BTreeSet<usize>| !refs.is_empty() && refs.is_subset(joined);
+
+    Ok(if is_outer(&left_refs) && right_refs == inner {
+        Some(((**left).clone(), (**right).clone()))
+    } else if is_outer(&right_refs) && left_refs == inner {
+        Some(((**right).clone(), (**left).clone()))
+    } else {
+        None
+    })
+}
+
+/// Estimated fraction of a relation's rows satisfying a single-relation predicate
+pub(crate) fn selectivity(predicate: &Expr, relation: &Relation) -> f64 {
+    if let Expr::BinaryOp {
+        left,
+        op: BinaryOperator::Eq,
+        right,
+    } = predicate
+    {
+        let column = match (&**left, &**right) {
+            (column, Expr::Value(_)) | (Expr::Value(_), column) => column_name(column),
+            _ => None,
+        };
+        return match (column, relation.stats()) {
+            (Some(column), Some(stats)) if stats.row_count > 0 => {
+                stats.estimate_equality_rows(column) / stats.row_count as f64
+            }
+            _ => DEFAULT_EQUALITY_SELECTIVITY,
+        };
+    }
+    DEFAULT_SELECTIVITY
+}
+
+/// Estimated size of joining `relation` (of `relation_rows` rows after filtering)
+/// into a running result of `current_rows` rows
+fn estimate_join(
+    relations: &[Relation],
+    predicates: &[(Expr, BTreeSet<usize>)],
+    joined: &BTreeSet<usize>,
+    relation: usize,
+    current_rows: f64,
+    relation_rows: f64,
+) -> f64 {
+    let mut available = joined.clone();
+    available.insert(relation);
+
+    let mut rows = current_rows * relation_rows;
+    for (predicate, refs) in predicates {
+        let applies = refs.len() > 1 && refs.contains(&relation) && refs.is_subset(&available);
+        if !applies {
+            continue;
+        }
+        rows *= match equi_join_sides(predicate, relations, joined, relation) {
+            Ok(Some((outer, inner))) => {
+                // Containment assumption: each key on the smaller side finds a match
+                let distinct = |expr: &Expr, rows: f64| {
+                    key_distinct_count(expr, relations).unwrap_or(rows.max(1.0))
+                };
+                1.0 / distinct(&outer, current_rows).max(distinct(&inner, relation_rows))
+            }
+            _ => DEFAULT_SELECTIVITY,
+        };
+    }
+    rows
+}
+
+/// Number of distinct values of a plain column reference, from statistics
+fn key_distinct_count(expr: &Expr, relations: &[Relation]) -> Option<f64> {
+    let column = column_name(expr)?;
+    let relation = match expr {
+        Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+            let qualifier = &idents[idents.len() - 2].value;
+            relations.iter().find(|r| &r.qualifier == qualifier)?
+        }
+        _ => relations.iter().find(|r| r.has_column(column))?,
+    };
+    relation
+        .distinct_count(column)
+        .filter(|&n| n > 0)
+        .map(|n| n as f64)
+}
+
+/// Column name of a plain column reference
+pub(crate) fn column_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Identifier(ident) => Some(&ident.value),
+        Expr::CompoundIdentifier(idents) => idents.last().map(|ident| ident.value.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::types::{DataType, TableBuilder};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn table(name: &str, columns: &[&str]) -> Table {
+        columns
+            .iter()
+            .fold(TableBuilder::new(name), |builder, column| {
+                builder.column(*column, DataType::Int32)
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn parse(expr: &str) -> Expr {
+        Parser::new(&GenericDialect {})
+            .try_with_sql(expr)
+            .unwrap()
+            .parse_expr()
+            .unwrap()
+    }
+
+    fn names(columns: &BTreeSet<String>) -> Vec<&str> {
+        columns.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn required_columns_are_the_ones_expressions_read() {
+        let mut catalog = Catalog::new();
+        catalog
+            .create_table(table("users", &["id", "name", "age", "bio"]))
+            .unwrap();
+        catalog
+            .create_table(table("orders", &["id", "user_id", "total", "notes"]))
+            .unwrap();
+        let relation = |name: &str, qualifier: &str| {
+            Relation::new(
+                catalog.get_table(name).unwrap(),
+                qualifier.to_string(),
+                catalog.table_data(name).unwrap(),
+            )
+        };
+        let relations = [relation("users", "u"), relation("orders", "orders")];
+        let exprs = [
+            parse("u.name"),
+            parse("orders.user_id = u.id"),
+            parse("total * 2 > age"),
+        ];
+        let required = required_columns(&exprs, &relations);
+        assert_eq!(names(&required[0]), ["age", "id", "name"]);
+        assert_eq!(names(&required[1]), ["total", "user_id"]);
+
+        // A bare name both relations have is carried from each
+        let required = required_columns(&[parse("id")], &relations);
+        assert_eq!(names(&required[0]), ["id"]);
+        assert_eq!(names(&required[1]), ["id"]);
+        // Qualifiers no relation answers to select nothing
+        let required = required_columns(&[parse("x.name")], &relations);
+        assert!(required.iter().all(BTreeSet::is_empty));
+    }
+
+    #[test]
+    fn explain_lines_carry_annotations_for_steps_and_filters() {
+        let mut catalog = Catalog::new();
+        catalog.create_table(table("t", &["id", "a"])).unwrap();
+        catalog.create_table(table("u", &["id", "t_id"])).unwrap();
+        let relation = |name: &str| {
+            Relation::new(
+                catalog.get_table(name).unwrap(),
+                name.to_string(),
+                catalog.table_data(name).unwrap(),
+            )
+        };
+        let relations = [relation("t"), relation("u")];
+        // A predicate reading no column is left to the residual filter
+        let predicates = vec![parse("t.id = u.t_id"), parse("1 < 2")];
+        let plan = plan(&relations, predicates).unwrap();
+
+        let plain = plan.explain(&relations);
+        let annotated = plan.explain_with(&relations, |i| Some(format!("note {}", i)));
+        assert_eq!(plain.len(), annotated.len());
+        assert!(annotated[0].starts_with("Scan t (estimated rows: "));
+        assert!(annotated[0].ends_with(", note 0)"));
+        let join = annotated.iter().find(|line| line.contains(" u (")).unwrap();
+        assert!(join.ends_with(", note 1)"));
+        assert_eq!(annotated.last().unwrap(), "Filter: 1 < 2 (note 2)");
+        assert_eq!(plain.last().unwrap(), "Filter: 1 < 2");
+        assert_eq!(plan.explain_with(&relations, |_| None), plain);
+    }
+}
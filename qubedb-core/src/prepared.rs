@@ -0,0 +1,367 @@
+//! Prepared statements for QubeDB
+//!
+//! A `PreparedStatement` holds a parsed statement containing parameter
+//! placeholders, either positional (`?`), numbered (`$1`, `$2`, ...) or
+//! named (`:name` or `@name`). Executing it binds a fresh set of values
+//! without parsing the SQL again: a slice of values for positional and
+//! numbered placeholders, a map from name to value for named ones.
+
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::types::Value;
+use sqlparser::ast::{
+    visit_expressions, visit_expressions_mut, Expr, Statement, Value as SqlValue,
+};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+/// How a statement refers to its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlaceholderStyle {
+    /// `?`, bound in order of appearance
+    Positional,
+    /// `$1`, `$2`, ..., bound by number
+    Numbered,
+    /// `:name` or `@name`, bound by name
+    Named,
+}
+
+/// A parsed statement that can be executed repeatedly with different
+/// parameter values
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    sql: Arc<str>,
+    statement: Arc<Statement>,
+    style: Option<PlaceholderStyle>,
+    param_count: usize,
+    /// Names of the named parameters, in order of first appearance
+    param_names: Vec<String>,
+}
+
+impl PreparedStatement {
+    /// Wrap a parsed statement, validating its placeholders
+    pub(crate) fn new(sql: &str, statement: Statement) -> QubeResult<Self> {
+        let mut style = None;
+        let mut param_count = 0;
+        let mut param_names: Vec<String> = Vec::new();
+        let mut error = None;
+
+        let _ = visit_expressions(&statement, |expr| {
+            if let Some(name) = placeholder_name(expr) {
+                if !param_names.iter().any(|n| n == name) {
+                    param_names.push(name.to_string());
+                }
+                if *style.get_or_insert(PlaceholderStyle::Named) != PlaceholderStyle::Named {
+                    error = Some(mixed_styles());
+                    return ControlFlow::Break(());
+                }
+            } else if let Expr::Value(SqlValue::Placeholder(placeholder)) = expr {
+                let found = match placeholder_number(placeholder) {
+                    Ok(None) => {
+                        param_count += 1;
+                        PlaceholderStyle::Positional
+                    }
+                    Ok(Some(n)) => {
+                        param_count = param_count.max(n);
+                        PlaceholderStyle::Numbered
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        return ControlFlow::Break(());
+                    }
+                };
+                if *style.get_or_insert(found) != found {
+                    error = Some(mixed_styles());
+                    return ControlFlow::Break(());
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        Ok(PreparedStatement {
+            sql: sql.into(),
+            statement: Arc::new(statement),
+            style,
+            param_count: param_count.max(param_names.len()),
+            param_names,
+        })
+    }
+
+    /// The SQL text this statement was prepared from
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// Number of parameter values `bind` or `bind_named` expects
+    pub fn param_count(&self) -> usize {
+        self.param_count
+    }
+
+    /// Names of the statement's named parameters, without their `:` or
+    /// `@`, in order of first appearance
+    pub fn param_names(&self) -> &[String] {
+        &self.param_names
+    }
+
+    /// Substitute `params` for the placeholders, producing a statement ready
+    /// to execute
+    pub fn bind(&self, params: &[Value]) -> QubeResult<Statement> {
+        if self.style == Some(PlaceholderStyle::Named) {
+            return Err(QubeError::QueryParse(
+                "Statement has named parameters, which are bound by name".to_string(),
+            ));
+        }
+        if params.len() != self.param_count {
+            return Err(QubeError::QueryParse(format!(
+                "Statement expects {} parameters but {} were given",
+                self.param_count,
+                params.len()
+            )));
+        }
+
+        let mut statement = (*self.statement).clone();
+        if self.style.is_none() {
+            return Ok(statement);
+        }
+
+        let mut next = 0;
+        let _ = visit_expressions_mut(&mut statement, |expr| {
+            if let Expr::Value(SqlValue::Placeholder(placeholder)) = expr {
+                let index = match self.style {
+                    Some(PlaceholderStyle::Numbered) => {
+                        // Validated in `new`
+                        placeholder_number(placeholder).ok().flatten().unwrap_or(1) - 1
+                    }
+                    _ => {
+                        next += 1;
+                        next - 1
+                    }
+                };
+                *expr = Expr::Value(expr::to_literal(&params[index]));
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        Ok(statement)
+    }
+
+    /// Substitute `params` for the named placeholders, producing a
+    /// statement ready to execute. Names are given without their `:` or
+    /// `@`. Every parameter of the statement must be bound, and every name
+    /// bound must be a parameter of the statement.
+    pub fn bind_named(&self, params: &HashMap<String, Value>) -> QubeResult<Statement> {
+        if matches!(
+            self.style,
+            Some(PlaceholderStyle::Positional | PlaceholderStyle::Numbered)
+        ) {
+            return Err(QubeError::QueryParse(
+                "Statement has positional parameters, which are bound in order".to_string(),
+            ));
+        }
+        if let Some(name) = self.param_names.iter().find(|n| !params.contains_key(*n)) {
+            return Err(QubeError::QueryParse(format!(
+                "No value given for parameter '{}'",
+                name
+            )));
+        }
+        let mut unused: Vec<&String> = params
+            .keys()
+            .filter(|name| !self.param_names.contains(name))
+            .collect();
+        unused.sort();
+        if let Some(name) = unused.first() {
+            return Err(QubeError::QueryParse(format!(
+                "Parameter '{}' does not appear in the statement",
+                name
+            )));
+        }
+
+        let mut statement = (*self.statement).clone();
+        let _ = visit_expressions_mut(&mut statement, |expr| {
+            if let Some(value) = placeholder_name(expr).and_then(|name| params.get(name)) {
+                *expr = Expr::Value(expr::to_literal(value));
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        Ok(statement)
+    }
+}
+
+fn mixed_styles() -> QubeError {
+    QubeError::QueryParse("Cannot mix ?, $n and named parameters in one statement".to_string())
+}
+
+/// The name of a named placeholder, without its `:` or `@`. The generic
+/// dialect reads `@name` as an identifier rather than a placeholder.
+fn placeholder_name(expr: &Expr) -> Option<&str> {
+    let placeholder = match expr {
+        Expr::Value(SqlValue::Placeholder(placeholder)) => placeholder.as_str(),
+        Expr::Identifier(ident) if ident.quote_style.is_none() => ident.value.as_str(),
+        _ => return None,
+    };
+    placeholder
+        .strip_prefix(':')
+        .or_else(|| placeholder.strip_prefix('@'))
+        .filter(|name| !name.is_empty())
+}
+
+/// Parse a placeholder: `?` is positional (`None`), `$n` is numbered
+fn placeholder_number(placeholder: &str) -> QubeResult<Option<usize>> {
+    if placeholder == "?" {
+        return Ok(None);
+    }
+    placeholder
+        .strip_prefix('$')
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .map(Some)
+        .ok_or_else(|| QubeError::QueryParse(format!("Invalid parameter: {}", placeholder)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryEngine;
+    use crate::security::SecurityContext;
+    use crate::session::Session;
+
+    fn params(values: &[(&str, Value)]) -> HashMap<String, Value> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn engine() -> QueryEngine {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script("CREATE TABLE people (name TEXT, age INT)")
+            .unwrap();
+        engine
+    }
+
+    #[tokio::test]
+    async fn named_parameters_bind_in_insert_and_where() {
+        let engine = engine();
+        for (name, age) in [("ann", 31), ("bob", 45)] {
+            engine
+                .execute_sql_with_named_params(
+                    "INSERT INTO people VALUES (:name, @age)",
+                    params(&[
+                        ("name", Value::String(name.to_string())),
+                        ("age", Value::Int32(age)),
+                    ]),
+                )
+                .await
+                .unwrap();
+        }
+
+        // A name may appear more than once
+        let result = engine
+            .execute_sql_with_named_params(
+                "SELECT name FROM people WHERE age > :min AND age < :min + 10",
+                params(&[("min", Value::Int32(40))]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0]["name"], Value::String("bob".to_string()));
+    }
+
+    #[tokio::test]
+    async fn unbound_and_unused_names_are_errors() {
+        let engine = engine();
+        let error = engine
+            .execute_sql_with_named_params(
+                "SELECT name FROM people WHERE age > :min AND name = :name",
+                params(&[("min", Value::Int32(40))]),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Query parsing error: No value given for parameter 'name'"
+        );
+
+        let error = engine
+            .execute_sql_with_named_params(
+                "SELECT name FROM people WHERE age > :min",
+                params(&[("min", Value::Int32(40)), ("max", Value::Int32(50))]),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Query parsing error: Parameter 'max' does not appear in the statement"
+        );
+    }
+
+    #[tokio::test]
+    async fn named_and_positional_parameters_do_not_mix() {
+        let engine = engine();
+        let error = engine
+            .execute_sql_with_named_params(
+                "SELECT name FROM people WHERE age > :min AND age < ?",
+                params(&[("min", Value::Int32(40))]),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), mixed_styles().to_string());
+
+        assert!(engine
+            .execute_sql_with_named_params(
+                "SELECT name FROM people WHERE age > ?",
+                params(&[("min", Value::Int32(40))]),
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn sessions_cache_statements_and_bind_them_in_order() {
+        let mut session = Session::new(Arc::new(engine()), SecurityContext::admin("ann"));
+        let insert = session.prepare("INSERT INTO people VALUES (?, ?)").unwrap();
+        assert_eq!(insert.param_count(), 2);
+        for (name, age) in [("ann", 31), ("bob", 45), ("cy", 52)] {
+            let values = [Value::String(name.to_string()), Value::Int32(age)];
+            session.execute_prepared(&insert, &values).await.unwrap();
+        }
+
+        // The same SQL comes back from the cache without parsing again
+        let again = session.prepare("INSERT INTO people VALUES (?, ?)").unwrap();
+        assert!(Arc::ptr_eq(&insert.statement, &again.statement));
+        session.clear_prepared();
+        let reparsed = session.prepare("INSERT INTO people VALUES (?, ?)").unwrap();
+        assert!(!Arc::ptr_eq(&insert.statement, &reparsed.statement));
+
+        // Numbered parameters may repeat and come in any order
+        let between = session
+            .prepare("SELECT name FROM people WHERE age > $2 AND age < $1 AND age <> $2")
+            .unwrap();
+        assert_eq!(between.param_count(), 2);
+        let result = session
+            .execute_prepared(&between, &[Value::Int32(50), Value::Int32(31)])
+            .await
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0]["name"], Value::String("bob".to_string()));
+
+        let error = session
+            .execute_prepared(&between, &[Value::Int32(50)])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Query parsing error: Statement expects 2 parameters but 1 were given"
+        );
+        assert!(session
+            .prepare("SELECT name FROM people WHERE age > ? AND age < $1")
+            .is_err());
+        assert!(session
+            .prepare("SELECT name FROM people WHERE age > $0")
+            .is_err());
+    }
+}
@@ -6,146 +6,6412 @@
 //! - JSONPath (document)
 //! - Vector similarity search
 
-use crate::error::{QubeError, QubeResult};
-use crate::types::{QueryResult, Row, Value};
-use sqlparser::ast::{Query, SelectItem, Statement};
+use crate::advisor::{self, IndexRecommendation};
+use crate::aggregate;
+use crate::catalog::{Catalog, HistoryRetention, RowEffect, TableData};
+use crate::changes::{ChangeFeed, ChangeKind, ChangeListener, ChangeListenerConfig};
+use crate::columnar::ColumnStoreStats;
+use crate::dump;
+use crate::error::{self, QubeError, QubeResult};
+use crate::expr;
+use crate::index::{BTreeIndex, IndexEntry, IndexIssue};
+use crate::locks::{LockManager, LockOwner};
+use crate::namespace::{self, DEFAULT_DATABASE};
+use crate::pagination::{Keyset, Page};
+use crate::parallel::{self, ParallelConfig};
+use crate::planner::{self, IndexOrder, IndexScan, JoinStep, QueryPlan, RangeBound, Relation};
+use crate::prepared::PreparedStatement;
+use crate::quota::{self, MemoryUsage, Quota, QuotaUsage};
+use crate::security::Permission;
+use crate::stats::{TableStats, AUTO_ANALYZE_THRESHOLD};
+use crate::types::{
+    Column, Constraint, ConstraintType, DataType, DefaultValue, Generated, Index, IndexType,
+    JsonSchema, QueryResult, ResultKind, Row, StorageLayout, Table, TableBuilder, Value,
+};
+use crate::validate;
+use crate::view::{View, ViewRefresh};
+use crate::wal::{Durability, Wal, WalConfig};
+use crate::window;
+use sqlparser::ast::{
+    visit_expressions, Assignment, BinaryOperator, ColumnDef, ColumnOption, ConflictTarget,
+    DataType as SqlDataType, Distinct, Expr, FunctionArg, FunctionArgExpr, GeneratedAs, Ident,
+    JoinConstraint, JoinOperator, ObjectName, ObjectType, OnConflict, OnConflictAction, OnInsert,
+    OrderByExpr, Query, SchemaName, Select, SelectItem, SetExpr, ShowStatementFilter, SqlOption,
+    Statement, TableAlias, TableConstraint, TableFactor, TableWithJoins, Value as SqlValue,
+    VisitMut, VisitorMut, With,
+};
 use sqlparser::dialect::GenericDialect;
+use sqlparser::keywords::Keyword;
 use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, TokenWithLocation, Tokenizer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::{Bound, ControlFlow};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How many rows a scan loop processes between deadline/cancellation checks
+const CHECK_INTERVAL: usize = 1024;
+
+/// Column comment standing in for the `VIRTUAL` keyword of a generated
+/// column, which sqlparser does not accept
+pub(crate) const VIRTUAL_MARKER: &str = "qubedb:virtual";
+
+/// Table hint standing in for `AS OF TIMESTAMP`, which sqlparser does not
+/// accept after a table name
+const AS_OF_HINT: &str = "qubedb_as_of";
+
+/// Handle used to cancel a running query from another task or thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new, non-cancelled token
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of every query holding this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Per-query execution options
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Abort the query once it has run for longer than this
+    pub timeout: Option<Duration>,
+    /// Abort the query once this token is cancelled
+    pub cancellation: Option<CancellationToken>,
+    /// How durable a write must be before it returns. Defaults to
+    /// `Durability::Sync`; only matters for an engine with a log.
+    pub durability: Option<Durability>,
+}
+
+impl QueryOptions {
+    /// Set the query timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Attach a cancellation token
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Set how durable writes must be before they return
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+}
+
+/// What a VACUUM did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VacuumStats {
+    /// Tables compacted
+    pub tables: usize,
+    /// Live rows read while compacting them
+    pub rows_scanned: usize,
+    /// Past row versions dropped for falling out of their table's
+    /// history retention
+    pub versions_reclaimed: usize,
+    /// Bytes the write-ahead log shrank by, zero without a log
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+}
+
+/// Counts of how SELECTs have read table rows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanMetrics {
+    /// Rows read from table storage
+    pub rows_fetched: u64,
+    /// Rows answered from index entries alone, without reading the table
+    pub index_only_rows: u64,
+}
+
+impl ScanMetrics {
+    /// Rows read either way
+    fn rows_read(&self) -> u64 {
+        self.rows_fetched + self.index_only_rows
+    }
+}
+
+/// Operators of a SELECT that EXPLAIN ANALYZE measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operator {
+    /// The scan or join of the plan step at this position
+    Step(usize),
+    /// The residual predicates
+    Filter,
+    Aggregate,
+    Window,
+    Sort,
+    /// OFFSET and LIMIT
+    Limit,
+    Project,
+    Distinct,
+}
+
+/// What one operator did while EXPLAIN ANALYZE ran a query
+#[derive(Debug, Clone, Copy, Default)]
+struct OperatorStats {
+    /// Rows the operator took in: read by a scan, from both sides of a join
+    rows_examined: u64,
+    /// Rows it passed on
+    rows_returned: u64,
+    /// Time spent in it, including a join's scan of its inner relation
+    elapsed: Duration,
+}
+
+impl fmt::Display for OperatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "actual rows: {}, examined: {}, time: {:.3} ms",
+            self.rows_returned,
+            self.rows_examined,
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Deadline and cancellation state for a single query execution
+pub(crate) struct ExecutionContext {
+    deadline: Option<Instant>,
+    cancellation: Option<CancellationToken>,
+    processed: usize,
+    /// How the current statement's scans have read rows
+    scans: ScanMetrics,
+    pub(crate) durability: Option<Durability>,
+    /// Transaction the statement runs for, whose own row locks don't
+    /// block it
+    pub(crate) lock_owner: Option<LockOwner>,
+    /// Holder of a row lock that stopped the statement
+    blocked_by: Option<LockOwner>,
+    /// What each operator did, while EXPLAIN ANALYZE runs the statement
+    profile: Option<BTreeMap<Operator, OperatorStats>>,
+    /// How operators may use threads; serial unless the engine says so
+    pub(crate) parallel: ParallelConfig,
+}
+
+impl ExecutionContext {
+    pub(crate) fn new(options: &QueryOptions) -> Self {
+        ExecutionContext {
+            deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+            cancellation: options.cancellation.clone(),
+            processed: 0,
+            scans: ScanMetrics::default(),
+            durability: options.durability,
+            lock_owner: None,
+            blocked_by: None,
+            profile: None,
+            parallel: ParallelConfig::serial(),
+        }
+    }
+
+    /// Fail if the query has been cancelled or has passed its deadline
+    pub(crate) fn check(&self) -> QubeResult<()> {
+        if let Some(token) = &self.cancellation {
+            if token.is_cancelled() {
+                return Err(QubeError::Cancelled);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(QubeError::Timeout(
+                    "query exceeded its time limit".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Note what an operator did since `started`, if the statement is
+    /// being profiled
+    fn profile(&mut self, operator: Operator, examined: u64, returned: usize, started: Instant) {
+        if let Some(profile) = &mut self.profile {
+            profile.insert(
+                operator,
+                OperatorStats {
+                    rows_examined: examined,
+                    rows_returned: returned as u64,
+                    elapsed: started.elapsed(),
+                },
+            );
+        }
+    }
+
+    /// Record one processed row, checking the deadline every `CHECK_INTERVAL` rows
+    pub(crate) fn tick(&mut self) -> QubeResult<()> {
+        self.processed += 1;
+        if self.processed.is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    /// On a worker thread, check the deadline every `CHECK_INTERVAL` rows
+    /// of its partition; `row` counts from 0
+    pub(crate) fn check_at(&self, row: usize) -> QubeResult<()> {
+        if (row + 1).is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    /// Record rows that worker threads processed
+    pub(crate) fn tick_many(&mut self, rows: usize) -> QubeResult<()> {
+        self.processed += rows;
+        self.check()
+    }
+}
+
+/// One write of a batch committed as a unit
+#[derive(Debug, Clone)]
+pub enum Write {
+    /// A statement, run as the batch commits
+    Statement(Statement),
+    /// The rows `statement` wrote to a transaction's snapshot, applied as
+    /// they are rather than by running it again
+    Rows {
+        statement: Statement,
+        effects: Vec<RowEffect>,
+    },
+}
 
 /// Query engine that handles different query types
 pub struct QueryEngine {
-    // Query engine components will be added here
+    catalog: RwLock<Catalog>,
+    /// Session transactions currently open against this engine
+    open_transactions: AtomicUsize,
+    /// Listeners for the rows this engine writes
+    changes: ChangeFeed,
+    /// How SELECTs against this engine have read rows
+    scans: Mutex<ScanMetrics>,
+    /// Log that successful writes are appended to, if the engine is durable
+    wal: Option<Wal>,
+    /// Held shared by a write from applying it until it is logged, and
+    /// exclusively by VACUUM, so a checkpoint matches the log it replaces
+    checkpointing: RwLock<()>,
+    /// Row locks of transactions, shared with staging copies
+    locks: Arc<LockManager>,
+    /// How SELECTs split large scans, aggregates and sorts across threads
+    parallelism: Mutex<ParallelConfig>,
 }
 
 impl QueryEngine {
     /// Create a new query engine
     pub fn new() -> Self {
-        QueryEngine {}
+        QueryEngine {
+            catalog: RwLock::new(Catalog::new()),
+            open_transactions: AtomicUsize::new(0),
+            changes: ChangeFeed::new(),
+            scans: Mutex::default(),
+            wal: None,
+            checkpointing: RwLock::default(),
+            locks: Arc::default(),
+            parallelism: Mutex::default(),
+        }
     }
 
-    /// Parse SQL query
-    pub fn parse_sql(&self, sql: &str) -> QubeResult<Statement> {
-        let dialect = GenericDialect {};
-        let _parser = Parser::new(&dialect);
+    /// Open an engine whose writes are logged to the write-ahead log at
+    /// `path`. Any statements already in the log are replayed first, so
+    /// the engine starts with every write that reached the disk before
+    /// the log was last closed or the process crashed.
+    pub fn open<P: AsRef<std::path::Path>>(path: P, config: WalConfig) -> QubeResult<Self> {
+        let path = path.as_ref();
+        let mut engine = Self::open_read_only(path)?;
+        engine.wal = Some(Wal::open(path, config)?);
+        Ok(engine)
+    }
+
+    /// Rebuild an engine from the write-ahead log at `path` without opening
+    /// the log for writing, so nothing the engine then does is logged and
+    /// the file is never touched. A missing log gives an empty engine.
+    pub fn open_read_only<P: AsRef<std::path::Path>>(path: P) -> QubeResult<Self> {
+        let path = path.as_ref();
+        let engine = QueryEngine::new();
+        if path.exists() {
+            for record in Wal::read(path)? {
+                let sql = String::from_utf8(record.payload).map_err(|_| {
+                    QubeError::Storage(format!("WAL record {} is not valid UTF-8", record.lsn))
+                })?;
+                // A checkpoint of an empty database holds no statements
+                if sql.trim().is_empty() {
+                    continue;
+                }
+                engine.execute_script(&sql).map_err(|e| {
+                    QubeError::Storage(format!("Failed to replay WAL record {}: {}", record.lsn, e))
+                })?;
+            }
+        }
+        Ok(engine)
+    }
+
+    /// The engine's write-ahead log, if it has one
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// List the names of all tables, sorted
+    pub fn list_tables(&self) -> Vec<String> {
+        let catalog = self.catalog.read().unwrap();
+        catalog
+            .list_tables()
+            .into_iter()
+            .map(|table| table.name.clone())
+            .collect()
+    }
+
+    /// Names of all databases, the default one first. See `namespace`.
+    pub fn list_databases(&self) -> Vec<String> {
+        self.catalog.read().unwrap().list_databases()
+    }
+
+    /// Whether a database with this name exists
+    pub fn has_database(&self, name: &str) -> bool {
+        self.catalog.read().unwrap().has_database(name)
+    }
+
+    /// Keep the history of the rows of every table without a retention of
+    /// its own as `retention` says, so a SELECT can read a table `AS OF
+    /// TIMESTAMP` a moment within it. Shrinking it reclaims the history
+    /// that falls outside at once. Returns how many past versions were
+    /// reclaimed. See `catalog`.
+    pub fn set_history_retention(&self, retention: HistoryRetention) -> usize {
+        self.catalog
+            .write()
+            .unwrap()
+            .set_history_retention(retention)
+    }
+
+    /// Estimated memory held by the rows of every table and their history
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.catalog.read().unwrap().memory_usage()
+    }
+
+    /// Softly cap the estimated memory held by all tables, or lift the cap
+    /// with `None`. Writes that take the engine over it evict the oldest
+    /// history of rows, and fail if the rows alone would exceed it. Returns
+    /// how many past versions were evicted at once. See `quota`.
+    pub fn set_memory_limit(&self, limit: Option<u64>) -> usize {
+        self.catalog.write().unwrap().set_memory_limit(limit)
+    }
+
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.catalog.read().unwrap().memory_limit()
+    }
+
+    /// How much history of their rows tables keep, unless set per table
+    pub fn history_retention(&self) -> HistoryRetention {
+        self.catalog.read().unwrap().history_retention()
+    }
+
+    /// Keep the history of one table's rows as `retention` says, or as the
+    /// engine-wide retention says when `None`, reclaiming what it no longer
+    /// covers at once. Returns how many past versions were reclaimed.
+    pub fn set_table_history_retention(
+        &self,
+        table: &str,
+        retention: Option<HistoryRetention>,
+    ) -> QubeResult<usize> {
+        self.catalog
+            .write()
+            .unwrap()
+            .set_table_history_retention(table, retention)
+    }
+
+    /// Limit the rows, bytes and indexes a database may hold. An
+    /// unlimited quota removes the limits. See `quota`.
+    pub fn set_quota(&self, database: &str, quota: Quota) -> QubeResult<()> {
+        self.catalog.write().unwrap().set_quota(database, quota)
+    }
+
+    /// The quota of a database
+    pub fn quota(&self, database: &str) -> QubeResult<Quota> {
+        let catalog = self.catalog.read().unwrap();
+        if !catalog.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+        Ok(catalog.quota(database))
+    }
 
-        let statements = Parser::parse_sql(&dialect, sql)
-            .map_err(|e| QubeError::QueryParse(format!("SQL parsing error: {}", e)))?;
+    /// What a database currently holds, in the units of its quota
+    pub fn quota_usage(&self, database: &str) -> QubeResult<QuotaUsage> {
+        let catalog = self.catalog.read().unwrap();
+        if !catalog.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+        Ok(catalog.quota_usage(database))
+    }
+
+    /// Get the definition of a table
+    pub fn describe_table(&self, name: &str) -> QubeResult<Table> {
+        let catalog = self.catalog.read().unwrap();
+        catalog.get_table(name).cloned()
+    }
 
-        statements
+    /// Parse SQL query
+    pub fn parse_sql(&self, sql: &str) -> QubeResult<Statement> {
+        self.parse_script(sql)?
             .into_iter()
             .next()
             .ok_or_else(|| QubeError::QueryParse("No SQL statement found".to_string()))
     }
 
+    /// Parse a script of `;`-separated statements
+    pub fn parse_script(&self, sql: &str) -> QubeResult<Vec<Statement>> {
+        parse_script(sql)
+    }
+
     /// Execute SQL query
     pub async fn execute_sql(&self, sql: &str) -> QubeResult<QueryResult> {
-        let statement = self.parse_sql(sql)?;
+        self.execute_sql_with_options(sql, &QueryOptions::default())
+    }
 
-        match statement {
-            Statement::Query(query) => self.execute_select(*query).await,
-            Statement::Insert { .. } => {
-                // TODO: Implement INSERT
-                Ok(QueryResult {
-                    columns: vec![],
-                    rows: vec![],
-                    affected_rows: 0,
-                    execution_time: std::time::Duration::from_millis(0),
-                })
-            }
-            Statement::Update { .. } => {
-                // TODO: Implement UPDATE
-                Ok(QueryResult {
-                    columns: vec![],
-                    rows: vec![],
-                    affected_rows: 0,
-                    execution_time: std::time::Duration::from_millis(0),
-                })
-            }
-            Statement::Delete { .. } => {
-                // TODO: Implement DELETE
-                Ok(QueryResult {
-                    columns: vec![],
-                    rows: vec![],
-                    affected_rows: 0,
-                    execution_time: std::time::Duration::from_millis(0),
-                })
+    /// Execute SQL query with a timeout and/or cancellation token.
+    ///
+    /// The query runs on the calling thread until it finishes, times out or
+    /// is cancelled, so async code should call this through
+    /// `tokio::task::spawn_blocking` rather than hold up the executor. The
+    /// cancellation token can be cancelled from any other thread or task.
+    ///
+    /// Runs inside an `execute_sql` tracing span, recording the row count
+    /// and duration, with `parse`, `plan` and `execute` child spans.
+    pub fn execute_sql_with_options(
+        &self,
+        sql: &str,
+        options: &QueryOptions,
+    ) -> QubeResult<QueryResult> {
+        let span = tracing::info_span!(
+            "execute_sql",
+            sql,
+            rows = tracing::field::Empty,
+            duration_ms = tracing::field::Empty
+        );
+        span.in_scope(|| {
+            let start = Instant::now();
+            let mut ctx = ExecutionContext::new(options);
+            ctx.check()?;
+
+            let statement = tracing::info_span!("parse").in_scope(|| self.parse_sql(sql))?;
+            let result = tracing::info_span!("execute")
+                .in_scope(|| self.execute_statement(statement, &mut ctx));
+            if let Ok(result) = &result {
+                span.record("rows", result.rows.len().max(result.affected_rows));
             }
-            _ => Err(QubeError::QueryParse(
-                "Unsupported SQL statement".to_string(),
-            )),
+            span.record("duration_ms", start.elapsed().as_millis() as u64);
+            result
+        })
+    }
+
+    /// Execute SQL whose named placeholders (`:name` or `@name`) are bound
+    /// to `params`, keyed by name without the `:` or `@`. Every placeholder
+    /// must have a value and every value a placeholder.
+    pub async fn execute_sql_with_named_params(
+        &self,
+        sql: &str,
+        params: HashMap<String, Value>,
+    ) -> QubeResult<QueryResult> {
+        let prepared = PreparedStatement::new(sql, self.parse_sql(sql)?)?;
+        let statement = prepared.bind_named(&params)?;
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        self.execute_statement(statement, &mut ctx)
+    }
+
+    /// Check a script of `;`-separated statements without running it.
+    ///
+    /// Each statement is checked against the schema (tables and columns
+    /// exist, functions are known, operand types line up) and then run on
+    /// an empty copy of the schema, so later statements see tables created
+    /// by earlier ones and anything only the executor rejects is reported
+    /// too. The first problem found is returned.
+    pub fn validate(&self, sql: &str) -> QubeResult<()> {
+        let statements = self.parse_script(sql)?;
+        let dry_run = QueryEngine {
+            catalog: RwLock::new(self.catalog.read().unwrap().schema()),
+            open_transactions: AtomicUsize::new(0),
+            changes: ChangeFeed::new(),
+            scans: Mutex::default(),
+            wal: None,
+            checkpointing: RwLock::default(),
+            locks: Arc::default(),
+            parallelism: Mutex::new(self.parallelism()),
+        };
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        for mut statement in statements {
+            namespace::qualify(&mut statement, DEFAULT_DATABASE);
+            validate::check_statement(&dry_run.catalog.read().unwrap(), &statement)?;
+            dry_run.execute_statement(statement, &mut ctx)?;
         }
+        Ok(())
     }
 
-    /// Execute SELECT query
-    async fn execute_select(&self, query: Query) -> QubeResult<QueryResult> {
-        // TODO: Implement actual query execution
-        // This is a placeholder implementation
+    /// Row locks held by transactions on this engine
+    pub fn locks(&self) -> &LockManager {
+        &self.locks
+    }
 
-        let start_time = std::time::Instant::now();
+    /// How SELECTs use threads
+    pub fn parallelism(&self) -> ParallelConfig {
+        *self.parallelism.lock().unwrap()
+    }
 
-        // Extract columns from SELECT
-        let columns = match &*query.body {
-            sqlparser::ast::SetExpr::Select(select) => select
-                .projection
-                .iter()
-                .map(|item| match item {
-                    SelectItem::UnnamedExpr(_expr) => "column".to_string(),
-                    SelectItem::ExprWithAlias { expr: _expr, alias } => alias.value.clone(),
-                    SelectItem::Wildcard(_) => "*".to_string(),
-                    _ => "unknown".to_string(),
-                })
-                .collect(),
-            _ => vec!["*".to_string()],
+    /// Set how many threads SELECTs may split large scans, aggregates and
+    /// sorts across, and from what size on
+    pub fn set_parallelism(&self, config: ParallelConfig) {
+        *self.parallelism.lock().unwrap() = config;
+    }
+
+    /// Create an engine holding a private copy of this engine's tables.
+    /// Changes made through the copy are not visible here.
+    pub fn snapshot(&self) -> QueryEngine {
+        QueryEngine {
+            catalog: RwLock::new(self.catalog.read().unwrap().clone()),
+            open_transactions: AtomicUsize::new(0),
+            changes: ChangeFeed::new(),
+            scans: Mutex::default(),
+            wal: None,
+            checkpointing: RwLock::default(),
+            locks: Arc::default(),
+            parallelism: Mutex::new(self.parallelism()),
+        }
+    }
+
+    /// Run a batch of statements as one unit: either every statement takes
+    /// effect or, if any of them fails, none does
+    pub fn execute_atomically(&self, statements: &[Statement]) -> QubeResult<Vec<QueryResult>> {
+        self.execute_atomically_with(statements, Durability::default())
+    }
+
+    /// Run a batch of statements as one unit, logging its writes as a
+    /// single record at the given durability
+    pub fn execute_atomically_with(
+        &self,
+        statements: &[Statement],
+        durability: Durability,
+    ) -> QubeResult<Vec<QueryResult>> {
+        self.execute_validated(statements, &[], durability)
+    }
+
+    /// Run a batch of statements as one unit like `execute_atomically_with`,
+    /// provided every read in `reads` still returns the rows recorded for
+    /// it. Each read is `(position, query, rows)` and is re-run after the
+    /// first `position` statements, so it sees them as it did originally.
+    /// If any read returns different rows, nothing is applied and the call
+    /// fails with `QubeError::Transaction("conflict")`.
+    pub fn execute_validated(
+        &self,
+        statements: &[Statement],
+        reads: &[(usize, Statement, Vec<Row>)],
+        durability: Durability,
+    ) -> QubeResult<Vec<QueryResult>> {
+        let writes: Vec<Write> = statements.iter().cloned().map(Write::Statement).collect();
+        self.commit_transaction(None, &writes, reads, durability)
+    }
+
+    /// Commit the writes of the transaction `owner` like
+    /// `execute_validated`, its reads positioned among `writes`. Its own
+    /// row locks don't stop its writes; writes to rows other transactions
+    /// hold wait for them to finish, then the whole batch starts over.
+    ///
+    /// Rows the transaction wrote are applied as they are, so the ids and
+    /// defaults it was given stand. A row it updated or deleted must still
+    /// be as it read it: if another transaction changed it meanwhile,
+    /// nothing is applied and the commit fails with CONFLICT.
+    pub(crate) fn commit_transaction(
+        &self,
+        owner: Option<LockOwner>,
+        writes: &[Write],
+        reads: &[(usize, Statement, Vec<Row>)],
+        durability: Durability,
+    ) -> QubeResult<Vec<QueryResult>> {
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        ctx.lock_owner = owner;
+        loop {
+            match self.try_commit(writes, reads, durability, &mut ctx) {
+                Err(e) => match ctx.blocked_by.take() {
+                    Some(holder) => self.locks.wait(owner, holder, &ctx)?,
+                    None => return Err(e),
+                },
+                outcome => return outcome,
+            }
+        }
+    }
+
+    fn try_commit(
+        &self,
+        writes: &[Write],
+        reads: &[(usize, Statement, Vec<Row>)],
+        durability: Durability,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<Vec<QueryResult>> {
+        if writes
+            .iter()
+            .any(|write| matches!(write, Write::Statement(statement) if is_vacuum(statement)))
+        {
+            return Err(QubeError::Transaction(
+                "VACUUM cannot run inside a transaction or script".to_string(),
+            ));
+        }
+        let _logging = self.checkpointing.read().unwrap();
+        let mut catalog = self.catalog.write().unwrap();
+        let staging = QueryEngine {
+            catalog: RwLock::new(catalog.clone()),
+            open_transactions: AtomicUsize::new(0),
+            changes: ChangeFeed::deferred(&self.changes),
+            scans: Mutex::default(),
+            wal: None,
+            checkpointing: RwLock::default(),
+            locks: Arc::clone(&self.locks),
+            parallelism: Mutex::new(self.parallelism()),
         };
+        let mut inserted = HashMap::new();
+        let mut logged = self.wal.as_ref().map(|_| Vec::new());
+        let mut results = Vec::with_capacity(writes.len());
+        for position in 0..=writes.len() {
+            for (_, read, rows) in reads.iter().filter(|(at, _, _)| *at == position) {
+                if staging.run_statement(read.clone(), ctx)?.rows != *rows {
+                    return Err(QubeError::Transaction(error::CONFLICT.to_string()));
+                }
+            }
+            if let Some(write) = writes.get(position) {
+                results.push(staging.apply(write, &mut inserted, &mut logged, ctx)?);
+            }
+        }
+        if let Some(logged) = logged.filter(|logged| !logged.is_empty()) {
+            self.log_write(&logged.join(";\n"), durability)?;
+        }
+        self.changes.publish(staging.changes.take_deferred());
+        *catalog = staging.catalog.into_inner().unwrap();
+        Ok(results)
+    }
 
-        // Placeholder result
-        let rows = vec![vec![
-            ("id".to_string(), Value::Int32(1)),
-            ("name".to_string(), Value::String("QubeDB".to_string())),
-        ]
-        .into_iter()
-        .collect::<Row>()];
+    /// Apply one write of a batch being committed, adding the statements
+    /// that replay it to `logged` when that is kept. `inserted` maps the
+    /// ids rows inserted by earlier writes of the batch had where they
+    /// were written to their ids here.
+    pub(crate) fn apply(
+        &self,
+        write: &Write,
+        inserted: &mut HashMap<(String, u64), u64>,
+        logged: &mut Option<Vec<String>>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        match write {
+            Write::Statement(statement) => {
+                let result = self.run_statement(statement.clone(), ctx)?;
+                if let Some(logged) = logged.as_mut().filter(|_| is_write(statement)) {
+                    logged.push(statement.to_string());
+                }
+                Ok(result)
+            }
+            Write::Rows { statement, effects } => {
+                self.apply_rows(statement, effects, inserted, logged, ctx)
+            }
+        }
+    }
+
+    /// Apply the rows `statement` wrote to a transaction's snapshot. Each
+    /// row the statement changed must still be as the snapshot had it, or
+    /// nothing is applied and the call fails with CONFLICT.
+    ///
+    /// The rows are logged as statements writing them one at a time, so
+    /// replaying the log gives them the same ids and defaults. Updates and
+    /// deletes are picked out by a unique key; on a table without one,
+    /// `statement` itself is logged.
+    fn apply_rows(
+        &self,
+        statement: &Statement,
+        effects: &[RowEffect],
+        inserted: &mut HashMap<(String, u64), u64>,
+        logged: &mut Option<Vec<String>>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = Instant::now();
+        let mut names: Vec<&str> = effects.iter().map(|effect| effect.table.as_str()).collect();
+        names.dedup();
+        let mut statements = Vec::with_capacity(effects.len());
+        let mut keyed = true;
+        {
+            let mut catalog = self.catalog.write().unwrap();
+            for &name in &names {
+                let effects: Vec<&RowEffect> = effects
+                    .iter()
+                    .filter(|effect| effect.table == name)
+                    .collect();
+                catalog.check_writable(name)?;
+                let headroom = catalog.headroom(name);
+                let (table, data) = catalog.table_data_mut(name)?;
+
+                // Rows inserted earlier in the batch have ids of their own
+                // here; rows that were already there kept theirs
+                let targets: Vec<Option<u64>> = effects
+                    .iter()
+                    .map(|effect| {
+                        effect.before.as_ref().map(|_| {
+                            inserted
+                                .get(&(name.to_string(), effect.row_id))
+                                .copied()
+                                .unwrap_or(effect.row_id)
+                        })
+                    })
+                    .collect();
+                for (effect, target) in effects.iter().zip(&targets) {
+                    if let Some(row_id) = target {
+                        let current = data.row(*row_id, None);
+                        if current.as_deref() != effect.before.as_ref() {
+                            return Err(QubeError::Transaction(error::CONFLICT.to_string()));
+                        }
+                    }
+                }
+                let changed: HashSet<u64> = targets.iter().flatten().copied().collect();
+                self.check_unlocked(name, changed.iter().copied(), ctx)?;
+                let written: Vec<Row> = effects
+                    .iter()
+                    .filter_map(|effect| effect.after.clone())
+                    .collect();
+                check_unique_keys(table, data, &changed, &written)?;
+                self.changes.check_room(effects.len())?;
+                if headroom.is_limited() {
+                    let size = |row: &Option<Row>| row.as_ref().map_or(0, quota::row_size) as i64;
+                    let rows = effects
+                        .iter()
+                        .map(|effect| {
+                            effect.after.is_some() as i64 - effect.before.is_some() as i64
+                        })
+                        .sum();
+                    let bytes = effects
+                        .iter()
+                        .map(|effect| size(&effect.after) - size(&effect.before))
+                        .sum();
+                    headroom.check(rows, bytes, 0)?;
+                }
 
-        let execution_time = start_time.elapsed();
+                let mut changes = Vec::new();
+                for (effect, target) in effects.iter().zip(&targets) {
+                    if logged.is_some() {
+                        let sql = match (&effect.before, &effect.after) {
+                            (None, Some(after)) => Some(dump::insert_row(table, after)),
+                            (Some(before), Some(after)) => dump::update_row(table, before, after),
+                            (Some(before), None) => dump::delete_row(table, before),
+                            (None, None) => None,
+                        };
+                        match sql {
+                            Some(sql) => statements.push(sql),
+                            None => keyed = false,
+                        }
+                    }
+                    match (target, &effect.after) {
+                        (None, Some(row)) => {
+                            changes.extend(self.changes.changes(name, ChangeKind::Insert, [row]));
+                            let row_id = data.insert(row.clone());
+                            inserted.insert((name.to_string(), effect.row_id), row_id);
+                        }
+                        (Some(row_id), Some(row)) => {
+                            changes.extend(self.changes.changes(name, ChangeKind::Update, [row]));
+                            data.update(*row_id, row.clone());
+                        }
+                        (Some(row_id), None) => {
+                            let removed = data.remove_many(&[*row_id]);
+                            changes.extend(self.changes.changes(
+                                name,
+                                ChangeKind::Delete,
+                                &removed,
+                            ));
+                        }
+                        (None, None) => {}
+                    }
+                }
+                // The ids the transaction was given came from this table's
+                // sequence, unless it created the table itself
+                if let Some(column) = table.columns.iter().find(|c| c.auto_increment) {
+                    if let Some(id) = written
+                        .iter()
+                        .filter_map(|row| row[&column.name].as_i64())
+                        .max()
+                    {
+                        data.advance_sequence(id);
+                    }
+                }
+                refresh_stale_stats(&mut catalog, name)?;
+                catalog.evict_history();
+                self.changes.publish(changes);
+            }
+        }
+        if let Some(logged) = logged {
+            if keyed {
+                logged.extend(statements);
+            } else {
+                logged.push(statement.to_string());
+            }
+        }
+        for name in names {
+            if let Err(e) = self.refresh_dependents(name, ctx) {
+                tracing::warn!(
+                    "Failed to refresh materialized views reading '{}': {}",
+                    name,
+                    e
+                );
+            }
+        }
 
         Ok(QueryResult {
-            columns,
-            rows,
-            affected_rows: 1,
-            execution_time,
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: effects.len(),
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
         })
     }
 
-    /// Execute GraphQL query
-    pub async fn execute_graphql(&self, _query: &str) -> QubeResult<QueryResult> {
-        // TODO: Implement GraphQL query execution
-        Err(QubeError::QueryParse(
-            "GraphQL queries not yet implemented".to_string(),
-        ))
+    /// Journal the rows written to this engine's tables from now on, for
+    /// `take_journal`. See `Catalog::start_journal`.
+    pub(crate) fn start_journal(&self) {
+        self.catalog.write().unwrap().start_journal();
     }
 
-    /// Execute JSONPath query
-    pub async fn execute_jsonpath(
+    /// The rows written since the journal was last taken
+    pub(crate) fn take_journal(&self) -> Vec<RowEffect> {
+        self.catalog.write().unwrap().take_journal()
+    }
+
+    /// Receive an event for every row this engine writes from now on.
+    ///
+    /// Events are published while the write holds the catalog lock, so a
+    /// consumer of a `Block` listener must not wait on this engine, or a
+    /// full buffer will deadlock.
+    pub fn subscribe_changes(&self, config: ChangeListenerConfig) -> ChangeListener {
+        self.changes.subscribe(config)
+    }
+
+    /// Number of session transactions open against this engine
+    pub fn open_transactions(&self) -> usize {
+        self.open_transactions.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn transaction_started(&self) {
+        self.open_transactions.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn transaction_ended(&self) {
+        self.open_transactions.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Drop every table, index and statistic. Fails while a transaction is
+    /// open, since committing it would recreate rows in an empty catalog.
+    /// The write-ahead log, if any, is checkpointed to the empty database,
+    /// so reopening the engine from it does not restore the dropped tables.
+    pub fn reset(&self) -> QubeResult<()> {
+        let _checkpoint = self.checkpointing.write().unwrap();
+        let mut catalog = self.catalog.write().unwrap();
+        match self.open_transactions() {
+            0 => {
+                *catalog = Catalog::new();
+                if let Some(wal) = &self.wal {
+                    wal.checkpoint(dump::dump(&catalog).as_bytes())?;
+                }
+                Ok(())
+            }
+            open => Err(QubeError::Transaction(format!(
+                "cannot reset the database while {} transaction(s) are open",
+                open
+            ))),
+        }
+    }
+
+    /// Run a SELECT over one table a page of `page_size` rows at a time,
+    /// starting after `cursor`, the `next_cursor` of the previous page, or
+    /// at the first page without one. See `pagination`.
+    pub fn execute_page(
         &self,
-        _jsonpath: &str,
-        _document: &serde_json::Value,
+        sql: &str,
+        page_size: usize,
+        cursor: Option<&str>,
+    ) -> QubeResult<Page> {
+        let mut statement = self.parse_sql(sql)?;
+        namespace::qualify(&mut statement, DEFAULT_DATABASE);
+        let mut query = match statement {
+            Statement::Query(query) => *query,
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "Only SELECT queries can be paginated".to_string(),
+                ))
+            }
+        };
+        let keyset = Keyset::apply(&mut query, &self.catalog.read().unwrap(), page_size, cursor)?;
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        let result = self.execute_statement(Statement::Query(Box::new(query)), &mut ctx)?;
+        keyset.page(result)
+    }
+
+    /// Execute a script of `;`-separated statements in order as a single
+    /// transaction, returning each statement's result. If any statement
+    /// fails, the whole script is rolled back.
+    pub fn execute_script(&self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        let statements = self.parse_script(sql)?;
+        self.execute_atomically(&statements)
+    }
+
+    /// Execute an already parsed statement, logging it if it is a write.
+    /// Bare names are taken to be in the default database; sessions using
+    /// another one qualify them first.
+    pub(crate) fn execute_statement(
+        &self,
+        statement: Statement,
+        ctx: &mut ExecutionContext,
     ) -> QubeResult<QueryResult> {
-        // TODO: Implement JSONPath query execution
-        Err(QubeError::QueryParse(
-            "JSONPath queries not yet implemented".to_string(),
-        ))
+        // Only writes to existing rows, including upserts, can be stopped
+        // by a row lock and need running again
+        if !matches!(
+            statement,
+            Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. }
+        ) {
+            return self.run_statement(statement, ctx);
+        }
+        loop {
+            match self.run_statement(statement.clone(), ctx) {
+                Err(e) => match ctx.blocked_by.take() {
+                    Some(holder) => self.locks.wait(ctx.lock_owner, holder, ctx)?,
+                    None => return Err(e),
+                },
+                outcome => return outcome,
+            }
+        }
     }
 
-    /// Execute vector similarity search
-    pub async fn execute_vector_search(
+    /// Execute a statement once, failing with `ctx.blocked_by` set if it
+    /// would write a row another transaction has locked
+    fn run_statement(
         &self,
-        _collection: &str,
-        _query_vector: &[f32],
-        _limit: usize,
+        mut statement: Statement,
+        ctx: &mut ExecutionContext,
     ) -> QubeResult<QueryResult> {
-        // TODO: Implement vector similarity search
-        Err(QubeError::QueryParse(
-            "Vector search not yet implemented".to_string(),
-        ))
+        namespace::qualify(&mut statement, DEFAULT_DATABASE);
+        let logged = match &self.wal {
+            Some(_) if is_write(&statement) => Some(statement.to_string()),
+            _ => None,
+        };
+        let _logging = logged.as_ref().map(|_| self.checkpointing.read().unwrap());
+        let written = written_table(&statement);
+        let result = self.dispatch(statement, ctx)?;
+        if let Some(sql) = logged {
+            self.log_write(&sql, ctx.durability.unwrap_or_default())?;
+        }
+        // The write stands whether or not the views reading it refresh
+        if let Some(table) = written {
+            if let Err(e) = self.refresh_dependents(&table, ctx) {
+                tracing::warn!(
+                    "Failed to refresh materialized views reading '{}': {}",
+                    table,
+                    e
+                );
+            }
+        }
+        Ok(result)
+    }
+
+    /// Append a write that has been applied to the log, if there is one
+    fn log_write(&self, sql: &str, durability: Durability) -> QubeResult<()> {
+        if let Some(wal) = &self.wal {
+            wal.append_with(sql.as_bytes(), durability)?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(
+        &self,
+        statement: Statement,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        match statement {
+            Statement::Query(query) => self.execute_select(&query, &[], ctx),
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+                on,
+                ..
+            } => self.execute_insert(&table_name, &columns, &source, on.as_ref(), ctx),
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                returning,
+            } => {
+                if from.is_some() || returning.is_some() {
+                    return Err(QubeError::Unsupported(
+                        "UPDATE ... FROM and RETURNING".to_string(),
+                    ));
+                }
+                self.execute_update(&table, &assignments, selection.as_ref(), ctx)
+            }
+            Statement::Delete {
+                tables,
+                from,
+                using,
+                selection,
+                returning,
+            } => {
+                if !tables.is_empty() || using.is_some() || returning.is_some() {
+                    return Err(QubeError::Unsupported(
+                        "multi-table DELETE, USING and RETURNING".to_string(),
+                    ));
+                }
+                self.execute_delete(&from, selection.as_ref(), ctx)
+            }
+            Statement::CreateTable {
+                name,
+                columns,
+                constraints,
+                with_options,
+                if_not_exists,
+                ..
+            } => self.execute_create_table(
+                &name,
+                &columns,
+                &constraints,
+                &with_options,
+                if_not_exists,
+            ),
+            Statement::CreateIndex {
+                name,
+                table_name,
+                using,
+                columns,
+                unique,
+                if_not_exists,
+                ..
+            } => self.execute_create_index(
+                name.as_ref(),
+                &table_name,
+                using.as_ref(),
+                &columns,
+                unique,
+                if_not_exists,
+            ),
+            Statement::CreateDatabase {
+                db_name,
+                if_not_exists,
+                ..
+            }
+            | Statement::CreateSchema {
+                schema_name: SchemaName::Simple(db_name),
+                if_not_exists,
+            } => self.execute_create_database(&db_name, if_not_exists),
+            Statement::Drop {
+                object_type,
+                if_exists,
+                names,
+                ..
+            } => self.execute_drop(object_type, &names, if_exists),
+            Statement::Use { .. } => Err(QubeError::QueryParse(
+                "USE is only supported in a session".to_string(),
+            )),
+            Statement::ShowTables {
+                db_name, filter, ..
+            } => self.execute_show_tables(db_name.as_ref(), filter.as_ref()),
+            Statement::ExplainTable { table_name, .. }
+            | Statement::ShowColumns { table_name, .. } => self.execute_describe(&table_name),
+            Statement::CreateView {
+                or_replace,
+                materialized,
+                name,
+                columns,
+                query,
+                with_options,
+                ..
+            } => {
+                let refresh = View::refresh_option(&with_options, materialized)?;
+                self.execute_create_view(&name, &columns, &query, refresh, or_replace, ctx)
+            }
+            Statement::ShowVariable { variable } => self.execute_show(&variable, ctx),
+            Statement::Analyze { table_name, .. } => self.execute_analyze(&table_name),
+            Statement::Explain {
+                statement, analyze, ..
+            } => self.execute_explain(&statement, analyze, ctx),
+            other => Err(QubeError::Unsupported(format!(
+                "{} statements",
+                statement_keywords(&other)
+            ))),
+        }
+    }
+
+    /// Execute SELECT query. Its FROM clause may read `ctes`, the CTEs of
+    /// the queries it is part of, besides tables, views and its own CTEs.
+    fn execute_select(
+        &self,
+        query: &Query,
+        ctes: &[Rc<CommonTable>],
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+
+        let materialized;
+        let ctes = match &query.with {
+            Some(with) => {
+                materialized = self.materialize_ctes(with, ctes, ctx)?;
+                &materialized[..]
+            }
+            None => ctes,
+        };
+        let select = match &*query.body {
+            SetExpr::Select(select) => select,
+            other => return Err(QubeError::Unsupported(format!("query {}", other))),
+        };
+        let expanded = self.expand_views(&select.from, ctes, ctx)?;
+        let ctes = &expanded[..];
+        check_supported(query, select)?;
+        let windows = window::has_window_functions(&select.projection);
+        if windows && aggregate::has_aggregates(&select.projection) {
+            return Err(QubeError::QueryParse(
+                "Window functions cannot be combined with aggregate functions".to_string(),
+            ));
+        }
+
+        ctx.parallel = self.parallelism();
+        let catalog = self.catalog.read().unwrap();
+        let past = read_past(&catalog, ctes, &select.from)?;
+        let relations = resolve_relations(&catalog, ctes, &past, &select.from)?;
+        let mut plan = plan_select(&relations, select)?;
+        let ambiguous = ambiguous_columns(&relations);
+
+        let offset = match &query.offset {
+            Some(offset) => row_count_of(&offset.value)?,
+            None => 0,
+        };
+        let limit = match &query.limit {
+            Some(limit) => Some(row_count_of(limit)?),
+            None => None,
+        };
+        let mut projection = expand_projection(&select.projection, &relations, &ambiguous)?;
+        let columns: Vec<String> = projection.iter().map(|(name, _)| name.clone()).collect();
+
+        let ordered = !aggregate::has_aggregates(&select.projection)
+            && plan.use_index_order(&relations, &query.order_by);
+        let required = required_columns(&relations, &plan, &projection, &query.order_by);
+        plan.mark_covering(&relations, |step| scan_columns(&relations, step, &required));
+
+        let mut rows = if relations.is_empty() {
+            vec![Cow::Owned(Row::new())]
+        } else if relations.len() == 1 {
+            let step = &plan.steps[0];
+            let scan_limit = limit
+                .filter(|_| limit_pushes_down(query, &relations, &plan))
+                .map(|limit| limit.saturating_add(offset));
+            let columns = scan_columns(&relations, step, &required);
+            let started = Instant::now();
+            let read = ctx.scans.rows_read();
+            let rows = scan(&relations[0], step, &columns, scan_limit, ctx)?;
+            let examined = ctx.scans.rows_read() - read;
+            ctx.profile(Operator::Step(0), examined, rows.len(), started);
+            rows
+        } else {
+            execute_joins(&relations, &plan, &ambiguous, &required, ctx)?
+                .into_iter()
+                .map(Cow::Owned)
+                .collect()
+        };
+        self.record_scans(ctx);
+        if !plan.residual.is_empty() {
+            let started = Instant::now();
+            let examined = rows.len() as u64;
+            rows = filter_rows(rows, &plan.residual, ctx)?;
+            ctx.profile(Operator::Filter, examined, rows.len(), started);
+        }
+
+        // Window functions see every row, before ORDER BY / OFFSET / LIMIT
+        if windows {
+            let started = Instant::now();
+            projection = window::apply_windows(&projection, &mut rows, ctx)?;
+            ctx.profile(Operator::Window, rows.len() as u64, rows.len(), started);
+        }
+
+        // Aggregates collapse every row into one, before OFFSET / LIMIT
+        if aggregate::has_aggregates(&select.projection) {
+            let started = Instant::now();
+            let row = aggregate::aggregate_rows(&projection, rows.iter().map(|r| r.as_ref()), ctx)?;
+            ctx.profile(Operator::Aggregate, rows.len() as u64, 1, started);
+            let result_rows = std::iter::once(row)
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect();
+            return Ok(QueryResult {
+                kind: ResultKind::Rows,
+                columns,
+                rows: result_rows,
+                affected_rows: 0,
+                last_insert_id: None,
+                execution_time: start_time.elapsed(),
+            });
+        }
+
+        // ORDER BY, unless the scan already produced rows in order
+        if !query.order_by.is_empty() && !ordered {
+            let started = Instant::now();
+            let order_by = &query.order_by;
+            let shared = &*ctx;
+            // Each partition is sorted on its own thread, then the sorted
+            // runs are merged by one more (stable) sort
+            let runs = parallel::map_partitions(rows, &ctx.parallel, |rows| {
+                let mut keyed = rows
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, row)| {
+                        shared.check_at(i)?;
+                        let keys = order_by
+                            .iter()
+                            .map(|order| expr::evaluate(&order.expr, &row))
+                            .collect::<QubeResult<Vec<_>>>()?;
+                        Ok((keys, row))
+                    })
+                    .collect::<QubeResult<Vec<_>>>()?;
+                keyed.sort_by(|(a, _), (b, _)| compare_sort_keys(a, b, order_by));
+                Ok(keyed)
+            })?;
+            let merge = runs.len() > 1;
+            let mut keyed: Vec<_> = runs.into_iter().flatten().collect();
+            if merge {
+                keyed.sort_by(|(a, _), (b, _)| compare_sort_keys(a, b, order_by));
+            }
+            rows = keyed.into_iter().map(|(_, row)| row).collect();
+            ctx.profile(Operator::Sort, rows.len() as u64, rows.len(), started);
+        }
+
+        // OFFSET / LIMIT, which with DISTINCT only apply once repeated
+        // rows are removed from the projected ones
+        let distinct = select.distinct.is_some();
+        if !distinct {
+            rows = offset_limit(rows, offset, limit, ctx);
+        }
+
+        // Projection
+        let started = Instant::now();
+        let examined = rows.len() as u64;
+        let mut result_rows = Vec::new();
+        for row in rows {
+            ctx.tick()?;
+            let projected = projection
+                .iter()
+                .map(|(name, expr)| Ok((name.clone(), expr::evaluate(expr, &row)?)))
+                .collect::<QubeResult<Row>>()?;
+            result_rows.push(projected);
+        }
+        ctx.profile(Operator::Project, examined, result_rows.len(), started);
+        ctx.check()?;
+
+        // DISTINCT keeps the first of each set of equal rows
+        if distinct {
+            let started = Instant::now();
+            let examined = result_rows.len() as u64;
+            let mut seen = HashSet::new();
+            result_rows.retain(|row| {
+                let key: Vec<Value> = columns
+                    .iter()
+                    .map(|column| row.get(column).cloned().unwrap_or(Value::Null))
+                    .collect();
+                seen.insert(key)
+            });
+            ctx.profile(Operator::Distinct, examined, result_rows.len(), started);
+            result_rows = offset_limit(result_rows, offset, limit, ctx);
+        }
+
+        Ok(QueryResult {
+            kind: ResultKind::Rows,
+            columns,
+            rows: result_rows,
+            affected_rows: 0,
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Run the CTEs of a WITH clause in order, each able to read `outer`
+    /// and the CTEs before it, and return them after `outer`
+    fn materialize_ctes(
+        &self,
+        with: &With,
+        outer: &[Rc<CommonTable>],
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<Vec<Rc<CommonTable>>> {
+        if with.recursive {
+            return Err(QubeError::Unsupported(
+                "WITH RECURSIVE (recursive common table expressions)".to_string(),
+            ));
+        }
+        // EXPLAIN ANALYZE profiles the operators of the query itself
+        let profile = ctx.profile.take();
+        let mut ctes = outer.to_vec();
+        let result = with.cte_tables.iter().try_for_each(|cte| {
+            let name = &cte.alias.name.value;
+            if ctes[outer.len()..].iter().any(|c| c.table.name == *name) {
+                return Err(QubeError::QueryParse(format!(
+                    "WITH query name '{}' specified more than once",
+                    name
+                )));
+            }
+            let result = self.execute_select(&cte.query, &ctes, ctx)?;
+            ctes.push(Rc::new(CommonTable::new(name, &cte.alias.columns, result)?));
+            Ok(())
+        });
+        ctx.profile = profile;
+        result.map(|()| ctes)
+    }
+
+    /// Run the views `from` reads, other than materialized ones, and
+    /// return their results after `ctes` to be read like CTEs. A name that
+    /// is also a CTE's refers to the CTE.
+    fn expand_views(
+        &self,
+        from: &[TableWithJoins],
+        ctes: &[Rc<CommonTable>],
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<Vec<Rc<CommonTable>>> {
+        let views: Vec<View> = {
+            let catalog = self.catalog.read().unwrap();
+            table_factors(from)
+                .filter_map(|factor| match factor {
+                    TableFactor::Table { name, .. } => catalog.view(&object_name(name)),
+                    _ => None,
+                })
+                .filter(|view| {
+                    !view.is_materialized() && !ctes.iter().any(|cte| cte.table.name == view.name)
+                })
+                .cloned()
+                .collect()
+        };
+        // EXPLAIN ANALYZE profiles the operators of the query itself
+        let profile = ctx.profile.take();
+        let mut expanded = ctes.to_vec();
+        let result = views.iter().try_for_each(|view| {
+            if !expanded[ctes.len()..]
+                .iter()
+                .any(|c| c.table.name == view.name)
+            {
+                expanded.push(Rc::new(self.run_view(view, ctx)?));
+            }
+            Ok(())
+        });
+        ctx.profile = profile;
+        result.map(|()| expanded)
+    }
+
+    /// Add a statement's scan counts to the engine's totals
+    fn record_scans(&self, ctx: &mut ExecutionContext) {
+        let scans = std::mem::take(&mut ctx.scans);
+        let mut totals = self.scans.lock().unwrap();
+        totals.rows_fetched += scans.rows_fetched;
+        totals.index_only_rows += scans.index_only_rows;
+    }
+
+    /// Execute EXPLAIN, describing how a SELECT would be run. EXPLAIN
+    /// ANALYZE also runs it, adding what each operator actually did.
+    fn execute_explain(
+        &self,
+        statement: &Statement,
+        analyze: bool,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let query = match statement {
+            Statement::Query(query) => query,
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "EXPLAIN only supports SELECT".to_string(),
+                ))
+            }
+        };
+        let select = match &*query.body {
+            SetExpr::Select(select) => select,
+            other => return Err(QubeError::Unsupported(format!("query {}", other))),
+        };
+        check_supported(query, select)?;
+
+        // Run the query first: it takes the catalog lock itself
+        let mut profile = BTreeMap::new();
+        let mut result_rows = 0;
+        if analyze {
+            ctx.profile = Some(BTreeMap::new());
+            let result = self.execute_select(query, &[], ctx);
+            profile = ctx.profile.take().unwrap_or_default();
+            result_rows = result?.rows.len();
+        }
+        let actual = |operator| profile.get(&operator).map(|stats| stats.to_string());
+        let annotate = |line: String, operator| match actual(operator) {
+            Some(stats) => format!("{} ({})", line, stats),
+            None => line,
+        };
+
+        // The query is planned against what its CTEs and views return
+        let ctes = match &query.with {
+            Some(with) => self.materialize_ctes(with, &[], ctx)?,
+            None => Vec::new(),
+        };
+        let ctes = self.expand_views(&select.from, &ctes, ctx)?;
+        let catalog = self.catalog.read().unwrap();
+        let past = read_past(&catalog, &ctes, &select.from)?;
+        let relations = resolve_relations(&catalog, &ctes, &past, &select.from)?;
+        let mut plan = plan_select(&relations, select)?;
+        let ambiguous = ambiguous_columns(&relations);
+        let projection = expand_projection(&select.projection, &relations, &ambiguous)?;
+        let ordered = !aggregate::has_aggregates(&select.projection)
+            && plan.use_index_order(&relations, &query.order_by);
+        let required = required_columns(&relations, &plan, &projection, &query.order_by);
+        plan.mark_covering(&relations, |step| scan_columns(&relations, step, &required));
+
+        let steps = plan.steps.len();
+        let mut lines: Vec<String> = ctes
+            .iter()
+            .map(|cte| format!("CTE {} ({} rows)", cte.table.name, cte.data.len()))
+            .collect();
+        if relations.is_empty() {
+            lines.push("Result".to_string());
+        } else {
+            lines.extend(plan.explain_with(&relations, |i| {
+                actual(if i < steps {
+                    Operator::Step(i)
+                } else {
+                    Operator::Filter
+                })
+            }));
+        }
+        if relations.len() > 1 {
+            let columns: Vec<String> = relations
+                .iter()
+                .zip(&required)
+                .flat_map(|(relation, columns)| {
+                    columns
+                        .iter()
+                        .map(move |column| format!("{}.{}", relation.qualifier, column))
+                })
+                .collect();
+            lines.push(format!("Columns: {}", columns.join(", ")));
+        }
+        let aggregates = aggregate::has_aggregates(&select.projection);
+        if window::has_window_functions(&select.projection) {
+            lines.push(annotate("Window".to_string(), Operator::Window));
+        }
+        if aggregates {
+            lines.push(annotate("Aggregate".to_string(), Operator::Aggregate));
+        } else if !query.order_by.is_empty() && !ordered {
+            let keys: Vec<String> = query.order_by.iter().map(|o| o.to_string()).collect();
+            lines.push(annotate(
+                format!("Sort: {}", keys.join(", ")),
+                Operator::Sort,
+            ));
+        }
+        // DISTINCT runs on the projected rows
+        let distinct = select.distinct.is_some() && !aggregates;
+        if distinct {
+            if analyze {
+                lines.push(annotate("Project".to_string(), Operator::Project));
+            }
+            lines.push(annotate("Distinct".to_string(), Operator::Distinct));
+        }
+        // OFFSET and LIMIT run as one operator, measured on the last line
+        if let Some(offset) = &query.offset {
+            let line = format!("Offset: {}", offset.value);
+            if query.limit.is_none() && !aggregates {
+                lines.push(annotate(line, Operator::Limit));
+            } else {
+                lines.push(line);
+            }
+        }
+        if let Some(limit) = &query.limit {
+            let line = if limit_pushes_down(query, &relations, &plan) {
+                format!("Limit: {} (pushed into scan)", limit)
+            } else {
+                format!("Limit: {}", limit)
+            };
+            if aggregates {
+                lines.push(line);
+            } else {
+                lines.push(annotate(line, Operator::Limit));
+            }
+        }
+        if analyze {
+            if !aggregates && !distinct {
+                lines.push(annotate("Project".to_string(), Operator::Project));
+            }
+            lines.push(format!(
+                "Execution Time: {:.3} ms ({} rows)",
+                start_time.elapsed().as_secs_f64() * 1000.0,
+                result_rows
+            ));
+        }
+
+        let rows = lines
+            .into_iter()
+            .map(|line| Row::from([("plan".to_string(), Value::String(line))]))
+            .collect();
+        Ok(rows_result(vec!["plan"], rows, start_time))
+    }
+
+    /// Execute INSERT ... VALUES
+    fn execute_insert(
+        &self,
+        table_name: &ObjectName,
+        columns: &[Ident],
+        source: &Query,
+        on: Option<&OnInsert>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let values = match &*source.body {
+            SetExpr::Values(values) => &values.rows,
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "Only INSERT ... VALUES is supported".to_string(),
+                ))
+            }
+        };
+
+        let name = object_name(table_name);
+        let mut catalog = self.catalog.write().unwrap();
+        catalog.check_writable(&name)?;
+        let headroom = catalog.headroom(&name);
+        let (table, data) = catalog.table_data_mut(&name)?;
+
+        // Without a column list, values go to every column but the
+        // generated ones
+        let targets = if columns.is_empty() {
+            (0..table.columns.len())
+                .filter(|&i| table.columns[i].generated.is_none())
+                .collect::<Vec<_>>()
+        } else {
+            columns
+                .iter()
+                .map(|ident| {
+                    table
+                        .columns
+                        .iter()
+                        .position(|c| c.name == ident.value)
+                        .ok_or_else(|| {
+                            QubeError::ColumnNotFound(format!("{}.{}", name, ident.value))
+                        })
+                })
+                .collect::<QubeResult<Vec<_>>>()?
+        };
+
+        // Build and validate every row before inserting any of them, so a bad
+        // row leaves the table (and its sequence) untouched
+        let empty_row = Row::new();
+        let auto_column = table.columns.iter().find(|c| c.auto_increment);
+        let generated = GeneratedColumns::of(table)?;
+        // Held until the rows are in, so an INSERT into a snapshot of the
+        // table, which shares its sequence, waits rather than taking the
+        // same values
+        let shared_sequence = data.shared_sequence();
+        let mut claimed = shared_sequence.lock().unwrap();
+        let mut sequence = *claimed;
+        let mut last_insert_id = None;
+        let mut new_rows = Vec::with_capacity(values.len());
+        for exprs in values {
+            if exprs.len() != targets.len() {
+                return Err(QubeError::QueryParse(format!(
+                    "INSERT has {} values but {} columns",
+                    exprs.len(),
+                    targets.len()
+                )));
+            }
+
+            // Omitted columns take their default, or NULL if they have none
+            let mut row: Row = table
+                .columns
+                .iter()
+                .map(|column| Ok((column.name.clone(), column_default(column)?)))
+                .collect::<QubeResult<_>>()?;
+            for (value_expr, &i) in exprs.iter().zip(&targets) {
+                let column = &table.columns[i];
+                let value = if is_default_keyword(value_expr) {
+                    column_default(column)?
+                } else if column.generated.is_some() {
+                    return Err(QubeError::QueryParse(format!(
+                        "Cannot insert a value into generated column '{}'",
+                        column.name
+                    )));
+                } else {
+                    expr::coerce(expr::evaluate(value_expr, &empty_row)?, &column.data_type)?
+                };
+                row.insert(column.name.clone(), value);
+            }
+            if let Some(column) = auto_column {
+                let value = &row[&column.name];
+                if value.is_null() {
+                    sequence = sequence.checked_add(1).ok_or_else(|| {
+                        QubeError::ConstraintViolation(format!("Sequence of '{}' exhausted", name))
+                    })?;
+                    let id = expr::coerce(Value::Int64(sequence), &column.data_type)?;
+                    row.insert(column.name.clone(), id);
+                    last_insert_id = Some(sequence);
+                } else if let Some(explicit) = value.as_i64() {
+                    // Never hand out an id that was inserted explicitly
+                    sequence = sequence.max(explicit);
+                }
+            }
+            for column in table.columns.iter().filter(|c| c.generated.is_none()) {
+                column.check(&row[&column.name])?;
+            }
+            generated.store(&mut row)?;
+            new_rows.push(row);
+        }
+
+        let Upsert {
+            inserts: new_rows,
+            updates,
+        } = match on {
+            Some(on) => resolve_conflicts(&name, table, data, on, new_rows)?,
+            None => Upsert {
+                inserts: new_rows,
+                updates: Vec::new(),
+            },
+        };
+
+        let updated_ids: HashSet<u64> = updates.iter().map(|(row_id, _)| *row_id).collect();
+        self.check_unlocked(&name, updated_ids.iter().copied(), ctx)?;
+        let written: Cow<[Row]> = if updates.is_empty() {
+            Cow::Borrowed(&new_rows)
+        } else {
+            Cow::Owned(
+                new_rows
+                    .iter()
+                    .chain(updates.iter().map(|(_, row)| row))
+                    .cloned()
+                    .collect(),
+            )
+        };
+        check_unique_keys(table, data, &updated_ids, &written)?;
+        self.changes.check_room(written.len())?;
+        if headroom.is_limited() {
+            let bytes = new_rows
+                .iter()
+                .map(|row| quota::row_size(row) as i64)
+                .sum::<i64>()
+                + size_change(data, &updates);
+            headroom.check(new_rows.len() as i64, bytes, 0)?;
+        }
+
+        let affected = written.len();
+        let mut changes = self.changes.changes(&name, ChangeKind::Insert, &new_rows);
+        changes.extend(self.changes.changes(
+            &name,
+            ChangeKind::Update,
+            updates.iter().map(|(_, row)| row),
+        ));
+        for row in new_rows {
+            data.insert(row);
+        }
+        for (row_id, row) in updates {
+            data.update(row_id, row);
+        }
+        *claimed = sequence;
+        drop(claimed);
+        refresh_stale_stats(&mut catalog, &name)?;
+        catalog.evict_history();
+        self.changes.publish(changes);
+
+        Ok(QueryResult {
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: affected,
+            last_insert_id,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Execute UPDATE ... SET ... [WHERE ...]
+    fn execute_update(
+        &self,
+        table: &TableWithJoins,
+        assignments: &[Assignment],
+        selection: Option<&Expr>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let name = match &table.relation {
+            TableFactor::Table { name, .. } if table.joins.is_empty() => object_name(name),
+            other => return Err(QubeError::Unsupported(format!("UPDATE target {}", other))),
+        };
+        if as_of(&table.relation)?.is_some() {
+            return Err(QubeError::QueryParse(
+                "A table read AS OF a past moment cannot be updated".to_string(),
+            ));
+        }
+
+        let mut catalog = self.catalog.write().unwrap();
+        catalog.check_writable(&name)?;
+        let headroom = catalog.headroom(&name);
+        let (table, data) = catalog.table_data_mut(&name)?;
+
+        let targets = assignment_targets(&name, table, assignments)?;
+        let generated = GeneratedColumns::of(table)?;
+
+        // Compute every new row before changing any, so a failing row (e.g.
+        // an overflowing increment) leaves the table untouched. SET
+        // expressions see the row as it was before the update.
+        let mut updates = Vec::new();
+        for (row_id, mut row) in data.rows() {
+            ctx.tick()?;
+            if generated.has_virtual() {
+                generated.fill_virtual(row.to_mut())?;
+            }
+            if let Some(selection) = selection {
+                if !expr::is_true(&expr::evaluate(selection, &row)?) {
+                    continue;
+                }
+            }
+
+            let mut updated = row.as_ref().clone();
+            assign(&targets, &row, &mut updated)?;
+            generated.store(&mut updated)?;
+            updates.push((row_id, updated));
+        }
+
+        let updated_ids: HashSet<u64> = updates.iter().map(|(row_id, _)| *row_id).collect();
+        self.check_unlocked(&name, updated_ids.iter().copied(), ctx)?;
+        let new_rows: Vec<Row> = updates.iter().map(|(_, row)| row.clone()).collect();
+        check_unique_keys(table, data, &updated_ids, &new_rows)?;
+        self.changes.check_room(new_rows.len())?;
+        if headroom.is_limited() {
+            headroom.check(0, size_change(data, &updates), 0)?;
+        }
+
+        // Never hand out an id that an UPDATE assigned explicitly
+        let sequence = table
+            .columns
+            .iter()
+            .find(|c| c.auto_increment)
+            .and_then(|column| {
+                new_rows
+                    .iter()
+                    .filter_map(|row| row[&column.name].as_i64())
+                    .max()
+            });
+
+        let affected = updates.len();
+        let changes = self.changes.changes(&name, ChangeKind::Update, &new_rows);
+        for (row_id, row) in updates {
+            data.update(row_id, row);
+        }
+        if let Some(sequence) = sequence {
+            data.advance_sequence(sequence);
+        }
+        refresh_stale_stats(&mut catalog, &name)?;
+        catalog.evict_history();
+        self.changes.publish(changes);
+
+        Ok(QueryResult {
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: affected,
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Execute DELETE
+    fn execute_delete(
+        &self,
+        from: &[TableWithJoins],
+        selection: Option<&Expr>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let name = match from {
+            [TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                joins,
+            }] if joins.is_empty() => object_name(name),
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "DELETE must target a single table".to_string(),
+                ))
+            }
+        };
+        if as_of(&from[0].relation)?.is_some() {
+            return Err(QubeError::QueryParse(
+                "Rows cannot be deleted from a table read AS OF a past moment".to_string(),
+            ));
+        }
+
+        let mut catalog = self.catalog.write().unwrap();
+        catalog.check_writable(&name)?;
+        let doomed = find_doomed(&catalog, &name, from, selection, ctx)?;
+        self.record_scans(ctx);
+        self.check_unlocked(&name, doomed.iter().copied(), ctx)?;
+        self.changes.check_room(doomed.len())?;
+
+        let removed = catalog.table_data_mut(&name)?.1.remove_many(&doomed);
+        let changes = self.changes.changes(&name, ChangeKind::Delete, &removed);
+        refresh_stale_stats(&mut catalog, &name)?;
+        catalog.evict_history();
+        self.changes.publish(changes);
+
+        Ok(QueryResult {
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: removed.len(),
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Fail, noting the holder in `ctx.blocked_by`, if another transaction
+    /// holds any of these rows of `table` locked
+    fn check_unlocked(
+        &self,
+        table: &str,
+        row_ids: impl Iterator<Item = u64>,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<()> {
+        let row_ids: Vec<u64> = row_ids.collect();
+        if let Some(holder) = self.locks.holder(ctx.lock_owner, table, &row_ids) {
+            ctx.blocked_by = Some(holder);
+            return Err(QubeError::Transaction(format!(
+                "rows of '{}' are locked by transaction {}",
+                table, holder
+            )));
+        }
+        Ok(())
+    }
+
+    /// Lock the rows a `SELECT ... FOR UPDATE` reads for the transaction
+    /// `owner`, waiting for any other transaction holding one of them.
+    /// Returns how many rows were locked.
+    pub(crate) fn lock_rows(
+        &self,
+        owner: LockOwner,
+        query: &Query,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<usize> {
+        let select = match query.body.as_ref() {
+            SetExpr::Select(select) => select,
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "FOR UPDATE needs a plain SELECT".to_string(),
+                ))
+            }
+        };
+        let name = match select.from.as_slice() {
+            [TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                joins,
+            }] if joins.is_empty() => object_name(name),
+            _ => {
+                return Err(QubeError::QueryParse(
+                    "FOR UPDATE must read a single table".to_string(),
+                ))
+            }
+        };
+
+        loop {
+            let row_ids = {
+                let catalog = self.catalog.read().unwrap();
+                let table = catalog.get_table(&name)?;
+                let data = catalog.table_data(&name)?;
+                let generated = GeneratedColumns::of(table)?;
+                let mut row_ids = Vec::new();
+                for (row_id, mut row) in data.rows() {
+                    ctx.tick()?;
+                    if generated.has_virtual() {
+                        generated.fill_virtual(row.to_mut())?;
+                    }
+                    if let Some(selection) = &select.selection {
+                        if !expr::is_true(&expr::evaluate(selection, &row)?) {
+                            continue;
+                        }
+                    }
+                    row_ids.push(row_id);
+                }
+                row_ids
+            };
+            match self.locks.try_lock(owner, &name, &row_ids) {
+                Some(holder) => self.locks.wait(Some(owner), holder, ctx)?,
+                None => return Ok(row_ids.len()),
+            }
+        }
+    }
+
+    /// Insert a single row given as column/value pairs, with the same type
+    /// coercion, defaults and constraint checks as `INSERT`
+    pub fn insert_row(&self, table: &str, row: &Row) -> QubeResult<QueryResult> {
+        let mut columns: Vec<&String> = row.keys().collect();
+        columns.sort();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            Ident::with_quote('"', table),
+            columns
+                .iter()
+                .map(|column| Ident::with_quote('"', column.as_str()).to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            columns
+                .iter()
+                .map(|column| expr::to_literal(&row[*column]).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        self.execute_statement(self.parse_sql(&sql)?, &mut ctx)
+    }
+
+    /// Register a table definition, e.g. one made with `TableBuilder`. An
+    /// engine with a write-ahead log logs it as the statements that create
+    /// it, so it is there again when the engine is reopened.
+    pub fn create_table(&self, table: Table) -> QubeResult<()> {
+        GeneratedColumns::of(&table)?;
+        let _logging = self
+            .wal
+            .as_ref()
+            .map(|_| self.checkpointing.read().unwrap());
+        let mut catalog = self.catalog.write().unwrap();
+        let name = table.name.clone();
+        catalog.create_table(table)?;
+        if self.wal.is_some() {
+            let table = catalog.get_table(&name)?;
+            let mut statements = vec![dump::create_table(table, catalog.table_data(&name)?)];
+            statements.extend(dump::create_indexes(table));
+            self.log_write(&statements.join(";\n"), Durability::default())?;
+        }
+        Ok(())
+    }
+
+    /// Collect statistics for a table
+    pub fn analyze_table(&self, name: &str) -> QubeResult<TableStats> {
+        let mut catalog = self.catalog.write().unwrap();
+        catalog.analyze_table(name).cloned()
+    }
+
+    /// Re-collect statistics of every table that has changed enough since
+    /// they were last collected, returning how many were refreshed
+    pub fn refresh_stale_stats(&self) -> QubeResult<usize> {
+        let mut catalog = self.catalog.write().unwrap();
+        let names: Vec<String> = catalog
+            .list_tables()
+            .into_iter()
+            .map(|table| table.name.clone())
+            .collect();
+        let mut refreshed = 0;
+        for name in names {
+            if refresh_stale_stats(&mut catalog, &name)? {
+                refreshed += 1;
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Get the last collected statistics for a table
+    pub fn table_stats(&self, name: &str) -> QubeResult<Option<TableStats>> {
+        let catalog = self.catalog.read().unwrap();
+        Ok(catalog.table_data(name)?.stats.clone())
+    }
+
+    /// Get the counts of how SELECTs have read rows so far
+    pub fn scan_metrics(&self) -> ScanMetrics {
+        *self.scans.lock().unwrap()
+    }
+
+    /// Get chunk counters for a columnar table, or None for a row table
+    pub fn column_store_stats(&self, name: &str) -> QubeResult<Option<ColumnStoreStats>> {
+        let catalog = self.catalog.read().unwrap();
+        Ok(catalog.table_data(name)?.column_store_stats())
+    }
+
+    /// Drop an index's entries and rebuild them by scanning its table,
+    /// repairing any drift `verify_indexes` reports. The rebuild holds the
+    /// catalog's write lock, so no reader ever sees a half-built index.
+    pub fn rebuild_index(&self, name: &str) -> QubeResult<()> {
+        let mut catalog = self.catalog.write().unwrap();
+        let table = catalog.rebuild_index(name)?;
+        tracing::info!("Rebuilt index {} on {}", name, table);
+        Ok(())
+    }
+
+    /// Rebuild every index of a table, as `rebuild_index` does. Returns
+    /// how many were rebuilt.
+    pub fn reindex_table(&self, name: &str) -> QubeResult<usize> {
+        let mut catalog = self.catalog.write().unwrap();
+        let rebuilt = catalog.rebuild_table_indexes(name)?;
+        tracing::info!("Rebuilt {} index(es) on {}", rebuilt, name);
+        Ok(rebuilt)
+    }
+
+    /// Reclaim the space that updates and deletes left behind in `table`,
+    /// or in every table when `None`: partly empty column chunks are
+    /// refilled, B-Tree indexes rebuilt from the live rows and past row
+    /// versions outside the history retention dropped. If the
+    /// engine has a write-ahead log, it is then checkpointed to a dump of
+    /// the whole database, dropping the history of every row. Writes wait
+    /// until the VACUUM is done.
+    pub fn vacuum(&self, table: Option<&str>) -> QubeResult<VacuumStats> {
+        let start = Instant::now();
+        let _checkpoint = self.checkpointing.write().unwrap();
+        let mut catalog = self.catalog.write().unwrap();
+        let names: Vec<String> = match table {
+            Some(name) => vec![catalog.get_table(name)?.name.clone()],
+            None => catalog
+                .list_tables()
+                .into_iter()
+                .map(|table| table.name.clone())
+                .collect(),
+        };
+        let mut stats = VacuumStats::default();
+        for name in &names {
+            let (table, data) = catalog.table_data_mut(name)?;
+            stats.rows_scanned += data.vacuum(table);
+            stats.versions_reclaimed += data.reclaim_history();
+            stats.tables += 1;
+        }
+        if let Some(wal) = &self.wal {
+            stats.bytes_reclaimed = wal.checkpoint(dump::dump(&catalog).as_bytes())?;
+        }
+        stats.duration = start.elapsed();
+        tracing::info!(
+            "Vacuumed {} table(s), {} row(s), reclaimed {} version(s) and {} bytes",
+            stats.tables,
+            stats.rows_scanned,
+            stats.versions_reclaimed,
+            stats.bytes_reclaimed
+        );
+        Ok(stats)
+    }
+
+    /// Suggest indexes for a workload of SELECT queries: B-Tree indexes
+    /// that would spare the full table scans their plans make, each with
+    /// its `CREATE INDEX` statement and the rows it would save reading,
+    /// most beneficial first. See `advisor`.
+    pub fn advise_indexes(&self, workload: &[&str]) -> QubeResult<Vec<IndexRecommendation>> {
+        let mut recommendations = Vec::new();
+        for sql in workload {
+            let mut statement = self.parse_sql(sql)?;
+            namespace::qualify(&mut statement, DEFAULT_DATABASE);
+            let Statement::Query(query) = &statement else {
+                return Err(QubeError::QueryParse(format!(
+                    "The index advisor only supports SELECT: {}",
+                    sql
+                )));
+            };
+            let SetExpr::Select(select) = &*query.body else {
+                return Err(QubeError::Unsupported(format!("query {}", query.body)));
+            };
+
+            let mut ctx = ExecutionContext::new(&QueryOptions::default());
+            let ctes = match &query.with {
+                Some(with) => self.materialize_ctes(with, &[], &mut ctx)?,
+                None => Vec::new(),
+            };
+            let ctes = self.expand_views(&select.from, &ctes, &mut ctx)?;
+            let catalog = self.catalog.read().unwrap();
+            let past = read_past(&catalog, &ctes, &select.from)?;
+            let relations = resolve_relations(&catalog, &ctes, &past, &select.from)?;
+            let plan = plan_select(&relations, select)?;
+            // CTEs, views and tables read AS OF hold rows no index can be
+            // built on
+            let indexable: Vec<bool> = relations
+                .iter()
+                .map(|relation| {
+                    catalog
+                        .table_data(&relation.table.name)
+                        .is_ok_and(|data| std::ptr::eq(data, relation.data))
+                })
+                .collect();
+            recommendations.extend(advisor::advise(
+                &relations,
+                &plan,
+                &indexable,
+                |relations| plan_select(relations, select),
+            )?);
+        }
+        Ok(advisor::merge(recommendations))
+    }
+
+    /// Check every table's B-Tree indexes against its rows, reporting any
+    /// entry that points at a missing row or at a row holding a different
+    /// key, and any row missing from an index. An empty report means the
+    /// indexes are consistent.
+    pub fn verify_indexes(&self) -> Vec<IndexIssue> {
+        let catalog = self.catalog.read().unwrap();
+        let mut issues = Vec::new();
+        for table in catalog.list_tables() {
+            if let Ok(data) = catalog.table_data(&table.name) {
+                issues.extend(data.verify_indexes(&table.name));
+            }
+        }
+        for issue in &issues {
+            tracing::warn!("Index inconsistency: {}", issue);
+        }
+        issues
+    }
+
+    /// Execute ANALYZE TABLE
+    fn execute_analyze(&self, table_name: &ObjectName) -> QubeResult<QueryResult> {
+        let name = object_name(table_name);
+        self.analyze_table(&name)?;
+        self.execute_show_stats(&name)
+    }
+
+    /// Execute SHOW STATS FROM table
+    fn execute_show_stats(&self, table_name: &str) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let catalog = self.catalog.read().unwrap();
+        let table = catalog.get_table(table_name)?;
+        let stats = match &catalog.table_data(table_name)?.stats {
+            Some(stats) => stats,
+            None => {
+                return Ok(rows_result(STATS_COLUMNS.to_vec(), vec![], start_time));
+            }
+        };
+
+        let rows = table
+            .columns
+            .iter()
+            .filter_map(|column| {
+                let column_stats = stats.columns.get(&column.name)?;
+                Some(
+                    vec![
+                        ("column".to_string(), Value::String(column.name.clone())),
+                        (
+                            "row_count".to_string(),
+                            Value::Int64(stats.row_count as i64),
+                        ),
+                        (
+                            "distinct_count".to_string(),
+                            Value::Int64(column_stats.distinct_count as i64),
+                        ),
+                        (
+                            "null_count".to_string(),
+                            Value::Int64(column_stats.null_count as i64),
+                        ),
+                        (
+                            "min".to_string(),
+                            column_stats.min.clone().unwrap_or(Value::Null),
+                        ),
+                        (
+                            "max".to_string(),
+                            column_stats.max.clone().unwrap_or(Value::Null),
+                        ),
+                    ]
+                    .into_iter()
+                    .collect::<Row>(),
+                )
+            })
+            .collect();
+
+        Ok(rows_result(STATS_COLUMNS.to_vec(), rows, start_time))
+    }
+
+    /// Execute CREATE TABLE
+    fn execute_create_table(
+        &self,
+        name: &ObjectName,
+        columns: &[ColumnDef],
+        constraints: &[TableConstraint],
+        options: &[SqlOption],
+        if_not_exists: bool,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let mut table = build_table(object_name(name), columns, constraints)?;
+        // Last value the auto-increment column already handed out
+        let mut sequence = 0;
+        // History retention of the table's own, see `catalog`
+        let mut history_retention = None;
+        let mut history_versions = None;
+        for option in options {
+            match (option.name.value.to_lowercase().as_str(), &option.value) {
+                ("storage", SqlValue::SingleQuotedString(layout)) => {
+                    table.storage = StorageLayout::parse(layout)?;
+                }
+                ("auto_increment", SqlValue::Number(value, _)) => {
+                    sequence = value.parse().map_err(|_| {
+                        QubeError::QueryParse(format!("Invalid auto_increment value: {}", value))
+                    })?;
+                }
+                ("history_retention", SqlValue::Number(seconds, _)) => {
+                    let duration = seconds
+                        .parse()
+                        .ok()
+                        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                        .ok_or_else(|| {
+                            QubeError::QueryParse(format!(
+                                "Invalid history_retention value: {}",
+                                seconds
+                            ))
+                        })?;
+                    history_retention = Some(duration);
+                }
+                ("history_versions", SqlValue::Number(count, _)) => {
+                    history_versions = Some(count.parse().map_err(|_| {
+                        QubeError::QueryParse(format!("Invalid history_versions value: {}", count))
+                    })?);
+                }
+                _ => return Err(QubeError::Unsupported(format!("table option {}", option))),
+            }
+        }
+
+        GeneratedColumns::of(&table)?;
+
+        let mut catalog = self.catalog.write().unwrap();
+        if if_not_exists && catalog.has_table(&table.name) {
+            return Ok(empty_result(start_time));
+        }
+        let name = table.name.clone();
+        catalog.create_table(table)?;
+        if sequence > 0 {
+            catalog.table_data_mut(&name)?.1.advance_sequence(sequence);
+        }
+        if history_retention.is_some() || history_versions.is_some() {
+            let default = catalog.history_retention();
+            let retention = HistoryRetention {
+                duration: history_retention.unwrap_or(default.duration),
+                max_versions: history_versions.or(default.max_versions),
+            };
+            catalog.set_table_history_retention(&name, Some(retention))?;
+        }
+
+        Ok(empty_result(start_time))
+    }
+
+    /// Execute CREATE INDEX
+    fn execute_create_index(
+        &self,
+        name: Option<&ObjectName>,
+        table_name: &ObjectName,
+        using: Option<&Ident>,
+        columns: &[OrderByExpr],
+        unique: bool,
+        if_not_exists: bool,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let table = object_name(table_name);
+
+        let columns = columns
+            .iter()
+            .map(|column| match &column.expr {
+                Expr::Identifier(ident) => Ok(ident.value.clone()),
+                other => Err(QubeError::Unsupported(format!(
+                    "index expression {}",
+                    other
+                ))),
+            })
+            .collect::<QubeResult<Vec<_>>>()?;
+
+        let index_type = match using.map(|ident| ident.value.to_lowercase()).as_deref() {
+            None | Some("btree") => IndexType::BTree,
+            Some("hash") => IndexType::Hash,
+            Some("vector") => IndexType::Vector,
+            Some("fulltext") => IndexType::FullText,
+            Some("spatial") => IndexType::Spatial,
+            Some(other) => return Err(QubeError::Unsupported(format!("index type {}", other))),
+        };
+
+        let name = match name {
+            Some(name) => object_name(name),
+            None => format!("{}_{}_idx", table, columns.join("_")),
+        };
+
+        let mut catalog = self.catalog.write().unwrap();
+        let index = Index {
+            name,
+            columns,
+            index_type,
+            unique,
+        };
+        match catalog.create_index(&table, index) {
+            Err(QubeError::AlreadyExists(_)) if if_not_exists => {}
+            result => result?,
+        }
+
+        Ok(empty_result(start_time))
+    }
+
+    /// Execute DROP TABLE / DROP VIEW / DROP INDEX
+    fn execute_drop(
+        &self,
+        object_type: ObjectType,
+        names: &[ObjectName],
+        if_exists: bool,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let mut catalog = self.catalog.write().unwrap();
+
+        for name in names {
+            let name = object_name(name);
+            let result = match object_type {
+                ObjectType::Table if catalog.view(&name).is_some() => {
+                    return Err(QubeError::QueryParse(format!(
+                        "'{}' is a view; drop it with DROP VIEW",
+                        name
+                    )))
+                }
+                ObjectType::Table => catalog.drop_table(&name).map(|_| ()),
+                ObjectType::View => catalog.drop_view(&name).map(|_| ()),
+                ObjectType::Index => catalog.drop_index(&name).map(|_| ()),
+                ObjectType::Schema => catalog.drop_database(&name).map(|_| ()),
+                other => return Err(QubeError::Unsupported(format!("DROP {}", other))),
+            };
+            match result {
+                Err(QubeError::TableNotFound(_))
+                | Err(QubeError::NotFound(_))
+                | Err(QubeError::DatabaseNotFound(_))
+                    if if_exists => {}
+                result => result?,
+            }
+        }
+
+        Ok(empty_result(start_time))
+    }
+
+    /// Execute CREATE VIEW, storing its query, or CREATE MATERIALIZED
+    /// VIEW, storing the result of its query, refreshed as `refresh` says
+    fn execute_create_view(
+        &self,
+        name: &ObjectName,
+        columns: &[Ident],
+        query: &Query,
+        refresh: Option<ViewRefresh>,
+        or_replace: bool,
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let name = object_name(name);
+        let view = View::new(name.clone(), columns.to_vec(), query.clone(), refresh);
+        if self.catalog.read().unwrap().view_reads(&view, &name) {
+            return Err(QubeError::QueryParse(format!(
+                "View '{}' cannot read itself",
+                name
+            )));
+        }
+        // A view that is not materialized still runs its query once, so
+        // that one which cannot run is not created
+        let CommonTable { table, data } = self.run_view(&view, ctx)?;
+
+        let mut catalog = self.catalog.write().unwrap();
+        if or_replace && catalog.view(&name).is_some() {
+            catalog.drop_view(&name)?;
+        }
+        if view.is_materialized() {
+            let rows = data.rows().map(|(_, row)| row.into_owned());
+            catalog.create_materialized_view(view, table, rows)?;
+        } else {
+            catalog.create_view(view)?;
+        }
+        Ok(empty_result(start_time))
+    }
+
+    /// Execute REFRESH MATERIALIZED VIEW, reporting the rows the view now
+    /// holds
+    fn execute_refresh(&self, name: &str, ctx: &mut ExecutionContext) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let view = self
+            .catalog
+            .read()
+            .unwrap()
+            .view(name)
+            .filter(|view| view.is_materialized())
+            .cloned()
+            .ok_or_else(|| QubeError::NotFound(format!("Materialized view '{}'", name)))?;
+        let mut result = empty_result(start_time);
+        result.affected_rows = self.refresh_view(&view, ctx)?;
+        result.execution_time = start_time.elapsed();
+        Ok(result)
+    }
+
+    /// Run the query of a view, returning its result as a table named
+    /// after the view
+    fn run_view(&self, view: &View, ctx: &mut ExecutionContext) -> QubeResult<CommonTable> {
+        let result = self.execute_select(&view.query, &[], ctx)?;
+        if !view.columns.is_empty() && view.columns.len() != result.columns.len() {
+            return Err(QubeError::QueryParse(format!(
+                "View '{}' returns {} columns but {} are named",
+                view.name,
+                result.columns.len(),
+                view.columns.len()
+            )));
+        }
+        CommonTable::new(&view.name, &view.columns, result)
+    }
+
+    /// Replace the rows of the materialized `view` with what its query
+    /// returns now, then refresh the views refreshed on write that read
+    /// it. Returns how many rows the view holds.
+    fn refresh_view(&self, view: &View, ctx: &mut ExecutionContext) -> QubeResult<usize> {
+        let CommonTable { table, data } = self.run_view(view, ctx)?;
+        let count = data.len();
+        let rows = data.rows().map(|(_, row)| row.into_owned());
+        self.catalog.write().unwrap().refresh_view(table, rows)?;
+        self.refresh_dependents(&view.name, ctx)?;
+        Ok(count)
+    }
+
+    /// Refresh the views refreshed on write that read `table`, directly or
+    /// through views that are not materialized, after it was written
+    fn refresh_dependents(&self, table: &str, ctx: &mut ExecutionContext) -> QubeResult<()> {
+        let views: Vec<View> = {
+            let catalog = self.catalog.read().unwrap();
+            catalog
+                .views()
+                .iter()
+                .filter(|view| {
+                    view.materialized == Some(ViewRefresh::OnWrite)
+                        && changes_with(&catalog, view, table)
+                })
+                .cloned()
+                .collect()
+        };
+        for view in &views {
+            self.refresh_view(view, ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Execute CREATE DATABASE / CREATE SCHEMA
+    fn execute_create_database(
+        &self,
+        name: &ObjectName,
+        if_not_exists: bool,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let name = object_name(name);
+        match self.catalog.write().unwrap().create_database(&name) {
+            Err(QubeError::AlreadyExists(_)) if if_not_exists => {}
+            result => result?,
+        }
+        Ok(empty_result(start_time))
+    }
+
+    /// Execute SHOW TABLES [FROM database], listing the tables of one
+    /// database by their names within it
+    fn execute_show_tables(
+        &self,
+        database: Option<&Ident>,
+        filter: Option<&ShowStatementFilter>,
+    ) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let catalog = self.catalog.read().unwrap();
+        let database = database.map_or(DEFAULT_DATABASE, |ident| ident.value.as_str());
+        if !catalog.has_database(database) {
+            return Err(QubeError::DatabaseNotFound(database.to_string()));
+        }
+
+        let rows = catalog
+            .list_tables()
+            .into_iter()
+            .filter_map(|table| match namespace::split(&table.name) {
+                (db, name) if db == database => Some(name),
+                _ => None,
+            })
+            .filter(|name| match filter {
+                None => true,
+                Some(ShowStatementFilter::Like(pattern)) => expr::like_match(pattern, name),
+                Some(ShowStatementFilter::ILike(pattern)) => {
+                    expr::like_match(&pattern.to_lowercase(), &name.to_lowercase())
+                }
+                Some(_) => false,
+            })
+            .map(|name| {
+                vec![("table_name".to_string(), Value::String(name.to_string()))]
+                    .into_iter()
+                    .collect::<Row>()
+            })
+            .collect();
+
+        Ok(rows_result(vec!["table_name"], rows, start_time))
+    }
+
+    /// Execute DESCRIBE table / SHOW COLUMNS FROM table
+    fn execute_describe(&self, table_name: &ObjectName) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let catalog = self.catalog.read().unwrap();
+        let table = catalog.get_table(&object_name(table_name))?;
+
+        let rows = table
+            .columns
+            .iter()
+            .map(|column| {
+                vec![
+                    ("name".to_string(), Value::String(column.name.clone())),
+                    (
+                        "type".to_string(),
+                        Value::String(column.data_type.to_string()),
+                    ),
+                    ("nullable".to_string(), Value::Boolean(column.nullable)),
+                    (
+                        "key".to_string(),
+                        Value::String(column_key(table, column).to_string()),
+                    ),
+                ]
+                .into_iter()
+                .collect::<Row>()
+            })
+            .collect();
+
+        Ok(rows_result(
+            vec!["name", "type", "nullable", "key"],
+            rows,
+            start_time,
+        ))
+    }
+
+    /// Execute MySQL-style SHOW commands that sqlparser does not model,
+    /// such as `SHOW INDEXES FROM t`
+    fn execute_show(
+        &self,
+        variable: &[Ident],
+        ctx: &mut ExecutionContext,
+    ) -> QubeResult<QueryResult> {
+        let words: Vec<String> = variable
+            .iter()
+            .map(|ident| ident.value.to_uppercase())
+            .collect();
+
+        match words
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            ["DATABASES" | "SCHEMAS"] => {
+                let start_time = std::time::Instant::now();
+                let rows = self
+                    .list_databases()
+                    .into_iter()
+                    .map(|name| {
+                        vec![("database_name".to_string(), Value::String(name))]
+                            .into_iter()
+                            .collect::<Row>()
+                    })
+                    .collect();
+                Ok(rows_result(vec!["database_name"], rows, start_time))
+            }
+            // A qualified name `db.t` arrives as the two words `db t`
+            ["INDEX" | "INDEXES" | "KEYS", "FROM" | "IN", _]
+            | ["INDEX" | "INDEXES" | "KEYS", "FROM" | "IN", _, _] => {
+                self.execute_show_indexes(&shown_name(&variable[2..]))
+            }
+            ["STATS" | "STATISTICS", "FROM" | "IN", _]
+            | ["STATS" | "STATISTICS", "FROM" | "IN", _, _] => {
+                self.execute_show_stats(&shown_name(&variable[2..]))
+            }
+            ["REINDEX", "TABLE", _] | ["REINDEX", "TABLE", _, _] => {
+                self.execute_reindex(None, &shown_name(&variable[2..]))
+            }
+            ["REINDEX", "INDEX", _] | ["REINDEX", "INDEX", _, _] => {
+                self.execute_reindex(Some(&shown_name(&variable[2..])), "")
+            }
+            ["VACUUM"] => self.execute_vacuum(None),
+            ["VACUUM", "TABLE", _] | ["VACUUM", "TABLE", _, _] => {
+                self.execute_vacuum(Some(&shown_name(&variable[2..])))
+            }
+            ["REFRESH", "VIEW", _] | ["REFRESH", "VIEW", _, _] => {
+                self.execute_refresh(&shown_name(&variable[2..]), ctx)
+            }
+            _ => Err(QubeError::Unsupported(format!("SHOW {}", words.join(" ")))),
+        }
+    }
+
+    /// Execute `REINDEX INDEX name` (when `index` is given) or `REINDEX
+    /// TABLE table`
+    fn execute_reindex(&self, index: Option<&str>, table: &str) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let rebuilt = match index {
+            Some(index) => self.rebuild_index(index).map(|_| 1)?,
+            None => self.reindex_table(table)?,
+        };
+        Ok(QueryResult {
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: rebuilt,
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Execute `VACUUM [table]`, returning what it did as one row
+    fn execute_vacuum(&self, table: Option<&str>) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let stats = self.vacuum(table)?;
+        let row: Row = [
+            ("tables", Value::Int64(stats.tables as i64)),
+            ("rows_scanned", Value::Int64(stats.rows_scanned as i64)),
+            (
+                "versions_reclaimed",
+                Value::Int64(stats.versions_reclaimed as i64),
+            ),
+            (
+                "bytes_reclaimed",
+                Value::Int64(stats.bytes_reclaimed as i64),
+            ),
+            (
+                "duration_ms",
+                Value::Int64(stats.duration.as_millis() as i64),
+            ),
+        ]
+        .into_iter()
+        .map(|(column, value)| (column.to_string(), value))
+        .collect();
+        Ok(rows_result(
+            vec![
+                "tables",
+                "rows_scanned",
+                "versions_reclaimed",
+                "bytes_reclaimed",
+                "duration_ms",
+            ],
+            vec![row],
+            start_time,
+        ))
+    }
+
+    /// Execute SHOW INDEXES FROM table
+    fn execute_show_indexes(&self, table_name: &str) -> QubeResult<QueryResult> {
+        let start_time = std::time::Instant::now();
+        let catalog = self.catalog.read().unwrap();
+        let table = catalog.get_table(table_name)?;
+
+        let rows = table
+            .indexes
+            .iter()
+            .map(|index| {
+                vec![
+                    ("table".to_string(), Value::String(table.name.clone())),
+                    ("name".to_string(), Value::String(index.name.clone())),
+                    (
+                        "columns".to_string(),
+                        Value::String(index.columns.join(", ")),
+                    ),
+                    (
+                        "type".to_string(),
+                        Value::String(index.index_type.to_string()),
+                    ),
+                    ("unique".to_string(), Value::Boolean(index.unique)),
+                ]
+                .into_iter()
+                .collect::<Row>()
+            })
+            .collect();
+
+        Ok(rows_result(
+            vec!["table", "name", "columns", "type", "unique"],
+            rows,
+            start_time,
+        ))
+    }
+
+    /// Execute GraphQL query
+    pub async fn execute_graphql(&self, _query: &str) -> QubeResult<QueryResult> {
+        // TODO: Implement GraphQL query execution
+        Err(QubeError::QueryParse(
+            "GraphQL queries not yet implemented".to_string(),
+        ))
+    }
+
+    /// Execute JSONPath query
+    pub async fn execute_jsonpath(
+        &self,
+        _jsonpath: &str,
+        _document: &serde_json::Value,
+    ) -> QubeResult<QueryResult> {
+        // TODO: Implement JSONPath query execution
+        Err(QubeError::QueryParse(
+            "JSONPath queries not yet implemented".to_string(),
+        ))
+    }
+
+    /// Execute vector similarity search
+    pub async fn execute_vector_search(
+        &self,
+        _collection: &str,
+        _query_vector: &[f32],
+        _limit: usize,
+    ) -> QubeResult<QueryResult> {
+        // TODO: Implement vector similarity search
+        Err(QubeError::QueryParse(
+            "Vector search not yet implemented".to_string(),
+        ))
+    }
+}
+
+impl Default for QueryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse a script of `;`-separated statements, folding unquoted identifiers
+/// to lower case
+pub(crate) fn parse_script(sql: &str) -> QubeResult<Vec<Statement>> {
+    let mut statements = Parser::parse_sql(&GenericDialect {}, sql)
+        .or_else(|e| parse_with_shorthands(sql).ok_or(e))
+        .map_err(|e| QubeError::QueryParse(format!("SQL parsing error: {}", e)))?;
+
+    for statement in &mut statements {
+        let _ = statement.visit(&mut IdentifierFolder);
+    }
+    Ok(statements)
+}
+
+/// Parse a script one statement at a time, rewriting each that sqlparser
+/// cannot parse through the shorthands: the PostgreSQL-style `ANALYZE t`
+/// alongside `ANALYZE TABLE t`, and `REINDEX`, `VACUUM`, `AS OF`,
+/// `DROP DATABASE`, `REFRESH` and `DROP MATERIALIZED VIEW` and virtual
+/// generated columns, which sqlparser lacks. `None` if a statement is
+/// invalid either way.
+fn parse_with_shorthands(sql: &str) -> Option<Vec<Statement>> {
+    let dialect = GenericDialect {};
+    let mut statements = Vec::new();
+    for statement in split_statements(sql)? {
+        let parsed = match Parser::parse_sql(&dialect, statement) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                let rewritten = analyze_shorthand(statement)
+                    .or_else(|| reindex_shorthand(statement))
+                    .or_else(|| vacuum_shorthand(statement))
+                    .or_else(|| as_of_shorthand(statement))
+                    .or_else(|| drop_database_shorthand(statement))
+                    .or_else(|| materialized_view_shorthand(statement))
+                    .or_else(|| virtual_column_shorthand(statement))?;
+                Parser::parse_sql(&dialect, &rewritten).ok()?
+            }
+        };
+        statements.extend(parsed);
+    }
+    Some(statements)
+}
+
+/// The text of each statement of a script, without the `;` between them.
+/// Statements holding nothing but whitespace and comments are left out.
+fn split_statements(sql: &str) -> Option<Vec<&str>> {
+    let tokens = Tokenizer::new(&GenericDialect {}, sql)
+        .tokenize_with_location()
+        .ok()?;
+    // Byte offset of the start of each line, as tokens are located by
+    // line and character
+    let lines: Vec<usize> = std::iter::once(0)
+        .chain(sql.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let offset = |token: &TokenWithLocation| {
+        let line = lines[token.location.line as usize - 1];
+        line + sql[line..]
+            .chars()
+            .take(token.location.column as usize - 1)
+            .map(char::len_utf8)
+            .sum::<usize>()
+    };
+
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut empty = true;
+    for token in &tokens {
+        match token.token {
+            Token::SemiColon => {
+                let end = offset(token);
+                if !empty {
+                    statements.push(&sql[start..end]);
+                }
+                start = end + 1;
+                empty = true;
+            }
+            Token::Whitespace(_) => {}
+            _ => empty = false,
+        }
+    }
+    if !empty {
+        statements.push(&sql[start..]);
+    }
+    Some(statements)
+}
+
+/// Build a result with no columns or rows, as returned by DDL statements
+fn empty_result(start_time: std::time::Instant) -> QueryResult {
+    QueryResult {
+        kind: ResultKind::Mutation,
+        columns: vec![],
+        rows: vec![],
+        affected_rows: 0,
+        last_insert_id: None,
+        execution_time: start_time.elapsed(),
+    }
+}
+
+/// Build a result set from rows
+fn rows_result(columns: Vec<&str>, rows: Vec<Row>, start_time: std::time::Instant) -> QueryResult {
+    QueryResult {
+        kind: ResultKind::Rows,
+        columns: columns.into_iter().map(String::from).collect(),
+        rows,
+        affected_rows: 0,
+        last_insert_id: None,
+        execution_time: start_time.elapsed(),
+    }
+}
+
+/// Columns returned by SHOW STATS and ANALYZE
+const STATS_COLUMNS: [&str; 6] = [
+    "column",
+    "row_count",
+    "distinct_count",
+    "null_count",
+    "min",
+    "max",
+];
+
+/// Whether a statement changes the database and so belongs in the log.
+/// VACUUM rewrites the log rather than being logged in it.
+fn is_write(statement: &Statement) -> bool {
+    !is_vacuum(statement) && Permission::required_for(statement) != Permission::Read
+}
+
+fn is_vacuum(statement: &Statement) -> bool {
+    ShowCommand::of(statement) == Some(ShowCommand::Vacuum)
+}
+
+/// A command sqlparser cannot parse, which the shorthands rewrite into a
+/// `SHOW <command> ...` statement for `execute_show` to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShowCommand {
+    Reindex,
+    Vacuum,
+    Refresh,
+}
+
+impl ShowCommand {
+    /// The command `statement` was rewritten from, if it was one
+    pub(crate) fn of(statement: &Statement) -> Option<Self> {
+        let Statement::ShowVariable { variable } = statement else {
+            return None;
+        };
+        let word = variable.first()?;
+        [
+            ("REINDEX", ShowCommand::Reindex),
+            ("VACUUM", ShowCommand::Vacuum),
+            ("REFRESH", ShowCommand::Refresh),
+        ]
+        .into_iter()
+        .find(|(keyword, _)| word.value.eq_ignore_ascii_case(keyword))
+        .map(|(_, command)| command)
+    }
+}
+
+/// Whether the result of `view`'s query changes when `table` does, as it
+/// reads `table` directly or through views that are not materialized
+fn changes_with(catalog: &Catalog, view: &View, table: &str) -> bool {
+    view.reads(table)
+        || view
+            .tables
+            .iter()
+            .filter_map(|name| catalog.view(name))
+            .any(|read| !read.is_materialized() && changes_with(catalog, read, table))
+}
+
+/// The table an INSERT, UPDATE or DELETE writes to
+fn written_table(statement: &Statement) -> Option<String> {
+    let factor = match statement {
+        Statement::Insert { table_name, .. } => return Some(object_name(table_name)),
+        Statement::Update { table, .. } => &table.relation,
+        Statement::Delete { from, .. } => &from.first()?.relation,
+        _ => return None,
+    };
+    match factor {
+        TableFactor::Table { name, .. } => Some(object_name(name)),
+        _ => None,
+    }
+}
+
+/// How much replacing rows with `updates` changes a table's estimated size
+fn size_change(data: &TableData, updates: &[(u64, Row)]) -> i64 {
+    updates
+        .iter()
+        .map(|(row_id, row)| {
+            let old = data
+                .row(*row_id, None)
+                .map_or(0, |old| quota::row_size(&old));
+            quota::row_size(row) as i64 - old as i64
+        })
+        .sum()
+}
+
+/// The name of the object a SHOW statement acts on, from its words
+fn shown_name(words: &[Ident]) -> String {
+    object_name(&ObjectName(words.to_vec()))
+}
+
+/// Rewrite `DROP DATABASE d`, which sqlparser cannot parse, into the
+/// equivalent `DROP SCHEMA d`
+fn drop_database_shorthand(sql: &str) -> Option<String> {
+    let mut words = sql.split_whitespace();
+    let (drop, database) = (words.next()?, words.next()?);
+    if !drop.eq_ignore_ascii_case("DROP") || !database.eq_ignore_ascii_case("DATABASE") {
+        return None;
+    }
+    Some(format!(
+        "DROP SCHEMA {}",
+        words.collect::<Vec<_>>().join(" ")
+    ))
+}
+
+/// Rewrite `REFRESH MATERIALIZED VIEW v` into the `SHOW REFRESH VIEW v`
+/// form the engine runs it as, and `DROP MATERIALIZED VIEW v`, which
+/// sqlparser cannot parse, into `DROP VIEW v`
+fn materialized_view_shorthand(sql: &str) -> Option<String> {
+    let words: Vec<&str> = sql
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect();
+    let is = |i: usize, keyword: &str| {
+        words
+            .get(i)
+            .is_some_and(|w| w.eq_ignore_ascii_case(keyword))
+    };
+    if !is(1, "MATERIALIZED") || !is(2, "VIEW") {
+        return None;
+    }
+    if is(0, "REFRESH") && words.len() == 4 {
+        Some(format!("SHOW REFRESH VIEW {}", words[3]))
+    } else if is(0, "DROP") {
+        Some(format!("DROP VIEW {}", words[3..].join(" ")))
+    } else {
+        None
+    }
+}
+
+/// Rewrite `ANALYZE t` into the `ANALYZE TABLE t` form sqlparser understands
+fn analyze_shorthand(sql: &str) -> Option<String> {
+    let trimmed = sql.trim_start();
+    let rest = trimmed.get(..8)?;
+    if !rest.eq_ignore_ascii_case("ANALYZE ") {
+        return None;
+    }
+    let rest = trimmed[8..].trim_start();
+    if rest
+        .get(..6)
+        .is_some_and(|word| word.eq_ignore_ascii_case("TABLE "))
+    {
+        return None;
+    }
+    Some(format!("ANALYZE TABLE {}", rest))
+}
+
+/// Rewrite `REINDEX [TABLE] t` and `REINDEX INDEX i`, which sqlparser
+/// cannot parse, into the `SHOW REINDEX ...` form the engine runs them as
+fn reindex_shorthand(sql: &str) -> Option<String> {
+    let mut words = sql.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("REINDEX") {
+        return None;
+    }
+    let target: Vec<&str> = words.collect();
+    match target.as_slice() {
+        [kind, name]
+            if kind.eq_ignore_ascii_case("TABLE") || kind.eq_ignore_ascii_case("INDEX") =>
+        {
+            Some(format!("SHOW REINDEX {} {}", kind.to_uppercase(), name))
+        }
+        [name] => Some(format!("SHOW REINDEX TABLE {}", name)),
+        _ => None,
+    }
+}
+
+/// Rewrite `VACUUM [[TABLE] t]`, which sqlparser cannot parse, into the
+/// `SHOW VACUUM ...` form the engine runs it as
+fn vacuum_shorthand(sql: &str) -> Option<String> {
+    let mut words = sql.trim().trim_end_matches(';').split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("VACUUM") {
+        return None;
+    }
+    let target: Vec<&str> = words.collect();
+    match target.as_slice() {
+        [] => Some("SHOW VACUUM".to_string()),
+        [kind, name] if kind.eq_ignore_ascii_case("TABLE") => {
+            Some(format!("SHOW VACUUM TABLE {}", name))
+        }
+        [name] => Some(format!("SHOW VACUUM TABLE {}", name)),
+        _ => None,
+    }
+}
+
+/// Rewrite `t AS OF TIMESTAMP '...'`, which sqlparser cannot parse, into
+/// the table hint `t WITH (qubedb_as_of = '...')`, which `resolve_relations`
+/// reads back. The moment may also be given in milliseconds since the Unix
+/// epoch.
+fn as_of_shorthand(sql: &str) -> Option<String> {
+    let tokens = Tokenizer::new(&GenericDialect {}, sql).tokenize().ok()?;
+    let is_word = |i: usize, keyword: &str| match &tokens[i] {
+        Token::Word(word) => word.value.eq_ignore_ascii_case(keyword),
+        _ => false,
+    };
+    let significant: Vec<usize> = (0..tokens.len())
+        .filter(|&i| !matches!(tokens[i], Token::Whitespace(_)))
+        .collect();
+    let mut out = String::with_capacity(sql.len());
+    let mut copied = 0;
+    for window in significant.windows(4) {
+        let &[as_, of, timestamp, moment] = window else {
+            continue;
+        };
+        if as_ < copied
+            || !is_word(as_, "AS")
+            || !is_word(of, "OF")
+            || !is_word(timestamp, "TIMESTAMP")
+            || !matches!(
+                tokens[moment],
+                Token::SingleQuotedString(_) | Token::Number(..)
+            )
+        {
+            continue;
+        }
+        for token in &tokens[copied..as_] {
+            out.push_str(&token.to_string());
+        }
+        out.push_str(&format!("WITH ({} = {})", AS_OF_HINT, tokens[moment]));
+        copied = moment + 1;
+    }
+    if copied == 0 {
+        return None;
+    }
+    for token in &tokens[copied..] {
+        out.push_str(&token.to_string());
+    }
+    Some(out)
+}
+
+/// Rewrite `GENERATED ALWAYS AS (expr) VIRTUAL`, which sqlparser cannot
+/// parse, into `GENERATED ALWAYS AS (expr) COMMENT '<marker>'`, which
+/// `build_table` reads back as a virtual column
+fn virtual_column_shorthand(sql: &str) -> Option<String> {
+    let tokens = Tokenizer::new(&GenericDialect {}, sql).tokenize().ok()?;
+    let mut rewritten = false;
+    let mut previous = None;
+    let mut out = String::with_capacity(sql.len());
+    for token in &tokens {
+        match token {
+            Token::Word(word)
+                if word.keyword == Keyword::VIRTUAL && previous == Some(&Token::RParen) =>
+            {
+                out.push_str(&format!("COMMENT '{}'", VIRTUAL_MARKER));
+                rewritten = true;
+            }
+            token => out.push_str(&token.to_string()),
+        }
+        if !matches!(token, Token::Whitespace(_)) {
+            previous = Some(token);
+        }
+    }
+    rewritten.then_some(out)
+}
+
+/// Fold an unquoted identifier to lower case so `Users`, `USERS` and `users`
+/// name the same object. Quoted identifiers keep their exact spelling.
+fn fold_ident(ident: &mut Ident) {
+    if ident.quote_style.is_none() {
+        ident.value = ident.value.to_lowercase();
+    }
+}
+
+fn fold_idents(idents: &mut [Ident]) {
+    idents.iter_mut().for_each(fold_ident);
+}
+
+fn fold_alias(alias: &mut Option<TableAlias>) {
+    if let Some(alias) = alias {
+        fold_ident(&mut alias.name);
+        fold_idents(&mut alias.columns);
+    }
+}
+
+/// Fold the output column aliases and CTE names a query defines
+fn fold_query_aliases(query: &mut Query) {
+    if let Some(with) = &mut query.with {
+        for cte in &mut with.cte_tables {
+            fold_ident(&mut cte.alias.name);
+            fold_idents(&mut cte.alias.columns);
+        }
+    }
+    fold_set_expr_aliases(&mut query.body);
+}
+
+fn fold_set_expr_aliases(body: &mut SetExpr) {
+    match body {
+        SetExpr::Select(select) => {
+            for item in &mut select.projection {
+                if let SelectItem::ExprWithAlias { alias, .. } = item {
+                    fold_ident(alias);
+                }
+            }
+        }
+        SetExpr::Query(query) => fold_query_aliases(query),
+        SetExpr::SetOperation { left, right, .. } => {
+            fold_set_expr_aliases(left);
+            fold_set_expr_aliases(right);
+        }
+        _ => {}
+    }
+}
+
+/// Rewrites every table, column, index and alias name in a statement to its
+/// canonical case right after parsing, so the rest of the engine can compare
+/// names exactly
+struct IdentifierFolder;
+
+impl VisitorMut for IdentifierFolder {
+    type Break = ();
+
+    fn pre_visit_relation(&mut self, relation: &mut ObjectName) -> ControlFlow<()> {
+        fold_idents(&mut relation.0);
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &mut TableFactor) -> ControlFlow<()> {
+        match table_factor {
+            TableFactor::Table { alias, .. } | TableFactor::NestedJoin { alias, .. } => {
+                fold_alias(alias)
+            }
+            TableFactor::Derived {
+                subquery, alias, ..
+            } => {
+                fold_query_aliases(subquery);
+                fold_alias(alias);
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Identifier(ident) => fold_ident(ident),
+            Expr::CompoundIdentifier(idents) => fold_idents(idents),
+            Expr::Subquery(query)
+            | Expr::Exists {
+                subquery: query, ..
+            }
+            | Expr::InSubquery {
+                subquery: query, ..
+            } => fold_query_aliases(query),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &mut Statement) -> ControlFlow<()> {
+        match statement {
+            Statement::Query(query) => fold_query_aliases(query),
+            Statement::Insert {
+                columns, source, ..
+            } => {
+                fold_idents(columns);
+                fold_query_aliases(source);
+            }
+            Statement::Update { assignments, .. } => {
+                for assignment in assignments {
+                    fold_idents(&mut assignment.id);
+                }
+            }
+            Statement::CreateTable {
+                columns,
+                constraints,
+                ..
+            } => {
+                for column in columns {
+                    fold_ident(&mut column.name);
+                    for option in &mut column.options {
+                        if let ColumnOption::ForeignKey {
+                            foreign_table,
+                            referred_columns,
+                            ..
+                        } = &mut option.option
+                        {
+                            fold_idents(&mut foreign_table.0);
+                            fold_idents(referred_columns);
+                        }
+                    }
+                }
+                for constraint in constraints {
+                    match constraint {
+                        TableConstraint::Unique { name, columns, .. } => {
+                            name.iter_mut().for_each(fold_ident);
+                            fold_idents(columns);
+                        }
+                        TableConstraint::ForeignKey {
+                            name,
+                            columns,
+                            foreign_table,
+                            referred_columns,
+                            ..
+                        } => {
+                            name.iter_mut().for_each(fold_ident);
+                            fold_idents(columns);
+                            fold_idents(&mut foreign_table.0);
+                            fold_idents(referred_columns);
+                        }
+                        TableConstraint::Check { name, .. } => name.iter_mut().for_each(fold_ident),
+                        _ => {}
+                    }
+                }
+            }
+            Statement::CreateIndex {
+                name: Some(name), ..
+            } => fold_idents(&mut name.0),
+            Statement::Drop { names, .. } => {
+                for name in names {
+                    fold_idents(&mut name.0);
+                }
+            }
+            Statement::ShowVariable { variable } => fold_idents(variable),
+            Statement::CreateDatabase { db_name: name, .. }
+            | Statement::CreateSchema {
+                schema_name: SchemaName::Simple(name),
+                ..
+            } => fold_idents(&mut name.0),
+            Statement::Use { db_name } => fold_ident(db_name),
+            Statement::ShowTables {
+                db_name: Some(db_name),
+                ..
+            } => fold_ident(db_name),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// The tables of a FROM clause, in the order they are written
+fn table_factors(from: &[TableWithJoins]) -> impl Iterator<Item = &TableFactor> {
+    from.iter().flat_map(|item| {
+        std::iter::once(&item.relation).chain(item.joins.iter().map(|j| &j.relation))
+    })
+}
+
+/// The moment `t AS OF TIMESTAMP ...` reads a table as of, in milliseconds
+/// since the Unix epoch
+fn as_of(factor: &TableFactor) -> QubeResult<Option<i64>> {
+    let TableFactor::Table { with_hints, .. } = factor else {
+        return Ok(None);
+    };
+    for hint in with_hints {
+        if let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } = hint
+        {
+            if matches!(&**left, Expr::Identifier(ident) if ident.value == AS_OF_HINT) {
+                let at = expr::coerce(expr::evaluate(right, &Row::new())?, &DataType::Timestamp)?;
+                return Ok(at.as_i64());
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// For each table of a FROM clause, in order, its rows as of the moment
+/// it is read `AS OF`, or `None` if it is read as it is now
+fn read_past(
+    catalog: &Catalog,
+    ctes: &[Rc<CommonTable>],
+    from: &[TableWithJoins],
+) -> QubeResult<Vec<Option<TableData>>> {
+    table_factors(from)
+        .map(|factor| {
+            let Some(at) = as_of(factor)? else {
+                return Ok(None);
+            };
+            let TableFactor::Table { name, .. } = factor else {
+                return Ok(None);
+            };
+            let name = object_name(name);
+            if ctes.iter().any(|cte| cte.table.name == name) {
+                return Err(QubeError::Unsupported(format!(
+                    "AS OF on WITH query '{}'",
+                    name
+                )));
+            }
+            let table = catalog.get_table(&name)?;
+            catalog.table_data(&name)?.as_of(table, at).map(Some)
+        })
+        .collect()
+}
+
+/// Resolve the FROM clause into relations, in the order they are written.
+/// A bare name refers to the last of `ctes` by that name, if any, before
+/// the catalog's tables. A table read `AS OF` a past moment reads its
+/// entry of `past`, see `read_past`.
+fn resolve_relations<'a>(
+    catalog: &'a Catalog,
+    ctes: &'a [Rc<CommonTable>],
+    past: &'a [Option<TableData>],
+    from: &[TableWithJoins],
+) -> QubeResult<Vec<Relation<'a>>> {
+    let mut relations: Vec<Relation<'a>> = Vec::new();
+    for (position, factor) in table_factors(from).enumerate() {
+        let (name, alias) = match factor {
+            TableFactor::Table { name, alias, .. } => (object_name(name), alias),
+            other => return Err(QubeError::Unsupported(format!("table reference {}", other))),
+        };
+        let qualifier = alias
+            .as_ref()
+            .map(|alias| alias.name.value.clone())
+            .unwrap_or_else(|| name.clone());
+        if relations.iter().any(|r| r.qualifier == qualifier) {
+            return Err(QubeError::QueryParse(format!(
+                "Table name '{}' specified more than once",
+                qualifier
+            )));
+        }
+        if let Some(Some(data)) = past.get(position) {
+            relations.push(Relation::new(catalog.get_table(&name)?, qualifier, data));
+            continue;
+        }
+        match ctes.iter().rev().find(|cte| cte.table.name == name) {
+            Some(cte) => relations.push(Relation::new(&cte.table, qualifier, &cte.data)),
+            None => relations.push(Relation::new(
+                catalog.get_table(&name)?,
+                qualifier,
+                catalog.table_data(&name)?,
+            )),
+        }
+    }
+    Ok(relations)
+}
+
+/// Ids of the rows of `table` a DELETE removes. When the WHERE clause
+/// constrains an indexed column, only the rows the index finds are read.
+fn find_doomed(
+    catalog: &Catalog,
+    table: &str,
+    from: &[TableWithJoins],
+    selection: Option<&Expr>,
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<u64>> {
+    let schema = catalog.get_table(table)?;
+    let data = catalog.table_data(table)?;
+    let generated = GeneratedColumns::of(schema)?;
+    let qualifier = match &from[0].relation {
+        TableFactor::Table {
+            alias: Some(alias), ..
+        } => alias.name.value.clone(),
+        _ => table.to_string(),
+    };
+    let relation = Relation::new(schema, qualifier, data);
+    // A WHERE clause the planner can't use still decides which rows match
+    let plan = planner::plan(
+        std::slice::from_ref(&relation),
+        selection.into_iter().cloned().collect(),
+    )
+    .ok();
+    let lookup = match plan.as_ref().and_then(|plan| plan.steps[0].index.as_ref()) {
+        Some(scan) => index_lookup(data, scan)?,
+        None => None,
+    };
+    let candidates: Box<dyn Iterator<Item = (u64, Cow<Row>)>> = match lookup {
+        Some((_, entries)) => {
+            Box::new(entries.filter_map(|(_, row_id)| Some((row_id, data.row(row_id, None)?))))
+        }
+        None => Box::new(data.rows()),
+    };
+
+    let mut doomed = Vec::new();
+    for (row_id, mut row) in candidates {
+        ctx.tick()?;
+        ctx.scans.rows_fetched += 1;
+        if generated.has_virtual() {
+            generated.fill_virtual(row.to_mut())?;
+        }
+        if let Some(selection) = selection {
+            if !expr::is_true(&expr::evaluate(selection, &row)?) {
+                continue;
+            }
+        }
+        doomed.push(row_id);
+    }
+    Ok(doomed)
+}
+
+/// Gather the join and WHERE predicates of a SELECT and plan its joins
+fn plan_select(relations: &[Relation], select: &Select) -> QubeResult<QueryPlan> {
+    let tables: Vec<&str> = relations.iter().map(|r| r.table.name.as_str()).collect();
+    let _span = tracing::info_span!("plan", tables = ?tables).entered();
+    let mut predicates = Vec::new();
+    let mut position = 0;
+    for item in &select.from {
+        position += 1;
+        for join in &item.joins {
+            let constraint = match &join.join_operator {
+                JoinOperator::Inner(constraint) => constraint,
+                JoinOperator::CrossJoin => &JoinConstraint::None,
+                other => return Err(QubeError::Unsupported(format!("join type {:?}", other))),
+            };
+            let right = &relations[position];
+            match constraint {
+                JoinConstraint::On(on) => predicates.push(on.clone()),
+                JoinConstraint::Using(columns) => {
+                    for column in columns {
+                        let left = relations[..position]
+                            .iter()
+                            .find(|r| r.table.columns.iter().any(|c| c.name == column.value))
+                            .ok_or_else(|| QubeError::ColumnNotFound(column.value.clone()))?;
+                        predicates.push(Expr::BinaryOp {
+                            left: Box::new(Expr::CompoundIdentifier(vec![
+                                Ident::new(left.qualifier.clone()),
+                                column.clone(),
+                            ])),
+                            op: BinaryOperator::Eq,
+                            right: Box::new(Expr::CompoundIdentifier(vec![
+                                Ident::new(right.qualifier.clone()),
+                                column.clone(),
+                            ])),
+                        });
+                    }
+                }
+                JoinConstraint::None => {}
+                JoinConstraint::Natural => {
+                    return Err(QubeError::Unsupported("NATURAL joins".to_string()))
+                }
+            }
+            position += 1;
+        }
+    }
+    if let Some(selection) = &select.selection {
+        predicates.push(selection.clone());
+    }
+    planner::plan(relations, predicates)
+}
+
+/// Column names that appear in more than one relation
+fn ambiguous_columns(relations: &[Relation]) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut ambiguous = HashSet::new();
+    for relation in relations {
+        for column in &relation.table.columns {
+            if !seen.insert(column.name.as_str()) {
+                ambiguous.insert(column.name.clone());
+            }
+        }
+    }
+    ambiguous
+}
+
+/// Scan a table for a plan step, keeping rows that satisfy every filter and
+/// stopping once `limit` rows have been found. Reads only the rows the
+/// step's index lookup finds, if it has one, and answers from the index
+/// entries alone when the index covers the scan. A columnar table only
+/// reads `columns`.
+fn scan<'a>(
+    relation: &Relation<'a>,
+    step: &JoinStep,
+    columns: &BTreeSet<String>,
+    limit: Option<usize>,
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<Cow<'a, Row>>> {
+    let data = relation.data;
+    let generated = GeneratedColumns::of(relation.table)?;
+    // Virtual columns are computed from the rest of the row, so read it all
+    let all_columns;
+    let columns = if generated.has_virtual() {
+        all_columns = relation
+            .table
+            .columns
+            .iter()
+            .map(|c| c.name.clone())
+            .collect();
+        &all_columns
+    } else {
+        columns
+    };
+    let lookup = match &step.index {
+        Some(scan) => index_lookup(data, scan)?,
+        None => None,
+    };
+    let covering = step.index.as_ref().is_some_and(|scan| scan.covering);
+    let candidates: Box<dyn Iterator<Item = (Cow<'a, Row>, bool)>> = match lookup {
+        Some((index, entries)) if covering => Box::new(entries.map(|(key, _)| {
+            let row = index.columns().iter().cloned().zip(key.iter().cloned());
+            (Cow::Owned(row.collect()), true)
+        })),
+        Some((_, entries)) => Box::new(
+            entries
+                .filter_map(|(_, row_id)| data.row(row_id, Some(columns)))
+                .map(|row| (row, false)),
+        ),
+        None => Box::new(data.rows_with(Some(columns)).map(|(_, row)| (row, false))),
+    };
+
+    // A large scan without a limit filters its rows on several threads
+    if limit.is_none() && ctx.parallel.partitions(data.len()) > 1 {
+        let candidates: Vec<_> = candidates.collect();
+        let read = candidates.len();
+        let shared = &*ctx;
+        let partitions = parallel::map_partitions(candidates, &ctx.parallel, |candidates| {
+            let mut scans = ScanMetrics::default();
+            let mut rows = Vec::new();
+            for (i, (row, from_index)) in candidates.into_iter().enumerate() {
+                shared.check_at(i)?;
+                if let Some(row) = keep_scanned(row, from_index, &generated, step, &mut scans)? {
+                    rows.push(row);
+                }
+            }
+            Ok((rows, scans))
+        })?;
+        ctx.tick_many(read)?;
+        let mut rows = Vec::new();
+        for (partition, scans) in partitions {
+            rows.extend(partition);
+            ctx.scans.rows_fetched += scans.rows_fetched;
+            ctx.scans.index_only_rows += scans.index_only_rows;
+        }
+        return Ok(rows);
+    }
+
+    let mut rows = Vec::new();
+    for (row, from_index) in candidates {
+        if limit.is_some_and(|limit| rows.len() >= limit) {
+            break;
+        }
+        ctx.tick()?;
+        if let Some(row) = keep_scanned(row, from_index, &generated, step, &mut ctx.scans)? {
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Count a row a scan read and return it if it passes the step's filters
+fn keep_scanned<'a>(
+    mut row: Cow<'a, Row>,
+    from_index: bool,
+    generated: &GeneratedColumns,
+    step: &JoinStep,
+    scans: &mut ScanMetrics,
+) -> QubeResult<Option<Cow<'a, Row>>> {
+    if from_index {
+        scans.index_only_rows += 1;
+    } else {
+        scans.rows_fetched += 1;
+        if generated.has_virtual() {
+            generated.fill_virtual(row.to_mut())?;
+        }
+    }
+    Ok(matches_all(&step.filters, &row)?.then_some(row))
+}
+
+/// Entries found by an index lookup
+type IndexEntries<'a> = Box<dyn Iterator<Item = IndexEntry<'a>> + 'a>;
+
+/// The entries an index lookup finds, in row id order or, for an ordered
+/// scan, lazily in key order. None if the table keeps no entries for the
+/// index.
+fn index_lookup<'a>(
+    data: &'a TableData,
+    scan: &IndexScan,
+) -> QubeResult<Option<(&'a BTreeIndex, IndexEntries<'a>)>> {
+    let index = match data.index(&scan.index) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let empty = Row::new();
+    let prefix = scan
+        .prefix
+        .iter()
+        .map(|value| expr::evaluate(value, &empty))
+        .collect::<QubeResult<Vec<_>>>()?;
+    let bound = |bound: &Option<RangeBound>| -> QubeResult<Bound<Value>> {
+        Ok(match bound {
+            Some(bound) if bound.inclusive => {
+                Bound::Included(expr::evaluate(&bound.value, &empty)?)
+            }
+            Some(bound) => Bound::Excluded(expr::evaluate(&bound.value, &empty)?),
+            None => Bound::Unbounded,
+        })
+    };
+    let lower = bound(&scan.lower)?;
+    let upper = bound(&scan.upper)?;
+
+    let descending = scan.order == Some(IndexOrder::Descending);
+    let entries = index.range_iter(&prefix, lower.as_ref(), upper.as_ref(), descending);
+    if scan.order.is_some() {
+        return Ok(Some((index, entries)));
+    }
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort_unstable_by_key(|(_, row_id)| *row_id);
+    Ok(Some((index, Box::new(entries.into_iter()))))
+}
+
+/// The generated columns of a table, with their expressions parsed
+struct GeneratedColumns<'a> {
+    columns: Vec<(&'a Column, Expr)>,
+}
+
+impl<'a> GeneratedColumns<'a> {
+    /// Parse the table's generation expressions, failing if one does not
+    /// parse or refers to anything but the table's other, non-generated
+    /// columns
+    fn of(table: &'a Table) -> QubeResult<Self> {
+        let mut columns = Vec::new();
+        for column in &table.columns {
+            let Some(generated) = &column.generated else {
+                continue;
+            };
+            let expression = Parser::new(&GenericDialect {})
+                .try_with_sql(generated.expression())
+                .and_then(|mut parser| parser.parse_expr())
+                .map_err(|e| {
+                    QubeError::QueryParse(format!(
+                        "Invalid expression for generated column '{}': {}",
+                        column.name, e
+                    ))
+                })?;
+            let mut referenced = Vec::new();
+            let _ = visit_expressions(&expression, |expr| {
+                match expr {
+                    Expr::Identifier(ident) => referenced.push(ident.value.clone()),
+                    Expr::CompoundIdentifier(idents) => {
+                        referenced.extend(idents.last().map(|ident| ident.value.clone()))
+                    }
+                    _ => {}
+                }
+                ControlFlow::<()>::Continue(())
+            });
+            for name in referenced {
+                match table.columns.iter().find(|c| c.name == name) {
+                    Some(base) if base.generated.is_none() => {}
+                    Some(_) => {
+                        return Err(QubeError::QueryParse(format!(
+                            "Generated column '{}' cannot refer to generated column '{}'",
+                            column.name, name
+                        )))
+                    }
+                    None => {
+                        return Err(QubeError::ColumnNotFound(format!(
+                            "{}.{}",
+                            table.name, name
+                        )))
+                    }
+                }
+            }
+            columns.push((column, expression));
+        }
+        Ok(GeneratedColumns { columns })
+    }
+
+    fn has_virtual(&self) -> bool {
+        self.columns.iter().any(|(column, _)| column.is_virtual())
+    }
+
+    /// Compute a column's value from the rest of `row`
+    fn compute(column: &Column, expression: &Expr, row: &Row) -> QubeResult<Value> {
+        expr::coerce(expr::evaluate(expression, row)?, &column.data_type)
+    }
+
+    /// Set the generated columns of a row about to be written: stored ones
+    /// to their value, virtual ones, once checked, to NULL
+    fn store(&self, row: &mut Row) -> QubeResult<()> {
+        for (column, expression) in &self.columns {
+            let value = Self::compute(column, expression, row)?;
+            column.check(&value)?;
+            let stored = if column.is_virtual() {
+                Value::Null
+            } else {
+                value
+            };
+            row.insert(column.name.clone(), stored);
+        }
+        Ok(())
+    }
+
+    /// Fill in the virtual columns of a row that was read
+    fn fill_virtual(&self, row: &mut Row) -> QubeResult<()> {
+        for (column, expression) in self.columns.iter().filter(|(c, _)| c.is_virtual()) {
+            let value = Self::compute(column, expression, row)?;
+            row.insert(column.name.clone(), value);
+        }
+        Ok(())
+    }
+}
+
+/// The value an INSERT stores in a column it does not set
+fn column_default(column: &Column) -> QubeResult<Value> {
+    match &column.default_value {
+        Some(default) => expr::coerce(default.evaluate(), &column.data_type),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Whether an INSERT value is the `DEFAULT` keyword, which the parser reads
+/// as a bare identifier
+pub(crate) fn is_default_keyword(expr: &Expr) -> bool {
+    matches!(expr, Expr::Identifier(ident)
+        if ident.quote_style.is_none() && ident.value.eq_ignore_ascii_case("default"))
+}
+
+/// Resolve the columns an UPDATE's `SET` assigns to
+fn assignment_targets<'a>(
+    name: &str,
+    table: &'a Table,
+    assignments: &'a [Assignment],
+) -> QubeResult<Vec<(&'a Column, &'a Expr)>> {
+    assignments
+        .iter()
+        .map(|assignment| {
+            let column = assignment.id.last().map(|ident| ident.value.as_str());
+            table
+                .columns
+                .iter()
+                .find(|c| Some(c.name.as_str()) == column)
+                .ok_or_else(|| {
+                    QubeError::ColumnNotFound(format!("{}.{}", name, column.unwrap_or("")))
+                })
+                .and_then(|c| match &c.generated {
+                    Some(_) if !is_default_keyword(&assignment.value) => {
+                        Err(QubeError::QueryParse(format!(
+                            "Cannot assign to generated column '{}'",
+                            c.name
+                        )))
+                    }
+                    _ => Ok((c, &assignment.value)),
+                })
+        })
+        .collect()
+}
+
+/// Apply `SET` assignments to `updated`, evaluating them against `row`
+fn assign(targets: &[(&Column, &Expr)], row: &Row, updated: &mut Row) -> QubeResult<()> {
+    for (column, value_expr) in targets {
+        let value = if is_default_keyword(value_expr) {
+            column_default(column)?
+        } else {
+            expr::coerce(expr::evaluate(value_expr, row)?, &column.data_type)?
+        };
+        column.check(&value)?;
+        updated.insert(column.name.clone(), value);
+    }
+    Ok(())
+}
+
+/// The rows of an `INSERT ... ON CONFLICT`, once conflicts are resolved
+struct Upsert {
+    inserts: Vec<Row>,
+    /// Existing rows to replace, by row id
+    updates: Vec<(u64, Row)>,
+}
+
+/// Split the rows of an `INSERT ... ON CONFLICT` into rows to insert and
+/// existing rows to update.
+///
+/// A row conflicts when its key on the conflict target's unique index (any
+/// unique index, if no target is named) matches an existing row. `DO
+/// NOTHING` skips it; `DO UPDATE` applies its assignments to the existing
+/// row, which they see alongside the proposed row as `excluded`. MySQL's
+/// `ON DUPLICATE KEY UPDATE` is treated as `DO UPDATE` on any unique index.
+fn resolve_conflicts(
+    name: &str,
+    table: &Table,
+    data: &TableData,
+    on: &OnInsert,
+    rows: Vec<Row>,
+) -> QubeResult<Upsert> {
+    let (target, action) = match on {
+        OnInsert::OnConflict(OnConflict {
+            conflict_target,
+            action,
+        }) => match action {
+            OnConflictAction::DoNothing => (conflict_target.as_ref(), None),
+            OnConflictAction::DoUpdate(update) => (
+                conflict_target.as_ref(),
+                Some((update.assignments.as_slice(), update.selection.as_ref())),
+            ),
+        },
+        OnInsert::DuplicateKeyUpdate(assignments) => (None, Some((assignments.as_slice(), None))),
+        other => return Err(QubeError::Unsupported(format!("INSERT clause {}", other))),
+    };
+    let indexes: Vec<&Index> = match target {
+        None => table.indexes.iter().filter(|index| index.unique).collect(),
+        Some(ConflictTarget::Columns(columns)) => {
+            let columns: BTreeSet<&str> = columns.iter().map(|c| c.value.as_str()).collect();
+            let index = table.indexes.iter().find(|index| {
+                index.unique
+                    && index
+                        .columns
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<BTreeSet<_>>()
+                        == columns
+            });
+            match index {
+                Some(index) => vec![index],
+                None => {
+                    return Err(QubeError::QueryParse(format!(
+                        "No unique index on '{}' matches the ON CONFLICT columns",
+                        name
+                    )))
+                }
+            }
+        }
+        Some(ConflictTarget::OnConstraint(constraint)) => {
+            let constraint = object_name(constraint);
+            match table.indexes.iter().find(|index| index.name == constraint) {
+                Some(index) if index.unique => vec![index],
+                _ => {
+                    return Err(QubeError::QueryParse(format!(
+                        "'{}' is not a unique constraint of '{}'",
+                        constraint, name
+                    )))
+                }
+            }
+        }
+    };
+    let targets = match action {
+        Some((assignments, _)) => assignment_targets(name, table, assignments)?,
+        None => Vec::new(),
+    };
+    let generated = GeneratedColumns::of(table)?;
+
+    let mut inserts: Vec<Row> = Vec::new();
+    let mut updates: Vec<(u64, Row)> = Vec::new();
+    for row in rows {
+        let keys: Vec<Option<Vec<Value>>> = indexes
+            .iter()
+            .map(|index| unique_key(index, &row))
+            .collect();
+        let repeated = inserts.iter().any(|earlier| {
+            indexes
+                .iter()
+                .zip(&keys)
+                .any(|(index, key)| key.is_some() && unique_key(index, earlier) == *key)
+        });
+        let existing = indexes
+            .iter()
+            .zip(&keys)
+            .find_map(|(index, key)| conflicting_row(data, index, key.as_deref()?));
+        let updated_before =
+            existing.is_some_and(|row_id| updates.iter().any(|(id, _)| *id == row_id));
+
+        let Some((_, selection)) = action else {
+            if !repeated && existing.is_none() {
+                inserts.push(row);
+            }
+            continue;
+        };
+        if repeated || updated_before {
+            return Err(QubeError::ConstraintViolation(
+                "ON CONFLICT DO UPDATE cannot affect the same row twice".to_string(),
+            ));
+        }
+        let Some(row_id) = existing else {
+            inserts.push(row);
+            continue;
+        };
+
+        let current = data.row(row_id, None).map(Cow::into_owned).ok_or_else(|| {
+            QubeError::Storage(format!("Row {} of '{}' is missing", row_id, name))
+        })?;
+        let mut scope = current.clone();
+        generated.fill_virtual(&mut scope)?;
+        for (column, value) in row {
+            scope.insert(format!("excluded.{}", column), value);
+        }
+        if let Some(selection) = selection {
+            if !expr::is_true(&expr::evaluate(selection, &scope)?) {
+                continue;
+            }
+        }
+        let mut updated = current;
+        assign(&targets, &scope, &mut updated)?;
+        generated.store(&mut updated)?;
+        updates.push((row_id, updated));
+    }
+    Ok(Upsert { inserts, updates })
+}
+
+/// A row's key on a unique index, or `None` if any part of it is NULL
+fn unique_key(index: &Index, row: &Row) -> Option<Vec<Value>> {
+    index
+        .columns
+        .iter()
+        .map(|column| row.get(column).filter(|v| !v.is_null()).cloned())
+        .collect()
+}
+
+/// The existing row holding `key` on a unique index, found through the
+/// index's entries when it keeps any
+fn conflicting_row(data: &TableData, index: &Index, key: &[Value]) -> Option<u64> {
+    if let Some(entries) = data.index(&index.name) {
+        return entries.search(key).first().copied();
+    }
+    let columns: BTreeSet<String> = index.columns.iter().cloned().collect();
+    data.rows_with(Some(&columns))
+        .find(|(_, row)| unique_key(index, row).as_deref() == Some(key))
+        .map(|(row_id, _)| row_id)
+}
+
+/// Fail if writing `new_rows` would duplicate a key of a unique index,
+/// either against the stored rows other than `replaced` (those the
+/// statement rewrites or removes) or among the new rows themselves. Keys
+/// containing NULL never conflict. Stored keys are looked up in the
+/// index's entries, so the check costs the size of the write rather than
+/// of the table.
+fn check_unique_keys(
+    table: &Table,
+    data: &TableData,
+    replaced: &HashSet<u64>,
+    new_rows: &[Row],
+) -> QubeResult<()> {
+    for index in table.indexes.iter().filter(|index| index.unique) {
+        let entries = data.index(&index.name);
+        // Keys of the stored rows, for an index that keeps no entries
+        let mut stored_keys: Option<HashSet<Vec<Value>>> = None;
+        let mut keys: HashSet<Vec<Value>> = HashSet::new();
+        for row in new_rows {
+            let Some(key) = unique_key(index, row) else {
+                continue;
+            };
+            let stored = match entries {
+                Some(entries) => entries
+                    .search(&key)
+                    .iter()
+                    .any(|row_id| !replaced.contains(row_id)),
+                None => stored_keys
+                    .get_or_insert_with(|| {
+                        let columns: BTreeSet<String> = index.columns.iter().cloned().collect();
+                        data.rows_with(Some(&columns))
+                            .filter(|(row_id, _)| !replaced.contains(row_id))
+                            .filter_map(|(_, row)| unique_key(index, &row))
+                            .collect()
+                    })
+                    .contains(&key),
+            };
+            if stored || !keys.insert(key) {
+                return Err(QubeError::ConstraintViolation(format!(
+                    "Duplicate key for unique index '{}' on ({})",
+                    index.name,
+                    index.columns.join(", ")
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Re-collect a table's statistics once enough of it has changed,
+/// returning whether they were
+fn refresh_stale_stats(catalog: &mut Catalog, name: &str) -> QubeResult<bool> {
+    let data = catalog.table_data(name)?;
+    let stale = match &data.stats {
+        Some(stats) => stats.is_stale(data.modified_rows),
+        None => data.modified_rows >= AUTO_ANALYZE_THRESHOLD,
+    };
+    if stale {
+        catalog.analyze_table(name)?;
+    }
+    Ok(stale)
+}
+
+/// Fail on the clauses of a SELECT the engine does not implement, rather
+/// than run the query as if they were not there
+fn check_supported(query: &Query, select: &Select) -> QubeResult<()> {
+    let clauses = [
+        (
+            !select.group_by.is_empty() || select.having.is_some(),
+            "GROUP BY and HAVING",
+        ),
+        (
+            matches!(select.distinct, Some(Distinct::On(_))),
+            "DISTINCT ON",
+        ),
+        (select.top.is_some(), "TOP"),
+        (select.into.is_some(), "SELECT INTO"),
+        (!select.lateral_views.is_empty(), "LATERAL VIEW"),
+        (
+            !select.cluster_by.is_empty()
+                || !select.distribute_by.is_empty()
+                || !select.sort_by.is_empty(),
+            "CLUSTER BY, DISTRIBUTE BY and SORT BY",
+        ),
+        (!select.named_window.is_empty(), "WINDOW clauses"),
+        (select.qualify.is_some(), "QUALIFY"),
+        (query.fetch.is_some(), "FETCH"),
+    ];
+    match clauses.iter().find(|(present, _)| *present) {
+        Some((_, clause)) => Err(QubeError::Unsupported(clause.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Whether a LIMIT can stop the scan early: only when a single table is read
+/// and every filter is applied during the scan, and no aggregate, window
+/// function or DISTINCT needs to see all rows first, nor an ORDER BY the
+/// scan does not already satisfy
+fn limit_pushes_down(query: &Query, relations: &[Relation], plan: &QueryPlan) -> bool {
+    // Aggregates, window functions and DISTINCT need every row
+    let needs_all_rows = match &*query.body {
+        SetExpr::Select(select) => {
+            aggregate::has_aggregates(&select.projection)
+                || window::has_window_functions(&select.projection)
+                || select.distinct.is_some()
+        }
+        _ => false,
+    };
+    (query.order_by.is_empty() || plan.is_ordered())
+        && relations.len() == 1
+        && plan.residual.is_empty()
+        && !needs_all_rows
+}
+
+/// Skip the first `offset` rows and keep at most `limit` of the rest
+fn offset_limit<T>(
+    rows: Vec<T>,
+    offset: usize,
+    limit: Option<usize>,
+    ctx: &mut ExecutionContext,
+) -> Vec<T> {
+    let started = Instant::now();
+    let examined = rows.len() as u64;
+    let rows: Vec<T> = rows
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    ctx.profile(Operator::Limit, examined, rows.len(), started);
+    rows
+}
+
+/// Keep the rows that satisfy every predicate
+fn filter_rows<'a>(
+    rows: Vec<Cow<'a, Row>>,
+    predicates: &[Expr],
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<Cow<'a, Row>>> {
+    let mut kept = Vec::with_capacity(rows.len());
+    for row in rows {
+        ctx.tick()?;
+        if matches_all(predicates, &row)? {
+            kept.push(row);
+        }
+    }
+    Ok(kept)
+}
+
+fn matches_all(predicates: &[Expr], row: &Row) -> QubeResult<bool> {
+    for predicate in predicates {
+        if !expr::is_true(&expr::evaluate(predicate, row)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Run the join steps of a plan, producing rows keyed by `qualifier.column`
+/// (and by bare column name where that is unambiguous)
+fn execute_joins(
+    relations: &[Relation],
+    plan: &QueryPlan,
+    ambiguous: &HashSet<String>,
+    required: &[BTreeSet<String>],
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<Row>> {
+    let first = &plan.steps[0];
+    let relation = &relations[first.relation];
+    let scanned = scan_columns(relations, first, required);
+    let started = Instant::now();
+    let read = ctx.scans.rows_read();
+    let mut rows: Vec<Row> = scan(relation, first, &scanned, None, ctx)?
+        .iter()
+        .map(|row| {
+            join_row(
+                &Row::new(),
+                row,
+                relation,
+                &required[first.relation],
+                ambiguous,
+            )
+        })
+        .collect();
+    let examined = ctx.scans.rows_read() - read;
+    ctx.profile(Operator::Step(0), examined, rows.len(), started);
+
+    for (position, step) in plan.steps.iter().enumerate().skip(1) {
+        let started = Instant::now();
+        let relation = &relations[step.relation];
+        let columns = &required[step.relation];
+        let scanned = scan_columns(relations, step, required);
+        let scanned_rows = scan(relation, step, &scanned, None, ctx)?;
+        let inner: Vec<&Row> = scanned_rows.iter().map(|row| row.as_ref()).collect();
+        let mut joined = Vec::new();
+
+        if step.hash_keys.is_empty() {
+            for outer in &rows {
+                for &inner_row in &inner {
+                    ctx.tick()?;
+                    let row = join_row(outer, inner_row, relation, columns, ambiguous);
+                    if matches_all(&step.conditions, &row)? {
+                        joined.push(row);
+                    }
+                }
+            }
+        } else {
+            let outer_keys = step
+                .hash_keys
+                .iter()
+                .map(|(outer, _)| outer)
+                .collect::<Vec<_>>();
+            let inner_keys = step
+                .hash_keys
+                .iter()
+                .map(|(_, inner)| inner)
+                .collect::<Vec<_>>();
+
+            // Build the hash table on whichever side is smaller and probe with the other
+            let mut emit = |outer: &Row, inner_row: &Row| -> QubeResult<()> {
+                ctx.tick()?;
+                let row = join_row(outer, inner_row, relation, columns, ambiguous);
+                if matches_all(&step.conditions, &row)? {
+                    joined.push(row);
+                }
+                Ok(())
+            };
+            if rows.len() <= inner.len() {
+                let mut table: HashMap<Vec<Value>, Vec<&Row>> = HashMap::new();
+                for outer in &rows {
+                    if let Some(key) = join_key(&outer_keys, outer)? {
+                        table.entry(key).or_default().push(outer);
+                    }
+                }
+                for &inner_row in &inner {
+                    if let Some(key) = join_key(&inner_keys, inner_row)? {
+                        for outer in table.get(&key).into_iter().flatten() {
+                            emit(outer, inner_row)?;
+                        }
+                    }
+                }
+            } else {
+                let mut table: HashMap<Vec<Value>, Vec<&Row>> = HashMap::new();
+                for &inner_row in &inner {
+                    if let Some(key) = join_key(&inner_keys, inner_row)? {
+                        table.entry(key).or_default().push(inner_row);
+                    }
+                }
+                for outer in &rows {
+                    if let Some(key) = join_key(&outer_keys, outer)? {
+                        for inner_row in table.get(&key).into_iter().flatten() {
+                            emit(outer, inner_row)?;
+                        }
+                    }
+                }
+            }
+        }
+        let examined = (rows.len() + inner.len()) as u64;
+        ctx.profile(Operator::Step(position), examined, joined.len(), started);
+        rows = joined;
+    }
+    Ok(rows)
+}
+
+/// Columns of each relation a join has to carry: everything the projection,
+/// join conditions, residual predicates and ORDER BY read. Scan filters are
+/// evaluated against the stored row and need nothing copied.
+fn required_columns(
+    relations: &[Relation],
+    plan: &QueryPlan,
+    projection: &[(String, Expr)],
+    order_by: &[OrderByExpr],
+) -> Vec<BTreeSet<String>> {
+    let exprs = projection
+        .iter()
+        .map(|(_, expr)| expr)
+        .chain(plan.residual.iter())
+        .chain(order_by.iter().map(|order| &order.expr))
+        .chain(plan.steps.iter().flat_map(|step| {
+            step.hash_keys
+                .iter()
+                .flat_map(|(outer, inner)| [outer, inner])
+                .chain(step.conditions.iter())
+        }));
+    planner::required_columns(exprs, relations)
+}
+
+/// Columns a scan of the step's relation reads: the ones carried past the
+/// scan, plus the ones its filters test
+fn scan_columns(
+    relations: &[Relation],
+    step: &JoinStep,
+    required: &[BTreeSet<String>],
+) -> BTreeSet<String> {
+    let mut columns =
+        planner::required_columns(&step.filters, relations).swap_remove(step.relation);
+    columns.extend(required[step.relation].iter().cloned());
+    columns
+}
+
+/// Extend a joined row with the `columns` of one more relation
+fn join_row(
+    outer: &Row,
+    inner: &Row,
+    relation: &Relation,
+    columns: &BTreeSet<String>,
+    ambiguous: &HashSet<String>,
+) -> Row {
+    let mut row = outer.clone();
+    for column in relation
+        .table
+        .columns
+        .iter()
+        .filter(|column| columns.contains(&column.name))
+    {
+        let value = inner.get(&column.name).cloned().unwrap_or(Value::Null);
+        if !ambiguous.contains(&column.name) {
+            row.insert(column.name.clone(), value.clone());
+        }
+        row.insert(format!("{}.{}", relation.qualifier, column.name), value);
+    }
+    row
+}
+
+/// Evaluate hash join keys for a row. Returns None if any key is NULL, since
+/// NULL never compares equal. Numeric keys are normalized so that values of
+/// different integer widths hash alike.
+fn join_key(keys: &[&Expr], row: &Row) -> QubeResult<Option<Vec<Value>>> {
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        let value = expr::evaluate(key, row)?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        let normalized = match value.as_f64() {
+            Some(v) if v.fract() == 0.0 && v.abs() < i64::MAX as f64 => Value::Int64(v as i64),
+            _ => value,
+        };
+        values.push(normalized);
+    }
+    Ok(Some(values))
+}
+
+/// Expand a SELECT list into output column names and expressions
+fn expand_projection(
+    items: &[SelectItem],
+    relations: &[Relation],
+    ambiguous: &HashSet<String>,
+) -> QubeResult<Vec<(String, Expr)>> {
+    let expand = |relation: &Relation| -> Vec<(String, Expr)> {
+        relation
+            .table
+            .columns
+            .iter()
+            .map(|column| {
+                let name = if ambiguous.contains(&column.name) {
+                    format!("{}.{}", relation.qualifier, column.name)
+                } else {
+                    column.name.clone()
+                };
+                let expr = Expr::CompoundIdentifier(vec![
+                    Ident::new(relation.qualifier.clone()),
+                    Ident::new(column.name.clone()),
+                ]);
+                (name, expr)
+            })
+            .collect()
+    };
+
+    let mut projection = Vec::new();
+    for item in items {
+        if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+            // Reject ambiguous column references up front
+            planner::references(expr, relations)?;
+        }
+        match item {
+            SelectItem::UnnamedExpr(expr) => {
+                let name = match expr {
+                    Expr::Identifier(ident) => ident.value.clone(),
+                    Expr::CompoundIdentifier(idents) => idents
+                        .last()
+                        .map(|ident| ident.value.clone())
+                        .unwrap_or_default(),
+                    other => other.to_string(),
+                };
+                projection.push((name, expr.clone()));
+            }
+            SelectItem::ExprWithAlias { expr, alias } => {
+                projection.push((alias.value.clone(), expr.clone()));
+            }
+            SelectItem::Wildcard(_) => {
+                if relations.is_empty() {
+                    return Err(QubeError::QueryParse(
+                        "SELECT * requires a FROM clause".to_string(),
+                    ));
+                }
+                relations
+                    .iter()
+                    .for_each(|relation| projection.extend(expand(relation)));
+            }
+            SelectItem::QualifiedWildcard(name, _) => {
+                let qualifier = object_name(name);
+                let relation = relations
+                    .iter()
+                    .find(|r| r.qualifier == qualifier)
+                    .ok_or_else(|| QubeError::TableNotFound(qualifier.clone()))?;
+                projection.extend(expand(relation));
+            }
+        }
+    }
+    unique_names(&mut projection);
+    Ok(projection)
+}
+
+/// Rename repeated output columns, since a row holds one value per name:
+/// a column of a relation takes its qualified name, e.g. `a.id` and `b.id`,
+/// and anything else left repeated a numbered suffix, e.g. `id_1`
+fn unique_names(projection: &mut [(String, Expr)]) {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (name, _) in projection.iter() {
+        *counts.entry(name.clone()).or_default() += 1;
+    }
+    for (name, expr) in projection.iter_mut() {
+        if let Expr::CompoundIdentifier(idents) = expr {
+            if counts[name.as_str()] > 1 {
+                let parts: Vec<&str> = idents.iter().map(|ident| ident.value.as_str()).collect();
+                *name = parts.join(".");
+            }
+        }
+    }
+
+    let mut taken: HashSet<String> = projection.iter().map(|(name, _)| name.clone()).collect();
+    let mut seen = HashSet::new();
+    for (name, _) in projection.iter_mut() {
+        if seen.insert(name.clone()) {
+            continue;
+        }
+        let mut suffix = 1;
+        while taken.contains(&format!("{}_{}", name, suffix)) {
+            suffix += 1;
+        }
+        *name = format!("{}_{}", name, suffix);
+        taken.insert(name.clone());
+        seen.insert(name.clone());
+    }
+}
+
+/// Compare two ORDER BY keys. NULLs sort last ascending and first descending
+/// unless NULLS FIRST/LAST says otherwise.
+pub(crate) fn compare_sort_keys(
+    a: &[Value],
+    b: &[Value],
+    order_by: &[OrderByExpr],
+) -> std::cmp::Ordering {
+    use std::cmp::Ordering as CmpOrdering;
+
+    for ((a, b), order) in a.iter().zip(b).zip(order_by) {
+        let ascending = order.asc.unwrap_or(true);
+        let nulls_first = order.nulls_first.unwrap_or(!ascending);
+        let ordering = match (a.is_null(), b.is_null()) {
+            (true, true) => CmpOrdering::Equal,
+            (true, false) if nulls_first => CmpOrdering::Less,
+            (true, false) => CmpOrdering::Greater,
+            (false, true) if nulls_first => CmpOrdering::Greater,
+            (false, true) => CmpOrdering::Less,
+            (false, false) => {
+                let ordering = a.compare(b).unwrap_or(CmpOrdering::Equal);
+                if ascending {
+                    ordering
+                } else {
+                    ordering.reverse()
+                }
+            }
+        };
+        if ordering != CmpOrdering::Equal {
+            return ordering;
+        }
+    }
+    CmpOrdering::Equal
+}
+
+/// Evaluate a LIMIT/OFFSET expression into a row count
+pub(crate) fn row_count_of(expr: &Expr) -> QubeResult<usize> {
+    expr::evaluate(expr, &Row::new())?
+        .as_i64()
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| QubeError::QueryParse(format!("Invalid row count: {}", expr)))
+}
+
+/// Flatten a possibly qualified SQL object name into a catalog name
+pub(crate) fn object_name(name: &ObjectName) -> String {
+    name.0
+        .iter()
+        .map(|ident| ident.value.clone())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The rows a common table expression returned, which the rest of its
+/// query reads like a table
+pub(crate) struct CommonTable {
+    table: Table,
+    data: TableData,
+}
+
+impl CommonTable {
+    /// Hold the result of the CTE `name`, renaming its columns to
+    /// `columns` when the CTE lists them. A column's type is that of its
+    /// first non-NULL value.
+    fn new(name: &str, columns: &[Ident], result: QueryResult) -> QubeResult<Self> {
+        let QueryResult {
+            columns: outputs,
+            rows,
+            ..
+        } = result;
+        let names: Vec<String> = if columns.is_empty() {
+            outputs.clone()
+        } else if columns.len() == outputs.len() {
+            columns.iter().map(|column| column.value.clone()).collect()
+        } else {
+            return Err(QubeError::QueryParse(format!(
+                "WITH query '{}' returns {} columns but {} are named",
+                name,
+                outputs.len(),
+                columns.len()
+            )));
+        };
+
+        let mut builder = TableBuilder::new(name);
+        for (column, output) in names.iter().zip(&outputs) {
+            let data_type = rows
+                .iter()
+                .find_map(|row| row.get(output).and_then(Value::data_type))
+                .unwrap_or(DataType::Text);
+            builder = builder.column(column.clone(), data_type);
+        }
+        let table = builder.build()?;
+        let rows = rows.into_iter().map(|mut row| {
+            names
+                .iter()
+                .zip(&outputs)
+                .map(|(column, output)| (column.clone(), row.remove(output).unwrap_or(Value::Null)))
+                .collect()
+        });
+        let data = TableData::from_rows(&table, rows);
+        Ok(CommonTable { table, data })
+    }
+}
+
+/// Leading keywords of a statement, e.g. `CREATE VIEW`, to name a kind of
+/// statement in errors
+fn statement_keywords(statement: &Statement) -> String {
+    statement
+        .to_string()
+        .split_whitespace()
+        .take_while(|word| word.chars().all(|c| c.is_ascii_uppercase() || c == '_'))
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// MySQL-style key marker for DESCRIBE output
+fn column_key(table: &Table, column: &Column) -> &'static str {
+    if column.primary_key {
+        "PRI"
+    } else if column.unique {
+        "UNI"
+    } else if table
+        .indexes
+        .iter()
+        .any(|index| index.columns.first() == Some(&column.name))
+    {
+        "MUL"
+    } else {
+        ""
+    }
+}
+
+/// Interpret a column's DEFAULT clause. Constants are evaluated and checked
+/// against the column type once, at CREATE TABLE; time functions are kept
+/// symbolic so they are evaluated on each insert.
+fn default_value(default: &Expr, data_type: &DataType) -> QubeResult<DefaultValue> {
+    if let Expr::Function(function) = default {
+        if function.args.is_empty() {
+            let name = object_name(&function.name).to_lowercase();
+            match name.as_str() {
+                "current_timestamp" | "now" | "localtimestamp" => {
+                    return Ok(DefaultValue::CurrentTimestamp)
+                }
+                "current_date" => return Ok(DefaultValue::CurrentDate),
+                "current_time" | "localtime" => return Ok(DefaultValue::CurrentTime),
+                _ => {}
+            }
+        }
+    }
+
+    let value = expr::evaluate(default, &Row::new())
+        .map_err(|e| QubeError::QueryParse(format!("Invalid DEFAULT {}: {}", default, e)))?;
+    Ok(DefaultValue::Value(expr::coerce(value, data_type)?))
+}
+
+/// The integer type behind a PostgreSQL-style SERIAL column type, which
+/// implies AUTO_INCREMENT
+fn serial_type(data_type: &SqlDataType) -> Option<DataType> {
+    match data_type {
+        SqlDataType::Custom(name, modifiers) if modifiers.is_empty() => {
+            match object_name(name).to_uppercase().as_str() {
+                "SMALLSERIAL" => Some(DataType::Int16),
+                "SERIAL" => Some(DataType::Int32),
+                "BIGSERIAL" => Some(DataType::Int64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Convert a SQL column type into a QubeDB data type
+fn convert_data_type(data_type: &SqlDataType) -> QubeResult<DataType> {
+    let converted = match data_type {
+        SqlDataType::TinyInt(_) => DataType::Int8,
+        SqlDataType::UnsignedTinyInt(_) => DataType::UInt8,
+        SqlDataType::SmallInt(_) | SqlDataType::Int2(_) => DataType::Int16,
+        SqlDataType::UnsignedSmallInt(_) | SqlDataType::UnsignedInt2(_) => DataType::UInt16,
+        SqlDataType::MediumInt(_)
+        | SqlDataType::Int(_)
+        | SqlDataType::Int4(_)
+        | SqlDataType::Integer(_) => DataType::Int32,
+        SqlDataType::UnsignedMediumInt(_)
+        | SqlDataType::UnsignedInt(_)
+        | SqlDataType::UnsignedInt4(_)
+        | SqlDataType::UnsignedInteger(_) => DataType::UInt32,
+        SqlDataType::BigInt(_) | SqlDataType::Int8(_) => DataType::Int64,
+        SqlDataType::UnsignedBigInt(_) | SqlDataType::UnsignedInt8(_) => DataType::UInt64,
+        SqlDataType::Float(_) | SqlDataType::Float4 | SqlDataType::Real => DataType::Float32,
+        SqlDataType::Float8
+        | SqlDataType::Double
+        | SqlDataType::DoublePrecision
+        | SqlDataType::Numeric(_)
+        | SqlDataType::Decimal(_)
+        | SqlDataType::BigNumeric(_)
+        | SqlDataType::BigDecimal(_)
+        | SqlDataType::Dec(_) => DataType::Float64,
+        SqlDataType::Character(_)
+        | SqlDataType::Char(_)
+        | SqlDataType::CharacterVarying(_)
+        | SqlDataType::CharVarying(_)
+        | SqlDataType::Varchar(_)
+        | SqlDataType::Nvarchar(_)
+        | SqlDataType::Uuid
+        | SqlDataType::String => DataType::String,
+        SqlDataType::Text
+        | SqlDataType::Clob(_)
+        | SqlDataType::CharacterLargeObject(_)
+        | SqlDataType::CharLargeObject(_) => DataType::Text,
+        SqlDataType::Binary(_) | SqlDataType::Varbinary(_) | SqlDataType::Bytea => DataType::Binary,
+        SqlDataType::Blob(_) => DataType::Blob,
+        SqlDataType::Bool | SqlDataType::Boolean => DataType::Boolean,
+        SqlDataType::Date => DataType::Date,
+        SqlDataType::Time(..) => DataType::Time,
+        SqlDataType::Datetime(_) | SqlDataType::Timestamp(..) => DataType::Timestamp,
+        SqlDataType::JSON => DataType::Json,
+        SqlDataType::Custom(name, modifiers) => match object_name(name).to_uppercase().as_str() {
+            "VECTOR" => {
+                let dimensions =
+                    modifiers
+                        .first()
+                        .and_then(|d| d.parse().ok())
+                        .ok_or_else(|| {
+                            QubeError::QueryParse(
+                                "VECTOR columns require a dimension, e.g. VECTOR(128)".to_string(),
+                            )
+                        })?;
+                DataType::Vector { dimensions }
+            }
+            "JSONB" => DataType::Json,
+            "COUNTER" => DataType::Counter,
+            "GRAPH_NODE" => DataType::GraphNode,
+            "GRAPH_EDGE" => DataType::GraphEdge,
+            _ => return Err(QubeError::Unsupported(format!("data type {}", data_type))),
+        },
+        other => return Err(QubeError::Unsupported(format!("data type {}", other))),
+    };
+    Ok(converted)
+}
+
+/// The schema of a `CHECK (JSON_SCHEMA_VALID('<schema>', column))`
+/// constraint on `column`, the form MySQL uses to validate JSON columns
+fn json_schema_check<'a>(expr: &'a Expr, column: &str) -> Option<&'a str> {
+    let Expr::Function(function) = expr else {
+        return None;
+    };
+    if !object_name(&function.name).eq_ignore_ascii_case("JSON_SCHEMA_VALID") {
+        return None;
+    }
+    match function.args.as_slice() {
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(SqlValue::SingleQuotedString(
+            schema,
+        )))), FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Identifier(ident)))]
+            if ident.value == column =>
+        {
+            Some(schema)
+        }
+        _ => None,
+    }
+}
+
+/// Compile the JSON schema a CHECK constraint attaches to a column
+fn column_json_schema(column: &Column, schema: &str) -> QubeResult<JsonSchema> {
+    if column.data_type != DataType::Json {
+        return Err(QubeError::QueryParse(format!(
+            "JSON_SCHEMA_VALID check on non-JSON column '{}'",
+            column.name
+        )));
+    }
+    let schema = serde_json::from_str(schema)
+        .map_err(|e| QubeError::QueryParse(format!("Invalid JSON schema: {}", e)))?;
+    JsonSchema::new(schema)
+}
+
+/// Build a table definition from a parsed CREATE TABLE statement
+fn build_table(
+    name: String,
+    column_defs: &[ColumnDef],
+    table_constraints: &[TableConstraint],
+) -> QubeResult<Table> {
+    let mut columns: Vec<Column> = Vec::with_capacity(column_defs.len());
+    let mut constraints = Vec::new();
+
+    for def in column_defs {
+        if columns.iter().any(|c| c.name == def.name.value) {
+            return Err(QubeError::QueryParse(format!(
+                "Duplicate column name: {}",
+                def.name.value
+            )));
+        }
+
+        let serial = serial_type(&def.data_type);
+        let mut column = Column {
+            name: def.name.value.clone(),
+            data_type: match &serial {
+                Some(data_type) => data_type.clone(),
+                None => convert_data_type(&def.data_type)?,
+            },
+            nullable: true,
+            default_value: None,
+            primary_key: false,
+            unique: false,
+            index: false,
+            auto_increment: serial.is_some(),
+            json_schema: None,
+            generated: None,
+        };
+
+        let is_virtual = def.options.iter().any(|option| {
+            matches!(&option.option, ColumnOption::Comment(comment) if comment == VIRTUAL_MARKER)
+        });
+        for option in &def.options {
+            match &option.option {
+                ColumnOption::Default(expr) => {
+                    column.default_value = Some(default_value(expr, &column.data_type)?)
+                }
+                ColumnOption::Null => column.nullable = true,
+                ColumnOption::NotNull => column.nullable = false,
+                ColumnOption::Unique { is_primary: true } => {
+                    column.primary_key = true;
+                    column.nullable = false;
+                }
+                ColumnOption::Unique { is_primary: false } => column.unique = true,
+                ColumnOption::DialectSpecific(tokens)
+                    if tokens.iter().any(|token| {
+                        let token = token.to_string().to_uppercase();
+                        token == "AUTO_INCREMENT" || token == "AUTOINCREMENT"
+                    }) =>
+                {
+                    column.auto_increment = true
+                }
+                ColumnOption::ForeignKey {
+                    foreign_table,
+                    referred_columns,
+                    ..
+                } => constraints.push(Constraint {
+                    name: format!("{}_{}_fkey", name, column.name),
+                    constraint_type: ConstraintType::ForeignKey {
+                        referenced_table: object_name(foreign_table),
+                        referenced_column: referred_columns
+                            .first()
+                            .map(|c| c.value.clone())
+                            .unwrap_or_else(|| column.name.clone()),
+                    },
+                    columns: vec![column.name.clone()],
+                }),
+                ColumnOption::Generated {
+                    generated_as: GeneratedAs::ExpStored,
+                    generation_expr: Some(expr),
+                    ..
+                } => {
+                    let expression = expr.to_string();
+                    column.generated = Some(if is_virtual {
+                        Generated::Virtual(expression)
+                    } else {
+                        Generated::Stored(expression)
+                    });
+                }
+                ColumnOption::Check(expr) => match json_schema_check(expr, &column.name) {
+                    Some(schema) => column.json_schema = Some(column_json_schema(&column, schema)?),
+                    None => constraints.push(Constraint {
+                        name: format!("{}_{}_check", name, column.name),
+                        constraint_type: ConstraintType::Check {
+                            expression: expr.to_string(),
+                        },
+                        columns: vec![column.name.clone()],
+                    }),
+                },
+                _ => {}
+            }
+        }
+
+        columns.push(column);
+    }
+
+    let mut primary_key: Vec<String> = columns
+        .iter()
+        .filter(|c| c.primary_key)
+        .map(|c| c.name.clone())
+        .collect();
+    let mut unique_keys: Vec<Vec<String>> = columns
+        .iter()
+        .filter(|c| c.unique)
+        .map(|c| vec![c.name.clone()])
+        .collect();
+
+    for constraint in table_constraints {
+        match constraint {
+            TableConstraint::Unique {
+                columns: key,
+                is_primary,
+                ..
+            } => {
+                let key: Vec<String> = key.iter().map(|c| c.value.clone()).collect();
+                for column_name in &key {
+                    if !columns.iter().any(|c| &c.name == column_name) {
+                        return Err(QubeError::ColumnNotFound(format!(
+                            "{}.{}",
+                            name, column_name
+                        )));
+                    }
+                }
+                if *is_primary {
+                    if !primary_key.is_empty() && primary_key != key {
+                        return Err(QubeError::QueryParse(format!(
+                            "Multiple primary keys defined for table '{}'",
+                            name
+                        )));
+                    }
+                    primary_key = key;
+                } else if !unique_keys.contains(&key) {
+                    unique_keys.push(key);
+                }
+            }
+            TableConstraint::ForeignKey {
+                name: constraint_name,
+                columns: key,
+                foreign_table,
+                referred_columns,
+                ..
+            } => constraints.push(Constraint {
+                name: constraint_name
+                    .as_ref()
+                    .map(|n| n.value.clone())
+                    .unwrap_or_else(|| format!("{}_fkey", name)),
+                constraint_type: ConstraintType::ForeignKey {
+                    referenced_table: object_name(foreign_table),
+                    referenced_column: referred_columns
+                        .first()
+                        .map(|c| c.value.clone())
+                        .unwrap_or_default(),
+                },
+                columns: key.iter().map(|c| c.value.clone()).collect(),
+            }),
+            TableConstraint::Check {
+                name: constraint_name,
+                expr,
+            } => constraints.push(Constraint {
+                name: constraint_name
+                    .as_ref()
+                    .map(|n| n.value.clone())
+                    .unwrap_or_else(|| format!("{}_check", name)),
+                constraint_type: ConstraintType::Check {
+                    expression: expr.to_string(),
+                },
+                columns: vec![],
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(Table::with_keys(
+        name,
+        columns,
+        constraints,
+        primary_key,
+        unique_keys,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a script on `engine`, returning the result of its last statement
+    fn run(engine: &QueryEngine, sql: &str) -> QueryResult {
+        engine
+            .execute_script(sql)
+            .unwrap_or_else(|e| panic!("{}: {}", sql, e))
+            .pop()
+            .expect("a statement")
+    }
+
+    fn text(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    /// A write-ahead log path no other test uses, removed if it exists
+    fn wal_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("qubedb-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn ids(engine: &QueryEngine) -> Vec<Value> {
+        run(engine, "SELECT id FROM t ORDER BY id")
+            .rows
+            .iter()
+            .map(|row| row["id"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn auto_increment_numbers_rows_from_one() {
+        let engine = QueryEngine::new();
+        run(&engine, "CREATE TABLE t (id INT AUTO_INCREMENT, name TEXT)");
+        for (name, id) in [("a", 1), ("b", 2), ("c", 3)] {
+            let sql = format!("INSERT INTO t (name) VALUES ('{}')", name);
+            assert_eq!(run(&engine, &sql).last_insert_id, Some(id));
+        }
+        assert_eq!(
+            ids(&engine),
+            vec![Value::Int32(1), Value::Int32(2), Value::Int32(3)]
+        );
+
+        // Explicit ids move the sequence past them; failed inserts leave it
+        run(&engine, "INSERT INTO t VALUES (10, 'd')");
+        assert!(engine
+            .execute_script("INSERT INTO t (id, name) VALUES (NULL, 'e'), ('x', 'f')")
+            .is_err());
+        let result = run(&engine, "INSERT INTO t (name) VALUES ('g')");
+        assert_eq!(result.last_insert_id, Some(11));
+    }
+
+    #[test]
+    fn sequences_survive_reopening() {
+        let path = wal_path("sequences");
+        {
+            let engine = QueryEngine::open(&path, WalConfig::default()).unwrap();
+            run(&engine, "CREATE TABLE t (id INT AUTO_INCREMENT, name TEXT)");
+            run(&engine, "INSERT INTO t (name) VALUES ('a'), ('b'), ('c')");
+            run(&engine, "DELETE FROM t WHERE id = 3");
+        }
+        {
+            let engine = QueryEngine::open(&path, WalConfig::default()).unwrap();
+            let result = run(&engine, "INSERT INTO t (name) VALUES ('d')");
+            assert_eq!(result.last_insert_id, Some(4));
+            run(&engine, "DELETE FROM t WHERE id = 4");
+            let mut ctx = ExecutionContext::new(&QueryOptions::default());
+            let vacuum = engine.parse_sql("VACUUM").unwrap();
+            engine.execute_statement(vacuum, &mut ctx).unwrap();
+        }
+        // The checkpoint carries the sequence, not just the rows
+        let engine = QueryEngine::open(&path, WalConfig::default()).unwrap();
+        let result = run(&engine, "INSERT INTO t (name) VALUES ('e')");
+        assert_eq!(result.last_insert_id, Some(5));
+        assert_eq!(
+            ids(&engine),
+            vec![Value::Int32(1), Value::Int32(2), Value::Int32(5)]
+        );
+        drop(engine);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transactions_log_the_rows_they_committed() {
+        let path = wal_path("transactions");
+        {
+            let engine = Arc::new(QueryEngine::open(&path, WalConfig::default()).unwrap());
+            run(
+                &engine,
+                "CREATE TABLE t (id INT AUTO_INCREMENT PRIMARY KEY, name TEXT, v INT);
+                 CREATE TABLE u (v INT);
+                 INSERT INTO u VALUES (1), (1), (2)",
+            );
+            let context = crate::security::SecurityContext::admin("test");
+            let mut a = crate::session::Session::new(Arc::clone(&engine), context.clone());
+            let mut b = crate::session::Session::new(Arc::clone(&engine), context);
+            a.begin().unwrap();
+            b.begin().unwrap();
+            a.execute_script("INSERT INTO t (name, v) VALUES ('a', 1)")
+                .unwrap();
+            b.execute_script("INSERT INTO t (name, v) VALUES ('b', 2); DELETE FROM u WHERE v = 1")
+                .unwrap();
+            b.commit().unwrap();
+            a.execute_script("UPDATE t SET v = 10 WHERE id = 1")
+                .unwrap();
+            a.commit().unwrap();
+        }
+        let engine = QueryEngine::open(&path, WalConfig::default()).unwrap();
+        let rows: Vec<(Value, Value, Value)> =
+            run(&engine, "SELECT id, name, v FROM t ORDER BY id")
+                .rows
+                .iter()
+                .map(|row| (row["id"].clone(), row["name"].clone(), row["v"].clone()))
+                .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (Value::Int32(1), text("a"), Value::Int32(10)),
+                (Value::Int32(2), text("b"), Value::Int32(2)),
+            ]
+        );
+        assert_eq!(run(&engine, "SELECT v FROM u").rows.len(), 1);
+        assert_eq!(
+            run(&engine, "INSERT INTO t (name, v) VALUES ('c', 3)").last_insert_id,
+            Some(3)
+        );
+        drop(engine);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_limit_evicts_history_before_refusing_rows() {
+        let engine = QueryEngine::new();
+        let payload = "x".repeat(100);
+        run(&engine, "CREATE TABLE t (id INT, payload TEXT)");
+        for id in 0..10 {
+            run(
+                &engine,
+                &format!("INSERT INTO t VALUES ({}, '{}')", id, payload),
+            );
+        }
+        let rows = engine.memory_usage().rows;
+        let limit = rows + 4_000;
+        engine.set_memory_limit(Some(limit));
+
+        // Every update keeps the row it replaced, until that is evicted
+        for round in 0..50 {
+            let sql = format!(
+                "UPDATE t SET payload = '{}{}' WHERE id = {}",
+                payload,
+                round,
+                round % 10
+            );
+            run(&engine, &sql);
+            assert!(engine.memory_usage().total() <= limit);
+        }
+        let usage = engine.memory_usage();
+        assert!(usage.history > 0);
+        assert!(usage.total() > limit - 1_000, "{:?}", usage);
+
+        // Rows cannot be evicted
+        let mut refused = None;
+        for id in 10..100 {
+            let sql = format!("INSERT INTO t VALUES ({}, '{}')", id, payload);
+            if let Err(e) = engine.execute_script(&sql) {
+                refused = Some(e);
+                break;
+            }
+        }
+        assert!(refused
+            .unwrap()
+            .to_string()
+            .contains("memory limit exceeded"));
+        assert!(engine.memory_usage().rows <= limit);
+        assert!(engine.memory_usage().total() <= limit);
+
+        engine.set_memory_limit(None);
+        run(
+            &engine,
+            &format!("INSERT INTO t VALUES (100, '{}')", payload),
+        );
+    }
+
+    #[test]
+    fn distinct_removes_repeated_rows() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, name TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'a'), (4, 'c'), (5, 'b')",
+        );
+        let result = run(&engine, "SELECT DISTINCT name FROM t ORDER BY name");
+        let names: Vec<&Value> = result.rows.iter().map(|row| &row["name"]).collect();
+        assert_eq!(names, [&text("a"), &text("b"), &text("c")]);
+
+        // OFFSET and LIMIT count distinct rows
+        let result = run(
+            &engine,
+            "SELECT DISTINCT name FROM t ORDER BY name LIMIT 2 OFFSET 1",
+        );
+        let names: Vec<&Value> = result.rows.iter().map(|row| &row["name"]).collect();
+        assert_eq!(names, [&text("b"), &text("c")]);
+        let result = run(&engine, "SELECT DISTINCT name FROM t LIMIT 3");
+        assert_eq!(result.rows.len(), 3);
+    }
+
+    #[test]
+    fn unsupported_constructs_are_named() {
+        let engine = QueryEngine::new();
+        run(&engine, "CREATE TABLE t (id INT PRIMARY KEY, name TEXT)");
+        let cases = [
+            ("SELECT name FROM t GROUP BY name", "GROUP BY"),
+            ("SELECT DISTINCT ON (name) id FROM t", "DISTINCT ON"),
+            ("SELECT TOP 1 id FROM t", "TOP"),
+            (
+                "WITH RECURSIVE r AS (SELECT 1 AS n) SELECT n FROM r",
+                "WITH RECURSIVE",
+            ),
+            ("SELECT a.id FROM t a NATURAL JOIN t b", "NATURAL joins"),
+            (
+                "SELECT SUM(id) OVER (ORDER BY id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) FROM t",
+                "window frame",
+            ),
+        ];
+        for (sql, construct) in cases {
+            match engine.execute_script(sql) {
+                Err(QubeError::Unsupported(message)) => {
+                    assert!(message.contains(construct), "{}: {}", sql, message)
+                }
+                other => panic!("{}: expected Unsupported, got {:?}", sql, other),
+            }
+        }
+    }
+
+    #[test]
+    fn joins_read_the_smaller_table_first() {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (0..500)
+            .map(|i| format!("({}, {})", i, i % 2 + 1))
+            .collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE big (id INT PRIMARY KEY, small_id INT);
+                 CREATE TABLE small (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO small VALUES (1, 'x'), (2, 'y');
+                 INSERT INTO big VALUES {};
+                 ANALYZE TABLE big;
+                 ANALYZE TABLE small",
+                values.join(", ")
+            ),
+        );
+        let plan = run(
+            &engine,
+            "EXPLAIN SELECT big.id, small.name FROM big JOIN small ON big.small_id = small.id",
+        );
+        let lines: Vec<&Value> = plan.rows.iter().map(|row| &row["plan"]).collect();
+        assert_eq!(lines[0], &text("Scan small (estimated rows: 2)"));
+        assert_eq!(lines[1], &text("Hash Join big (estimated rows: 500)"));
+
+        let result = run(
+            &engine,
+            "SELECT big.id, small.name FROM big JOIN small ON big.small_id = small.id",
+        );
+        assert_eq!(result.rows.len(), 500);
+    }
+
+    #[test]
+    fn repeated_output_names_are_made_unique() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE a (id INT PRIMARY KEY, b_id INT);
+             CREATE TABLE b (id INT PRIMARY KEY);
+             INSERT INTO a VALUES (1, 10);
+             INSERT INTO b VALUES (10)",
+        );
+        let result = run(&engine, "SELECT a.id, b.id FROM a, b WHERE a.b_id = b.id");
+        assert_eq!(result.columns, ["a.id", "b.id"]);
+        assert_eq!(result.rows[0]["a.id"], Value::Int32(1));
+        assert_eq!(result.rows[0]["b.id"], Value::Int32(10));
+
+        let result = run(&engine, "SELECT id, id FROM a");
+        assert_eq!(result.columns, ["id", "id_1"]);
+        assert_eq!(result.rows[0]["id_1"], Value::Int32(1));
+    }
+
+    /// An engine whose `t` cross-joined with itself takes seconds to scan
+    fn slow_engine() -> QueryEngine {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (0..3000).map(|i| format!("({})", i)).collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE t (id INT); INSERT INTO t VALUES {}",
+                values.join(", ")
+            ),
+        );
+        engine
+    }
+
+    const SLOW_QUERY: &str = "SELECT COUNT(*) FROM t a, t b WHERE a.id + b.id = -1";
+
+    #[test]
+    fn timeouts_stop_a_running_query() {
+        let engine = slow_engine();
+        let started = Instant::now();
+        let options = QueryOptions::default().with_timeout(Duration::from_millis(20));
+        let error = engine
+            .execute_sql_with_options(SLOW_QUERY, &options)
+            .unwrap_err();
+        assert!(matches!(error, QubeError::Timeout(_)), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // A query that finishes in time is unaffected
+        let options = QueryOptions::default().with_timeout(Duration::from_secs(60));
+        let result = engine
+            .execute_sql_with_options("SELECT COUNT(*) AS n FROM t", &options)
+            .unwrap();
+        assert_eq!(result.rows[0]["n"], Value::Int64(3000));
+    }
+
+    #[test]
+    fn cancellation_stops_a_running_query() {
+        let engine = slow_engine();
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        let started = Instant::now();
+        let options = QueryOptions::default().with_cancellation(token.clone());
+        let error = engine
+            .execute_sql_with_options(SLOW_QUERY, &options)
+            .unwrap_err();
+        canceller.join().unwrap();
+        assert!(matches!(error, QubeError::Cancelled), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // A cancelled token stops queries before they start
+        let error = engine
+            .execute_sql_with_options("SELECT id FROM t", &options)
+            .unwrap_err();
+        assert!(matches!(error, QubeError::Cancelled), "{}", error);
+    }
+
+    #[test]
+    fn on_conflict_inserts_updates_or_skips() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, name TEXT, hits INT)",
+        );
+        let contents = |engine: &QueryEngine| -> Vec<(Value, Value)> {
+            run(engine, "SELECT name, hits FROM t ORDER BY id")
+                .rows
+                .iter()
+                .map(|row| (row["name"].clone(), row["hits"].clone()))
+                .collect()
+        };
+
+        // No conflict: the row is inserted
+        let result = run(
+            &engine,
+            "INSERT INTO t VALUES (1, 'a', 1) ON CONFLICT (id) DO UPDATE SET hits = t.hits + 1",
+        );
+        assert_eq!(result.affected_rows, 1);
+        assert_eq!(contents(&engine), [(text("a"), Value::Int32(1))]);
+
+        // A conflict with DO UPDATE changes the existing row
+        let result = run(
+            &engine,
+            "INSERT INTO t VALUES (1, 'b', 1)
+             ON CONFLICT (id) DO UPDATE SET hits = t.hits + 1, name = excluded.name",
+        );
+        assert_eq!(result.affected_rows, 1);
+        assert_eq!(contents(&engine), [(text("b"), Value::Int32(2))]);
+
+        // A conflict with DO NOTHING leaves it alone, without an error
+        let result = run(
+            &engine,
+            "INSERT INTO t VALUES (1, 'c', 9), (2, 'd', 1) ON CONFLICT (id) DO NOTHING",
+        );
+        assert_eq!(result.affected_rows, 1);
+        assert_eq!(
+            contents(&engine),
+            [(text("b"), Value::Int32(2)), (text("d"), Value::Int32(1))]
+        );
+
+        // The same row may not be affected twice in one statement
+        assert!(engine
+            .execute_script(
+                "INSERT INTO t VALUES (3, 'e', 1), (3, 'f', 1)
+                 ON CONFLICT (id) DO UPDATE SET hits = t.hits + 1"
+            )
+            .is_err());
+        // The target must be a unique index
+        assert!(engine
+            .execute_script("INSERT INTO t VALUES (4, 'g', 1) ON CONFLICT (name) DO NOTHING")
+            .is_err());
+        assert_eq!(contents(&engine).len(), 2);
+    }
+
+    #[test]
+    fn unique_keys_are_checked_against_stored_and_new_rows() {
+        let engine = QueryEngine::new();
+        run(&engine, "CREATE TABLE t (id INT PRIMARY KEY, name TEXT)");
+        run(&engine, "INSERT INTO t VALUES (1, 'a'), (2, 'b')");
+        let duplicate = |sql: &str| match engine.execute_script(sql) {
+            Err(QubeError::ConstraintViolation(_)) => {}
+            other => panic!("{}: expected a constraint violation, got {:?}", sql, other),
+        };
+
+        // Rows from an earlier statement and rows of the same batch
+        duplicate("INSERT INTO t VALUES (2, 'c')");
+        duplicate("INSERT INTO t VALUES (3, 'c'), (3, 'd')");
+        duplicate("UPDATE t SET id = 2 WHERE id = 1");
+        duplicate("UPDATE t SET id = 5");
+        assert_eq!(ids(&engine), vec![Value::Int32(1), Value::Int32(2)]);
+
+        // Keys the statement itself gives up may be reused
+        run(&engine, "UPDATE t SET id = 3 - id");
+        assert_eq!(
+            run(&engine, "SELECT name FROM t WHERE id = 1").rows[0]["name"],
+            text("b")
+        );
+        run(&engine, "INSERT INTO t VALUES (3, 'c')");
+        duplicate("INSERT INTO t VALUES (3, 'd')");
+        assert_eq!(
+            ids(&engine),
+            vec![Value::Int32(1), Value::Int32(2), Value::Int32(3)]
+        );
+    }
+
+    #[test]
+    fn analyze_accepts_the_table_keyword_or_a_bare_name() {
+        assert_eq!(
+            analyze_shorthand("ANALYZE t").as_deref(),
+            Some("ANALYZE TABLE t")
+        );
+        assert_eq!(analyze_shorthand("ANALYZE TABLE t"), None);
+        assert_eq!(
+            analyze_shorthand("analyze aééé").as_deref(),
+            Some("ANALYZE TABLE aééé")
+        );
+
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT); INSERT INTO t VALUES (1), (2)",
+        );
+        for sql in ["ANALYZE t", "ANALYZE TABLE t"] {
+            let result = run(&engine, sql);
+            assert_eq!(result.rows.len(), 1, "{}", sql);
+            assert_eq!(result.rows[0]["row_count"], Value::Int64(2));
+        }
+        // A name that is not ASCII reaches the catalog instead of panicking
+        assert!(engine.execute_script("ANALYZE aééé").is_err());
+    }
+
+    #[test]
+    fn scripts_are_split_before_rewriting_shorthands() {
+        assert_eq!(
+            split_statements("SELECT 'a;b'; -- c;\n SELECT \"d;\" ;; /* e; */").unwrap(),
+            ["SELECT 'a;b'", " -- c;\n SELECT \"d;\" "]
+        );
+        let statements = parse_script("INSERT INTO t VALUES ('it''s'); VACUUM t; VACUUM").unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[0].to_string(), "INSERT INTO t VALUES ('it''s')");
+        assert_eq!(ShowCommand::of(&statements[1]), Some(ShowCommand::Vacuum));
+        assert_eq!(Permission::required_for(&statements[2]), Permission::Schema);
+
+        // VACUUM is refused inside a script rather than failing to parse
+        let engine = QueryEngine::new();
+        run(&engine, "CREATE TABLE t (name TEXT)");
+        match engine.execute_script("INSERT INTO t VALUES ('a'); VACUUM t") {
+            Err(QubeError::Transaction(_)) => {}
+            other => panic!("expected a transaction error, got {:?}", other),
+        }
+        assert!(run(&engine, "SELECT name FROM t").rows.is_empty());
+        // A statement no shorthand rewrites still reports the parse error
+        match engine.execute_script("INSERT INTO t VALUES ('a'); VACUUM t; SELEC 1") {
+            Err(QubeError::QueryParse(_)) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reindex_runs_inside_scripts() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, name TEXT);
+             CREATE INDEX by_name ON t (name)",
+        );
+        let results = engine
+            .execute_script(
+                "INSERT INTO t VALUES (1, 'a'), (2, 'b');
+                 REINDEX t;
+                 reindex index by_name;
+                 SELECT id FROM t WHERE name = 'b'",
+            )
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[1].affected_rows, 2);
+        assert_eq!(results[2].affected_rows, 1);
+        assert_eq!(results[3].rows[0]["id"], Value::Int32(2));
+        assert!(engine.verify_indexes().is_empty());
+
+        let statements = parse_script("REINDEX TABLE t; SELECT 1").unwrap();
+        assert_eq!(Permission::required_for(&statements[0]), Permission::Schema);
+    }
+
+    #[test]
+    fn as_of_reads_mix_with_other_shorthands_in_scripts() {
+        let engine = QueryEngine::new();
+        engine.set_history_retention(HistoryRetention::new(Duration::from_secs(3600)));
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, name TEXT); INSERT INTO t VALUES (1, 'a')",
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let results = engine
+            .execute_script(&format!(
+                "UPDATE t SET name = 'b';
+                 SELECT name FROM t AS OF TIMESTAMP {};
+                 REINDEX t;
+                 SELECT name FROM t",
+                before
+            ))
+            .unwrap();
+        assert_eq!(results[1].rows[0]["name"], text("a"));
+        assert_eq!(results[3].rows[0]["name"], text("b"));
+    }
+
+    #[test]
+    fn tables_can_keep_history_of_their_own() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, v INT)
+                 WITH (history_retention = 0.05, history_versions = 2);
+             CREATE TABLE u (id INT PRIMARY KEY);
+             INSERT INTO t VALUES (1, 1);
+             UPDATE t SET v = 2;
+             UPDATE t SET v = 3;
+             INSERT INTO u VALUES (1)",
+        );
+        let retention = HistoryRetention::new(Duration::from_millis(50)).with_max_versions(2);
+        {
+            let catalog = engine.catalog.read().unwrap();
+            let t = catalog.table_data("t").unwrap();
+            assert_eq!(t.own_history_retention(), Some(retention));
+            assert_eq!(t.history_len(), 2);
+            assert_eq!(
+                catalog.table_data("u").unwrap().history_retention(),
+                HistoryRetention::default()
+            );
+            let statement = dump::create_table(catalog.get_table("t").unwrap(), t);
+            assert!(
+                statement.contains("history_retention = 0.05, history_versions = 2"),
+                "{}",
+                statement
+            );
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            - 80;
+        assert!(matches!(
+            engine.execute_script(&format!("SELECT v FROM t AS OF TIMESTAMP {}", at)),
+            Err(QubeError::NotFound(_))
+        ));
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        let vacuum = engine.parse_sql("VACUUM").unwrap();
+        let vacuum = engine.execute_statement(vacuum, &mut ctx).unwrap();
+        assert_eq!(vacuum.rows[0]["versions_reclaimed"], Value::Int64(2));
+        assert_eq!(engine.set_history_retention(HistoryRetention::none()), 1);
+
+        let invalid = engine.execute_script("CREATE TABLE w (id INT) WITH (history_versions = -1)");
+        assert!(matches!(invalid, Err(QubeError::QueryParse(_))));
+    }
+
+    #[test]
+    fn the_advisor_proposes_indexes_the_planner_would_use() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE orders (id INT PRIMARY KEY, status TEXT, customer INT, total INT);
+             CREATE TABLE customers (id INT PRIMARY KEY, region TEXT)",
+        );
+        let mut script = String::new();
+        for id in 0..200 {
+            script.push_str(&format!(
+                "INSERT INTO orders VALUES ({}, '{}', {}, {});",
+                id,
+                ["new", "paid", "shipped", "closed"][id % 4],
+                id % 50,
+                id
+            ));
+        }
+        for id in 0..50 {
+            script.push_str(&format!(
+                "INSERT INTO customers VALUES ({}, 'r{}');",
+                id,
+                id % 5
+            ));
+        }
+        engine.execute_script(&script).unwrap();
+        run(&engine, "ANALYZE TABLE orders");
+
+        let advice = engine
+            .advise_indexes(&[
+                "SELECT id FROM orders WHERE status = 'paid' AND customer = 7",
+                "SELECT id FROM orders WHERE customer = 3 AND total > 100",
+                "SELECT * FROM orders o JOIN customers c ON o.customer = c.id",
+                "SELECT id FROM orders WHERE id = 5",
+            ])
+            .unwrap();
+        let proposed: Vec<(&str, &str, usize)> = advice
+            .iter()
+            .map(|r| (r.table.as_str(), r.statement.as_str(), r.queries))
+            .collect();
+        assert_eq!(
+            proposed,
+            [
+                (
+                    "orders",
+                    "CREATE INDEX orders_customer_status_idx ON orders (customer, status)",
+                    1
+                ),
+                (
+                    "orders",
+                    "CREATE INDEX orders_customer_total_idx ON orders (customer, total)",
+                    1
+                ),
+            ]
+        );
+        assert!(advice
+            .iter()
+            .all(|r| r.rows_scanned == 200.0 && r.benefit > 0.0));
+
+        // Once created, the index is no longer proposed
+        run(&engine, &advice[0].statement);
+        let advice = engine
+            .advise_indexes(&["SELECT id FROM orders WHERE status = 'paid' AND customer = 7"])
+            .unwrap();
+        assert!(advice.is_empty(), "{:?}", advice);
+        assert!(matches!(
+            engine.advise_indexes(&["DELETE FROM orders"]),
+            Err(QubeError::QueryParse(_))
+        ));
+    }
+
+    #[test]
+    fn show_and_describe_read_the_catalog() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE, name TEXT NOT NULL, team INT);
+             CREATE INDEX users_team ON users (team, name);
+             CREATE TABLE teams (id INT PRIMARY KEY)",
+        );
+
+        let tables = run(&engine, "SHOW TABLES");
+        assert_eq!(tables.columns, ["table_name"]);
+        let names: Vec<&Value> = tables.rows.iter().map(|row| &row["table_name"]).collect();
+        assert_eq!(names, [&text("teams"), &text("users")]);
+        let like = run(&engine, "SHOW TABLES LIKE 'us%'");
+        assert_eq!(like.rows.len(), 1);
+
+        let columns = run(&engine, "DESCRIBE users");
+        assert_eq!(columns.columns, ["name", "type", "nullable", "key"]);
+        let described: Vec<(&Value, &Value, &Value)> = columns
+            .rows
+            .iter()
+            .map(|row| (&row["name"], &row["nullable"], &row["key"]))
+            .collect();
+        assert_eq!(
+            described,
+            [
+                (&text("id"), &Value::Boolean(false), &text("PRI")),
+                (&text("email"), &Value::Boolean(true), &text("UNI")),
+                (&text("name"), &Value::Boolean(false), &text("")),
+                (&text("team"), &Value::Boolean(true), &text("MUL")),
+            ]
+        );
+        assert_eq!(columns.rows[0]["type"], text("INT"));
+
+        let indexes = run(&engine, "SHOW INDEXES FROM users");
+        let index = indexes
+            .rows
+            .iter()
+            .find(|row| row["name"] == text("users_team"))
+            .expect("the created index");
+        assert_eq!(index["columns"], text("team, name"));
+        assert_eq!(index["unique"], Value::Boolean(false));
+
+        assert!(matches!(
+            engine.execute_script("DESCRIBE missing"),
+            Err(QubeError::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn results_say_whether_they_carry_rows_or_a_count() {
+        let engine = QueryEngine::new();
+        let results = engine
+            .execute_script(
+                "CREATE TABLE t (id INT PRIMARY KEY, n INT);
+                 INSERT INTO t VALUES (1, 0), (2, 0), (3, 0);
+                 UPDATE t SET n = 1 WHERE id > 1;
+                 DELETE FROM t WHERE id = 3;
+                 SELECT id FROM t;
+                 SELECT id FROM t WHERE id > 5",
+            )
+            .unwrap();
+        let counts: Vec<(ResultKind, usize, usize)> = results
+            .iter()
+            .map(|result| (result.kind, result.affected_rows, result.row_count()))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (ResultKind::Mutation, 0, 0),
+                (ResultKind::Mutation, 3, 3),
+                (ResultKind::Mutation, 2, 2),
+                (ResultKind::Mutation, 1, 1),
+                (ResultKind::Rows, 0, 2),
+                (ResultKind::Rows, 0, 0),
+            ]
+        );
+        assert!(results[4].is_result_set() && !results[1].is_result_set());
+    }
+
+    #[test]
+    fn existing_and_missing_objects_have_their_own_errors() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT); CREATE INDEX t_id ON t (id)",
+        );
+        let error = engine
+            .execute_script("CREATE TABLE t (id INT)")
+            .unwrap_err();
+        assert!(matches!(error, QubeError::AlreadyExists(_)));
+        assert_eq!(error.http_status(), 409);
+        assert!(matches!(
+            engine.execute_script("CREATE INDEX t_id ON t (id)"),
+            Err(QubeError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            engine.execute_script("DROP INDEX missing"),
+            Err(QubeError::NotFound(_))
+        ));
+
+        run(
+            &engine,
+            "CREATE TABLE IF NOT EXISTS t (id INT);
+             CREATE INDEX IF NOT EXISTS t_id ON t (id);
+             DROP INDEX IF EXISTS missing;
+             DROP TABLE IF EXISTS missing",
+        );
+    }
+
+    #[test]
+    fn limits_stop_single_table_scans_early() {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (1..=100).map(|i| format!("({}, {})", i, i % 3)).collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE t (id INT PRIMARY KEY, g INT); INSERT INTO t VALUES {}",
+                values.join(", ")
+            ),
+        );
+        let sql = "SELECT id FROM t WHERE g = 1 LIMIT 3 OFFSET 2";
+        let ids: Vec<Value> = run(&engine, sql)
+            .rows
+            .iter()
+            .map(|row| row["id"].clone())
+            .collect();
+        assert_eq!(ids, [Value::Int32(7), Value::Int32(10), Value::Int32(13)]);
+
+        // The scan reads rows 1 to 13, where it has found OFFSET + LIMIT
+        let plan = run(&engine, &format!("EXPLAIN ANALYZE {}", sql));
+        let lines: Vec<String> = plan
+            .rows
+            .iter()
+            .map(|row| row["plan"].to_string())
+            .collect();
+        assert!(
+            lines[0].contains("actual rows: 5, examined: 13"),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[3].starts_with("Limit: 3 (pushed into scan)"));
+
+        // DISTINCT has to see every row first
+        let plan = run(&engine, "EXPLAIN SELECT DISTINCT g FROM t LIMIT 2");
+        let limit = plan.rows.last().unwrap()["plan"].clone();
+        assert_eq!(limit, text("Limit: 2"));
+        assert_eq!(
+            run(&engine, "SELECT DISTINCT g FROM t LIMIT 2").rows.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn unquoted_identifiers_fold_to_lower_case() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            r#"CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT);
+               CREATE TABLE "Users" ("ID" INT);
+               INSERT INTO USERS (id, NAME) VALUES (1, 'ann');
+               INSERT INTO "Users" VALUES (2);
+               CREATE INDEX Users_Name ON users (Name)"#,
+        );
+
+        let result = run(
+            &engine,
+            "SELECT U.Name AS Who FROM users AS U WHERE u.ID = 1",
+        );
+        assert_eq!(result.columns, ["who"]);
+        assert_eq!(result.rows[0]["who"], text("ann"));
+        // The quoted name is a table of its own, with its own spelling
+        let quoted = run(&engine, r#"SELECT "ID" FROM "Users""#);
+        assert_eq!(quoted.rows[0]["ID"], Value::Int32(2));
+        assert!(engine.execute_script(r#"SELECT id FROM "Users""#).is_err());
+
+        let tables = run(&engine, "SHOW TABLES");
+        let names: Vec<&Value> = tables.rows.iter().map(|row| &row["table_name"]).collect();
+        assert_eq!(names, [&text("Users"), &text("users")]);
+        run(&engine, "DROP INDEX USERS_NAME");
+    }
+
+    #[test]
+    fn inserts_fill_in_column_defaults() {
+        let engine = QueryEngine::new();
+        let before = chrono::Utc::now().timestamp_millis();
+        run(
+            &engine,
+            "CREATE TABLE t (
+                 id INT PRIMARY KEY,
+                 status TEXT DEFAULT 'new',
+                 n INT DEFAULT 5,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                 note TEXT
+             );
+             INSERT INTO t (id) VALUES (1);
+             INSERT INTO t VALUES (2, DEFAULT, 7, DEFAULT, 'x')",
+        );
+        let rows = run(&engine, "SELECT * FROM t ORDER BY id").rows;
+        assert_eq!(rows[0]["status"], text("new"));
+        assert_eq!(rows[0]["n"], Value::Int32(5));
+        assert_eq!(rows[0]["note"], Value::Null);
+        assert_eq!(rows[1]["status"], text("new"));
+        assert_eq!(rows[1]["n"], Value::Int32(7));
+        for row in &rows {
+            match row["created"] {
+                Value::Timestamp(at) => assert!(at >= before),
+                ref other => panic!("created is {:?}", other),
+            }
+        }
+
+        // Constants are checked against the column type up front
+        assert!(engine
+            .execute_script("CREATE TABLE bad (n INT DEFAULT 'many')")
+            .is_err());
+        assert!(engine.execute_script("SELECT * FROM bad").is_err());
+    }
+
+    /// Records each span as it opens, as `parent > name{fields}`, and each
+    /// field recorded later as `name{fields}`
+    #[derive(Clone, Default)]
+    struct SpanLog(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanLog
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields(String::new());
+            attrs.record(&mut fields);
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|p| format!("{} > ", p.name()));
+            let entry = format!(
+                "{}{}{{{}}}",
+                parent.unwrap_or_default(),
+                span.name(),
+                fields.0
+            );
+            self.0.lock().unwrap().push(entry);
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields(String::new());
+            values.record(&mut fields);
+            let entry = format!("{}{{{}}}", ctx.span(id).unwrap().name(), fields.0);
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn queries_run_inside_tracing_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY); INSERT INTO t VALUES (1), (2)",
+        );
+        let log = SpanLog::default();
+        let subscriber = tracing_subscriber::registry().with(log.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            engine
+                .execute_sql_with_options("SELECT * FROM t", &QueryOptions::default())
+                .unwrap();
+            assert!(engine
+                .execute_sql_with_options("SELEC", &QueryOptions::default())
+                .is_err());
+        });
+
+        let spans = log.0.lock().unwrap();
+        assert_eq!(
+            spans[..5],
+            [
+                r#"execute_sql{ sql="SELECT * FROM t"}"#,
+                "execute_sql > parse{}",
+                "execute_sql > execute{}",
+                r#"execute > plan{ tables=["t"]}"#,
+                "execute_sql{ rows=2}",
+            ]
+        );
+        assert!(spans[5].starts_with("execute_sql{ duration_ms="));
+        assert_eq!(
+            spans[6..],
+            [r#"execute_sql{ sql="SELEC"}"#, "execute_sql > parse{}"]
+        );
+    }
+
+    #[test]
+    fn written_rows_reach_change_listeners_once_committed() {
+        use crate::changes::{ChangeKind, OverflowPolicy};
+
+        let engine = QueryEngine::new();
+        run(&engine, "CREATE TABLE t (id INT PRIMARY KEY, n INT)");
+        let listener = engine.subscribe_changes(ChangeListenerConfig::default());
+        run(&engine, "INSERT INTO t VALUES (1, 10), (2, 20)");
+        run(&engine, "UPDATE t SET n = 11 WHERE id = 1");
+        let events = listener.drain(10);
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|e| (e.kind, e.row["id"].clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (ChangeKind::Insert, Value::Int32(1)),
+                (ChangeKind::Insert, Value::Int32(2)),
+                (ChangeKind::Update, Value::Int32(1)),
+            ]
+        );
+        assert_eq!(events[2].row["n"], Value::Int32(11));
+
+        // A script that fails part way publishes nothing
+        assert!(engine
+            .execute_script("INSERT INTO t VALUES (3, 30); INSERT INTO t VALUES (1, 0)")
+            .is_err());
+        assert!(listener.try_recv().is_none());
+
+        let strict = engine.subscribe_changes(ChangeListenerConfig {
+            capacity: 1,
+            overflow: OverflowPolicy::Error,
+        });
+        assert!(engine
+            .execute_script("INSERT INTO t VALUES (3, 30), (4, 40)")
+            .is_err());
+        assert_eq!(run(&engine, "SELECT * FROM t").rows.len(), 2);
+        run(&engine, "INSERT INTO t VALUES (3, 30)");
+        assert_eq!(strict.metrics().rejected, 1);
+        assert_eq!(listener.drain(10).len(), 1);
+    }
+
+    #[test]
+    fn joins_carry_only_the_columns_they_need() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, bio TEXT);
+             CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, total INT, notes TEXT);
+             INSERT INTO users VALUES (1, 'ann', 'x'), (2, 'bo', 'y');
+             INSERT INTO orders VALUES (10, 1, 5, 'a'), (11, 2, 7, 'b'), (12, 1, 9, 'c')",
+        );
+        let sql = "SELECT u.name FROM users u JOIN orders o ON o.user_id = u.id
+                   WHERE o.total + u.id > 6 ORDER BY o.id";
+        let plan = run(&engine, &format!("EXPLAIN {}", sql));
+        assert!(plan
+            .rows
+            .iter()
+            .any(|row| row["plan"] == text("Columns: u.id, u.name, o.id, o.total, o.user_id")));
+
+        let names: Vec<Value> = run(&engine, sql)
+            .rows
+            .iter()
+            .map(|row| row["name"].clone())
+            .collect();
+        assert_eq!(names, [text("bo"), text("ann")]);
+
+        // SELECT * still returns every column of both tables
+        let all = run(
+            &engine,
+            "SELECT * FROM users u JOIN orders o ON o.user_id = u.id",
+        );
+        assert_eq!(all.columns.len(), 7);
+        assert_eq!(all.rows[0].len(), 7);
+    }
+
+    #[test]
+    fn columnar_scans_read_only_the_columns_a_query_uses() {
+        use crate::columnar::{ColumnStoreStats, CHUNK_ROWS};
+
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (1..=CHUNK_ROWS + 6)
+            .map(|i| format!("({}, {}, 'note')", i, i % 10))
+            .collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE sales (id INT PRIMARY KEY, amount INT, note TEXT)
+                 WITH (storage = 'columnar');
+                 INSERT INTO sales VALUES {}",
+                values.join(", ")
+            ),
+        );
+        assert_eq!(
+            engine.describe_table("sales").unwrap().storage,
+            StorageLayout::Columnar
+        );
+        let stats = || engine.column_store_stats("sales").unwrap().unwrap();
+        let written = stats();
+        assert_eq!(
+            written,
+            ColumnStoreStats {
+                chunks: 1,
+                chunked_rows: CHUNK_ROWS,
+                buffered_rows: 6,
+                ..written
+            }
+        );
+
+        let sum = run(&engine, "SELECT SUM(amount) AS s FROM sales");
+        assert_eq!(sum.rows[0]["s"], Value::Int64(4_635));
+        assert_eq!(stats().values_read - written.values_read, CHUNK_ROWS);
+        run(&engine, "SELECT COUNT(*) FROM sales");
+        assert_eq!(stats().values_read - written.values_read, CHUNK_ROWS);
+
+        run(
+            &engine,
+            "UPDATE sales SET note = 'changed' WHERE id = 3; DELETE FROM sales WHERE id = 4",
+        );
+        let row = run(&engine, "SELECT note FROM sales WHERE id = 3");
+        assert_eq!(row.rows[0]["note"], text("changed"));
+        assert!(run(&engine, "SELECT * FROM sales WHERE id = 4")
+            .rows
+            .is_empty());
+
+        let plan = run(&engine, "EXPLAIN SELECT amount FROM sales");
+        assert!(plan.rows[0]["plan"]
+            .to_string()
+            .starts_with("Columnar Scan sales"));
+        assert_eq!(engine.column_store_stats("missing").ok(), None);
+    }
+
+    #[test]
+    fn composite_indexes_serve_leftmost_prefixes() {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (0..30)
+            .map(|i| format!("({}, {}, {})", i, i % 3, i % 10))
+            .collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE t (id INT PRIMARY KEY, a INT, b INT);
+                 CREATE INDEX t_ab ON t (a, b);
+                 INSERT INTO t VALUES {}",
+                values.join(", ")
+            ),
+        );
+        let plan = |sql: &str| -> Vec<String> {
+            run(&engine, &format!("EXPLAIN {}", sql))
+                .rows
+                .iter()
+                .map(|row| row["plan"].to_string())
+                .collect()
+        };
+        let ids = |sql: &str| -> Vec<Value> {
+            run(&engine, sql)
+                .rows
+                .iter()
+                .map(|row| row["id"].clone())
+                .collect()
+        };
+
+        let sql = "SELECT id FROM t WHERE a = 1 AND b > 5 ORDER BY id";
+        assert!(plan(sql).contains(&"  Index Scan: t_ab (a = 1 AND b > 5)".to_string()));
+        assert_eq!(
+            ids(sql),
+            [
+                Value::Int32(7),
+                Value::Int32(16),
+                Value::Int32(19),
+                Value::Int32(28)
+            ]
+        );
+
+        let sql = "SELECT id FROM t WHERE a = 2 AND b BETWEEN 1 AND 2 ORDER BY id";
+        assert!(plan(sql)
+            .iter()
+            .any(|line| line.starts_with("  Index Scan: t_ab")));
+        assert_eq!(ids(sql), [Value::Int32(2), Value::Int32(11)]);
+
+        // A filter that skips the first column cannot use the index
+        let sql = "SELECT id FROM t WHERE b = 4 ORDER BY id";
+        assert!(!plan(sql).iter().any(|line| line.contains("t_ab")));
+        assert_eq!(
+            ids(sql),
+            [Value::Int32(4), Value::Int32(14), Value::Int32(24)]
+        );
+
+        // The index follows later writes, and is rebuilt from the rows
+        let sql = "SELECT id FROM t WHERE a = 1 AND b > 5 ORDER BY id";
+        let expected = [Value::Int32(7), Value::Int32(28)];
+        run(
+            &engine,
+            "UPDATE t SET b = 0 WHERE id = 16; DELETE FROM t WHERE id = 19",
+        );
+        assert_eq!(ids(sql), expected);
+        run(&engine, "DROP INDEX t_ab; CREATE INDEX t_ab ON t (a, b)");
+        assert_eq!(ids(sql), expected);
+    }
+
+    #[test]
+    fn covering_indexes_answer_scans_without_reading_rows() {
+        let engine = QueryEngine::new();
+        let values: Vec<String> = (0..12)
+            .map(|i| format!("({}, {}, {}, 'x')", i, i % 3, i))
+            .collect();
+        run(
+            &engine,
+            &format!(
+                "CREATE TABLE t (id INT PRIMARY KEY, a INT, b INT, note TEXT);
+                 CREATE INDEX t_ab ON t (a, b);
+                 INSERT INTO t VALUES {}",
+                values.join(", ")
+            ),
+        );
+        let explain = |sql: &str| -> Vec<String> {
+            run(&engine, &format!("EXPLAIN {}", sql))
+                .rows
+                .iter()
+                .map(|row| row["plan"].to_string())
+                .collect()
+        };
+        let select = |sql: &str| {
+            engine
+                .execute_sql_with_options(sql, &QueryOptions::default())
+                .unwrap()
+                .rows
+        };
+
+        let covered = "SELECT a, b FROM t WHERE a = 1 AND b > 4 ORDER BY b";
+        assert!(explain(covered)
+            .contains(&"  Index-Only Scan: t_ab (a = 1 AND b > 4) in key order".to_string()));
+        let before = engine.scan_metrics();
+        let rows = select(covered);
+        let bs: Vec<&Value> = rows.iter().map(|row| &row["b"]).collect();
+        assert_eq!(bs, [&Value::Int32(7), &Value::Int32(10)]);
+        let after = engine.scan_metrics();
+        assert_eq!(after.index_only_rows - before.index_only_rows, 2);
+        assert_eq!(after.rows_fetched, before.rows_fetched);
+
+        // COUNT(*) reads no columns, so any index on the filter covers it
+        let count = "SELECT COUNT(*) AS n FROM t WHERE a = 2";
+        assert!(explain(count)
+            .iter()
+            .any(|line| line.starts_with("  Index-Only Scan: t_ab")));
+        assert_eq!(run(&engine, count).rows[0]["n"], Value::Int64(4));
+
+        // A column outside the index means reading the rows
+        let uncovered = "SELECT note FROM t WHERE a = 1 AND b > 4";
+        assert!(explain(uncovered)
+            .iter()
+            .any(|line| line.starts_with("  Index Scan: t_ab")));
+        let before = engine.scan_metrics();
+        assert_eq!(select(uncovered).len(), 2);
+        let after = engine.scan_metrics();
+        assert_eq!(after.rows_fetched - before.rows_fetched, 2);
+        assert_eq!(after.index_only_rows, before.index_only_rows);
+    }
+
+    #[test]
+    fn indexes_stay_consistent_through_writes() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, a INT, b TEXT UNIQUE);
+             CREATE INDEX t_a ON t (a);
+             INSERT INTO t VALUES (1, 1, 'x'), (2, 2, 'y'), (3, 1, 'z');
+             UPDATE t SET a = 5, b = 'w' WHERE id = 1;
+             DELETE FROM t WHERE id = 2",
+        );
+        assert_eq!(engine.verify_indexes(), []);
+        // A failed write leaves no half-applied index entries behind
+        assert!(engine
+            .execute_script("UPDATE t SET b = 'z' WHERE id = 1")
+            .is_err());
+        assert_eq!(engine.verify_indexes(), []);
+    }
+
+    #[test]
+    fn stale_statistics_are_refreshed_on_demand() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE a (id INT PRIMARY KEY); CREATE TABLE b (id INT PRIMARY KEY);
+             INSERT INTO a VALUES (1), (2); ANALYZE TABLE a",
+        );
+        assert_eq!(engine.refresh_stale_stats().unwrap(), 0);
+        assert!(engine.table_stats("b").unwrap().is_none());
+
+        // Changes below the threshold leave the statistics alone
+        for (table, modified) in [("a", 1), ("b", AUTO_ANALYZE_THRESHOLD)] {
+            let mut catalog = engine.catalog.write().unwrap();
+            catalog.table_data_mut(table).unwrap().1.modified_rows = modified;
+        }
+        assert_eq!(engine.refresh_stale_stats().unwrap(), 1);
+        assert!(engine.table_stats("b").unwrap().is_some());
+        assert_eq!(engine.refresh_stale_stats().unwrap(), 0);
+    }
+
+    #[test]
+    fn json_schema_checks_are_declared_in_sql() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            r#"CREATE TABLE docs (
+                 id INT PRIMARY KEY,
+                 body JSON CHECK (JSON_SCHEMA_VALID('{"required": ["kind"]}', body))
+               )"#,
+        );
+        let schema = engine.describe_table("docs").unwrap().columns[1]
+            .json_schema
+            .clone()
+            .unwrap();
+        assert_eq!(schema.schema()["required"][0], "kind");
+
+        run(
+            &engine,
+            r#"INSERT INTO docs VALUES (1, '{"kind": "note"}')"#,
+        );
+        for sql in [
+            r#"INSERT INTO docs VALUES (2, '{"other": 1}')"#,
+            r#"UPDATE docs SET body = '{"other": 2}' WHERE id = 1"#,
+        ] {
+            assert!(matches!(
+                engine.execute_script(sql),
+                Err(QubeError::ConstraintViolation(_))
+            ));
+        }
+        assert_eq!(run(&engine, "SELECT * FROM docs").rows.len(), 1);
+
+        assert!(engine
+            .execute_script(
+                "CREATE TABLE bad (body JSON CHECK (JSON_SCHEMA_VALID('{\"type\": 1}', body)))"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn generated_columns_follow_the_columns_they_read() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE people (
+               id INT PRIMARY KEY,
+               first TEXT,
+               last TEXT,
+               full_name TEXT GENERATED ALWAYS AS (CONCAT(first, ' ', last)) STORED,
+               shout TEXT GENERATED ALWAYS AS (UPPER(last)) VIRTUAL
+             );
+             CREATE INDEX people_full_name ON people (full_name)",
+        );
+        let columns = engine.describe_table("people").unwrap().columns;
+        assert!(!columns[3].is_virtual());
+        assert!(columns[4].is_virtual());
+
+        run(&engine, "INSERT INTO people VALUES (1, 'Ada', 'Lovelace')");
+        run(
+            &engine,
+            "INSERT INTO people (id, first, last, shout) VALUES (2, 'Alan', 'Turing', DEFAULT)",
+        );
+        run(&engine, "UPDATE people SET last = 'Byron' WHERE id = 1");
+        let rows = run(&engine, "SELECT full_name, shout FROM people ORDER BY id").rows;
+        assert_eq!(rows[0]["full_name"], text("Ada Byron"));
+        assert_eq!(rows[0]["shout"], text("BYRON"));
+        assert_eq!(rows[1]["full_name"], text("Alan Turing"));
+
+        let found = run(&engine, "SELECT id FROM people WHERE shout = 'TURING'").rows;
+        assert_eq!(
+            found,
+            vec![Row::from([("id".to_string(), Value::Int32(2))])]
+        );
+        run(&engine, "DELETE FROM people WHERE shout = 'BYRON'");
+        assert_eq!(run(&engine, "SELECT * FROM people").rows.len(), 1);
+
+        for sql in [
+            "INSERT INTO people (id, full_name) VALUES (3, 'x')",
+            "UPDATE people SET shout = 'x'",
+            "CREATE INDEX people_shout ON people (shout)",
+            "CREATE TABLE bad (a INT, b INT GENERATED ALWAYS AS (a +) STORED)",
+            "CREATE TABLE bad (a INT, b INT GENERATED ALWAYS AS (c + 1) STORED)",
+            "CREATE TABLE bad (a INT, b INT GENERATED ALWAYS AS (a) STORED, \
+             c INT GENERATED ALWAYS AS (b) STORED)",
+        ] {
+            assert!(engine.execute_script(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn quotas_stop_writes_that_would_exceed_them() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE DATABASE sales;
+             CREATE TABLE sales.t (id INT PRIMARY KEY, name TEXT);
+             CREATE TABLE t (id INT PRIMARY KEY)",
+        );
+        let quota = Quota::unlimited().with_max_rows(2).with_max_indexes(2);
+        engine.set_quota("sales", quota).unwrap();
+        assert_eq!(engine.quota("sales").unwrap(), quota);
+        assert!(engine.set_quota("nowhere", quota).is_err());
+
+        run(&engine, "INSERT INTO sales.t VALUES (1, 'a'), (2, 'b')");
+        let exceeded = |sql: &str| {
+            matches!(engine.execute_script(sql),
+                Err(QubeError::ConstraintViolation(m)) if m.starts_with("quota exceeded"))
+        };
+        assert!(exceeded("INSERT INTO sales.t VALUES (3, 'c')"));
+        assert_eq!(run(&engine, "SELECT * FROM sales.t").rows.len(), 2);
+        // Other databases are not limited
+        run(&engine, "INSERT INTO t VALUES (1), (2), (3)");
+
+        run(&engine, "CREATE INDEX t_name ON sales.t (name)");
+        assert!(exceeded("CREATE INDEX t_name2 ON sales.t (name)"));
+        assert!(exceeded("CREATE TABLE sales.u (id INT PRIMARY KEY)"));
+
+        let usage = engine.quota_usage("sales").unwrap();
+        assert_eq!((usage.rows, usage.indexes), (2, 2));
+        engine
+            .set_quota("sales", quota.with_max_bytes(usage.bytes))
+            .unwrap();
+        assert!(exceeded("UPDATE sales.t SET name = 'longer' WHERE id = 1"));
+        run(&engine, "UPDATE sales.t SET name = '' WHERE id = 1");
+        run(&engine, "DELETE FROM sales.t WHERE id = 2");
+        let usage = engine.quota_usage("sales").unwrap();
+        assert_eq!(usage.rows, 1);
+        run(&engine, "INSERT INTO sales.t VALUES (3, 'c')");
+
+        engine.set_quota("sales", Quota::unlimited()).unwrap();
+        run(&engine, "INSERT INTO sales.t VALUES (4, 'd')");
+    }
+
+    #[test]
+    fn deletes_read_only_the_rows_an_index_finds() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, a INT, note TEXT);
+             CREATE INDEX t_a ON t (a)",
+        );
+        for id in 0..20 {
+            let sql = format!("INSERT INTO t VALUES ({}, {}, 'n')", id, id % 5);
+            engine
+                .execute_sql_with_options(&sql, &QueryOptions::default())
+                .unwrap();
+        }
+        let delete = |sql: &str| {
+            let before = engine.scan_metrics().rows_fetched;
+            let result = engine
+                .execute_sql_with_options(sql, &QueryOptions::default())
+                .unwrap();
+            let read = engine.scan_metrics().rows_fetched - before;
+            (result.affected_rows, read)
+        };
+
+        assert_eq!(delete("DELETE FROM t WHERE a = 3"), (4, 4));
+        assert_eq!(delete("DELETE FROM t WHERE a = 2 AND id > 10"), (2, 4));
+        assert_eq!(delete("DELETE FROM t WHERE id = 1"), (1, 1));
+        assert_eq!(delete("DELETE FROM t WHERE a = 3"), (0, 0));
+        // Without an index on the filter every row is read
+        assert_eq!(delete("DELETE FROM t WHERE note = 'x'"), (0, 13));
+
+        let left = run(&engine, "SELECT id FROM t WHERE a = 2").rows;
+        assert_eq!(left.len(), 2);
+        assert!(engine.verify_indexes().is_empty());
+        assert_eq!(
+            run(&engine, "SELECT COUNT(*) AS n FROM t").rows[0]["n"],
+            Value::Int64(13)
+        );
+    }
+
+    #[test]
+    fn explain_analyze_reports_what_each_operator_did() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, a INT, b TEXT);
+             CREATE TABLE u (id INT PRIMARY KEY, t_id INT)",
+        );
+        for id in 0..10 {
+            run(
+                &engine,
+                &format!(
+                    "INSERT INTO t VALUES ({}, {}, 'x'); INSERT INTO u VALUES ({}, {})",
+                    id,
+                    id % 3,
+                    id,
+                    id % 4
+                ),
+            );
+        }
+        // The plan lines, with timings masked
+        let explain = |sql: &str| -> Vec<String> {
+            run(&engine, sql)
+                .rows
+                .iter()
+                .map(|row| {
+                    let line = row["plan"].to_string();
+                    match (line.find("time: "), line.rfind(" ms")) {
+                        (Some(start), Some(end)) => {
+                            format!("{}time: _{}", &line[..start], &line[end + 3..])
+                        }
+                        _ => line,
+                    }
+                })
+                .collect()
+        };
+
+        let lines = explain("EXPLAIN ANALYZE SELECT b FROM t WHERE a = 1 ORDER BY id DESC LIMIT 2");
+        assert!(lines[0].starts_with("Scan t (estimated rows: "));
+        assert!(lines[0].ends_with(", actual rows: 2, examined: 6, time: _)"));
+        assert_eq!(
+            lines[3..5],
+            [
+                "Limit: 2 (pushed into scan) (actual rows: 2, examined: 2, time: _)",
+                "Project (actual rows: 2, examined: 2, time: _)",
+            ]
+        );
+        assert!(lines[5].starts_with("Execution Time: ") && lines[5].ends_with("(2 rows)"));
+
+        let lines = explain(
+            "EXPLAIN ANALYZE SELECT COUNT(*) AS n FROM t JOIN u ON t.id = u.t_id WHERE t.a > 0",
+        );
+        assert!(lines[0].ends_with(", actual rows: 6, examined: 10, time: _)"));
+        assert!(lines[2].starts_with("Hash Join u"));
+        assert!(lines[2].ends_with(", actual rows: 5, examined: 16, time: _)"));
+        assert!(lines.contains(&"Aggregate (actual rows: 1, examined: 5, time: _)".to_string()));
+
+        // Plain EXPLAIN runs nothing
+        let lines = explain("EXPLAIN SELECT b FROM t WHERE a = 1");
+        assert!(lines.iter().all(|line| !line.contains("actual rows")));
+        assert!(!lines.last().unwrap().starts_with("Execution Time"));
+    }
+
+    #[test]
+    fn parallel_scans_and_sorts_match_serial_ones() {
+        let engine = QueryEngine::new();
+        run(
+            &engine,
+            "CREATE TABLE t (id INT PRIMARY KEY, g INT, v INT, name TEXT)",
+        );
+        let values: Vec<String> = (0..500)
+            .map(|id| format!("({}, {}, {}, 'n{}')", id, id % 7, (id * 37) % 101, id % 13))
+            .collect();
+        run(
+            &engine,
+            &format!("INSERT INTO t VALUES {}", values.join(", ")),
+        );
+
+        let queries = [
+            "SELECT id, v FROM t WHERE v > 50 AND name <> 'n3'",
+            "SELECT id FROM t WHERE g = 2 ORDER BY v DESC, name",
+            "SELECT name, v FROM t ORDER BY name, v LIMIT 40 OFFSET 5",
+            "SELECT COUNT(*) AS n, SUM(v) AS s, MIN(name) AS lo, MAX(v) AS hi FROM t WHERE g > 1",
+        ];
+        let results = |config: ParallelConfig| -> Vec<Vec<Row>> {
+            engine.set_parallelism(config);
+            queries
+                .iter()
+                .map(|sql| {
+                    engine
+                        .execute_sql_with_options(sql, &QueryOptions::default())
+                        .unwrap()
+                        .rows
+                })
+                .collect()
+        };
+        let serial = results(ParallelConfig::serial());
+        let parallel = results(ParallelConfig {
+            threads: 4,
+            min_rows: 16,
+        });
+        assert_eq!(parallel, serial);
+        assert!(serial.iter().all(|rows| !rows.is_empty()));
     }
 }
@@ -0,0 +1,211 @@
+//! Resource quotas for QubeDB databases
+//!
+//! A `Quota` caps how many rows, bytes of row data and indexes one database
+//! (see `namespace`) may hold, so tenants sharing an engine cannot exhaust
+//! it. A write that would take a database over its quota fails with
+//! `ConstraintViolation` and changes nothing; deleting rows or dropping
+//! indexes frees quota again. Sizes are estimates of the row data held in
+//! memory, not of files on disk. Quotas are configuration rather than data,
+//! so they are not written to the write-ahead log.
+//!
+//! An engine can also be given a memory limit across all its databases
+//! (`QueryEngine::set_memory_limit`). It is soft: a write that takes the
+//! engine over it first evicts the oldest past versions of rows kept for
+//! AS OF reads, which shortens how far back tables can be read. The rows
+//! themselves cannot be evicted, so a write that would take them alone
+//! over the limit fails like one exceeding a quota.
+
+use crate::error::{QubeError, QubeResult};
+use crate::types::{Row, Value};
+use serde::{Deserialize, Serialize};
+
+/// Limits on what one database may hold. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_rows: Option<u64>,
+    /// Estimated size of the rows, in bytes
+    pub max_bytes: Option<u64>,
+    pub max_indexes: Option<u64>,
+}
+
+impl Quota {
+    /// A quota without limits
+    pub fn unlimited() -> Self {
+        Quota::default()
+    }
+
+    pub fn with_max_rows(mut self, rows: u64) -> Self {
+        self.max_rows = Some(rows);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_max_indexes(mut self, indexes: u64) -> Self {
+        self.max_indexes = Some(indexes);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Quota::unlimited()
+    }
+}
+
+/// What a database currently holds, in the units of its quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    pub rows: u64,
+    pub bytes: u64,
+    pub indexes: u64,
+}
+
+/// Estimated memory held by an engine's tables
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    /// Estimated size of the rows, see `row_size`
+    pub rows: u64,
+    /// Estimated size of the past versions of rows kept for AS OF reads
+    pub history: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.rows + self.history
+    }
+}
+
+/// A database's quota together with its usage before a write, used to
+/// check the write fits
+#[derive(Debug, Clone)]
+pub(crate) struct Headroom {
+    pub(crate) database: String,
+    pub(crate) quota: Quota,
+    pub(crate) usage: QuotaUsage,
+    /// The engine's memory limit, if it has one
+    pub(crate) memory_limit: Option<u64>,
+    /// Estimated size of the rows of every database, when there is a
+    /// memory limit
+    pub(crate) memory: u64,
+}
+
+impl Headroom {
+    /// Whether the database or engine has any limit, and writes to it need
+    /// checking
+    pub(crate) fn is_limited(&self) -> bool {
+        !self.quota.is_unlimited() || self.memory_limit.is_some()
+    }
+
+    /// Fail if adding `rows`, `bytes` and `indexes` (any of which may be
+    /// negative) would exceed the quota
+    pub(crate) fn check(&self, rows: i64, bytes: i64, indexes: i64) -> QubeResult<()> {
+        let limits = [
+            ("rows", self.quota.max_rows, self.usage.rows, rows),
+            ("bytes", self.quota.max_bytes, self.usage.bytes, bytes),
+            (
+                "indexes",
+                self.quota.max_indexes,
+                self.usage.indexes,
+                indexes,
+            ),
+        ];
+        for (unit, limit, used, added) in limits {
+            match limit {
+                // Shrinking is always allowed, even over a lowered quota
+                Some(limit) if added > 0 && used.saturating_add(added as u64) > limit => {
+                    return Err(QubeError::ConstraintViolation(format!(
+                        "quota exceeded: database '{}' may hold at most {} {}",
+                        self.database, limit, unit
+                    )))
+                }
+                _ => {}
+            }
+        }
+        match self.memory_limit {
+            Some(limit) if bytes > 0 && self.memory.saturating_add(bytes as u64) > limit => {
+                Err(QubeError::ConstraintViolation(format!(
+                    "memory limit exceeded: the engine may hold at most {} bytes of rows",
+                    limit
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Estimated size of a row's data, in bytes
+pub fn row_size(row: &Row) -> u64 {
+    row.iter()
+        .map(|(column, value)| (column.len() + value_size(value)) as u64)
+        .sum()
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Null => 0,
+        Value::Int8(_) | Value::UInt8(_) | Value::Boolean(_) => 1,
+        Value::Int16(_) | Value::UInt16(_) => 2,
+        Value::Int32(_) | Value::UInt32(_) | Value::Float32(_) => 4,
+        Value::Int64(_) | Value::UInt64(_) | Value::Float64(_) | Value::Timestamp(_) => 8,
+        Value::String(s) => s.len(),
+        Value::Binary(b) => b.len(),
+        Value::Json(json) => json.to_string().len(),
+        Value::Vector(v) => v.len() * std::mem::size_of::<f32>(),
+        Value::Counter(counter) => counter
+            .replicas()
+            .iter()
+            .map(|replica| replica.len() + 16)
+            .sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headroom(quota: Quota, usage: QuotaUsage) -> Headroom {
+        Headroom {
+            database: "sales".to_string(),
+            quota,
+            usage,
+            memory_limit: None,
+            memory: 0,
+        }
+    }
+
+    #[test]
+    fn writes_may_fill_a_quota_but_not_pass_it() {
+        let quota = Quota::unlimited().with_max_rows(10).with_max_indexes(1);
+        let usage = QuotaUsage {
+            rows: 8,
+            bytes: 500,
+            indexes: 1,
+        };
+        let headroom = headroom(quota, usage);
+        assert!(headroom.is_limited());
+        headroom.check(2, 1_000_000, 0).unwrap();
+        let over = headroom.check(3, 0, 0);
+        assert!(matches!(over, Err(QubeError::ConstraintViolation(m))
+            if m == "quota exceeded: database 'sales' may hold at most 10 rows"));
+        assert!(headroom.check(0, 0, 1).is_err());
+        // Over a lowered quota, shrinking still works
+        let lowered = Headroom {
+            quota: quota.with_max_rows(5),
+            ..headroom
+        };
+        lowered.check(-1, -100, -1).unwrap();
+    }
+
+    #[test]
+    fn row_sizes_count_column_names_and_values() {
+        let row = Row::from([
+            ("id".to_string(), Value::Int64(1)),
+            ("name".to_string(), Value::String("Ada".to_string())),
+            ("note".to_string(), Value::Null),
+        ]);
+        assert_eq!(row_size(&row), 2 + 8 + 4 + 3 + 4);
+        assert_eq!(row_size(&Row::new()), 0);
+    }
+}
@@ -0,0 +1,492 @@
+//! Leader-to-follower log replication for QubeDB
+//!
+//! The leader keeps an append-only log of entries and ships them to each
+//! follower through a `FollowerTransport`. Failed append-entries calls are
+//! retried with exponential backoff and jitter; a follower that keeps failing
+//! is marked unhealthy until it accepts entries again.
+//!
+//! Reads can be routed to followers according to a `ConsistencyLevel`, using
+//! each follower's replication lag to decide whether it is fresh enough.
+
+use crate::error::{QubeError, QubeResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A single replicated log entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    /// Time the leader appended the entry, in milliseconds since the epoch
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+}
+
+/// How fresh the data returned by a read must be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsistencyLevel {
+    /// Always read from the leader
+    Strong,
+    /// Read from any healthy replica
+    Eventual,
+    /// Read from a replica at most this many milliseconds behind the leader
+    BoundedStaleness(u64),
+}
+
+/// Node chosen to serve a read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadTarget {
+    Leader,
+    Follower(String),
+}
+
+/// Ships log entries to followers
+pub trait FollowerTransport: Send + Sync {
+    /// Send entries to a follower, returning the highest index it now holds
+    fn append_entries(&self, follower: &str, entries: &[LogEntry]) -> QubeResult<u64>;
+}
+
+/// Backoff settings for retrying failed append-entries calls
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of the backoff randomly added or removed, between 0 and 1
+    pub jitter: f64,
+    /// Consecutive failures after which a follower is reported unhealthy
+    pub unhealthy_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+            unhealthy_after: 5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt after `failures` consecutive failures
+    pub fn backoff(&self, failures: u32, seed: u64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(32) as i32;
+        let base = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let capped = base.min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * (2.0 * unit_random(seed) - 1.0);
+        Duration::from_secs_f64((capped * (1.0 + jitter)).min(self.max_backoff.as_secs_f64()))
+    }
+}
+
+/// Replication progress of one follower
+#[derive(Debug, Clone)]
+pub struct FollowerState {
+    pub id: String,
+    /// Highest log index known to be stored on the follower
+    pub match_index: u64,
+    pub consecutive_failures: u32,
+    /// Earliest time the next attempt may be made
+    pub next_attempt: Instant,
+    pub last_contact: Option<Instant>,
+    pub last_error: Option<String>,
+    pub healthy: bool,
+}
+
+impl FollowerState {
+    fn new(id: String) -> Self {
+        FollowerState {
+            id,
+            match_index: 0,
+            consecutive_failures: 0,
+            next_attempt: Instant::now(),
+            last_contact: None,
+            last_error: None,
+            healthy: true,
+        }
+    }
+}
+
+/// Leader side of log replication
+pub struct Replicator<T: FollowerTransport> {
+    term: u64,
+    log: Vec<LogEntry>,
+    followers: BTreeMap<String, FollowerState>,
+    transport: T,
+    policy: RetryPolicy,
+    next_read: AtomicUsize,
+}
+
+impl<T: FollowerTransport> Replicator<T> {
+    /// Create a replicator for the given term
+    pub fn new(term: u64, transport: T, policy: RetryPolicy) -> Self {
+        Replicator {
+            term,
+            log: Vec::new(),
+            followers: BTreeMap::new(),
+            transport,
+            policy,
+            next_read: AtomicUsize::new(0),
+        }
+    }
+
+    /// Register a follower
+    pub fn add_follower(&mut self, id: &str) {
+        self.followers
+            .entry(id.to_string())
+            .or_insert_with(|| FollowerState::new(id.to_string()));
+    }
+
+    /// Stop replicating to a follower
+    pub fn remove_follower(&mut self, id: &str) -> bool {
+        self.followers.remove(id).is_some()
+    }
+
+    /// Append an entry to the leader log, returning its index
+    pub fn append(&mut self, payload: Vec<u8>) -> u64 {
+        let index = self.last_index() + 1;
+        self.log.push(LogEntry {
+            index,
+            term: self.term,
+            timestamp: now_millis(),
+            payload,
+        });
+        index
+    }
+
+    /// Index of the newest entry, or 0 if the log is empty
+    pub fn last_index(&self) -> u64 {
+        self.log.last().map(|entry| entry.index).unwrap_or(0)
+    }
+
+    pub fn follower(&self, id: &str) -> Option<&FollowerState> {
+        self.followers.get(id)
+    }
+
+    pub fn followers(&self) -> impl Iterator<Item = &FollowerState> {
+        self.followers.values()
+    }
+
+    /// Followers that have failed at least `unhealthy_after` times in a row
+    pub fn unhealthy_followers(&self) -> Vec<&FollowerState> {
+        self.followers.values().filter(|f| !f.healthy).collect()
+    }
+
+    /// How far a follower is behind the leader, in milliseconds: the age of
+    /// the oldest entry it has not yet received, or 0 if it is caught up
+    pub fn staleness_ms(&self, id: &str) -> Option<u64> {
+        let state = self.followers.get(id)?;
+        Some(
+            self.log
+                .get(state.match_index as usize)
+                .map(|entry| now_millis().saturating_sub(entry.timestamp))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Choose the node that should serve a read at the given consistency
+    /// level. Eligible followers are used in turn; if none qualifies the read
+    /// falls back to the leader.
+    pub fn route_read(&self, level: ConsistencyLevel) -> ReadTarget {
+        let max_staleness = match level {
+            ConsistencyLevel::Strong => return ReadTarget::Leader,
+            ConsistencyLevel::Eventual => None,
+            ConsistencyLevel::BoundedStaleness(ms) => Some(ms),
+        };
+
+        let eligible: Vec<&FollowerState> = self
+            .followers
+            .values()
+            .filter(|f| f.healthy)
+            .filter(|f| match max_staleness {
+                Some(ms) => self.staleness_ms(&f.id).is_some_and(|lag| lag <= ms),
+                None => true,
+            })
+            .collect();
+        if eligible.is_empty() {
+            return ReadTarget::Leader;
+        }
+
+        let turn = self.next_read.fetch_add(1, Ordering::Relaxed);
+        ReadTarget::Follower(eligible[turn % eligible.len()].id.clone())
+    }
+
+    /// Try once to bring a follower up to date. On failure the follower is
+    /// scheduled for a retry after a backoff and the error is returned.
+    pub fn replicate_to_follower(&mut self, id: &str) -> QubeResult<()> {
+        let state = self
+            .followers
+            .get(id)
+            .ok_or_else(|| QubeError::NotFound(format!("Follower '{}'", id)))?;
+        let from = state.match_index as usize;
+        if from >= self.log.len() {
+            return Ok(());
+        }
+
+        let result = self.transport.append_entries(id, &self.log[from..]);
+        let state = self.followers.get_mut(id).expect("follower checked above");
+        let now = Instant::now();
+        match result {
+            Ok(match_index) => {
+                state.match_index = match_index.min(self.log.len() as u64);
+                state.consecutive_failures = 0;
+                state.next_attempt = now;
+                state.last_contact = Some(now);
+                state.last_error = None;
+                state.healthy = true;
+                Ok(())
+            }
+            Err(e) => {
+                state.consecutive_failures += 1;
+                let seed = now_nanos() ^ u64::from(state.consecutive_failures);
+                state.next_attempt = now + self.policy.backoff(state.consecutive_failures, seed);
+                state.last_error = Some(e.to_string());
+                if state.consecutive_failures >= self.policy.unhealthy_after {
+                    state.healthy = false;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Attempt every lagging follower whose backoff has elapsed. Returns the
+    /// followers that failed in this round.
+    pub fn replicate_pending(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let last_index = self.last_index();
+        let due: Vec<String> = self
+            .followers
+            .values()
+            .filter(|f| f.match_index < last_index && f.next_attempt <= now)
+            .map(|f| f.id.clone())
+            .collect();
+
+        due.into_iter()
+            .filter(|id| self.replicate_to_follower(id).is_err())
+            .collect()
+    }
+
+    /// Keep retrying a follower, sleeping out each backoff, until it has every
+    /// entry or `max_attempts` attempts have failed
+    pub async fn replicate_with_retry(&mut self, id: &str, max_attempts: u32) -> QubeResult<()> {
+        let mut attempts = 0;
+        loop {
+            match self.replicate_to_follower(id) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= max_attempts {
+                        return Err(e);
+                    }
+                }
+            }
+
+            let next_attempt = self.followers[id].next_attempt;
+            tokio::time::sleep_until(next_attempt.into()).await;
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Map a seed to a pseudo-random number in [0, 1) (SplitMix64)
+fn unit_random(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Accepts entries except for a number of calls it is told to fail
+    #[derive(Default)]
+    struct FlakyTransport {
+        failures_left: Mutex<HashMap<String, u32>>,
+        calls: AtomicUsize,
+    }
+
+    impl FlakyTransport {
+        fn failing(follower: &str, times: u32) -> Self {
+            let transport = FlakyTransport::default();
+            transport.fail(follower, times);
+            transport
+        }
+
+        fn fail(&self, follower: &str, times: u32) {
+            self.failures_left
+                .lock()
+                .unwrap()
+                .insert(follower.to_string(), times);
+        }
+    }
+
+    impl FollowerTransport for FlakyTransport {
+        fn append_entries(&self, follower: &str, entries: &[LogEntry]) -> QubeResult<u64> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut failures_left = self.failures_left.lock().unwrap();
+            match failures_left.get_mut(follower) {
+                Some(left) if *left > 0 => {
+                    *left -= 1;
+                    Err(QubeError::Network(format!("{} unreachable", follower)))
+                }
+                _ => Ok(entries.last().map_or(0, |entry| entry.index)),
+            }
+        }
+    }
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            unhealthy_after: 3,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<u128> = (1..=4)
+            .map(|failures| policy.backoff(failures, 7).as_millis())
+            .collect();
+        assert_eq!(delays, [50, 100, 200, 400]);
+        assert_eq!(policy.backoff(20, 7), policy.max_backoff);
+
+        // Jitter stays within its fraction of the delay
+        let policy = RetryPolicy::default();
+        for seed in 0..100 {
+            let delay = policy.backoff(2, seed).as_secs_f64();
+            assert!((0.08..=0.12).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn followers_are_unhealthy_after_repeated_failures_until_they_recover() {
+        let mut replicator = Replicator::new(1, FlakyTransport::failing("f1", 3), quick_policy());
+        replicator.add_follower("f1");
+        replicator.append(b"a".to_vec());
+        replicator.append(b"b".to_vec());
+
+        for failures in 1..=3 {
+            assert!(replicator.replicate_to_follower("f1").is_err());
+            assert_eq!(
+                replicator.follower("f1").unwrap().consecutive_failures,
+                failures
+            );
+        }
+        let state = replicator.follower("f1").unwrap();
+        assert!(!state.healthy);
+        assert_eq!(
+            state.last_error.as_deref(),
+            Some("Network error: f1 unreachable")
+        );
+        assert_eq!(replicator.unhealthy_followers().len(), 1);
+
+        replicator.replicate_to_follower("f1").unwrap();
+        let state = replicator.follower("f1").unwrap();
+        assert!(state.healthy);
+        assert_eq!(state.match_index, 2);
+        assert_eq!(state.consecutive_failures, 0);
+        assert!(matches!(
+            replicator.replicate_to_follower("missing"),
+            Err(QubeError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn pending_replication_waits_out_each_followers_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+            ..RetryPolicy::default()
+        };
+        let mut replicator = Replicator::new(1, FlakyTransport::failing("down", 1), policy);
+        replicator.add_follower("up");
+        replicator.add_follower("down");
+        replicator.append(b"a".to_vec());
+
+        assert_eq!(replicator.replicate_pending(), ["down"]);
+        assert_eq!(replicator.follower("up").unwrap().match_index, 1);
+        // "up" is caught up and "down" is backing off, so nothing is sent
+        let calls = replicator.transport.calls.load(Ordering::SeqCst);
+        assert!(replicator.replicate_pending().is_empty());
+        assert_eq!(replicator.transport.calls.load(Ordering::SeqCst), calls);
+    }
+
+    #[tokio::test]
+    async fn retries_continue_until_the_follower_catches_up_or_attempts_run_out() {
+        let mut replicator = Replicator::new(1, FlakyTransport::failing("f1", 2), quick_policy());
+        replicator.add_follower("f1");
+        replicator.append(b"a".to_vec());
+        replicator.replicate_with_retry("f1", 3).await.unwrap();
+        assert_eq!(replicator.follower("f1").unwrap().match_index, 1);
+
+        replicator.transport.fail("f1", 5);
+        replicator.append(b"b".to_vec());
+        assert!(replicator.replicate_with_retry("f1", 3).await.is_err());
+        assert_eq!(replicator.transport.calls.load(Ordering::SeqCst), 6);
+        assert_eq!(replicator.follower("f1").unwrap().match_index, 1);
+    }
+
+    #[test]
+    fn reads_go_to_followers_fresh_enough_for_their_level() {
+        let mut replicator = Replicator::new(1, FlakyTransport::failing("down", 3), quick_policy());
+        for id in ["behind", "current", "down"] {
+            replicator.add_follower(id);
+        }
+        replicator.append(b"a".to_vec());
+        replicator.log[0].timestamp = now_millis() - 10_000;
+        replicator.replicate_to_follower("current").unwrap();
+        for _ in 0..3 {
+            assert!(replicator.replicate_to_follower("down").is_err());
+        }
+
+        assert_eq!(replicator.staleness_ms("current"), Some(0));
+        assert!(replicator.staleness_ms("behind").unwrap() >= 10_000);
+        assert_eq!(replicator.staleness_ms("missing"), None);
+
+        let route = |level| replicator.route_read(level);
+        assert_eq!(route(ConsistencyLevel::Strong), ReadTarget::Leader);
+        let current = ReadTarget::Follower("current".to_string());
+        for _ in 0..3 {
+            assert_eq!(route(ConsistencyLevel::BoundedStaleness(1_000)), current);
+        }
+        // Healthy followers take turns; the unhealthy one is skipped
+        let mut eventual: Vec<ReadTarget> =
+            (0..4).map(|_| route(ConsistencyLevel::Eventual)).collect();
+        eventual.sort_by_key(|target| format!("{:?}", target));
+        let behind = ReadTarget::Follower("behind".to_string());
+        assert_eq!(eventual, [behind.clone(), behind, current.clone(), current]);
+
+        replicator.remove_follower("current");
+        assert_eq!(
+            replicator.route_read(ConsistencyLevel::BoundedStaleness(1_000)),
+            ReadTarget::Leader
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Background maintenance for QubeDB
+//!
+//! A `MaintenanceScheduler` runs registered tasks, such as statistics
+//! refreshes or compaction, each on its own interval. Tasks run one at a
+//! time on a single background thread, so a slow task delays the others
+//! rather than piling up. A task that fails, or panics, is recorded in its
+//! metrics and simply runs again at its next interval.
+
+use crate::error::{QubeError, QubeResult};
+use serde::Serialize;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Shortest interval a task may be registered with
+pub const MIN_TASK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The work a maintenance task does on each run
+pub type MaintenanceFn = Arc<dyn Fn() -> QubeResult<()> + Send + Sync>;
+
+/// How a maintenance task has fared
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TaskMetrics {
+    pub name: String,
+    pub interval: Duration,
+    pub runs: u64,
+    /// Runs that returned an error or panicked
+    pub errors: u64,
+    /// When the last run started
+    pub last_run: Option<SystemTime>,
+    pub last_duration: Option<Duration>,
+    /// Error of the last run, if it failed
+    pub last_error: Option<String>,
+}
+
+struct Task {
+    run: MaintenanceFn,
+    next_run: Instant,
+    metrics: TaskMetrics,
+}
+
+#[derive(Default)]
+struct State {
+    tasks: Vec<Task>,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Runs periodic maintenance tasks on a background thread
+pub struct MaintenanceScheduler {
+    shared: Arc<Shared>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MaintenanceScheduler {
+    /// A scheduler with no tasks, not yet started
+    pub fn new() -> Self {
+        MaintenanceScheduler {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+            }),
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// Add a task that runs every `interval`, first one interval from now.
+    /// Names must be unique.
+    pub fn register<F>(
+        &self,
+        name: impl Into<String>,
+        interval: Duration,
+        task: F,
+    ) -> QubeResult<()>
+    where
+        F: Fn() -> QubeResult<()> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let interval = interval.max(MIN_TASK_INTERVAL);
+        let mut state = self.shared.state.lock().unwrap();
+        if state.tasks.iter().any(|t| t.metrics.name == name) {
+            return Err(QubeError::AlreadyExists(format!(
+                "Maintenance task '{}'",
+                name
+            )));
+        }
+        state.tasks.push(Task {
+            run: Arc::new(task),
+            next_run: Instant::now() + interval,
+            metrics: TaskMetrics {
+                name,
+                interval,
+                ..TaskMetrics::default()
+            },
+        });
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Remove a task. A run already under way finishes. Returns false if
+    /// there is no such task.
+    pub fn unregister(&self, name: &str) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        let before = state.tasks.len();
+        state.tasks.retain(|t| t.metrics.name != name);
+        before != state.tasks.len()
+    }
+
+    /// Start running tasks in the background. Does nothing if already
+    /// started.
+    pub fn start(&self) -> QubeResult<()> {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.is_some() {
+            return Ok(());
+        }
+        self.shared.state.lock().unwrap().stopped = false;
+        let shared = Arc::clone(&self.shared);
+        *worker = Some(
+            std::thread::Builder::new()
+                .name("qubedb-maintenance".to_string())
+                .spawn(move || run_tasks(&shared))?,
+        );
+        Ok(())
+    }
+
+    /// Stop the background thread, waiting for a task under way to finish.
+    /// Tasks stay registered and resume on the next `start`.
+    pub fn stop(&self) {
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return;
+        };
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.changed.notify_all();
+        if worker.join().is_err() {
+            tracing::warn!("Maintenance thread panicked");
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.worker.lock().unwrap().is_some()
+    }
+
+    /// Names of the registered tasks, in registration order
+    pub fn tasks(&self) -> Vec<String> {
+        let state = self.shared.state.lock().unwrap();
+        state.tasks.iter().map(|t| t.metrics.name.clone()).collect()
+    }
+
+    /// Metrics of one task, or `None` if there is no such task
+    pub fn task_metrics(&self, name: &str) -> Option<TaskMetrics> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .tasks
+            .iter()
+            .find(|t| t.metrics.name == name)
+            .map(|t| t.metrics.clone())
+    }
+
+    /// Metrics of every task, in registration order
+    pub fn metrics(&self) -> Vec<TaskMetrics> {
+        let state = self.shared.state.lock().unwrap();
+        state.tasks.iter().map(|t| t.metrics.clone()).collect()
+    }
+}
+
+impl Default for MaintenanceScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MaintenanceScheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl std::fmt::Debug for MaintenanceScheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MaintenanceScheduler")
+            .field("running", &self.is_running())
+            .field("tasks", &self.metrics())
+            .finish()
+    }
+}
+
+/// The background thread: sleep until the next task is due, run it, repeat
+fn run_tasks(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let due = state
+            .tasks
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, t)| t.next_run)
+            .map(|(i, t)| (i, t.next_run));
+        let index = match due {
+            Some((index, next_run)) if next_run <= now => index,
+            Some((_, next_run)) => {
+                state = shared
+                    .changed
+                    .wait_timeout(state, next_run - now)
+                    .unwrap()
+                    .0;
+                continue;
+            }
+            None => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+
+        let task = &state.tasks[index];
+        let run = Arc::clone(&task.run);
+        let name = task.metrics.name.clone();
+        drop(state);
+
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+        let outcome = catch_unwind(AssertUnwindSafe(|| run()))
+            .unwrap_or_else(|_| Err(QubeError::Other("Task panicked".to_string())));
+        let duration = started.elapsed();
+
+        state = shared.state.lock().unwrap();
+        // The task may have been unregistered while it ran
+        let Some(task) = state.tasks.iter_mut().find(|t| t.metrics.name == name) else {
+            continue;
+        };
+        task.next_run = Instant::now() + task.metrics.interval;
+        let metrics = &mut task.metrics;
+        metrics.runs += 1;
+        metrics.last_run = Some(started_at);
+        metrics.last_duration = Some(duration);
+        match outcome {
+            Ok(()) => metrics.last_error = None,
+            Err(e) => {
+                tracing::warn!("Maintenance task {} failed: {}", name, e);
+                metrics.errors += 1;
+                metrics.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TICK: Duration = Duration::from_millis(5);
+
+    /// Wait up to five seconds for `done` to hold
+    fn wait_for(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn counter(scheduler: &MaintenanceScheduler, name: &str) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let task_count = Arc::clone(&count);
+        scheduler
+            .register(name, TICK, move || {
+                task_count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap();
+        count
+    }
+
+    #[test]
+    fn tasks_run_on_their_interval_until_stopped() {
+        let scheduler = MaintenanceScheduler::new();
+        let count = counter(&scheduler, "count");
+        assert!(matches!(
+            scheduler.register("count", TICK, || Ok(())),
+            Err(QubeError::AlreadyExists(_))
+        ));
+        assert_eq!(scheduler.tasks(), ["count"]);
+        // Nothing runs before the scheduler is started
+        std::thread::sleep(TICK * 4);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        scheduler.start().unwrap();
+        scheduler.start().unwrap();
+        assert!(scheduler.is_running());
+        wait_for(|| count.load(Ordering::SeqCst) >= 3);
+        scheduler.stop();
+        assert!(!scheduler.is_running());
+        let runs = count.load(Ordering::SeqCst);
+        std::thread::sleep(TICK * 4);
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+
+        let metrics = scheduler.task_metrics("count").unwrap();
+        assert_eq!(metrics.runs, runs as u64);
+        assert_eq!((metrics.errors, metrics.interval), (0, TICK));
+        assert!(metrics.last_run.is_some() && metrics.last_duration.is_some());
+
+        // Tasks stay registered and resume on the next start
+        scheduler.start().unwrap();
+        wait_for(|| count.load(Ordering::SeqCst) > runs);
+        assert!(scheduler.unregister("count"));
+        assert!(!scheduler.unregister("count"));
+        assert!(scheduler.task_metrics("count").is_none());
+    }
+
+    #[test]
+    fn failing_and_panicking_tasks_are_recorded_and_retried() {
+        let scheduler = MaintenanceScheduler::new();
+        scheduler
+            .register("fails", TICK, || {
+                Err(QubeError::Other("disk full".to_string()))
+            })
+            .unwrap();
+        scheduler
+            .register("panics", TICK, || panic!("task bug"))
+            .unwrap();
+        counter(&scheduler, "count");
+        scheduler.start().unwrap();
+        wait_for(|| {
+            let metrics = scheduler.metrics();
+            metrics[0].errors >= 2 && metrics[1].errors >= 2 && metrics[2].runs >= 1
+        });
+        scheduler.stop();
+
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics[0].last_error.as_deref(), Some("disk full"));
+        assert!(metrics[1]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("Task panicked"));
+        assert_eq!(metrics[0].runs, metrics[0].errors);
+        // The other task kept running alongside them
+        assert_eq!(metrics[2].errors, 0);
+    }
+
+    #[test]
+    fn stopping_waits_for_the_task_under_way() {
+        let scheduler = MaintenanceScheduler::new();
+        let (started, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (task_started, task_finished) = (Arc::clone(&started), Arc::clone(&finished));
+        scheduler
+            .register("slow", TICK, move || {
+                task_started.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                task_finished.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap();
+        scheduler.start().unwrap();
+        wait_for(|| started.load(Ordering::SeqCst) == 1);
+        scheduler.stop();
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+}
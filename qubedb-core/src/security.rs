@@ -0,0 +1,114 @@
+//! Access control for QubeDB
+//!
+//! A `SecurityContext` identifies who is running a query and which kinds of
+//! statement they may execute.
+
+use crate::error::{QubeError, QubeResult};
+use crate::query::ShowCommand;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::Statement;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Kind of access a statement needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    /// Query and inspect data
+    Read,
+    /// Insert, update and delete rows
+    Write,
+    /// Create, alter and drop tables and indexes
+    Schema,
+}
+
+impl Permission {
+    /// The permission needed to execute a statement
+    pub fn required_for(statement: &Statement) -> Permission {
+        match statement {
+            Statement::Insert { .. }
+            | Statement::Update { .. }
+            | Statement::Delete { .. }
+            | Statement::Analyze { .. } => Permission::Write,
+            Statement::CreateTable { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateView { .. }
+            | Statement::AlterTable { .. }
+            | Statement::Drop { .. } => Permission::Schema,
+            // `REFRESH`, `REINDEX` and `VACUUM` run as `SHOW` statements
+            Statement::ShowVariable { .. } => match ShowCommand::of(statement) {
+                Some(ShowCommand::Refresh) => Permission::Write,
+                Some(ShowCommand::Reindex | ShowCommand::Vacuum) => Permission::Schema,
+                None => Permission::Read,
+            },
+            _ => Permission::Read,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Schema => write!(f, "schema"),
+        }
+    }
+}
+
+/// Identity and permissions of the user running queries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityContext {
+    pub user: String,
+    pub permissions: HashSet<Permission>,
+    /// Identifies the client request being served, for the audit trail
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl SecurityContext {
+    /// A user with the given permissions
+    pub fn new(user: impl Into<String>, permissions: &[Permission]) -> Self {
+        SecurityContext {
+            user: user.into(),
+            permissions: permissions.iter().copied().collect(),
+            request_id: None,
+        }
+    }
+
+    /// Tag the context with the id of the request it serves
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// A user allowed to run any statement
+    pub fn admin(user: impl Into<String>) -> Self {
+        Self::new(
+            user,
+            &[Permission::Read, Permission::Write, Permission::Schema],
+        )
+    }
+
+    /// A user allowed only to query data
+    pub fn read_only(user: impl Into<String>) -> Self {
+        Self::new(user, &[Permission::Read])
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Fail with `PermissionDenied` unless the user holds `permission`
+    pub fn check(&self, permission: Permission) -> QubeResult<()> {
+        if self.has_permission(permission) {
+            Ok(())
+        } else {
+            Err(QubeError::PermissionDenied(format!(
+                "user '{}' lacks {} permission",
+                self.user, permission
+            )))
+        }
+    }
+}
@@ -0,0 +1,813 @@
+//! Client sessions for QubeDB
+//!
+//! A `Session` carries the state of one client connection: who the client
+//! is, its open transaction, the database (or schema) it is using, how
+//! durable its writes must be and any session variables.
+//! Queries run through a session are checked against its permissions, and
+//! while a transaction is open they see the session's own uncommitted
+//! changes but no one else's. Prepared statements are cached per session.
+//! With an audit trail attached, refused statements and schema changes are
+//! recorded in it.
+
+use crate::audit::{AuditAction, AuditLog, AuditOutcome};
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::locks::LockOwner;
+use crate::namespace::{self, DEFAULT_DATABASE};
+use crate::prepared::PreparedStatement;
+use crate::query::{ExecutionContext, QueryEngine, QueryOptions, Write};
+use crate::security::{Permission, SecurityContext};
+use crate::types::{QueryResult, ResultKind, Row, Value};
+use crate::wal::Durability;
+use sqlparser::ast::{Expr, ObjectName, Query, Statement};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Schema new sessions start in
+pub const DEFAULT_SCHEMA: &str = DEFAULT_DATABASE;
+
+/// An open transaction.
+///
+/// Statements run against a private snapshot of the tables taken when the
+/// transaction began, which only copies a table once the transaction
+/// writes to it. The rows each INSERT, UPDATE or DELETE wrote are recorded
+/// and applied as they are, atomically, to the shared tables on commit, so
+/// the ids, defaults and rows the transaction saw are the ones committed.
+/// Auto-increment ids come from the shared table's sequence, so no two
+/// transactions are given the same one; ids of a transaction that rolls
+/// back are skipped. Other statements, such as CREATE TABLE, run again on
+/// commit.
+///
+/// A row the transaction updated or deleted must not have been changed by
+/// anyone else since it began: if it was, commit applies nothing and fails
+/// with `QubeError::Transaction` carrying `CONFLICT`.
+///
+/// An optimistic transaction also records what each of its queries
+/// returned. Commit re-runs them among the replayed writes and, if any now
+/// returns different rows, because another transaction changed them
+/// meanwhile, applies nothing and fails with `QubeError::Transaction`
+/// carrying `CONFLICT`. No locks are taken, so the caller retries instead.
+///
+/// `SELECT ... FOR UPDATE` locks the rows it reads until the transaction
+/// ends, first waiting for any other transaction holding them, and then
+/// reads them as last committed. Writes to locked rows from elsewhere wait
+/// meanwhile. A transaction whose wait would deadlock fails with an error
+/// carrying `DEADLOCK` and is rolled back.
+pub struct Transaction {
+    engine: Arc<QueryEngine>,
+    snapshot: QueryEngine,
+    lock_owner: LockOwner,
+    writes: Vec<Write>,
+    /// Queries run so far, each with the number of writes before it and
+    /// the rows it returned, if the transaction is optimistic
+    reads: Option<Vec<(usize, Statement, Vec<Row>)>>,
+}
+
+impl Transaction {
+    fn begin(engine: &Arc<QueryEngine>, optimistic: bool) -> Self {
+        engine.transaction_started();
+        let snapshot = engine.snapshot();
+        snapshot.start_journal();
+        Transaction {
+            engine: Arc::clone(engine),
+            snapshot,
+            lock_owner: engine.locks().new_owner(),
+            writes: Vec::new(),
+            reads: optimistic.then(Vec::new),
+        }
+    }
+
+    /// Lock the rows `query` reads, then take a fresh snapshot with this
+    /// transaction's writes applied, so they are read as last committed
+    fn lock_rows(&mut self, query: &Query, ctx: &mut ExecutionContext) -> QubeResult<()> {
+        self.engine.lock_rows(self.lock_owner, query, ctx)?;
+        let snapshot = self.engine.snapshot();
+        snapshot.start_journal();
+        let mut inserted = HashMap::new();
+        let mut writes = Vec::with_capacity(self.writes.len());
+        for write in &self.writes {
+            snapshot.apply(write, &mut inserted, &mut None, ctx)?;
+            // Rows the transaction inserted have new ids in the new snapshot
+            let effects = snapshot.take_journal();
+            writes.push(match write {
+                Write::Rows { statement, .. } => Write::Rows {
+                    statement: statement.clone(),
+                    effects,
+                },
+                write => write.clone(),
+            });
+        }
+        self.snapshot = snapshot;
+        self.writes = writes;
+        Ok(())
+    }
+
+    /// Record the write `statement` just made to the snapshot
+    fn record_write(&mut self, statement: Statement) {
+        let effects = self.snapshot.take_journal();
+        self.writes.push(match statement {
+            Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. } => {
+                Write::Rows { statement, effects }
+            }
+            statement => Write::Statement(statement),
+        });
+    }
+
+    /// Whether commit checks the transaction's reads for conflicts
+    pub fn is_optimistic(&self) -> bool {
+        self.reads.is_some()
+    }
+
+    /// Number of write statements waiting to be committed
+    pub fn pending_writes(&self) -> usize {
+        self.writes.len()
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.engine.locks().release(self.lock_owner);
+        self.engine.transaction_ended();
+    }
+}
+
+/// State of a single client connection
+pub struct Session {
+    engine: Arc<QueryEngine>,
+    context: SecurityContext,
+    transaction: Option<Transaction>,
+    schema: String,
+    variables: HashMap<String, Value>,
+    prepared: HashMap<String, PreparedStatement>,
+    /// Durability of writes that don't ask for their own
+    durability: Durability,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Session {
+    /// Create a session running queries on `engine` as `context`
+    pub fn new(engine: Arc<QueryEngine>, context: SecurityContext) -> Self {
+        Session {
+            engine,
+            context,
+            transaction: None,
+            schema: DEFAULT_SCHEMA.to_string(),
+            variables: HashMap::new(),
+            prepared: HashMap::new(),
+            durability: Durability::default(),
+            audit: None,
+        }
+    }
+
+    /// Record this session's refused statements and schema changes in
+    /// `audit`
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn audit_log(&self) -> Option<&Arc<AuditLog>> {
+        self.audit.as_ref()
+    }
+
+    pub fn security_context(&self) -> &SecurityContext {
+        &self.context
+    }
+
+    pub fn transaction(&self) -> Option<&Transaction> {
+        self.transaction.as_ref()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// The database (or schema) bare table names are resolved in
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    /// Switch to another database, also done with `USE name` or
+    /// `SET schema = 'name'`
+    pub fn set_schema(&mut self, schema: impl Into<String>) -> QubeResult<()> {
+        let schema = schema.into();
+        if !self.engine.has_database(&schema) {
+            return Err(QubeError::DatabaseNotFound(schema));
+        }
+        self.schema = schema;
+        Ok(())
+    }
+
+    /// Durability of this session's writes
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Set the durability of this session's writes, also settable with
+    /// `SET durability = 'async'`. Statements run with their own
+    /// `QueryOptions::durability` override it.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Get a session variable
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(&name.to_lowercase())
+    }
+
+    /// Set a session variable
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_lowercase(), value);
+    }
+
+    /// Start a transaction
+    pub fn begin(&mut self) -> QubeResult<()> {
+        self.start(false)
+    }
+
+    /// Start an optimistic transaction, whose commit fails with a conflict
+    /// if the rows it read have changed since
+    pub fn begin_optimistic(&mut self) -> QubeResult<()> {
+        self.start(true)
+    }
+
+    fn start(&mut self, optimistic: bool) -> QubeResult<()> {
+        if self.transaction.is_some() {
+            return Err(QubeError::Transaction(
+                "a transaction is already in progress".to_string(),
+            ));
+        }
+        self.transaction = Some(Transaction::begin(&self.engine, optimistic));
+        Ok(())
+    }
+
+    /// Apply the open transaction's writes. If any of them fails, none is
+    /// applied and the transaction is rolled back.
+    pub fn commit(&mut self) -> QubeResult<()> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or_else(|| QubeError::Transaction("no transaction in progress".to_string()))?;
+        self.engine.commit_transaction(
+            Some(transaction.lock_owner),
+            &transaction.writes,
+            transaction.reads.as_deref().unwrap_or_default(),
+            self.durability,
+        )?;
+        for write in &transaction.writes {
+            if let Write::Statement(statement) = write {
+                if Permission::required_for(statement) == Permission::Schema {
+                    self.audit(AuditAction::SchemaChange, AuditOutcome::Success, statement);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the open transaction's writes
+    pub fn rollback(&mut self) -> QubeResult<()> {
+        self.transaction
+            .take()
+            .map(|_| ())
+            .ok_or_else(|| QubeError::Transaction("no transaction in progress".to_string()))
+    }
+
+    /// Execute a SQL statement in this session
+    pub async fn execute(&mut self, sql: &str) -> QubeResult<QueryResult> {
+        self.execute_with_options(sql, QueryOptions::default())
+            .await
+    }
+
+    /// Execute a SQL statement in this session with a timeout and/or
+    /// cancellation token
+    pub async fn execute_with_options(
+        &mut self,
+        sql: &str,
+        options: QueryOptions,
+    ) -> QubeResult<QueryResult> {
+        let mut ctx = ExecutionContext::new(&options);
+        ctx.durability.get_or_insert(self.durability);
+        ctx.check()?;
+
+        let statement = self.engine.parse_sql(sql)?;
+        self.run(statement, &mut ctx)
+    }
+
+    /// Execute a script of `;`-separated statements in this session, in
+    /// order, stopping at the first that fails. Unlike
+    /// `QueryEngine::execute_script` the statements are not made atomic
+    /// unless a transaction is open.
+    pub fn execute_script(&mut self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        let options = QueryOptions::default().with_durability(self.durability);
+        self.engine
+            .parse_script(sql)?
+            .into_iter()
+            .map(|statement| self.run(statement, &mut ExecutionContext::new(&options)))
+            .collect()
+    }
+
+    /// Run `body` in an optimistic transaction and commit it, starting
+    /// over up to `max_retries` times while the commit conflicts. Any
+    /// other error, from `body` or the commit, rolls back and is returned
+    /// at once, as is the conflict once retries run out.
+    pub fn transaction_retry<T>(
+        &mut self,
+        mut body: impl FnMut(&mut Session) -> QubeResult<T>,
+        max_retries: usize,
+    ) -> QubeResult<T> {
+        let mut retries = 0;
+        loop {
+            self.begin_optimistic()?;
+            let outcome = body(self).and_then(|value| self.commit().map(|()| value));
+            if self.transaction.is_some() {
+                self.rollback()?;
+            }
+            match outcome {
+                Err(e) if e.is_conflict() && retries < max_retries => retries += 1,
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// Parse a statement once so it can be executed many times with
+    /// different parameters. Preparing the same SQL again reuses the
+    /// session's cached statement.
+    pub fn prepare(&mut self, sql: &str) -> QubeResult<PreparedStatement> {
+        if let Some(prepared) = self.prepared.get(sql) {
+            return Ok(prepared.clone());
+        }
+        let prepared = PreparedStatement::new(sql, self.engine.parse_sql(sql)?)?;
+        self.prepared.insert(sql.to_string(), prepared.clone());
+        Ok(prepared)
+    }
+
+    /// Execute a prepared statement with the given parameter values
+    pub async fn execute_prepared(
+        &mut self,
+        prepared: &PreparedStatement,
+        params: &[Value],
+    ) -> QubeResult<QueryResult> {
+        let options = QueryOptions::default().with_durability(self.durability);
+        let mut ctx = ExecutionContext::new(&options);
+        let statement = prepared.bind(params)?;
+        self.run(statement, &mut ctx)
+    }
+
+    /// Execute a prepared statement with its named parameters bound to
+    /// `params`
+    pub async fn execute_prepared_named(
+        &mut self,
+        prepared: &PreparedStatement,
+        params: &HashMap<String, Value>,
+    ) -> QubeResult<QueryResult> {
+        let options = QueryOptions::default().with_durability(self.durability);
+        let mut ctx = ExecutionContext::new(&options);
+        let statement = prepared.bind_named(params)?;
+        self.run(statement, &mut ctx)
+    }
+
+    /// Drop the session's cached prepared statements
+    pub fn clear_prepared(&mut self) {
+        self.prepared.clear();
+    }
+
+    /// Execute a parsed statement in this session
+    fn run(&mut self, statement: Statement, ctx: &mut ExecutionContext) -> QubeResult<QueryResult> {
+        let start_time = Instant::now();
+        match statement {
+            Statement::StartTransaction { .. } => self.begin()?,
+            Statement::Commit { .. } => self.commit()?,
+            Statement::Rollback { .. } => self.rollback()?,
+            Statement::SetVariable {
+                variable, value, ..
+            } => self.execute_set(&variable, &value)?,
+            Statement::Use { db_name } => self.set_schema(db_name.value)?,
+            mut statement => {
+                namespace::qualify(&mut statement, &self.schema);
+                let permission = Permission::required_for(&statement);
+                if let Err(e) = self.context.check(permission) {
+                    self.audit(
+                        AuditAction::PermissionDenied,
+                        AuditOutcome::Failure,
+                        &statement,
+                    );
+                    return Err(e);
+                }
+
+                return match &mut self.transaction {
+                    Some(transaction) => {
+                        let outcome = match &statement {
+                            Statement::Query(query) if !query.locks.is_empty() => {
+                                transaction.lock_rows(query, ctx)
+                            }
+                            _ => Ok(()),
+                        }
+                        .and_then(|()| {
+                            transaction
+                                .snapshot
+                                .execute_statement(statement.clone(), ctx)
+                        });
+                        let result = match outcome {
+                            Err(e) if e.is_deadlock() => {
+                                self.transaction = None;
+                                return Err(e);
+                            }
+                            Err(e) => {
+                                transaction.snapshot.take_journal();
+                                return Err(e);
+                            }
+                            Ok(result) => result,
+                        };
+                        if permission != Permission::Read {
+                            transaction.record_write(statement);
+                        } else if let (Some(reads), Statement::Query(_)) =
+                            (&mut transaction.reads, &statement)
+                        {
+                            reads.push((transaction.writes.len(), statement, result.rows.clone()));
+                        }
+                        Ok(result)
+                    }
+                    None if permission == Permission::Schema => {
+                        let detail = statement.to_string();
+                        let result = self.engine.execute_statement(statement, ctx)?;
+                        self.audit(AuditAction::SchemaChange, AuditOutcome::Success, &detail);
+                        Ok(result)
+                    }
+                    None => self.engine.execute_statement(statement, ctx),
+                };
+            }
+        }
+
+        Ok(QueryResult {
+            kind: ResultKind::Mutation,
+            columns: vec![],
+            rows: vec![],
+            affected_rows: 0,
+            last_insert_id: None,
+            execution_time: start_time.elapsed(),
+        })
+    }
+
+    /// Append to the audit trail, if there is one. A trail that cannot be
+    /// written is reported but does not fail the statement.
+    fn audit(&self, action: AuditAction, outcome: AuditOutcome, detail: &dyn std::fmt::Display) {
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.record(action, outcome, &self.context, detail.to_string()) {
+                tracing::error!("Failed to write audit entry: {}", e);
+            }
+        }
+    }
+
+    /// Execute `SET name = value`. `SET schema` switches database and
+    /// `SET durability` the durability of writes; anything else becomes a
+    /// session variable.
+    fn execute_set(&mut self, variable: &ObjectName, value: &[Expr]) -> QubeResult<()> {
+        let name = variable.to_string();
+        let value = match value {
+            [expr] => expr::evaluate(expr, &Row::new())?,
+            _ => {
+                return Err(QubeError::QueryParse(format!(
+                    "SET {} expects a single value",
+                    name
+                )))
+            }
+        };
+
+        if name.eq_ignore_ascii_case("schema") {
+            match value {
+                Value::String(schema) => self.set_schema(schema)?,
+                other => {
+                    return Err(QubeError::QueryParse(format!(
+                        "Invalid schema name: {:?}",
+                        other
+                    )))
+                }
+            }
+        } else if name.eq_ignore_ascii_case("durability") {
+            match value {
+                Value::String(level) => self.set_durability(level.parse()?),
+                other => {
+                    return Err(QubeError::QueryParse(format!(
+                        "Invalid durability level: {:?}",
+                        other
+                    )))
+                }
+            }
+        } else {
+            self.set_variable(&name, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(engine: &Arc<QueryEngine>) -> Session {
+        Session::new(Arc::clone(engine), SecurityContext::admin("test"))
+    }
+
+    fn run(session: &mut Session, sql: &str) -> QubeResult<QueryResult> {
+        session
+            .execute_script(sql)
+            .map(|mut results| results.pop().unwrap())
+    }
+
+    fn ids(engine: &QueryEngine, sql: &str) -> Vec<Value> {
+        let result = engine.execute_script(sql).unwrap().pop().unwrap();
+        result.rows.iter().map(|row| row["id"].clone()).collect()
+    }
+
+    #[test]
+    fn transactions_do_not_see_each_others_writes() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script("CREATE TABLE t (id INT, name TEXT)")
+            .unwrap();
+        let mut a = session(&engine);
+        let mut b = session(&engine);
+        a.begin().unwrap();
+        b.begin().unwrap();
+        run(&mut a, "INSERT INTO t VALUES (1, 'a')").unwrap();
+        run(&mut b, "INSERT INTO t VALUES (2, 'b')").unwrap();
+
+        assert_eq!(run(&mut a, "SELECT id FROM t").unwrap().rows.len(), 1);
+        assert_eq!(run(&mut b, "SELECT id FROM t").unwrap().rows.len(), 1);
+        assert!(ids(&engine, "SELECT id FROM t").is_empty());
+
+        a.commit().unwrap();
+        assert_eq!(ids(&engine, "SELECT id FROM t"), vec![Value::Int32(1)]);
+        b.rollback().unwrap();
+        assert_eq!(ids(&engine, "SELECT id FROM t"), vec![Value::Int32(1)]);
+    }
+
+    #[test]
+    fn concurrent_transactions_get_distinct_ids() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script("CREATE TABLE t (id INT AUTO_INCREMENT, name TEXT)")
+            .unwrap();
+        let mut a = session(&engine);
+        let mut b = session(&engine);
+        a.begin().unwrap();
+        b.begin().unwrap();
+        let first = run(&mut a, "INSERT INTO t (name) VALUES ('a')").unwrap();
+        let second = run(&mut b, "INSERT INTO t (name) VALUES ('b')").unwrap();
+        assert_eq!(first.last_insert_id, Some(1));
+        assert_eq!(second.last_insert_id, Some(2));
+
+        b.commit().unwrap();
+        a.commit().unwrap();
+        let rows = engine
+            .execute_script("SELECT id, name FROM t ORDER BY id")
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows;
+        let rows: Vec<(Value, Value)> = rows
+            .iter()
+            .map(|row| (row["id"].clone(), row["name"].clone()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (Value::Int32(1), Value::String("a".to_string())),
+                (Value::Int32(2), Value::String("b".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_applies_the_rows_the_transaction_wrote() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT, v INT, flagged INT);
+                 INSERT INTO t VALUES (1, 1, 0), (2, 7, 0)",
+            )
+            .unwrap();
+        let mut a = session(&engine);
+        a.begin().unwrap();
+        let updated = run(&mut a, "UPDATE t SET flagged = 1 WHERE v > 5").unwrap();
+        assert_eq!(updated.affected_rows, 1);
+
+        // Matches the UPDATE's WHERE, but was not there when it ran
+        engine
+            .execute_script("INSERT INTO t VALUES (3, 9, 0)")
+            .unwrap();
+        a.commit().unwrap();
+        assert_eq!(
+            ids(&engine, "SELECT id FROM t WHERE flagged = 1"),
+            vec![Value::Int32(2)]
+        );
+    }
+
+    #[test]
+    fn commit_conflicts_on_rows_changed_since_begin() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT, v INT);
+                 INSERT INTO t VALUES (1, 10)",
+            )
+            .unwrap();
+        let mut a = session(&engine);
+        a.begin().unwrap();
+        run(&mut a, "UPDATE t SET v = v + 1 WHERE id = 1").unwrap();
+        run(&mut a, "INSERT INTO t VALUES (2, 20)").unwrap();
+        engine
+            .execute_script("UPDATE t SET v = 100 WHERE id = 1")
+            .unwrap();
+
+        assert!(a.commit().unwrap_err().is_conflict());
+        assert!(!a.in_transaction());
+        let rows = engine
+            .execute_script("SELECT v FROM t")
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["v"], Value::Int32(100));
+    }
+
+    #[test]
+    fn rows_inserted_in_a_transaction_can_be_changed_before_commit() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT, v INT);
+                 INSERT INTO t VALUES (1, 10)",
+            )
+            .unwrap();
+        let mut a = session(&engine);
+        a.begin().unwrap();
+        run(&mut a, "INSERT INTO t VALUES (2, 20), (3, 30)").unwrap();
+        // Takes the row id the transaction's first insert had
+        engine
+            .execute_script("INSERT INTO t VALUES (4, 40)")
+            .unwrap();
+        run(&mut a, "UPDATE t SET v = v + 1 WHERE id >= 2").unwrap();
+        run(&mut a, "DELETE FROM t WHERE id = 3").unwrap();
+        a.commit().unwrap();
+
+        let rows = engine
+            .execute_script("SELECT id, v FROM t ORDER BY id")
+            .unwrap()
+            .pop()
+            .unwrap()
+            .rows;
+        let rows: Vec<(Value, Value)> = rows
+            .iter()
+            .map(|row| (row["id"].clone(), row["v"].clone()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (Value::Int32(1), Value::Int32(10)),
+                (Value::Int32(2), Value::Int32(21)),
+                (Value::Int32(4), Value::Int32(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn sessions_keep_each_databases_tables_apart() {
+        let engine = Arc::new(QueryEngine::new());
+        let mut a = session(&engine);
+        let mut b = session(&engine);
+        run(&mut a, "CREATE DATABASE sales; CREATE SCHEMA hr").unwrap();
+        assert!(matches!(
+            run(&mut a, "CREATE DATABASE sales"),
+            Err(QubeError::AlreadyExists(_))
+        ));
+        run(&mut a, "CREATE DATABASE IF NOT EXISTS sales").unwrap();
+        let databases = engine.list_databases();
+        assert_eq!(databases, ["public", "hr", "sales"]);
+
+        run(&mut a, "USE sales").unwrap();
+        run(&mut b, "SET schema = 'hr'").unwrap();
+        assert_eq!((a.schema(), b.schema()), ("sales", "hr"));
+        for session in [&mut a, &mut b] {
+            run(session, "CREATE TABLE t (id INT PRIMARY KEY)").unwrap();
+        }
+        run(&mut a, "INSERT INTO t VALUES (1)").unwrap();
+        run(
+            &mut b,
+            "INSERT INTO t VALUES (2); INSERT INTO sales.t VALUES (3)",
+        )
+        .unwrap();
+        assert_eq!(ids(&engine, "SELECT id FROM hr.t"), vec![Value::Int32(2)]);
+        assert_eq!(run(&mut a, "SELECT id FROM t").unwrap().rows.len(), 2);
+        assert!(ids(&engine, "SHOW TABLES").is_empty());
+        let tables = run(&mut a, "SHOW TABLES").unwrap().rows;
+        assert_eq!(tables[0]["table_name"], Value::String("t".to_string()));
+
+        run(&mut a, "CREATE TABLE public.p (id INT)").unwrap();
+        assert!(engine.describe_table("p").is_ok());
+        assert!(matches!(
+            run(&mut a, "USE nowhere"),
+            Err(QubeError::DatabaseNotFound(_))
+        ));
+        assert_eq!(a.schema(), "sales");
+
+        run(&mut b, "DROP DATABASE sales").unwrap();
+        assert!(engine.describe_table("sales.t").is_err());
+        assert!(run(&mut b, "DROP DATABASE public").is_err());
+        run(&mut b, "DROP DATABASE IF EXISTS sales").unwrap();
+        assert_eq!(engine.list_databases(), ["public", "hr"]);
+    }
+
+    #[test]
+    fn optimistic_commits_conflict_when_their_reads_change() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT PRIMARY KEY, v INT);
+                 INSERT INTO t VALUES (1, 10), (2, 20)",
+            )
+            .unwrap();
+        let mut a = session(&engine);
+        a.begin_optimistic().unwrap();
+        assert!(a.transaction().unwrap().is_optimistic());
+        run(&mut a, "SELECT v FROM t WHERE id = 1").unwrap();
+        run(&mut a, "UPDATE t SET v = 11 WHERE id = 2").unwrap();
+        // A write to a row the transaction did not read is no conflict
+        engine
+            .execute_script("INSERT INTO t VALUES (3, 30)")
+            .unwrap();
+        a.commit().unwrap();
+
+        a.begin_optimistic().unwrap();
+        run(&mut a, "SELECT v FROM t WHERE id = 1").unwrap();
+        run(&mut a, "UPDATE t SET v = 0 WHERE id = 2").unwrap();
+        engine
+            .execute_script("UPDATE t SET v = 12 WHERE id = 1")
+            .unwrap();
+        assert!(a.commit().unwrap_err().is_conflict());
+        assert!(!a.in_transaction());
+        assert_eq!(
+            ids(&engine, "SELECT id FROM t WHERE v = 11"),
+            vec![Value::Int32(2)]
+        );
+    }
+
+    #[test]
+    fn transaction_retry_reruns_the_body_after_a_conflict() {
+        let engine = Arc::new(QueryEngine::new());
+        engine
+            .execute_script(
+                "CREATE TABLE t (id INT PRIMARY KEY, v INT);
+                 INSERT INTO t VALUES (1, 10)",
+            )
+            .unwrap();
+        let mut a = session(&engine);
+        let mut attempts = 0;
+        let v = a
+            .transaction_retry(
+                |session| {
+                    attempts += 1;
+                    let rows = run(session, "SELECT v FROM t WHERE id = 1")?.rows;
+                    if attempts == 1 {
+                        // Another writer gets in first, once
+                        engine.execute_script("UPDATE t SET v = 100 WHERE id = 1")?;
+                    }
+                    run(session, "UPDATE t SET v = v + 1 WHERE id = 1")?;
+                    Ok(rows[0]["v"].clone())
+                },
+                3,
+            )
+            .unwrap();
+        assert_eq!((attempts, v), (2, Value::Int32(100)));
+        let rows = run(&mut a, "SELECT v FROM t").unwrap().rows;
+        assert_eq!(rows[0]["v"], Value::Int32(101));
+
+        let mut attempts = 0;
+        let conflict = a.transaction_retry(
+            |session| {
+                attempts += 1;
+                run(session, "SELECT v FROM t")?;
+                engine.execute_script("UPDATE t SET v = v + 1")?;
+                run(session, "INSERT INTO t VALUES (2, 0)")
+            },
+            2,
+        );
+        assert!(conflict.unwrap_err().is_conflict());
+        assert_eq!(attempts, 3);
+        assert!(!a.in_transaction());
+
+        let mut attempts = 0;
+        let failed = a.transaction_retry(
+            |session| {
+                attempts += 1;
+                run(session, "INSERT INTO missing VALUES (1)")
+            },
+            2,
+        );
+        assert!(matches!(failed, Err(QubeError::TableNotFound(_))));
+        assert_eq!(attempts, 1);
+        assert!(ids(&engine, "SELECT id FROM t WHERE id = 2").is_empty());
+    }
+}
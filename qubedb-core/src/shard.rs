@@ -0,0 +1,1152 @@
+//! Sharding for QubeDB
+//!
+//! A `ShardCoordinator` spreads the rows of sharded tables over several
+//! `Shard`s by hashing a shard key column, and routes each statement to the
+//! shards it concerns. An INSERT sends each row to the shard owning its key.
+//! A SELECT, UPDATE or DELETE whose WHERE clause pins the key with `=` or
+//! `IN` goes to the shards owning those values; any other is scattered to
+//! every shard and the partial results are gathered into one:
+//!
+//! - row counts of writes are summed
+//! - rows are concatenated in shard order, then re-sorted by the ORDER BY
+//!   and cut to the LIMIT and OFFSET. Each shard is asked for LIMIT plus
+//!   OFFSET rows, since any of them may hold the first ones.
+//! - aggregates are recomputed from each shard's partial results: counts
+//!   and sums are added up, minima and maxima compared, and AVG becomes a
+//!   SUM and a COUNT on each shard
+//!
+//! Tables without a shard key are copied in full on every shard: writes to
+//! them and schema changes go to all shards, reads to the first. Joins and
+//! subqueries touching a sharded table must be pinned to a single shard.
+//! A write reaching several shards runs on each independently, so if one
+//! fails the others keep their changes, unless two-phase commit is enabled
+//! (see `two_phase`), which also lets a script of writes run as one
+//! transaction across shards.
+//!
+//! Reads scattered over several shards see them all at the same point:
+//! writes through the coordinator wait for them to finish, and they for the
+//! writes, so a read never sees a multi-shard write on some shards but not
+//! others. Each write advances the coordinator's timestamp, and
+//! `ShardCoordinator::snapshot` freezes every shard at one timestamp for
+//! any number of later reads. Writes that bypass the coordinator are not
+//! ordered this way.
+
+use crate::aggregate::{self, AggregateFunction};
+use crate::error::{QubeError, QubeResult};
+use crate::query::{self, ExecutionContext, QueryEngine, QueryOptions};
+use crate::security::Permission;
+use crate::two_phase::TwoPhaseCoordinator;
+use crate::types::{QueryResult, ResultKind, Row, Value};
+use crate::window;
+use sqlparser::ast::{
+    visit_expressions_mut, visit_relations, BinaryOperator, Expr, Function, Ident, Query,
+    SelectItem, SetExpr, Statement, UnaryOperator, Value as SqlValue,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::ControlFlow;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// One partition of the data, local or remote
+pub trait Shard: Send + Sync {
+    /// Run one statement on this shard
+    fn execute(&self, statement: &Statement) -> QubeResult<QueryResult>;
+
+    /// A read-only copy of this shard as it is now, unaffected by later
+    /// writes
+    fn snapshot(&self) -> QubeResult<Arc<dyn Shard>>;
+}
+
+impl Shard for QueryEngine {
+    fn execute(&self, statement: &Statement) -> QubeResult<QueryResult> {
+        let mut ctx = ExecutionContext::new(&QueryOptions::default());
+        self.execute_statement(statement.clone(), &mut ctx)
+    }
+
+    fn snapshot(&self) -> QubeResult<Arc<dyn Shard>> {
+        Ok(Arc::new(QueryEngine::snapshot(self)))
+    }
+}
+
+/// Routes statements to the shards holding the rows they touch, and merges
+/// what those shards return
+pub struct ShardCoordinator {
+    shards: Vec<Arc<dyn Shard>>,
+    /// Shard key column of each sharded table
+    keys: HashMap<String, String>,
+    /// Makes writes reaching several shards atomic, if enabled
+    transactions: Option<TwoPhaseCoordinator>,
+    /// Number of writes run so far. Writes hold it exclusively, and reads
+    /// spanning shards shared, so neither sees the other half done.
+    timestamp: RwLock<u64>,
+}
+
+impl ShardCoordinator {
+    pub fn new(shards: Vec<Arc<dyn Shard>>) -> QubeResult<Self> {
+        if shards.is_empty() {
+            return Err(QubeError::Config(
+                "a shard coordinator needs at least one shard".to_string(),
+            ));
+        }
+        Ok(ShardCoordinator {
+            shards,
+            keys: HashMap::new(),
+            transactions: None,
+            timestamp: RwLock::new(0),
+        })
+    }
+
+    /// Spread the rows of `table` over the shards by the value of `column`.
+    /// Names are matched as the catalog holds them, so unquoted ones in
+    /// lower case.
+    pub fn shard_table(&mut self, table: impl Into<String>, column: impl Into<String>) {
+        self.keys.insert(table.into(), column.into());
+    }
+
+    /// The shard key column of `table`, if it is sharded
+    pub fn shard_key(&self, table: &str) -> Option<&str> {
+        self.keys.get(table).map(String::as_str)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard owning rows whose shard key is `value`
+    pub fn shard_of(&self, value: &Value) -> usize {
+        (key_hash(&value.to_string()) % self.shards.len() as u64) as usize
+    }
+
+    /// Number of writes run through this coordinator, which every read
+    /// sees either all or none of
+    pub fn timestamp(&self) -> u64 {
+        *self.timestamp.read().unwrap()
+    }
+
+    /// Freeze every shard at the current timestamp, for reads that must
+    /// all see the same data however many writes run meanwhile
+    pub fn snapshot(&self) -> QubeResult<ShardSnapshot> {
+        let timestamp = self.timestamp.read().unwrap();
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.snapshot())
+            .collect::<QubeResult<Vec<_>>>()?;
+        Ok(ShardSnapshot {
+            view: ShardCoordinator {
+                shards,
+                keys: self.keys.clone(),
+                transactions: None,
+                timestamp: RwLock::new(*timestamp),
+            },
+        })
+    }
+
+    /// Parse and run a script of `;`-separated statements in order,
+    /// returning each statement's result
+    pub fn execute(&self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        query::parse_script(sql)?
+            .iter()
+            .map(|statement| self.execute_statement(statement))
+            .collect()
+    }
+
+    /// Run one statement on the shards it concerns and merge their results
+    pub fn execute_statement(&self, statement: &Statement) -> QubeResult<QueryResult> {
+        let start_time = Instant::now();
+        let result = match statement {
+            Statement::Query(query) => {
+                let sharded = self.sharded_tables(statement);
+                if sharded.is_empty() {
+                    self.shards[0].execute(statement)?
+                } else {
+                    let targets = self.targets(statement, &sharded)?;
+                    if targets.len() == 1 {
+                        self.shards[targets[0]].execute(statement)?
+                    } else {
+                        let _timestamp = self.timestamp.read().unwrap();
+                        scatter_select(self, query, &targets)?
+                    }
+                }
+            }
+            _ if Permission::required_for(statement) == Permission::Read => {
+                self.shards[0].execute(statement)?
+            }
+            _ => {
+                let plan = self.plan_write(statement)?;
+                let mut timestamp = self.timestamp.write().unwrap();
+                let results = match &self.transactions {
+                    Some(transactions) if plan.work.len() > 1 => transactions
+                        .execute(
+                            plan.work
+                                .into_iter()
+                                .map(|(shard, statement)| (shard, vec![statement]))
+                                .collect(),
+                        )?
+                        .into_iter()
+                        .flatten()
+                        .collect(),
+                    _ => self.scatter(plan.work)?,
+                };
+                *timestamp += 1;
+                plan.gather.apply(results)
+            }
+        };
+        Ok(QueryResult {
+            execution_time: start_time.elapsed(),
+            ..result
+        })
+    }
+
+    /// Parse and run a script of writes as one transaction across shards,
+    /// using two-phase commit: either every statement takes effect on every
+    /// shard it concerns, or none does. Needs `enable_two_phase_commit`.
+    pub fn execute_atomically(&self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        let transactions = self.transactions.as_ref().ok_or_else(|| {
+            QubeError::Config("two-phase commit is not enabled for these shards".to_string())
+        })?;
+        let mut statements_by_shard: BTreeMap<usize, Vec<Statement>> = BTreeMap::new();
+        // Where each statement's part landed: shard and position there
+        let mut parts = Vec::new();
+        for statement in query::parse_script(sql)? {
+            if Permission::required_for(&statement) == Permission::Read {
+                return Err(QubeError::QueryParse(format!(
+                    "Only writes can run in a transaction across shards: {}",
+                    statement
+                )));
+            }
+            let plan = self.plan_write(&statement)?;
+            let placed: Vec<(usize, usize)> = plan
+                .work
+                .into_iter()
+                .map(|(shard, statement)| {
+                    let statements = statements_by_shard.entry(shard).or_default();
+                    statements.push(statement);
+                    (shard, statements.len() - 1)
+                })
+                .collect();
+            parts.push((placed, plan.gather));
+        }
+
+        let shards: Vec<usize> = statements_by_shard.keys().copied().collect();
+        let mut timestamp = self.timestamp.write().unwrap();
+        let mut results: HashMap<usize, Vec<Option<QueryResult>>> = shards
+            .iter()
+            .copied()
+            .zip(
+                transactions
+                    .execute(statements_by_shard.into_iter().collect())?
+                    .into_iter()
+                    .map(|results| results.into_iter().map(Some).collect()),
+            )
+            .collect();
+        *timestamp += 1;
+        Ok(parts
+            .into_iter()
+            .map(|(placed, gather)| {
+                gather.apply(
+                    placed
+                        .into_iter()
+                        .filter_map(|(shard, i)| results.get_mut(&shard)?[i].take())
+                        .collect(),
+                )
+            })
+            .collect())
+    }
+
+    /// Run multi-shard writes through `transactions`, so each is applied on
+    /// all of its shards or none. Its participants must be these shards, in
+    /// the same order.
+    pub fn enable_two_phase_commit(&mut self, transactions: TwoPhaseCoordinator) -> QubeResult<()> {
+        if transactions.participant_count() != self.shards.len() {
+            return Err(QubeError::Config(format!(
+                "two-phase commit has {} participants for {} shards",
+                transactions.participant_count(),
+                self.shards.len()
+            )));
+        }
+        self.transactions = Some(transactions);
+        Ok(())
+    }
+
+    pub fn two_phase_commit(&self) -> Option<&TwoPhaseCoordinator> {
+        self.transactions.as_ref()
+    }
+
+    /// The shards a write runs on, and how their results combine
+    fn plan_write(&self, statement: &Statement) -> QubeResult<WritePlan> {
+        let sharded = self.sharded_tables(statement);
+        match statement {
+            Statement::Insert { table_name, .. } => {
+                if let Some(key) = self.keys.get(&query::object_name(table_name)) {
+                    return self.plan_insert(statement, key);
+                }
+            }
+            Statement::Update { assignments, .. } if !sharded.is_empty() => {
+                let (table, key) = &sharded[0];
+                if let Some(assignment) = assignments
+                    .iter()
+                    .find(|a| a.id.last().is_some_and(|id| &id.value == key))
+                {
+                    return Err(QubeError::ConstraintViolation(format!(
+                        "cannot update shard key '{}' of table '{}' in '{}'",
+                        key, table, assignment
+                    )));
+                }
+            }
+            _ => {}
+        }
+        let (targets, gather) = match statement {
+            Statement::Update { .. } | Statement::Delete { .. } if !sharded.is_empty() => {
+                (self.targets(statement, &sharded)?, Gather::Concat)
+            }
+            // Copied to every shard, which each report the same outcome
+            _ => ((0..self.shards.len()).collect(), Gather::First),
+        };
+        Ok(WritePlan {
+            work: targets
+                .into_iter()
+                .map(|shard| (shard, statement.clone()))
+                .collect(),
+            gather,
+        })
+    }
+
+    /// Sharded tables `statement` reads or writes, with their shard keys
+    fn sharded_tables(&self, statement: &Statement) -> Vec<(String, String)> {
+        let mut tables = Vec::new();
+        let _ = visit_relations(statement, |relation| {
+            let name = query::object_name(relation);
+            if let Some(key) = self.keys.get(&name) {
+                tables.push((name, key.clone()));
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        tables
+    }
+
+    /// Shards a read, update or delete of one sharded table must run on
+    fn targets(
+        &self,
+        statement: &Statement,
+        sharded: &[(String, String)],
+    ) -> QubeResult<Vec<usize>> {
+        // Each shard would run the CTEs over its own rows only
+        if matches!(statement, Statement::Query(query) if query.with.is_some()) {
+            return Err(QubeError::Unsupported(format!(
+                "WITH (common table expressions) over sharded table '{}'",
+                sharded[0].0
+            )));
+        }
+        let mut relations = 0;
+        let _ = visit_relations(statement, |_| {
+            relations += 1;
+            ControlFlow::<()>::Continue(())
+        });
+        let selection = match statement {
+            Statement::Query(query) => match &*query.body {
+                SetExpr::Select(select) => select.selection.as_ref(),
+                _ => None,
+            },
+            Statement::Update { selection, .. } | Statement::Delete { selection, .. } => {
+                selection.as_ref()
+            }
+            _ => None,
+        };
+        let pinned = selection.and_then(|selection| self.pinned(selection, &sharded[0].1));
+        match pinned {
+            // The WHERE clause contradicts itself, so no shard has a match
+            Some(shards) if shards.is_empty() => Ok(vec![0]),
+            Some(shards) if shards.len() == 1 || relations == 1 => Ok(shards.into_iter().collect()),
+            None if relations == 1 => Ok((0..self.shards.len()).collect()),
+            _ => Err(QubeError::QueryParse(format!(
+                "Joins and subqueries over sharded table '{}' must select a single shard key",
+                sharded[0].0
+            ))),
+        }
+    }
+
+    /// The shards a WHERE clause confines `key` to, if it does
+    fn pinned(&self, selection: &Expr, key: &str) -> Option<BTreeSet<usize>> {
+        match selection {
+            Expr::Nested(inner) => self.pinned(inner, key),
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => match (self.pinned(left, key), self.pinned(right, key)) {
+                (Some(left), Some(right)) => Some(left.intersection(&right).copied().collect()),
+                (left, right) => left.or(right),
+            },
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => {
+                let value = match (is_column(left, key), is_column(right, key)) {
+                    (true, _) => literal(right)?,
+                    (_, true) => literal(left)?,
+                    _ => return None,
+                };
+                Some(BTreeSet::from([self.shard_of(&value)]))
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } if is_column(expr, key) => list
+                .iter()
+                .map(|item| literal(item).map(|value| self.shard_of(&value)))
+                .collect(),
+            _ => None,
+        }
+    }
+
+    /// Send each row of an INSERT into a sharded table to its shard
+    fn plan_insert(&self, statement: &Statement, key: &str) -> QubeResult<WritePlan> {
+        let (table_name, columns, source) = match statement {
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+                ..
+            } => (table_name, columns, source),
+            _ => unreachable!("not an INSERT"),
+        };
+        let table = query::object_name(table_name);
+        let position = columns
+            .iter()
+            .position(|column| column.value == key)
+            .ok_or_else(|| {
+                QubeError::ConstraintViolation(format!(
+                    "INSERT into sharded table '{}' must set its shard key '{}'",
+                    table, key
+                ))
+            })?;
+        let values = match &*source.body {
+            SetExpr::Values(values) => values,
+            _ => {
+                return Err(QubeError::QueryParse(format!(
+                    "INSERT into sharded table '{}' must list its rows with VALUES",
+                    table
+                )))
+            }
+        };
+
+        let mut rows_by_shard: Vec<Vec<Vec<Expr>>> = vec![Vec::new(); self.shards.len()];
+        for row in &values.rows {
+            let value = row
+                .get(position)
+                .and_then(literal)
+                .filter(|value| !value.is_null())
+                .ok_or_else(|| {
+                    QubeError::ConstraintViolation(format!(
+                        "shard key '{}' of table '{}' must be a non-NULL literal",
+                        key, table
+                    ))
+                })?;
+            rows_by_shard[self.shard_of(&value)].push(row.clone());
+        }
+
+        let work = rows_by_shard
+            .into_iter()
+            .enumerate()
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(shard, rows)| {
+                let mut statement = statement.clone();
+                if let Statement::Insert { source, .. } = &mut statement {
+                    if let SetExpr::Values(values) = &mut *source.body {
+                        values.rows = rows;
+                    }
+                }
+                (shard, statement)
+            })
+            .collect();
+        Ok(WritePlan {
+            work,
+            gather: Gather::Concat,
+        })
+    }
+
+    /// Run each statement on its shard, in parallel, returning the results
+    /// in the order given
+    fn scatter(&self, work: Vec<(usize, Statement)>) -> QubeResult<Vec<QueryResult>> {
+        if let [(shard, statement)] = work.as_slice() {
+            return Ok(vec![self.shards[*shard].execute(statement)?]);
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = work
+                .iter()
+                .map(|(shard, statement)| {
+                    let shard = &self.shards[*shard];
+                    scope.spawn(move || shard.execute(statement))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .zip(&work)
+                .map(|(handle, (shard, _))| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(QubeError::Storage(format!("shard {} panicked", shard)))
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+impl std::fmt::Debug for ShardCoordinator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardCoordinator")
+            .field("shards", &self.shards.len())
+            .field("keys", &self.keys)
+            .field("transactions", &self.transactions)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+/// Every shard frozen at one timestamp, taken by
+/// `ShardCoordinator::snapshot`. Only reads can run on it.
+#[derive(Debug)]
+pub struct ShardSnapshot {
+    view: ShardCoordinator,
+}
+
+impl ShardSnapshot {
+    /// The coordinator's timestamp when the snapshot was taken
+    pub fn timestamp(&self) -> u64 {
+        self.view.timestamp()
+    }
+
+    /// Parse and run a script of reads in order
+    pub fn execute(&self, sql: &str) -> QubeResult<Vec<QueryResult>> {
+        query::parse_script(sql)?
+            .iter()
+            .map(|statement| self.execute_statement(statement))
+            .collect()
+    }
+
+    /// Run one read on the frozen shards it concerns
+    pub fn execute_statement(&self, statement: &Statement) -> QubeResult<QueryResult> {
+        if Permission::required_for(statement) != Permission::Read {
+            return Err(QubeError::Transaction(format!(
+                "a shard snapshot is read-only: {}",
+                statement
+            )));
+        }
+        self.view.execute_statement(statement)
+    }
+}
+
+/// A write split into the statements each shard runs
+struct WritePlan {
+    work: Vec<(usize, Statement)>,
+    gather: Gather,
+}
+
+/// How the results of a write's parts combine
+enum Gather {
+    /// Each shard changed its own rows; add them up
+    Concat,
+    /// Each shard holds a copy of the table and did the same
+    First,
+}
+
+impl Gather {
+    fn apply(&self, results: Vec<QueryResult>) -> QueryResult {
+        match self {
+            Gather::Concat => concat(results),
+            Gather::First => results.into_iter().next().expect("at least one shard ran"),
+        }
+    }
+}
+
+/// Scatter a SELECT over one sharded table to `targets` and merge the rows
+/// or aggregates they return
+fn scatter_select(
+    coordinator: &ShardCoordinator,
+    query: &Query,
+    targets: &[usize],
+) -> QubeResult<QueryResult> {
+    let select = match &*query.body {
+        SetExpr::Select(select) => select,
+        other => {
+            return Err(QubeError::Unsupported(format!(
+                "query across shards {}",
+                other
+            )))
+        }
+    };
+    // A window may span shards, which only see their own rows
+    if targets.len() > 1 && window::has_window_functions(&select.projection) {
+        return Err(QubeError::QueryParse(
+            "Window functions cannot be combined across shards".to_string(),
+        ));
+    }
+    let offset = match &query.offset {
+        Some(offset) => query::row_count_of(&offset.value)?,
+        None => 0,
+    };
+    let limit = match &query.limit {
+        Some(limit) => Some(query::row_count_of(limit)?),
+        None => None,
+    };
+    let run = |shard_query: Query| {
+        let statement = Statement::Query(Box::new(shard_query));
+        let work = targets
+            .iter()
+            .map(|&shard| (shard, statement.clone()))
+            .collect();
+        coordinator.scatter(work)
+    };
+
+    if aggregate::has_aggregates(&select.projection) {
+        let plan = PartialAggregates::plan(&select.projection)?;
+        let mut shard_select = (**select).clone();
+        shard_select.projection = plan.projection();
+        let shard_query = Query {
+            body: Box::new(SetExpr::Select(Box::new(shard_select))),
+            order_by: vec![],
+            limit: None,
+            offset: None,
+            ..query.clone()
+        };
+        let row = plan.merge(&run(shard_query)?)?;
+        return Ok(QueryResult {
+            kind: ResultKind::Rows,
+            columns: plan.outputs.iter().map(|(name, _)| name.clone()).collect(),
+            rows: std::iter::once(row)
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect(),
+            affected_rows: 0,
+            last_insert_id: None,
+            execution_time: Default::default(),
+        });
+    }
+
+    // Have every shard return its sort keys as extra columns, and enough
+    // rows to cover the OFFSET
+    let sort_keys: Vec<String> = (0..query.order_by.len())
+        .map(|i| format!("#sort{}", i))
+        .collect();
+    let mut shard_select = (**select).clone();
+    for (order, name) in query.order_by.iter().zip(&sort_keys) {
+        shard_select.projection.push(SelectItem::ExprWithAlias {
+            expr: order.expr.clone(),
+            alias: Ident::with_quote('"', name.as_str()),
+        });
+    }
+    let shard_query = Query {
+        body: Box::new(SetExpr::Select(Box::new(shard_select))),
+        limit: limit.map(|limit| number(limit.saturating_add(offset))),
+        offset: None,
+        ..query.clone()
+    };
+    let mut result = concat(run(shard_query)?);
+
+    if !sort_keys.is_empty() {
+        let mut keyed: Vec<(Vec<Value>, Row)> = result
+            .rows
+            .into_iter()
+            .map(|mut row| {
+                let keys = sort_keys
+                    .iter()
+                    .map(|key| row.remove(key).unwrap_or(Value::Null))
+                    .collect();
+                (keys, row)
+            })
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| query::compare_sort_keys(a, b, &query.order_by));
+        result.rows = keyed.into_iter().map(|(_, row)| row).collect();
+        result.columns.retain(|column| !sort_keys.contains(column));
+    }
+    result.rows = result
+        .rows
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    Ok(result)
+}
+
+/// The aggregates of a SELECT list split into what each shard computes and
+/// how the coordinator combines it
+struct PartialAggregates {
+    /// Aggregate calls each shard evaluates, returned as `#p0`, `#p1`, ...
+    partials: Vec<Function>,
+    /// How to combine the partials into each aggregate's final value
+    merges: Vec<Merge>,
+    /// Output columns, with aggregate calls replaced by `#0`, `#1`, ...
+    outputs: Vec<(String, Expr)>,
+}
+
+/// How one aggregate's partial results, by index into the partials, combine
+enum Merge {
+    Sum(usize),
+    Min(usize),
+    Max(usize),
+    Avg { sum: usize, count: usize },
+}
+
+impl PartialAggregates {
+    fn plan(projection: &[SelectItem]) -> QubeResult<Self> {
+        let mut plan = PartialAggregates {
+            partials: Vec::new(),
+            merges: Vec::new(),
+            outputs: Vec::new(),
+        };
+        for item in projection {
+            let (name, expr) = match item {
+                SelectItem::UnnamedExpr(expr) => (output_name(expr), expr),
+                SelectItem::ExprWithAlias { expr, alias } => (alias.value.clone(), expr),
+                _ => {
+                    return Err(QubeError::QueryParse(
+                        "Wildcards cannot be combined with aggregate functions".to_string(),
+                    ))
+                }
+            };
+            let mut expr = expr.clone();
+            let mut error = None;
+            let _ = visit_expressions_mut(&mut expr, |e| {
+                let function = match e {
+                    Expr::Function(function) if function.name.0.len() == 1 => function,
+                    _ => return ControlFlow::Continue(()),
+                };
+                let kind = match AggregateFunction::from_name(&function.name.0[0].value) {
+                    Some(kind) => kind,
+                    None => return ControlFlow::Continue(()),
+                };
+                match plan.split(kind, function) {
+                    Ok(merge) => {
+                        *e = Expr::Identifier(Ident::new(format!("#{}", plan.merges.len())));
+                        plan.merges.push(merge);
+                        ControlFlow::Continue(())
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        ControlFlow::Break(())
+                    }
+                }
+            });
+            if let Some(e) = error {
+                return Err(e);
+            }
+            plan.outputs.push((name, expr));
+        }
+        Ok(plan)
+    }
+
+    /// Add the partials of one aggregate call
+    fn split(&mut self, kind: AggregateFunction, function: &Function) -> QubeResult<Merge> {
+        // Shards may hold the same value, so their estimates cannot be added
+        if kind == AggregateFunction::ApproxCountDistinct
+            || (function.distinct
+                && matches!(
+                    kind,
+                    AggregateFunction::Count | AggregateFunction::Sum | AggregateFunction::Avg
+                ))
+        {
+            return Err(QubeError::QueryParse(format!(
+                "{} cannot be combined across shards",
+                function
+            )));
+        }
+        let mut partial = |name: &str| {
+            let mut call = function.clone();
+            call.name.0[0] = Ident::new(name);
+            self.partials.push(call);
+            self.partials.len() - 1
+        };
+        Ok(match kind {
+            AggregateFunction::Count | AggregateFunction::Sum => {
+                Merge::Sum(partial(&function.name.0[0].value))
+            }
+            AggregateFunction::Min => Merge::Min(partial("MIN")),
+            AggregateFunction::Max => Merge::Max(partial("MAX")),
+            AggregateFunction::ApproxCountDistinct => unreachable!("rejected above"),
+            AggregateFunction::Avg => Merge::Avg {
+                sum: partial("SUM"),
+                count: partial("COUNT"),
+            },
+        })
+    }
+
+    /// The SELECT list each shard runs
+    fn projection(&self) -> Vec<SelectItem> {
+        self.partials
+            .iter()
+            .enumerate()
+            .map(|(i, call)| SelectItem::ExprWithAlias {
+                expr: Expr::Function(call.clone()),
+                alias: Ident::with_quote('"', format!("#p{}", i)),
+            })
+            .collect()
+    }
+
+    /// Combine the single row each shard returned into the final one
+    fn merge(&self, results: &[QueryResult]) -> QubeResult<Row> {
+        let partial = |i: usize| {
+            let key = format!("#p{}", i);
+            results
+                .iter()
+                .map(move |result| {
+                    result
+                        .rows
+                        .first()
+                        .and_then(|row| row.get(&key))
+                        .cloned()
+                        .unwrap_or(Value::Null)
+                })
+                .filter(|value| !value.is_null())
+        };
+        let mut values = Row::new();
+        for (i, merge) in self.merges.iter().enumerate() {
+            let value = match merge {
+                Merge::Sum(p) => sum(partial(*p), *p, &self.partials)?,
+                Merge::Min(p) => extreme(partial(*p), std::cmp::Ordering::Less),
+                Merge::Max(p) => extreme(partial(*p), std::cmp::Ordering::Greater),
+                Merge::Avg { sum: s, count: c } => {
+                    let total = sum(partial(*s), *s, &self.partials)?;
+                    let count = partial(*c).filter_map(|v| v.as_i64()).sum::<i64>();
+                    match total.as_f64().or_else(|| total.as_i64().map(|v| v as f64)) {
+                        Some(total) if count > 0 => Value::Float64(total / count as f64),
+                        _ => Value::Null,
+                    }
+                }
+            };
+            values.insert(format!("#{}", i), value);
+        }
+        self.outputs
+            .iter()
+            .map(|(name, expr)| Ok((name.clone(), crate::expr::evaluate(expr, &values)?)))
+            .collect()
+    }
+}
+
+/// Add up partial counts or sums; COUNT gives 0 and SUM NULL when there
+/// are none
+fn sum(
+    values: impl Iterator<Item = Value>,
+    partial: usize,
+    partials: &[Function],
+) -> QubeResult<Value> {
+    let is_count = partials[partial].name.0[0]
+        .value
+        .eq_ignore_ascii_case("COUNT");
+    let mut total = if is_count {
+        Some(Value::Int64(0))
+    } else {
+        None
+    };
+    for value in values {
+        total = Some(match (total, value) {
+            (None, value) => value,
+            (Some(Value::Int64(a)), Value::Int64(b)) => {
+                Value::Int64(a.checked_add(b).ok_or_else(crate::expr::overflow)?)
+            }
+            (Some(a), b) => Value::Float64(
+                a.as_f64()
+                    .or_else(|| a.as_i64().map(|v| v as f64))
+                    .unwrap_or(0.0)
+                    + b.as_f64()
+                        .or_else(|| b.as_i64().map(|v| v as f64))
+                        .unwrap_or(0.0),
+            ),
+        });
+    }
+    Ok(total.unwrap_or(Value::Null))
+}
+
+/// The least or greatest of some partial minima or maxima
+fn extreme(values: impl Iterator<Item = Value>, wanted: std::cmp::Ordering) -> Value {
+    values
+        .reduce(|best, value| {
+            if value.compare(&best) == Some(wanted) {
+                value
+            } else {
+                best
+            }
+        })
+        .unwrap_or(Value::Null)
+}
+
+/// Merge the results of shards that each ran part of a statement
+fn concat(results: Vec<QueryResult>) -> QueryResult {
+    let mut results = results.into_iter();
+    let mut merged = results.next().expect("at least one shard ran");
+    for result in results {
+        merged.rows.extend(result.rows);
+        merged.affected_rows += result.affected_rows;
+        merged.last_insert_id = result.last_insert_id.or(merged.last_insert_id);
+    }
+    merged
+}
+
+/// Name a shard gives the output column of an unaliased expression
+fn output_name(expr: &Expr) -> String {
+    match expr {
+        Expr::Identifier(ident) => ident.value.clone(),
+        Expr::CompoundIdentifier(idents) => idents
+            .last()
+            .map(|ident| ident.value.clone())
+            .unwrap_or_default(),
+        other => other.to_string(),
+    }
+}
+
+/// Whether `expr` names the column `key`
+fn is_column(expr: &Expr, key: &str) -> bool {
+    match expr {
+        Expr::Identifier(ident) => ident.value == key,
+        Expr::CompoundIdentifier(idents) => idents.last().is_some_and(|ident| ident.value == key),
+        _ => false,
+    }
+}
+
+/// The value of a literal, possibly negated
+fn literal(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Value(SqlValue::Placeholder(_)) => None,
+        Expr::Value(_)
+        | Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            ..
+        } => crate::expr::evaluate(expr, &Row::new()).ok(),
+        _ => None,
+    }
+}
+
+fn number(n: usize) -> Expr {
+    Expr::Value(SqlValue::Number(n.to_string(), false))
+}
+
+/// 64-bit FNV-1a of a shard key's text. Rows are placed by it, so this must
+/// never change.
+fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A coordinator over two in-memory shards, with `orders` sharded by
+    /// customer and `regions` copied to both
+    fn two_shards() -> (ShardCoordinator, Vec<Arc<QueryEngine>>) {
+        let engines = vec![Arc::new(QueryEngine::new()), Arc::new(QueryEngine::new())];
+        let shards = engines
+            .iter()
+            .map(|engine| Arc::clone(engine) as Arc<dyn Shard>)
+            .collect();
+        let mut coordinator = ShardCoordinator::new(shards).unwrap();
+        coordinator.shard_table("orders", "customer");
+        coordinator
+            .execute(
+                "CREATE TABLE orders (id INT PRIMARY KEY, customer TEXT, amount BIGINT);
+                 CREATE TABLE regions (name TEXT)",
+            )
+            .unwrap();
+        (coordinator, engines)
+    }
+
+    fn count(engine: &QueryEngine, sql: &str) -> usize {
+        Shard::execute(engine, &query::parse_script(sql).unwrap()[0])
+            .unwrap()
+            .rows
+            .len()
+    }
+
+    #[test]
+    fn rows_go_to_the_shard_owning_their_key() {
+        let (coordinator, engines) = two_shards();
+        let customers = ["ann", "bob", "cat", "dan", "eve", "fay"];
+        let values: Vec<String> = customers
+            .iter()
+            .enumerate()
+            .map(|(i, customer)| format!("({}, '{}', {})", i, customer, (i + 1) * 10))
+            .collect();
+        let result = coordinator
+            .execute(&format!(
+                "INSERT INTO orders (id, customer, amount) VALUES {}",
+                values.join(", ")
+            ))
+            .unwrap();
+        assert_eq!(result[0].affected_rows, 6);
+        coordinator
+            .execute("INSERT INTO regions VALUES ('north')")
+            .unwrap();
+
+        for customer in customers {
+            let owner = coordinator.shard_of(&Value::String(customer.to_string()));
+            let sql = format!("SELECT id FROM orders WHERE customer = '{}'", customer);
+            assert_eq!(count(&engines[owner], &sql), 1);
+            assert_eq!(count(&engines[1 - owner], &sql), 0);
+            // A read pinning the key is answered by the owner alone
+            assert_eq!(coordinator.execute(&sql).unwrap()[0].rows.len(), 1);
+        }
+        // Unsharded tables are copied to every shard
+        for engine in &engines {
+            assert_eq!(count(engine, "SELECT name FROM regions"), 1);
+        }
+        assert!(coordinator
+            .execute("UPDATE orders SET customer = 'zed' WHERE id = 1")
+            .is_err());
+    }
+
+    #[test]
+    fn scattered_reads_are_merged_in_order_and_reaggregated() {
+        let (coordinator, engines) = two_shards();
+        coordinator
+            .execute(
+                "INSERT INTO orders (id, customer, amount) VALUES
+                 (1, 'ann', 10), (2, 'bob', 20), (3, 'cat', 30),
+                 (4, 'dan', 40), (5, 'eve', 50), (6, 'fay', 60)",
+            )
+            .unwrap();
+        assert!(engines
+            .iter()
+            .all(|engine| count(engine, "SELECT id FROM orders") > 0));
+
+        let result = coordinator
+            .execute("SELECT id FROM orders ORDER BY amount DESC LIMIT 3 OFFSET 1")
+            .unwrap();
+        let ids: Vec<&Value> = result[0].rows.iter().map(|row| &row["id"]).collect();
+        assert_eq!(ids, [&Value::Int32(5), &Value::Int32(4), &Value::Int32(3)]);
+
+        let result = coordinator
+            .execute(
+                "SELECT COUNT(*) AS n, SUM(amount) AS total, AVG(amount) AS mean,
+                        MIN(amount) AS low, MAX(amount) AS high
+                 FROM orders",
+            )
+            .unwrap();
+        let row = &result[0].rows[0];
+        assert_eq!(row["n"], Value::Int64(6));
+        assert_eq!(row["total"], Value::Int64(210));
+        assert_eq!(row["mean"], Value::Float64(35.0));
+        assert_eq!(row["low"], Value::Int64(10));
+        assert_eq!(row["high"], Value::Int64(60));
+
+        let result = coordinator
+            .execute("DELETE FROM orders WHERE amount > 25")
+            .unwrap();
+        assert_eq!(result[0].affected_rows, 4);
+    }
+
+    /// A customer whose orders live on `shard`
+    fn customer_on(coordinator: &ShardCoordinator, shard: usize) -> String {
+        (0..)
+            .map(|i| format!("customer{}", i))
+            .find(|customer| coordinator.shard_of(&Value::String(customer.clone())) == shard)
+            .unwrap()
+    }
+
+    fn total_orders(result: &[QueryResult]) -> Value {
+        result[0].rows[0]["n"].clone()
+    }
+
+    #[test]
+    fn snapshots_ignore_later_writes_and_refuse_their_own() {
+        let (coordinator, _engines) = two_shards();
+        let (first, second) = (customer_on(&coordinator, 0), customer_on(&coordinator, 1));
+        coordinator
+            .execute(&format!(
+                "INSERT INTO orders (id, customer, amount) VALUES (1, '{}', 10), (2, '{}', 20)",
+                first, second
+            ))
+            .unwrap();
+        // Creating the tables and the insert were writes
+        assert_eq!(coordinator.timestamp(), 3);
+
+        let snapshot = coordinator.snapshot().unwrap();
+        coordinator
+            .execute(&format!(
+                "INSERT INTO orders (id, customer, amount) VALUES (3, '{}', 30), (4, '{}', 40);
+                 DELETE FROM orders WHERE id = 1",
+                first, second
+            ))
+            .unwrap();
+        assert_eq!(coordinator.timestamp(), 5);
+
+        let count = "SELECT COUNT(*) AS n FROM orders";
+        assert_eq!(snapshot.timestamp(), 3);
+        assert_eq!(
+            total_orders(&snapshot.execute(count).unwrap()),
+            Value::Int64(2)
+        );
+        let result = snapshot
+            .execute("SELECT id FROM orders ORDER BY id")
+            .unwrap();
+        let ids: Vec<&Value> = result[0].rows.iter().map(|row| &row["id"]).collect();
+        assert_eq!(ids, [&Value::Int32(1), &Value::Int32(2)]);
+        assert_eq!(
+            total_orders(&coordinator.execute(count).unwrap()),
+            Value::Int64(3)
+        );
+
+        let err = snapshot
+            .execute("DELETE FROM orders WHERE id = 2")
+            .unwrap_err();
+        assert!(matches!(err, QubeError::Transaction(_)), "{}", err);
+    }
+
+    #[test]
+    fn scattered_reads_never_see_half_a_write() {
+        let (coordinator, _engines) = two_shards();
+        let (first, second) = (customer_on(&coordinator, 0), customer_on(&coordinator, 1));
+        let coordinator = Arc::new(coordinator);
+
+        // Each insert puts one row on each shard, so every read that sees
+        // whole writes counts an even number of orders
+        let writer = {
+            let coordinator = Arc::clone(&coordinator);
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    coordinator
+                        .execute(&format!(
+                            "INSERT INTO orders (id, customer, amount) VALUES ({}, '{}', 1), ({}, '{}', 1)",
+                            2 * i,
+                            first,
+                            2 * i + 1,
+                            second
+                        ))
+                        .unwrap();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let result = coordinator
+                .execute("SELECT COUNT(*) AS n FROM orders")
+                .unwrap();
+            match total_orders(&result) {
+                Value::Int64(n) => assert_eq!(n % 2, 0, "read saw {} orders", n),
+                other => panic!("unexpected count {:?}", other),
+            }
+        }
+        writer.join().unwrap();
+        let result = coordinator
+            .execute("SELECT COUNT(*) AS n FROM orders")
+            .unwrap();
+        assert_eq!(total_orders(&result), Value::Int64(200));
+    }
+
+    #[test]
+    fn approximate_distinct_counts_are_not_scattered() {
+        let (coordinator, _) = two_shards();
+        coordinator
+            .execute(
+                "INSERT INTO orders (id, customer, amount) VALUES (1, 'ann', 10), (2, 'bob', 10)",
+            )
+            .unwrap();
+        let approx = coordinator.execute("SELECT APPROX_COUNT_DISTINCT(amount) AS n FROM orders");
+        assert!(matches!(approx, Err(QubeError::QueryParse(m)) if m.contains("across shards")));
+        let exact = coordinator
+            .execute("SELECT COUNT(*) AS n FROM orders")
+            .unwrap();
+        assert_eq!(exact[0].rows[0]["n"], Value::Int64(2));
+    }
+}
@@ -0,0 +1,557 @@
+//! Streaming ingestion from a topic into a table
+//!
+//! A `Sink` reads JSON messages from a `Topic` and writes each one as a row
+//! of its table, inserting it or, in `SinkMode::Upsert`, replacing the row
+//! with the same key. Message fields map to columns by name unless the
+//! `SinkConfig` maps them explicitly; a mapping may reach into nested
+//! objects with a dotted path such as `user.id`. A column whose field is
+//! missing from a message is left to its default.
+//!
+//! As with `subscriber::Subscriber`, the offset to resume from is kept in
+//! the database, in the `__sinks__` table, and each message is written in
+//! one script with the update of that offset, so it lands exactly once
+//! however often the sink is stopped and restarted.
+//!
+//! A message that can't become a row, because it is not a JSON object,
+//! holds a value its column can't take or breaks a constraint, is moved to
+//! the `__sink_dead_letters__` table with the error instead, and counted
+//! in the sink's `SinkMetrics`. Any other failure stops the sink.
+
+use crate::embedded::EmbeddedQubeDB;
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::replication::{self, RetryPolicy};
+use crate::subscriber::{DEFAULT_BATCH_SIZE, DEFAULT_POLL_INTERVAL};
+use crate::types::{Table, Value};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sqlparser::ast::Ident;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Table holding each sink's offset
+const POSITION_TABLE: &str = "__sinks__";
+
+/// Table holding the messages sinks could not write
+pub const DEAD_LETTER_TABLE: &str = "__sink_dead_letters__";
+
+/// One message of a topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMessage {
+    /// Position of the message in its topic
+    pub offset: u64,
+    /// The message, a JSON object
+    pub payload: Vec<u8>,
+}
+
+/// Where a sink reads messages from
+pub trait Topic: Send + Sync {
+    /// Up to `max` messages with an offset of at least `from_offset`, in
+    /// offset order. Empty once the sink has caught up.
+    fn fetch(&self, from_offset: u64, max: usize) -> QubeResult<Vec<TopicMessage>>;
+}
+
+/// A topic kept in memory, published to by this process
+#[derive(Debug, Default)]
+pub struct MemoryTopic {
+    messages: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryTopic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a message, returning its offset
+    pub fn publish(&self, payload: impl Into<Vec<u8>>) -> u64 {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push(payload.into());
+        messages.len() as u64 - 1
+    }
+
+    /// Messages published so far
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Topic for MemoryTopic {
+    fn fetch(&self, from_offset: u64, max: usize) -> QubeResult<Vec<TopicMessage>> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .enumerate()
+            .skip(usize::try_from(from_offset).unwrap_or(usize::MAX))
+            .take(max)
+            .map(|(offset, payload)| TopicMessage {
+                offset: offset as u64,
+                payload: payload.clone(),
+            })
+            .collect())
+    }
+}
+
+/// How a sink writes a message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SinkMode {
+    /// Insert a new row; a message with a key already present is a dead
+    /// letter
+    #[default]
+    Insert,
+    /// Insert a new row, or update the row with the same key
+    Upsert,
+}
+
+/// Settings for a `Sink`
+#[derive(Debug, Clone)]
+pub struct SinkConfig {
+    pub mode: SinkMode,
+    /// Columns and the message fields they are read from, as dotted paths.
+    /// When empty, each top-level field goes to the column of its name.
+    pub mapping: Vec<(String, String)>,
+    /// Most messages fetched at once
+    pub batch_size: usize,
+    /// Wait between fetches once caught up
+    pub poll_interval: Duration,
+    /// Backoff between attempts while the topic can't be reached
+    pub retry: RetryPolicy,
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        SinkConfig {
+            mode: SinkMode::Insert,
+            mapping: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl SinkConfig {
+    pub fn with_mode(mut self, mode: SinkMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Read `column` from the message field at `field`, e.g. `user.id`
+    pub fn map(mut self, column: impl Into<String>, field: impl Into<String>) -> Self {
+        self.mapping.push((column.into(), field.into()));
+        self
+    }
+}
+
+/// Counters describing a sink since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SinkMetrics {
+    /// Messages written to the table
+    pub ingested: u64,
+    /// Messages moved to the dead-letter table
+    pub dead_lettered: u64,
+}
+
+/// Writes the messages of a topic to a table
+pub struct Sink {
+    name: String,
+    table: String,
+    topic: Arc<dyn Topic>,
+    config: SinkConfig,
+    ingested: AtomicU64,
+    dead_lettered: AtomicU64,
+}
+
+impl Sink {
+    /// A sink into `table` whose offset is kept under `name`, so several
+    /// can feed one database from different topics
+    pub fn new(
+        name: impl Into<String>,
+        table: impl Into<String>,
+        topic: Arc<dyn Topic>,
+        config: SinkConfig,
+    ) -> Self {
+        Sink {
+            name: name.into(),
+            table: table.into(),
+            topic,
+            config,
+            ingested: AtomicU64::new(0),
+            dead_lettered: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn config(&self) -> &SinkConfig {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> SinkMetrics {
+        SinkMetrics {
+            ingested: self.ingested.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Offset of the next message to write to `db`, 0 before the first
+    pub fn position(&self, db: &EmbeddedQubeDB) -> QubeResult<u64> {
+        Ok(self.stored_position(db)?.unwrap_or(0))
+    }
+
+    /// Write the messages available now, up to one batch. Returns how many
+    /// were taken from the topic, dead letters included; 0 means the sink
+    /// has caught up.
+    pub fn sync(&self, db: &EmbeddedQubeDB) -> QubeResult<usize> {
+        self.ensure_tables(db)?;
+        let definition = db.describe_table(&self.table)?;
+        let position = self.position(db)?;
+        let messages = self.topic.fetch(position, self.config.batch_size.max(1))?;
+        for message in &messages {
+            let advance = format!(
+                "UPDATE {} SET next_offset = {} WHERE name = {}",
+                POSITION_TABLE,
+                message.offset + 1,
+                literal(&self.name)
+            );
+            let written = self
+                .insert_sql(&definition, &message.payload)
+                .and_then(|insert| db.execute_script(&format!("{};\n{}", insert, advance)));
+            match written {
+                Ok(_) => {
+                    self.ingested.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) if is_malformed(&e) => {
+                    tracing::warn!(
+                        "Sink {} moved message {} to the dead letters: {}",
+                        self.name,
+                        message.offset,
+                        e
+                    );
+                    let dead_letter = format!(
+                        "INSERT INTO {} (sink, message_offset, payload, error) VALUES ({}, {}, {}, {})",
+                        DEAD_LETTER_TABLE,
+                        literal(&self.name),
+                        message.offset,
+                        literal(&String::from_utf8_lossy(&message.payload)),
+                        literal(&e.to_string())
+                    );
+                    db.execute_script(&format!("{};\n{}", dead_letter, advance))?;
+                    self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    return Err(QubeError::Storage(format!(
+                        "Failed to write message {} of sink {}: {}",
+                        message.offset, self.name, e
+                    )))
+                }
+            }
+        }
+        Ok(messages.len())
+    }
+
+    /// Keep writing messages until `stop` is set. While the topic can't be
+    /// reached attempts back off as `config.retry` says; any other error
+    /// stops the sink.
+    pub fn run(&self, db: &EmbeddedQubeDB, stop: &AtomicBool) -> QubeResult<()> {
+        let mut failures = 0;
+        while !stop.load(Ordering::Relaxed) {
+            match self.sync(db) {
+                Ok(0) => {
+                    failures = 0;
+                    std::thread::sleep(self.config.poll_interval);
+                }
+                Ok(_) => failures = 0,
+                Err(e @ (QubeError::Network(_) | QubeError::Io(_))) => {
+                    failures += 1;
+                    tracing::warn!("Failed to fetch messages for sink {}: {}", self.name, e);
+                    let seed = replication::now_nanos() ^ u64::from(failures);
+                    std::thread::sleep(self.config.retry.backoff(failures, seed));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The statement writing `payload` as a row of `definition`
+    fn insert_sql(&self, definition: &Table, payload: &[u8]) -> QubeResult<String> {
+        let message: JsonValue = serde_json::from_slice(payload)
+            .map_err(|e| QubeError::Serialization(format!("Message is not JSON: {}", e)))?;
+        if !message.is_object() {
+            return Err(QubeError::Serialization(
+                "Message is not a JSON object".to_string(),
+            ));
+        }
+        let fields: Vec<(&str, &JsonValue)> = if self.config.mapping.is_empty() {
+            message
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, value)| (name.as_str(), value))
+                .collect()
+        } else {
+            self.config
+                .mapping
+                .iter()
+                .filter_map(|(column, field)| Some((column.as_str(), lookup(&message, field)?)))
+                .collect()
+        };
+
+        let mut columns = Vec::with_capacity(fields.len());
+        let mut values = Vec::with_capacity(fields.len());
+        for (name, value) in fields {
+            let column = definition
+                .columns
+                .iter()
+                .find(|c| c.name == name)
+                .ok_or_else(|| QubeError::ColumnNotFound(format!("{}.{}", self.table, name)))?;
+            columns.push(Ident::with_quote('"', name).to_string());
+            let value = expr::from_json(value, Some(&column.data_type))?;
+            values.push(expr::to_literal(&value).to_string());
+        }
+        if columns.is_empty() {
+            return Err(QubeError::ConstraintViolation(
+                "Message has no field for any column".to_string(),
+            ));
+        }
+
+        let mut sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.table,
+            columns.join(", "),
+            values.join(", ")
+        );
+        if self.config.mode == SinkMode::Upsert {
+            let assignments: Vec<String> = columns
+                .iter()
+                .map(|column| format!("{} = excluded.{}", column, column))
+                .collect();
+            sql.push_str(&format!(
+                " ON CONFLICT DO UPDATE SET {}",
+                assignments.join(", ")
+            ));
+        }
+        Ok(sql)
+    }
+
+    /// The offset saved in `db`, if there is one
+    fn stored_position(&self, db: &EmbeddedQubeDB) -> QubeResult<Option<u64>> {
+        if !db.list_tables().iter().any(|table| table == POSITION_TABLE) {
+            return Ok(None);
+        }
+        let sql = format!(
+            "SELECT next_offset FROM {} WHERE name = {}",
+            POSITION_TABLE,
+            literal(&self.name)
+        );
+        Ok(db.execute_script(&sql)?[0]
+            .rows
+            .first()
+            .and_then(|row| row["next_offset"].as_i64())
+            .map(|offset| offset as u64))
+    }
+
+    /// Create the offset and dead-letter tables and this sink's row in the
+    /// former, if missing
+    fn ensure_tables(&self, db: &EmbeddedQubeDB) -> QubeResult<()> {
+        let tables = db.list_tables();
+        if !tables.iter().any(|table| table == POSITION_TABLE) {
+            db.execute_script(&format!(
+                "CREATE TABLE {} (name TEXT PRIMARY KEY, next_offset BIGINT NOT NULL)",
+                POSITION_TABLE
+            ))?;
+        }
+        if !tables.iter().any(|table| table == DEAD_LETTER_TABLE) {
+            db.execute_script(&format!(
+                "CREATE TABLE {} (sink TEXT NOT NULL, message_offset BIGINT NOT NULL, \
+                 payload TEXT, error TEXT, PRIMARY KEY (sink, message_offset))",
+                DEAD_LETTER_TABLE
+            ))?;
+        }
+        if self.stored_position(db)?.is_none() {
+            db.execute_script(&format!(
+                "INSERT INTO {} (name, next_offset) VALUES ({}, 0)",
+                POSITION_TABLE,
+                literal(&self.name)
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sink")
+            .field("name", &self.name)
+            .field("table", &self.table)
+            .field("config", &self.config)
+            .field("metrics", &self.metrics())
+            .finish()
+    }
+}
+
+/// Whether writing a message failed because of the message itself
+fn is_malformed(error: &QubeError) -> bool {
+    matches!(
+        error,
+        QubeError::Serialization(_)
+            | QubeError::ColumnNotFound(_)
+            | QubeError::ConstraintViolation(_)
+    )
+}
+
+/// The value at a dotted path into a JSON object
+fn lookup<'a>(message: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    path.split('.')
+        .try_fold(message, |value, field| value.as_object()?.get(field))
+}
+
+fn literal(s: &str) -> String {
+    expr::to_literal(&Value::String(s.to_string())).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-sink-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn database(path: &std::path::Path) -> EmbeddedQubeDB {
+        let db = EmbeddedQubeDB::open(path).unwrap();
+        if db.list_tables().is_empty() {
+            db.execute_script("CREATE TABLE users (id INT PRIMARY KEY, name TEXT, city TEXT)")
+                .unwrap();
+        }
+        db
+    }
+
+    fn users(db: &EmbeddedQubeDB) -> Vec<(Value, Value, Value)> {
+        db.execute_script("SELECT id, name, city FROM users ORDER BY id")
+            .unwrap()[0]
+            .rows
+            .iter()
+            .map(|row| (row["id"].clone(), row["name"].clone(), row["city"].clone()))
+            .collect()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn upserts_apply_messages_in_offset_order_and_resume() {
+        let path = db_path("upsert");
+        let topic = Arc::new(MemoryTopic::new());
+        for message in [
+            r#"{"id": 1, "name": "ann", "city": "oslo"}"#,
+            r#"{"id": 2, "name": "bob"}"#,
+            r#"{"id": 1, "name": "anne", "city": "rome"}"#,
+        ] {
+            topic.publish(message);
+        }
+        let config = SinkConfig {
+            batch_size: 2,
+            ..SinkConfig::default().with_mode(SinkMode::Upsert)
+        };
+        let sink = Sink::new("users", "users", topic.clone(), config.clone());
+        {
+            let db = database(&path);
+            assert_eq!(sink.sync(&db).unwrap(), 2);
+            assert_eq!(sink.position(&db).unwrap(), 2);
+            assert_eq!(sink.sync(&db).unwrap(), 1);
+            assert_eq!(sink.sync(&db).unwrap(), 0);
+            assert_eq!(sink.position(&db).unwrap(), 3);
+        }
+
+        // The later message for id 1 won, and missing fields took defaults
+        topic.publish(r#"{"id": 3, "name": "cat"}"#);
+        let db = database(&path);
+        let sink = Sink::new("users", "users", topic, config);
+        assert_eq!(sink.position(&db).unwrap(), 3);
+        assert_eq!(sink.sync(&db).unwrap(), 1);
+        assert_eq!(
+            users(&db),
+            [
+                (Value::Int32(1), text("anne"), text("rome")),
+                (Value::Int32(2), text("bob"), Value::Null),
+                (Value::Int32(3), text("cat"), Value::Null),
+            ]
+        );
+        assert_eq!(sink.metrics().ingested, 1);
+    }
+
+    #[test]
+    fn malformed_messages_become_dead_letters() {
+        let db = database(&db_path("dead-letters"));
+        let topic = Arc::new(MemoryTopic::new());
+        for message in [
+            r#"{"id": 1, "name": "ann"}"#,
+            "not json",
+            r#"{"id": 1, "name": "again"}"#,
+            r#"{"id": "two"}"#,
+            r#"{"id": 2, "unknown": true}"#,
+            r#"[1, 2]"#,
+            r#"{"id": 3, "name": "cat"}"#,
+        ] {
+            topic.publish(message);
+        }
+        let sink = Sink::new("users", "users", topic, SinkConfig::default());
+        assert_eq!(sink.sync(&db).unwrap(), 7);
+        assert_eq!(
+            sink.metrics(),
+            SinkMetrics {
+                ingested: 2,
+                dead_lettered: 5
+            }
+        );
+        assert_eq!(users(&db).len(), 2);
+
+        let sql = format!(
+            "SELECT message_offset, payload FROM {} ORDER BY message_offset",
+            DEAD_LETTER_TABLE
+        );
+        let dead: Vec<(Value, Value)> = db.execute_script(&sql).unwrap()[0]
+            .rows
+            .iter()
+            .map(|row| (row["message_offset"].clone(), row["payload"].clone()))
+            .collect();
+        assert_eq!(
+            dead.iter()
+                .map(|(offset, _)| offset.clone())
+                .collect::<Vec<_>>(),
+            [1, 2, 3, 4, 5].map(Value::Int64)
+        );
+        assert_eq!(dead[0].1, text("not json"));
+        assert_eq!(sink.position(&db).unwrap(), 7);
+    }
+
+    #[test]
+    fn mapped_fields_are_read_from_nested_paths() {
+        let db = database(&db_path("mapping"));
+        let topic = Arc::new(MemoryTopic::new());
+        topic.publish(r#"{"user": {"id": 7, "profile": {"name": "dan"}}, "city": "lima"}"#);
+        let config = SinkConfig::default()
+            .map("id", "user.id")
+            .map("name", "user.profile.name");
+        let sink = Sink::new("nested", "users", topic, config);
+        assert_eq!(sink.sync(&db).unwrap(), 1);
+        // Fields not mapped are ignored, even with a matching column
+        assert_eq!(users(&db), [(Value::Int32(7), text("dan"), Value::Null)]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Table statistics for the query planner
+//!
+//! Statistics are collected by `ANALYZE` (or automatically after enough
+//! writes) and consulted by the planner when estimating result sizes.
+
+use crate::types::{Column, Row, Value};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Minimum number of modified rows before statistics are refreshed automatically
+pub const AUTO_ANALYZE_THRESHOLD: usize = 50;
+
+/// Fraction of the table that must change before statistics are refreshed automatically
+pub const AUTO_ANALYZE_SCALE_FACTOR: f64 = 0.1;
+
+/// Statistics for a single column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub distinct_count: usize,
+    pub null_count: usize,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+}
+
+/// Statistics for a table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableStats {
+    pub row_count: usize,
+    pub columns: HashMap<String, ColumnStats>,
+    /// Unix timestamp (seconds) of the last collection
+    pub analyzed_at: u64,
+}
+
+impl TableStats {
+    /// Compute statistics over every row of a table
+    pub fn collect<'a>(columns: &[Column], rows: impl Iterator<Item = &'a Row>) -> Self {
+        let mut distinct: Vec<HashSet<&'a Value>> = vec![HashSet::new(); columns.len()];
+        let mut column_stats: Vec<ColumnStats> = columns
+            .iter()
+            .map(|_| ColumnStats {
+                distinct_count: 0,
+                null_count: 0,
+                min: None,
+                max: None,
+            })
+            .collect();
+
+        let mut row_count = 0;
+        for row in rows {
+            row_count += 1;
+            for (i, column) in columns.iter().enumerate() {
+                let stats = &mut column_stats[i];
+                let value = match row.get(&column.name) {
+                    Some(value) if !value.is_null() => value,
+                    _ => {
+                        stats.null_count += 1;
+                        continue;
+                    }
+                };
+
+                distinct[i].insert(value);
+                if stats
+                    .min
+                    .as_ref()
+                    .is_none_or(|min| value.compare(min) == Some(Ordering::Less))
+                {
+                    stats.min = Some(value.clone());
+                }
+                if stats
+                    .max
+                    .as_ref()
+                    .is_none_or(|max| value.compare(max) == Some(Ordering::Greater))
+                {
+                    stats.max = Some(value.clone());
+                }
+            }
+        }
+
+        let columns = columns
+            .iter()
+            .zip(column_stats)
+            .zip(distinct)
+            .map(|((column, mut stats), values)| {
+                stats.distinct_count = values.len();
+                (column.name.clone(), stats)
+            })
+            .collect();
+
+        TableStats {
+            row_count,
+            columns,
+            analyzed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Estimated number of rows matching `column = <constant>`
+    pub fn estimate_equality_rows(&self, column: &str) -> f64 {
+        match self.columns.get(column) {
+            Some(stats) if stats.distinct_count > 0 => {
+                (self.row_count - stats.null_count) as f64 / stats.distinct_count as f64
+            }
+            _ => self.row_count as f64,
+        }
+    }
+
+    /// Whether enough rows changed since collection to warrant a refresh
+    pub fn is_stale(&self, modified_rows: usize) -> bool {
+        modified_rows as f64
+            > AUTO_ANALYZE_THRESHOLD as f64 + AUTO_ANALYZE_SCALE_FACTOR * self.row_count as f64
+    }
+}
@@ -0,0 +1,467 @@
+//! Logical replication from a primary's write-ahead log
+//!
+//! A `Subscriber` turns an `EmbeddedQubeDB` into a replica of a primary
+//! without a consensus cluster. It pulls committed changes from a
+//! `WalSource`, either the primary's log on this machine or its REST API's
+//! `GET /wal` endpoint through `HttpWalSource`, and applies them in LSN
+//! order.
+//!
+//! The position to resume from is kept in the replica itself, in the
+//! `__replication__` table, and every change is applied in one script
+//! with the update of that position. A change is therefore applied exactly
+//! once, however often the subscriber is stopped, restarted or cut off
+//! from the primary.
+
+use crate::embedded::EmbeddedQubeDB;
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::replication::{self, RetryPolicy};
+use crate::types::Value;
+use crate::wal::{WalChange, WalTail};
+use serde_json::Value as JsonValue;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Most changes fetched from the source at once
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// How long a subscriber that has caught up waits before fetching again
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long `HttpWalSource` waits to connect, send or receive
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Table in the replica holding each subscriber's position
+const POSITION_TABLE: &str = "__replication__";
+
+/// Where a subscriber pulls committed changes from
+pub trait WalSource: Send + Sync {
+    /// Up to `max` changes with an LSN of at least `from_lsn`, in LSN
+    /// order. Empty once the subscriber has caught up.
+    fn fetch(&self, from_lsn: u64, max: usize) -> QubeResult<Vec<WalChange>>;
+}
+
+/// A log on this machine, e.g. from `Wal::tail`
+impl WalSource for Mutex<WalTail> {
+    fn fetch(&self, from_lsn: u64, max: usize) -> QubeResult<Vec<WalChange>> {
+        let mut tail = self.lock().unwrap();
+        tail.seek(from_lsn)?;
+        tail.by_ref().take(max).collect()
+    }
+}
+
+/// A primary's log, fetched from the `GET /wal` endpoint of its REST API
+#[derive(Debug, Clone)]
+pub struct HttpWalSource {
+    addr: String,
+    timeout: Duration,
+}
+
+impl HttpWalSource {
+    /// Fetch from the API server listening at `addr`, e.g.
+    /// `"10.0.0.5:8080"`
+    pub fn new(addr: impl Into<String>) -> Self {
+        HttpWalSource {
+            addr: addr.into(),
+            timeout: DEFAULT_HTTP_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Send a GET request for `path` and return its JSON body
+    fn get(&self, path: &str) -> QubeResult<JsonValue> {
+        let unreachable =
+            |e: std::io::Error| QubeError::Network(format!("Failed to reach {}: {}", self.addr, e));
+        let addr = self
+            .addr
+            .to_socket_addrs()
+            .map_err(unreachable)?
+            .next()
+            .ok_or_else(|| QubeError::Network(format!("No address for {}", self.addr)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout).map_err(unreachable)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(unreachable)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(unreachable)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+            path, self.addr
+        )
+        .map_err(unreachable)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map_err(unreachable)?;
+
+        let malformed = || QubeError::Network(format!("Malformed response from {}", self.addr));
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(malformed)?;
+        let head = String::from_utf8_lossy(&response[..split]).to_lowercase();
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(malformed)?;
+        let mut body = response[split + 4..].to_vec();
+        if head.contains("transfer-encoding: chunked") {
+            body = dechunk(&body).ok_or_else(malformed)?;
+        }
+        let json: JsonValue = serde_json::from_slice(&body).map_err(|_| malformed())?;
+        if status != 200 {
+            return Err(QubeError::Network(format!(
+                "{} answered {}: {}",
+                self.addr,
+                status,
+                json["error"].as_str().unwrap_or("unknown error")
+            )));
+        }
+        Ok(json)
+    }
+}
+
+impl WalSource for HttpWalSource {
+    fn fetch(&self, from_lsn: u64, max: usize) -> QubeResult<Vec<WalChange>> {
+        let body = self.get(&format!("/wal?from={}&limit={}", from_lsn, max))?;
+        let malformed = || QubeError::Network(format!("Malformed WAL page from {}", self.addr));
+        body["changes"]
+            .as_array()
+            .ok_or_else(malformed)?
+            .iter()
+            .map(|change| {
+                let lsn = change["lsn"].as_u64().ok_or_else(malformed)?;
+                let sql = change["sql"].as_str().ok_or_else(malformed)?;
+                Ok(WalChange {
+                    lsn,
+                    statements: crate::query::parse_script(sql)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Decode a `Transfer-Encoding: chunked` body
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Settings for a `Subscriber`
+#[derive(Debug, Clone)]
+pub struct SubscriberConfig {
+    /// Most changes fetched at once
+    pub batch_size: usize,
+    /// Wait between fetches once caught up
+    pub poll_interval: Duration,
+    /// Backoff between attempts while the source can't be reached
+    pub retry: RetryPolicy,
+}
+
+impl Default for SubscriberConfig {
+    fn default() -> Self {
+        SubscriberConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Applies a primary's changes to a replica
+pub struct Subscriber {
+    name: String,
+    source: Arc<dyn WalSource>,
+    config: SubscriberConfig,
+}
+
+impl Subscriber {
+    /// A subscriber whose position is kept under `name`, so several can
+    /// feed one replica from different primaries
+    pub fn new(
+        name: impl Into<String>,
+        source: Arc<dyn WalSource>,
+        config: SubscriberConfig,
+    ) -> Self {
+        Subscriber {
+            name: name.into(),
+            source,
+            config,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &SubscriberConfig {
+        &self.config
+    }
+
+    /// LSN of the next change to apply to `db`, 0 before the first
+    pub fn position(&self, db: &EmbeddedQubeDB) -> QubeResult<u64> {
+        Ok(self.stored_position(db)?.unwrap_or(0))
+    }
+
+    /// Apply the changes available now, up to one batch. Returns how many
+    /// were applied; 0 means the replica has caught up.
+    pub fn sync(&self, db: &EmbeddedQubeDB) -> QubeResult<usize> {
+        self.ensure_position_row(db)?;
+        let position = self.position(db)?;
+        let changes = self.source.fetch(position, self.config.batch_size.max(1))?;
+        for change in &changes {
+            let mut script: Vec<String> = change.statements.iter().map(|s| s.to_string()).collect();
+            script.push(format!(
+                "UPDATE {} SET lsn = {} WHERE name = {}",
+                POSITION_TABLE,
+                change.lsn + 1,
+                self.name_literal()
+            ));
+            db.execute_script(&script.join(";\n")).map_err(|e| {
+                QubeError::Storage(format!("Failed to apply WAL record {}: {}", change.lsn, e))
+            })?;
+        }
+        Ok(changes.len())
+    }
+
+    /// Keep applying changes until `stop` is set. While the source can't
+    /// be reached attempts back off as `config.retry` says; a change that
+    /// fails to apply, like any other error, stops the subscriber, since
+    /// every later change depends on it.
+    pub fn run(&self, db: &EmbeddedQubeDB, stop: &AtomicBool) -> QubeResult<()> {
+        let mut failures = 0;
+        while !stop.load(Ordering::Relaxed) {
+            match self.sync(db) {
+                Ok(0) => {
+                    failures = 0;
+                    std::thread::sleep(self.config.poll_interval);
+                }
+                Ok(_) => failures = 0,
+                Err(e @ (QubeError::Network(_) | QubeError::Io(_))) => {
+                    failures += 1;
+                    tracing::warn!(
+                        "Failed to fetch changes for subscriber {}: {}",
+                        self.name,
+                        e
+                    );
+                    let seed = replication::now_nanos() ^ u64::from(failures);
+                    std::thread::sleep(self.config.retry.backoff(failures, seed));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The position saved in `db`, if there is one
+    fn stored_position(&self, db: &EmbeddedQubeDB) -> QubeResult<Option<u64>> {
+        if !db.list_tables().iter().any(|table| table == POSITION_TABLE) {
+            return Ok(None);
+        }
+        let sql = format!(
+            "SELECT lsn FROM {} WHERE name = {}",
+            POSITION_TABLE,
+            self.name_literal()
+        );
+        Ok(db.execute_script(&sql)?[0]
+            .rows
+            .first()
+            .and_then(|row| row["lsn"].as_i64())
+            .map(|lsn| lsn as u64))
+    }
+
+    fn name_literal(&self) -> String {
+        expr::to_literal(&Value::String(self.name.clone())).to_string()
+    }
+
+    /// Create the position table and this subscriber's row in it, if
+    /// missing
+    fn ensure_position_row(&self, db: &EmbeddedQubeDB) -> QubeResult<()> {
+        if !db.list_tables().iter().any(|table| table == POSITION_TABLE) {
+            db.execute_script(&format!(
+                "CREATE TABLE {} (name TEXT PRIMARY KEY, lsn BIGINT NOT NULL)",
+                POSITION_TABLE
+            ))?;
+        }
+        if self.stored_position(db)?.is_none() {
+            db.execute_script(&format!(
+                "INSERT INTO {} (name, lsn) VALUES ({}, 0)",
+                POSITION_TABLE,
+                self.name_literal()
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscriber")
+            .field("name", &self.name)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryEngine;
+    use crate::wal::WalConfig;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-subscriber-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// A primary logging to a WAL, and a subscriber tailing that log
+    fn primary(name: &str, batch_size: usize) -> (QueryEngine, Subscriber) {
+        let engine =
+            QueryEngine::open(temp_path(&format!("{}.wal", name)), WalConfig::default()).unwrap();
+        let tail = engine.wal().unwrap().tail(0).unwrap();
+        let config = SubscriberConfig {
+            batch_size,
+            ..SubscriberConfig::default()
+        };
+        let subscriber = Subscriber::new("primary", Arc::new(Mutex::new(tail)), config);
+        (engine, subscriber)
+    }
+
+    fn contents(db: &EmbeddedQubeDB) -> Vec<(Value, Value)> {
+        db.execute_script("SELECT id, name FROM users ORDER BY id")
+            .unwrap()[0]
+            .rows
+            .iter()
+            .map(|row| (row["id"].clone(), row["name"].clone()))
+            .collect()
+    }
+
+    fn last_lsn(engine: &QueryEngine) -> u64 {
+        engine.wal().unwrap().metrics().durable_lsn
+    }
+
+    #[test]
+    fn changes_are_applied_in_log_order_in_batches() {
+        let (primary, subscriber) = primary("order", 2);
+        primary
+            .execute_script("CREATE TABLE users (id INT PRIMARY KEY, name TEXT)")
+            .unwrap();
+        for sql in [
+            "INSERT INTO users VALUES (1, 'ann')",
+            "INSERT INTO users VALUES (2, 'bob')",
+            "UPDATE users SET name = 'anne' WHERE id = 1",
+            "DELETE FROM users WHERE id = 2",
+            "INSERT INTO users VALUES (2, 'cat')",
+        ] {
+            primary.execute_script(sql).unwrap();
+        }
+
+        let replica = EmbeddedQubeDB::open(temp_path("order-replica")).unwrap();
+        let mut applied = Vec::new();
+        loop {
+            match subscriber.sync(&replica).unwrap() {
+                0 => break,
+                n => applied.push(n),
+            }
+        }
+        assert_eq!(applied, [2, 2, 2]);
+        assert_eq!(
+            subscriber.position(&replica).unwrap(),
+            last_lsn(&primary) + 1
+        );
+        // Applied out of order, the UPDATE or DELETE would have missed
+        assert_eq!(
+            contents(&replica),
+            [
+                (Value::Int32(1), Value::String("anne".to_string())),
+                (Value::Int32(2), Value::String("cat".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_reopened_replica_resumes_where_it_stopped() {
+        let (primary, subscriber) = primary("resume", DEFAULT_BATCH_SIZE);
+        let replica_path = temp_path("resume-replica");
+        primary
+            .execute_script(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO users VALUES (1, 'ann')",
+            )
+            .unwrap();
+        let position = {
+            let replica = EmbeddedQubeDB::open(&replica_path).unwrap();
+            assert_eq!(subscriber.position(&replica).unwrap(), 0);
+            assert_eq!(subscriber.sync(&replica).unwrap(), 1);
+            subscriber.position(&replica).unwrap()
+        };
+        assert_eq!(position, last_lsn(&primary) + 1);
+
+        primary
+            .execute_script("INSERT INTO users VALUES (2, 'bob')")
+            .unwrap();
+        let replica = EmbeddedQubeDB::open(&replica_path).unwrap();
+        assert_eq!(subscriber.position(&replica).unwrap(), position);
+        // Only the new change is applied; replaying the first insert would
+        // fail on its primary key
+        assert_eq!(subscriber.sync(&replica).unwrap(), 1);
+        assert_eq!(subscriber.sync(&replica).unwrap(), 0);
+        assert_eq!(contents(&replica).len(), 2);
+        assert_eq!(
+            subscriber.position(&replica).unwrap(),
+            last_lsn(&primary) + 1
+        );
+    }
+
+    #[test]
+    fn a_change_that_fails_to_apply_leaves_the_position() {
+        let (primary, subscriber) = primary("failure", DEFAULT_BATCH_SIZE);
+        primary
+            .execute_script(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO users VALUES (1, 'ann')",
+            )
+            .unwrap();
+        let replica = EmbeddedQubeDB::open(temp_path("failure-replica")).unwrap();
+        replica
+            .execute_script(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);
+                 INSERT INTO users VALUES (1, 'zed')",
+            )
+            .unwrap();
+
+        let err = subscriber.sync(&replica).unwrap_err();
+        assert!(matches!(err, QubeError::Storage(_)), "{}", err);
+        assert_eq!(subscriber.position(&replica).unwrap(), 0);
+        assert_eq!(
+            contents(&replica),
+            [(Value::Int32(1), Value::String("zed".to_string()))]
+        );
+    }
+}
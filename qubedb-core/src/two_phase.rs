@@ -0,0 +1,606 @@
+//! Two-phase commit for QubeDB
+//!
+//! A `TwoPhaseCoordinator` makes a write spanning several participants,
+//! typically shards, all-or-nothing. In the first phase every participant
+//! is asked to prepare its part: check it would succeed and promise to
+//! apply it later. Only if all of them vote yes within the prepare timeout
+//! is the decision to commit written to the coordinator's log, and then
+//! each participant told to commit; otherwise all are told to abort.
+//!
+//! A participant that prepared but never heard the decision, because it or
+//! the coordinator restarted or a message was lost, holds the transaction
+//! in doubt. `TwoPhaseCoordinator::recover` settles those: a transaction
+//! whose commit was logged is committed, any other aborted, since the
+//! coordinator never logs a commit before every vote is in.
+
+use crate::error::{QubeError, QubeResult};
+use crate::query::{self, QueryEngine};
+use crate::types::QueryResult;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::Statement;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the coordinator waits for every vote before aborting
+pub const DEFAULT_PREPARE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Takes part in transactions run by a `TwoPhaseCoordinator`
+pub trait Participant: Send + Sync {
+    /// Check that `statements` would succeed as one unit and hold them
+    /// until told to commit or abort. Returning their results is a yes
+    /// vote, an error a no.
+    fn prepare(&self, id: &str, statements: &[Statement]) -> QubeResult<Vec<QueryResult>>;
+    /// Apply a prepared transaction; a transaction not held is taken to be
+    /// committed already
+    fn commit(&self, id: &str) -> QubeResult<()>;
+    /// Discard a prepared transaction, if held
+    fn abort(&self, id: &str) -> QubeResult<()>;
+    /// Transactions prepared but neither committed nor aborted
+    fn in_doubt(&self) -> QubeResult<Vec<String>>;
+}
+
+/// Settings for a `TwoPhaseCoordinator`
+#[derive(Debug, Clone)]
+pub struct TwoPhaseConfig {
+    /// A participant that hasn't voted by then counts as voting no
+    pub prepare_timeout: Duration,
+}
+
+impl Default for TwoPhaseConfig {
+    fn default() -> Self {
+        TwoPhaseConfig {
+            prepare_timeout: DEFAULT_PREPARE_TIMEOUT,
+        }
+    }
+}
+
+/// Transactions `TwoPhaseCoordinator::recover` settled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    pub committed: Vec<String>,
+    pub aborted: Vec<String>,
+}
+
+/// Decision the coordinator logs before telling participants to commit
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitRecord {
+    id: String,
+    participants: Vec<usize>,
+}
+
+/// Runs transactions across participants with two-phase commit
+pub struct TwoPhaseCoordinator {
+    participants: Vec<Arc<dyn Participant>>,
+    config: TwoPhaseConfig,
+    path: PathBuf,
+    log: Mutex<File>,
+    /// Transactions whose commit was logged
+    committed: Mutex<HashSet<String>>,
+    /// Transactions still in their first phase, which recovery must leave
+    /// alone
+    active: Mutex<HashSet<String>>,
+    next_id: AtomicU64,
+}
+
+impl TwoPhaseCoordinator {
+    /// Coordinate `participants`, logging commit decisions to `path`.
+    /// Decisions already there are kept, for `recover`.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        participants: Vec<Arc<dyn Participant>>,
+        config: TwoPhaseConfig,
+    ) -> QubeResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let committed = match read_lines::<CommitRecord>(&path) {
+            Ok(records) => records.into_iter().map(|record| record.id).collect(),
+            Err(QubeError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(TwoPhaseCoordinator {
+            participants,
+            config,
+            path,
+            log: Mutex::new(log),
+            committed: Mutex::new(committed),
+            active: Mutex::new(HashSet::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn participant_count(&self) -> usize {
+        self.participants.len()
+    }
+
+    /// Run each list of statements on its participant, given by index, as
+    /// one transaction: either all of them take effect or none does.
+    /// Returns each list's results, or a `Transaction` error if the
+    /// transaction aborted.
+    ///
+    /// Once the commit is logged the transaction counts as committed even
+    /// if a participant fails to apply it; that participant keeps it in
+    /// doubt until `recover` commits it.
+    pub fn execute(&self, work: Vec<(usize, Vec<Statement>)>) -> QubeResult<Vec<Vec<QueryResult>>> {
+        let mut seen = HashSet::new();
+        for (participant, _) in &work {
+            if *participant >= self.participants.len() || !seen.insert(*participant) {
+                return Err(QubeError::Config(format!(
+                    "participant {} is unknown or listed twice",
+                    participant
+                )));
+            }
+        }
+
+        let id = format!(
+            "tx-{:x}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        );
+        self.active.lock().unwrap().insert(id.clone());
+        let result = self.run(&id, &work);
+        self.active.lock().unwrap().remove(&id);
+        result
+    }
+
+    fn run(&self, id: &str, work: &[(usize, Vec<Statement>)]) -> QubeResult<Vec<Vec<QueryResult>>> {
+        let participants: Vec<usize> = work.iter().map(|(participant, _)| *participant).collect();
+
+        // Phase one: collect every vote, or give up at the deadline
+        let (sender, votes) = mpsc::channel();
+        for (i, (participant, statements)) in work.iter().enumerate() {
+            let participant = Arc::clone(&self.participants[*participant]);
+            let statements = statements.clone();
+            let id = id.to_string();
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let _ = sender.send((i, participant.prepare(&id, &statements)));
+            });
+        }
+        drop(sender);
+
+        let deadline = Instant::now() + self.config.prepare_timeout;
+        let mut results = vec![None; work.len()];
+        let mut refusal = None;
+        for _ in 0..work.len() {
+            match votes.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((i, Ok(result))) => results[i] = Some(result),
+                Ok((i, Err(e))) => {
+                    refusal = Some(format!("participant {} voted no: {}", participants[i], e));
+                    break;
+                }
+                Err(_) => {
+                    refusal = Some(format!(
+                        "not every participant voted within {:?}",
+                        self.config.prepare_timeout
+                    ));
+                    break;
+                }
+            }
+        }
+        if refusal.is_none() {
+            if let Err(e) = self.log_commit(id, &participants) {
+                refusal = Some(format!("could not log the commit: {}", e));
+            }
+        }
+        if let Some(reason) = refusal {
+            for &participant in &participants {
+                if let Err(e) = self.participants[participant].abort(id) {
+                    tracing::warn!(
+                        "Failed to abort {} on participant {}: {}",
+                        id,
+                        participant,
+                        e
+                    );
+                }
+            }
+            return Err(QubeError::Transaction(format!(
+                "transaction {} aborted: {}",
+                id, reason
+            )));
+        }
+
+        // Phase two: the decision is durable, so failures only delay it
+        for &participant in &participants {
+            if let Err(e) = self.participants[participant].commit(id) {
+                tracing::warn!(
+                    "Participant {} failed to commit {}, leaving it in doubt: {}",
+                    participant,
+                    id,
+                    e
+                );
+            }
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn log_commit(&self, id: &str, participants: &[usize]) -> QubeResult<()> {
+        let record = CommitRecord {
+            id: id.to_string(),
+            participants: participants.to_vec(),
+        };
+        let mut line =
+            serde_json::to_vec(&record).map_err(|e| QubeError::Serialization(e.to_string()))?;
+        line.push(b'\n');
+        let mut log = self.log.lock().unwrap();
+        log.write_all(&line)?;
+        log.sync_data()?;
+        self.committed.lock().unwrap().insert(id.to_string());
+        Ok(())
+    }
+
+    /// Settle the transactions participants hold in doubt: commit those
+    /// whose commit was logged and abort the rest. Transactions this
+    /// coordinator is still preparing are left alone. Run it after a
+    /// restart, or periodically to finish commits a participant missed.
+    pub fn recover(&self) -> QubeResult<Recovery> {
+        let mut recovery = Recovery::default();
+        for participant in &self.participants {
+            for id in participant.in_doubt()? {
+                if self.active.lock().unwrap().contains(&id) {
+                    continue;
+                }
+                if self.committed.lock().unwrap().contains(&id) {
+                    participant.commit(&id)?;
+                    recovery.committed.push(id);
+                } else {
+                    participant.abort(&id)?;
+                    recovery.aborted.push(id);
+                }
+            }
+        }
+        Ok(recovery)
+    }
+}
+
+impl std::fmt::Debug for TwoPhaseCoordinator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TwoPhaseCoordinator")
+            .field("participants", &self.participants.len())
+            .field("config", &self.config)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// What a `LocalParticipant` logs about a transaction
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum ParticipantRecord {
+    Prepared { id: String, statements: Vec<String> },
+    Committed { id: String },
+    Aborted { id: String },
+}
+
+/// A `QueryEngine` taking part in two-phase commit.
+///
+/// Preparing runs the statements on a copy of the engine's tables and
+/// holds them; committing runs them on the engine itself. The participant
+/// holds one prepared transaction at a time and votes no on any other, as
+/// it would on a lock conflict. Writes made to the engine directly while a
+/// transaction is prepared can still make its commit fail.
+///
+/// Opened with a log file, prepared transactions survive a restart in
+/// doubt, until the coordinator's `recover` settles them.
+pub struct LocalParticipant {
+    engine: Arc<QueryEngine>,
+    log: Option<Mutex<File>>,
+    prepared: Mutex<BTreeMap<String, Vec<Statement>>>,
+}
+
+impl LocalParticipant {
+    /// A participant that forgets prepared transactions when dropped
+    pub fn new(engine: Arc<QueryEngine>) -> Self {
+        LocalParticipant {
+            engine,
+            log: None,
+            prepared: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// A participant logging to `path`, holding in doubt the transactions
+    /// prepared there but never settled
+    pub fn open<P: AsRef<Path>>(engine: Arc<QueryEngine>, path: P) -> QubeResult<Self> {
+        let path = path.as_ref();
+        let records = match read_lines::<ParticipantRecord>(path) {
+            Ok(records) => records,
+            Err(QubeError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut prepared = BTreeMap::new();
+        for record in records {
+            match record {
+                ParticipantRecord::Prepared { id, statements } => {
+                    let statements = query::parse_script(&statements.join(";\n"))?;
+                    prepared.insert(id, statements);
+                }
+                ParticipantRecord::Committed { id } | ParticipantRecord::Aborted { id } => {
+                    prepared.remove(&id);
+                }
+            }
+        }
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LocalParticipant {
+            engine,
+            log: Some(Mutex::new(log)),
+            prepared: Mutex::new(prepared),
+        })
+    }
+
+    pub fn engine(&self) -> &Arc<QueryEngine> {
+        &self.engine
+    }
+
+    fn log(&self, record: &ParticipantRecord) -> QubeResult<()> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let mut line =
+            serde_json::to_vec(record).map_err(|e| QubeError::Serialization(e.to_string()))?;
+        line.push(b'\n');
+        let mut log = log.lock().unwrap();
+        log.write_all(&line)?;
+        log.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Participant for LocalParticipant {
+    fn prepare(&self, id: &str, statements: &[Statement]) -> QubeResult<Vec<QueryResult>> {
+        let mut prepared = self.prepared.lock().unwrap();
+        if let Some(holder) = prepared.keys().find(|holder| holder.as_str() != id) {
+            return Err(QubeError::Transaction(format!(
+                "transaction {} is already prepared here",
+                holder
+            )));
+        }
+        let results = self.engine.snapshot().execute_atomically(statements)?;
+        self.log(&ParticipantRecord::Prepared {
+            id: id.to_string(),
+            statements: statements.iter().map(|s| s.to_string()).collect(),
+        })?;
+        prepared.insert(id.to_string(), statements.to_vec());
+        Ok(results)
+    }
+
+    fn commit(&self, id: &str) -> QubeResult<()> {
+        let mut prepared = self.prepared.lock().unwrap();
+        let statements = match prepared.get(id) {
+            Some(statements) => statements,
+            None => return Ok(()),
+        };
+        self.engine.execute_atomically(statements)?;
+        self.log(&ParticipantRecord::Committed { id: id.to_string() })?;
+        prepared.remove(id);
+        Ok(())
+    }
+
+    fn abort(&self, id: &str) -> QubeResult<()> {
+        let mut prepared = self.prepared.lock().unwrap();
+        if prepared.contains_key(id) {
+            self.log(&ParticipantRecord::Aborted { id: id.to_string() })?;
+            prepared.remove(id);
+        }
+        Ok(())
+    }
+
+    fn in_doubt(&self) -> QubeResult<Vec<String>> {
+        Ok(self.prepared.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+impl std::fmt::Debug for LocalParticipant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalParticipant")
+            .field("logged", &self.log.is_some())
+            .field("prepared", &self.prepared.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+/// Every line of a file of JSON records
+fn read_lines<T: serde::de::DeserializeOwned>(path: &Path) -> QubeResult<Vec<T>> {
+    let reader = BufReader::new(File::open(path)?);
+    reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(&line?).map_err(|e| {
+                QubeError::Serialization(format!("{} line {}: {}", path.display(), i + 1, e))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shard::{Shard, ShardCoordinator};
+    use crate::types::Value;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("qubedb-2pc-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn engine() -> Arc<QueryEngine> {
+        let engine = Arc::new(QueryEngine::new());
+        run(
+            &engine,
+            "CREATE TABLE orders (id INT PRIMARY KEY, customer TEXT)",
+        );
+        engine
+    }
+
+    fn run(engine: &QueryEngine, sql: &str) -> Vec<QueryResult> {
+        engine
+            .execute_atomically(&query::parse_script(sql).unwrap())
+            .unwrap()
+    }
+
+    fn ids(engine: &QueryEngine) -> Vec<Value> {
+        run(engine, "SELECT id FROM orders ORDER BY id")[0]
+            .rows
+            .iter()
+            .map(|row| row["id"].clone())
+            .collect()
+    }
+
+    /// Two shards of `orders`, sharded by customer and committed with 2PC
+    fn sharded(name: &str) -> (ShardCoordinator, Vec<Arc<QueryEngine>>) {
+        let engines = vec![engine(), engine()];
+        let participants = engines
+            .iter()
+            .map(|engine| {
+                Arc::new(LocalParticipant::new(Arc::clone(engine))) as Arc<dyn Participant>
+            })
+            .collect();
+        let transactions =
+            TwoPhaseCoordinator::open(log_path(name), participants, TwoPhaseConfig::default())
+                .unwrap();
+        let shards = engines
+            .iter()
+            .map(|engine| Arc::clone(engine) as Arc<dyn Shard>)
+            .collect();
+        let mut coordinator = ShardCoordinator::new(shards).unwrap();
+        coordinator.shard_table("orders", "customer");
+        coordinator.enable_two_phase_commit(transactions).unwrap();
+        (coordinator, engines)
+    }
+
+    /// A customer stored on each shard
+    fn customer_on(coordinator: &ShardCoordinator, shard: usize) -> String {
+        (0..)
+            .map(|i| format!("customer{}", i))
+            .find(|customer| coordinator.shard_of(&Value::String(customer.clone())) == shard)
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_across_shards_commit_on_every_shard() {
+        let (coordinator, engines) = sharded("commit");
+        let (first, second) = (customer_on(&coordinator, 0), customer_on(&coordinator, 1));
+        coordinator
+            .execute_atomically(&format!(
+                "INSERT INTO orders (id, customer) VALUES (1, '{}'), (2, '{}')",
+                first, second
+            ))
+            .unwrap();
+
+        assert_eq!(ids(&engines[0]), vec![Value::Int32(1)]);
+        assert_eq!(ids(&engines[1]), vec![Value::Int32(2)]);
+        let recovery = coordinator.two_phase_commit().unwrap().recover().unwrap();
+        assert_eq!(recovery, Recovery::default());
+    }
+
+    #[test]
+    fn a_failed_prepare_leaves_every_shard_unchanged() {
+        let (coordinator, engines) = sharded("abort");
+        let (first, second) = (customer_on(&coordinator, 0), customer_on(&coordinator, 1));
+        coordinator
+            .execute_atomically(&format!(
+                "INSERT INTO orders (id, customer) VALUES (2, '{}')",
+                second
+            ))
+            .unwrap();
+
+        // The second shard already holds id 2, so it votes no
+        let err = coordinator
+            .execute_atomically(&format!(
+                "INSERT INTO orders (id, customer) VALUES (1, '{}'), (2, '{}')",
+                first, second
+            ))
+            .unwrap_err();
+        assert!(matches!(err, QubeError::Transaction(_)), "{}", err);
+
+        assert!(ids(&engines[0]).is_empty());
+        assert_eq!(ids(&engines[1]), vec![Value::Int32(2)]);
+        let transactions = coordinator.two_phase_commit().unwrap();
+        assert_eq!(transactions.recover().unwrap(), Recovery::default());
+    }
+
+    #[test]
+    fn recovery_commits_logged_transactions_and_aborts_the_rest() {
+        let engines = [engine(), engine()];
+        let participant_logs = [log_path("participant0"), log_path("participant1")];
+        let coordinator_log = log_path("coordinator");
+        let statements = |id: i32| {
+            query::parse_script(&format!(
+                "INSERT INTO orders (id, customer) VALUES ({}, 'ann')",
+                id
+            ))
+            .unwrap()
+        };
+
+        // Both participants prepare, then lose the coordinator: the first
+        // transaction's commit was logged, the second's never was
+        {
+            let first =
+                LocalParticipant::open(Arc::clone(&engines[0]), &participant_logs[0]).unwrap();
+            first.prepare("logged", &statements(1)).unwrap();
+            let second =
+                LocalParticipant::open(Arc::clone(&engines[1]), &participant_logs[1]).unwrap();
+            second.prepare("unlogged", &statements(2)).unwrap();
+            let record = CommitRecord {
+                id: "logged".to_string(),
+                participants: vec![0],
+            };
+            let mut line = serde_json::to_string(&record).unwrap();
+            line.push('\n');
+            std::fs::write(&coordinator_log, line).unwrap();
+        }
+        assert!(ids(&engines[0]).is_empty());
+
+        let participants: Vec<Arc<dyn Participant>> = engines
+            .iter()
+            .zip(&participant_logs)
+            .map(|(engine, path)| {
+                Arc::new(LocalParticipant::open(Arc::clone(engine), path).unwrap())
+                    as Arc<dyn Participant>
+            })
+            .collect();
+        for participant in &participants {
+            assert_eq!(participant.in_doubt().unwrap().len(), 1);
+        }
+        let coordinator = TwoPhaseCoordinator::open(
+            &coordinator_log,
+            participants.clone(),
+            TwoPhaseConfig::default(),
+        )
+        .unwrap();
+        let recovery = coordinator.recover().unwrap();
+        assert_eq!(recovery.committed, vec!["logged".to_string()]);
+        assert_eq!(recovery.aborted, vec!["unlogged".to_string()]);
+
+        assert_eq!(ids(&engines[0]), vec![Value::Int32(1)]);
+        assert!(ids(&engines[1]).is_empty());
+        for participant in &participants {
+            assert!(participant.in_doubt().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn a_participant_holding_another_transaction_votes_no() {
+        let participant = LocalParticipant::new(engine());
+        let insert = query::parse_script("INSERT INTO orders VALUES (1, 'ann')").unwrap();
+        participant.prepare("first", &insert).unwrap();
+        let err = participant.prepare("second", &insert).unwrap_err();
+        assert!(matches!(err, QubeError::Transaction(_)), "{}", err);
+
+        participant.abort("first").unwrap();
+        participant.prepare("second", &insert).unwrap();
+        participant.commit("second").unwrap();
+        assert_eq!(ids(participant.engine()), vec![Value::Int32(1)]);
+    }
+}
@@ -1,7 +1,11 @@
 //! Core data types for QubeDB
 
+use crate::crdt::Counter;
+use crate::error::{QubeError, QubeResult};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 /// Supported data types in QubeDB
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -47,6 +51,56 @@ pub enum DataType {
 
     /// Boolean
     Boolean,
+
+    /// Conflict-free replicated counter
+    Counter,
+}
+
+impl DataType {
+    /// Whether this is one of the integer types
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            DataType::Int8
+                | DataType::Int16
+                | DataType::Int32
+                | DataType::Int64
+                | DataType::UInt8
+                | DataType::UInt16
+                | DataType::UInt32
+                | DataType::UInt64
+        )
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataType::Int8 => write!(f, "TINYINT"),
+            DataType::Int16 => write!(f, "SMALLINT"),
+            DataType::Int32 => write!(f, "INT"),
+            DataType::Int64 => write!(f, "BIGINT"),
+            DataType::UInt8 => write!(f, "TINYINT UNSIGNED"),
+            DataType::UInt16 => write!(f, "SMALLINT UNSIGNED"),
+            DataType::UInt32 => write!(f, "INT UNSIGNED"),
+            DataType::UInt64 => write!(f, "BIGINT UNSIGNED"),
+            DataType::Float32 => write!(f, "FLOAT"),
+            DataType::Float64 => write!(f, "DOUBLE"),
+            DataType::String => write!(f, "VARCHAR"),
+            DataType::Text => write!(f, "TEXT"),
+            DataType::Binary => write!(f, "BINARY"),
+            DataType::Blob => write!(f, "BLOB"),
+            DataType::Json => write!(f, "JSON"),
+            DataType::Vector { dimensions } => write!(f, "VECTOR({})", dimensions),
+            DataType::GraphNode => write!(f, "GRAPH_NODE"),
+            DataType::GraphEdge => write!(f, "GRAPH_EDGE"),
+            DataType::Timestamp => write!(f, "TIMESTAMP"),
+            DataType::Date => write!(f, "DATE"),
+            DataType::Time => write!(f, "TIME"),
+            DataType::Boolean => write!(f, "BOOLEAN"),
+            DataType::Counter => write!(f, "COUNTER"),
+        }
+    }
 }
 
 /// Column definition
@@ -55,10 +109,174 @@ pub struct Column {
     pub name: String,
     pub data_type: DataType,
     pub nullable: bool,
-    pub default_value: Option<Value>,
+    pub default_value: Option<DefaultValue>,
     pub primary_key: bool,
     pub unique: bool,
     pub index: bool,
+    /// Filled from the table's sequence when an INSERT leaves it NULL
+    pub auto_increment: bool,
+    /// Schema every document stored in a `Json` column must match
+    #[serde(default)]
+    pub json_schema: Option<JsonSchema>,
+    /// Expression the column's value is computed from, for a generated
+    /// column
+    #[serde(default)]
+    pub generated: Option<Generated>,
+}
+
+impl Column {
+    /// Whether the column is computed on read rather than stored
+    pub fn is_virtual(&self) -> bool {
+        matches!(self.generated, Some(Generated::Virtual(_)))
+    }
+
+    /// Fail if `value` may not be stored in this column: NULL in a NOT NULL
+    /// column, or a document that does not match the column's JSON schema
+    pub fn check(&self, value: &Value) -> QubeResult<()> {
+        if !self.nullable && value.is_null() {
+            return Err(QubeError::ConstraintViolation(format!(
+                "Column '{}' cannot be null",
+                self.name
+            )));
+        }
+        if let (Some(schema), Value::Json(document)) = (&self.json_schema, value) {
+            schema.check(&self.name, document)?;
+        }
+        Ok(())
+    }
+}
+
+/// A compiled JSON Schema. Serialized as the schema document itself.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "serde_json::Value", into = "serde_json::Value")]
+pub struct JsonSchema {
+    schema: serde_json::Value,
+    validator: Arc<jsonschema::Validator>,
+}
+
+impl JsonSchema {
+    /// Compile a schema, failing if it is not a valid JSON Schema
+    pub fn new(schema: serde_json::Value) -> QubeResult<Self> {
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| QubeError::QueryParse(format!("Invalid JSON schema: {}", e)))?;
+        Ok(JsonSchema {
+            schema,
+            validator: Arc::new(validator),
+        })
+    }
+
+    pub fn schema(&self) -> &serde_json::Value {
+        &self.schema
+    }
+
+    pub fn is_valid(&self, document: &serde_json::Value) -> bool {
+        self.validator.is_valid(document)
+    }
+
+    /// Fail with every way `document`, stored in `column`, breaks the schema
+    pub fn check(&self, column: &str, document: &serde_json::Value) -> QubeResult<()> {
+        let violations: Vec<String> = self
+            .validator
+            .iter_errors(document)
+            .map(|e| match e.instance_path.to_string() {
+                path if path.is_empty() => e.to_string(),
+                path => format!("{}: {}", path, e),
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(QubeError::ConstraintViolation(format!(
+            "Value of column '{}' does not match its JSON schema: {}",
+            column,
+            violations.join("; ")
+        )))
+    }
+}
+
+impl TryFrom<serde_json::Value> for JsonSchema {
+    type Error = QubeError;
+
+    fn try_from(schema: serde_json::Value) -> QubeResult<Self> {
+        JsonSchema::new(schema)
+    }
+}
+
+impl From<JsonSchema> for serde_json::Value {
+    fn from(schema: JsonSchema) -> Self {
+        schema.schema
+    }
+}
+
+impl fmt::Debug for JsonSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("JsonSchema").field(&self.schema).finish()
+    }
+}
+
+/// How a generated column computes its value from the other columns of its
+/// row. The expression is SQL, e.g. `CONCAT(first, ' ', last)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Generated {
+    /// Computed whenever the row is written and stored with it, so it can
+    /// be indexed
+    Stored(String),
+    /// Computed whenever the row is read and never stored
+    Virtual(String),
+}
+
+impl Generated {
+    pub fn expression(&self) -> &str {
+        match self {
+            Generated::Stored(expression) | Generated::Virtual(expression) => expression,
+        }
+    }
+}
+
+impl fmt::Display for Generated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Generated::Stored(expression) => write!(f, "AS ({}) STORED", expression),
+            Generated::Virtual(expression) => write!(f, "AS ({}) VIRTUAL", expression),
+        }
+    }
+}
+
+/// Value used for a column that an INSERT omits or sets to DEFAULT
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DefaultValue {
+    /// A constant
+    Value(Value),
+    /// The time of the insert (`CURRENT_TIMESTAMP`, `NOW()`)
+    CurrentTimestamp,
+    /// The date of the insert, as `YYYY-MM-DD`
+    CurrentDate,
+    /// The time of day of the insert, as `HH:MM:SS`
+    CurrentTime,
+}
+
+impl DefaultValue {
+    /// Produce the value to store, evaluating time-based defaults now
+    pub fn evaluate(&self) -> Value {
+        let now = chrono::Utc::now();
+        match self {
+            DefaultValue::Value(value) => value.clone(),
+            DefaultValue::CurrentTimestamp => Value::Timestamp(now.timestamp_millis()),
+            DefaultValue::CurrentDate => Value::String(now.format("%Y-%m-%d").to_string()),
+            DefaultValue::CurrentTime => Value::String(now.format("%H:%M:%S").to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DefaultValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefaultValue::Value(value) => write!(f, "{}", value),
+            DefaultValue::CurrentTimestamp => write!(f, "CURRENT_TIMESTAMP"),
+            DefaultValue::CurrentDate => write!(f, "CURRENT_DATE"),
+            DefaultValue::CurrentTime => write!(f, "CURRENT_TIME"),
+        }
+    }
 }
 
 /// Table definition
@@ -68,6 +286,269 @@ pub struct Table {
     pub columns: Vec<Column>,
     pub indexes: Vec<Index>,
     pub constraints: Vec<Constraint>,
+    #[serde(default)]
+    pub storage: StorageLayout,
+}
+
+impl Table {
+    /// Assemble a table definition, adding the constraints and implicit
+    /// unique indexes that back its primary key and unique keys
+    pub fn with_keys(
+        name: String,
+        mut columns: Vec<Column>,
+        mut constraints: Vec<Constraint>,
+        primary_key: Vec<String>,
+        unique_keys: Vec<Vec<String>>,
+    ) -> Table {
+        // Primary key and unique constraints are backed by implicit indexes
+        let mut indexes = Vec::new();
+        if !primary_key.is_empty() {
+            for column in columns.iter_mut() {
+                if primary_key.contains(&column.name) {
+                    column.primary_key = true;
+                    column.nullable = false;
+                }
+            }
+            constraints.push(Constraint {
+                name: format!("{}_pkey", name),
+                constraint_type: ConstraintType::PrimaryKey,
+                columns: primary_key.clone(),
+            });
+            indexes.push(Index {
+                name: format!("{}_pkey", name),
+                columns: primary_key,
+                index_type: IndexType::BTree,
+                unique: true,
+            });
+        }
+        for key in unique_keys {
+            if let [column_name] = key.as_slice() {
+                if let Some(column) = columns.iter_mut().find(|c| &c.name == column_name) {
+                    column.unique = true;
+                }
+            }
+            let index_name = format!("{}_{}_key", name, key.join("_"));
+            constraints.push(Constraint {
+                name: index_name.clone(),
+                constraint_type: ConstraintType::Unique,
+                columns: key.clone(),
+            });
+            indexes.push(Index {
+                name: index_name,
+                columns: key,
+                index_type: IndexType::BTree,
+                unique: true,
+            });
+        }
+
+        Table {
+            name,
+            columns,
+            indexes,
+            constraints,
+            storage: StorageLayout::Row,
+        }
+    }
+}
+
+/// How a table lays out its rows in memory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageLayout {
+    /// Each row is stored whole
+    #[default]
+    Row,
+    /// Each column is stored contiguously, see `crate::columnar`
+    Columnar,
+}
+
+impl StorageLayout {
+    /// Parse the value of a `storage` table option
+    pub fn parse(name: &str) -> QubeResult<Self> {
+        match name.to_lowercase().as_str() {
+            "row" => Ok(StorageLayout::Row),
+            "columnar" => Ok(StorageLayout::Columnar),
+            other => Err(QubeError::QueryParse(format!(
+                "Unsupported storage layout: {}",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for StorageLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageLayout::Row => write!(f, "row"),
+            StorageLayout::Columnar => write!(f, "columnar"),
+        }
+    }
+}
+
+/// Fluent builder for table definitions.
+///
+/// Column modifiers such as `primary_key` and `unique` apply to the most
+/// recently added column:
+///
+/// ```ignore
+/// let users = TableBuilder::new("users")
+///     .column("id", DataType::Int32).primary_key()
+///     .column("email", DataType::String).unique().nullable(false)
+///     .build()?;
+/// ```
+pub struct TableBuilder {
+    name: String,
+    columns: Vec<Column>,
+    storage: StorageLayout,
+    error: Option<QubeError>,
+}
+
+impl TableBuilder {
+    /// Start building a table
+    pub fn new(name: impl Into<String>) -> Self {
+        TableBuilder {
+            name: name.into(),
+            columns: Vec::new(),
+            storage: StorageLayout::Row,
+            error: None,
+        }
+    }
+
+    /// Add a nullable column
+    pub fn column(mut self, name: impl Into<String>, data_type: DataType) -> Self {
+        let name = name.into();
+        if self.error.is_none() && self.columns.iter().any(|c| c.name == name) {
+            self.error = Some(QubeError::QueryParse(format!(
+                "Duplicate column name: {}",
+                name
+            )));
+        }
+        self.columns.push(Column {
+            name,
+            data_type,
+            nullable: true,
+            default_value: None,
+            primary_key: false,
+            unique: false,
+            index: false,
+            auto_increment: false,
+            json_schema: None,
+            generated: None,
+        });
+        self
+    }
+
+    /// Make the last column (part of) the primary key
+    pub fn primary_key(self) -> Self {
+        self.modify("primary_key", |column| {
+            column.primary_key = true;
+            column.nullable = false;
+        })
+    }
+
+    /// Require the last column's values to be unique
+    pub fn unique(self) -> Self {
+        self.modify("unique", |column| column.unique = true)
+    }
+
+    /// Set whether the last column accepts NULL
+    pub fn nullable(self, nullable: bool) -> Self {
+        self.modify("nullable", |column| column.nullable = nullable)
+    }
+
+    /// Number the last column from the table's sequence when an insert
+    /// leaves it out
+    pub fn auto_increment(self) -> Self {
+        self.modify("auto_increment", |column| column.auto_increment = true)
+    }
+
+    /// Validate every document stored in the last column, which must be a
+    /// `Json` column, against a JSON Schema
+    pub fn json_schema(mut self, schema: serde_json::Value) -> Self {
+        let schema = match JsonSchema::new(schema) {
+            Ok(schema) => schema,
+            Err(e) => {
+                self.error.get_or_insert(e);
+                return self;
+            }
+        };
+        if let Some(column) = self.columns.last() {
+            if column.data_type != DataType::Json {
+                self.error.get_or_insert_with(|| {
+                    QubeError::QueryParse(format!(
+                        "JSON schema on non-JSON column '{}'",
+                        column.name
+                    ))
+                });
+                return self;
+            }
+        }
+        self.modify("json_schema", |column| column.json_schema = Some(schema))
+    }
+
+    /// Compute the last column from an expression over the other columns
+    pub fn generated(self, generated: Generated) -> Self {
+        self.modify("generated", |column| column.generated = Some(generated))
+    }
+
+    /// Set the last column's default value
+    pub fn default_value(self, value: DefaultValue) -> Self {
+        self.modify("default_value", |column| column.default_value = Some(value))
+    }
+
+    /// Set how the table lays out its rows
+    pub fn storage(mut self, storage: StorageLayout) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Build the table definition
+    pub fn build(self) -> QubeResult<Table> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if self.columns.is_empty() {
+            return Err(QubeError::QueryParse(format!(
+                "Table '{}' has no columns",
+                self.name
+            )));
+        }
+
+        let primary_key = self
+            .columns
+            .iter()
+            .filter(|c| c.primary_key)
+            .map(|c| c.name.clone())
+            .collect();
+        let unique_keys = self
+            .columns
+            .iter()
+            .filter(|c| c.unique)
+            .map(|c| vec![c.name.clone()])
+            .collect();
+        let table = Table::with_keys(
+            self.name,
+            self.columns,
+            Vec::new(),
+            primary_key,
+            unique_keys,
+        );
+        Ok(Table {
+            storage: self.storage,
+            ..table
+        })
+    }
+
+    fn modify(mut self, modifier: &str, apply: impl FnOnce(&mut Column)) -> Self {
+        match self.columns.last_mut() {
+            Some(column) => apply(column),
+            None => {
+                self.error.get_or_insert_with(|| {
+                    QubeError::QueryParse(format!("{}() called before any column()", modifier))
+                });
+            }
+        }
+        self
+    }
 }
 
 /// Index definition
@@ -89,6 +570,18 @@ pub enum IndexType {
     Spatial,
 }
 
+impl fmt::Display for IndexType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexType::BTree => write!(f, "BTREE"),
+            IndexType::Hash => write!(f, "HASH"),
+            IndexType::Vector => write!(f, "VECTOR"),
+            IndexType::FullText => write!(f, "FULLTEXT"),
+            IndexType::Spatial => write!(f, "SPATIAL"),
+        }
+    }
+}
+
 /// Constraint definition
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Constraint {
@@ -131,21 +624,51 @@ pub enum Value {
     Vector(Vec<f32>),
     Boolean(bool),
     Timestamp(i64),
+    Counter(Counter),
 }
 
 /// Row in a table
 pub type Row = HashMap<String, Value>;
 
+/// What a query result carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResultKind {
+    /// A result set (SELECT, SHOW, ...); see `rows`
+    Rows,
+    /// A statement that changes data or schema; see `affected_rows`
+    Mutation,
+}
+
 /// Query result
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueryResult {
+    pub kind: ResultKind,
     pub columns: Vec<String>,
     pub rows: Vec<Row>,
+    /// Rows inserted, updated or deleted; always 0 for result sets
     pub affected_rows: usize,
+    /// Auto-increment value generated for the last row an INSERT added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_insert_id: Option<i64>,
     #[serde(skip)]
     pub execution_time: std::time::Duration,
 }
 
+impl QueryResult {
+    /// Whether this result carries rows rather than a mutation count
+    pub fn is_result_set(&self) -> bool {
+        self.kind == ResultKind::Rows
+    }
+
+    /// Rows returned for a result set, or rows affected for a mutation
+    pub fn row_count(&self) -> usize {
+        match self.kind {
+            ResultKind::Rows => self.rows.len(),
+            ResultKind::Mutation => self.affected_rows,
+        }
+    }
+}
+
 // Manual implementations for Value to handle float types
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
@@ -174,6 +697,7 @@ impl PartialEq for Value {
             }
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Timestamp(a), Value::Timestamp(b)) => a == b,
+            (Value::Counter(a), Value::Counter(b)) => a == b,
             _ => false,
         }
     }
@@ -205,6 +729,283 @@ impl std::hash::Hash for Value {
             }
             Value::Boolean(v) => v.hash(state),
             Value::Timestamp(v) => v.hash(state),
+            Value::Counter(v) => v.hash(state),
         }
     }
 }
+
+impl Value {
+    /// Whether this is SQL NULL
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// The column type this value is stored as; none for NULL
+    pub fn data_type(&self) -> Option<DataType> {
+        let data_type = match self {
+            Value::Null => return None,
+            Value::Int8(_) => DataType::Int8,
+            Value::Int16(_) => DataType::Int16,
+            Value::Int32(_) => DataType::Int32,
+            Value::Int64(_) => DataType::Int64,
+            Value::UInt8(_) => DataType::UInt8,
+            Value::UInt16(_) => DataType::UInt16,
+            Value::UInt32(_) => DataType::UInt32,
+            Value::UInt64(_) => DataType::UInt64,
+            Value::Float32(_) => DataType::Float32,
+            Value::Float64(_) => DataType::Float64,
+            Value::String(_) => DataType::Text,
+            Value::Binary(_) => DataType::Binary,
+            Value::Json(_) => DataType::Json,
+            Value::Vector(v) => DataType::Vector {
+                dimensions: v.len(),
+            },
+            Value::Boolean(_) => DataType::Boolean,
+            Value::Timestamp(_) => DataType::Timestamp,
+            Value::Counter(_) => DataType::Counter,
+        };
+        Some(data_type)
+    }
+
+    /// Integer view of an integer-typed value
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int8(v) => Some(*v as i64),
+            Value::Int16(v) => Some(*v as i64),
+            Value::Int32(v) => Some(*v as i64),
+            Value::Int64(v) => Some(*v),
+            Value::UInt8(v) => Some(*v as i64),
+            Value::UInt16(v) => Some(*v as i64),
+            Value::UInt32(v) => Some(*v as i64),
+            Value::UInt64(v) => i64::try_from(*v).ok(),
+            Value::Timestamp(v) => Some(*v),
+            Value::Counter(v) => Some(v.value()),
+            _ => None,
+        }
+    }
+
+    /// Floating point view of a numeric value
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float32(v) => Some(*v as f64),
+            Value::Float64(v) => Some(*v),
+            Value::UInt64(v) => Some(*v as f64),
+            other => other.as_i64().map(|v| v as f64),
+        }
+    }
+
+    /// Whether this is an integer or floating point value
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Value::Int8(_)
+                | Value::Int16(_)
+                | Value::Int32(_)
+                | Value::Int64(_)
+                | Value::UInt8(_)
+                | Value::UInt16(_)
+                | Value::UInt32(_)
+                | Value::UInt64(_)
+                | Value::Float32(_)
+                | Value::Float64(_)
+                | Value::Counter(_)
+        )
+    }
+
+    /// SQL comparison between two values.
+    ///
+    /// Numeric values compare across widths, and `None` is returned when
+    /// either side is NULL or the types are not comparable.
+    pub fn compare(&self, other: &Value) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+            (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.cmp(b)),
+            (Value::Binary(a), Value::Binary(b)) => Some(a.cmp(b)),
+            (Value::UInt64(a), Value::UInt64(b)) => Some(a.cmp(b)),
+            // Timestamps are epoch-based and compare like integers
+            (a, b)
+                if (a.is_numeric() || matches!(a, Value::Timestamp(_)))
+                    && (b.is_numeric() || matches!(b, Value::Timestamp(_))) =>
+            {
+                match (a.as_i64(), b.as_i64()) {
+                    (Some(a), Some(b)) => Some(a.cmp(&b)),
+                    _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Int8(v) => write!(f, "{}", v),
+            Value::Int16(v) => write!(f, "{}", v),
+            Value::Int32(v) => write!(f, "{}", v),
+            Value::Int64(v) => write!(f, "{}", v),
+            Value::UInt8(v) => write!(f, "{}", v),
+            Value::UInt16(v) => write!(f, "{}", v),
+            Value::UInt32(v) => write!(f, "{}", v),
+            Value::UInt64(v) => write!(f, "{}", v),
+            Value::Float32(v) => write!(f, "{}", v),
+            Value::Float64(v) => write!(f, "{}", v),
+            Value::String(v) => write!(f, "{}", v),
+            Value::Binary(v) => write!(f, "<{} bytes>", v.len()),
+            Value::Json(v) => write!(f, "{}", v),
+            Value::Vector(v) => write!(f, "{:?}", v),
+            Value::Boolean(v) => write!(f, "{}", v),
+            Value::Timestamp(v) => write!(f, "{}", v),
+            Value::Counter(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+macro_rules! value_from {
+    ($($type:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$type> for Value {
+                fn from(v: $type) -> Self {
+                    Value::$variant(v)
+                }
+            }
+        )*
+    };
+}
+
+value_from! {
+    i8 => Int8,
+    i16 => Int16,
+    i32 => Int32,
+    i64 => Int64,
+    u8 => UInt8,
+    u16 => UInt16,
+    u32 => UInt32,
+    u64 => UInt64,
+    f32 => Float32,
+    f64 => Float64,
+    bool => Boolean,
+    String => String,
+    Vec<u8> => Binary,
+    Vec<f32> => Vector,
+    serde_json::Value => Json,
+    Counter => Counter,
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer", "minimum": 0 }
+            },
+            "required": ["name"]
+        })
+    }
+
+    #[test]
+    fn json_columns_check_documents_against_their_schema() {
+        let table = TableBuilder::new("people")
+            .column("body", DataType::Json)
+            .json_schema(person_schema())
+            .nullable(false)
+            .build()
+            .unwrap();
+        let body = &table.columns[0];
+        body.check(&Value::Json(json!({ "name": "ann", "age": 3 })))
+            .unwrap();
+
+        let error = body.check(&Value::Json(json!({ "age": -1 }))).unwrap_err();
+        let message = match error {
+            QubeError::ConstraintViolation(message) => message,
+            other => panic!("unexpected error {:?}", other),
+        };
+        assert!(message.starts_with("Value of column 'body' does not match its JSON schema: "));
+        // Every violation is listed, with where it occurs
+        assert!(message.contains("\"name\" is a required property"));
+        assert!(message.contains("/age: -1 is less than the minimum of 0"));
+
+        assert!(matches!(
+            body.check(&Value::Null),
+            Err(QubeError::ConstraintViolation(_))
+        ));
+    }
+
+    #[test]
+    fn bad_schemas_are_rejected_when_the_table_is_defined() {
+        let invalid = TableBuilder::new("t")
+            .column("body", DataType::Json)
+            .json_schema(json!({ "type": "no-such-type" }))
+            .build();
+        assert!(
+            matches!(invalid, Err(QubeError::QueryParse(m)) if m.starts_with("Invalid JSON schema"))
+        );
+
+        let not_json = TableBuilder::new("t")
+            .column("name", DataType::String)
+            .json_schema(person_schema())
+            .build();
+        let expected = "non-JSON column 'name'";
+        assert!(matches!(not_json, Err(QubeError::QueryParse(m)) if m.contains(expected)));
+    }
+
+    #[test]
+    fn json_schemas_serialize_as_the_schema_document() {
+        let schema = JsonSchema::new(person_schema()).unwrap();
+        let serialized = serde_json::to_value(&schema).unwrap();
+        assert_eq!(serialized, person_schema());
+        let restored: JsonSchema = serde_json::from_value(serialized).unwrap();
+        assert!(restored.is_valid(&json!({ "name": "bo" })));
+        assert!(!restored.is_valid(&json!({ "name": 7 })));
+        assert!(serde_json::from_value::<JsonSchema>(json!({ "type": 5 })).is_err());
+    }
+
+    #[test]
+    fn generated_columns_describe_their_expression() {
+        let table = TableBuilder::new("people")
+            .column("last", DataType::String)
+            .column("shout", DataType::String)
+            .generated(Generated::Virtual("UPPER(last)".to_string()))
+            .build()
+            .unwrap();
+        let generated = table.columns[1].generated.as_ref().unwrap();
+        assert!(table.columns[1].is_virtual());
+        assert!(!table.columns[0].is_virtual());
+        assert_eq!(generated.expression(), "UPPER(last)");
+        assert_eq!(generated.to_string(), "AS (UPPER(last)) VIRTUAL");
+        let stored = Generated::Stored("1".to_string());
+        assert_eq!(stored.to_string(), "AS (1) STORED");
+    }
+
+    #[test]
+    fn rust_values_convert_into_their_variants() {
+        assert_eq!(Value::from(7u16), Value::UInt16(7));
+        assert_eq!(Value::from(-7i64), Value::Int64(-7));
+        assert_eq!(Value::from(0.5f32), Value::Float32(0.5));
+        assert_eq!(Value::from("hi"), Value::String("hi".to_string()));
+        assert_eq!(Value::from(vec![1u8, 2]), Value::Binary(vec![1, 2]));
+        assert_eq!(Value::from(json!({"a": 1})), Value::Json(json!({"a": 1})));
+        assert_eq!(Value::from(Some(true)), Value::Boolean(true));
+        assert_eq!(Value::from(None::<i32>), Value::Null);
+    }
+}
@@ -0,0 +1,834 @@
+//! Semantic checks for SQL statements
+//!
+//! Used by `QueryEngine::validate` to catch mistakes before a statement
+//! runs: tables and columns must exist, functions must be known and get
+//! the right number of arguments, and operands must have types that can
+//! be combined. Expression types are tracked as broad kinds (number, text,
+//! ...) since that is all the evaluator distinguishes at runtime.
+
+use crate::aggregate::AggregateFunction;
+use crate::catalog::Catalog;
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::functions;
+use crate::query::{is_default_keyword, object_name};
+use crate::types::{Column, DataType, Row, Table, Value};
+use crate::window::RankingFunction;
+use sqlparser::ast::{
+    Assignment, BinaryOperator, DateTimeField, Expr, FunctionArg, FunctionArgExpr, Ident,
+    JoinConstraint, JoinOperator, ObjectName, Query, SelectItem, SetExpr, Statement, TableFactor,
+    TableWithJoins, UnaryOperator, Value as SqlValue, WindowType,
+};
+use std::fmt;
+
+/// The kind of value an expression produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// NULL, a placeholder, or a value such as JSON that converts freely
+    Any,
+    Number,
+    Timestamp,
+    Text,
+    Boolean,
+    Binary,
+}
+
+impl Kind {
+    fn of(data_type: &DataType) -> Kind {
+        match data_type {
+            DataType::Timestamp => Kind::Timestamp,
+            DataType::String | DataType::Text | DataType::Date | DataType::Time => Kind::Text,
+            DataType::Binary | DataType::Blob => Kind::Binary,
+            DataType::Boolean => Kind::Boolean,
+            DataType::Json
+            | DataType::Vector { .. }
+            | DataType::GraphNode
+            | DataType::GraphEdge => Kind::Any,
+            _ => Kind::Number,
+        }
+    }
+
+    /// Whether values of the two kinds can be compared. Timestamps compare
+    /// like the integers they are stored as.
+    fn comparable(self, other: Kind) -> bool {
+        use Kind::*;
+        self == other
+            || self == Any
+            || other == Any
+            || matches!((self, other), (Number, Timestamp) | (Timestamp, Number))
+    }
+
+    fn is(self, wanted: Kind) -> bool {
+        self == wanted || self == Kind::Any
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Kind::Any => "any",
+            Kind::Number => "number",
+            Kind::Timestamp => "timestamp",
+            Kind::Text => "text",
+            Kind::Boolean => "boolean",
+            Kind::Binary => "binary",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn mismatch(message: String) -> QubeError {
+    QubeError::QueryParse(format!("Type mismatch: {}", message))
+}
+
+/// Where an expression appears, which decides whether aggregates are
+/// allowed in it
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Clause {
+    Projection,
+    Other,
+}
+
+/// The tables an expression's column references resolve against
+struct Scope<'a> {
+    relations: Vec<(String, &'a Table)>,
+}
+
+impl<'a> Scope<'a> {
+    fn empty() -> Self {
+        Scope {
+            relations: Vec::new(),
+        }
+    }
+
+    fn single(table: &'a Table) -> Self {
+        Scope {
+            relations: vec![(table.name.clone(), table)],
+        }
+    }
+
+    fn resolve(&self, qualifier: Option<&Ident>, name: &Ident) -> QubeResult<&'a Column> {
+        if let Some(qualifier) = qualifier {
+            let (_, table) = self
+                .relations
+                .iter()
+                .find(|(q, _)| *q == qualifier.value)
+                .ok_or_else(|| QubeError::TableNotFound(qualifier.value.clone()))?;
+            return find_column(table, &name.value).ok_or_else(|| {
+                QubeError::ColumnNotFound(format!("{}.{}", qualifier.value, name.value))
+            });
+        }
+
+        let mut matches = self
+            .relations
+            .iter()
+            .filter_map(|(_, table)| find_column(table, &name.value));
+        match (matches.next(), matches.next()) {
+            (Some(column), None) => Ok(column),
+            (Some(_), Some(_)) => Err(QubeError::QueryParse(format!(
+                "Column reference '{}' is ambiguous",
+                name.value
+            ))),
+            (None, _) => Err(QubeError::ColumnNotFound(name.value.clone())),
+        }
+    }
+
+    /// The kind of `expr`, checking everything it references and combines
+    fn kind(&self, expr: &Expr, clause: Clause) -> QubeResult<Kind> {
+        let binary = |left: &Expr, right: &Expr| -> QubeResult<(Kind, Kind)> {
+            Ok((self.kind(left, clause)?, self.kind(right, clause)?))
+        };
+        let compare = |left: &Expr, right: &Expr| -> QubeResult<Kind> {
+            let (l, r) = binary(left, right)?;
+            if !l.comparable(r) {
+                return Err(mismatch(format!(
+                    "cannot compare {} ({}) with {} ({})",
+                    left, l, right, r
+                )));
+            }
+            Ok(Kind::Boolean)
+        };
+
+        match expr {
+            Expr::Identifier(ident) => Ok(Kind::of(&self.resolve(None, ident)?.data_type)),
+            Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                [column] => Ok(Kind::of(&self.resolve(None, column)?.data_type)),
+                [.., table, column] => Ok(Kind::of(&self.resolve(Some(table), column)?.data_type)),
+                [] => Err(QubeError::QueryParse("Empty identifier".to_string())),
+            },
+            Expr::Value(SqlValue::Placeholder(_)) => Ok(Kind::Any),
+            Expr::Value(value) => Ok(match expr::literal(value)? {
+                Value::Null => Kind::Any,
+                Value::String(_) => Kind::Text,
+                Value::Boolean(_) => Kind::Boolean,
+                Value::Binary(_) => Kind::Binary,
+                _ => Kind::Number,
+            }),
+            Expr::Nested(inner) => self.kind(inner, clause),
+            Expr::UnaryOp { op, expr: inner } => {
+                let kind = self.kind(inner, clause)?;
+                match op {
+                    UnaryOperator::Not => Ok(Kind::Boolean),
+                    UnaryOperator::Minus | UnaryOperator::Plus if kind.is(Kind::Number) => {
+                        Ok(Kind::Number)
+                    }
+                    UnaryOperator::Minus | UnaryOperator::Plus => Err(mismatch(format!(
+                        "cannot apply {} to {} ({})",
+                        op, inner, kind
+                    ))),
+                    other => Err(QubeError::Unsupported(format!("unary operator {}", other))),
+                }
+            }
+            Expr::BinaryOp { left, op, right } => match op {
+                BinaryOperator::And | BinaryOperator::Or => {
+                    binary(left, right)?;
+                    Ok(Kind::Boolean)
+                }
+                BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq => compare(left, right),
+                BinaryOperator::StringConcat => {
+                    binary(left, right)?;
+                    Ok(Kind::Text)
+                }
+                BinaryOperator::Plus
+                | BinaryOperator::Minus
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo => {
+                    let (l, r) = binary(left, right)?;
+                    for (side, kind) in [(left, l), (right, r)] {
+                        if !kind.is(Kind::Number) {
+                            return Err(mismatch(format!(
+                                "cannot apply {} to {} ({})",
+                                op, side, kind
+                            )));
+                        }
+                    }
+                    Ok(Kind::Number)
+                }
+                other => Err(QubeError::Unsupported(format!("operator {}", other))),
+            },
+            Expr::IsNull(inner)
+            | Expr::IsNotNull(inner)
+            | Expr::IsTrue(inner)
+            | Expr::IsFalse(inner) => {
+                self.kind(inner, clause)?;
+                Ok(Kind::Boolean)
+            }
+            Expr::Between {
+                expr: value,
+                low,
+                high,
+                ..
+            } => {
+                compare(value, low)?;
+                compare(value, high)
+            }
+            Expr::InList {
+                expr: value, list, ..
+            } => {
+                for item in list {
+                    compare(value, item)?;
+                }
+                Ok(Kind::Boolean)
+            }
+            Expr::Like {
+                expr: value,
+                pattern,
+                ..
+            }
+            | Expr::ILike {
+                expr: value,
+                pattern,
+                ..
+            } => {
+                binary(value, pattern)?;
+                Ok(Kind::Boolean)
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                for condition in conditions {
+                    match operand {
+                        Some(operand) => compare(operand, condition)?,
+                        None => self.kind(condition, clause)?,
+                    };
+                }
+                let mut kind = Kind::Any;
+                for result in results.iter().chain(else_result.as_deref()) {
+                    let next = self.kind(result, clause)?;
+                    if !next.comparable(kind) {
+                        return Err(mismatch(format!(
+                            "CASE branches return both {} and {}",
+                            kind, next
+                        )));
+                    }
+                    if kind == Kind::Any {
+                        kind = next;
+                    }
+                }
+                Ok(kind)
+            }
+            Expr::Function(function) => {
+                let name = function.name.to_string();
+                let mut args = Vec::new();
+                for arg in &function.args {
+                    match arg {
+                        FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => args.push(arg),
+                        FunctionArg::Unnamed(FunctionArgExpr::Wildcard)
+                            if name.eq_ignore_ascii_case("COUNT") => {}
+                        other => {
+                            return Err(QubeError::Unsupported(format!(
+                                "argument {} to {}",
+                                other, name
+                            )))
+                        }
+                    }
+                }
+
+                if let Some(window) = &function.over {
+                    if clause != Clause::Projection {
+                        return Err(QubeError::QueryParse(format!(
+                            "Window function {} is not allowed here",
+                            name
+                        )));
+                    }
+                    let window = match window {
+                        WindowType::WindowSpec(spec) => spec,
+                        WindowType::NamedWindow(window) => {
+                            return Err(QubeError::Unsupported(format!("named window {}", window)))
+                        }
+                    };
+                    for expr in window
+                        .partition_by
+                        .iter()
+                        .chain(window.order_by.iter().map(|order| &order.expr))
+                    {
+                        self.kind(expr, Clause::Other)?;
+                    }
+                    if RankingFunction::from_name(&name).is_some() {
+                        functions::check_arity(&name.to_uppercase(), function.args.len(), 0..=0)?;
+                        return Ok(Kind::Number);
+                    }
+                    if AggregateFunction::from_name(&name).is_none() {
+                        return Err(QubeError::QueryParse(format!(
+                            "{} is not a window function",
+                            name
+                        )));
+                    }
+                }
+
+                if let Some(aggregate) = AggregateFunction::from_name(&name) {
+                    if clause != Clause::Projection {
+                        return Err(QubeError::QueryParse(format!(
+                            "Aggregate function {} is not allowed here",
+                            name
+                        )));
+                    }
+                    let arity = if aggregate == AggregateFunction::ApproxCountDistinct {
+                        1..=2
+                    } else {
+                        1..=1
+                    };
+                    functions::check_arity(&name.to_uppercase(), function.args.len(), arity)?;
+                    // Aggregates cannot nest
+                    let kinds = args
+                        .iter()
+                        .map(|arg| self.kind(arg, Clause::Other))
+                        .collect::<QubeResult<Vec<_>>>()?;
+                    return match (aggregate, kinds.first()) {
+                        (AggregateFunction::Count | AggregateFunction::ApproxCountDistinct, _) => {
+                            Ok(Kind::Number)
+                        }
+                        (AggregateFunction::Sum | AggregateFunction::Avg, Some(kind))
+                            if !kind.is(Kind::Number) =>
+                        {
+                            Err(mismatch(format!("{} of {} ({})", name, args[0], kind)))
+                        }
+                        (AggregateFunction::Sum | AggregateFunction::Avg, _) => Ok(Kind::Number),
+                        (_, kind) => Ok(kind.copied().unwrap_or(Kind::Any)),
+                    };
+                }
+
+                if function.over.is_some() || function.distinct || !function.order_by.is_empty() {
+                    return Err(QubeError::Unsupported(format!(
+                        "call to {}: {}",
+                        name, function
+                    )));
+                }
+                let upper = name.to_uppercase();
+                let arity = functions::arity(&upper)
+                    .ok_or_else(|| QubeError::QueryParse(format!("Unknown function: {}", upper)))?;
+                functions::check_arity(&upper, args.len(), arity)?;
+                let kinds = args
+                    .iter()
+                    .map(|arg| self.kind(arg, clause))
+                    .collect::<QubeResult<Vec<_>>>()?;
+                scalar_kind(&upper, &args, &kinds)
+            }
+            Expr::Substring {
+                expr: value,
+                substring_from,
+                substring_for,
+                ..
+            } => {
+                self.kind(value, clause)?;
+                for position in substring_from.iter().chain(substring_for) {
+                    let kind = self.kind(position, clause)?;
+                    if !kind.is(Kind::Number) {
+                        return Err(mismatch(format!(
+                            "SUBSTRING position {} ({})",
+                            position, kind
+                        )));
+                    }
+                }
+                Ok(Kind::Text)
+            }
+            Expr::Trim {
+                expr: value,
+                trim_what,
+                ..
+            } => {
+                self.kind(value, clause)?;
+                if let Some(what) = trim_what {
+                    self.kind(what, clause)?;
+                }
+                Ok(Kind::Text)
+            }
+            Expr::Ceil {
+                expr: value,
+                field: DateTimeField::NoDateTime,
+            }
+            | Expr::Floor {
+                expr: value,
+                field: DateTimeField::NoDateTime,
+            } => scalar_kind("CEIL", &[value], &[self.kind(value, clause)?]),
+            Expr::Extract { expr: value, .. } => {
+                self.kind(value, clause)?;
+                Ok(Kind::Number)
+            }
+            Expr::Interval(interval) => {
+                self.kind(&interval.value, clause)?;
+                Ok(Kind::Text)
+            }
+            other => Err(QubeError::Unsupported(format!("expression {}", other))),
+        }
+    }
+
+    /// Check a WHERE or ON condition
+    fn condition(&self, condition: &Expr) -> QubeResult<()> {
+        let kind = self.kind(condition, Clause::Other)?;
+        if matches!(kind, Kind::Text | Kind::Binary) {
+            return Err(mismatch(format!(
+                "condition {} is {}, not boolean",
+                condition, kind
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn find_column<'a>(table: &'a Table, name: &str) -> Option<&'a Column> {
+    table.columns.iter().find(|column| column.name == name)
+}
+
+/// The kind a scalar function returns, checking arguments that must be
+/// numbers
+fn scalar_kind(name: &str, args: &[&Expr], kinds: &[Kind]) -> QubeResult<Kind> {
+    let numeric = |count: usize| -> QubeResult<()> {
+        for (arg, kind) in args.iter().zip(kinds).take(count) {
+            if !kind.is(Kind::Number) {
+                return Err(mismatch(format!("{} of {} ({})", name, arg, kind)));
+            }
+        }
+        Ok(())
+    };
+    Ok(match name {
+        "LENGTH" | "CHAR_LENGTH" | "CHARACTER_LENGTH" | "EXTRACT" => Kind::Number,
+        "ABS" | "CEIL" | "CEILING" | "FLOOR" | "ROUND" | "MOD" | "POWER" | "POW" => {
+            numeric(args.len())?;
+            Kind::Number
+        }
+        "NOW" | "CURRENT_TIMESTAMP" | "DATE_TRUNC" | "DATE_ADD" | "DATE_SUB" => Kind::Timestamp,
+        _ => Kind::Text,
+    })
+}
+
+/// Check that `value` can be stored in `column`. Constant expressions are
+/// converted the way INSERT and UPDATE would; other expressions are
+/// checked by kind.
+fn check_assignment(column: &Column, value: &Expr, kind: Kind) -> QubeResult<()> {
+    let target = Kind::of(&column.data_type);
+    if kind == Kind::Any || target == Kind::Any {
+        return Ok(());
+    }
+    if let Ok(constant) = expr::evaluate(value, &Row::new()) {
+        return match expr::coerce(constant, &column.data_type) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(mismatch(format!(
+                "cannot store {} in column '{}' of type {}",
+                value, column.name, column.data_type
+            ))),
+        };
+    }
+    let fits = match target {
+        Kind::Text => true,
+        Kind::Number | Kind::Timestamp => target.comparable(kind),
+        Kind::Boolean => matches!(kind, Kind::Boolean | Kind::Number),
+        Kind::Binary => matches!(kind, Kind::Binary | Kind::Text),
+        Kind::Any => true,
+    };
+    if !fits {
+        return Err(mismatch(format!(
+            "cannot store {} ({}) in column '{}' of type {}",
+            value, kind, column.name, column.data_type
+        )));
+    }
+    Ok(())
+}
+
+/// Check a statement against `catalog` without running it
+pub(crate) fn check_statement(catalog: &Catalog, statement: &Statement) -> QubeResult<()> {
+    match statement {
+        Statement::Query(query) => check_query(catalog, query),
+        Statement::Insert {
+            table_name,
+            columns,
+            source,
+            ..
+        } => check_insert(catalog, table_name, columns, source),
+        Statement::Update {
+            table,
+            assignments,
+            selection,
+            ..
+        } => check_update(catalog, table, assignments, selection.as_ref()),
+        Statement::Delete {
+            from, selection, ..
+        } => {
+            for item in from {
+                let table = table_of(catalog, &item.relation)?;
+                if let Some(selection) = selection {
+                    Scope::single(table).condition(selection)?;
+                }
+            }
+            Ok(())
+        }
+        Statement::Explain { statement, .. } => check_statement(catalog, statement),
+        Statement::CreateView { query, .. } => check_query(catalog, query),
+        _ => Ok(()),
+    }
+}
+
+fn table_of<'a>(catalog: &'a Catalog, factor: &TableFactor) -> QubeResult<&'a Table> {
+    match factor {
+        TableFactor::Table { name, .. } => catalog.get_table(&object_name(name)),
+        other => Err(QubeError::Unsupported(format!("table reference {}", other))),
+    }
+}
+
+fn check_query(catalog: &Catalog, query: &Query) -> QubeResult<()> {
+    // The columns of CTEs are only known once they run, which the dry run
+    // after these checks does
+    if query.with.is_some() {
+        return Ok(());
+    }
+    let select = match &*query.body {
+        SetExpr::Select(select) => select,
+        other => return Err(QubeError::Unsupported(format!("query {}", other))),
+    };
+    // Nor are the columns of views that run their query when read
+    let reads_view = select.from.iter().any(|item| {
+        std::iter::once(&item.relation)
+            .chain(item.joins.iter().map(|j| &j.relation))
+            .any(|factor| match factor {
+                TableFactor::Table { name, .. } => catalog
+                    .view(&object_name(name))
+                    .is_some_and(|view| !view.is_materialized()),
+                _ => false,
+            })
+    });
+    if reads_view {
+        return Ok(());
+    }
+    let scope = scope_of(catalog, &select.from)?;
+
+    for item in &select.from {
+        for join in &item.joins {
+            let constraint = match &join.join_operator {
+                JoinOperator::Inner(constraint)
+                | JoinOperator::LeftOuter(constraint)
+                | JoinOperator::RightOuter(constraint)
+                | JoinOperator::FullOuter(constraint) => Some(constraint),
+                _ => None,
+            };
+            if let Some(JoinConstraint::On(on)) = constraint {
+                scope.condition(on)?;
+            }
+        }
+    }
+    if let Some(selection) = &select.selection {
+        scope.condition(selection)?;
+    }
+    for item in &select.projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                scope.kind(expr, Clause::Projection)?;
+            }
+            SelectItem::QualifiedWildcard(name, _) => {
+                let qualifier = object_name(name);
+                if !scope.relations.iter().any(|(q, _)| *q == qualifier) {
+                    return Err(QubeError::TableNotFound(qualifier));
+                }
+            }
+            SelectItem::Wildcard(_) => {}
+        }
+    }
+    for order in &query.order_by {
+        scope.kind(&order.expr, Clause::Other)?;
+    }
+    for count in query
+        .limit
+        .iter()
+        .chain(query.offset.as_ref().map(|o| &o.value))
+    {
+        let kind = Scope::empty().kind(count, Clause::Other)?;
+        if !kind.is(Kind::Number) {
+            return Err(mismatch(format!("LIMIT/OFFSET {} ({})", count, kind)));
+        }
+    }
+    Ok(())
+}
+
+fn scope_of<'a>(catalog: &'a Catalog, from: &[TableWithJoins]) -> QubeResult<Scope<'a>> {
+    let mut scope = Scope::empty();
+    let factors = from.iter().flat_map(|item| {
+        std::iter::once(&item.relation).chain(item.joins.iter().map(|j| &j.relation))
+    });
+    for factor in factors {
+        let table = table_of(catalog, factor)?;
+        let qualifier = match factor {
+            TableFactor::Table {
+                alias: Some(alias), ..
+            } => alias.name.value.clone(),
+            _ => table.name.clone(),
+        };
+        scope.relations.push((qualifier, table));
+    }
+    Ok(scope)
+}
+
+fn check_insert(
+    catalog: &Catalog,
+    table_name: &ObjectName,
+    columns: &[Ident],
+    source: &Query,
+) -> QubeResult<()> {
+    let table = catalog.get_table(&object_name(table_name))?;
+    let targets = if columns.is_empty() {
+        table
+            .columns
+            .iter()
+            .filter(|c| c.generated.is_none())
+            .collect::<Vec<_>>()
+    } else {
+        columns
+            .iter()
+            .map(|ident| {
+                find_column(table, &ident.value).ok_or_else(|| {
+                    QubeError::ColumnNotFound(format!("{}.{}", table.name, ident.value))
+                })
+            })
+            .collect::<QubeResult<Vec<_>>>()?
+    };
+
+    let rows = match &*source.body {
+        SetExpr::Values(values) => &values.rows,
+        _ => {
+            return Err(QubeError::QueryParse(
+                "Only INSERT ... VALUES is supported".to_string(),
+            ))
+        }
+    };
+    let scope = Scope::empty();
+    for row in rows {
+        if row.len() != targets.len() {
+            return Err(QubeError::QueryParse(format!(
+                "INSERT has {} values but {} columns",
+                row.len(),
+                targets.len()
+            )));
+        }
+        for (value, column) in row.iter().zip(&targets) {
+            if !is_default_keyword(value) {
+                check_assignment(column, value, scope.kind(value, Clause::Other)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_update(
+    catalog: &Catalog,
+    table: &TableWithJoins,
+    assignments: &[Assignment],
+    selection: Option<&Expr>,
+) -> QubeResult<()> {
+    let table = table_of(catalog, &table.relation)?;
+    let scope = Scope::single(table);
+    for assignment in assignments {
+        let name = assignment.id.last().map(|ident| ident.value.as_str());
+        let column = name
+            .and_then(|name| find_column(table, name))
+            .ok_or_else(|| {
+                QubeError::ColumnNotFound(format!("{}.{}", table.name, name.unwrap_or("")))
+            })?;
+        if !is_default_keyword(&assignment.value) {
+            let kind = scope.kind(&assignment.value, Clause::Other)?;
+            check_assignment(column, &assignment.value, kind)?;
+        }
+    }
+    if let Some(selection) = selection {
+        scope.condition(selection)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::QubeError;
+    use crate::query::QueryEngine;
+
+    fn engine() -> QueryEngine {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, joined TIMESTAMP);
+                 CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, total DOUBLE);
+                 INSERT INTO users VALUES (1, 'ann', 0)",
+            )
+            .unwrap();
+        engine
+    }
+
+    /// The message of the error validating `sql` reports
+    fn problem(engine: &QueryEngine, sql: &str) -> String {
+        match engine.validate(sql) {
+            Ok(()) => panic!("{} passed validation", sql),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn valid_scripts_pass_without_running() {
+        let engine = engine();
+        engine
+            .validate(
+                "SELECT u.name, o.total * 2 FROM users u JOIN orders o ON o.user_id = u.id
+                 WHERE u.joined > 5 AND UPPER(u.name) LIKE 'A%'",
+            )
+            .unwrap();
+        // Later statements see tables created by earlier ones
+        engine
+            .validate(
+                "CREATE TABLE tags (id INT PRIMARY KEY, label TEXT);
+                 INSERT INTO tags VALUES (1, 'new');
+                 UPDATE tags SET label = 'old' WHERE id = 1;
+                 DELETE FROM users WHERE id = 1",
+            )
+            .unwrap();
+        assert!(!engine.list_tables().contains(&"tags".to_string()));
+        let users = engine.execute_script("SELECT * FROM users").unwrap();
+        assert_eq!(users[0].rows.len(), 1);
+    }
+
+    #[test]
+    fn missing_tables_and_columns_are_reported() {
+        let engine = engine();
+        assert!(matches!(
+            engine.validate("SELECT * FROM nowhere"),
+            Err(QubeError::TableNotFound(_))
+        ));
+        assert!(matches!(
+            engine.validate("SELECT email FROM users"),
+            Err(QubeError::ColumnNotFound(_))
+        ));
+        assert!(matches!(
+            engine.validate("SELECT u.total FROM users u"),
+            Err(QubeError::ColumnNotFound(_))
+        ));
+        assert!(problem(
+            &engine,
+            "SELECT id FROM users JOIN orders ON user_id = users.id"
+        )
+        .contains("Column reference 'id' is ambiguous"));
+        assert!(matches!(
+            engine.validate("UPDATE users SET email = 'x'"),
+            Err(QubeError::ColumnNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn functions_and_operand_types_are_checked() {
+        let engine = engine();
+        assert!(problem(&engine, "SELECT SHOUT(name) FROM users").contains("Unknown function"));
+        assert!(problem(&engine, "SELECT UPPER(name, id) FROM users")
+            .contains("UPPER expects 1 argument but 2 were given"));
+        assert!(problem(&engine, "SELECT * FROM users WHERE name > 3")
+            .contains("Type mismatch: cannot compare"));
+        assert!(problem(&engine, "SELECT -name FROM users").contains("Type mismatch"));
+        assert!(problem(&engine, "SELECT * FROM users WHERE name").contains("not boolean"));
+        assert!(problem(
+            &engine,
+            "SELECT CASE WHEN id = 1 THEN 'a' ELSE 2 END FROM users"
+        )
+        .contains("CASE branches return both"));
+        assert!(problem(&engine, "SELECT * FROM users WHERE COUNT(*) > 1")
+            .contains("Aggregate function COUNT is not allowed here"));
+        // Timestamps compare like numbers
+        engine
+            .validate("SELECT * FROM users WHERE joined < 10")
+            .unwrap();
+    }
+
+    #[test]
+    fn problems_only_the_executor_finds_are_reported_too() {
+        let engine = engine();
+        assert!(matches!(
+            engine.validate("CREATE TABLE users (id INT PRIMARY KEY)"),
+            Err(QubeError::AlreadyExists(_))
+        ));
+        assert!(engine
+            .validate("INSERT INTO users VALUES (2, 'bo', 0)")
+            .is_ok());
+        assert!(engine.validate("SELEC 1").is_err());
+    }
+
+    #[test]
+    fn approx_count_distinct_takes_an_optional_precision() {
+        let engine = engine();
+        engine
+            .validate("SELECT APPROX_COUNT_DISTINCT(name) + 1 AS n FROM users")
+            .unwrap();
+        engine
+            .validate("SELECT APPROX_COUNT_DISTINCT(name, 10) AS n FROM users")
+            .unwrap();
+        let message = problem(&engine, "SELECT APPROX_COUNT_DISTINCT() AS n FROM users");
+        assert!(message.contains("APPROX_COUNT_DISTINCT"), "{}", message);
+        problem(
+            &engine,
+            "SELECT APPROX_COUNT_DISTINCT(name, 10, 2) AS n FROM users",
+        );
+        problem(
+            &engine,
+            "SELECT APPROX_COUNT_DISTINCT(email) AS n FROM users",
+        );
+    }
+}
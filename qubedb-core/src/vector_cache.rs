@@ -0,0 +1,628 @@
+//! Memory-bounded storage for the vectors of a `VectorIndex`
+//!
+//! By default an index keeps every vector in memory. Given a
+//! `VectorCacheConfig` it keeps at most `memory_budget` bytes of vectors
+//! resident and spills the rest to a file, the coldest first by the
+//! configured `EvictionPolicy`. A spilled vector read with
+//! `VectorIndex::get` is loaded back and stays resident while it is hot;
+//! searches read spilled vectors straight from the file, so results never
+//! depend on what is resident, only their speed does.
+//!
+//! The spill file is scratch space, recreated whenever a cache is
+//! configured. `VectorIndex::save` still writes every vector, spilled or
+//! not, and a loaded index starts out with all of them in memory.
+
+use crate::error::{QubeError, QubeResult};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Which resident vectors are spilled first once over budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// The least recently used
+    #[default]
+    Lru,
+    /// The least frequently used, the least recently used among those
+    Lfu,
+}
+
+impl std::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionPolicy::Lru => write!(f, "lru"),
+            EvictionPolicy::Lfu => write!(f, "lfu"),
+        }
+    }
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = QubeError;
+
+    fn from_str(s: &str) -> QubeResult<Self> {
+        match s.to_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            other => Err(QubeError::Config(format!(
+                "Unknown eviction policy: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Settings for spilling a vector index to disk
+#[derive(Debug, Clone)]
+pub struct VectorCacheConfig {
+    /// Most bytes of vectors, ids included, kept in memory
+    pub memory_budget: usize,
+    pub policy: EvictionPolicy,
+    /// File spilled vectors are written to
+    pub spill_path: PathBuf,
+}
+
+impl VectorCacheConfig {
+    pub fn new(memory_budget: usize, spill_path: impl Into<PathBuf>) -> Self {
+        VectorCacheConfig {
+            memory_budget,
+            policy: EvictionPolicy::default(),
+            spill_path: spill_path.into(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+/// Where a cached vector index's vectors are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VectorCacheStats {
+    /// Vectors in memory
+    pub resident: usize,
+    /// Vectors in the spill file
+    pub spilled: usize,
+    /// Bytes the resident vectors take, counted against the budget
+    pub resident_bytes: usize,
+    /// Vectors written to the spill file so far
+    pub spills: u64,
+    /// Spilled vectors loaded back into memory so far
+    pub reloads: u64,
+}
+
+/// The vectors of an index, some of them possibly spilled to disk
+pub(crate) struct VectorStore {
+    state: Mutex<StoreState>,
+}
+
+struct StoreState {
+    resident: BTreeMap<String, Vec<f32>>,
+    cache: Option<Cache>,
+}
+
+/// Bookkeeping of a store with a memory budget
+struct Cache {
+    config: VectorCacheConfig,
+    file: File,
+    /// Offset and length (in floats) of each spilled vector in the file
+    spilled: HashMap<String, (u64, usize)>,
+    /// Length of the file
+    end: u64,
+    /// Bytes of the file holding vectors since removed or reloaded
+    dead: u64,
+    /// Times each vector was used and its last use, spilled ones included
+    usage: HashMap<String, (u64, u64)>,
+    /// Resident vectors by eviction rank, coldest first
+    order: BTreeSet<(u64, u64, String)>,
+    clock: u64,
+    resident_bytes: usize,
+    spills: u64,
+    reloads: u64,
+}
+
+/// Bytes a resident vector is counted as
+fn footprint(id: &str, vector: &[f32]) -> usize {
+    id.len() + std::mem::size_of_val(vector)
+}
+
+impl Cache {
+    fn open(config: VectorCacheConfig) -> QubeResult<Self> {
+        if let Some(parent) = config.spill_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&config.spill_path)?;
+        Ok(Cache {
+            config,
+            file,
+            spilled: HashMap::new(),
+            end: 0,
+            dead: 0,
+            usage: HashMap::new(),
+            order: BTreeSet::new(),
+            clock: 0,
+            resident_bytes: 0,
+            spills: 0,
+            reloads: 0,
+        })
+    }
+
+    /// Eviction rank of a vector used `uses` times, last at `last`
+    fn rank(&self, id: &str, (uses, last): (u64, u64)) -> (u64, u64, String) {
+        match self.config.policy {
+            EvictionPolicy::Lru => (last, 0, id.to_string()),
+            EvictionPolicy::Lfu => (uses, last, id.to_string()),
+        }
+    }
+
+    /// Count a use of a resident vector
+    fn touch(&mut self, id: &str) {
+        self.clock += 1;
+        let usage = self.usage.get(id).copied();
+        if let Some(usage) = usage {
+            let rank = self.rank(id, usage);
+            self.order.remove(&rank);
+        }
+        let (uses, _) = usage.unwrap_or_default();
+        let usage = (uses + 1, self.clock);
+        self.usage.insert(id.to_string(), usage);
+        let rank = self.rank(id, usage);
+        self.order.insert(rank);
+    }
+
+    /// Forget a vector that is no longer resident
+    fn untrack(&mut self, id: &str, vector: &[f32]) {
+        if let Some(&usage) = self.usage.get(id) {
+            let rank = self.rank(id, usage);
+            self.order.remove(&rank);
+        }
+        self.resident_bytes = self.resident_bytes.saturating_sub(footprint(id, vector));
+    }
+
+    fn read(&mut self, (offset, len): (u64, usize)) -> QubeResult<Vec<f32>> {
+        let mut bytes = vec![0u8; len * 4];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
+
+    /// Append a vector to the file, returning where it went
+    fn write(&mut self, vector: &[f32]) -> QubeResult<(u64, usize)> {
+        let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&bytes)?;
+        let offset = self.end;
+        self.end += bytes.len() as u64;
+        Ok((offset, vector.len()))
+    }
+
+    /// Drop a spilled vector from the file, returning where it was
+    fn discard(&mut self, id: &str) -> Option<(u64, usize)> {
+        let location = self.spilled.remove(id)?;
+        self.dead += location.1 as u64 * 4;
+        Some(location)
+    }
+
+    /// Rewrite the file without its dead space, once that is most of it
+    fn compact(&mut self) -> QubeResult<()> {
+        if self.dead == 0 || self.dead * 2 < self.end {
+            return Ok(());
+        }
+        let tmp_path = self.config.spill_path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        let mut spilled: Vec<(String, (u64, usize))> = self.spilled.drain().collect();
+        spilled.sort_unstable_by_key(|(_, (offset, _))| *offset);
+        let mut end = 0;
+        for (id, location) in spilled {
+            let vector = self.read(location)?;
+            let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+            tmp.write_all(&bytes)?;
+            self.spilled.insert(id, (end, location.1));
+            end += bytes.len() as u64;
+        }
+        drop(tmp);
+        std::fs::rename(&tmp_path, &self.config.spill_path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.config.spill_path)?;
+        self.end = end;
+        self.dead = 0;
+        Ok(())
+    }
+}
+
+impl StoreState {
+    /// Spill the coldest resident vectors until back within budget
+    fn evict(&mut self) -> QubeResult<()> {
+        let cache = match &mut self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        while cache.resident_bytes > cache.config.memory_budget {
+            let id = match cache.order.pop_first() {
+                Some((_, _, id)) => id,
+                None => break,
+            };
+            let vector = match self.resident.remove(&id) {
+                Some(vector) => vector,
+                None => continue,
+            };
+            let location = match cache.write(&vector) {
+                Ok(location) => location,
+                Err(e) => {
+                    let rank = cache.rank(&id, cache.usage[&id]);
+                    cache.order.insert(rank);
+                    self.resident.insert(id, vector);
+                    return Err(e);
+                }
+            };
+            cache.resident_bytes = cache.resident_bytes.saturating_sub(footprint(&id, &vector));
+            cache.spilled.insert(id, location);
+            cache.spills += 1;
+        }
+        Ok(())
+    }
+
+    /// Every vector, read from the spill file where necessary
+    fn all(&mut self) -> QubeResult<BTreeMap<String, Vec<f32>>> {
+        let mut all = self.resident.clone();
+        if let Some(cache) = &mut self.cache {
+            let locations: Vec<(String, (u64, usize))> = cache
+                .spilled
+                .iter()
+                .map(|(id, &location)| (id.clone(), location))
+                .collect();
+            for (id, location) in locations {
+                all.insert(id, cache.read(location)?);
+            }
+        }
+        Ok(all)
+    }
+}
+
+impl VectorStore {
+    pub(crate) fn new() -> Self {
+        Self::from(BTreeMap::new())
+    }
+
+    /// Keep at most `config.memory_budget` bytes of vectors in memory from
+    /// now on, spilling any excess right away
+    pub(crate) fn set_cache(&mut self, config: VectorCacheConfig) -> QubeResult<()> {
+        let state = self.state.get_mut().unwrap();
+        let all = state.all()?;
+        let mut cache = Cache::open(config)?;
+        for (id, vector) in &all {
+            cache.touch(id);
+            cache.resident_bytes += footprint(id, vector);
+        }
+        state.resident = all;
+        state.cache = Some(cache);
+        state.evict()
+    }
+
+    pub(crate) fn cache_stats(&self) -> Option<VectorCacheStats> {
+        let state = self.state.lock().unwrap();
+        state.cache.as_ref().map(|cache| VectorCacheStats {
+            resident: state.resident.len(),
+            spilled: cache.spilled.len(),
+            resident_bytes: cache.resident_bytes,
+            spills: cache.spills,
+            reloads: cache.reloads,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.resident.len() + state.cache.as_ref().map_or(0, |cache| cache.spilled.len())
+    }
+
+    pub(crate) fn insert(&mut self, id: &str, vector: Vec<f32>) -> QubeResult<()> {
+        self.remove(id);
+        let state = self.state.get_mut().unwrap();
+        if let Some(cache) = &mut state.cache {
+            cache.touch(id);
+            cache.resident_bytes += footprint(id, &vector);
+        }
+        state.resident.insert(id.to_string(), vector);
+        state.evict()
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) -> bool {
+        let state = self.state.get_mut().unwrap();
+        let cache = match &mut state.cache {
+            Some(cache) => cache,
+            None => return state.resident.remove(id).is_some(),
+        };
+        let removed = match state.resident.remove(id) {
+            Some(vector) => {
+                cache.untrack(id, &vector);
+                true
+            }
+            None => cache.discard(id).is_some(),
+        };
+        cache.usage.remove(id);
+        if let Err(e) = cache.compact() {
+            tracing::warn!("Failed to compact vector spill file: {}", e);
+        }
+        removed
+    }
+
+    /// A vector, loaded back into memory if it was spilled
+    pub(crate) fn get(&self, id: &str) -> QubeResult<Option<Vec<f32>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let cache = match &mut state.cache {
+            Some(cache) => cache,
+            None => return Ok(state.resident.get(id).cloned()),
+        };
+        if let Some(vector) = state.resident.get(id) {
+            cache.touch(id);
+            return Ok(Some(vector.clone()));
+        }
+        let location = match cache.spilled.get(id) {
+            Some(&location) => location,
+            None => return Ok(None),
+        };
+        let vector = cache.read(location)?;
+        cache.discard(id);
+        cache.touch(id);
+        cache.resident_bytes += footprint(id, &vector);
+        cache.reloads += 1;
+        cache.compact()?;
+        state.resident.insert(id.to_string(), vector.clone());
+        state.evict()?;
+        Ok(Some(vector))
+    }
+
+    /// Count a use of each of these vectors, e.g. search results
+    pub(crate) fn touch<'a>(&self, ids: impl IntoIterator<Item = &'a str>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(cache) = &mut state.cache {
+            for id in ids {
+                if state.resident.contains_key(id) {
+                    cache.touch(id);
+                } else if let Some(usage) = cache.usage.get_mut(id) {
+                    usage.0 += 1;
+                }
+            }
+        }
+    }
+
+    /// Call `f` with every vector, reading spilled ones from the file
+    /// without loading them back
+    pub(crate) fn scan(&self, mut f: impl FnMut(&str, &[f32])) -> QubeResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for (id, vector) in &state.resident {
+            f(id, vector);
+        }
+        if let Some(cache) = &mut state.cache {
+            let mut locations: Vec<(String, (u64, usize))> = cache
+                .spilled
+                .iter()
+                .map(|(id, &location)| (id.clone(), location))
+                .collect();
+            locations.sort_unstable_by_key(|(_, (offset, _))| *offset);
+            for (id, location) in locations {
+                f(&id, &cache.read(location)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change every vector in place, spilled ones included
+    pub(crate) fn map_all(&mut self, mut f: impl FnMut(&str, &mut Vec<f32>)) -> QubeResult<()> {
+        let state = self.state.get_mut().unwrap();
+        for (id, vector) in state.resident.iter_mut() {
+            f(id, vector);
+        }
+        if let Some(cache) = &mut state.cache {
+            let ids: Vec<String> = cache.spilled.keys().cloned().collect();
+            for id in ids {
+                let location = cache.spilled[&id];
+                let mut vector = cache.read(location)?;
+                f(&id, &mut vector);
+                cache.discard(&id);
+                let location = cache.write(&vector)?;
+                cache.spilled.insert(id, location);
+            }
+            cache.compact()?;
+        }
+        Ok(())
+    }
+}
+
+/// A copy holds every vector in memory, without a budget
+impl Clone for VectorStore {
+    fn clone(&self) -> Self {
+        let all = self
+            .state
+            .lock()
+            .unwrap()
+            .all()
+            .expect("Failed to read spilled vectors");
+        Self::from(all)
+    }
+}
+
+impl std::fmt::Debug for VectorStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VectorStore")
+            .field("len", &self.len())
+            .field("cache", &self.cache_stats())
+            .finish()
+    }
+}
+
+/// Serialized as a map of every vector, the layout of an index file
+impl Serialize for VectorStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let all = self
+            .state
+            .lock()
+            .unwrap()
+            .all()
+            .map_err(serde::ser::Error::custom)?;
+        all.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VectorStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        BTreeMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// A store holding these vectors in memory, without a budget
+impl From<BTreeMap<String, Vec<f32>>> for VectorStore {
+    fn from(resident: BTreeMap<String, Vec<f32>>) -> Self {
+        VectorStore {
+            state: Mutex::new(StoreState {
+                resident,
+                cache: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes two of the test vectors take, ids included
+    const TWO_VECTORS: usize = 2 * (1 + 4 * 4);
+
+    fn vector(x: f32) -> Vec<f32> {
+        vec![x; 4]
+    }
+
+    fn store(name: &str, policy: EvictionPolicy) -> VectorStore {
+        let path = std::env::temp_dir().join(format!(
+            "qubedb-vector-cache-{}-{}.spill",
+            name,
+            std::process::id()
+        ));
+        let mut store = VectorStore::new();
+        store
+            .set_cache(VectorCacheConfig::new(TWO_VECTORS, path).with_policy(policy))
+            .unwrap();
+        store
+    }
+
+    fn resident(store: &VectorStore) -> Vec<String> {
+        store
+            .state
+            .lock()
+            .unwrap()
+            .resident
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn lru_spills_the_least_recently_used() {
+        let mut store = store("lru", EvictionPolicy::Lru);
+        store.insert("a", vector(1.0)).unwrap();
+        store.insert("b", vector(2.0)).unwrap();
+        store.get("a").unwrap();
+        store.insert("c", vector(3.0)).unwrap();
+        assert_eq!(resident(&store), ["a", "c"]);
+
+        let stats = store.cache_stats().unwrap();
+        assert_eq!((stats.resident, stats.spilled), (2, 1));
+        assert_eq!(stats.resident_bytes, TWO_VECTORS);
+        assert_eq!(stats.spills, 1);
+
+        // Reading the spilled vector loads it back, spilling the coldest
+        assert_eq!(store.get("b").unwrap(), Some(vector(2.0)));
+        assert_eq!(resident(&store), ["b", "c"]);
+        let stats = store.cache_stats().unwrap();
+        assert_eq!((stats.spills, stats.reloads), (2, 1));
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn lfu_spills_the_least_frequently_used() {
+        let mut store = store("lfu", EvictionPolicy::Lfu);
+        store.insert("a", vector(1.0)).unwrap();
+        store.insert("b", vector(2.0)).unwrap();
+        store.touch(["a", "a"]);
+        store.get("b").unwrap();
+        // A newcomer has been used least, so it goes first
+        store.insert("c", vector(3.0)).unwrap();
+        assert_eq!(resident(&store), ["a", "b"]);
+        // Reloaded, it ties with b, which was used longer ago
+        store.get("c").unwrap();
+        assert_eq!(resident(&store), ["a", "c"]);
+    }
+
+    #[test]
+    fn changed_and_removed_vectors_are_not_read_stale() {
+        let mut store = store("invalidate", EvictionPolicy::Lru);
+        for (id, x) in [("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)] {
+            store.insert(id, vector(x)).unwrap();
+        }
+        assert_eq!(resident(&store), ["c", "d"]);
+
+        // Replacing a spilled vector drops the old copy from the file
+        store.insert("a", vector(10.0)).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(vector(10.0)));
+        assert!(store.remove("b"));
+        assert!(!store.remove("b"));
+        assert_eq!(store.get("b").unwrap(), None);
+        assert_eq!(store.len(), 3);
+
+        store.map_all(|_, vector| vector[0] = -1.0).unwrap();
+        let mut seen = Vec::new();
+        store
+            .scan(|id, vector| seen.push((id.to_string(), vector[0], vector[1])))
+            .unwrap();
+        seen.sort_by(|x, y| x.0.cmp(&y.0));
+        assert_eq!(
+            seen,
+            [
+                ("a".to_string(), -1.0, 10.0),
+                ("c".to_string(), -1.0, 3.0),
+                ("d".to_string(), -1.0, 4.0),
+            ]
+        );
+        // Scanning reads spilled vectors without loading them back
+        assert_eq!(store.cache_stats().unwrap().spilled, 1);
+
+        // A copy holds every vector in memory
+        let copy = store.clone();
+        assert!(copy.cache_stats().is_none());
+        assert_eq!(copy.get("c").unwrap(), Some(vec![-1.0, 3.0, 3.0, 3.0]));
+    }
+
+    #[test]
+    fn the_spill_file_is_compacted_once_mostly_dead() {
+        let mut store = store("compact", EvictionPolicy::Lru);
+        for i in 0..10 {
+            store.insert(&i.to_string(), vector(i as f32)).unwrap();
+        }
+        for i in 0..7 {
+            store.remove(&i.to_string());
+        }
+        let state = store.state.lock().unwrap();
+        let cache = state.cache.as_ref().unwrap();
+        assert_eq!(cache.spilled.len(), 1);
+        assert_eq!(cache.end, 16);
+        drop(state);
+        assert_eq!(store.get("7").unwrap(), Some(vector(7.0)));
+    }
+}
@@ -0,0 +1,234 @@
+//! Views for QubeDB
+//!
+//! `CREATE VIEW v AS SELECT ...` stores the query under the name `v`. A
+//! query reading `v` runs the view's query and reads its result like that
+//! of a CTE, so the view always reflects the tables it reads, and fails
+//! once one of them is dropped.
+//!
+//! `CREATE MATERIALIZED VIEW v AS SELECT ...` instead runs its query once
+//! and stores the result as the rows of `v`, which is then read like any
+//! table. The rows only change when the view is refreshed: on demand with
+//! `REFRESH MATERIALIZED VIEW v`, or, for a view created `WITH (refresh =
+//! 'on_write')`, after every INSERT, UPDATE or DELETE of a table its query
+//! reads. A refresh runs the query again and replaces all of the rows,
+//! keeping the indexes created on the view.
+//!
+//! Columns are named after the query's outputs, or after the column list
+//! of the view when it has one, and get the type of their first non-NULL
+//! value, as the columns of a CTE do. Views cannot be written to.
+
+use crate::error::{QubeError, QubeResult};
+use crate::query::object_name;
+use sqlparser::ast::{visit_relations, Ident, Query, SqlOption, Value as SqlValue};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::ControlFlow;
+
+/// When a materialized view is refreshed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ViewRefresh {
+    /// Only by `REFRESH MATERIALIZED VIEW`
+    #[default]
+    OnDemand,
+    /// Also after every write to a table the view reads
+    OnWrite,
+}
+
+impl ViewRefresh {
+    /// Parse the value of a `refresh` view option
+    pub fn parse(name: &str) -> QubeResult<Self> {
+        match name.to_lowercase().as_str() {
+            "on_demand" => Ok(ViewRefresh::OnDemand),
+            "on_write" => Ok(ViewRefresh::OnWrite),
+            other => Err(QubeError::QueryParse(format!(
+                "Unsupported view refresh: {}",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ViewRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViewRefresh::OnDemand => write!(f, "on_demand"),
+            ViewRefresh::OnWrite => write!(f, "on_write"),
+        }
+    }
+}
+
+/// The definition of a view
+#[derive(Debug, Clone)]
+pub struct View {
+    pub name: String,
+    /// Names given to the columns, in place of the query's output names
+    pub columns: Vec<Ident>,
+    pub query: Query,
+    /// How the rows of a materialized view, stored as a table of the same
+    /// name, are refreshed; `None` for a view that runs its query when read
+    pub materialized: Option<ViewRefresh>,
+    /// Tables and views the query reads
+    pub tables: BTreeSet<String>,
+}
+
+impl View {
+    pub fn new(
+        name: String,
+        columns: Vec<Ident>,
+        query: Query,
+        materialized: Option<ViewRefresh>,
+    ) -> Self {
+        let mut tables = BTreeSet::new();
+        let _ = visit_relations(&query, |relation| {
+            tables.insert(object_name(relation));
+            ControlFlow::<()>::Continue(())
+        });
+        View {
+            name,
+            columns,
+            query,
+            materialized,
+            tables,
+        }
+    }
+
+    /// Whether the view stores its rows
+    pub fn is_materialized(&self) -> bool {
+        self.materialized.is_some()
+    }
+
+    /// Whether the view's query reads `table`, a table or view
+    pub fn reads(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+
+    /// Read the options of `CREATE [MATERIALIZED] VIEW ... WITH (...)`:
+    /// how a materialized view is refreshed, `None` for another view
+    pub(crate) fn refresh_option(
+        options: &[SqlOption],
+        materialized: bool,
+    ) -> QubeResult<Option<ViewRefresh>> {
+        let mut refresh = ViewRefresh::default();
+        for option in options {
+            match (option.name.value.to_lowercase().as_str(), &option.value) {
+                ("refresh", SqlValue::SingleQuotedString(value)) if materialized => {
+                    refresh = ViewRefresh::parse(value)?;
+                }
+                _ => return Err(QubeError::Unsupported(format!("view option {}", option))),
+            }
+        }
+        Ok(materialized.then_some(refresh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::QueryEngine;
+    use crate::types::Value;
+
+    /// The row count and total of the `totals` view
+    fn totals(engine: &QueryEngine) -> (Value, Value) {
+        let result = engine
+            .execute_script("SELECT n, total FROM totals")
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(result.rows.len(), 1);
+        (result.rows[0]["n"].clone(), result.rows[0]["total"].clone())
+    }
+
+    fn engine_with_totals(options: &str) -> QueryEngine {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(&format!(
+                "CREATE TABLE sales (category TEXT, amount BIGINT);
+                 INSERT INTO sales VALUES ('a', 1), ('a', 2), ('b', 5);
+                 CREATE MATERIALIZED VIEW totals {} AS
+                 SELECT COUNT(*) AS n, SUM(amount) AS total FROM sales",
+                options
+            ))
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn materialized_views_keep_their_rows_until_refreshed() {
+        let engine = engine_with_totals("");
+        assert_eq!(totals(&engine), (Value::Int64(3), Value::Int64(8)));
+
+        engine
+            .execute_script("INSERT INTO sales VALUES ('a', 10), ('c', 1)")
+            .unwrap();
+        assert_eq!(totals(&engine), (Value::Int64(3), Value::Int64(8)));
+
+        // REFRESH runs alongside other statements in a script
+        let results = engine
+            .execute_script(
+                "DELETE FROM sales WHERE category = 'b';
+                 REFRESH MATERIALIZED VIEW totals;
+                 SELECT total FROM totals",
+            )
+            .unwrap();
+        assert_eq!(results[2].rows[0]["total"], Value::Int64(14));
+        assert_eq!(totals(&engine), (Value::Int64(4), Value::Int64(14)));
+    }
+
+    #[test]
+    fn on_write_views_refresh_after_every_write() {
+        let engine = engine_with_totals("WITH (refresh = 'on_write')");
+        engine
+            .execute_script("UPDATE sales SET amount = 4 WHERE category = 'b'")
+            .unwrap();
+        assert_eq!(totals(&engine), (Value::Int64(3), Value::Int64(7)));
+        engine
+            .execute_script("INSERT INTO sales VALUES ('c', 7)")
+            .unwrap();
+        assert_eq!(totals(&engine), (Value::Int64(4), Value::Int64(14)));
+        engine.execute_script("DELETE FROM sales").unwrap();
+        assert_eq!(totals(&engine), (Value::Int64(0), Value::Null));
+    }
+
+    #[test]
+    fn views_run_their_query_when_read() {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE sales (category TEXT, amount BIGINT);
+                 INSERT INTO sales VALUES ('a', 1), ('b', 5);
+                 CREATE VIEW big_sales (kind, amount) AS
+                 SELECT category, amount FROM sales WHERE amount > 1",
+            )
+            .unwrap();
+        let kinds = |sql: &str| -> Vec<Value> {
+            engine.execute_script(sql).unwrap()[0]
+                .rows
+                .iter()
+                .map(|row| row["kind"].clone())
+                .collect()
+        };
+        let text = |value: &str| Value::String(value.to_string());
+        assert_eq!(kinds("SELECT kind FROM big_sales"), [text("b")]);
+
+        // A view reflects the table as it is now, and takes more predicates
+        engine
+            .execute_script("INSERT INTO sales VALUES ('c', 9), ('d', 2)")
+            .unwrap();
+        assert_eq!(
+            kinds("SELECT kind FROM big_sales ORDER BY kind"),
+            [text("b"), text("c"), text("d")]
+        );
+        assert_eq!(
+            kinds("SELECT kind FROM big_sales WHERE amount < 9 ORDER BY kind"),
+            [text("b"), text("d")]
+        );
+        assert!(engine
+            .execute_script("INSERT INTO big_sales VALUES ('e', 3)")
+            .is_err());
+
+        // Once its table is dropped the view fails, and can itself be dropped
+        engine.execute_script("DROP TABLE sales").unwrap();
+        assert!(engine.execute_script("SELECT kind FROM big_sales").is_err());
+        engine.execute_script("DROP VIEW big_sales").unwrap();
+        assert!(engine.list_tables().is_empty());
+    }
+}
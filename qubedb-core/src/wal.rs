@@ -0,0 +1,877 @@
+//! Write-ahead log for QubeDB
+//!
+//! Records are appended to a single file and only reported as written once
+//! they have been synced to disk. Syncing is shared between concurrent
+//! writers (group commit): the first writer to need a sync waits a short
+//! window for others to append, then one `fsync` makes the whole batch
+//! durable. Writers arriving while a sync is running join the next batch.
+//! Records become durable in the order they were appended.
+//!
+//! Not every write needs to wait for a sync. A record appended with
+//! `Durability::Async` is only buffered; a background thread flushes and
+//! syncs buffered records every `async_flush_interval`. If the process or
+//! machine crashes, async records appended within the last interval (plus
+//! the time a sync takes) may be lost, though never out of order: whatever
+//! survives is a prefix of the log. `Durability::None` skips the log
+//! entirely, so the write lives only in memory.
+//!
+//! Each record is framed as its payload length, its log sequence number
+//! (LSN), the payload and a checksum, so a record torn by a crash is
+//! detected and dropped when the log is reopened.
+//!
+//! A `WalTail` follows the log from a given LSN, turning each record back
+//! into the statements it committed, so external systems such as search
+//! indexes or caches can keep in sync. Saving its `position` lets a
+//! consumer resume where it stopped.
+//!
+//! A checkpoint replaces the whole log with a single record that rebuilds
+//! the current state, numbered with the last LSN written, so the space
+//! taken by the history of overwritten and deleted rows is given back.
+//! Tails that were behind it read that record next; tails that were not
+//! carry on with the records after it.
+
+use crate::error::{QubeError, QubeResult};
+use crate::query;
+use serde::Serialize;
+use sqlparser::ast::Statement;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+/// Default time the first writer of a batch waits for others to join it
+pub const DEFAULT_GROUP_COMMIT_WINDOW: Duration = Duration::from_millis(2);
+
+/// Default number of pending records that ends the wait early
+pub const DEFAULT_MAX_BATCH: usize = 128;
+
+/// Default interval between background flushes of async records
+pub const DEFAULT_ASYNC_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bytes of framing around each payload: length, LSN and checksum
+const FRAME_OVERHEAD: usize = 4 + 8 + 4;
+
+/// How often `WalTail::wait` looks for new records
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Group commit settings
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    /// How long the first writer of a batch waits for others to append
+    /// before syncing. Zero syncs straight away; writers that arrive during
+    /// a sync are still batched into the next one.
+    pub group_commit_window: Duration,
+    /// Sync as soon as this many records are waiting, without waiting out
+    /// the window
+    pub max_batch: usize,
+    /// How often buffered `Durability::Async` records are synced. This is
+    /// the window of async writes a crash can lose.
+    pub async_flush_interval: Duration,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            group_commit_window: DEFAULT_GROUP_COMMIT_WINDOW,
+            max_batch: DEFAULT_MAX_BATCH,
+            async_flush_interval: DEFAULT_ASYNC_FLUSH_INTERVAL,
+        }
+    }
+}
+
+/// How much a write waits for before it is reported as done
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum Durability {
+    /// Synced to disk before returning. Survives any crash.
+    #[default]
+    Sync,
+    /// Buffered and synced by the next background flush. A crash can lose
+    /// the writes of the last `async_flush_interval`.
+    Async,
+    /// Not logged at all. Lost when the process exits.
+    None,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Durability::Sync => "sync",
+            Durability::Async => "async",
+            Durability::None => "none",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Durability {
+    type Err = QubeError;
+
+    fn from_str(s: &str) -> QubeResult<Self> {
+        match s.to_lowercase().as_str() {
+            "sync" => Ok(Durability::Sync),
+            "async" => Ok(Durability::Async),
+            "none" => Ok(Durability::None),
+            _ => Err(QubeError::Other(format!(
+                "Unknown durability level '{}': expected sync, async or none",
+                s
+            ))),
+        }
+    }
+}
+
+/// One record read back from the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub lsn: u64,
+    pub payload: Vec<u8>,
+}
+
+/// The writes one log record committed
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalChange {
+    pub lsn: u64,
+    /// Statements applied together, in order
+    pub statements: Vec<Statement>,
+}
+
+/// Counters describing a log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct WalMetrics {
+    /// Records appended since the log was opened
+    pub appends: u64,
+    /// Syncs to disk since the log was opened
+    pub fsyncs: u64,
+    pub bytes_written: u64,
+    /// LSN of the last record known to be on disk
+    pub durable_lsn: u64,
+    /// Checkpoints since the log was opened
+    pub checkpoints: u64,
+}
+
+struct State {
+    writer: BufWriter<File>,
+    next_lsn: u64,
+    /// LSN of the last record appended, durable or not
+    written_lsn: u64,
+    /// Set while a writer is leading a batch
+    syncing: bool,
+    metrics: WalMetrics,
+}
+
+/// An append-only log with group commit
+pub struct Wal {
+    inner: Arc<Inner>,
+}
+
+/// The log itself, shared with the background flusher
+struct Inner {
+    path: PathBuf,
+    config: WalConfig,
+    /// Second handle on the log file, synced without holding the state lock
+    /// so writers can keep appending during a sync
+    sync_file: Mutex<File>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if needed. A torn record at the
+    /// end of the file is truncated away.
+    pub fn open<P: AsRef<Path>>(path: P, config: WalConfig) -> QubeResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let (records, valid_len) = if path.exists() {
+            read_records(&path)?
+        } else {
+            (Vec::new(), 0)
+        };
+        let last_lsn = records.last().map_or(0, |record| record.lsn);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        file.set_len(valid_len)?;
+        let mut writer = BufWriter::new(file);
+        writer.seek(SeekFrom::End(0))?;
+        let sync_file = writer.get_ref().try_clone()?;
+
+        let inner = Arc::new(Inner {
+            path,
+            config: WalConfig {
+                max_batch: config.max_batch.max(1),
+                async_flush_interval: config.async_flush_interval.max(Duration::from_millis(1)),
+                ..config
+            },
+            sync_file: Mutex::new(sync_file),
+            state: Mutex::new(State {
+                writer,
+                next_lsn: last_lsn + 1,
+                written_lsn: last_lsn,
+                syncing: false,
+                metrics: WalMetrics {
+                    durable_lsn: last_lsn,
+                    ..Default::default()
+                },
+            }),
+            changed: Condvar::new(),
+        });
+        spawn_flusher(&inner)?;
+        Ok(Self { inner })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    pub fn config(&self) -> &WalConfig {
+        &self.inner.config
+    }
+
+    /// Append a record and wait until it is on disk. Returns its LSN.
+    pub fn append(&self, payload: &[u8]) -> QubeResult<u64> {
+        let mut state = self.inner.state.lock().unwrap();
+        let lsn = self.inner.write(&mut state, payload)?;
+        self.inner
+            .wait_durable(state, lsn, self.inner.config.group_commit_window)?;
+        Ok(lsn)
+    }
+
+    /// Append a record at the given durability. `Sync` waits until the
+    /// record is on disk, `Async` returns once it is buffered, and `None`
+    /// writes nothing. Returns the LSN, if the record was logged.
+    pub fn append_with(&self, payload: &[u8], durability: Durability) -> QubeResult<Option<u64>> {
+        match durability {
+            Durability::Sync => self.append(payload).map(Some),
+            Durability::Async => {
+                let mut state = self.inner.state.lock().unwrap();
+                self.inner.write(&mut state, payload).map(Some)
+            }
+            Durability::None => Ok(None),
+        }
+    }
+
+    /// Sync every record appended so far, including buffered async ones
+    pub fn sync(&self) -> QubeResult<()> {
+        self.inner.sync()
+    }
+
+    /// LSN of the last record known to be on disk
+    pub fn durable_lsn(&self) -> u64 {
+        self.metrics().durable_lsn
+    }
+
+    pub fn metrics(&self) -> WalMetrics {
+        self.inner.state.lock().unwrap().metrics
+    }
+
+    /// Read every intact record of the log at `path`, in LSN order
+    pub fn read<P: AsRef<Path>>(path: P) -> QubeResult<Vec<WalRecord>> {
+        read_records(path.as_ref()).map(|(records, _)| records)
+    }
+
+    /// Follow this log from `from_lsn` on. Only records already on disk
+    /// are returned, so a change is never seen that a crash could undo.
+    pub fn tail(&self, from_lsn: u64) -> QubeResult<WalTail> {
+        let mut tail = WalTail::open(&self.inner.path, from_lsn)?;
+        tail.checkpoints = self.metrics().checkpoints;
+        tail.log = Some(Arc::downgrade(&self.inner));
+        Ok(tail)
+    }
+
+    /// Replace every record with one holding `payload`, which must rebuild
+    /// what the records did, numbered with the last LSN written. The new
+    /// log is written beside the old one and renamed over it, so a crash
+    /// leaves one or the other. Returns the bytes the log shrank by.
+    pub fn checkpoint(&self, payload: &[u8]) -> QubeResult<u64> {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.syncing {
+            state = self.inner.changed.wait(state).unwrap();
+        }
+        let lsn = state.written_lsn;
+        if lsn == 0 {
+            return Ok(0);
+        }
+        state.writer.flush()?;
+        let old_len = state.writer.get_ref().metadata()?.len();
+
+        let path = &self.inner.path;
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".checkpoint");
+        let temporary = path.with_file_name(name);
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        write_frame(&mut writer, lsn, payload)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&temporary, path)?;
+        if let Some(parent) = path.parent().and_then(|parent| File::open(parent).ok()) {
+            // Make the rename itself durable where directories can be synced
+            let _ = parent.sync_all();
+        }
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        let new_len = file.metadata()?.len();
+        let mut writer = BufWriter::new(file);
+        writer.seek(SeekFrom::End(0))?;
+        *self.inner.sync_file.lock().unwrap() = writer.get_ref().try_clone()?;
+        state.writer = writer;
+        state.metrics.durable_lsn = lsn;
+        state.metrics.checkpoints += 1;
+        self.inner.changed.notify_all();
+        Ok(old_len.saturating_sub(new_len))
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Err(e) = self.inner.sync() {
+            tracing::warn!("Failed to sync {}: {}", self.inner.path.display(), e);
+        }
+    }
+}
+
+impl std::fmt::Debug for Wal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wal")
+            .field("path", &self.inner.path)
+            .field("config", &self.inner.config)
+            .field("metrics", &self.metrics())
+            .finish()
+    }
+}
+
+impl Inner {
+    /// Buffer a record without syncing it. Returns its LSN.
+    fn write(&self, state: &mut State, payload: &[u8]) -> QubeResult<u64> {
+        let lsn = state.next_lsn;
+        write_frame(&mut state.writer, lsn, payload)?;
+        state.next_lsn += 1;
+        state.written_lsn = lsn;
+        state.metrics.appends += 1;
+        state.metrics.bytes_written += (payload.len() + FRAME_OVERHEAD) as u64;
+        // A leader waiting out its window may now have a full batch
+        self.changed.notify_all();
+        Ok(lsn)
+    }
+
+    /// Sync everything written so far, without waiting for other writers
+    fn sync(&self) -> QubeResult<()> {
+        let state = self.state.lock().unwrap();
+        let lsn = state.written_lsn;
+        self.wait_durable(state, lsn, Duration::ZERO)
+    }
+
+    /// Wait until `lsn` is on disk, leading a batch if no sync is running.
+    /// A leader waits up to `window` for other writers to append first.
+    fn wait_durable<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        lsn: u64,
+        window: Duration,
+    ) -> QubeResult<()> {
+        loop {
+            if state.metrics.durable_lsn >= lsn {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+
+            // Lead a batch: give other writers a moment to append, then
+            // sync everything written so far
+            state.syncing = true;
+            let deadline = Instant::now() + window;
+            while state.written_lsn - state.metrics.durable_lsn < self.config.max_batch as u64 {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                state = self.changed.wait_timeout(state, remaining).unwrap().0;
+            }
+
+            let target = state.written_lsn;
+            let flushed = state.writer.flush();
+            drop(state);
+            let synced = flushed.and_then(|_| self.sync_file.lock().unwrap().sync_data());
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.changed.notify_all();
+            synced?;
+            state.metrics.fsyncs += 1;
+            state.metrics.durable_lsn = state.metrics.durable_lsn.max(target);
+        }
+    }
+}
+
+/// Follows a log as it grows, yielding the changes of each record from a
+/// starting LSN on. Iterating yields the changes available now and ends
+/// when it catches up; iterating again later picks up newer ones, and
+/// `wait` blocks for the next.
+pub struct WalTail {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /// File offset just past the last whole record read
+    offset: u64,
+    next_lsn: u64,
+    /// Checkpoints of the log already accounted for
+    checkpoints: u64,
+    /// The log being written, when tailing through `Wal::tail`
+    log: Option<Weak<Inner>>,
+}
+
+impl WalTail {
+    /// Follow the log at `path`, which may be written by another process,
+    /// from `from_lsn` on. Any intact record counts, synced or not.
+    pub fn open<P: AsRef<Path>>(path: P, from_lsn: u64) -> QubeResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(WalTail {
+            path,
+            reader,
+            offset: 0,
+            next_lsn: from_lsn,
+            checkpoints: 0,
+            log: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// LSN of the next record to read. Saved, it resumes tailing when
+    /// passed to `Wal::tail` or `WalTail::open`.
+    pub fn position(&self) -> u64 {
+        self.next_lsn
+    }
+
+    /// Move to `lsn`, so the next record read is the first at or after it
+    pub fn seek(&mut self, lsn: u64) -> QubeResult<()> {
+        if lsn < self.next_lsn {
+            self.offset = 0;
+            self.reader.seek(SeekFrom::Start(0))?;
+        }
+        self.next_lsn = lsn;
+        Ok(())
+    }
+
+    /// The next record, or `None` if there is none yet
+    pub fn next_record(&mut self) -> QubeResult<Option<WalRecord>> {
+        let (limit, checkpoints) = match self.log.as_ref().and_then(Weak::upgrade) {
+            Some(log) => {
+                let metrics = log.state.lock().unwrap().metrics;
+                (metrics.durable_lsn, metrics.checkpoints)
+            }
+            None => (u64::MAX, self.checkpoints),
+        };
+        // A checkpoint renamed a new, shorter file over the one being read
+        if checkpoints != self.checkpoints || std::fs::metadata(&self.path)?.len() < self.offset {
+            self.reader = BufReader::new(File::open(&self.path)?);
+            self.offset = 0;
+            self.checkpoints = checkpoints;
+        }
+        loop {
+            match self.read_frame(limit)? {
+                Some(record) if record.lsn < self.next_lsn => continue,
+                Some(record) => {
+                    self.next_lsn = record.lsn + 1;
+                    return Ok(Some(record));
+                }
+                None => {
+                    // Leave a record still being written for next time
+                    self.reader.seek(SeekFrom::Start(self.offset))?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// The next change, or `None` if there is none yet
+    pub fn next_change(&mut self) -> QubeResult<Option<WalChange>> {
+        let Some(record) = self.next_record()? else {
+            return Ok(None);
+        };
+        let sql = String::from_utf8(record.payload).map_err(|_| {
+            QubeError::Storage(format!("WAL record {} is not valid UTF-8", record.lsn))
+        })?;
+        Ok(Some(WalChange {
+            lsn: record.lsn,
+            statements: query::parse_script(&sql)?,
+        }))
+    }
+
+    /// Wait up to `timeout` for the next change
+    pub fn wait(&mut self, timeout: Duration) -> QubeResult<Option<WalChange>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(change) = self.next_change()? {
+                return Ok(Some(change));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            std::thread::sleep(remaining.min(TAIL_POLL_INTERVAL));
+        }
+    }
+
+    /// Read the record at the current offset if it is whole and its LSN is
+    /// at most `limit`
+    fn read_frame(&mut self, limit: u64) -> QubeResult<Option<WalRecord>> {
+        let mut header = [0u8; 12];
+        if !read_exact_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let lsn = u64::from_le_bytes(header[4..].try_into().unwrap());
+        if lsn > limit {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; len];
+        let mut sum = [0u8; 4];
+        if !read_exact_or_eof(&mut self.reader, &mut payload)?
+            || !read_exact_or_eof(&mut self.reader, &mut sum)?
+            || u32::from_le_bytes(sum) != checksum(lsn, &payload)
+        {
+            return Ok(None);
+        }
+        self.offset += (len + FRAME_OVERHEAD) as u64;
+        Ok(Some(WalRecord { lsn, payload }))
+    }
+}
+
+impl Iterator for WalTail {
+    type Item = QubeResult<WalChange>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
+
+impl std::fmt::Debug for WalTail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalTail")
+            .field("path", &self.path)
+            .field("position", &self.next_lsn)
+            .finish()
+    }
+}
+
+/// Start the thread that syncs buffered async records every
+/// `async_flush_interval`. It stops once the log is dropped.
+fn spawn_flusher(inner: &Arc<Inner>) -> QubeResult<()> {
+    let log = Arc::downgrade(inner);
+    let interval = inner.config.async_flush_interval;
+    std::thread::Builder::new()
+        .name("qubedb-wal-flush".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(inner) = log.upgrade() else {
+                break;
+            };
+            if let Err(e) = inner.sync() {
+                tracing::warn!("Failed to sync {}: {}", inner.path.display(), e);
+            }
+        })?;
+    Ok(())
+}
+
+fn write_frame(writer: &mut impl Write, lsn: u64, payload: &[u8]) -> QubeResult<()> {
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        QubeError::Storage(format!(
+            "WAL record of {} bytes is too large",
+            payload.len()
+        ))
+    })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&lsn.to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.write_all(&checksum(lsn, payload).to_le_bytes())?;
+    Ok(())
+}
+
+/// Read the intact records of a log, along with the length of the file they
+/// occupy. Reading stops at the first torn or corrupt record.
+fn read_records(path: &Path) -> QubeResult<(Vec<WalRecord>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0u64;
+    loop {
+        let mut header = [0u8; 12];
+        if !read_exact_or_eof(&mut reader, &mut header)? {
+            break;
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let lsn = u64::from_le_bytes(header[4..].try_into().unwrap());
+        let mut payload = vec![0u8; len];
+        let mut sum = [0u8; 4];
+        if !read_exact_or_eof(&mut reader, &mut payload)?
+            || !read_exact_or_eof(&mut reader, &mut sum)?
+            || u32::from_le_bytes(sum) != checksum(lsn, &payload)
+        {
+            break;
+        }
+        valid_len += (len + FRAME_OVERHEAD) as u64;
+        records.push(WalRecord { lsn, payload });
+    }
+    Ok((records, valid_len))
+}
+
+/// Fill `buf`, returning false if the input ends first
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> QubeResult<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// FNV-1a over a record's LSN and payload
+fn checksum(lsn: u64, payload: &[u8]) -> u32 {
+    lsn.to_le_bytes()
+        .iter()
+        .chain(payload)
+        .fold(0x811c_9dc5u32, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log path no other test uses, removed if it exists
+    fn wal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qubedb-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// A config whose background flusher never runs during a test
+    fn manual_flush() -> WalConfig {
+        WalConfig {
+            async_flush_interval: Duration::from_secs(3600),
+            ..WalConfig::default()
+        }
+    }
+
+    fn payloads(path: &Path) -> Vec<(u64, Vec<u8>)> {
+        Wal::read(path)
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.lsn, record.payload))
+            .collect()
+    }
+
+    #[test]
+    fn a_torn_trailing_record_is_dropped_on_open() {
+        let path = wal_path("torn");
+        let wal = Wal::open(&path, WalConfig::default()).unwrap();
+        wal.append(b"first").unwrap();
+        wal.append(b"second").unwrap();
+        drop(wal);
+        let intact = std::fs::metadata(&path).unwrap().len();
+
+        // A crash in the middle of writing a third record
+        let mut torn = Vec::new();
+        write_frame(&mut torn, 3, b"third").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+        assert_eq!(payloads(&path).len(), 2);
+
+        let wal = Wal::open(&path, WalConfig::default()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+        assert_eq!(wal.append(b"third").unwrap(), 3);
+        drop(wal);
+        assert_eq!(
+            payloads(&path),
+            [
+                (1, b"first".to_vec()),
+                (2, b"second".to_vec()),
+                (3, b"third".to_vec())
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn async_records_are_on_disk_after_a_sync() {
+        let path = wal_path("async");
+        let wal = Wal::open(&path, manual_flush()).unwrap();
+        let lsn = wal
+            .append_with(b"buffered", Durability::Async)
+            .unwrap()
+            .unwrap();
+        assert_eq!(lsn, 1);
+        assert_eq!(wal.durable_lsn(), 0);
+
+        wal.sync().unwrap();
+        assert_eq!(wal.durable_lsn(), 1);
+        assert_eq!(payloads(&path), [(1, b"buffered".to_vec())]);
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unlogged_records_write_nothing() {
+        let path = wal_path("unlogged");
+        let wal = Wal::open(&path, manual_flush()).unwrap();
+        assert_eq!(wal.append_with(b"lost", Durability::None).unwrap(), None);
+        wal.sync().unwrap();
+        assert_eq!(wal.metrics().appends, 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        // The next logged record still takes the first LSN
+        assert_eq!(wal.append_with(b"kept", Durability::Sync).unwrap(), Some(1));
+        drop(wal);
+        assert_eq!(payloads(&path), [(1, b"kept".to_vec())]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn lsns(tail: &mut WalTail) -> Vec<u64> {
+        tail.map(|change| change.unwrap().lsn).collect()
+    }
+
+    #[test]
+    fn a_tail_resumes_from_its_saved_position() {
+        let path = wal_path("tail-resume");
+        let wal = Wal::open(&path, WalConfig::default()).unwrap();
+        wal.append(b"CREATE TABLE t (id INT)").unwrap();
+        wal.append(b"INSERT INTO t VALUES (1)").unwrap();
+
+        let mut tail = wal.tail(1).unwrap();
+        let change = tail.next_change().unwrap().unwrap();
+        assert_eq!(change.lsn, 1);
+        assert!(matches!(
+            change.statements[..],
+            [Statement::CreateTable { .. }]
+        ));
+        assert_eq!(lsns(&mut tail), [2]);
+        let position = tail.position();
+        assert_eq!(position, 3);
+        drop(tail);
+
+        wal.append(b"INSERT INTO t VALUES (2); INSERT INTO t VALUES (3)")
+            .unwrap();
+        let mut tail = wal.tail(position).unwrap();
+        let change = tail.next_change().unwrap().unwrap();
+        assert_eq!(change.lsn, 3);
+        assert_eq!(change.statements.len(), 2);
+        assert!(tail.next_change().unwrap().is_none());
+
+        // A tail of the file alone resumes the same way
+        let mut tail = WalTail::open(&path, 2).unwrap();
+        assert_eq!(lsns(&mut tail), [2, 3]);
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_tail_behind_a_checkpoint_reads_the_rebuild_record() {
+        let path = wal_path("tail-checkpoint");
+        let wal = Wal::open(&path, WalConfig::default()).unwrap();
+        wal.append(b"CREATE TABLE t (id INT)").unwrap();
+        wal.append(b"INSERT INTO t VALUES (1)").unwrap();
+        wal.append(b"DELETE FROM t").unwrap();
+
+        let mut behind = wal.tail(1).unwrap();
+        assert_eq!(behind.next_change().unwrap().unwrap().lsn, 1);
+        let mut caught_up = wal.tail(1).unwrap();
+        assert_eq!(lsns(&mut caught_up), [1, 2, 3]);
+
+        assert!(wal.checkpoint(b"CREATE TABLE t (id INT)").unwrap() > 0);
+        let change = behind.next_change().unwrap().unwrap();
+        assert_eq!(change.lsn, 3);
+        assert!(matches!(
+            change.statements[..],
+            [Statement::CreateTable { .. }]
+        ));
+        assert!(behind.next_change().unwrap().is_none());
+        assert!(caught_up.next_change().unwrap().is_none());
+
+        // Both carry on with the records after the checkpoint
+        wal.append(b"INSERT INTO t VALUES (2)").unwrap();
+        assert_eq!(lsns(&mut behind), [4]);
+        assert_eq!(lsns(&mut caught_up), [4]);
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_tail_only_sees_records_on_disk() {
+        let path = wal_path("tail-durable");
+        let wal = Wal::open(&path, manual_flush()).unwrap();
+        wal.append(b"CREATE TABLE t (id INT)").unwrap();
+        wal.append_with(b"INSERT INTO t VALUES (1)", Durability::Async)
+            .unwrap();
+        let mut tail = wal.tail(1).unwrap();
+        assert_eq!(lsns(&mut tail), [1]);
+
+        wal.sync().unwrap();
+        assert_eq!(lsns(&mut tail), [2]);
+        assert_eq!(tail.position(), 3);
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_tail_waits_for_the_next_change() {
+        let path = wal_path("tail-wait");
+        let wal = Arc::new(Wal::open(&path, WalConfig::default()).unwrap());
+        let mut tail = wal.tail(1).unwrap();
+        assert!(tail.wait(Duration::from_millis(20)).unwrap().is_none());
+
+        let writer = {
+            let wal = Arc::clone(&wal);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                wal.append(b"CREATE TABLE t (id INT)").unwrap();
+            })
+        };
+        let change = tail.wait(Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(change.lsn, 1);
+        writer.join().unwrap();
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_record_still_being_written_is_read_once_whole() {
+        let path = wal_path("tail-torn");
+        let wal = Wal::open(&path, WalConfig::default()).unwrap();
+        wal.append(b"CREATE TABLE t (id INT)").unwrap();
+        drop(wal);
+        let mut tail = WalTail::open(&path, 0).unwrap();
+        assert_eq!(lsns(&mut tail), [1]);
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, 2, b"INSERT INTO t VALUES (1)").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&frame[..5]).unwrap();
+        assert!(tail.next_change().unwrap().is_none());
+        file.write_all(&frame[5..]).unwrap();
+        drop(file);
+        assert_eq!(lsns(&mut tail), [2]);
+
+        tail.seek(1).unwrap();
+        assert_eq!(lsns(&mut tail), [1, 2]);
+        tail.seek(2).unwrap();
+        assert_eq!(lsns(&mut tail), [2]);
+
+        std::fs::write(&path, b"not a record").unwrap();
+        let mut garbled = WalTail::open(&path, 0).unwrap();
+        assert!(garbled.next_change().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Window functions for QubeDB queries
+//!
+//! A window function computes a value for every row from the rows around
+//! it: `ROW_NUMBER() OVER (PARTITION BY dept ORDER BY salary DESC)`. Rows
+//! are split into partitions by the PARTITION BY values and ordered within
+//! each by the window's ORDER BY. Supported are `ROW_NUMBER`, `RANK`,
+//! `DENSE_RANK` and the aggregates COUNT, SUM, AVG, MIN and MAX.
+//!
+//! An aggregate over a window with ORDER BY is running: it covers the
+//! partition from its first row through the current row and every row
+//! tied with it. Without ORDER BY it covers the whole partition. Explicit
+//! frames (`ROWS BETWEEN ...`) are not supported.
+//!
+//! Window values are computed after WHERE and before the query's own
+//! ORDER BY, OFFSET and LIMIT, and do not change the order of the rows.
+
+use crate::aggregate::{self, Accumulator};
+use crate::error::{QubeError, QubeResult};
+use crate::expr;
+use crate::query::{compare_sort_keys, ExecutionContext};
+use crate::types::{Row, Value};
+use sqlparser::ast::{
+    visit_expressions, visit_expressions_mut, Expr, Function, Ident, OrderByExpr, SelectItem,
+    WindowType,
+};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+/// A ranking function, computed from a row's position in its partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankingFunction {
+    /// 1, 2, 3, ... in window order; ties are numbered arbitrarily
+    RowNumber,
+    /// Position of the first row tied with this one, leaving gaps after ties
+    Rank,
+    /// Like `Rank` but without gaps
+    DenseRank,
+}
+
+impl RankingFunction {
+    /// The ranking function a function name refers to, if any
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "ROW_NUMBER" => Some(RankingFunction::RowNumber),
+            "RANK" => Some(RankingFunction::Rank),
+            "DENSE_RANK" => Some(RankingFunction::DenseRank),
+            _ => None,
+        }
+    }
+}
+
+/// What a window call computes
+enum WindowFunction {
+    Ranking(RankingFunction),
+    /// An aggregate's argument (`None` for `COUNT(*)`) and empty state
+    Aggregate(Option<Expr>, Box<Accumulator>),
+}
+
+/// One window call, e.g. `RANK() OVER (PARTITION BY dept ORDER BY pay)`
+struct WindowCall {
+    function: WindowFunction,
+    partition_by: Vec<Expr>,
+    order_by: Vec<OrderByExpr>,
+}
+
+/// Whether a SELECT list calls any window function
+pub fn has_window_functions(items: &[SelectItem]) -> bool {
+    items.iter().any(|item| match item {
+        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+            contains_window(expr)
+        }
+        _ => false,
+    })
+}
+
+/// Whether an expression calls a window function anywhere inside it
+pub fn contains_window(expr: &Expr) -> bool {
+    visit_expressions(expr, |e| match e {
+        Expr::Function(function) if function.over.is_some() => ControlFlow::Break(()),
+        _ => ControlFlow::Continue(()),
+    })
+    .is_break()
+}
+
+/// Compute every window call in `projection` for each of `rows`. The
+/// values are stored in the rows, and the projection returned reads them
+/// in place of the calls.
+pub(crate) fn apply_windows(
+    projection: &[(String, Expr)],
+    rows: &mut [Cow<'_, Row>],
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<(String, Expr)>> {
+    let mut calls = Vec::new();
+    let mut outputs = Vec::with_capacity(projection.len());
+    for (name, expr) in projection {
+        let mut expr = expr.clone();
+        let mut error = None;
+        let _ = visit_expressions_mut(&mut expr, |e| {
+            let function = match e {
+                Expr::Function(function) if function.over.is_some() => function,
+                _ => return ControlFlow::Continue(()),
+            };
+            match window_call(function) {
+                Ok(call) => {
+                    *e = Expr::Identifier(Ident::new(result_key(calls.len())));
+                    calls.push(call);
+                    ControlFlow::Continue(())
+                }
+                Err(e) => {
+                    error = Some(e);
+                    ControlFlow::Break(())
+                }
+            }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        outputs.push((name.clone(), expr));
+    }
+
+    for (i, call) in calls.into_iter().enumerate() {
+        let values = evaluate_call(call, rows, ctx)?;
+        let key = result_key(i);
+        for (row, value) in rows.iter_mut().zip(values) {
+            row.to_mut().insert(key.clone(), value);
+        }
+    }
+    Ok(outputs)
+}
+
+/// Name under which the result of the `i`th window call is stored
+fn result_key(i: usize) -> String {
+    format!("#w{}", i)
+}
+
+/// Parse a call with OVER
+fn window_call(function: &Function) -> QubeResult<WindowCall> {
+    let window = match function.over.as_ref().expect("a window call has OVER") {
+        WindowType::WindowSpec(spec) => spec,
+        WindowType::NamedWindow(name) => {
+            return Err(QubeError::Unsupported(format!("named window {}", name)))
+        }
+    };
+    if window.window_frame.is_some() {
+        return Err(QubeError::Unsupported(format!(
+            "window frame in {}",
+            window
+        )));
+    }
+    let nested = window
+        .partition_by
+        .iter()
+        .chain(window.order_by.iter().map(|order| &order.expr))
+        .any(|e| contains_window(e) || aggregate::contains_aggregate(e));
+    if nested {
+        return Err(QubeError::QueryParse(
+            "Window and aggregate function calls cannot be nested in a window".to_string(),
+        ));
+    }
+
+    let function_kind =
+        if let Some(ranking) = RankingFunction::from_name(&function.name.to_string()) {
+            if !function.args.is_empty() || function.distinct {
+                return Err(QubeError::QueryParse(format!(
+                    "Invalid arguments to {}",
+                    function.name
+                )));
+            }
+            WindowFunction::Ranking(ranking)
+        } else {
+            match aggregate::window_aggregate(function)? {
+                Some((argument, accumulator)) => {
+                    if argument.as_ref().is_some_and(contains_window) {
+                        return Err(QubeError::QueryParse(
+                            "Window function calls cannot be nested".to_string(),
+                        ));
+                    }
+                    WindowFunction::Aggregate(argument, Box::new(accumulator))
+                }
+                None => {
+                    return Err(QubeError::QueryParse(format!(
+                        "{} is not a window function",
+                        function.name
+                    )))
+                }
+            }
+        };
+    Ok(WindowCall {
+        function: function_kind,
+        partition_by: window.partition_by.clone(),
+        order_by: window.order_by.clone(),
+    })
+}
+
+/// The value of one window call for each row, in row order
+fn evaluate_call(
+    call: WindowCall,
+    rows: &[Cow<'_, Row>],
+    ctx: &mut ExecutionContext,
+) -> QubeResult<Vec<Value>> {
+    // Group row positions by partition, in order of first appearance
+    let mut partitions: Vec<Vec<usize>> = Vec::new();
+    let mut positions: HashMap<Vec<Value>, usize> = HashMap::new();
+    let mut sort_keys = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        ctx.tick()?;
+        let partition = call
+            .partition_by
+            .iter()
+            .map(|e| expr::evaluate(e, row))
+            .collect::<QubeResult<Vec<_>>>()?;
+        let keys = call
+            .order_by
+            .iter()
+            .map(|order| expr::evaluate(&order.expr, row))
+            .collect::<QubeResult<Vec<_>>>()?;
+        sort_keys.push(keys);
+        let next = partitions.len();
+        let position = *positions.entry(partition).or_insert(next);
+        if position == next {
+            partitions.push(Vec::new());
+        }
+        partitions[position].push(i);
+    }
+
+    let mut values = vec![Value::Null; rows.len()];
+    for mut partition in partitions {
+        partition.sort_by(|&a, &b| compare_sort_keys(&sort_keys[a], &sort_keys[b], &call.order_by));
+        let tied = |a: usize, b: usize| {
+            compare_sort_keys(&sort_keys[a], &sort_keys[b], &call.order_by) == Ordering::Equal
+        };
+
+        match &call.function {
+            WindowFunction::Ranking(function) => {
+                let mut rank = 0;
+                let mut dense_rank = 0;
+                for (position, &row) in partition.iter().enumerate() {
+                    if position == 0 || !tied(partition[position - 1], row) {
+                        rank = position + 1;
+                        dense_rank += 1;
+                    }
+                    let value = match function {
+                        RankingFunction::RowNumber => position + 1,
+                        RankingFunction::Rank => rank,
+                        RankingFunction::DenseRank => dense_rank,
+                    };
+                    values[row] = Value::Int64(value as i64);
+                }
+            }
+            WindowFunction::Aggregate(argument, empty) => {
+                // Rows tied in window order (all of them, without ORDER BY)
+                // share the value computed through the last of them
+                let mut accumulator = (**empty).clone();
+                let mut start = 0;
+                while start < partition.len() {
+                    let mut end = start + 1;
+                    while end < partition.len() && tied(partition[start], partition[end]) {
+                        end += 1;
+                    }
+                    for &row in &partition[start..end] {
+                        let value = match argument {
+                            Some(argument) => Some(expr::evaluate(argument, &rows[row])?),
+                            None => None,
+                        };
+                        accumulator.add(value)?;
+                    }
+                    let value = accumulator.clone().finish();
+                    for &row in &partition[start..end] {
+                        values[row] = value.clone();
+                    }
+                    start = end;
+                }
+            }
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::QueryEngine;
+    use crate::types::{Row, Value};
+
+    fn engine() -> QueryEngine {
+        let engine = QueryEngine::new();
+        engine
+            .execute_script(
+                "CREATE TABLE sales (id INT, region TEXT, amount INT);
+                 INSERT INTO sales VALUES
+                     (1, 'east', 10), (2, 'east', 30), (3, 'east', 20),
+                     (4, 'west', 5), (5, 'west', 5), (6, 'west', 7)",
+            )
+            .unwrap();
+        engine
+    }
+
+    fn query(engine: &QueryEngine, sql: &str) -> Vec<Row> {
+        engine.execute_script(sql).unwrap().pop().unwrap().rows
+    }
+
+    /// `column` of every row, in result order
+    fn column(rows: &[Row], column: &str) -> Vec<Value> {
+        rows.iter().map(|row| row[column].clone()).collect()
+    }
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|&v| Value::Int64(v)).collect()
+    }
+
+    #[test]
+    fn row_number_restarts_in_each_partition() {
+        let rows = query(
+            &engine(),
+            "SELECT id, ROW_NUMBER() OVER (PARTITION BY region ORDER BY amount DESC, id) AS n
+             FROM sales ORDER BY id",
+        );
+        assert_eq!(column(&rows, "n"), ints(&[3, 1, 2, 2, 3, 1]));
+    }
+
+    #[test]
+    fn rank_leaves_gaps_after_ties() {
+        let rows = query(
+            &engine(),
+            "SELECT id,
+                    RANK() OVER (PARTITION BY region ORDER BY amount) AS r,
+                    DENSE_RANK() OVER (ORDER BY amount) AS d
+             FROM sales ORDER BY id",
+        );
+        assert_eq!(column(&rows, "r"), ints(&[1, 3, 2, 1, 1, 3]));
+        assert_eq!(column(&rows, "d"), ints(&[3, 5, 4, 1, 1, 2]));
+    }
+
+    #[test]
+    fn aggregates_run_over_the_window_order() {
+        let rows = query(
+            &engine(),
+            "SELECT id,
+                    SUM(amount) OVER (PARTITION BY region ORDER BY amount) AS running,
+                    SUM(amount) OVER (PARTITION BY region) AS total,
+                    AVG(amount) OVER (PARTITION BY region ORDER BY id) AS average
+             FROM sales ORDER BY id",
+        );
+        // Tied rows are in each other's running total
+        assert_eq!(column(&rows, "running"), ints(&[10, 60, 30, 10, 10, 17]));
+        assert_eq!(column(&rows, "total"), ints(&[60, 60, 60, 17, 17, 17]));
+        let averages: Vec<Value> = [10.0, 20.0, 20.0, 5.0, 5.0, 17.0 / 3.0]
+            .into_iter()
+            .map(Value::Float64)
+            .collect();
+        assert_eq!(column(&rows, "average"), averages);
+    }
+
+    #[test]
+    fn unsupported_window_forms_are_rejected() {
+        let engine = engine();
+        for sql in [
+            "SELECT SUM(amount) OVER (ORDER BY id ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) FROM sales",
+            "SELECT COUNT(DISTINCT region) OVER (ORDER BY id) FROM sales",
+            "SELECT ROW_NUMBER() OVER (ORDER BY id), COUNT(*) FROM sales",
+        ] {
+            assert!(engine.execute_script(sql).is_err(), "{}", sql);
+        }
+    }
+}